@@ -3,6 +3,8 @@
 // use std::sync::mpsc::{Sender, Receiver};
 // use std::sync::mpsc;
 use core::fmt::Debug;
+use std::fs::File;
+use std::io::BufReader;
 use std::net::SocketAddr;
 use std::net::UdpSocket;
 use std::time::{Duration, SystemTime};
@@ -22,7 +24,10 @@ use std::sync::Arc;
 use util::conn::*;
 use webrtc_dtls::config::ExtendedMasterSecretType;
 use webrtc_dtls::Error;
-use webrtc_dtls::{config::Config, crypto::Certificate, listener::listen};
+use webrtc_dtls::{
+    config::Config,
+    crypto::{Certificate, CryptoPrivateKey},
+};
 use env_logger::*;
 use std::io::Write;
 use clap::{App, AppSettings, Arg};
@@ -30,8 +35,17 @@ use clap::{App, AppSettings, Arg};
 
 // use DTLS::dtls_client::DtlsClient;
 use broker_lib::{
+    authorization::{AllowAll, ReloadableAuthorizer},
     broker_lib::MqttSnClient,
-    hub::Hub,
+    client_id::{ClientId, ClientIdRules},
+    cluster::{Cluster, ClusterConfig},
+    config::ConfigWatcher,
+    demo::Broker,
+    dtls_auth::{AuthListener, DtlsAuth},
+    hub::{Hub, HUB_IDLE_TIMEOUT, HUB_REKEY_INTERVAL},
+    listener::Listeners,
+    timer_wheel::TimerWheelConfig,
+    ws_transport::WsTransport,
 };
 // use BrokerLib::MqttSnClient;
 
@@ -60,24 +74,34 @@ fn mpmc() {
     tx_thread.join().expect("The sender thread has panicked");
 }
     */
+/// Load the PEM certificate chain and private key of the DTLS listener.
+fn load_certificate(
+    cert_path: &str,
+    key_path: &str,
+) -> Result<Certificate, Error> {
+    let key_pem = std::fs::read_to_string(key_path)
+        .map_err(|why| Error::Other(format!("{}: {}", key_path, why)))?;
+    let key_pair = rcgen::KeyPair::from_pem(&key_pem)
+        .map_err(|why| Error::Other(format!("{}: {}", key_path, why)))?;
+    let private_key = CryptoPrivateKey::from_key_pair(&key_pair)?;
+    let file = File::open(cert_path)
+        .map_err(|why| Error::Other(format!("{}: {}", cert_path, why)))?;
+    let certificate = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|why| Error::Other(format!("{}: {}", cert_path, why)))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect::<Vec<_>>();
+    if certificate.is_empty() {
+        return Err(Error::Other(format!("{}: no certificate", cert_path)));
+    }
+    Ok(Certificate {
+        certificate,
+        private_key,
+    })
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    env_logger::Builder::new()
-        .format(|buf, record| {
-            writeln!(
-                buf,
-                "{}:{} [{}] {} - {}",
-                record.file().unwrap_or("unknown"),
-                record.line().unwrap_or(0),
-                record.level(),
-                chrono::Local::now().format("%H:%M:%S.%6f"),
-                record.args()
-            )
-        })
-        .filter(None, log::LevelFilter::Trace)
-        .init();
-
-
     let mut app = App::new("DTLS Server")
         .version("0.1.0")
         .author("Rain Liu <yliu@webrtc.rs>")
@@ -96,6 +120,154 @@ async fn main() -> Result<(), Error> {
                 .default_value("127.0.0.1:61003")
                 .long("host")
                 .help("DTLS host name."),
+        )
+        .arg(
+            Arg::with_name("bind")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .default_value("0.0.0.0:60000")
+                .long("bind")
+                .help(
+                    "UDP addresses, [::]:60000 for dual-stack, one per \
+                     interface of a multi-homed gateway, e.g. \
+                     --bind 10.0.0.1:60000,192.168.1.1:60000.",
+                ),
+        )
+        .arg(
+            Arg::with_name("demo")
+                .long("demo")
+                .help("Runs a simulated sensor and prints the demo topics."),
+        )
+        .arg(
+            Arg::with_name("cert")
+                .takes_value(true)
+                .long("cert")
+                .requires("key")
+                .help("DTLS certificate chain, PEM. Self-signed without it."),
+        )
+        .arg(
+            Arg::with_name("key")
+                .takes_value(true)
+                .long("key")
+                .requires("cert")
+                .help("DTLS private key, PEM."),
+        )
+        .arg(
+            Arg::with_name("psk-file")
+                .takes_value(true)
+                .long("psk-file")
+                .conflicts_with("client-ca")
+                .help("DTLS PSK file, an identity and a hex key per line."),
+        )
+        .arg(
+            Arg::with_name("client-ca")
+                .takes_value(true)
+                .long("client-ca")
+                .help("PEM CAs of the DTLS client certificates, required."),
+        )
+        .arg(
+            Arg::with_name("require-auth")
+                .long("require-auth")
+                .help("Reject CONNECT without a PSK identity or certificate."),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .takes_value(true)
+                .default_value("trace")
+                .possible_values(&[
+                    "off", "error", "warn", "info", "debug", "trace",
+                ])
+                .long("log-level")
+                .help("Log level, the configuration file can change it."),
+        )
+        .arg(
+            Arg::with_name("gw-id")
+                .takes_value(true)
+                .default_value("5")
+                .long("gw-id")
+                .help("Gateway id of ADVERTISE and GWINFO."),
+        )
+        .arg(
+            Arg::with_name("advertise")
+                .takes_value(true)
+                .default_value("2")
+                .long("advertise")
+                .help("Seconds between ADVERTISE messages, 0 disables them."),
+        )
+        .arg(
+            Arg::with_name("sys-interval")
+                .takes_value(true)
+                .default_value("10")
+                .long("sys-interval")
+                .help("Seconds between the $SYS statistics, 0 disables them."),
+        )
+        .arg(
+            Arg::with_name("timer-tick")
+                .takes_value(true)
+                .default_value("100")
+                .long("timer-tick")
+                .help("Milliseconds per tick of the timer wheels."),
+        )
+        .arg(
+            Arg::with_name("timer-precision")
+                .takes_value(true)
+                .default_value("100")
+                .long("timer-precision")
+                .help("Timer deadlines are rounded up to these milliseconds."),
+        )
+        .arg(
+            Arg::with_name("dtls-rekey")
+                .takes_value(true)
+                .long("dtls-rekey")
+                .help("Seconds before a DTLS session is renewed, 0 disables."),
+        )
+        .arg(
+            Arg::with_name("relaxed-client-id")
+                .long("relaxed-client-id")
+                .help("Accept longer client ids with any printable character."),
+        )
+        .arg(
+            Arg::with_name("config")
+                .takes_value(true)
+                .long("config")
+                .help("TOML configuration file, reloaded when it changes."),
+        )
+        .arg(
+            Arg::with_name("ws")
+                .takes_value(true)
+                .long("ws")
+                .value_name("ADDR")
+                .help("WebSocket listen address for browser clients."),
+        )
+        .arg(
+            Arg::with_name("cluster-bind")
+                .takes_value(true)
+                .long("cluster-bind")
+                .value_name("ADDR")
+                .requires("cluster-peers")
+                .help("UDP address of the cluster socket, for the peers only."),
+        )
+        .arg(
+            Arg::with_name("cluster-peers")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .long("cluster-peers")
+                .requires("cluster-bind")
+                .help("Cluster sockets of the other gateways."),
+        )
+        .arg(
+            Arg::with_name("node-id")
+                .takes_value(true)
+                .default_value("1")
+                .long("node-id")
+                .help("Node id of the gateway in the cluster, unique."),
+        )
+        .arg(
+            Arg::with_name("tracing")
+                .long("tracing")
+                .help("Log the spans, filtered with RUST_LOG."),
         );
 
     let matches = app.clone().get_matches();
@@ -105,49 +277,204 @@ async fn main() -> Result<(), Error> {
         std::process::exit(0);
     }
 
+    let log_level = matches
+        .value_of("log-level")
+        .unwrap()
+        .parse::<log::LevelFilter>()
+        .expect("invalid log level");
+    if matches.is_present("tracing") {
+        // The log records go to the spans of broker_lib::msg_span.
+        let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| {
+                tracing_subscriber::EnvFilter::new(log_level.to_string())
+            });
+        tracing_subscriber::fmt().with_env_filter(filter).init();
+    } else {
+        env_logger::Builder::new()
+            .format(|buf, record| {
+                writeln!(
+                    buf,
+                    "{}:{} [{}] {} - {}",
+                    record.file().unwrap_or("unknown"),
+                    record.line().unwrap_or(0),
+                    record.level(),
+                    chrono::Local::now().format("%H:%M:%S.%6f"),
+                    record.args()
+                )
+            })
+            .filter(None, log_level)
+            .init();
+    }
+
     let host = matches.value_of("host").unwrap().to_owned();
+    let gw_id = matches
+        .value_of("gw-id")
+        .unwrap()
+        .parse::<u8>()
+        .expect("invalid gateway id");
+    let advertise_duration = matches
+        .value_of("advertise")
+        .unwrap()
+        .parse::<u16>()
+        .expect("invalid advertise interval");
+    let sys_interval = matches
+        .value_of("sys-interval")
+        .unwrap()
+        .parse::<u16>()
+        .expect("invalid $SYS interval");
+    let dtls_rekey = match matches.value_of("dtls-rekey") {
+        Some(secs) => {
+            Duration::from_secs(secs.parse().expect("invalid rekey interval"))
+        }
+        None => HUB_REKEY_INTERVAL,
+    };
+    let timer_tick = matches
+        .value_of("timer-tick")
+        .unwrap()
+        .parse::<u64>()
+        .expect("invalid timer tick");
+    let timer_precision = matches
+        .value_of("timer-precision")
+        .unwrap()
+        .parse::<u64>()
+        .expect("invalid timer precision");
 
-    // Generate a certificate and private key to secure the connection
-    let certificate = Certificate::generate_self_signed(vec!["localhost".to_owned()])?;
+    let certificate = match (matches.value_of("cert"), matches.value_of("key"))
+    {
+        (Some(cert_path), Some(key_path)) => {
+            load_certificate(cert_path, key_path)?
+        }
+        // Generate a certificate and private key to secure the connection
+        _ => Certificate::generate_self_signed(vec!["localhost".to_owned()])?,
+    };
 
-    let cfg = Config {
+    let mut cfg = Config {
         certificates: vec![certificate],
         extended_master_secret: ExtendedMasterSecretType::Require,
         ..Default::default()
     };
+    if let Some(path) = matches.value_of("psk-file") {
+        let keys = std::fs::read_to_string(path)
+            .map_err(|why| why.to_string())
+            .and_then(|text| DtlsAuth::parse_psk(&text))
+            .map_err(|why| Error::Other(format!("{}: {}", path, why)))?;
+        let identity_hint = format!("gw{}", gw_id);
+        cfg = DtlsAuth::with_psk(cfg, keys, identity_hint.as_bytes());
+    }
+    if let Some(path) = matches.value_of("client-ca") {
+        let file = File::open(path)
+            .map_err(|why| Error::Other(format!("{}: {}", path, why)))?;
+        match cfg.client_cas.add_pem_file(&mut BufReader::new(file)) {
+            Ok((valid, _)) if valid > 0 => (),
+            _ => return Err(Error::Other(format!("{}: no CA", path))),
+        }
+        cfg = DtlsAuth::with_client_cert(cfg);
+    }
+    DtlsAuth::set_required(matches.is_present("require-auth"));
 
     println!("listening {}...\ntype 'exit' to shutdown gracefully", host);
 
     let remote_addr = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
-    let socket = UdpSocket::bind("0.0.0.0:60000").unwrap();
-
-    let client = MqttSnClient::new();
-
+    let bind_addrs: Vec<SocketAddr> = matches
+        .values_of("bind")
+        .unwrap()
+        .map(|bind_addr| bind_addr.parse().expect("invalid bind address"))
+        .collect();
+    let sockets = match Listeners::bind(&bind_addrs) {
+        Ok(sockets) => sockets,
+        Err(why) => {
+            error!("{}", why);
+            std::process::exit(1);
+        }
+    };
 
+    let authorizer = Arc::new(ReloadableAuthorizer::new(Arc::new(AllowAll {})));
+    let client = MqttSnClient::new()
+        .with_authorizer(authorizer.clone())
+        .with_advertise(gw_id, advertise_duration)
+        .with_sys_interval(sys_interval)
+        .with_timer_config(TimerWheelConfig::new(
+            Duration::from_millis(timer_tick),
+            Duration::from_millis(timer_precision),
+        ));
+    if matches.is_present("relaxed-client-id") {
+        ClientId::set_rules(ClientIdRules::relaxed());
+    }
+    if let Some(path) = matches.value_of("config") {
+        if let Err(why) = ConfigWatcher::run(path, authorizer) {
+            error!("{}", why);
+            std::process::exit(1);
+        }
+    }
 
-    let listener = Arc::new(listen(host, cfg).await?);
+    let listener =
+        AuthListener::bind(&host, cfg).await.map_err(Error::Other)?;
+    let listener = Arc::new(listener);
     let listener2 = Arc::clone(&listener);
     let hub = Arc::clone(&client.hub);
 
     tokio::spawn(async move {
+        // The identity of the peer is registered by accept().
         while let Ok((dtls_conn, _remote_addr)) = listener2.accept().await {
             // Register the connection with the chat hub
-            hub.register(dtls_conn).await;
+            if dtls_rekey.as_secs() == 0 {
+                hub.register(dtls_conn).await;
+            } else {
+                hub.register_with_lifetime(dtls_conn, dtls_rekey).await;
+            }
         }
     });
+    client.run_hub_eviction(HUB_IDLE_TIMEOUT);
+    if let Some(ws_addr) = matches.value_of("ws") {
+        let ws_addr =
+            ws_addr.parse::<SocketAddr>().expect("invalid ws address");
+        match WsTransport::serve(ws_addr, Arc::clone(&client.hub)).await {
+            Ok(ws_addr) => println!("WebSocket listening {}", ws_addr),
+            Err(why) => error!("{}", why),
+        }
+    }
 
     // init_logging();
+    if let Some(cluster_addr) = matches.value_of("cluster-bind") {
+        let cluster_addr = cluster_addr
+            .parse::<SocketAddr>()
+            .expect("invalid cluster address");
+        let peers: Vec<SocketAddr> = matches
+            .values_of("cluster-peers")
+            .unwrap()
+            .map(|peer| peer.parse().expect("invalid cluster peer"))
+            .collect();
+        let node_id = matches
+            .value_of("node-id")
+            .unwrap()
+            .parse::<u32>()
+            .expect("invalid node id");
+        let config = ClusterConfig::new(node_id, peers);
+        let joined = UdpSocket::bind(cluster_addr)
+            .map_err(|why| why.to_string())
+            .and_then(|socket| Cluster::run(client.clone(), socket, config));
+        if let Err(why) = joined {
+            error!("{}", why);
+            std::process::exit(1);
+        }
+    }
+
     let client_loop = client.clone();
     let client_sub = client.clone();
     let client_ingress = client.clone();
     let client_egress = client.clone();
-    client_loop.broker_rx_loop(socket);
+    client_loop.broker_rx_loop(sockets);
+    if matches.is_present("demo") {
+        if let Err(why) = Broker::demo(&client) {
+            error!("{}", why);
+        }
+    }
 
     // This thread reads the channel for all subscribed topics.
     // The struct Publish is recv.
     // TODO return error for subscribe and publish function calls.
-        let _result = client_ingress.handle_ingress();
-        let _result = client_egress.handle_egress();
+    let _result = client_ingress.handle_ingress();
+    let _result = client_egress.handle_egress();
 
     let rx_thread2 = thread::spawn(move || loop {
         let _result = client_sub.subscribe_rx.recv();
@@ -0,0 +1,624 @@
+/// Soak test of the broker with thousands of sleepy clients over loopback.
+///
+/// Each simulated client connects, subscribes to the topic of its group and
+/// registers the topic of the next group, then cycles: PUBLISH, DISCONNECT
+/// with a sleep duration, PINGREQ with the client id to get the messages
+/// queued while asleep, and CONNECT without the clean session flag. The
+/// clients are spread over worker threads, a worker runs the next step of
+/// the client with the earliest deadline. The payload carries the publish
+/// time, the delivery latency is measured when the subscriber gets it,
+/// including the time it was asleep.
+///
+/// cargo run --release --features test-support -- --clients 5000 \
+///     --threads 16 --seconds 600
+///
+/// Without --broker the broker runs in this process, see
+/// broker_lib::test_support::LoopbackBroker, and the resource use is the
+/// one of this process. With --broker, --broker-pid gives the process of
+/// the broker. Every report interval prints the steps done, the errors and
+/// the memory and threads of the broker, the end prints the latency
+/// percentiles of each step. The exit code is 1 if a step failed.
+use clap::{App, AppSettings, Arg};
+use log::*;
+use std::fs;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use broker_lib::{
+    flags::{QoSConst, RETAIN_FALSE},
+    test_support::{LoopbackBroker, TestClient, TestPublish},
+};
+
+/// RETURN_CODE_ACCEPTED of CONNACK and SUBACK.
+const ACCEPTED: u8 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Step {
+    Connect = 0,
+    Subscribe,
+    Register,
+    Publish,
+    /// DISCONNECT with a duration.
+    Sleep,
+    /// PINGREQ with the client id up to the PINGRESP.
+    Wake,
+    /// From the PUBLISH to the subscriber.
+    Delivery,
+}
+
+const STEP_LEN: usize = 7;
+
+/// In the Step order.
+const STEP_NAMES: [&str; STEP_LEN] = [
+    "connect",
+    "subscribe",
+    "register",
+    "publish",
+    "sleep",
+    "wake",
+    "delivery",
+];
+
+#[derive(Debug, Clone)]
+struct SoakConfig {
+    broker: SocketAddr,
+    clients: usize,
+    threads: usize,
+    /// Clients publishing to the same topic.
+    groups: usize,
+    qos: QoSConst,
+    payload_len: usize,
+    keep_alive: u16,
+    /// PUBLISH messages of an ACTIVE period.
+    publishes: u32,
+    publish_interval: Duration,
+    /// PINGREQs before the client connects again.
+    wakes: u32,
+    /// Time asleep before each PINGREQ.
+    sleep: Duration,
+    /// The clients connect for the first time over the ramp.
+    ramp: Duration,
+    timeout: Duration,
+}
+
+impl SoakConfig {
+    /// Duration of the DISCONNECT, the broker keeps the client ASLEEP for
+    /// twice the time between the PINGREQs.
+    fn sleep_duration(&self) -> u16 {
+        (self.sleep.as_secs() as u16 + 1).saturating_mul(2)
+    }
+}
+
+/// Steps and errors of all the workers, for the periodic report.
+#[derive(Debug, Default)]
+struct Progress {
+    steps: [AtomicU64; STEP_LEN],
+    errors: AtomicU64,
+}
+
+/// Latencies of a worker, in µs.
+#[derive(Debug, Default)]
+struct Stats {
+    latencies: [Vec<u64>; STEP_LEN],
+}
+
+impl Stats {
+    fn record(&mut self, progress: &Progress, step: Step, latency: Duration) {
+        self.latencies[step as usize].push(latency.as_micros() as u64);
+        progress.steps[step as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn merge(&mut self, other: Stats) {
+        for (latencies, other) in
+            self.latencies.iter_mut().zip(other.latencies.iter())
+        {
+            latencies.extend_from_slice(other);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ClientState {
+    New,
+    Active,
+    Asleep,
+}
+
+#[derive(Debug)]
+struct SimClient {
+    client_id: String,
+    client: TestClient,
+    sub_topic: String,
+    pub_topic: String,
+    pub_topic_id: u16,
+    state: ClientState,
+    /// PUBLISH messages left in the ACTIVE period.
+    publishes: u32,
+    /// PINGREQs left before the CONNECT.
+    wakes: u32,
+    next_at: Instant,
+}
+
+impl SimClient {
+    fn new(
+        index: usize,
+        config: &SoakConfig,
+        next_at: Instant,
+    ) -> Result<Self, String> {
+        let group = index % config.groups;
+        let mut client = TestClient::new(config.broker)?;
+        client.set_timeout(config.timeout);
+        Ok(SimClient {
+            client_id: format!("soak{}", index),
+            client,
+            sub_topic: format!("soak/{}", group),
+            pub_topic: format!("soak/{}", (group + 1) % config.groups),
+            pub_topic_id: 0,
+            state: ClientState::New,
+            publishes: 0,
+            wakes: 0,
+            next_at,
+        })
+    }
+
+    /// Run the next step, schedules the one after.
+    fn step(
+        &mut self,
+        config: &SoakConfig,
+        stats: &mut Stats,
+        progress: &Progress,
+    ) -> Result<(), String> {
+        let start = Instant::now();
+        match self.state {
+            ClientState::New => {
+                let return_code = self.client.connect(
+                    &self.client_id,
+                    config.keep_alive,
+                    None,
+                )?;
+                SimClient::accepted(&self.client_id, return_code)?;
+                stats.record(progress, Step::Connect, start.elapsed());
+                let start = Instant::now();
+                let (_topic_id, return_code) =
+                    self.client.subscribe(&self.sub_topic, config.qos)?;
+                SimClient::accepted(&self.sub_topic, return_code)?;
+                stats.record(progress, Step::Subscribe, start.elapsed());
+                let start = Instant::now();
+                self.pub_topic_id = self.client.register(&self.pub_topic)?;
+                stats.record(progress, Step::Register, start.elapsed());
+                self.activate(config);
+            }
+            ClientState::Active if self.publishes > 0 => {
+                let payload = SimClient::payload(config.payload_len);
+                self.client.publish(
+                    self.pub_topic_id,
+                    config.qos,
+                    RETAIN_FALSE,
+                    &payload,
+                )?;
+                stats.record(progress, Step::Publish, start.elapsed());
+                self.publishes -= 1;
+                self.drain(config, stats, progress);
+                self.next_at = Instant::now() + config.publish_interval;
+            }
+            ClientState::Active => {
+                self.client.disconnect(Some(config.sleep_duration()))?;
+                stats.record(progress, Step::Sleep, start.elapsed());
+                self.state = ClientState::Asleep;
+                self.wakes = config.wakes;
+                self.next_at = Instant::now() + config.sleep;
+            }
+            ClientState::Asleep => {
+                let publishes =
+                    self.client.ping(Some(self.client_id.as_str()))?;
+                stats.record(progress, Step::Wake, start.elapsed());
+                for publish in publishes.iter() {
+                    SimClient::delivered(publish, stats, progress);
+                }
+                self.wakes = self.wakes.saturating_sub(1);
+                if self.wakes > 0 {
+                    self.next_at = Instant::now() + config.sleep;
+                } else {
+                    let start = Instant::now();
+                    let return_code = self
+                        .client
+                        .reconnect(&self.client_id, config.keep_alive)?;
+                    SimClient::accepted(&self.client_id, return_code)?;
+                    stats.record(progress, Step::Connect, start.elapsed());
+                    self.activate(config);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn activate(&mut self, config: &SoakConfig) {
+        self.state = ClientState::Active;
+        self.publishes = config.publishes;
+        self.next_at = Instant::now();
+    }
+
+    /// The PUBLISH messages received by the ACTIVE client meanwhile. Only
+    /// the ones already read by the client, a QoS 1 PUBLISH is
+    /// acknowledged without waiting.
+    fn drain(
+        &mut self,
+        config: &SoakConfig,
+        stats: &mut Stats,
+        progress: &Progress,
+    ) {
+        self.client.set_timeout(Duration::from_millis(0));
+        while let Ok(publish) = self.client.recv_publish() {
+            SimClient::delivered(&publish, stats, progress);
+        }
+        self.client.set_timeout(config.timeout);
+    }
+
+    fn accepted(name: &str, return_code: u8) -> Result<(), String> {
+        match return_code {
+            ACCEPTED => Ok(()),
+            return_code => {
+                Err(format!("{}: return code {}", name, return_code))
+            }
+        }
+    }
+
+    /// The publish time in µs since the Unix epoch, padded to len.
+    fn payload(len: usize) -> Vec<u8> {
+        let mut payload = unix_micros().to_be_bytes().to_vec();
+        payload.resize(usize::max(len, payload.len()), b's');
+        payload
+    }
+
+    fn delivered(
+        publish: &TestPublish,
+        stats: &mut Stats,
+        progress: &Progress,
+    ) {
+        if publish.payload.len() < 8 {
+            return;
+        }
+        let mut sent_at = [0u8; 8];
+        sent_at.copy_from_slice(&publish.payload[..8]);
+        let latency = unix_micros().saturating_sub(u64::from_be_bytes(sent_at));
+        stats.record(progress, Step::Delivery, Duration::from_micros(latency));
+    }
+}
+
+fn unix_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+/// Run the steps of the clients until the deadline. The clients are
+/// scanned for the earliest next step, a worker has a few hundred.
+fn run_worker(
+    mut clients: Vec<SimClient>,
+    config: Arc<SoakConfig>,
+    progress: Arc<Progress>,
+    deadline: Instant,
+) -> Stats {
+    let mut stats = Stats::default();
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        let client = match clients.iter_mut().min_by_key(|c| c.next_at) {
+            Some(client) => client,
+            None => break,
+        };
+        if client.next_at > now {
+            thread::sleep(Instant::min(client.next_at, deadline) - now);
+            continue;
+        }
+        if let Err(why) = client.step(&config, &mut stats, &progress) {
+            warn!("{}: {}", client.client_id, why);
+            progress.errors.fetch_add(1, Ordering::Relaxed);
+            // Start over with a clean session.
+            client.state = ClientState::New;
+            client.next_at = Instant::now() + config.timeout;
+        }
+    }
+    stats
+}
+
+/// VmRSS and VmHWM in kB and the threads of the process, None if /proc
+/// isn't readable.
+fn process_status(pid: Option<u32>) -> Option<(u64, u64, u64)> {
+    let path = match pid {
+        Some(pid) => format!("/proc/{}/status", pid),
+        None => "/proc/self/status".to_string(),
+    };
+    let status = fs::read_to_string(path).ok()?;
+    let field = |name: &str| -> Option<u64> {
+        let line = status.lines().find(|line| line.starts_with(name))?;
+        line[name.len()..].split_whitespace().next()?.parse().ok()
+    };
+    Some((field("VmRSS:")?, field("VmHWM:")?, field("Threads:")?))
+}
+
+fn resource_use(pid: Option<u32>) -> String {
+    match process_status(pid) {
+        Some((rss, hwm, threads)) => format!(
+            "rss {} MB, peak {} MB, {} threads",
+            rss / 1024,
+            hwm / 1024,
+            threads
+        ),
+        None => "no resource use".to_string(),
+    }
+}
+
+/// The value at the quantile of the sorted latencies.
+fn percentile(sorted: &[u64], quantile: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let index = ((sorted.len() - 1) as f64 * quantile).round() as usize;
+    sorted[index]
+}
+
+fn print_latencies(stats: &mut Stats) {
+    println!(
+        "{:<10} {:>9} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "step", "count", "p50 ms", "p90 ms", "p99 ms", "p99.9 ms", "max ms"
+    );
+    for (name, latencies) in STEP_NAMES.iter().zip(stats.latencies.iter_mut()) {
+        latencies.sort_unstable();
+        let sorted: &[u64] = latencies;
+        let ms = |quantile| percentile(sorted, quantile) as f64 / 1000.0;
+        println!(
+            "{:<10} {:>9} {:>10.3} {:>10.3} {:>10.3} {:>10.3} {:>10.3}",
+            name,
+            latencies.len(),
+            ms(0.5),
+            ms(0.9),
+            ms(0.99),
+            ms(0.999),
+            ms(1.0)
+        );
+    }
+}
+
+fn arg<T: std::str::FromStr>(matches: &clap::ArgMatches, name: &str) -> T {
+    matches
+        .value_of(name)
+        .unwrap()
+        .parse::<T>()
+        .unwrap_or_else(|_| panic!("invalid {}", name))
+}
+
+fn main() {
+    let app = App::new("MQTT-SN soak test")
+        .version("0.1.0")
+        .about("Simulates sleepy clients against the broker over loopback.")
+        .setting(AppSettings::DeriveDisplayOrder)
+        .arg(
+            Arg::with_name("broker")
+                .takes_value(true)
+                .long("broker")
+                .help("UDP address of the broker, in this process without."),
+        )
+        .arg(
+            Arg::with_name("broker-pid")
+                .takes_value(true)
+                .long("broker-pid")
+                .requires("broker")
+                .help("Process of the broker, for its memory and threads."),
+        )
+        .arg(
+            Arg::with_name("clients")
+                .takes_value(true)
+                .default_value("1000")
+                .long("clients")
+                .help("Simulated clients."),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .takes_value(true)
+                .default_value("8")
+                .long("threads")
+                .help("Worker threads running the clients."),
+        )
+        .arg(
+            Arg::with_name("groups")
+                .takes_value(true)
+                .default_value("100")
+                .long("groups")
+                .help("Topics, the clients of a group share one."),
+        )
+        .arg(
+            Arg::with_name("qos")
+                .takes_value(true)
+                .default_value("1")
+                .possible_values(&["0", "1"])
+                .long("qos")
+                .help("QoS of the PUBLISH and SUBSCRIBE messages."),
+        )
+        .arg(
+            Arg::with_name("payload")
+                .takes_value(true)
+                .default_value("32")
+                .long("payload")
+                .help("Payload bytes, at least the 8 of the publish time."),
+        )
+        .arg(
+            Arg::with_name("keep-alive")
+                .takes_value(true)
+                .default_value("60")
+                .long("keep-alive")
+                .help("Keep alive seconds of the CONNECT."),
+        )
+        .arg(
+            Arg::with_name("publishes")
+                .takes_value(true)
+                .default_value("1")
+                .long("publishes")
+                .help("PUBLISH messages each time a client is awake."),
+        )
+        .arg(
+            Arg::with_name("publish-interval")
+                .takes_value(true)
+                .default_value("100")
+                .long("publish-interval")
+                .help("Milliseconds between the PUBLISH messages."),
+        )
+        .arg(
+            Arg::with_name("wakes")
+                .takes_value(true)
+                .default_value("2")
+                .long("wakes")
+                .help("PINGREQs of an asleep client before it connects."),
+        )
+        .arg(
+            Arg::with_name("sleep")
+                .takes_value(true)
+                .default_value("5000")
+                .long("sleep")
+                .help("Milliseconds asleep before each PINGREQ."),
+        )
+        .arg(
+            Arg::with_name("ramp")
+                .takes_value(true)
+                .default_value("10000")
+                .long("ramp")
+                .help("Milliseconds over which the clients first connect."),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .takes_value(true)
+                .default_value("2000")
+                .long("timeout")
+                .help("Milliseconds to wait for each answer of the broker."),
+        )
+        .arg(
+            Arg::with_name("seconds")
+                .takes_value(true)
+                .default_value("60")
+                .long("seconds")
+                .help("Length of the test."),
+        )
+        .arg(
+            Arg::with_name("report")
+                .takes_value(true)
+                .default_value("10")
+                .long("report")
+                .help("Seconds between the progress reports."),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .takes_value(true)
+                .default_value("warn")
+                .possible_values(&[
+                    "off", "error", "warn", "info", "debug", "trace",
+                ])
+                .long("log-level")
+                .help("Log level."),
+        );
+    let matches = app.get_matches();
+
+    env_logger::Builder::new()
+        .format(|buf, record| {
+            writeln!(buf, "[{}] {}", record.level(), record.args())
+        })
+        .filter(None, arg(&matches, "log-level"))
+        .init();
+
+    let broker = match matches.value_of("broker") {
+        Some(_) => arg(&matches, "broker"),
+        None => LoopbackBroker::addr(),
+    };
+    let broker_pid = matches
+        .value_of("broker-pid")
+        .map(|_| arg::<u32>(&matches, "broker-pid"));
+    let millis = |name| Duration::from_millis(arg(&matches, name));
+    let config = Arc::new(SoakConfig {
+        broker,
+        clients: arg(&matches, "clients"),
+        threads: usize::max(arg(&matches, "threads"), 1),
+        groups: usize::max(arg(&matches, "groups"), 1),
+        qos: arg(&matches, "qos"),
+        payload_len: arg(&matches, "payload"),
+        keep_alive: arg(&matches, "keep-alive"),
+        publishes: arg(&matches, "publishes"),
+        publish_interval: millis("publish-interval"),
+        wakes: u32::max(arg(&matches, "wakes"), 1),
+        sleep: millis("sleep"),
+        ramp: millis("ramp"),
+        timeout: millis("timeout"),
+    });
+    let seconds: u64 = arg(&matches, "seconds");
+    let report = Duration::from_secs(u64::max(arg(&matches, "report"), 1));
+
+    let progress = Arc::new(Progress::default());
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(seconds);
+    let mut workers: Vec<Vec<SimClient>> =
+        (0..config.threads).map(|_| Vec::new()).collect();
+    for index in 0..config.clients {
+        let next_at =
+            start + config.ramp.mul_f64(index as f64 / config.clients as f64);
+        match SimClient::new(index, &config, next_at) {
+            Ok(client) => workers[index % config.threads].push(client),
+            Err(why) => {
+                eprintln!("soak{}: {}", index, why);
+                std::process::exit(1);
+            }
+        }
+    }
+    println!(
+        "{} clients on {} threads against {} for {}s",
+        config.clients, config.threads, config.broker, seconds
+    );
+    let handles: Vec<_> = workers
+        .into_iter()
+        .enumerate()
+        .map(|(index, clients)| {
+            let config = config.clone();
+            let progress = progress.clone();
+            thread::Builder::new()
+                .name(format!("soak_worker_{}", index))
+                .spawn(move || run_worker(clients, config, progress, deadline))
+                .expect("failed to spawn a worker")
+        })
+        .collect();
+
+    while Instant::now() + report < deadline {
+        thread::sleep(report);
+        let steps: Vec<String> = STEP_NAMES
+            .iter()
+            .zip(progress.steps.iter())
+            .map(|(name, count)| {
+                format!("{} {}", name, count.load(Ordering::Relaxed))
+            })
+            .collect();
+        println!(
+            "{:>5}s {}, errors {}, {}",
+            start.elapsed().as_secs(),
+            steps.join(", "),
+            progress.errors.load(Ordering::Relaxed),
+            resource_use(broker_pid)
+        );
+    }
+
+    let mut stats = Stats::default();
+    for handle in handles {
+        match handle.join() {
+            Ok(worker_stats) => stats.merge(worker_stats),
+            Err(_) => error!("a worker panicked"),
+        }
+    }
+    print_latencies(&mut stats);
+    let errors = progress.errors.load(Ordering::Relaxed);
+    println!("errors {}, {}", errors, resource_use(broker_pid));
+    if errors > 0 {
+        std::process::exit(1);
+    }
+}
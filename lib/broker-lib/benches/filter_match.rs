@@ -0,0 +1,95 @@
+/// Wildcard filter matching and subscriber lookups, the baseline for a
+/// refactoring of the filter tables.
+/// match_filters walks the topic tree of the wildcard filters, like the
+/// lookup of an uncached topic, match_topics returns the cached
+/// subscribers.
+/// get_subscribers_with_topic_id merges the subscribers of the topic id and
+/// of the matching filters at various fan-out sizes.
+/// Run with: cargo bench --bench filter_match
+use broker_lib::{
+    broker_context::BrokerContext,
+    filter::{
+        get_subscribers_with_topic_id, subscribe_with_topic_id,
+        subscribe_with_topic_name, try_insert_topic_name,
+    },
+    flags::QOS_LEVEL_1,
+    subscription_store::{SubscriptionStore, SUBSCRIPTION_SHARDS},
+};
+use criterion::{
+    black_box, criterion_group, criterion_main, BenchmarkId, Criterion,
+    Throughput,
+};
+use std::net::SocketAddr;
+
+const SITES: usize = 100;
+
+fn addr(index: usize) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 20000 + index as u16))
+}
+
+/// filters wildcard filters, half of them match one sensor, the other half
+/// a whole site.
+fn with_filters(filters: usize) -> SubscriptionStore {
+    let store = SubscriptionStore::new(SUBSCRIPTION_SHARDS);
+    for i in 0..filters {
+        let filter = if i % 2 == 0 {
+            format!("site/{}/sensor/{}/#", i % SITES, i)
+        } else {
+            format!("site/{}/#", i)
+        };
+        store.insert_filter(filter, addr(i % 1000)).unwrap();
+    }
+    store
+}
+
+fn bench_match_topics(c: &mut Criterion) {
+    let mut group = c.benchmark_group("match_topics");
+    let topic = "site/42/sensor/42/temp";
+    for &filters in [1_000usize, 5_000, 10_000].iter() {
+        let store = with_filters(filters);
+        group.bench_with_input(
+            BenchmarkId::new("match_filters", filters),
+            &store,
+            |b, store| b.iter(|| black_box(store.match_filters(topic))),
+        );
+        // The first call caches the subscribers of the topic.
+        store.match_topics(topic);
+        group.bench_with_input(
+            BenchmarkId::new("match_topics_cached", filters),
+            &store,
+            |b, store| b.iter(|| black_box(store.match_topics(topic))),
+        );
+    }
+    group.finish();
+}
+
+fn bench_get_subscribers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get_subscribers_with_topic_id");
+    for &fan_out in [1usize, 10, 100, 1_000].iter() {
+        group.throughput(Throughput::Elements(fan_out as u64));
+        // Isolated from the other benchmarks.
+        let context = BrokerContext::new();
+        let _context = context.enter();
+        let topic_id =
+            try_insert_topic_name("fan_out/sensor/temp".to_string()).unwrap();
+        for i in 0..fan_out {
+            subscribe_with_topic_id(addr(i), topic_id, QOS_LEVEL_1).unwrap();
+        }
+        group.bench_function(BenchmarkId::new("topic_id", fan_out), |b| {
+            b.iter(|| black_box(get_subscribers_with_topic_id(topic_id)))
+        });
+        // The same number of subscribers with a matching wildcard filter.
+        for i in 0..fan_out {
+            let filter = "fan_out/#".to_string();
+            subscribe_with_topic_name(addr(fan_out + i), filter, QOS_LEVEL_1)
+                .unwrap();
+        }
+        group.bench_function(BenchmarkId::new("with_filter", fan_out), |b| {
+            b.iter(|| black_box(get_subscribers_with_topic_id(topic_id)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_match_topics, bench_get_subscribers);
+criterion_main!(benches);
@@ -0,0 +1,60 @@
+/// End-to-end publish throughput through the loopback broker: a PUBLISH
+/// over UDP, the fan-out to the subscribers and their PUBLISH messages.
+/// Each iteration publishes one message and waits until every subscriber
+/// received it, the throughput is in delivered messages.
+/// Run with: cargo bench --bench loopback_publish --features test-support
+use broker_lib::{
+    flags::{QOS_LEVEL_0, QOS_LEVEL_1, RETAIN_FALSE},
+    test_support::{LoopbackBroker, TestClient},
+};
+use criterion::{
+    criterion_group, criterion_main, BenchmarkId, Criterion, Throughput,
+};
+
+/// Longer than a benchmark run, the subscribers don't send anything.
+const KEEP_ALIVE: u16 = 3600;
+
+fn bench_loopback_publish(c: &mut Criterion) {
+    let mut group = c.benchmark_group("loopback_publish");
+    let broker = LoopbackBroker::addr();
+    let mut publisher = TestClient::new(broker).unwrap();
+    publisher
+        .connect("benchPublisher", KEEP_ALIVE, None)
+        .unwrap();
+    let payload = [b'x'; 64];
+    for &fan_out in [1usize, 10, 50].iter() {
+        group.throughput(Throughput::Elements(fan_out as u64));
+        let topic = format!("bench/loopback/{}", fan_out);
+        let mut subscribers: Vec<TestClient> = (0..fan_out)
+            .map(|i| {
+                let mut subscriber = TestClient::new(broker).unwrap();
+                let client_id = format!("benchSub{}x{}", fan_out, i);
+                subscriber.connect(&client_id, KEEP_ALIVE, None).unwrap();
+                subscriber.subscribe(&topic, QOS_LEVEL_1).unwrap();
+                subscriber
+            })
+            .collect();
+        let topic_id = publisher.register(&topic).unwrap();
+        for &qos in [QOS_LEVEL_0, QOS_LEVEL_1].iter() {
+            let id = format!("qos{}", qos);
+            group.bench_function(BenchmarkId::new(id, fan_out), |b| {
+                b.iter(|| {
+                    publisher
+                        .publish(topic_id, qos, RETAIN_FALSE, &payload)
+                        .unwrap();
+                    for subscriber in subscribers.iter_mut() {
+                        subscriber.recv_publish().unwrap();
+                    }
+                })
+            });
+        }
+        for subscriber in subscribers.iter_mut() {
+            subscriber.disconnect(None).unwrap();
+        }
+    }
+    publisher.disconnect(None).unwrap();
+    group.finish();
+}
+
+criterion_group!(benches, bench_loopback_publish);
+criterion_main!(benches);
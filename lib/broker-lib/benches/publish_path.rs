@@ -0,0 +1,90 @@
+/// Publish::recv latency under mixed load, with and without the QoS 0
+/// fast path.
+/// 80% of the messages are tiny QoS 0 sensor readings, 20% are 512 bytes.
+/// Run with: cargo bench --bench publish_path
+use broker_lib::{
+    broker_lib::MqttSnClient,
+    conn::{udp_conn, Conn},
+    connection::Connection,
+    filter::*,
+    flags::*,
+    msg_hdr::MsgHeader,
+    publish::Publish,
+};
+use bytes::Bytes;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: usize = 100_000;
+const SUBSCRIBERS: u16 = 4;
+const TOPIC_ID: u16 = 7;
+
+fn publish_bytes(msg_id: u16, data_len: usize) -> Vec<u8> {
+    let len = data_len + 7;
+    let mut buf = Vec::with_capacity(len + 2);
+    if len < 256 {
+        buf.push(len as u8);
+    } else {
+        // 3 bytes length, the length includes the 2 extra bytes.
+        let len = len + 2;
+        buf.extend_from_slice(&[1, (len >> 8) as u8, len as u8]);
+    }
+    buf.push(0x0C); // PUBLISH
+    buf.push(QOS_LEVEL_0);
+    buf.extend_from_slice(&TOPIC_ID.to_be_bytes());
+    buf.extend_from_slice(&msg_id.to_be_bytes());
+    buf.resize(buf.len() + data_len, b'x');
+    buf
+}
+
+fn percentile(sorted: &[Duration], pct: usize) -> Duration {
+    sorted[(sorted.len() - 1) * pct / 100]
+}
+
+fn run(
+    name: &str,
+    client: &MqttSnClient,
+    conn: &Arc<dyn Conn + Send + Sync>,
+    publisher: SocketAddr,
+) {
+    let tiny = publish_bytes(1, 32);
+    let large = publish_bytes(2, 512);
+    let mut latencies = Vec::with_capacity(ITERATIONS);
+    for i in 0..ITERATIONS {
+        let buf = if i % 5 == 0 { &large } else { &tiny };
+        let start = Instant::now();
+        let msg_header =
+            MsgHeader::try_read(buf, buf.len(), publisher, conn.clone())
+                .unwrap();
+        Publish::recv(buf, buf.len(), client, msg_header).unwrap();
+        latencies.push(start.elapsed());
+        // Nobody sends the egress messages, drop them.
+        while client.egress_batch_rx.try_recv().is_ok() {}
+        while client.egress_rx.try_recv().is_ok() {}
+    }
+    latencies.sort();
+    println!(
+        "{}: p50 {:?} p99 {:?} max {:?}",
+        name,
+        percentile(&latencies, 50),
+        percentile(&latencies, 99),
+        latencies[latencies.len() - 1]
+    );
+}
+
+fn main() {
+    let client = MqttSnClient::new();
+    let conn = udp_conn();
+    let publisher = "127.0.0.1:20000".parse::<SocketAddr>().unwrap();
+    for port in 0..SUBSCRIBERS {
+        let addr = SocketAddr::new(publisher.ip(), 20001 + port);
+        let client_id = Bytes::from(format!("bench{}", port));
+        Connection::try_insert(addr, 0, 1, 60, client_id).unwrap();
+        subscribe_with_topic_id(addr, TOPIC_ID, QOS_LEVEL_0).unwrap();
+    }
+    Publish::set_fast_path(false);
+    run("full path", &client, &conn, publisher);
+    Publish::set_fast_path(true);
+    run("fast path", &client, &conn, publisher);
+}
@@ -0,0 +1,81 @@
+/// Subscriber lookups of concurrent PUBLISH fan-out, one shard (a single
+/// global lock) against SUBSCRIPTION_SHARDS shards.
+/// Each thread looks up its own topics while one thread keeps subscribing
+/// and unsubscribing, like clients connecting during the fan-out.
+/// Run with: cargo bench --bench subscription_store
+use broker_lib::{
+    flags::QOS_LEVEL_1,
+    subscription_store::{SubscriptionStore, SUBSCRIPTION_SHARDS},
+};
+use criterion::{
+    black_box, criterion_group, criterion_main, BenchmarkId, Criterion,
+};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const TOPICS: u16 = 256;
+const SUBSCRIBERS: u16 = 16;
+const LOOKUPS: u16 = 1_000;
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 30000 + port))
+}
+
+fn populated(shards: usize) -> SubscriptionStore {
+    let store = SubscriptionStore::new(shards);
+    for topic_id in 0..TOPICS {
+        for port in 0..SUBSCRIBERS {
+            store.subscribe(topic_id, addr(port), QOS_LEVEL_1).unwrap();
+        }
+    }
+    store
+}
+
+fn fan_out(store: &SubscriptionStore, threads: u16) {
+    let done = AtomicBool::new(false);
+    crossbeam::scope(|scope| {
+        // Subscription churn on the side.
+        scope.spawn(|_| {
+            let churn = addr(SUBSCRIBERS);
+            let mut topic_id = 0;
+            while !done.load(Ordering::Relaxed) {
+                store.subscribe(topic_id, churn, QOS_LEVEL_1).unwrap();
+                store.unsubscribe(topic_id, &churn);
+                topic_id = (topic_id + 1) % TOPICS;
+            }
+        });
+        let lookups: Vec<_> = (0..threads)
+            .map(|thread| {
+                scope.spawn(move |_| {
+                    for i in 0..LOOKUPS {
+                        let topic_id = (thread * 31 + i) % TOPICS;
+                        black_box(store.subscribers(topic_id));
+                    }
+                })
+            })
+            .collect();
+        for lookup in lookups {
+            lookup.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+    })
+    .unwrap();
+}
+
+fn bench_fan_out(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fan_out");
+    for &shards in [1, SUBSCRIPTION_SHARDS].iter() {
+        let store = populated(shards);
+        for &threads in [1u16, 4, 8].iter() {
+            group.bench_with_input(
+                BenchmarkId::new(format!("{}_shards", shards), threads),
+                &threads,
+                |b, &threads| b.iter(|| fan_out(&store, threads)),
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_fan_out);
+criterion_main!(benches);
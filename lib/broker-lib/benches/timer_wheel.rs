@@ -0,0 +1,111 @@
+/// Timer insert and cancel cost of the time wheels at 50k connections.
+/// insert schedules a keep-alive timer per connection, cancel removes them,
+/// reschedule replaces the timer of each connection, like a retransmit
+/// doubling its duration, and expire advances the wheel until all the
+/// timers expired, at the default precision and coalesced to 1 second.
+/// Run with: cargo bench --bench timer_wheel
+use broker_lib::timer_wheel::{TimerWheel, TimerWheelConfig};
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, BenchmarkId,
+    Criterion, Throughput,
+};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+const CONNECTIONS: usize = 50_000;
+
+fn addr(index: usize) -> SocketAddr {
+    SocketAddr::from((
+        [10, 0, (index >> 8) as u8, index as u8],
+        1884 + (index >> 16) as u16,
+    ))
+}
+
+/// Keep-alive of 10 to 70 seconds.
+fn delay(wheel: &TimerWheel<SocketAddr, u16>, index: usize) -> u64 {
+    wheel.ticks(Duration::from_secs(10 + (index % 61) as u64))
+}
+
+fn populated(config: TimerWheelConfig) -> TimerWheel<SocketAddr, u16> {
+    let mut wheel = TimerWheel::new(config);
+    for index in 0..CONNECTIONS {
+        let delay = delay(&wheel, index);
+        wheel.insert(addr(index), delay, 60);
+    }
+    wheel
+}
+
+fn bench_timer_wheel(c: &mut Criterion) {
+    let configs = [
+        ("100ms", TimerWheelConfig::default()),
+        (
+            "100ms_coalesced_1s",
+            TimerWheelConfig::new(
+                Duration::from_millis(100),
+                Duration::from_secs(1),
+            ),
+        ),
+    ];
+    let mut group = c.benchmark_group("timer_wheel");
+    group.throughput(Throughput::Elements(CONNECTIONS as u64));
+    for (name, config) in configs.iter() {
+        group.bench_with_input(
+            BenchmarkId::new("insert", name),
+            config,
+            |b, config| b.iter(|| black_box(populated(*config))),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("cancel", name),
+            config,
+            |b, config| {
+                b.iter_batched(
+                    || populated(*config),
+                    |mut wheel| {
+                        for index in 0..CONNECTIONS {
+                            black_box(wheel.cancel(&addr(index)));
+                        }
+                        wheel
+                    },
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("reschedule", name),
+            config,
+            |b, config| {
+                b.iter_batched(
+                    || populated(*config),
+                    |mut wheel| {
+                        for index in 0..CONNECTIONS {
+                            let delay = delay(&wheel, index) * 2;
+                            black_box(wheel.insert(addr(index), delay, 60));
+                        }
+                        wheel
+                    },
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("expire", name),
+            config,
+            |b, config| {
+                b.iter_batched(
+                    || populated(*config),
+                    |mut wheel| {
+                        while !wheel.is_empty() {
+                            black_box(wheel.advance());
+                        }
+                        wheel
+                    },
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_timer_wheel);
+criterion_main!(benches);
@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{advertise::Advertise, MSG_TYPE_ADVERTISE};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(Advertise::recv, MSG_TYPE_ADVERTISE, data);
+});
@@ -0,0 +1,72 @@
+// Each target uses a part of the module.
+#![allow(dead_code)]
+/// Feeds the fuzzer input to a message decoder.
+///
+/// The input is the body of the message, the header with the length and the
+/// message type of the decoder is prepended, MsgHeader::try_read() rejects
+/// the datagrams with a wrong length before the decoders see them.
+use broker_lib::{broker_lib::MqttSnClient, msg_hdr::MsgHeader};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use util::conn::conn_pipe::pipe;
+use util::conn::Conn;
+
+pub type Recv =
+    fn(&[u8], usize, &MqttSnClient, MsgHeader) -> Result<(), String>;
+
+thread_local! {
+    static CLIENT: MqttSnClient = MqttSnClient::new();
+}
+
+fn addr() -> SocketAddr {
+    "127.0.0.1:1884".parse::<SocketAddr>().unwrap()
+}
+
+fn conn() -> Arc<dyn Conn + Send + Sync> {
+    let (conn, _peer) = pipe();
+    Arc::new(conn)
+}
+
+/// Header and body, the 3-octet length if the message is 256 bytes or more.
+pub fn datagram(msg_type: u8, body: &[u8]) -> Bytes {
+    let mut bytes = BytesMut::with_capacity(body.len() + 4);
+    if let Err(_why) = MsgHeader::put_len(&mut bytes, body.len() + 2) {
+        return Bytes::new();
+    }
+    bytes.put_u8(msg_type);
+    bytes.put_slice(body);
+    bytes.freeze()
+}
+
+/// The errors are expected, a panic is a bug.
+pub fn recv(recv: Recv, msg_type: u8, body: &[u8]) {
+    let bytes = datagram(msg_type, body);
+    let msg_header =
+        match MsgHeader::try_read(&bytes, bytes.len(), addr(), conn()) {
+            Ok(msg_header) => msg_header,
+            Err(_why) => return,
+        };
+    CLIENT.with(|client| {
+        let _result = recv(&bytes, bytes.len(), client, msg_header);
+        drain(client);
+    });
+}
+
+/// The whole ingress path, the first byte is the message type.
+pub fn dispatch(data: &[u8]) {
+    let bytes = match data.split_first() {
+        Some((msg_type, body)) => datagram(*msg_type, body),
+        None => return,
+    };
+    CLIENT.with(|client| {
+        client.dispatch(addr(), bytes, conn());
+        drain(client);
+    });
+}
+
+/// The egress channels are unbounded.
+fn drain(client: &MqttSnClient) {
+    while client.egress_rx.try_recv().is_ok() {}
+    while client.egress_batch_rx.try_recv().is_ok() {}
+}
@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{conn_ack::ConnAck, MSG_TYPE_CONNACK};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(ConnAck::recv, MSG_TYPE_CONNACK, data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{connect::Connect, MSG_TYPE_CONNECT};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(Connect::recv, MSG_TYPE_CONNECT, data);
+});
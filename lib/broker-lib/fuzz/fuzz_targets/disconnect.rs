@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{disconnect::Disconnect, MSG_TYPE_DISCONNECT};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(Disconnect::recv, MSG_TYPE_DISCONNECT, data);
+});
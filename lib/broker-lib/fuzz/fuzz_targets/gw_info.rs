@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{gw_info::GwInfo, MSG_TYPE_GW_INFO};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(GwInfo::recv, MSG_TYPE_GW_INFO, data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{ping_req::PingReq, MSG_TYPE_PINGREQ};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(PingReq::recv, MSG_TYPE_PINGREQ, data);
+});
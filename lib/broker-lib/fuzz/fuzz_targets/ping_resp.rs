@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{ping_resp::PingResp, MSG_TYPE_PINGRESP};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(PingResp::recv, MSG_TYPE_PINGRESP, data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{pub_ack::PubAck, MSG_TYPE_PUBACK};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(PubAck::recv, MSG_TYPE_PUBACK, data);
+});
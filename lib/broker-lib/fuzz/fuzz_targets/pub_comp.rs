@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{pub_comp::PubComp, MSG_TYPE_PUBCOMP};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(PubComp::recv, MSG_TYPE_PUBCOMP, data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{pub_rec::PubRec, MSG_TYPE_PUBREC};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(PubRec::recv, MSG_TYPE_PUBREC, data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{pub_rel::PubRel, MSG_TYPE_PUBREL};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(PubRel::recv, MSG_TYPE_PUBREL, data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{publish::Publish, MSG_TYPE_PUBLISH};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(Publish::recv, MSG_TYPE_PUBLISH, data);
+});
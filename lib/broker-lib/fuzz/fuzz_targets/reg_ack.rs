@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{reg_ack::RegAck, MSG_TYPE_REGACK};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(RegAck::recv, MSG_TYPE_REGACK, data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{register::Register, MSG_TYPE_REGISTER};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(Register::recv, MSG_TYPE_REGISTER, data);
+});
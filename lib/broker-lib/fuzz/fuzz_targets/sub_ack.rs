@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{sub_ack::SubAck, MSG_TYPE_SUBACK};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(SubAck::recv, MSG_TYPE_SUBACK, data);
+});
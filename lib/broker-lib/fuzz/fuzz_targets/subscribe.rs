@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{subscribe::Subscribe, MSG_TYPE_SUBSCRIBE};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(Subscribe::recv, MSG_TYPE_SUBSCRIBE, data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{unsub_ack::UnsubAck, MSG_TYPE_UNSUBACK};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(UnsubAck::recv, MSG_TYPE_UNSUBACK, data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{unsubscribe::Unsubscribe, MSG_TYPE_UNSUBSCRIBE};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(Unsubscribe::recv, MSG_TYPE_UNSUBSCRIBE, data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{will_msg::WillMsg, MSG_TYPE_WILL_MSG};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(WillMsg::recv, MSG_TYPE_WILL_MSG, data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{will_msg_req::WillMsgReq, MSG_TYPE_WILL_MSG_REQ};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(WillMsgReq::recv, MSG_TYPE_WILL_MSG_REQ, data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{will_msg_resp::WillMsgResp, MSG_TYPE_WILL_MSG_RESP};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(WillMsgResp::recv, MSG_TYPE_WILL_MSG_RESP, data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{will_msg_upd::WillMsgUpd, MSG_TYPE_WILL_MSG_UPD};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(WillMsgUpd::recv, MSG_TYPE_WILL_MSG_UPD, data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{will_topic::WillTopic, MSG_TYPE_WILL_TOPIC};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(WillTopic::recv, MSG_TYPE_WILL_TOPIC, data);
+});
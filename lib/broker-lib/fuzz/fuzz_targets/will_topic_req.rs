@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{will_topic_req::WillTopicReq, MSG_TYPE_WILL_TOPIC_REQ};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(WillTopicReq::recv, MSG_TYPE_WILL_TOPIC_REQ, data);
+});
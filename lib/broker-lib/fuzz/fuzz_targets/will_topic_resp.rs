@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{will_topic_resp::WillTopicResp, MSG_TYPE_WILL_TOPIC_RESP};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(WillTopicResp::recv, MSG_TYPE_WILL_TOPIC_RESP, data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use broker_lib::{will_topic_upd::WillTopicUpd, MSG_TYPE_WILL_TOPIC_UPD};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::recv(WillTopicUpd::recv, MSG_TYPE_WILL_TOPIC_UPD, data);
+});
@@ -0,0 +1,444 @@
+/// Admin commands of a running broker, over a loopback TCP line protocol.
+///
+/// Admin::serve() accepts connections on a loopback address only, e.g. for
+/// `nc 127.0.0.1 1885`. A command is one line, the answer is zero or more
+/// lines followed by "OK", or by "ERR" and the reason:
+///
+/// clients                       address, state and client id
+/// disconnect <addr|client id>   end the session without the will
+/// ban <ip>                      drop the datagrams, disconnect the clients
+/// unban <ip>
+/// bans
+/// topics                        topic id, subscribers and topic name
+/// topic <id|name>               topic id and topic name
+/// retained [filter]             topic, QoS, length and payload, "#" default
+/// trace <id|name>               trace the next PUBLISH to the topic
+/// trace-report                  trace id, topic id, msg id, publisher and
+///                               number of events of each report
+/// trace-report <trace id>       time, address and stage of each event
+/// quit
+///
+/// Admin::execute() runs a command for other front ends. The datagrams of
+/// a banned address are dropped first thing in MqttSnClient::dispatch(),
+/// before a forwarder frame is unwrapped or a fragment is buffered, the
+/// bans aren't persisted.
+use bytes::Bytes;
+use hashbrown::{HashMap, HashSet};
+use log::*;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::RwLock;
+use std::thread;
+
+use crate::{
+    asleep_msg_cache::AsleepMsgCache,
+    broker_context::BrokerContext,
+    broker_lib::MqttSnClient,
+    client_id::ClientId,
+    connection::Connection,
+    connection_info::ConnectionInfo,
+    disconnect::Disconnect,
+    eformat,
+    filter::{
+        delete_subscribers_with_socket_addr, get_subscriptions, get_topic_id,
+        get_topic_name, get_topic_names,
+    },
+    function,
+    keep_alive::KeepAliveTimeWheel,
+    msg_trace::{MsgTrace, TraceId},
+    retain::Retain,
+    retransmit::RetransTimeWheel,
+    storage_backend::Persistence,
+    TopicIdType,
+};
+
+/// Banned addresses of a broker, see BrokerContext.
+#[derive(Default)]
+pub(crate) struct AdminState {
+    banned: RwLock<HashSet<IpAddr>>,
+}
+
+#[inline(always)]
+fn state() -> &'static AdminState {
+    &BrokerContext::current().admin
+}
+
+#[derive(Debug, Clone)]
+pub struct Admin {}
+
+impl Admin {
+    /// Serve the admin commands on socket_addr, a loopback address.
+    pub fn serve(
+        socket_addr: SocketAddr,
+        client: MqttSnClient,
+    ) -> Result<(), String> {
+        if !socket_addr.ip().is_loopback() {
+            return Err(eformat!(socket_addr, "not a loopback address"));
+        }
+        let listener = match TcpListener::bind(socket_addr) {
+            Ok(listener) => listener,
+            Err(why) => return Err(eformat!(socket_addr, why)),
+        };
+        let builder = thread::Builder::new().name("admin_thread".into());
+        let result = builder.spawn(move || {
+            let _context = client.context.enter();
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(why) = Admin::handle(stream, &client) {
+                            error!("{}", why);
+                        }
+                    }
+                    Err(why) => {
+                        error!("{}", eformat!(socket_addr, why));
+                    }
+                }
+            }
+        });
+        match result {
+            Ok(_admin_thread) => Ok(()),
+            Err(why) => Err(eformat!(socket_addr, why)),
+        }
+    }
+
+    fn handle(stream: TcpStream, client: &MqttSnClient) -> Result<(), String> {
+        let mut writer = stream.try_clone().map_err(|why| eformat!(why))?;
+        for line in BufReader::new(stream).lines() {
+            let line = line.map_err(|why| eformat!(why))?;
+            if line.trim() == "quit" {
+                break;
+            }
+            let answer = match Admin::execute(client, &line) {
+                Ok(mut lines) => {
+                    lines.push("OK".to_string());
+                    lines
+                }
+                Err(why) => vec![format!("ERR {}", why)],
+            };
+            for line in answer {
+                writeln!(writer, "{}", line).map_err(|why| eformat!(why))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Run one command line, the lines of the answer.
+    pub fn execute(
+        client: &MqttSnClient,
+        line: &str,
+    ) -> Result<Vec<String>, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["clients"] => Ok(Admin::clients()),
+            ["disconnect", target] => {
+                let socket_addrs = Admin::resolve(target)?;
+                for socket_addr in socket_addrs.iter() {
+                    Admin::disconnect(client, *socket_addr)?;
+                }
+                Ok(socket_addrs.iter().map(|addr| addr.to_string()).collect())
+            }
+            ["ban", ip] => {
+                let ip =
+                    ip.parse::<IpAddr>().map_err(|why| eformat!(ip, why))?;
+                Ok(Admin::ban(client, ip)
+                    .iter()
+                    .map(|addr| addr.to_string())
+                    .collect())
+            }
+            ["unban", ip] => {
+                let ip =
+                    ip.parse::<IpAddr>().map_err(|why| eformat!(ip, why))?;
+                if !Admin::unban(&ip) {
+                    return Err(eformat!(ip, "not banned"));
+                }
+                Ok(Vec::new())
+            }
+            ["bans"] => {
+                let mut banned: Vec<IpAddr> =
+                    state().banned.read().unwrap().iter().copied().collect();
+                banned.sort();
+                Ok(banned.iter().map(|ip| ip.to_string()).collect())
+            }
+            ["topics"] => Ok(Admin::topics()),
+            ["topic", target] => Admin::topic(target).map(|line| vec![line]),
+            ["retained"] => Ok(Admin::retained("#")),
+            ["retained", filter] => Ok(Admin::retained(filter)),
+            ["trace", target] => {
+                let (topic_id, topic_name) = Admin::resolve_topic(target)?;
+                MsgTrace::arm(topic_id);
+                Ok(vec![format!("{} {}", topic_id, topic_name)])
+            }
+            ["trace-report"] => Ok(Admin::trace_reports()),
+            ["trace-report", trace_id] => {
+                let trace_id = trace_id
+                    .parse::<TraceId>()
+                    .map_err(|why| eformat!(trace_id, why))?;
+                Admin::trace_report(trace_id)
+            }
+            _ => Err(eformat!(line.trim(), "unknown command")),
+        }
+    }
+
+    fn clients() -> Vec<String> {
+        ConnectionInfo::list()
+            .iter()
+            .map(|info| {
+                format!(
+                    "{} {:?} {}",
+                    info.socket_addr,
+                    info.state,
+                    String::from_utf8_lossy(&info.client_id)
+                )
+            })
+            .collect()
+    }
+
+    /// The connection of the address, or of the client id.
+    fn resolve(target: &str) -> Result<Vec<SocketAddr>, String> {
+        let socket_addrs = match target.parse::<SocketAddr>() {
+            Ok(socket_addr) => vec![socket_addr],
+            Err(_) => ClientId::get(&Bytes::from(target.to_string())),
+        };
+        let socket_addrs: Vec<SocketAddr> = socket_addrs
+            .into_iter()
+            .filter(|socket_addr| Connection::contains_key(*socket_addr))
+            .collect();
+        if socket_addrs.is_empty() {
+            return Err(eformat!(target, "no connection"));
+        }
+        Ok(socket_addrs)
+    }
+
+    /// End the session of the client, its subscriptions and buffered
+    /// messages are deleted and its will isn't published, so is its stored
+    /// session. The client is sent a DISCONNECT.
+    pub fn disconnect(
+        client: &MqttSnClient,
+        socket_addr: SocketAddr,
+    ) -> Result<(), String> {
+        let conn = Connection::remove(&socket_addr)?;
+        Persistence::forget(&conn.client_id);
+        ClientId::rev_delete(&socket_addr);
+        RetransTimeWheel::cancel_all(socket_addr);
+        let _result = KeepAliveTimeWheel::cancel(&socket_addr);
+        AsleepMsgCache::delete(socket_addr);
+        delete_subscribers_with_socket_addr(&socket_addr);
+        client.events.on_disconnect(socket_addr);
+        info!("{}", eformat!(socket_addr, "disconnected by the admin"));
+        Disconnect::send_to(client, socket_addr)
+    }
+
+    /// Ban the address and disconnect its clients, returns their
+    /// addresses.
+    pub fn ban(client: &MqttSnClient, ip: IpAddr) -> Vec<SocketAddr> {
+        state().banned.write().unwrap().insert(ip);
+        let socket_addrs: Vec<SocketAddr> = Connection::socket_addrs()
+            .into_iter()
+            .filter(|socket_addr| socket_addr.ip() == ip)
+            .collect();
+        for socket_addr in socket_addrs.iter() {
+            if let Err(why) = Admin::disconnect(client, *socket_addr) {
+                error!("{}", why);
+            }
+        }
+        socket_addrs
+    }
+
+    /// false if the address wasn't banned.
+    pub fn unban(ip: &IpAddr) -> bool {
+        state().banned.write().unwrap().remove(ip)
+    }
+
+    #[inline(always)]
+    pub fn is_banned(ip: &IpAddr) -> bool {
+        let banned = state().banned.read().unwrap();
+        !banned.is_empty() && banned.contains(ip)
+    }
+
+    /// The topic names and filters, with the number of subscribers.
+    fn topics() -> Vec<String> {
+        let mut counts: HashMap<TopicIdType, usize> = HashMap::new();
+        for (_addr, topic_id, _qos) in get_subscriptions() {
+            *counts.entry(topic_id).or_default() += 1;
+        }
+        let mut topics = get_topic_names();
+        topics.sort_by_key(|(_topic_name, topic_id)| *topic_id);
+        topics
+            .iter()
+            .map(|(topic_name, topic_id)| {
+                let count = counts.get(topic_id).copied().unwrap_or(0);
+                format!("{} {} {}", topic_id, count, topic_name)
+            })
+            .collect()
+    }
+
+    /// The topic of the topic id, or of the topic name or filter.
+    fn topic(target: &str) -> Result<String, String> {
+        let (topic_id, topic_name) = Admin::resolve_topic(target)?;
+        Ok(format!("{} {}", topic_id, topic_name))
+    }
+
+    fn resolve_topic(target: &str) -> Result<(TopicIdType, String), String> {
+        let topic = match target.parse::<TopicIdType>() {
+            Ok(topic_id) => get_topic_name(topic_id)
+                .map(|topic_name| (topic_id, topic_name)),
+            Err(_) => get_topic_id(target)
+                .map(|topic_id| (topic_id, target.to_string())),
+        };
+        topic.ok_or_else(|| eformat!(target, "unknown topic"))
+    }
+
+    fn trace_reports() -> Vec<String> {
+        MsgTrace::reports()
+            .iter()
+            .map(|report| {
+                format!(
+                    "{} {} {} {} {}",
+                    report.trace_id,
+                    report.topic_id,
+                    report.msg_id,
+                    report.publisher,
+                    report.events.len()
+                )
+            })
+            .collect()
+    }
+
+    fn trace_report(trace_id: TraceId) -> Result<Vec<String>, String> {
+        let report = match MsgTrace::report(trace_id) {
+            Some(report) => report,
+            None => return Err(eformat!(trace_id, "unknown trace")),
+        };
+        Ok(report
+            .events
+            .iter()
+            .map(|event| {
+                format!(
+                    "{} {} {:?}",
+                    event.time.format("%H:%M:%S%.3f"),
+                    event.addr,
+                    event.stage
+                )
+            })
+            .collect())
+    }
+
+    fn retained(filter: &str) -> Vec<String> {
+        let mut retained = Retain::matching(filter);
+        retained.sort_by(|(topic, _), (other, _)| topic.cmp(other));
+        retained
+            .iter()
+            .map(|(topic, retain)| {
+                format!(
+                    "{} {} {} {}",
+                    topic,
+                    retain.qos,
+                    retain.payload.len(),
+                    String::from_utf8_lossy(&retain.payload).escape_debug()
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_admin_commands() {
+        use super::*;
+        use crate::annotation::Annotations;
+        use crate::conn::udp_conn;
+        use crate::flags::{QOS_LEVEL_0, RETAIN_FALSE};
+        use crate::forwarder::Forwarder;
+        use crate::test_support::{LoopbackBroker, TestClient};
+        use crate::MSG_TYPE_PINGREQ;
+        let client = MqttSnClient::new();
+        let mut test_client = TestClient::new(LoopbackBroker::addr()).unwrap();
+        test_client.connect("adminTest", 60, None).unwrap();
+        let (topic_id, _) =
+            test_client.subscribe("admin/temp", QOS_LEVEL_0).unwrap();
+        let addr = test_client.local_addr();
+        let clients = Admin::execute(&client, "clients").unwrap();
+        let line = format!("{} ACTIVE adminTest", addr);
+        assert!(clients.contains(&line));
+        let topics = Admin::execute(&client, "topics").unwrap();
+        let line = format!("{} 1 admin/temp", topic_id);
+        assert!(topics.contains(&line));
+        let line = format!("{} admin/temp", topic_id);
+        assert_eq!(
+            Admin::execute(&client, &format!("topic {}", topic_id)),
+            Ok(vec![line.clone()])
+        );
+        assert_eq!(Admin::execute(&client, "topic admin/temp"), Ok(vec![line]));
+        assert!(Admin::execute(&client, "topic admin/none").is_err());
+        Retain::insert(
+            QOS_LEVEL_0,
+            topic_id,
+            0,
+            Bytes::from("21.5"),
+            Annotations::new(),
+        );
+        assert_eq!(
+            Admin::execute(&client, "retained admin/#"),
+            Ok(vec!["admin/temp 0 4 21.5".to_string()])
+        );
+        Retain::remove(topic_id);
+        // The next PUBLISH to the topic is traced.
+        assert_eq!(
+            Admin::execute(&client, "trace admin/temp"),
+            Ok(vec![format!("{} admin/temp", topic_id)])
+        );
+        assert!(Admin::execute(&client, "trace admin/none").is_err());
+        test_client
+            .publish(topic_id, QOS_LEVEL_0, RETAIN_FALSE, b"traced")
+            .unwrap();
+        assert_eq!(&test_client.recv_publish().unwrap().payload[..], b"traced");
+        let reports = Admin::execute(&client, "trace-report").unwrap();
+        let suffix = format!(" {} 0 {} ", topic_id, addr);
+        let trace_id = reports
+            .iter()
+            .find(|line| line.contains(&suffix))
+            .and_then(|line| line.split(' ').next())
+            .unwrap()
+            .to_string();
+        let events =
+            Admin::execute(&client, &format!("trace-report {}", trace_id))
+                .unwrap();
+        assert!(events[0].ends_with(&format!("{} Decode", addr)));
+        assert!(events.iter().any(|line| line.ends_with("Egress")));
+        assert!(Admin::execute(&client, "trace-report x").is_err());
+        // By client id.
+        assert_eq!(
+            Admin::execute(&client, "disconnect adminTest"),
+            Ok(vec![addr.to_string()])
+        );
+        assert!(!Connection::contains_key(addr));
+        assert!(Admin::execute(&client, "disconnect adminTest").is_err());
+        let topics = Admin::execute(&client, "topics").unwrap();
+        let line = format!("{} 0 admin/temp", topic_id);
+        assert!(topics.contains(&line));
+        // The bans.
+        let ip = "10.9.0.1".parse::<IpAddr>().unwrap();
+        assert_eq!(Admin::execute(&client, "ban 10.9.0.1"), Ok(vec![]));
+        assert!(Admin::is_banned(&ip));
+        // A banned forwarder gets no virtual address for its nodes.
+        let forwarder = "10.9.0.1:2000".parse::<SocketAddr>().unwrap();
+        let frame =
+            Bytes::from_static(&[4, 0xFE, 0, 0x0D, 2, MSG_TYPE_PINGREQ]);
+        client.dispatch(forwarder, frame.clone(), udp_conn());
+        assert!(Forwarder::node_addr(forwarder, &[0x0D]).is_none());
+        assert_eq!(
+            Admin::execute(&client, "bans"),
+            Ok(vec!["10.9.0.1".to_string()])
+        );
+        assert_eq!(Admin::execute(&client, "unban 10.9.0.1"), Ok(vec![]));
+        assert!(!Admin::is_banned(&ip));
+        client.dispatch(forwarder, frame, udp_conn());
+        assert!(Forwarder::node_addr(forwarder, &[0x0D]).is_some());
+        assert!(Admin::execute(&client, "unban 10.9.0.1").is_err());
+        assert!(Admin::execute(&client, "ban nowhere").is_err());
+        assert!(Admin::execute(&client, "reboot").is_err());
+        let any = "0.0.0.0:0".parse::<SocketAddr>().unwrap();
+        assert!(Admin::serve(any, client).is_err());
+    }
+}
@@ -11,8 +11,8 @@ Table 6:
 • Duration: time interval until the next ADVERTISE is broadcasted by this gateway
 */
 use crate::{
-    broker_lib::MqttSnClient, msg_hdr::MsgHeader, multicast, MSG_LEN_ADVERTISE,
-    MSG_TYPE_ADVERTISE,
+    broker_lib::MqttSnClient, codec, decode::Decode, function,
+    msg_hdr::MsgHeader, multicast,
 };
 use bytes::{BufMut, BytesMut};
 use custom_debug::Debug;
@@ -32,20 +32,16 @@ pub struct Advertise {
     pub gw_id: u8,
     pub duration: u16,
 }
+
+codec!(Advertise, {
+    gw_id: u8,
+    duration: u16,
+});
+
 impl Advertise {
     pub fn run(socket_addr: SocketAddr, gw_id: u8, duration: u16) {
-        let duration_0 = (duration >> 8) as u8;
-        let duration_1 = duration as u8;
-        let mut bytes = BytesMut::with_capacity(MSG_LEN_ADVERTISE as usize);
-        let buf: &[u8] = &[
-            MSG_LEN_ADVERTISE,
-            MSG_TYPE_ADVERTISE,
-            gw_id,
-            duration_0,
-            duration_1,
-        ];
-        bytes.put(buf);
-        dbg!(&buf);
+        let bytes = Advertise::encode(gw_id, duration);
+        dbg!(&bytes);
         multicast::broadcast_loop(bytes.freeze(), socket_addr, duration);
     }
     pub fn recv(
@@ -55,7 +51,7 @@ impl Advertise {
         msg_header: MsgHeader,
     ) -> Result<(), String> {
         let (advertise, _read_fixed_len) =
-            Advertise::try_read(buf, size).unwrap();
+            Decode::read(Advertise::try_read(buf, size), size, &msg_header)?;
         info!(
             "{}: advertise {} with {} id",
             msg_header.remote_socket_addr, advertise.gw_id, advertise.duration
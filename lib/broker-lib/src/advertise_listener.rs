@@ -0,0 +1,221 @@
+/// Client side tracking of the active gateways from their ADVERTISE.
+///
+/// AdvertiseListener::spawn() joins the ADVERTISE group and keeps a
+/// GatewayTable from the advertise_listener_thread. A gateway is added by
+/// its first ADVERTISE and expires after missed intervals without one, the
+/// interval is the Duration of its last ADVERTISE. The table sends a
+/// GatewayEvent for each gateway added or expired, e.g. for the embedder to
+/// fail over to another gateway. The thread ends when the receiver of the
+/// events is dropped.
+use crossbeam::channel::Sender;
+use hashbrown::HashMap;
+use log::*;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{
+    advertise::Advertise, eformat, function, multicast::multicast_bind,
+};
+
+/// ADVERTISE intervals missed before a gateway expires, N_adv of the spec.
+pub const ADVERTISE_MISSED_MAX: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActiveGateway {
+    pub gw_id: u8,
+    /// Source address of the last ADVERTISE.
+    pub addr: SocketAddr,
+    /// Duration of the last ADVERTISE, in seconds.
+    pub duration: u16,
+    pub last_seen: Instant,
+}
+
+impl ActiveGateway {
+    fn expires_at(&self, missed: u32) -> Instant {
+        let interval = Duration::from_secs(u64::max(self.duration as u64, 1));
+        self.last_seen + interval * missed
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GatewayEvent {
+    Added(ActiveGateway),
+    Expired(ActiveGateway),
+}
+
+/// The gateways with an ADVERTISE in the last missed intervals.
+#[derive(Debug)]
+pub struct GatewayTable {
+    gateways: HashMap<u8, ActiveGateway>,
+    missed: u32,
+    tx: Sender<GatewayEvent>,
+}
+
+impl GatewayTable {
+    pub fn new(missed: u32, tx: Sender<GatewayEvent>) -> Self {
+        GatewayTable {
+            gateways: HashMap::new(),
+            missed: u32::max(missed, 1),
+            tx,
+        }
+    }
+
+    /// Record an ADVERTISE, sends Added for a new gateway. Returns false
+    /// if the receiver of the events is dropped.
+    pub fn advertise(
+        &mut self,
+        gw_id: u8,
+        addr: SocketAddr,
+        duration: u16,
+        now: Instant,
+    ) -> bool {
+        let gateway = ActiveGateway {
+            gw_id,
+            addr,
+            duration,
+            last_seen: now,
+        };
+        match self.gateways.insert(gw_id, gateway.clone()) {
+            Some(_previous) => true,
+            None => self.tx.send(GatewayEvent::Added(gateway)).is_ok(),
+        }
+    }
+
+    /// Remove the gateways without an ADVERTISE for missed intervals,
+    /// sends Expired for each. Returns false if the receiver of the events
+    /// is dropped.
+    pub fn expire(&mut self, now: Instant) -> bool {
+        let missed = self.missed;
+        let expired: Vec<u8> = self
+            .gateways
+            .values()
+            .filter(|gateway| gateway.expires_at(missed) <= now)
+            .map(|gateway| gateway.gw_id)
+            .collect();
+        let mut connected = true;
+        for gw_id in expired {
+            if let Some(gateway) = self.gateways.remove(&gw_id) {
+                info!("{}", eformat!(gateway.addr, "gateway expired", gw_id));
+                connected &=
+                    self.tx.send(GatewayEvent::Expired(gateway)).is_ok();
+            }
+        }
+        connected
+    }
+
+    pub fn get(&self, gw_id: u8) -> Option<ActiveGateway> {
+        self.gateways.get(&gw_id).cloned()
+    }
+
+    /// The active gateways, by gw_id.
+    pub fn list(&self) -> Vec<ActiveGateway> {
+        let mut gateways: Vec<ActiveGateway> =
+            self.gateways.values().cloned().collect();
+        gateways.sort_by_key(|gateway| gateway.gw_id);
+        gateways
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AdvertiseListener {}
+
+impl AdvertiseListener {
+    /// Listen to the ADVERTISE of multicast_addr, see
+    /// multicast::multicast_groups(). Returns the table shared with the
+    /// advertise_listener_thread.
+    pub fn spawn(
+        multicast_addr: SocketAddr,
+        missed: u32,
+        tx: Sender<GatewayEvent>,
+    ) -> Result<Arc<Mutex<GatewayTable>>, String> {
+        let socket = multicast_bind(multicast_addr)
+            .map_err(|why| eformat!(multicast_addr, why))?;
+        let table = Arc::new(Mutex::new(GatewayTable::new(missed, tx)));
+        let thread_table = table.clone();
+        let builder =
+            thread::Builder::new().name("advertise_listener_thread".into());
+        let result = builder.spawn(move || {
+            let mut buf = [0u8; 64]; // receive buffer
+            loop {
+                let now = Instant::now();
+                let connected = match socket.recv_from(&mut buf) {
+                    Ok((size, remote_addr)) => {
+                        match Advertise::decode(&buf, size) {
+                            Ok((gw_id, duration)) => thread_table
+                                .lock()
+                                .unwrap()
+                                .advertise(gw_id, remote_addr, duration, now),
+                            Err(why) => {
+                                error!("{}", eformat!(remote_addr, why));
+                                true
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        // The socket read timeout, keep looping.
+                        if err.kind() != io::ErrorKind::WouldBlock
+                            && err.kind() != io::ErrorKind::TimedOut
+                        {
+                            error!("{}", eformat!(multicast_addr, err));
+                        }
+                        true
+                    }
+                };
+                if !connected || !thread_table.lock().unwrap().expire(now) {
+                    break;
+                }
+            }
+        });
+        match result {
+            Ok(_advertise_listener_thread) => Ok(table),
+            Err(why) => Err(eformat!(multicast_addr, why)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_gateway_table() {
+        use super::*;
+        use crossbeam::channel::unbounded;
+        let (tx, rx) = unbounded();
+        let mut table = GatewayTable::new(ADVERTISE_MISSED_MAX, tx);
+        let addr = "10.0.0.1:61000".parse::<SocketAddr>().unwrap();
+        let other = "10.0.0.2:61000".parse::<SocketAddr>().unwrap();
+        let start = Instant::now();
+        let secs = |secs| start + Duration::from_secs(secs);
+        assert!(table.advertise(1, addr, 10, start));
+        assert!(table.advertise(2, other, 60, start));
+        // Seen again, no event.
+        assert!(table.advertise(1, addr, 10, secs(20)));
+        let added: Vec<GatewayEvent> = rx.try_iter().collect();
+        assert_eq!(added.len(), 2);
+        assert!(matches!(&added[0], GatewayEvent::Added(gw) if gw.gw_id == 1));
+        // Gateway 1 expires 3 intervals after its last ADVERTISE.
+        assert!(table.expire(secs(49)));
+        assert!(rx.try_recv().is_err());
+        assert!(table.expire(secs(50)));
+        match rx.try_recv() {
+            Ok(GatewayEvent::Expired(gw)) => {
+                assert_eq!(
+                    (gw.gw_id, gw.addr, gw.last_seen),
+                    (1, addr, secs(20))
+                )
+            }
+            event => panic!("{:?}", event),
+        }
+        let ids: Vec<u8> = table.list().iter().map(|gw| gw.gw_id).collect();
+        assert_eq!(ids, vec![2]);
+        assert_eq!(table.get(1), None);
+        // The receiver is gone.
+        drop(rx);
+        assert!(!table.advertise(3, addr, 10, secs(60)));
+        assert!(!table.expire(secs(1000)));
+        let encoded = Advertise::encode(5, 900);
+        assert_eq!(Advertise::decode(&encoded, encoded.len()), Ok((5, 900)));
+    }
+}
@@ -0,0 +1,368 @@
+/// Alerting rules evaluated in the publish pipeline, so a gateway can act
+/// locally when the backhaul is down.
+///
+/// A rule fires when the numeric payload of a topic stays above (or below)
+/// a threshold for for_sec seconds. The rule is evaluated when a message
+/// is received and every ALERT_TICK_MS by the thread of AlertRules::run(),
+/// a sensor reporting less often than for_sec still fires on time. The
+/// rule fires once, and is re-armed when the value goes back. The rules
+/// are loaded per broker, see BrokerContext.
+///
+/// Rules are loaded from TOML:
+///
+/// [[rule]]
+/// name = "temp_high"
+/// topic = "sensors/temp"
+/// above = 30.0            # or below = 5.0
+/// for_sec = 10
+/// action = "publish"      # "log", "publish" or "webhook"
+/// alarm_topic = "alarms/temp"
+/// webhook = "http://10.0.0.1:8080/alarm"
+use bytes::{BufMut, BytesMut};
+use log::*;
+use serde::Deserialize;
+use std::io::Write;
+use std::net::TcpStream;
+use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{
+    annotation::Annotations,
+    broker_context::BrokerContext,
+    broker_lib::MqttSnClient,
+    eformat,
+    filter::{get_subscribers_with_topic_id, try_insert_topic_name},
+    flags::{QOS_LEVEL_0, RETAIN_FALSE},
+    function,
+    publish::Publish,
+    TopicIdType,
+};
+
+pub const WEBHOOK_TIMEOUT_MS: u64 = 2000;
+/// Interval of the evaluation of the rules waiting for for_sec.
+pub const ALERT_TICK_MS: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertAction {
+    Log,
+    Publish,
+    Webhook,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AlertRule {
+    pub name: String,
+    pub topic: String,
+    pub above: Option<f64>,
+    pub below: Option<f64>,
+    #[serde(default)]
+    pub for_sec: u64,
+    pub action: AlertAction,
+    pub alarm_topic: Option<String>,
+    pub webhook: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AlertConfig {
+    #[serde(default)]
+    rule: Vec<AlertRule>,
+}
+
+#[derive(Debug, Clone)]
+struct RuleState {
+    rule: AlertRule,
+    topic_id: TopicIdType,
+    alarm_topic_id: Option<TopicIdType>,
+    /// Time the value crossed the threshold.
+    since: Option<Instant>,
+    /// Last value received, reported when the rule fires.
+    value: f64,
+    fired: bool,
+}
+
+/// Alerting rules of a broker, see BrokerContext.
+#[derive(Default)]
+pub(crate) struct AlertState {
+    enabled: AtomicBool,
+    rules: Mutex<Vec<RuleState>>,
+}
+
+#[inline(always)]
+fn state() -> &'static AlertState {
+    &BrokerContext::current().alert
+}
+
+#[derive(Debug, Clone)]
+pub struct AlertRules {}
+
+impl AlertRules {
+    /// Replace the rules with the rules in the TOML string.
+    pub fn load(toml_str: &str) -> Result<usize, String> {
+        let config: AlertConfig =
+            toml::from_str(toml_str).map_err(|why| eformat!(why))?;
+        let mut state_vec = Vec::with_capacity(config.rule.len());
+        for rule in config.rule {
+            if rule.above.is_none() == rule.below.is_none() {
+                return Err(eformat!(rule.name, "needs one of above/below"));
+            }
+            let alarm_topic_id = match (&rule.action, &rule.alarm_topic) {
+                (AlertAction::Publish, Some(alarm_topic)) => {
+                    Some(try_insert_topic_name(alarm_topic.clone())?)
+                }
+                (AlertAction::Publish, None) => {
+                    return Err(eformat!(rule.name, "alarm_topic missing"))
+                }
+                _ => None,
+            };
+            if rule.action == AlertAction::Webhook && rule.webhook.is_none() {
+                return Err(eformat!(rule.name, "webhook missing"));
+            }
+            state_vec.push(RuleState {
+                topic_id: try_insert_topic_name(rule.topic.clone())?,
+                alarm_topic_id,
+                rule,
+                since: None,
+                value: 0.0,
+                fired: false,
+            });
+        }
+        let len = state_vec.len();
+        state().enabled.store(len > 0, Ordering::Relaxed);
+        *state().rules.lock().unwrap() = state_vec;
+        Ok(len)
+    }
+    pub fn load_file(path: &str) -> Result<usize, String> {
+        match std::fs::read_to_string(path) {
+            Ok(toml_str) => AlertRules::load(&toml_str),
+            Err(why) => Err(eformat!(path, why)),
+        }
+    }
+    #[inline(always)]
+    pub fn is_empty() -> bool {
+        !state().enabled.load(Ordering::Relaxed)
+    }
+
+    /// Evaluate the rules for a received message.
+    #[inline(always)]
+    pub fn evaluate(topic_id: TopicIdType, data: &[u8], client: &MqttSnClient) {
+        if AlertRules::is_empty() {
+            return;
+        }
+        AlertRules::evaluate_at(topic_id, data, Instant::now(), client);
+    }
+
+    fn evaluate_at(
+        topic_id: TopicIdType,
+        data: &[u8],
+        now: Instant,
+        client: &MqttSnClient,
+    ) {
+        let value = match str::from_utf8(data)
+            .ok()
+            .and_then(|text| text.trim().parse::<f64>().ok())
+        {
+            Some(value) => value,
+            None => return, // not a numeric payload
+        };
+        for state in state().rules.lock().unwrap().iter_mut() {
+            if state.topic_id != topic_id {
+                continue;
+            }
+            let crossed = match (state.rule.above, state.rule.below) {
+                (Some(above), _) => value > above,
+                (None, Some(below)) => value < below,
+                (None, None) => false,
+            };
+            state.value = value;
+            if crossed {
+                state.since.get_or_insert(now);
+            } else {
+                // back to normal, re-arm the rule.
+                state.since = None;
+                state.fired = false;
+            }
+        }
+        AlertRules::fire_pending(now, client);
+    }
+
+    /// Fire the rules above (or below) their threshold for for_sec.
+    fn fire_pending(now: Instant, client: &MqttSnClient) {
+        let mut fired_vec = Vec::new();
+        for state in state().rules.lock().unwrap().iter_mut() {
+            let since = match state.since {
+                Some(since) if !state.fired => since,
+                _ => continue,
+            };
+            if now.saturating_duration_since(since)
+                >= Duration::from_secs(state.rule.for_sec)
+            {
+                state.fired = true;
+                fired_vec.push(state.clone());
+            }
+        }
+        // Run the actions without the lock.
+        for state in fired_vec {
+            AlertRules::fire(&state, client);
+        }
+    }
+
+    /// Evaluate the rules waiting for for_sec every ALERT_TICK_MS, between
+    /// the messages of their topics.
+    pub fn run(client: MqttSnClient) {
+        let builder = thread::Builder::new().name("alert_thread".into());
+        let _alert_thread = builder.spawn(move || {
+            let _context = client.context.enter();
+            loop {
+                thread::sleep(Duration::from_millis(ALERT_TICK_MS));
+                if !AlertRules::is_empty() {
+                    AlertRules::fire_pending(Instant::now(), &client);
+                }
+            }
+        });
+    }
+
+    fn fire(state: &RuleState, client: &MqttSnClient) {
+        let rule = &state.rule;
+        let value = state.value;
+        let text = format!("{},{},{}", rule.name, rule.topic, value);
+        match rule.action {
+            AlertAction::Log => {
+                warn!("alert {}: {} = {}", rule.name, rule.topic, value);
+            }
+            AlertAction::Publish => {
+                if let Some(alarm_topic_id) = state.alarm_topic_id {
+                    let mut data = BytesMut::new();
+                    data.put(text.as_bytes());
+                    let publish = Publish::new(
+                        alarm_topic_id,
+                        0,
+                        QOS_LEVEL_0,
+                        RETAIN_FALSE,
+                        data,
+                    );
+                    if let Err(why) = Publish::send_msg_to_subscribers(
+                        get_subscribers_with_topic_id(alarm_topic_id),
+                        publish,
+                        &Annotations::new(),
+                        client,
+                    ) {
+                        error!("{}", why);
+                    }
+                }
+            }
+            AlertAction::Webhook => {
+                if let Some(url) = rule.webhook.clone() {
+                    let context = client.context;
+                    // Don't block the publish pipeline.
+                    let _webhook_thread = thread::spawn(move || {
+                        let _context = context.enter();
+                        if let Err(why) = AlertRules::post(&url, &text) {
+                            error!("{}", why);
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    /// Minimal HTTP POST, only http://host:port/path is supported.
+    fn post(url: &str, body: &str) -> Result<(), String> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| eformat!(url, "only http:// is supported"))?;
+        let (host, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+        let addr = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:80", host)
+        };
+        let mut stream =
+            TcpStream::connect(&addr).map_err(|why| eformat!(url, why))?;
+        let timeout = Some(Duration::from_millis(WEBHOOK_TIMEOUT_MS));
+        let _result = stream.set_write_timeout(timeout);
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\n\
+             Content-Type: text/plain\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            path,
+            host,
+            body.len(),
+            body
+        );
+        stream
+            .write_all(request.as_bytes())
+            .map_err(|why| eformat!(url, why))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_alert_rules() {
+        use super::*;
+        let toml_str = r#"
+            [[rule]]
+            name = "temp_high"
+            topic = "alert/test/temp"
+            above = 30.0
+            for_sec = 0
+            action = "log"
+        "#;
+        assert_eq!(AlertRules::load(toml_str), Ok(1));
+        let context = BrokerContext::new();
+        let _context = context.enter();
+        let client = MqttSnClient::new().with_context(context);
+        let topic_id =
+            try_insert_topic_name("alert/test/temp".to_string()).unwrap();
+        let fired = || state().rules.lock().unwrap()[0].fired;
+        AlertRules::evaluate(topic_id, b"25.0", &client);
+        assert!(!fired());
+        AlertRules::evaluate(topic_id, b"31.5", &client);
+        assert!(fired());
+        // Back to normal, re-armed.
+        AlertRules::evaluate(topic_id, b"20", &client);
+        assert!(!fired());
+
+        // Fires after for_sec without another message.
+        let toml_str = r#"
+            [[rule]]
+            name = "temp_high_for"
+            topic = "alert/test/temp"
+            above = 30.0
+            for_sec = 60
+            action = "log"
+        "#;
+        assert_eq!(AlertRules::load(toml_str), Ok(1));
+        let now = Instant::now();
+        AlertRules::evaluate_at(topic_id, b"31.5", now, &client);
+        assert!(!fired());
+        AlertRules::fire_pending(now + Duration::from_secs(30), &client);
+        assert!(!fired());
+        AlertRules::fire_pending(now + Duration::from_secs(60), &client);
+        assert!(fired());
+        assert_eq!(state().rules.lock().unwrap()[0].value, 31.5);
+        // Back to normal before for_sec, not fired.
+        AlertRules::evaluate_at(topic_id, b"20", now, &client);
+        AlertRules::evaluate_at(topic_id, b"31.5", now, &client);
+        AlertRules::evaluate_at(topic_id, b"29", now, &client);
+        AlertRules::fire_pending(now + Duration::from_secs(60), &client);
+        assert!(!fired());
+
+        let toml_str = r#"
+            [[rule]]
+            name = "no_threshold"
+            topic = "alert/test/temp"
+            action = "log"
+        "#;
+        assert!(AlertRules::load(toml_str).is_err());
+        assert_eq!(AlertRules::load(""), Ok(0));
+        assert!(AlertRules::is_empty());
+    }
+}
@@ -0,0 +1,188 @@
+/// Annotations are key-value metadata attached to an in-flight PUBLISH
+/// message by hooks, e.g. tenant=acme, schema=v2.
+/// They are computed once when the message is received, and carried with
+/// the message through the retain, QoS 2 cache, fan-out and bridge stages,
+/// so later stages don't have to re-parse the payload. The library
+/// consumers and the connectors get them with the message, see
+/// RichPublish and ConnectorRecord.
+/// Annotations are never sent to the clients, only to the cluster peers.
+/// The hooks and predicates are registered per broker, see BrokerContext.
+use hashbrown::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use crate::{
+    broker_context::BrokerContext, filter::Subscriber, publish::Publish,
+};
+
+/// Hook called for every received PUBLISH message.
+/// The hook can insert, modify or remove annotations.
+pub type AnnotationHook = fn(&SocketAddr, &Publish, &mut Annotations);
+
+/// Subscription predicate, return false to skip the subscriber
+/// for this message.
+pub type SubscriberPredicate = fn(&Subscriber, &Annotations) -> bool;
+
+/// Hooks and predicates of a broker, see BrokerContext.
+#[derive(Default)]
+pub(crate) struct AnnotationState {
+    hooks: Mutex<Vec<AnnotationHook>>,
+    predicates: Mutex<Vec<SubscriberPredicate>>,
+}
+
+#[inline(always)]
+fn state() -> &'static AnnotationState {
+    &BrokerContext::current().annotation
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Annotations {
+    map: HashMap<String, String>,
+}
+
+impl Annotations {
+    pub fn new() -> Self {
+        Annotations {
+            map: HashMap::new(),
+        }
+    }
+    /// Insert an annotation, returns the old value if the key exists.
+    pub fn insert(&mut self, key: &str, val: &str) -> Option<String> {
+        self.map.insert(key.to_string(), val.to_string())
+    }
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.map.get(key)
+    }
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.map.remove(key)
+    }
+    pub fn contains(&self, key: &str, val: &str) -> bool {
+        match self.map.get(key) {
+            Some(v) => v == val,
+            None => false,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.map.iter()
+    }
+
+    /// Register a hook, hooks are run in the order of registration.
+    pub fn register_hook(hook: AnnotationHook) {
+        state().hooks.lock().unwrap().push(hook);
+    }
+    /// Register a predicate, all predicates must return true for a
+    /// subscriber to receive the message.
+    pub fn register_predicate(predicate: SubscriberPredicate) {
+        state().predicates.lock().unwrap().push(predicate);
+    }
+    /// No hooks or predicates, every message has empty annotations.
+    #[inline(always)]
+    pub fn has_hooks() -> bool {
+        !state().hooks.lock().unwrap().is_empty()
+            || !state().predicates.lock().unwrap().is_empty()
+    }
+    pub fn clear_hooks() {
+        state().hooks.lock().unwrap().clear();
+        state().predicates.lock().unwrap().clear();
+    }
+
+    /// Run all registered hooks on a received message.
+    #[inline(always)]
+    pub fn run_hooks(remote_addr: &SocketAddr, publish: &Publish) -> Self {
+        let mut annotations = Annotations::new();
+        // Copy the hooks, so a hook can register other hooks without
+        // deadlock.
+        let hooks = state().hooks.lock().unwrap().clone();
+        for hook in hooks {
+            hook(remote_addr, publish, &mut annotations);
+        }
+        annotations
+    }
+
+    /// Remove the subscribers rejected by the predicates.
+    #[inline(always)]
+    pub fn filter_subscribers(
+        &self,
+        subscriber_vec: Vec<Subscriber>,
+    ) -> Vec<Subscriber> {
+        let predicates = state().predicates.lock().unwrap().clone();
+        if predicates.is_empty() {
+            return subscriber_vec;
+        }
+        subscriber_vec
+            .into_iter()
+            .filter(|subscriber| {
+                predicates
+                    .iter()
+                    .all(|predicate| predicate(subscriber, self))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_annotations() {
+        use super::Annotations;
+        use crate::broker_context::BrokerContext;
+        use crate::filter::Subscriber;
+        use crate::publish::Publish;
+        use bytes::BytesMut;
+        use std::net::SocketAddr;
+
+        fn tenant_hook(
+            _addr: &SocketAddr,
+            publish: &Publish,
+            annotations: &mut Annotations,
+        ) {
+            if *publish.topic_id() == 7 {
+                annotations.insert("tenant", "acme");
+            }
+        }
+        fn tenant_predicate(
+            subscriber: &Subscriber,
+            annotations: &Annotations,
+        ) -> bool {
+            // only 127.0.0.1 belongs to acme.
+            !annotations.contains("tenant", "acme")
+                || subscriber.socket_addr.ip().to_string() == "127.0.0.1"
+        }
+        // Not seen by the brokers of the other tests.
+        let _context = BrokerContext::new().enter();
+        Annotations::register_hook(tenant_hook);
+        Annotations::register_predicate(tenant_predicate);
+
+        let socket = "127.0.0.1:1200".parse::<SocketAddr>().unwrap();
+        let socket2 = "127.0.0.2:1200".parse::<SocketAddr>().unwrap();
+        let bytes = BytesMut::from(&b"hello"[..]);
+        let publish = Publish::new(7, 1, 0, 0, bytes.clone());
+        let annotations = Annotations::run_hooks(&socket, &publish);
+        assert_eq!(annotations.get("tenant"), Some(&"acme".to_string()));
+        let subscriber_vec = vec![
+            Subscriber {
+                socket_addr: socket,
+                qos: 0,
+            },
+            Subscriber {
+                socket_addr: socket2,
+                qos: 0,
+            },
+        ];
+        let result = annotations.filter_subscribers(subscriber_vec.clone());
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].socket_addr, socket);
+
+        // No annotation for other topics, all subscribers get the message.
+        let publish = Publish::new(8, 1, 0, 0, bytes);
+        let annotations = Annotations::run_hooks(&socket, &publish);
+        assert!(annotations.is_empty());
+        let result = annotations.filter_subscribers(subscriber_vec);
+        assert_eq!(result.len(), 2);
+        Annotations::clear_hooks();
+        assert!(!Annotations::has_hooks());
+    }
+}
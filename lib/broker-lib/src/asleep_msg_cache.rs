@@ -1,30 +1,144 @@
-use crate::publish::Publish;
-use bisetmap::BisetMap;
+use crate::{
+    broker_lib::MqttSnClient,
+    eformat,
+    flags::{flag_qos_level, RETAIN_FALSE},
+    function,
+    publish::Publish,
+    wake::Wake,
+};
+use hashbrown::HashMap;
+use log::*;
+use std::collections::VecDeque;
 use std::net::SocketAddr;
-/// Cache for published messages
+/// Cache for published messages to ASLEEP clients, section 6.14.
+///
+/// Each client has a FIFO queue bounded by AsleepLimits, when the queue is
+/// full the oldest message is dropped or the new message is rejected.
+/// The queue is flushed in the order the messages were published when the
+/// client sends a PINGREQ with its client id, the flush is followed by a
+/// PINGRESP, see PingReq::recv(). A client can be woken out-of-band when
+/// a message is queued for it, see Wake.
 use std::sync::Mutex;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest messages to make room for the new message.
+    DropOldest,
+    /// Keep the queue, reject the new message.
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AsleepLimits {
+    /// Max. messages per client.
+    pub max_msgs: usize,
+    /// Max. payload bytes per client.
+    pub max_bytes: usize,
+    pub policy: OverflowPolicy,
+}
+
+impl Default for AsleepLimits {
+    fn default() -> Self {
+        AsleepLimits {
+            max_msgs: 64,
+            max_bytes: 16 * 1024,
+            policy: OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct AsleepQueue {
+    msgs: VecDeque<Publish>,
+    /// Sum of the payload lengths in msgs.
+    bytes: usize,
+}
+
 lazy_static! {
-    static ref ASLEEP_MSG_CACHE: Mutex<BisetMap<SocketAddr, Publish>> =
-        Mutex::new(BisetMap::new());
+    static ref ASLEEP_MSG_CACHE: Mutex<HashMap<SocketAddr, AsleepQueue>> =
+        Mutex::new(HashMap::new());
+    static ref LIMITS: Mutex<AsleepLimits> =
+        Mutex::new(AsleepLimits::default());
 }
 
 #[derive(Debug, Clone)]
 pub struct AsleepMsgCache {}
 
 impl AsleepMsgCache {
-    // Don't need vec of Publish because BisetMap allows the same key with different
-    // values. HashMap would require a Vec of Publish, one key maps to one value.
-    pub fn insert(key: SocketAddr, value: Publish) {
-        let cache = ASLEEP_MSG_CACHE.lock().unwrap();
-        cache.insert(key, value);
+    pub fn set_limits(limits: AsleepLimits) {
+        *LIMITS.lock().unwrap() = limits;
+    }
+    pub fn limits() -> AsleepLimits {
+        *LIMITS.lock().unwrap()
     }
 
-    // returns all the Publish objects with the key.
+    /// Queue the message for the client, Err if the message is rejected.
+    pub fn insert(key: SocketAddr, value: Publish) -> Result<(), String> {
+        let limits = AsleepMsgCache::limits();
+        let msg_bytes = value.data().len();
+        if limits.max_msgs == 0 || msg_bytes > limits.max_bytes {
+            return Err(eformat!(key, "message too large", msg_bytes));
+        }
+        let mut cache = ASLEEP_MSG_CACHE.lock().unwrap();
+        let queue = cache.entry(key).or_insert_with(AsleepQueue::default);
+        while queue.msgs.len() >= limits.max_msgs
+            || queue.bytes + msg_bytes > limits.max_bytes
+        {
+            match limits.policy {
+                OverflowPolicy::Reject => {
+                    return Err(eformat!(key, "queue full", queue.msgs.len()));
+                }
+                OverflowPolicy::DropOldest => {
+                    if let Some(oldest) = queue.msgs.pop_front() {
+                        queue.bytes -= oldest.data().len();
+                        warn!("{}: drop {:?}", key, oldest);
+                    }
+                }
+            }
+        }
+        queue.bytes += msg_bytes;
+        queue.msgs.push_back(value);
+        let queued = queue.msgs.len();
+        drop(cache);
+        Wake::on_queued(key, queued);
+        Ok(())
+    }
+
+    // returns all the Publish objects with the key, oldest first.
     pub fn delete(key: SocketAddr) -> Vec<Publish> {
+        let mut cache = ASLEEP_MSG_CACHE.lock().unwrap();
+        match cache.remove(&key) {
+            Some(queue) => Vec::from(queue.msgs),
+            None => Vec::new(),
+        }
+    }
+
+    /// Number of messages queued for the client.
+    pub fn len(key: SocketAddr) -> usize {
         let cache = ASLEEP_MSG_CACHE.lock().unwrap();
-        cache.delete(&key)
+        cache.get(&key).map_or(0, |queue| queue.msgs.len())
     }
+
+    /// Send the queued messages to the awake client, oldest first.
+    /// Returns the number of messages sent.
+    pub fn flush(key: SocketAddr, client: &MqttSnClient) -> usize {
+        let mut sent = 0;
+        for publish in AsleepMsgCache::delete(key) {
+            match Publish::send(
+                *publish.topic_id(),
+                flag_qos_level(*publish.flags()),
+                RETAIN_FALSE,
+                publish.data().clone(),
+                client,
+                key,
+            ) {
+                Ok(()) => sent += 1,
+                Err(why) => error!("{}", why),
+            }
+        }
+        sent
+    }
+
     pub fn debug() {
         let cache = ASLEEP_MSG_CACHE.lock().unwrap();
         dbg!(&cache);
@@ -40,16 +154,56 @@ fn test_asleep_cache() {
     let socket2 = "127.0.0.2:1200".parse::<SocketAddr>().unwrap();
     let bytes = BytesMut::from(&b"hello"[..]);
     let p = Publish::new(22, 22, 1, 3, bytes.clone());
-    AsleepMsgCache::insert(socket, p);
+    AsleepMsgCache::insert(socket, p).unwrap();
     let p = Publish::new(11, 11, 1, 3, bytes.clone());
-    AsleepMsgCache::insert(socket, p);
+    AsleepMsgCache::insert(socket, p).unwrap();
     let p = Publish::new(33, 33, 1, 3, bytes.clone());
-    AsleepMsgCache::insert(socket2, p);
+    AsleepMsgCache::insert(socket2, p).unwrap();
     let p = Publish::new(55, 55, 1, 3, bytes);
-    AsleepMsgCache::insert(socket2, p);
+    AsleepMsgCache::insert(socket2, p).unwrap();
 
     AsleepMsgCache::debug();
     let msg_vec = AsleepMsgCache::delete(socket);
-    dbg!(msg_vec);
+    dbg!(&msg_vec);
+    // Flush order is the publish order.
+    assert_eq!(*msg_vec[0].topic_id(), 22);
+    assert_eq!(*msg_vec[1].topic_id(), 11);
     AsleepMsgCache::debug();
 }
+
+#[cfg(test)]
+#[test]
+fn test_asleep_cache_limits() {
+    use bytes::BytesMut;
+    use std::net::SocketAddr;
+
+    let socket = "127.0.0.3:1200".parse::<SocketAddr>().unwrap();
+    let bytes = BytesMut::from(&b"hello"[..]);
+    let limits = AsleepMsgCache::limits();
+    // The tests share the limits, 2 messages are enough for both tests.
+    AsleepMsgCache::set_limits(AsleepLimits {
+        max_msgs: 2,
+        ..limits
+    });
+    for msg_id in 1..=3 {
+        let p = Publish::new(1, msg_id, 1, 0, bytes.clone());
+        AsleepMsgCache::insert(socket, p).unwrap();
+    }
+    let msg_vec = AsleepMsgCache::delete(socket);
+    assert_eq!(msg_vec.len(), 2);
+    assert_eq!(*msg_vec[0].msg_id(), 2);
+
+    AsleepMsgCache::set_limits(AsleepLimits {
+        max_msgs: 2,
+        policy: OverflowPolicy::Reject,
+        ..limits
+    });
+    for msg_id in 1..=2 {
+        let p = Publish::new(1, msg_id, 1, 0, bytes.clone());
+        AsleepMsgCache::insert(socket, p).unwrap();
+    }
+    let p = Publish::new(1, 3, 1, 0, bytes);
+    assert!(AsleepMsgCache::insert(socket, p).is_err());
+    assert_eq!(AsleepMsgCache::len(socket), 2);
+    AsleepMsgCache::set_limits(limits);
+}
@@ -0,0 +1,329 @@
+/// Audit log of the client activity, one JSON object per line.
+///
+/// AuditLog is a BrokerEvents, MqttSnClient::with_events() installs it, the
+/// callbacks of another BrokerEvents are chained with AuditLog::chain().
+/// A record is written for each connect, rebind, disconnect, keep-alive
+/// expiration, subscribe and denial of the Authorizer or the DTLS identity
+/// check:
+///
+/// {"timestamp":"2022-05-01T10:00:00.000+00:00","client_id":"sensor1",
+///  "addr":"10.0.0.7:5000","action":"subscribe","topic":"sensors/temp",
+///  "outcome":"accepted"}
+///
+/// The outcome of a rebind is the previous address of the client.
+/// AuditLog::to_file() writes the lines from the audit thread, the broker
+/// threads don't wait for the disk. The file is rotated when it reaches
+/// max_bytes, path.1 is the previous file, up to path.{max_files}.
+/// AuditLog::to_channel() sends the lines to the embedder instead.
+use bytes::Bytes;
+use crossbeam::channel::{unbounded, Sender};
+use hashbrown::HashMap;
+use log::*;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::{
+    authorization::topic_of,
+    connection::StateEnum2,
+    eformat,
+    events::{BrokerEvents, DeliveryStatus, DeniedAction, NoEvents},
+    flags::QoSConst,
+    function,
+    publish::Publish,
+    MsgIdType, TopicIdType,
+};
+
+pub const AUDIT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+pub const AUDIT_MAX_FILES: usize = 5;
+
+#[derive(Debug, Serialize)]
+struct AuditRecord<'a> {
+    timestamp: String,
+    client_id: &'a str,
+    addr: SocketAddr,
+    action: &'a str,
+    topic: &'a str,
+    outcome: &'a str,
+}
+
+pub struct AuditLog {
+    tx: Sender<String>,
+    /// Client id of each connected address, the connection is gone when
+    /// the disconnect is recorded.
+    client_ids: Mutex<HashMap<SocketAddr, Bytes>>,
+    next: Arc<dyn BrokerEvents>,
+}
+
+impl AuditLog {
+    /// Send the JSON lines to tx.
+    pub fn to_channel(tx: Sender<String>) -> Self {
+        AuditLog {
+            tx,
+            client_ids: Mutex::new(HashMap::new()),
+            next: Arc::new(NoEvents {}),
+        }
+    }
+
+    /// Append the JSON lines to the file at path, rotated at max_bytes.
+    pub fn to_file(
+        path: &str,
+        max_bytes: u64,
+        max_files: usize,
+    ) -> Result<Self, String> {
+        let mut file = RotatingFile::open(path, max_bytes, max_files)?;
+        let (tx, rx) = unbounded::<String>();
+        let builder = thread::Builder::new().name("audit_thread".into());
+        let result = builder.spawn(move || {
+            while let Ok(line) = rx.recv() {
+                if let Err(why) = file.write_line(&line) {
+                    error!("{}", why);
+                }
+            }
+        });
+        if let Err(why) = result {
+            return Err(eformat!(path, why));
+        }
+        Ok(AuditLog::to_channel(tx))
+    }
+
+    /// Call the callbacks of next after the audit record.
+    pub fn chain(mut self, next: Arc<dyn BrokerEvents>) -> Self {
+        self.next = next;
+        self
+    }
+
+    fn client_id(&self, remote_addr: &SocketAddr) -> Bytes {
+        let client_ids = self.client_ids.lock().unwrap();
+        client_ids.get(remote_addr).cloned().unwrap_or_default()
+    }
+
+    fn record(
+        &self,
+        client_id: &Bytes,
+        addr: SocketAddr,
+        action: &str,
+        topic: &str,
+        outcome: &str,
+    ) {
+        let client_id = String::from_utf8_lossy(client_id);
+        let record = AuditRecord {
+            timestamp: chrono::Local::now().to_rfc3339(),
+            client_id: &client_id,
+            addr,
+            action,
+            topic,
+            outcome,
+        };
+        let line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(why) => {
+                error!("{}", eformat!(addr, why));
+                return;
+            }
+        };
+        if let Err(why) = self.tx.send(line) {
+            error!("{}", eformat!(addr, why));
+        }
+    }
+}
+
+impl BrokerEvents for AuditLog {
+    fn on_connect(&self, remote_addr: SocketAddr, client_id: &Bytes) {
+        self.client_ids
+            .lock()
+            .unwrap()
+            .insert(remote_addr, client_id.clone());
+        self.record(client_id, remote_addr, "connect", "", "accepted");
+        self.next.on_connect(remote_addr, client_id);
+    }
+    fn on_disconnect(&self, remote_addr: SocketAddr) {
+        let client_id = self.client_ids.lock().unwrap().remove(&remote_addr);
+        let client_id = client_id.unwrap_or_default();
+        self.record(&client_id, remote_addr, "disconnect", "", "closed");
+        self.next.on_disconnect(remote_addr);
+    }
+    fn on_subscribe(
+        &self,
+        remote_addr: SocketAddr,
+        topic_id: TopicIdType,
+        qos: QoSConst,
+    ) {
+        let client_id = self.client_id(&remote_addr);
+        let topic = topic_of(topic_id);
+        self.record(&client_id, remote_addr, "subscribe", &topic, "accepted");
+        self.next.on_subscribe(remote_addr, topic_id, qos);
+    }
+    fn on_publish(&self, remote_addr: SocketAddr, publish: &Publish) {
+        self.next.on_publish(remote_addr, publish);
+    }
+    fn on_keepalive_expired(&self, remote_addr: SocketAddr) {
+        let client_id = self.client_ids.lock().unwrap().remove(&remote_addr);
+        let client_id = client_id.unwrap_or_default();
+        self.record(&client_id, remote_addr, "disconnect", "", "expired");
+        self.next.on_keepalive_expired(remote_addr);
+    }
+    fn on_rebind(&self, old_addr: SocketAddr, new_addr: SocketAddr) {
+        let client_id = self.client_ids.lock().unwrap().remove(&old_addr);
+        let client_id = client_id.unwrap_or_default();
+        let old = old_addr.to_string();
+        self.record(&client_id, new_addr, "rebind", "", &old);
+        self.next.on_rebind(old_addr, new_addr);
+    }
+    fn on_denied(
+        &self,
+        remote_addr: SocketAddr,
+        action: DeniedAction,
+        topic: &str,
+    ) {
+        let client_id = self.client_id(&remote_addr);
+        let name = match action {
+            DeniedAction::Connect => "connect",
+            DeniedAction::Publish => "publish",
+            DeniedAction::Subscribe => "subscribe",
+        };
+        self.record(&client_id, remote_addr, name, topic, "denied");
+        self.next.on_denied(remote_addr, action, topic);
+    }
+    fn on_delivery(
+        &self,
+        remote_addr: SocketAddr,
+        msg_id: MsgIdType,
+        status: DeliveryStatus,
+    ) {
+        self.next.on_delivery(remote_addr, msg_id, status);
+    }
+    fn on_state_change(
+        &self,
+        remote_addr: SocketAddr,
+        from: StateEnum2,
+        to: StateEnum2,
+    ) {
+        self.next.on_state_change(remote_addr, from, to);
+    }
+}
+
+/// The audit file, rotated at max_bytes.
+struct RotatingFile {
+    path: String,
+    file: File,
+    len: u64,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(
+        path: &str,
+        max_bytes: u64,
+        max_files: usize,
+    ) -> Result<Self, String> {
+        let file = RotatingFile::append(path)?;
+        let len = file.metadata().map(|meta| meta.len()).unwrap_or(0);
+        Ok(RotatingFile {
+            path: path.to_string(),
+            file,
+            len,
+            max_bytes,
+            max_files,
+        })
+    }
+
+    fn append(path: &str) -> Result<File, String> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|why| eformat!(path, why))
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), String> {
+        if self.len > 0 && self.len + line.len() as u64 + 1 > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)
+            .map_err(|why| eformat!(self.path, why))?;
+        self.len += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    /// path.{n} to path.{n + 1}, the oldest is removed.
+    fn rotate(&mut self) -> Result<(), String> {
+        let rotated = |index: usize| format!("{}.{}", self.path, index);
+        if self.max_files > 0 {
+            let _result = fs::remove_file(rotated(self.max_files));
+            for index in (1..self.max_files).rev() {
+                let _result = fs::rename(rotated(index), rotated(index + 1));
+            }
+            fs::rename(&self.path, rotated(1))
+                .map_err(|why| eformat!(self.path, why))?;
+        } else {
+            fs::remove_file(&self.path)
+                .map_err(|why| eformat!(self.path, why))?;
+        }
+        self.file = RotatingFile::append(&self.path)?;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_audit_log() {
+        use super::*;
+        let (tx, rx) = unbounded();
+        let audit = AuditLog::to_channel(tx);
+        let addr = "127.0.0.1:1700".parse::<SocketAddr>().unwrap();
+        audit.on_connect(addr, &Bytes::from("auditor"));
+        audit.on_denied(addr, DeniedAction::Publish, "audit/secret");
+        audit.on_disconnect(addr);
+        let records: Vec<serde_json::Value> = rx
+            .try_iter()
+            .map(|line| serde_json::from_str(&line).unwrap())
+            .collect();
+        let fields: Vec<(&str, &str, &str, &str)> = records
+            .iter()
+            .map(|record| {
+                (
+                    record["client_id"].as_str().unwrap(),
+                    record["action"].as_str().unwrap(),
+                    record["topic"].as_str().unwrap(),
+                    record["outcome"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                ("auditor", "connect", "", "accepted"),
+                ("auditor", "publish", "audit/secret", "denied"),
+                ("auditor", "disconnect", "", "closed"),
+            ]
+        );
+        assert_eq!(records[0]["addr"], "127.0.0.1:1700");
+        assert!(records[0]["timestamp"].as_str().unwrap().contains('T'));
+        // Rotation, 2 files of 2 lines.
+        let path = std::env::temp_dir().join("audit_log_test.log");
+        let path = path.to_str().unwrap().to_string();
+        for index in 0..3 {
+            let _result = fs::remove_file(format!("{}.{}", path, index));
+        }
+        let _result = fs::remove_file(&path);
+        let mut file = RotatingFile::open(&path, 20, 2).unwrap();
+        for line in ["line 1", "line 2", "line 3", "line 4", "line 5"].iter() {
+            file.write_line(line).unwrap();
+        }
+        let read = |path: &str| fs::read_to_string(path).unwrap();
+        assert_eq!(read(&path), "line 5\n");
+        assert_eq!(read(&format!("{}.1", path)), "line 3\nline 4\n");
+        assert_eq!(read(&format!("{}.2", path)), "line 1\nline 2\n");
+        for index in 1..3 {
+            let _result = fs::remove_file(format!("{}.{}", path, index));
+        }
+        let _result = fs::remove_file(&path);
+    }
+}
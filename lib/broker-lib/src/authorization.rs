@@ -0,0 +1,207 @@
+/// Topic based authorization for PUBLISH and SUBSCRIBE.
+///
+/// The Authorizer in MqttSnClient is checked by Publish::recv() and
+/// Subscribe::recv(). AllowAll is the default, AclAuthorizer reads the
+/// rules from an ACL file:
+///
+/// # Rules before the first "user" line apply to all clients.
+/// topic read $SYS/#
+/// # Rules for the client id sensor1.
+/// user sensor1
+/// topic write sensors/sensor1/#
+/// topic readwrite cmd/sensor1
+///
+/// Pre-defined topic ids are checked with the name in the pre-defined topic
+/// table of the configuration, or without a name with the topic id in
+/// decimal, e.g. "topic read 7".
+use bytes::Bytes;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use crate::{
+    client_id::ClientId,
+    config::PreDefinedTopics,
+    eformat,
+    filter::{get_topic_name_with_topic_id, match_topic},
+    function, TopicIdType,
+};
+
+pub trait Authorizer: Send + Sync {
+    fn allow_publish(&self, client_id: &Bytes, topic: &str) -> bool;
+    fn allow_subscribe(&self, client_id: &Bytes, topic: &str) -> bool;
+    /// Every request is allowed, the checks can be skipped.
+    fn allow_all(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AllowAll {}
+
+impl Authorizer for AllowAll {
+    fn allow_publish(&self, _client_id: &Bytes, _topic: &str) -> bool {
+        true
+    }
+    fn allow_subscribe(&self, _client_id: &Bytes, _topic: &str) -> bool {
+        true
+    }
+    fn allow_all(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AclRule {
+    /// None for all clients.
+    pub client_id: Option<Bytes>,
+    pub access: Access,
+    pub filter: String,
+}
+
+/// Deny unless a rule allows it.
+#[derive(Debug, Clone, Default)]
+pub struct AclAuthorizer {
+    rules: Vec<AclRule>,
+}
+
+impl AclAuthorizer {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut rules = Vec::new();
+        let mut client_id = None;
+        for (index, line) in text.lines().map(str::trim).enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words[..] {
+                ["user", id] => client_id = Some(Bytes::from(id.to_string())),
+                ["topic", access, filter] => {
+                    let access = match access {
+                        "read" => Access::Read,
+                        "write" => Access::Write,
+                        "readwrite" => Access::ReadWrite,
+                        _ => return Err(eformat!(index + 1, line)),
+                    };
+                    rules.push(AclRule {
+                        client_id: client_id.clone(),
+                        access,
+                        filter: filter.to_string(),
+                    });
+                }
+                _ => return Err(eformat!(index + 1, line)),
+            }
+        }
+        Ok(AclAuthorizer { rules })
+    }
+    pub fn load_file(path: &str) -> Result<Self, String> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => AclAuthorizer::parse(&text),
+            Err(why) => Err(eformat!(path, why)),
+        }
+    }
+
+    fn allow(&self, client_id: &Bytes, topic: &str, write: bool) -> bool {
+        self.rules.iter().any(|rule| {
+            let access = match rule.access {
+                Access::ReadWrite => true,
+                Access::Write => write,
+                Access::Read => !write,
+            };
+            access
+                && rule.client_id.as_ref().map_or(true, |id| id == client_id)
+                && (rule.filter == topic || match_topic(topic, &rule.filter))
+        })
+    }
+}
+
+impl Authorizer for AclAuthorizer {
+    fn allow_publish(&self, client_id: &Bytes, topic: &str) -> bool {
+        self.allow(client_id, topic, true)
+    }
+    fn allow_subscribe(&self, client_id: &Bytes, topic: &str) -> bool {
+        self.allow(client_id, topic, false)
+    }
+}
+
+/// Authorizer that can be swapped while the broker runs, e.g. when the
+/// configuration is reloaded.
+pub struct ReloadableAuthorizer {
+    inner: RwLock<Arc<dyn Authorizer>>,
+}
+
+impl ReloadableAuthorizer {
+    pub fn new(inner: Arc<dyn Authorizer>) -> Self {
+        ReloadableAuthorizer {
+            inner: RwLock::new(inner),
+        }
+    }
+    /// The checks in progress finish with the old authorizer.
+    pub fn swap(&self, inner: Arc<dyn Authorizer>) {
+        *self.inner.write().unwrap() = inner;
+    }
+    fn current(&self) -> Arc<dyn Authorizer> {
+        self.inner.read().unwrap().clone()
+    }
+}
+
+impl Authorizer for ReloadableAuthorizer {
+    fn allow_publish(&self, client_id: &Bytes, topic: &str) -> bool {
+        self.current().allow_publish(client_id, topic)
+    }
+    fn allow_subscribe(&self, client_id: &Bytes, topic: &str) -> bool {
+        self.current().allow_subscribe(client_id, topic)
+    }
+    fn allow_all(&self) -> bool {
+        self.current().allow_all()
+    }
+}
+
+/// Client id of the connection, empty for clients without a connection,
+/// e.g. QoS -1 publishers.
+pub fn client_id_of(socket_addr: &SocketAddr) -> Bytes {
+    ClientId::rev_get(socket_addr)
+        .into_iter()
+        .next()
+        .unwrap_or_default()
+}
+
+/// Topic name for the authorizer, the pre-defined name or the topic id in
+/// decimal for pre-defined topic ids.
+pub fn topic_of(topic_id: TopicIdType) -> String {
+    get_topic_name_with_topic_id(topic_id)
+        .or_else(|| PreDefinedTopics::name(topic_id))
+        .unwrap_or_else(|| topic_id.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_acl_authorizer() {
+        use super::*;
+        let acl = AclAuthorizer::parse(
+            "# all clients\n\
+             topic read $SYS/#\n\
+             user sensor1\n\
+             topic write sensors/sensor1/#\n\
+             topic readwrite 7\n",
+        )
+        .unwrap();
+        let sensor1 = Bytes::from("sensor1");
+        let sensor2 = Bytes::from("sensor2");
+        assert!(acl.allow_publish(&sensor1, "sensors/sensor1/temp"));
+        assert!(!acl.allow_subscribe(&sensor1, "sensors/sensor1/temp"));
+        assert!(!acl.allow_publish(&sensor2, "sensors/sensor1/temp"));
+        assert!(acl.allow_subscribe(&sensor2, "$SYS/#"));
+        assert!(acl.allow_publish(&sensor1, "7"));
+        assert!(!acl.allow_all());
+        assert!(AclAuthorizer::parse("topic delete x\n").is_err());
+        assert!(AllowAll {}.allow_publish(&sensor2, "any"));
+    }
+}
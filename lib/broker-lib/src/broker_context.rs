@@ -0,0 +1,167 @@
+/// State of one broker instance.
+///
+/// The connections, client ids, topic names and subscriptions, topic refs,
+/// retained messages, in-flight windows, msg ids, pending REGISTERs, the
+/// listener sockets, the imported subscriptions, the cluster peers, the
+/// banned addresses, the storage backend, the keep-alive and retransmit
+/// time wheels, the annotation hooks, the test topics, the QoS 2 messages
+/// waiting for the PUBREL, the duplicate filter, the delivery traces, the
+/// alerting rules and the health of the workers live in a BrokerContext
+/// instead of process-wide globals, two brokers on different ports run
+/// isolated in one process, e.g. for multi-gateway tests or multi-tenant
+/// embedding.
+///
+/// The functions of those modules keep their signatures, they use the
+/// context entered by the calling thread, or the global context if none.
+/// MqttSnClient::new() uses the global context, MqttSnClient::with_context()
+/// another one. The broker enters its context in dispatch() and in the
+/// threads it starts. The threads started without a client, e.g. by
+/// ConfigWatcher::run() or Metrics::serve(), enter the context of the
+/// calling thread. A thread started elsewhere must call enter() before
+/// touching the state of a non-global broker.
+///
+/// A context lives until the process exits. Process-wide on purpose:
+/// - the will setup, the sleeping client caches and the DTLS identities,
+///   keyed by the client address, a client is connected to one broker.
+/// - the local consumers, the connectors, the rich publish filters, the
+///   last-value cache and the wake hook, registered by the embedding
+///   application, not by a broker.
+/// - the metrics and the $SYS counters, they count the process.
+/// - the topic metadata, the local topics, the ordered delivery queues
+///   and the forwarder nodes, configured once per process.
+/// - the client mode sessions, the process is the client.
+/// - the receive buffer pool and the maximum datagram size, shared by the
+///   listeners.
+use std::cell::Cell;
+
+use crate::{
+    admin::AdminState, alert::AlertState, annotation::AnnotationState,
+    client_id::ClientIdState, connection::ConnState,
+    dup_filter::DupFilterState, filter::FilterState, in_flight::InFlightState,
+    keep_alive::KeepAliveState, listener::ListenerState, msg_id::MsgIdState,
+    msg_trace::MsgTraceState, pub_msg_cache::PubMsgCacheState,
+    register_on_demand::RegisterOnDemandState, retain::RetainState,
+    retransmit::RetransState, storage_backend::StorageBackendState,
+    subscription_export::SubscriptionExportState,
+    subscription_limits::SubscriptionLimitsState, test_topics::TestTopicsState,
+    topic_refs::TopicRefsState,
+};
+
+lazy_static! {
+    static ref GLOBAL_CONTEXT: BrokerContext = BrokerContext::default();
+}
+
+thread_local! {
+    static CURRENT_CONTEXT: Cell<Option<&'static BrokerContext>> =
+        Cell::new(None);
+}
+
+#[derive(Default)]
+pub struct BrokerContext {
+    pub(crate) filter: FilterState,
+    pub(crate) connection: ConnState,
+    pub(crate) client_id: ClientIdState,
+    pub(crate) topic_refs: TopicRefsState,
+    pub(crate) retain: RetainState,
+    pub(crate) in_flight: InFlightState,
+    pub(crate) msg_id: MsgIdState,
+    pub(crate) register_on_demand: RegisterOnDemandState,
+    pub(crate) keep_alive: KeepAliveState,
+    pub(crate) retransmit: RetransState,
+    pub(crate) listener: ListenerState,
+    pub(crate) subscription_export: SubscriptionExportState,
+    pub(crate) subscription_limits: SubscriptionLimitsState,
+    #[cfg(feature = "bridge")]
+    pub(crate) cluster: crate::cluster::ClusterState,
+    pub(crate) admin: AdminState,
+    pub(crate) annotation: AnnotationState,
+    pub(crate) test_topics: TestTopicsState,
+    pub(crate) pub_msg_cache: PubMsgCacheState,
+    pub(crate) dup_filter: DupFilterState,
+    pub(crate) msg_trace: MsgTraceState,
+    pub(crate) alert: AlertState,
+    pub(crate) storage_backend: StorageBackendState,
+    #[cfg(feature = "fragmentation")]
+    pub(crate) fragment: crate::fragment::FragmentState,
+    #[cfg(feature = "health")]
+    pub(crate) health: crate::health::HealthState,
+}
+
+/// Restores the previous context of the thread when dropped.
+pub struct ContextGuard {
+    previous: Option<&'static BrokerContext>,
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        CURRENT_CONTEXT.with(|current| current.set(self.previous));
+    }
+}
+
+impl BrokerContext {
+    /// A new empty context, it isn't freed.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> &'static BrokerContext {
+        Box::leak(Box::new(BrokerContext::default()))
+    }
+    /// The context of the brokers without one.
+    pub fn global() -> &'static BrokerContext {
+        &GLOBAL_CONTEXT
+    }
+    /// The context entered by the thread, or the global context.
+    #[inline(always)]
+    pub fn current() -> &'static BrokerContext {
+        CURRENT_CONTEXT
+            .with(|current| current.get())
+            .unwrap_or_else(BrokerContext::global)
+    }
+    /// Use this context in the thread until the guard is dropped.
+    pub fn enter(&'static self) -> ContextGuard {
+        let previous =
+            CURRENT_CONTEXT.with(|current| current.replace(Some(self)));
+        ContextGuard { previous }
+    }
+    /// Same instance.
+    pub fn is(&self, other: &BrokerContext) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_broker_context() {
+        use super::*;
+        use crate::{
+            connection::Connection,
+            filter::{get_topic_id_with_topic_name, try_insert_topic_name},
+        };
+        use bytes::Bytes;
+        use std::net::SocketAddr;
+        let topic = "broker_context/isolated".to_string();
+        let addr = "10.5.0.1:1234".parse::<SocketAddr>().unwrap();
+        let context = BrokerContext::new();
+        assert!(!context.is(BrokerContext::global()));
+        let topic_id = {
+            let _context = context.enter();
+            assert!(BrokerContext::current().is(context));
+            {
+                // Nested, restored on drop.
+                let _global = BrokerContext::global().enter();
+                assert!(BrokerContext::current().is(BrokerContext::global()));
+            }
+            assert!(BrokerContext::current().is(context));
+            let client_id = Bytes::from_static(b"broker_context");
+            Connection::try_insert(addr, 0, 1, 60, client_id).unwrap();
+            assert!(Connection::contains_key(addr));
+            try_insert_topic_name(topic.clone()).unwrap()
+        };
+        // Not visible from the global context.
+        assert!(BrokerContext::current().is(BrokerContext::global()));
+        assert!(!Connection::contains_key(addr));
+        assert_eq!(get_topic_id_with_topic_name(topic.clone()), None);
+        let _context = context.enter();
+        assert_eq!(get_topic_id_with_topic_name(topic), Some(topic_id));
+        Connection::remove(&addr).unwrap();
+    }
+}
@@ -4,24 +4,47 @@ use crossbeam::channel::*;
 use log::*;
 use std::net::SocketAddr;
 use std::net::UdpSocket;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::Arc;
 use std::thread;
-use util::conn::*;
+use std::time::Duration;
 
+#[cfg(feature = "fragmentation")]
+use crate::fragment::Fragment;
+#[cfg(feature = "health")]
+use crate::health::{Health, Worker};
+#[cfg(feature = "async-runtime")]
+use crate::hub::{Hub, HUB_EVICTION_INTERVAL};
 use crate::{
+    admin::Admin,
     advertise::*,
+    alert::AlertRules,
+    authorization::{AllowAll, Authorizer},
+    broker_context::BrokerContext,
     // Channels::Channels,
+    client_mode::{ClientMode, ConnectOptions},
+    clock::{Clock, SystemClock},
+    conn::Conn,
     conn_ack::ConnAck,
     connect::Connect,
     connection::Connection,
+    connection_info::ConnectionInfo,
     dbg_buf,
     disconnect::Disconnect,
     eformat,
+    egress::Egress,
+    events::{BrokerEvents, NoEvents},
+    filter::get_topic_id,
+    forwarder::Forwarder,
     function,
     gw_info::GwInfo,
-    hub::Hub,
     keep_alive::KeepAliveTimeWheel,
+    last_value::{LastValue, LastValueCache},
+    listener::Listeners,
+    local_consumer::LocalConsumer,
     msg_hdr::MsgHeader,
+    msg_span::MsgSpan,
+    multicast::multicast_groups,
     ping_req::PingReq,
     ping_resp::PingResp,
     // Connection::ConnHashMap,
@@ -32,10 +55,19 @@ use crate::{
     publish::Publish,
     reg_ack::RegAck,
     register::Register,
+    retain::Retain,
     retransmit::RetransTimeWheel,
+    rich_publish::RichPublish,
     search_gw::SearchGw,
+    storage_backend::{Persistence, StorageBackend},
     sub_ack::SubAck,
     subscribe::Subscribe,
+    subscribe_batch::SubscribeBatch,
+    subscription_export::SubscriptionExport,
+    sys_stats::SysStats,
+    sys_topics::SysTopics,
+    timer_wheel::TimerWheelConfig,
+    topic_metadata::TopicMetadata,
     unsub_ack::UnsubAck,
     unsubscribe::Unsubscribe,
     will_msg::WillMsg,
@@ -46,7 +78,9 @@ use crate::{
     will_topic_req::WillTopicReq,
     will_topic_resp::WillTopicResp,
     will_topic_upd::WillTopicUpd,
+    TopicIdType,
     MSG_TYPE_CONNECT,
+    MSG_TYPE_PUBLISH,
 };
 // use trace_var::trace_var;
 
@@ -63,6 +97,47 @@ fn reserved(
     ))
 }
 
+type IngressFn = fn(
+    buf: &[u8],
+    size: usize,
+    client: &MqttSnClient,
+    msg_header: MsgHeader,
+) -> Result<(), String>;
+
+/// Receive functions indexed by the message type.
+const INGRESS_FUNCTIONS: [IngressFn; 30] = [
+    Advertise::recv,     // 0x00
+    GwInfo::recv,        // 0x01
+    GwInfo::recv,        // 0x02
+    reserved,            // 0x03
+    Connect::recv,       // 0x04
+    ConnAck::recv,       // 0x05
+    WillTopicReq::recv,  // 0x06
+    WillTopic::recv,     // 0x07
+    WillMsgReq::recv,    // 0x08
+    WillMsg::recv,       // 0x09
+    Register::recv,      // 0x0A
+    RegAck::recv,        // 0x0B
+    Publish::recv,       // 0x0C
+    PubAck::recv,        // 0x0D
+    PubComp::recv,       // 0x0E
+    PubRec::recv,        // 0x0F
+    PubRel::recv,        // 0x10
+    reserved,            // 0x11
+    Subscribe::recv,     // 0x12
+    SubAck::recv,        // 0x13
+    Unsubscribe::recv,   // 0x14
+    UnsubAck::recv,      // 0x15
+    PingReq::recv,       // 0x16
+    PingResp::recv,      // 0x17
+    Disconnect::recv,    // 0x18
+    reserved,            // 0x19
+    WillTopicUpd::recv,  // 0x1A
+    WillTopicResp::recv, // 0x1B
+    WillMsgUpd::recv,    // 0x1C
+    WillMsgResp::recv,   // 0x1D
+];
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum MessageTypeEnum {
     Connect(Connect),
@@ -74,19 +149,69 @@ pub enum MessageTypeEnum {
 }
 pub type IngressChannelType = (SocketAddr, Bytes, Arc<dyn Conn + Send + Sync>);
 pub type EgressChannelType = (SocketAddr, BytesMut);
+/// Fan-out entry, the same serialized message for all addresses. The
+/// PUBLISH messages and the retransmits, sent after the messages of the
+/// egress channel, see Egress::next().
+pub type EgressBatchChannelType = (Vec<SocketAddr>, Bytes);
+/// Messages waiting in the egress channels before new CONNECT, REGISTER,
+/// SUBSCRIBE and QoS 1/2 PUBLISH messages are rejected with
+/// RETURN_CODE_CONGESTION.
+pub const EGRESS_CONGESTION_LEN: usize = 10_000;
+/// Datagrams taken from the ingress channel per iteration of the ingress
+/// thread.
+pub const INGRESS_BATCH_LEN: usize = 64;
+/// The Cargo features of the build, see banner(). A new feature in
+/// Cargo.toml is added here.
+const FEATURES: [(&str, bool); 13] = [
+    ("dtls", cfg!(feature = "dtls")),
+    ("metrics", cfg!(feature = "metrics")),
+    ("bridge", cfg!(feature = "bridge")),
+    ("async-runtime", cfg!(feature = "async-runtime")),
+    ("map-hashbrown", cfg!(feature = "map-hashbrown")),
+    ("map-std", cfg!(feature = "map-std")),
+    ("fragmentation", cfg!(feature = "fragmentation")),
+    ("mqtt-sn-v2", cfg!(feature = "mqtt-sn-v2")),
+    ("connector", cfg!(feature = "connector")),
+    ("connector-kafka", cfg!(feature = "connector-kafka")),
+    ("connector-nats", cfg!(feature = "connector-nats")),
+    ("storage-sqlite", cfg!(feature = "storage-sqlite")),
+    ("health", cfg!(feature = "health")),
+];
 
 #[derive(Clone)]
 pub struct MqttSnClient {
     // pub remote_addr: SocketAddr,
     pub transmit_tx: Sender<(SocketAddr, BytesMut)>,
-    pub subscribe_tx: Sender<Publish>,
+    pub subscribe_tx: Sender<RichPublish>,
     pub transmit_rx: Receiver<(SocketAddr, BytesMut)>,
-    pub subscribe_rx: Receiver<Publish>,
+    pub subscribe_rx: Receiver<RichPublish>,
     pub ingress_tx: Sender<IngressChannelType>,
     pub ingress_rx: Receiver<IngressChannelType>,
     pub egress_tx: Sender<EgressChannelType>,
     pub egress_rx: Receiver<EgressChannelType>,
+    pub egress_batch_tx: Sender<EgressBatchChannelType>,
+    pub egress_batch_rx: Receiver<EgressBatchChannelType>,
+    /// DTLS and WebSocket conns, behind the "async-runtime" feature.
+    #[cfg(feature = "async-runtime")]
     pub hub: Arc<Hub>,
+    /// Checked for PUBLISH and SUBSCRIBE, AllowAll by default.
+    pub authorizer: Arc<dyn Authorizer>,
+    /// Connection, subscribe and publish callbacks, NoEvents by default.
+    pub events: Arc<dyn BrokerEvents>,
+    /// Gateway id of the ADVERTISE and GWINFO messages.
+    pub gw_id: u8,
+    /// Seconds between ADVERTISE messages, 0 disables them.
+    pub advertise_duration: u16,
+    /// Seconds between the $SYS statistics, 0 disables them.
+    pub sys_interval: u16,
+    /// Tick and precision of the keep-alive and retransmit time wheels.
+    pub timer_config: TimerWheelConfig,
+    /// Clock of the time wheels, SystemClock by default.
+    pub clock: Arc<dyn Clock>,
+    /// Retained messages, wills and sessions, none by default.
+    pub storage: Option<Arc<dyn StorageBackend>>,
+    /// Connections, topics and timers, BrokerContext::global() by default.
+    pub context: &'static BrokerContext,
 }
 
 impl MqttSnClient {
@@ -97,8 +222,10 @@ impl MqttSnClient {
             Sender<(SocketAddr, BytesMut)>,
             Receiver<(SocketAddr, BytesMut)>,
         ) = unbounded();
-        let (subscribe_tx, subscribe_rx): (Sender<Publish>, Receiver<Publish>) =
-            unbounded();
+        let (subscribe_tx, subscribe_rx): (
+            Sender<RichPublish>,
+            Receiver<RichPublish>,
+        ) = unbounded();
         // Channel for ingress messages.
         // Incoming messages from the socket are sent from this channel for processing.
         // Multiple consumer threads can receive from this channel.
@@ -112,6 +239,13 @@ impl MqttSnClient {
             Sender<EgressChannelType>,
             Receiver<EgressChannelType>,
         ) = unbounded();
+        // Channel for fan-out egress messages.
+        // One entry per PUBLISH message instead of one per subscriber.
+        let (egress_batch_tx, egress_batch_rx): (
+            Sender<EgressBatchChannelType>,
+            Receiver<EgressBatchChannelType>,
+        ) = unbounded();
+        #[cfg(feature = "async-runtime")]
         let hub = Arc::new(Hub::new(Arc::new(ingress_tx.clone())));
         MqttSnClient {
             // remote_addr,
@@ -123,157 +257,459 @@ impl MqttSnClient {
             ingress_rx,
             egress_tx,
             egress_rx,
+            egress_batch_tx,
+            egress_batch_rx,
+            #[cfg(feature = "async-runtime")]
             hub,
+            authorizer: Arc::new(AllowAll {}),
+            events: Arc::new(NoEvents {}),
+            gw_id: 5,
+            advertise_duration: 2,
+            sys_interval: 10,
+            timer_config: TimerWheelConfig::default(),
+            clock: Arc::new(SystemClock {}),
+            storage: None,
+            context: BrokerContext::global(),
         }
     }
 
+    /// Replace the authorizer, call before the broker starts.
+    pub fn with_authorizer(mut self, authorizer: Arc<dyn Authorizer>) -> Self {
+        self.authorizer = authorizer;
+        self
+    }
+    /// Replace the event callbacks, call before the broker starts.
+    pub fn with_events(mut self, events: Arc<dyn BrokerEvents>) -> Self {
+        self.events = events;
+        self
+    }
+    /// Replace the context, e.g. BrokerContext::new() for a broker isolated
+    /// from the other brokers of the process. Call before the broker starts.
+    pub fn with_context(mut self, context: &'static BrokerContext) -> Self {
+        self.context = context;
+        self
+    }
+    /// Set the gateway id and the ADVERTISE interval in seconds, 0 disables
+    /// the ADVERTISE messages. Call before the broker starts.
+    pub fn with_advertise(mut self, gw_id: u8, duration: u16) -> Self {
+        self.gw_id = gw_id;
+        self.advertise_duration = duration;
+        self
+    }
+    /// Set the interval of the $SYS statistics in seconds, 0 disables them.
+    /// Call before the broker starts.
+    pub fn with_sys_interval(mut self, interval: u16) -> Self {
+        self.sys_interval = interval;
+        self
+    }
+    /// Set the tick and the precision of the time wheels. Call before the
+    /// broker starts.
+    pub fn with_timer_config(mut self, config: TimerWheelConfig) -> Self {
+        self.timer_config = config;
+        self
+    }
+    /// Replace the clock of the time wheels, e.g. a MockClock. Call before
+    /// the broker starts.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+    /// Set the storage backend, e.g. from StorageConfig::open(). The
+    /// retained messages are loaded when the broker starts. Call before
+    /// the broker starts.
+    pub fn with_storage(mut self, storage: Arc<dyn StorageBackend>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+    /// The egress thread is behind, new requests are rejected.
+    #[inline(always)]
+    pub fn is_congested(&self) -> bool {
+        self.egress_tx.len() + self.egress_batch_tx.len()
+            >= EGRESS_CONGESTION_LEN
+    }
+
+    /// The datagrams of data to addr: fragmented or rejected if longer
+    /// than a datagram, a message to a wireless node goes through its
+    /// forwarder.
+    fn egress_datagrams(
+        &self,
+        addr: SocketAddr,
+        data: Bytes,
+    ) -> Vec<(SocketAddr, Bytes)> {
+        let _context = self.context.enter();
+        let datagrams = match Egress::datagrams(addr, data) {
+            Ok(datagrams) => datagrams,
+            Err(why) => {
+                error!("{}", why);
+                return Vec::new();
+            }
+        };
+        datagrams
+            .into_iter()
+            .map(|data| match Forwarder::encapsulate(addr, &data) {
+                Some((forwarder, frame)) => (forwarder, frame.freeze()),
+                None => (addr, data),
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "async-runtime")]
     pub fn handle_egress(self) {
         let hub2 = Arc::clone(&self.hub);
         // *NOTE: thread and tokio spawn are not compatible.
         // use thread instead of tokio spawn to read from channel.
         tokio::spawn(async move {
+            #[cfg(feature = "health")]
+            let _worker = Health::start(self.context, Worker::Egress);
             loop {
-                match self.egress_rx.recv() {
-                    Ok((addr, data)) => {
-                        let dtls_conn = hub2.get_conn(addr).await.unwrap();
-                        let _result = dtls_conn.send(&data[..]).await;
-                    }
+                // Egress::next() doesn't await, receive first then send.
+                // The protocol messages go before the PUBLISH messages.
+                let (addr_vec, data) = match Egress::next(&self) {
+                    Ok(next) => next,
                     Err(why) => {
-                        error!("{}", eformat!(why));
+                        error!("{}", why);
                         break;
                     }
+                };
+                for addr in addr_vec {
+                    for (addr, data) in
+                        self.egress_datagrams(addr, data.clone())
+                    {
+                        match hub2.get_conn(addr) {
+                            Some(dtls_conn) => {
+                                if let Err(why) =
+                                    dtls_conn.send(&data[..]).await
+                                {
+                                    error!("{}", eformat!(addr, why));
+                                }
+                            }
+                            // The DTLS session was closed, the client
+                            // handshakes again, no plaintext reply.
+                            None if hub2.is_secure(&addr) => {
+                                error!(
+                                    "{}",
+                                    eformat!(addr, "DTLS session closed")
+                                );
+                            }
+                            // A UDP client, from the socket it's reachable
+                            // on.
+                            None => {
+                                let _context = self.context.enter();
+                                if let Err(why) =
+                                    Listeners::send_to(addr, &data)
+                                {
+                                    error!("{}", why);
+                                }
+                            }
+                        }
+                    }
                 }
             }
         });
     }
-    pub fn handle_ingress(self) {
-        // *NOTE: thread and tokio spawn are not compatible.
-        // use thread instead of tokio spawn to read from channel.
-
-        let functions: Vec<
-            fn(
-                buf: &[u8],
-                size: usize,
-                client: &MqttSnClient,
-                msg_header: MsgHeader,
-            ) -> Result<(), String>,
-        > = vec![
-            Advertise::recv,     // 0x00
-            GwInfo::recv,        // 0x01
-            GwInfo::recv,        // 0x02
-            reserved,            // 0x03
-            Connect::recv,       // 0x04
-            ConnAck::recv,       // 0x05
-            WillTopicReq::recv,  // 0x06
-            WillTopic::recv,     // 0x07
-            WillMsgReq::recv,    // 0x08
-            WillMsg::recv,       // 0x09
-            Register::recv,      // 0x0A
-            RegAck::recv,        // 0x0B
-            Publish::recv,       // 0x0C
-            PubAck::recv,        // 0x0D
-            reserved,            // 0x0E
-            PubRec::recv,        // 0x0F
-            PubRel::recv,        // 0x10
-            reserved,            // 0x11
-            Subscribe::recv,     // 0x12
-            SubAck::recv,        // 0x13
-            Unsubscribe::recv,   // 0x14
-            UnsubAck::recv,      // 0x15
-            PingReq::recv,       // 0x16
-            PingResp::recv,      // 0x17
-            Disconnect::recv,    // 0x18
-            reserved,            // 0x19
-            WillTopicUpd::recv,  // 0x1A
-            WillTopicResp::recv, // 0x1B
-            WillMsgUpd::recv,    // 0x1C
-            WillMsgResp::recv,   // 0x1D
-        ];
-
-        tokio::spawn(async move {
+    /// Without the "async-runtime" feature there are only UDP clients, the
+    /// egress thread sends from the socket of their listener.
+    #[cfg(not(feature = "async-runtime"))]
+    pub fn handle_egress(self) {
+        let builder = thread::Builder::new().name("egress_thread".into());
+        let result = builder.spawn(move || {
+            let _context = self.context.enter();
+            #[cfg(feature = "health")]
+            let _worker = Health::start(self.context, Worker::Egress);
             loop {
-                match self.ingress_rx.recv() {
-                    Ok((addr, bytes, conn)) => {
-                        let buf = &bytes[..];
-                        let size = bytes.len();
-                        // Update the last seen time of the client.
-                        let _result = KeepAliveTimeWheel::reschedule(addr);
-                        // Parse the message header: length, and message type.
-                        let msg_header =
-                            match MsgHeader::try_read(&buf, size, addr, conn) {
-                                Ok(header) => header,
-                                Err(e) => {
-                                    error!("{}", e);
-                                    continue;
-                                }
-                            };
-                        let msg_type = msg_header.msg_type;
-                        let fn_index = msg_header.msg_type as usize;
-                        // Existing MQTT-SN connection or new connection.
-                        // DTLS connection is created at lower layer.
-                        if Connection::contains_key(addr) {
-                            // New connection.
-                            // TODO: the broadcast messages doesn't have connection.
-                            // TODO: broadcast messages are not encrypted.
-                            if msg_type == MSG_TYPE_CONNECT {
-                                error!("{}", "Connect message received twice.");
-                                continue;
-                            }
-                        } else {
-                            // Existing connection shouldn't receive CONNECT message.
-                            if msg_type != MSG_TYPE_CONNECT {
-                                error!("{}", "No connection found");
-                                continue;
-                            }
-                        }
-                        if fn_index >= functions.len() {
-                            error!(
-                                "{}",
-                                eformat!(
-                                    msg_header.remote_socket_addr,
-                                    "Invalid message type",
-                                    fn_index
-                                )
-                            );
-                            continue;
-                        }
-                        let result = functions[fn_index](
-                            &buf,
-                            size,
-                            &self,
-                            msg_header.clone(),
-                        );
-                        if result.is_err() {
-                            error!("{}", result.unwrap_err());
+                // The protocol messages go before the PUBLISH messages.
+                let (addr_vec, data) = match Egress::next(&self) {
+                    Ok(next) => next,
+                    Err(why) => {
+                        error!("{}", why);
+                        break;
+                    }
+                };
+                for addr in addr_vec {
+                    for (addr, data) in
+                        self.egress_datagrams(addr, data.clone())
+                    {
+                        if let Err(why) = Listeners::send_to(addr, &data) {
+                            error!("{}", why);
                         }
-                        continue;
                     }
-                    Err(why) => {
-                        error!("{:?}", why);
+                }
+            }
+        });
+        if let Err(why) = result {
+            error!("{}", eformat!(why));
+        }
+    }
+    /// A DTLS or WebSocket conn of addr is registered with the hub, the
+    /// replies to addr go through handle_egress().
+    #[cfg(feature = "async-runtime")]
+    fn has_conn(&self, addr: &SocketAddr) -> bool {
+        self.hub.contains(addr)
+    }
+    #[cfg(not(feature = "async-runtime"))]
+    fn has_conn(&self, _addr: &SocketAddr) -> bool {
+        false
+    }
+    /// Close the DTLS and WebSocket conns idle for longer than max_idle
+    /// without an MQTT-SN connection, e.g. after the keep-alive expired,
+    /// and the DTLS sessions to rekey, see Hub::rekey().
+    #[cfg(feature = "async-runtime")]
+    pub fn run_hub_eviction(&self, max_idle: Duration) {
+        let client = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HUB_EVICTION_INTERVAL);
+            loop {
+                interval.tick().await;
+                let evicted = client
+                    .hub
+                    .evict_idle(max_idle, |socket_addr| {
+                        let _context = client.context.enter();
+                        Connection::is_connected(socket_addr)
+                    })
+                    .await;
+                if !evicted.is_empty() {
+                    debug!("{}", eformat!("evicted", evicted.len()));
+                }
+                let rekeyed = client.hub.rekey().await;
+                if !rekeyed.is_empty() {
+                    debug!("{}", eformat!("rekeyed", rekeyed.len()));
+                }
+            }
+        });
+    }
+    /// Dispatch the received datagrams to the message handlers. The
+    /// blocking recv() runs in a dedicated thread, not in a tokio worker,
+    /// the thread ends when the ingress channel is disconnected. The
+    /// waiting datagrams are drained up to INGRESS_BATCH_LEN, the PUBACKs
+    /// among them are processed together, before the next other message.
+    pub fn handle_ingress(self) {
+        let builder = thread::Builder::new().name("ingress_thread".into());
+        let result = builder.spawn(move || {
+            #[cfg(feature = "health")]
+            let _worker = Health::start(self.context, Worker::Ingress);
+            let mut acks = Vec::with_capacity(INGRESS_BATCH_LEN);
+            while let Ok(first) = self.ingress_rx.recv() {
+                let waiting =
+                    self.ingress_rx.try_iter().take(INGRESS_BATCH_LEN - 1);
+                for (addr, bytes, conn) in std::iter::once(first).chain(waiting)
+                {
+                    if PubAck::is_puback(&bytes)
+                        && !Forwarder::is_encapsulated(&bytes)
+                    {
+                        acks.push((addr, bytes));
                         continue;
                     }
+                    self.dispatch_acks(&mut acks);
+                    self.dispatch(addr, bytes, conn);
                 }
+                self.dispatch_acks(&mut acks);
             }
+            error!("{}", eformat!("ingress channel disconnected"));
         });
+        if let Err(why) = result {
+            error!("{}", eformat!(why));
+        }
     }
 
-    pub fn broker_rx_loop(self, socket: UdpSocket) {
+    /// The PUBACKs batched by handle_ingress(), the checks of dispatch()
+    /// then PubAck::recv_batch().
+    fn dispatch_acks(&self, acks: &mut Vec<(SocketAddr, Bytes)>) {
+        if acks.is_empty() {
+            return;
+        }
+        let _context = self.context.enter();
+        let acks: Vec<(SocketAddr, Bytes)> = acks
+            .drain(..)
+            .filter(|(addr, bytes)| {
+                if Admin::is_banned(&addr.ip()) {
+                    return false;
+                }
+                SysStats::add_bytes(bytes.len());
+                let _result = KeepAliveTimeWheel::reschedule(*addr);
+                if !Connection::contains_key(*addr)
+                    && !ClientMode::contains(addr)
+                {
+                    error!("{}", "No connection found");
+                    return false;
+                }
+                true
+            })
+            .collect();
+        PubAck::recv_batch(&acks, self);
+    }
+
+    /// Process one datagram received from addr.
+    pub fn dispatch(
+        &self,
+        addr: SocketAddr,
+        bytes: Bytes,
+        conn: Arc<dyn Conn + Send + Sync>,
+    ) {
+        let _context = self.context.enter();
+        // Before any state is created for the sender, e.g. a virtual
+        // address or a fragment buffer.
+        if Admin::is_banned(&addr.ip()) {
+            return;
+        }
+        // Frames from a forwarder carry the message of a
+        // wireless node, keyed on its virtual address.
+        let (addr, bytes) = if Forwarder::is_encapsulated(&bytes) {
+            match Forwarder::decapsulate(addr, &bytes) {
+                // A node can be banned by its virtual address.
+                Ok((addr, _bytes)) if Admin::is_banned(&addr.ip()) => return,
+                Ok(node) => node,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            }
+        } else {
+            (addr, bytes)
+        };
+        // The message is dispatched once all its fragments are received.
+        #[cfg(feature = "fragmentation")]
+        let bytes = if Fragment::is_fragment(&bytes) {
+            match Fragment::recv(self, addr, &bytes) {
+                Ok(Some(message)) => message,
+                Ok(None) => return,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            }
+        } else {
+            bytes
+        };
+        let _conn_span = MsgSpan::conn(addr).entered();
+        let buf = &bytes[..];
+        let size = bytes.len();
+        SysStats::add_bytes(size);
+        // Update the last seen time of the client.
+        let _result = KeepAliveTimeWheel::reschedule(addr);
+        // SUBSCRIBE messages batched in one datagram, see SubscribeBatch.
+        if let Some(messages) = SubscribeBatch::split(buf) {
+            let result = match panic::catch_unwind(AssertUnwindSafe(|| {
+                SubscribeBatch::recv(buf, &messages, self, addr, conn)
+            })) {
+                Ok(result) => result,
+                Err(_) => Err(eformat!(addr, "panic", size)),
+            };
+            if let Err(e) = result {
+                error!("{}", e);
+            }
+            return;
+        }
+        // Parse the message header: length, and message type.
+        let msg_header = match MsgHeader::try_read(&buf, size, addr, conn) {
+            Ok(header) => header,
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        };
+        let msg_type = msg_header.msg_type;
+        let fn_index = msg_header.msg_type as usize;
+        // Existing MQTT-SN connection or new connection.
+        // DTLS connection is created at lower layer.
+        // TODO: the broadcast messages doesn't have connection.
+        // TODO: broadcast messages are not encrypted.
+        // The CONNECT of a connected client is a new connection setup, see
+        // Connection::try_insert().
+        if !Connection::contains_key(addr) && !ClientMode::contains(&addr) {
+            // Existing connection shouldn't receive CONNECT message.
+            // QoS -1 PUBLISH doesn't need a connection.
+            if msg_type != MSG_TYPE_CONNECT
+                && !Publish::is_qos_minus_one(buf, &msg_header)
+            {
+                error!("{}", "No connection found");
+                return;
+            }
+        }
+        if fn_index >= INGRESS_FUNCTIONS.len() {
+            error!(
+                "{}",
+                eformat!(
+                    msg_header.remote_socket_addr,
+                    "Invalid message type",
+                    fn_index
+                )
+            );
+            return;
+        }
+        // A malformed datagram that panics a decoder is dropped, the rx
+        // thread keeps running.
+        let result = match panic::catch_unwind(AssertUnwindSafe(|| {
+            if msg_type == MSG_TYPE_PUBLISH {
+                // The data of the PUBLISH is a slice of the received bytes.
+                Publish::recv_bytes(&bytes, self, msg_header)
+            } else {
+                // The recv functions read the 1-octet length field layout.
+                let (buf, size) = msg_header.short_view(buf, size);
+                INGRESS_FUNCTIONS[fn_index](buf, size, self, msg_header.clone())
+            }
+        })) {
+            Ok(result) => result,
+            Err(_) => Err(eformat!(addr, "panic", msg_type, size)),
+        };
+        if result.is_err() {
+            error!("{}", result.unwrap_err());
+        }
+    }
+
+    /// Start the broker threads and a listener per socket, the multicast
+    /// groups are the groups of the first socket.
+    pub fn broker_rx_loop(self, sockets: Vec<UdpSocket>) {
+        // The time wheels are initialized in the context of the broker.
+        let _context = self.context.enter();
         let self_transmit = self.clone();
         // name for easy debug
-        let socket_tx = socket.try_clone().expect("couldn't clone the socket");
         let builder = thread::Builder::new().name("recv_thread".into());
 
-        let broadcast_socket_addr =
-            "224.0.0.123:61000".parse::<SocketAddr>().unwrap();
-        let gateway_info_socket_addr =
-            "224.0.0.123:62000".parse::<SocketAddr>().unwrap();
+        let local_addr = match sockets.first().map(UdpSocket::local_addr) {
+            Some(Ok(local_addr)) => local_addr,
+            Some(Err(why)) => {
+                error!("{}", eformat!(why));
+                return;
+            }
+            None => {
+                error!("{}", eformat!("no listener socket"));
+                return;
+            }
+        };
+        let (broadcast_socket_addr, gateway_info_socket_addr) =
+            multicast_groups(&local_addr);
+        // Restored before the first message is received.
+        Persistence::install(self.storage.clone());
+        match Retain::restore() {
+            Ok(0) => (),
+            Ok(count) => info!("{} retained messages restored", count),
+            Err(why) => error!("{}", why),
+        }
+        if let Err(why) = Listeners::run(self.clone(), sockets) {
+            error!("{}", why);
+        }
+        info!("{}", self.banner());
 
-        KeepAliveTimeWheel::init();
+        KeepAliveTimeWheel::init_with(self.timer_config);
+        KeepAliveTimeWheel::set_clock(self.clock.clone());
         KeepAliveTimeWheel::run(self.clone());
-        RetransTimeWheel::init();
+        RetransTimeWheel::init_with(self.timer_config);
+        RetransTimeWheel::set_clock(self.clock.clone());
         RetransTimeWheel::run(self.clone());
-        Advertise::run(broadcast_socket_addr, 5, 2);
-        GwInfo::run(gateway_info_socket_addr);
+        if self.advertise_duration > 0 {
+            Advertise::run(
+                broadcast_socket_addr,
+                self.gw_id,
+                self.advertise_duration,
+            );
+        }
+        GwInfo::run(gateway_info_socket_addr, self.clone());
+        LocalConsumer::run();
+        AlertRules::run(self.clone());
+        SysStats::run();
+        if self.sys_interval > 0 {
+            SysTopics::run(self.clone());
+        }
 
         // client runs this to search for gateway.
         // SearchGw::run(gateway_info_socket_addr, 2, 2);
@@ -417,118 +853,175 @@ impl MqttSnClient {
         let builder = thread::Builder::new().name("transmit_rx_thread".into());
         // process input datagram from network
         let egress_tx = self.egress_tx.clone();
-        let _transmit_rx_thread = builder.spawn(move || loop {
-            match self_transmit.transmit_rx.recv() {
-                Ok((addr, bytes)) => {
-                    // TODO DTLS
-                    dbg!((addr, &bytes));
-
-                    let new_bytes = bytes.clone();
-                    egress_tx.send((addr, new_bytes)).unwrap();
-
-                    match socket_tx.send_to(&bytes[..], addr) {
-                        Ok(size) if size == bytes.len() => (),
-                        Ok(size) => {
-                            error!(
-                                "send_to: {} bytes sent, but {} bytes expected",
-                                size,
-                                bytes.len()
-                            );
-                        }
-                        Err(why) => {
+        let _transmit_rx_thread = builder.spawn(move || {
+            let _context = self_transmit.context.enter();
+            #[cfg(feature = "health")]
+            let _worker =
+                Health::start(self_transmit.context, Worker::Transmit);
+            loop {
+                match self_transmit.transmit_rx.recv() {
+                    Ok((addr, bytes)) => {
+                        dbg!((addr, &bytes));
+                        // A peer with a DTLS session is answered through
+                        // the hub, see handle_egress().
+                        if self_transmit.has_conn(&addr) {
+                            if let Err(why) = egress_tx.send((addr, bytes)) {
+                                error!("{}", eformat!(addr, why));
+                            }
+                        } else if let Err(why) =
+                            Listeners::send_to(addr, &bytes[..])
+                        {
                             error!("{}", why);
                         }
                     }
-                }
-                Err(why) => {
-                    println!("channel_rx_thread: {}", why);
+                    // All the senders are dropped.
+                    Err(why) => {
+                        error!("{}", eformat!(why));
+                        break;
+                    }
                 }
             }
         });
     }
 
-    /*
-    pub fn connect(mut self, flags: u8, client_id: String, socket: UdpSocket) {
-        let self_time_wheel = self.clone();
-        let self_transmit = self.clone();
-        let socket_tx = socket.try_clone().expect("couldn't clone the socket");
-        let builder = thread::Builder::new().name("send_thread".into());
-        // process input datagram from network
-        let _send_thread = builder.spawn(move || loop {
-            match self_transmit.transmit_rx.recv() {
-                Ok((addr, bytes)) => {
-                    // TODO DTLS
-                    dbg!(("#####", addr, &bytes));
-                    let _result = socket_tx.send_to(&bytes[..], addr);
-                }
-                Err(why) => {
-                    println!("channel_rx_thread: {}", why);
-                }
-            }
-        });
-        dbg!(&client_id);
-        let duration = 5;
-        let client_id = Bytes::from(client_id);
-        let _result = Connect::send(flags, 1, duration, client_id, &self);
-        dbg!(*self.state.lock().unwrap());
-        let cur_state = *self.state.lock().unwrap();
-        *self.state.lock().unwrap() = self
-            .state_machine
-            .transition(cur_state, MSG_TYPE_CONNECT)
-            .unwrap();
-        dbg!(*self.state.lock().unwrap());
-        'outer: loop {
-            let mut buf = [0; 1500];
-            match socket.recv_from(&mut buf) {
-                Ok((size, addr)) => {
-                    dbg!((size, addr, buf));
-                    self.remote_addr = addr;
-                    // TODO process 3 bytes length
-                    let msg_type = buf[1] as u8;
-                    if msg_type == MSG_TYPE_CONNACK {
-                        match ConnAck::recv(&buf, size, &self) {
-                            Ok(_) => {
-                                dbg!(*self.state.lock().unwrap());
-                                let cur_state = *self.state.lock().unwrap();
-                                *self.state.lock().unwrap() = self
-                                    .state_machine
-                                    .transition(cur_state, MSG_TYPE_CONNACK)
-                                    .unwrap();
-                                dbg!(*self.state.lock().unwrap());
-                            }
-                            Err(why) => error!("ConnAck {:?}", why),
-                        }
-                        break 'outer;
-                    };
-                }
-                Err(why) => {
-                    error!("{}", why);
-                }
-            }
-        }
+    /// Version, gateway id, listeners and features, logged at startup.
+    fn banner(&self) -> String {
+        let features: Vec<&str> = FEATURES
+            .iter()
+            .filter(|(_name, enabled)| *enabled)
+            .map(|(name, _enabled)| *name)
+            .collect();
+        format!(
+            "{} {}, gateway {}, listening on {:?}, features [{}]",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            self.gw_id,
+            Listeners::local_addrs(),
+            features.join(", ")
+        )
     }
-        */
 
-    /* XXX TODO client code.
+    /// Receive the messages published to the topics matching the filter.
     pub fn subscribe(
         &self,
-        topic: String,
-        msg_id: u16,
+        filter: &str,
+    ) -> Result<&Receiver<RichPublish>, String> {
+        RichPublish::subscribe(filter)?;
+        Ok(&self.subscribe_rx)
+    }
+
+    /// Set the payload metadata of the topic name or filter, delivered with
+    /// the RichPublish, see TopicMetadata. Returns the previous one.
+    pub fn set_topic_metadata(
+        &self,
+        filter: &str,
+        metadata: TopicMetadata,
+    ) -> Result<Option<TopicMetadata>, String> {
+        TopicMetadata::set(filter, metadata)
+    }
+    /// Remove the metadata set with set_topic_metadata().
+    pub fn remove_topic_metadata(&self, filter: &str) -> Option<TopicMetadata> {
+        TopicMetadata::remove(filter)
+    }
+
+    /// Latest value of the topic, see LastValueCache::enable().
+    pub fn last_value(&self, topic: &str) -> Option<LastValue> {
+        let _context = self.context.enter();
+        let topic_id = get_topic_id(topic)?;
+        LastValueCache::get(topic_id)
+    }
+
+    /// Publish to the subscribers of the topic name, the topic name is
+    /// registered if it's new. Returns the topic id.
+    pub fn publish_by_name(
+        &self,
+        topic: &str,
         qos: u8,
         retain: u8,
-    ) -> &Receiver<Publish> {
-        let _result = Subscribe::send(topic, msg_id, qos, retain, self);
-        &self.subscribe_rx
+        payload: &[u8],
+    ) -> Result<TopicIdType, String> {
+        let _context = self.context.enter();
+        let data = Bytes::copy_from_slice(payload);
+        Publish::send_by_name(topic, qos, retain, data, self)
+    }
+
+    /// Statistics of the connection of addr, None without a connection.
+    pub fn connection_info(&self, addr: SocketAddr) -> Option<ConnectionInfo> {
+        let _context = self.context.enter();
+        ConnectionInfo::get(addr)
+    }
+    /// Statistics of all the connections, sorted by address.
+    pub fn list_connections(&self) -> Vec<ConnectionInfo> {
+        let _context = self.context.enter();
+        ConnectionInfo::list()
     }
+
+    /// The subscriptions as JSON, see SubscriptionExport.
+    pub fn export_subscriptions(&self) -> Result<String, String> {
+        let _context = self.context.enter();
+        SubscriptionExport::export()
+    }
+    /// Import the subscriptions exported by export_subscriptions(), returns
+    /// the number of records.
+    pub fn import_subscriptions(&self, json: &str) -> Result<usize, String> {
+        let _context = self.context.enter();
+        SubscriptionExport::import(json, self)
+    }
+
+    /// Client mode, connect to the gateway as an MQTT-SN client.
+    /// conn is the connection to the gateway, registered with the hub.
+    /// Blocks until the CONNACK is received or options.timeout.
+    pub fn connect(
+        &self,
+        gateway: SocketAddr,
+        conn: Arc<dyn Conn + Send + Sync>,
+        options: ConnectOptions,
+    ) -> Result<(), String> {
+        let _context = self.context.enter();
+        ClientMode::connect(self, gateway, conn, options)
+    }
+    /// Client mode, the messages are delivered to subscribe_rx.
+    pub fn subscribe_topic(
+        &self,
+        gateway: SocketAddr,
+        topic: &str,
+        qos: u8,
+    ) -> Result<(), String> {
+        let _context = self.context.enter();
+        ClientMode::subscribe(self, gateway, topic, qos)
+    }
+    /// Client mode, subscribe to the topics with one datagram, the gateway
+    /// must support the batched SUBSCRIBE extension, see SubscribeBatch.
+    /// Returns the msg_id of each SUBSCRIBE.
+    pub fn subscribe_topics(
+        &self,
+        gateway: SocketAddr,
+        topics: &[(&str, u8)],
+    ) -> Result<Vec<u16>, String> {
+        let _context = self.context.enter();
+        ClientMode::subscribe_batch(self, gateway, topics)
+    }
+    /// Client mode, publish to a topic id of the gateway.
     pub fn publish(
         &self,
+        gateway: SocketAddr,
         topic_id: u16,
-        msg_id: u16,
         qos: u8,
         retain: u8,
-        data: String,
-    ) {
-        let _result = Publish::send(topic_id, msg_id, qos, retain, data, &self);
+        data: &[u8],
+    ) -> Result<(), String> {
+        let _context = self.context.enter();
+        ClientMode::publish(self, gateway, topic_id, qos, retain, data)
+    }
+    /// Client mode, end the session with the gateway.
+    pub fn disconnect(&self, gateway: SocketAddr) -> Result<(), String> {
+        let _context = self.context.enter();
+        ClientMode::disconnect(self, gateway)
+    }
+    /// Client mode, stop the PINGREQs and the reconnects of the session
+    /// without a DISCONNECT, false if there is no session.
+    pub fn stop_keep_alive(&self, gateway: SocketAddr) -> bool {
+        let _context = self.context.enter();
+        ClientMode::stop(&gateway)
     }
-    */
 }
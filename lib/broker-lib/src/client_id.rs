@@ -1,41 +1,122 @@
 /// Client Id BisetMap stores client id and its socket addresses.
+///
+/// The client id of a CONNECT is 1-23 characters long, section 5.4.4 of the
+/// spec, the characters are [0-9a-zA-Z] like the ones an MQTT server must
+/// accept. ClientIdRules relaxes the length and the characters.
 use bisetmap::BisetMap;
 use bytes::Bytes;
 use std::net::SocketAddr;
 use std::sync::Mutex;
 
-lazy_static! {
-    static ref CLIENT_ID_MAP: Mutex<BisetMap<Bytes, SocketAddr>> =
-        Mutex::new(BisetMap::new());
+use crate::{broker_context::BrokerContext, eformat, function};
+
+/// Max. length of a client id in the spec.
+pub const CLIENT_ID_MAX_LEN: usize = 23;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientIdRules {
+    /// Max. length in bytes, an empty client id is always rejected.
+    pub max_len: usize,
+    /// Accept the printable ASCII characters, not only [0-9a-zA-Z].
+    pub printable: bool,
+}
+
+impl Default for ClientIdRules {
+    fn default() -> Self {
+        ClientIdRules {
+            max_len: CLIENT_ID_MAX_LEN,
+            printable: false,
+        }
+    }
+}
+
+impl ClientIdRules {
+    /// Printable client ids up to the max. length of a CONNECT.
+    pub fn relaxed() -> Self {
+        ClientIdRules {
+            max_len: u16::MAX as usize,
+            printable: true,
+        }
+    }
+    pub fn check(&self, client_id: &Bytes) -> Result<(), String> {
+        if client_id.is_empty() || client_id.len() > self.max_len {
+            return Err(eformat!("client id len", client_id.len()));
+        }
+        let allowed = |c: &u8| {
+            if self.printable {
+                c.is_ascii_graphic()
+            } else {
+                c.is_ascii_alphanumeric()
+            }
+        };
+        if !client_id.iter().all(allowed) {
+            return Err(eformat!("client id characters", client_id));
+        }
+        Ok(())
+    }
+}
+
+/// Client ids of a broker, see BrokerContext.
+pub(crate) struct ClientIdState {
+    client_id_map: Mutex<BisetMap<Bytes, SocketAddr>>,
+    rules: Mutex<ClientIdRules>,
+}
+
+impl Default for ClientIdState {
+    fn default() -> Self {
+        ClientIdState {
+            client_id_map: Mutex::new(BisetMap::new()),
+            rules: Mutex::new(ClientIdRules::default()),
+        }
+    }
+}
+
+#[inline(always)]
+fn state() -> &'static ClientIdState {
+    &BrokerContext::current().client_id
 }
 
 #[derive(Debug, Clone)]
 pub struct ClientId {}
 
 impl ClientId {
+    pub fn set_rules(rules: ClientIdRules) {
+        *state().rules.lock().unwrap() = rules;
+    }
+    pub fn rules() -> ClientIdRules {
+        *state().rules.lock().unwrap()
+    }
+    /// Check the client id of a CONNECT with the rules of the broker.
+    pub fn validate(client_id: &Bytes) -> Result<(), String> {
+        ClientId::rules().check(client_id)
+    }
     pub fn insert(client_id: Bytes, val: SocketAddr) {
-        CLIENT_ID_MAP.lock().unwrap().insert(client_id, val);
+        state().client_id_map.lock().unwrap().insert(client_id, val);
     }
     pub fn exists(client_id: &Bytes) -> bool {
-        CLIENT_ID_MAP.lock().unwrap().key_exists(client_id)
+        state().client_id_map.lock().unwrap().key_exists(client_id)
     }
     pub fn contains(client_id: &Bytes, val: &SocketAddr) -> bool {
-        CLIENT_ID_MAP.lock().unwrap().contains(client_id, val)
+        state()
+            .client_id_map
+            .lock()
+            .unwrap()
+            .contains(client_id, val)
     }
     pub fn get(client_id: &Bytes) -> Vec<SocketAddr> {
-        CLIENT_ID_MAP.lock().unwrap().get(client_id)
+        state().client_id_map.lock().unwrap().get(client_id)
     }
     pub fn rev_get(client_id: &SocketAddr) -> Vec<Bytes> {
-        CLIENT_ID_MAP.lock().unwrap().rev_get(client_id)
+        state().client_id_map.lock().unwrap().rev_get(client_id)
     }
     pub fn delete(client_id: &Bytes) -> Vec<SocketAddr> {
-        CLIENT_ID_MAP.lock().unwrap().delete(client_id)
+        state().client_id_map.lock().unwrap().delete(client_id)
     }
     pub fn rev_delete(client_id: &SocketAddr) -> Vec<Bytes> {
-        CLIENT_ID_MAP.lock().unwrap().rev_delete(client_id)
+        state().client_id_map.lock().unwrap().rev_delete(client_id)
     }
     pub fn debug() {
-        let cache = CLIENT_ID_MAP.lock().unwrap();
+        let cache = state().client_id_map.lock().unwrap();
         dbg!(&cache);
     }
 }
@@ -71,3 +152,18 @@ fn test_client_id() {
     let val = ClientId::exists(&bytes);
     dbg!(val);
 }
+#[cfg(test)]
+#[test]
+fn test_client_id_rules() {
+    let strict = ClientIdRules::default();
+    assert!(strict.check(&Bytes::from("sensor42")).is_ok());
+    assert!(strict.check(&Bytes::from("a".repeat(23))).is_ok());
+    assert!(strict.check(&Bytes::from("a".repeat(24))).is_err());
+    assert!(strict.check(&Bytes::new()).is_err());
+    assert!(strict.check(&Bytes::from("sensor_42")).is_err());
+    let relaxed = ClientIdRules::relaxed();
+    assert!(relaxed.check(&Bytes::from("sensor_42/kitchen")).is_ok());
+    assert!(relaxed.check(&Bytes::from("a".repeat(24))).is_ok());
+    assert!(relaxed.check(&Bytes::from("sensor 42")).is_err());
+    assert!(relaxed.check(&Bytes::new()).is_err());
+}
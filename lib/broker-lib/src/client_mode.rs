@@ -0,0 +1,764 @@
+/// Client mode, the crate as an MQTT-SN client of a gateway.
+///
+/// MqttSnClient::connect() sends CONNECT and blocks until the CONNACK, the
+/// WILLTOPICREQ and WILLMSGREQ prompts of the gateway are answered with
+/// ConnectOptions::will. While connected, the client_ping_thread sends a
+/// PINGREQ every ConnectOptions::ping_interval, duration/2 seconds by
+/// default, the session is lost after MAX_MISSED_PINGS PINGRESPs are
+/// missed. With ConnectOptions::reconnect, the thread then connects again
+/// with an exponential backoff and subscribes again to the topics of the
+/// lost session. ClientMode::stop() or disconnect() ends the thread.
+/// The messages from the gateway go through handle_ingress() like the
+/// messages from the clients. PUBLISH messages are not sent to the local
+/// subscribers, they are delivered to subscribe_rx as RichPublish with the
+/// topic names from the SUBACKs.
+use bytes::Bytes;
+use crossbeam::channel::{bounded, Sender};
+use hashbrown::HashMap;
+use log::*;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    broker_lib::MqttSnClient,
+    conn::Conn,
+    connect::Connect,
+    disconnect::Disconnect,
+    eformat,
+    flags::{
+        flag_is_retain, flag_qos_level, flags_set, QoSConst, RetainConst,
+        CLEAN_SESSION_FALSE, CLEAN_SESSION_TRUE, DUP_FALSE, QOS_LEVEL_0,
+        QOS_LEVEL_1, QOS_LEVEL_2, RETAIN_FALSE, TOPIC_ID_TYPE_NORMAL,
+        WILL_FALSE, WILL_TRUE,
+    },
+    function,
+    msg_hdr::MsgHeader,
+    ping_req::PingReq,
+    pub_ack::PubAck,
+    publish::Publish,
+    retransmit::RetransTimeWheel,
+    rich_publish::RichPublish,
+    subscribe::Subscribe,
+    subscribe_batch::SubscribeBatch,
+    topic_metadata::TopicMetadata,
+    will_msg::WillMsg,
+    will_topic::WillTopic,
+    TopicIdType, MSG_TYPE_CONNACK, MSG_TYPE_CONNECT, RETURN_CODE_ACCEPTED,
+};
+
+/// MQTT-SN v1.2.
+const PROTOCOL_ID: u8 = 0x01;
+pub const MAX_MISSED_PINGS: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Will {
+    pub topic: String,
+    pub msg: String,
+    pub qos: QoSConst,
+    pub retain: RetainConst,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectOptions {
+    pub client_id: String,
+    /// Keep-alive duration in seconds, 0 disables the PINGREQ.
+    pub duration: u16,
+    pub clean_session: bool,
+    pub will: Option<Will>,
+    /// How long connect() waits for the CONNACK.
+    pub timeout: Duration,
+    /// Interval of the PINGREQs, None is duration/2.
+    pub ping_interval: Option<Duration>,
+    /// Reconnect when the session is lost, None doesn't reconnect.
+    pub reconnect: Option<ReconnectPolicy>,
+}
+
+impl ConnectOptions {
+    pub fn new(client_id: &str) -> Self {
+        ConnectOptions {
+            client_id: client_id.to_string(),
+            duration: 60,
+            clean_session: true,
+            will: None,
+            timeout: Duration::from_secs(10),
+            ping_interval: None,
+            reconnect: None,
+        }
+    }
+    fn ping_interval(&self) -> Duration {
+        match self.ping_interval {
+            Some(interval) => interval,
+            None => Duration::from_secs(u64::max(self.duration as u64 / 2, 1)),
+        }
+    }
+}
+
+/// Reconnect of a lost session, see ConnectOptions::reconnect.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    /// Delay before the first attempt, doubled after each failed attempt.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// 0 retries until ClientMode::stop().
+    pub max_attempts: u32,
+    /// Subscribe again to the topics of the lost session.
+    pub resubscribe: bool,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_attempts: 0,
+            resubscribe: true,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Delay before the attempt, from 0.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt).unwrap_or(u32::MAX);
+        match self.initial_delay.checked_mul(factor) {
+            Some(delay) => delay.min(self.max_delay),
+            None => self.max_delay,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientState {
+    Connecting,
+    Connected,
+    Disconnecting,
+    /// PINGRESPs missed, the gateway is not reachable.
+    Lost,
+}
+
+struct Session {
+    options: ConnectOptions,
+    conn: Arc<dyn Conn + Send + Sync>,
+    state: ClientState,
+    conn_ack_tx: Sender<u8>,
+    missed_pings: u8,
+    /// Topic names of the SUBSCRIBE messages waiting for the SUBACK.
+    pending: HashMap<u16, String>,
+    /// Topic ids assigned by the gateway.
+    topics: HashMap<TopicIdType, String>,
+    /// Topic names and QoS of the SUBSCRIBE messages, subscribed again
+    /// after a reconnect.
+    subscriptions: Vec<(String, QoSConst)>,
+    /// Set by stop(), shared by the sessions of the reconnects.
+    stopped: Arc<AtomicBool>,
+}
+
+lazy_static! {
+    static ref ENABLED: AtomicBool = AtomicBool::new(false);
+    static ref SESSIONS: Mutex<HashMap<SocketAddr, Session>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientMode {}
+
+impl ClientMode {
+    /// Connect to the gateway, returns when the CONNACK is received.
+    /// Blocks the calling thread, don't call it from a tokio task.
+    pub fn connect(
+        client: &MqttSnClient,
+        gateway: SocketAddr,
+        conn: Arc<dyn Conn + Send + Sync>,
+        options: ConnectOptions,
+    ) -> Result<(), String> {
+        let stopped = Arc::new(AtomicBool::new(false));
+        ClientMode::connect_session(client, gateway, conn, options, stopped)
+    }
+
+    fn connect_session(
+        client: &MqttSnClient,
+        gateway: SocketAddr,
+        conn: Arc<dyn Conn + Send + Sync>,
+        options: ConnectOptions,
+        stopped: Arc<AtomicBool>,
+    ) -> Result<(), String> {
+        let (conn_ack_tx, conn_ack_rx) = bounded(1);
+        let will = match options.will {
+            Some(_) => WILL_TRUE,
+            None => WILL_FALSE,
+        };
+        let clean_session = if options.clean_session {
+            CLEAN_SESSION_TRUE
+        } else {
+            CLEAN_SESSION_FALSE
+        };
+        let flags = flags_set(
+            DUP_FALSE,
+            QOS_LEVEL_0,
+            RETAIN_FALSE,
+            will,
+            clean_session,
+            TOPIC_ID_TYPE_NORMAL,
+        );
+        let duration = options.duration;
+        let client_id = Bytes::from(options.client_id.clone());
+        let timeout = options.timeout;
+        let ping_interval = options.ping_interval();
+        SESSIONS.lock().unwrap().insert(
+            gateway,
+            Session {
+                options,
+                conn: conn.clone(),
+                state: ClientState::Connecting,
+                conn_ack_tx,
+                missed_pings: 0,
+                pending: HashMap::new(),
+                topics: HashMap::new(),
+                subscriptions: Vec::new(),
+                stopped: stopped.clone(),
+            },
+        );
+        ENABLED.store(true, Ordering::Relaxed);
+        let msg_header = MsgHeader::new(gateway, conn, MSG_TYPE_CONNECT);
+        if let Err(why) = Connect::send(
+            flags,
+            PROTOCOL_ID,
+            duration,
+            client_id,
+            client,
+            msg_header,
+        ) {
+            ClientMode::remove(&gateway);
+            return Err(why);
+        }
+        let return_code = match conn_ack_rx.recv_timeout(timeout) {
+            Ok(return_code) => return_code,
+            Err(why) => {
+                let _result = RetransTimeWheel::cancel_timer(
+                    gateway,
+                    MSG_TYPE_CONNACK,
+                    0,
+                );
+                ClientMode::remove(&gateway);
+                return Err(eformat!(gateway, "no CONNACK", why));
+            }
+        };
+        if return_code != RETURN_CODE_ACCEPTED {
+            ClientMode::remove(&gateway);
+            return Err(eformat!(gateway, "rejected", return_code));
+        }
+        if duration > 0 {
+            ClientMode::run_ping(
+                client.clone(),
+                gateway,
+                ping_interval,
+                stopped,
+            );
+        }
+        // The gateway answers if it supports fragmentation.
+        #[cfg(feature = "fragmentation")]
+        if crate::fragment::Fragment::is_enabled() {
+            crate::fragment::Fragment::announce(client, gateway)?;
+        }
+        Ok(())
+    }
+
+    /// Subscribe to the topic name, the topic id is known after the SUBACK.
+    /// QoS 2 is not supported in client mode, it's downgraded to QoS 1.
+    pub fn subscribe(
+        client: &MqttSnClient,
+        gateway: SocketAddr,
+        topic: &str,
+        qos: QoSConst,
+    ) -> Result<(), String> {
+        let qos = match qos {
+            QOS_LEVEL_2 => QOS_LEVEL_1,
+            _ => qos,
+        };
+        // Hold the lock until the msg_id is pending, the SUBACK might be
+        // received before Subscribe::send() returns.
+        let mut sessions = SESSIONS.lock().unwrap();
+        let session = match sessions.get_mut(&gateway) {
+            Some(session) => session,
+            None => return Err(eformat!(gateway, "not connected")),
+        };
+        let msg_header = MsgHeader::new(gateway, session.conn.clone(), 0);
+        let msg_id = Subscribe::send(
+            topic.to_string(),
+            qos,
+            RETAIN_FALSE,
+            client,
+            msg_header,
+        )?;
+        session.pending.insert(msg_id, topic.to_string());
+        session.remember(topic.to_string(), qos);
+        Ok(())
+    }
+
+    /// Subscribe to the topic names with a batched SUBSCRIBE, returns the
+    /// msg_ids in the same order. QoS 2 is downgraded to QoS 1.
+    pub fn subscribe_batch(
+        client: &MqttSnClient,
+        gateway: SocketAddr,
+        topics: &[(&str, QoSConst)],
+    ) -> Result<Vec<u16>, String> {
+        let topics: Vec<(String, QoSConst)> = topics
+            .iter()
+            .map(|(topic, qos)| match *qos {
+                QOS_LEVEL_2 => (topic.to_string(), QOS_LEVEL_1),
+                qos => (topic.to_string(), qos),
+            })
+            .collect();
+        // Hold the lock until the msg_ids are pending.
+        let mut sessions = SESSIONS.lock().unwrap();
+        let session = match sessions.get_mut(&gateway) {
+            Some(session) => session,
+            None => return Err(eformat!(gateway, "not connected")),
+        };
+        let msg_header = MsgHeader::new(gateway, session.conn.clone(), 0);
+        let msg_ids =
+            SubscribeBatch::send(&topics, RETAIN_FALSE, client, msg_header)?;
+        for (msg_id, (topic, qos)) in msg_ids.iter().zip(topics) {
+            session.pending.insert(*msg_id, topic.clone());
+            session.remember(topic, qos);
+        }
+        Ok(msg_ids)
+    }
+
+    pub fn publish(
+        client: &MqttSnClient,
+        gateway: SocketAddr,
+        topic_id: TopicIdType,
+        qos: QoSConst,
+        retain: RetainConst,
+        data: &[u8],
+    ) -> Result<(), String> {
+        match ClientMode::state(&gateway) {
+            Some(ClientState::Connected) => {}
+            state => return Err(eformat!(gateway, "not connected", state)),
+        }
+        Publish::send(
+            topic_id,
+            qos,
+            retain,
+            Bytes::copy_from_slice(data),
+            client,
+            gateway,
+        )
+    }
+
+    /// Send DISCONNECT, the session is removed when the gateway replies.
+    pub fn disconnect(
+        client: &MqttSnClient,
+        gateway: SocketAddr,
+    ) -> Result<(), String> {
+        let msg_header = ClientMode::msg_header(gateway)?;
+        ClientMode::stop(&gateway);
+        ClientMode::set_state(&gateway, ClientState::Disconnecting);
+        Disconnect::send(client, msg_header)
+    }
+
+    /// Topic id of a subscribed topic name.
+    pub fn topic_id(gateway: &SocketAddr, topic: &str) -> Option<TopicIdType> {
+        let sessions = SESSIONS.lock().unwrap();
+        let session = sessions.get(gateway)?;
+        session
+            .topics
+            .iter()
+            .find(|(_, name)| name.as_str() == topic)
+            .map(|(topic_id, _)| *topic_id)
+    }
+
+    #[inline(always)]
+    pub fn is_enabled() -> bool {
+        ENABLED.load(Ordering::Relaxed)
+    }
+    #[inline(always)]
+    pub fn contains(gateway: &SocketAddr) -> bool {
+        ClientMode::is_enabled()
+            && SESSIONS.lock().unwrap().contains_key(gateway)
+    }
+    pub fn state(gateway: &SocketAddr) -> Option<ClientState> {
+        SESSIONS
+            .lock()
+            .unwrap()
+            .get(gateway)
+            .map(|session| session.state)
+    }
+    fn set_state(gateway: &SocketAddr, state: ClientState) {
+        if let Some(session) = SESSIONS.lock().unwrap().get_mut(gateway) {
+            session.state = state;
+        }
+    }
+    fn msg_header(gateway: SocketAddr) -> Result<MsgHeader, String> {
+        match SESSIONS.lock().unwrap().get(&gateway) {
+            Some(session) => {
+                Ok(MsgHeader::new(gateway, session.conn.clone(), 0))
+            }
+            None => Err(eformat!(gateway, "not connected")),
+        }
+    }
+    /// Stop the PINGREQs and the reconnects of the session, the session
+    /// is kept. Returns false if there was no session with the gateway.
+    pub fn stop(gateway: &SocketAddr) -> bool {
+        match SESSIONS.lock().unwrap().get(gateway) {
+            Some(session) => {
+                session.stopped.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+    /// Returns false if there was no session with the gateway.
+    pub fn remove(gateway: &SocketAddr) -> bool {
+        let mut sessions = SESSIONS.lock().unwrap();
+        let removed = sessions.remove(gateway).is_some();
+        ENABLED.store(!sessions.is_empty(), Ordering::Relaxed);
+        removed
+    }
+
+    pub fn on_conn_ack(gateway: &SocketAddr, return_code: u8) {
+        let mut sessions = SESSIONS.lock().unwrap();
+        if let Some(session) = sessions.get_mut(gateway) {
+            if return_code == RETURN_CODE_ACCEPTED {
+                session.state = ClientState::Connected;
+            }
+            // connect() might have timed out, don't block.
+            let _result = session.conn_ack_tx.try_send(return_code);
+        }
+    }
+    pub fn on_will_topic_req(
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+    ) -> Result<(), String> {
+        let gateway = msg_header.remote_socket_addr;
+        match ClientMode::will(&gateway) {
+            Some(will) => WillTopic::send(
+                will.qos | will.retain,
+                will.topic,
+                client,
+                msg_header,
+            ),
+            None => Err(eformat!(gateway, "no will")),
+        }
+    }
+    pub fn on_will_msg_req(
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+    ) -> Result<(), String> {
+        let gateway = msg_header.remote_socket_addr;
+        match ClientMode::will(&gateway) {
+            Some(will) => WillMsg::send(will.msg, client, msg_header),
+            None => Err(eformat!(gateway, "no will")),
+        }
+    }
+    fn will(gateway: &SocketAddr) -> Option<Will> {
+        let sessions = SESSIONS.lock().unwrap();
+        sessions.get(gateway)?.options.will.clone()
+    }
+    pub fn on_ping_resp(gateway: &SocketAddr) {
+        if let Some(session) = SESSIONS.lock().unwrap().get_mut(gateway) {
+            session.missed_pings = 0;
+        }
+    }
+    pub fn on_sub_ack(
+        gateway: &SocketAddr,
+        msg_id: u16,
+        topic_id: TopicIdType,
+        return_code: u8,
+    ) {
+        if let Some(session) = SESSIONS.lock().unwrap().get_mut(gateway) {
+            if let Some(topic) = session.pending.remove(&msg_id) {
+                if return_code == RETURN_CODE_ACCEPTED {
+                    session.topics.insert(topic_id, topic);
+                }
+            }
+        }
+    }
+    /// Deliver the PUBLISH message from the gateway to subscribe_rx.
+    pub fn on_publish(
+        publish: &Publish,
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+    ) -> Result<(), String> {
+        let gateway = msg_header.remote_socket_addr;
+        let topic_id = *publish.topic_id();
+        let flags = *publish.flags();
+        match flag_qos_level(flags) {
+            QOS_LEVEL_1 => PubAck::send(
+                topic_id,
+                *publish.msg_id(),
+                RETURN_CODE_ACCEPTED,
+                client,
+                msg_header,
+            )?,
+            QOS_LEVEL_2 => {
+                return Err(eformat!(gateway, "QoS 2 not supported", topic_id))
+            }
+            _ => {}
+        }
+        let topic_name = SESSIONS
+            .lock()
+            .unwrap()
+            .get(&gateway)
+            .and_then(|session| session.topics.get(&topic_id).cloned());
+        let metadata = topic_name.as_deref().and_then(TopicMetadata::get);
+        let now = SystemTime::now();
+        let rich = RichPublish {
+            topic_id,
+            topic_name,
+            msg_id: *publish.msg_id(),
+            qos: flag_qos_level(flags),
+            retain: flag_is_retain(flags),
+            data: publish.data().clone(),
+            publisher: gateway,
+            metadata,
+            received_at: now,
+            forwarded_at: now,
+        };
+        match client.subscribe_tx.send(rich) {
+            Ok(()) => Ok(()),
+            Err(why) => Err(eformat!(gateway, why)),
+        }
+    }
+
+    /// Count the PINGREQ as missed until the PINGRESP, None when the
+    /// session is not connected.
+    fn ping_header(gateway: SocketAddr) -> Option<MsgHeader> {
+        let mut sessions = SESSIONS.lock().unwrap();
+        let session = sessions.get_mut(&gateway)?;
+        if session.state != ClientState::Connected {
+            return None;
+        }
+        if session.missed_pings >= MAX_MISSED_PINGS {
+            session.state = ClientState::Lost;
+            error!("{}", eformat!(gateway, "PINGRESP missed"));
+            return None;
+        }
+        session.missed_pings += 1;
+        Some(MsgHeader::new(gateway, session.conn.clone(), 0))
+    }
+    fn run_ping(
+        mut client: MqttSnClient,
+        gateway: SocketAddr,
+        interval: Duration,
+        stopped: Arc<AtomicBool>,
+    ) {
+        let builder = thread::Builder::new().name("client_ping_thread".into());
+        let _client_ping_thread = builder.spawn(move || {
+            let _context = client.context.enter();
+            loop {
+                if !ClientMode::sleep(interval, &stopped) {
+                    break;
+                }
+                let msg_header = match ClientMode::ping_header(gateway) {
+                    Some(msg_header) => msg_header,
+                    None => {
+                        if ClientMode::state(&gateway)
+                            == Some(ClientState::Lost)
+                        {
+                            ClientMode::reconnect(&client, gateway, &stopped);
+                        }
+                        break;
+                    }
+                };
+                // No client id, the client is active, not sleeping.
+                if let Err(why) =
+                    PingReq::send(String::new(), &mut client, msg_header)
+                {
+                    error!("{}", why);
+                }
+            }
+        });
+    }
+    /// Connect the lost session again with its options, until an attempt
+    /// succeeds, the attempts of the policy are exhausted or stop(). The
+    /// connected session runs its own client_ping_thread.
+    fn reconnect(
+        client: &MqttSnClient,
+        gateway: SocketAddr,
+        stopped: &Arc<AtomicBool>,
+    ) {
+        let (options, conn, subscriptions) = {
+            let sessions = SESSIONS.lock().unwrap();
+            match sessions.get(&gateway) {
+                Some(session) => (
+                    session.options.clone(),
+                    session.conn.clone(),
+                    session.subscriptions.clone(),
+                ),
+                None => return,
+            }
+        };
+        let policy = match options.reconnect.clone() {
+            Some(policy) => policy,
+            None => return,
+        };
+        let mut attempt = 0;
+        loop {
+            if policy.max_attempts > 0 && attempt >= policy.max_attempts {
+                ClientMode::remove(&gateway);
+                error!("{}", eformat!(gateway, "reconnect failed", attempt));
+                return;
+            }
+            if !ClientMode::sleep(policy.delay(attempt), stopped) {
+                return;
+            }
+            attempt += 1;
+            match ClientMode::connect_session(
+                client,
+                gateway,
+                conn.clone(),
+                options.clone(),
+                stopped.clone(),
+            ) {
+                Ok(()) => break,
+                Err(why) => error!("{}", why),
+            }
+        }
+        info!("{}", eformat!(gateway, "reconnected", attempt));
+        if !policy.resubscribe {
+            return;
+        }
+        for (topic, qos) in subscriptions {
+            if let Err(why) =
+                ClientMode::subscribe(client, gateway, &topic, qos)
+            {
+                error!("{}", why);
+            }
+        }
+    }
+    /// Sleep for duration, false if stopped before or during the sleep.
+    fn sleep(duration: Duration, stopped: &AtomicBool) -> bool {
+        let step = Duration::from_millis(100);
+        let mut slept = Duration::from_secs(0);
+        while slept < duration {
+            if stopped.load(Ordering::Relaxed) {
+                return false;
+            }
+            let nap = step.min(duration - slept);
+            thread::sleep(nap);
+            slept += nap;
+        }
+        !stopped.load(Ordering::Relaxed)
+    }
+}
+
+impl Session {
+    /// Keep the topic for a reconnect, once.
+    fn remember(&mut self, topic: String, qos: QoSConst) {
+        match self
+            .subscriptions
+            .iter_mut()
+            .find(|(name, _)| *name == topic)
+        {
+            Some(subscription) => subscription.1 = qos,
+            None => self.subscriptions.push((topic, qos)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_client_mode_connect() {
+        use super::*;
+        use crate::conn::udp_conn;
+        use crate::{
+            conn_ack::ConnAck, sub_ack::SubAck, will_msg_req::WillMsgReq,
+            will_topic_req::WillTopicReq, MSG_LEN_CONNACK, MSG_LEN_PUBACK,
+            MSG_LEN_SUBACK, MSG_LEN_WILL_MSG_REQ, MSG_LEN_WILL_TOPIC_REQ,
+            MSG_TYPE_PUBACK, MSG_TYPE_SUBACK, MSG_TYPE_WILL_MSG,
+            MSG_TYPE_WILL_MSG_REQ, MSG_TYPE_WILL_TOPIC,
+            MSG_TYPE_WILL_TOPIC_REQ,
+        };
+        RetransTimeWheel::init();
+        let client = MqttSnClient::new();
+        let conn = udp_conn();
+        let gateway = "127.0.0.1:1900".parse::<SocketAddr>().unwrap();
+        let mut options = ConnectOptions::new("client_mode");
+        options.duration = 0;
+        options.will = Some(Will {
+            topic: "client/will".to_string(),
+            msg: "gone".to_string(),
+            qos: QOS_LEVEL_0,
+            retain: RETAIN_FALSE,
+        });
+        let connect_client = client.clone();
+        let connect_conn = conn.clone();
+        let connect_thread = thread::spawn(move || {
+            ClientMode::connect(&connect_client, gateway, connect_conn, options)
+        });
+        // Play the gateway: CONNECT, WILLTOPICREQ, WILLMSGREQ and CONNACK.
+        let recv = |buf: &[u8]| {
+            MsgHeader::try_read(buf, buf.len(), gateway, conn.clone()).unwrap()
+        };
+        let (_, bytes) = client.egress_rx.recv().unwrap();
+        assert_eq!(bytes[1], MSG_TYPE_CONNECT);
+        assert_eq!(bytes[2] & WILL_TRUE, WILL_TRUE);
+        let buf = [MSG_LEN_WILL_TOPIC_REQ, MSG_TYPE_WILL_TOPIC_REQ];
+        WillTopicReq::recv(&buf, buf.len(), &client, recv(&buf)).unwrap();
+        let (_, bytes) = client.egress_rx.recv().unwrap();
+        assert_eq!(bytes[1], MSG_TYPE_WILL_TOPIC);
+        assert_eq!(&bytes[3..], b"client/will");
+        let buf = [MSG_LEN_WILL_MSG_REQ, MSG_TYPE_WILL_MSG_REQ];
+        WillMsgReq::recv(&buf, buf.len(), &client, recv(&buf)).unwrap();
+        let (_, bytes) = client.egress_rx.recv().unwrap();
+        assert_eq!(bytes[1], MSG_TYPE_WILL_MSG);
+        assert_eq!(&bytes[2..], b"gone");
+        let buf = [MSG_LEN_CONNACK, MSG_TYPE_CONNACK, RETURN_CODE_ACCEPTED];
+        ConnAck::recv(&buf, buf.len(), &client, recv(&buf)).unwrap();
+        assert_eq!(connect_thread.join().unwrap(), Ok(()));
+        assert_eq!(ClientMode::state(&gateway), Some(ClientState::Connected));
+
+        // SUBSCRIBE, the SUBACK assigns topic id 7.
+        ClientMode::subscribe(&client, gateway, "client/temp", QOS_LEVEL_2)
+            .unwrap();
+        let (_, bytes) = client.egress_rx.recv().unwrap();
+        assert_eq!(flag_qos_level(bytes[2]), QOS_LEVEL_1);
+        let msg_id = u16::from_be_bytes([bytes[3], bytes[4]]);
+        let [id0, id1] = msg_id.to_be_bytes();
+        let buf = [MSG_LEN_SUBACK, MSG_TYPE_SUBACK, 0, 0, 7, id0, id1, 0];
+        SubAck::recv(&buf, buf.len(), &client, recv(&buf)).unwrap();
+        assert_eq!(ClientMode::topic_id(&gateway, "client/temp"), Some(7));
+
+        // PUBLISH from the gateway is acknowledged and delivered.
+        let bytes =
+            Publish::encode(7, 3, QOS_LEVEL_1, RETAIN_FALSE, b"21.5").unwrap();
+        Publish::recv(&bytes, bytes.len(), &client, recv(&bytes)).unwrap();
+        let (_, bytes) = client.egress_rx.recv().unwrap();
+        assert_eq!(&bytes[..2], &[MSG_LEN_PUBACK, MSG_TYPE_PUBACK]);
+        let rich = client.subscribe_rx.try_recv().unwrap();
+        assert_eq!(rich.topic_name, Some("client/temp".to_string()));
+        assert_eq!(&rich.data[..], b"21.5");
+
+        // The topics kept for a reconnect, and stop().
+        let subscriptions =
+            SESSIONS.lock().unwrap()[&gateway].subscriptions.clone();
+        assert_eq!(
+            subscriptions,
+            vec![("client/temp".to_string(), QOS_LEVEL_2)]
+        );
+        assert!(ClientMode::stop(&gateway));
+        let stopped = SESSIONS.lock().unwrap()[&gateway].stopped.clone();
+        assert!(!ClientMode::sleep(Duration::from_secs(60), &stopped));
+        assert!(ClientMode::remove(&gateway));
+        assert!(!ClientMode::contains(&gateway));
+        assert!(!ClientMode::stop(&gateway));
+
+        // The backoff doubles up to max_delay.
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(3),
+            ..ReconnectPolicy::default()
+        };
+        let delays: Vec<u128> = (0..5)
+            .map(|attempt| policy.delay(attempt).as_millis())
+            .collect();
+        assert_eq!(delays, vec![500, 1000, 2000, 3000, 3000]);
+        assert_eq!(policy.delay(40), Duration::from_secs(3));
+    }
+}
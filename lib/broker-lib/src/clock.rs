@@ -0,0 +1,55 @@
+/// Time source of the keep-alive and retransmit time wheels.
+///
+/// The wheels process the ticks elapsed on their Clock, see
+/// TimerWheel::is_due(). SystemClock is the default. A MockClock only moves
+/// with advance(), a test calls KeepAliveTimeWheel::poll() or
+/// RetransTimeWheel::poll() after advance() and checks the expirations and
+/// the retransmits without waiting. MqttSnClient::with_clock() sets the
+/// clock of a broker.
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The monotonic clock of the system.
+#[derive(Debug, Clone, Default)]
+pub struct SystemClock {}
+
+impl Clock for SystemClock {
+    #[inline(always)]
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Virtual time, moved by advance().
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<Instant>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
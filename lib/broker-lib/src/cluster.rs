@@ -0,0 +1,735 @@
+/// Gateway clustering, brokers forward PUBLISH messages to each other.
+///
+/// Each broker of a cluster binds a cluster socket and has a node id and a
+/// list of peers. Every CLUSTER_DIGEST_INTERVAL it sends its subscription
+/// digest, the topic names and filters subscribed by its clients, to the
+/// peers. A PUBLISH from a local client is forwarded to the peers with a
+/// matching digest, unless its topic is local, see LocalTopics, with the
+/// topic name: the topic ids of the brokers are independent. The peer
+/// delivers it to its subscribers and, in a partial mesh, forwards it to
+/// its other peers.
+///
+/// The messages between the brokers are wrapped in an envelope:
+///
+/// Version Kind Origin Seq   Hops Body
+/// (0)     (1)  (2-5)  (6-9) (10) (11:n)
+///
+/// The Body of a PUBLISH has the flags, the topic name, the annotations of
+/// the message, see Annotations, and the data:
+///
+/// Flags Topic len Topic name Annotations len Annotations Data
+/// (0)   (1-2)     (3:m)      (m:m+2)         (m+2:k)     (k:n)
+///
+/// Each annotation is a key and a value with a u16 length. The trace id of
+/// MsgTrace is local to a broker and isn't forwarded.
+///
+/// Origin is the node id of the broker of the publisher, Seq its sequence
+/// number and Hops the number of forwards. A PUBLISH is dropped when it
+/// comes back to its origin, when (Origin, Seq) was already seen, or when
+/// it was forwarded max_hops times. The Seq of a digest is its generation,
+/// a digest larger than a datagram is sent in several parts.
+///
+/// The peers are trusted, the datagrams from other addresses are dropped
+/// and the cluster socket shouldn't be reachable by the clients. The digest
+/// of a peer expires after CLUSTER_DIGEST_TTL, e.g. when the peer is down.
+use bytes::{BufMut, Bytes, BytesMut};
+use hashbrown::{HashMap, HashSet};
+use log::*;
+use std::collections::VecDeque;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::{
+    annotation::Annotations,
+    broker_context::BrokerContext,
+    broker_lib::MqttSnClient,
+    codec::put_u16_be,
+    config::PreDefinedTopics,
+    eformat,
+    filter::{
+        get_subscribers_with_topic_id, get_subscriptions,
+        get_topic_id_with_topic_name, get_topic_name_with_topic_id,
+        match_topic, match_topics, try_insert_topic_name,
+    },
+    flags::{
+        flag_is_retain, flag_qos_level, QOS_LEVEL_0, QOS_LEVEL_3, RETAIN_FALSE,
+    },
+    function,
+    local_topics::LocalTopics,
+    msg_id::MsgIdAllocator,
+    msg_trace::TRACE_ANNOTATION_KEY,
+    publish::Publish,
+    retain::Retain,
+    rich_publish::RichPublish,
+    MTU,
+};
+
+pub const CLUSTER_VERSION: u8 = 2;
+pub const CLUSTER_DIGEST: u8 = 1;
+pub const CLUSTER_PUBLISH: u8 = 2;
+pub const CLUSTER_HEADER_LEN: usize = 11;
+pub const CLUSTER_MAX_HOPS: u8 = 4;
+pub const CLUSTER_DIGEST_INTERVAL: Duration = Duration::from_secs(5);
+pub const CLUSTER_DIGEST_TTL: Duration = Duration::from_secs(15);
+/// Number of (Origin, Seq) pairs remembered to drop the duplicates.
+pub const CLUSTER_SEEN_LEN: usize = 4096;
+/// Max length of a digest part.
+const CLUSTER_DIGEST_LEN: usize = MTU - 100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClusterConfig {
+    pub node_id: u32,
+    /// Cluster sockets of the other brokers.
+    pub peers: Vec<SocketAddr>,
+    /// Max number of forwards of a PUBLISH.
+    pub max_hops: u8,
+}
+
+impl ClusterConfig {
+    pub fn new(node_id: u32, peers: Vec<SocketAddr>) -> Self {
+        ClusterConfig {
+            node_id,
+            peers,
+            max_hops: CLUSTER_MAX_HOPS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Envelope {
+    kind: u8,
+    origin: u32,
+    seq: u32,
+    hops: u8,
+    body: Bytes,
+}
+
+#[derive(Debug)]
+struct PeerDigest {
+    generation: u32,
+    filters: Vec<String>,
+    received: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Seen {
+    set: HashSet<(u32, u32)>,
+    order: VecDeque<(u32, u32)>,
+}
+
+/// Cluster socket, peers and digests of a broker, see BrokerContext.
+#[derive(Default)]
+pub(crate) struct ClusterState {
+    node: RwLock<Option<(ClusterConfig, Arc<UdpSocket>)>>,
+    seq: AtomicU32,
+    digests: Mutex<HashMap<SocketAddr, PeerDigest>>,
+    seen: Mutex<Seen>,
+}
+
+#[inline(always)]
+fn state() -> &'static ClusterState {
+    &BrokerContext::current().cluster
+}
+
+#[derive(Debug, Clone)]
+pub struct Cluster {}
+
+impl Cluster {
+    /// Join the cluster with the socket, start the rx thread and the digest
+    /// thread.
+    pub fn run(
+        client: MqttSnClient,
+        socket: UdpSocket,
+        config: ClusterConfig,
+    ) -> Result<(), String> {
+        let _context = client.context.enter();
+        let local_addr = socket.local_addr().map_err(|why| eformat!(why))?;
+        let socket = Arc::new(socket);
+        *state().node.write().unwrap() = Some((config, socket.clone()));
+        let rx_client = client.clone();
+        let builder = thread::Builder::new().name("cluster_rx_thread".into());
+        let spawned = builder.spawn(move || {
+            let _context = rx_client.context.enter();
+            let mut buf = vec![0u8; 2 * MTU];
+            loop {
+                let (size, peer) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(why) => {
+                        error!("{}", eformat!(local_addr, why));
+                        continue;
+                    }
+                };
+                let bytes = Bytes::copy_from_slice(&buf[..size]);
+                if let Err(why) = Cluster::recv(peer, bytes, &rx_client) {
+                    error!("{}", why);
+                }
+            }
+        });
+        if let Err(why) = spawned {
+            return Err(eformat!(local_addr, why));
+        }
+        let builder =
+            thread::Builder::new().name("cluster_digest_thread".into());
+        let spawned = builder.spawn(move || {
+            let _context = client.context.enter();
+            loop {
+                if let Err(why) = Cluster::send_digest() {
+                    error!("{}", why);
+                }
+                thread::sleep(CLUSTER_DIGEST_INTERVAL);
+            }
+        });
+        if let Err(why) = spawned {
+            return Err(eformat!(local_addr, why));
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn is_enabled() -> bool {
+        state().node.read().unwrap().is_some()
+    }
+
+    /// Send the subscription digest to the peers now, e.g. after a
+    /// SUBSCRIBE, instead of waiting for the digest thread.
+    pub fn send_digest() -> Result<(), String> {
+        let (node_id, peers, socket) = match Cluster::node() {
+            Some(node) => node,
+            None => return Ok(()),
+        };
+        let generation = state().seq.fetch_add(1, Ordering::Relaxed);
+        let mut parts = vec![BytesMut::new()];
+        for filter in Cluster::local_filters() {
+            if 2 + filter.len() > CLUSTER_DIGEST_LEN {
+                warn!("{}", eformat!("filter too long", filter));
+                continue;
+            }
+            if parts.last().unwrap().len() + 2 + filter.len()
+                > CLUSTER_DIGEST_LEN
+            {
+                parts.push(BytesMut::new());
+            }
+            let part = parts.last_mut().unwrap();
+            put_u16_be(part, filter.len() as u16);
+            part.put_slice(filter.as_bytes());
+        }
+        for part in parts {
+            let bytes = Cluster::encode(&Envelope {
+                kind: CLUSTER_DIGEST,
+                origin: node_id,
+                seq: generation,
+                hops: 0,
+                body: part.freeze(),
+            });
+            for peer in peers.iter() {
+                Cluster::send_to(&socket, *peer, &bytes);
+            }
+        }
+        Ok(())
+    }
+
+    /// Forward a PUBLISH of a local client and its annotations to the peers
+    /// with a matching digest.
+    pub fn forward(publish: &Publish, annotations: &Annotations) {
+        let (node_id, _peers, socket) = match Cluster::node() {
+            Some(node) => node,
+            None => return,
+        };
+        let topic_id = *publish.topic_id();
+        let topic_name = match get_topic_name_with_topic_id(topic_id)
+            .or_else(|| PreDefinedTopics::name(topic_id))
+        {
+            Some(topic_name) => topic_name,
+            None => return,
+        };
+        // The $SYS topics are the statistics of each broker.
+        if topic_name.starts_with('$')
+            || LocalTopics::is_local_topic(&topic_name)
+        {
+            return;
+        }
+        let peers = Cluster::matching_peers(&topic_name, None);
+        if peers.is_empty() {
+            return;
+        }
+        let seq = state().seq.fetch_add(1, Ordering::Relaxed);
+        let annotations = Cluster::encode_annotations(annotations);
+        let mut body = BytesMut::with_capacity(
+            5 + topic_name.len() + annotations.len() + publish.data().len(),
+        );
+        body.put_u8(*publish.flags());
+        put_u16_be(&mut body, topic_name.len() as u16);
+        body.put_slice(topic_name.as_bytes());
+        put_u16_be(&mut body, annotations.len() as u16);
+        body.put_slice(&annotations);
+        body.put_slice(publish.data());
+        let bytes = Cluster::encode(&Envelope {
+            kind: CLUSTER_PUBLISH,
+            origin: node_id,
+            seq,
+            hops: 0,
+            body: body.freeze(),
+        });
+        for peer in peers {
+            Cluster::send_to(&socket, peer, &bytes);
+        }
+    }
+
+    /// Peers with a digest that isn't expired.
+    pub fn peers() -> Vec<SocketAddr> {
+        let digests = state().digests.lock().unwrap();
+        digests
+            .iter()
+            .filter(|(_, digest)| {
+                digest.received.elapsed() < CLUSTER_DIGEST_TTL
+            })
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+
+    /// Topic names and filters in the digest of the peer.
+    pub fn remote_filters(peer: &SocketAddr) -> Vec<String> {
+        let digests = state().digests.lock().unwrap();
+        digests
+            .get(peer)
+            .map_or_else(Vec::new, |digest| digest.filters.clone())
+    }
+
+    fn node() -> Option<(u32, Vec<SocketAddr>, Arc<UdpSocket>)> {
+        let node = state().node.read().unwrap();
+        let (config, socket) = node.as_ref()?;
+        Some((config.node_id, config.peers.clone(), socket.clone()))
+    }
+
+    /// Topic names and filters of the local subscriptions, sorted.
+    fn local_filters() -> Vec<String> {
+        let mut filters: Vec<String> = get_subscriptions()
+            .into_iter()
+            .filter_map(|(_socket_addr, topic_id, _qos)| {
+                get_topic_name_with_topic_id(topic_id)
+                    .or_else(|| PreDefinedTopics::name(topic_id))
+            })
+            .filter(|filter| !filter.starts_with('$'))
+            .collect();
+        filters.sort();
+        filters.dedup();
+        filters
+    }
+
+    fn matching_peers(
+        topic_name: &str,
+        except: Option<SocketAddr>,
+    ) -> Vec<SocketAddr> {
+        let digests = state().digests.lock().unwrap();
+        digests
+            .iter()
+            .filter(|(peer, digest)| {
+                Some(**peer) != except
+                    && digest.received.elapsed() < CLUSTER_DIGEST_TTL
+                    && digest.filters.iter().any(|filter| {
+                        filter == topic_name || match_topic(topic_name, filter)
+                    })
+            })
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+
+    fn recv(
+        peer: SocketAddr,
+        bytes: Bytes,
+        client: &MqttSnClient,
+    ) -> Result<(), String> {
+        let (node_id, peers, socket) = match Cluster::node() {
+            Some(node) => node,
+            None => return Ok(()),
+        };
+        if !peers.contains(&peer) {
+            return Err(eformat!(peer, "not a peer"));
+        }
+        let envelope = Cluster::decode(&bytes)
+            .ok_or_else(|| eformat!(peer, "invalid envelope", bytes.len()))?;
+        match envelope.kind {
+            CLUSTER_DIGEST => Cluster::on_digest(peer, envelope),
+            CLUSTER_PUBLISH => {
+                if envelope.origin == node_id || !Cluster::first_seen(&envelope)
+                {
+                    // Looped back.
+                    return Ok(());
+                }
+                Cluster::on_publish(peer, &envelope, &socket, client)
+            }
+            kind => Err(eformat!(peer, "unknown kind", kind)),
+        }
+    }
+
+    fn on_digest(peer: SocketAddr, envelope: Envelope) -> Result<(), String> {
+        let mut filters = Vec::new();
+        let body = &envelope.body[..];
+        let mut offset = 0;
+        while offset < body.len() {
+            let len = match body.get(offset..offset + 2) {
+                Some(&[high, low]) => u16::from_be_bytes([high, low]) as usize,
+                _ => return Err(eformat!(peer, "digest len", offset)),
+            };
+            offset += 2;
+            let filter = body
+                .get(offset..offset + len)
+                .and_then(|filter| std::str::from_utf8(filter).ok())
+                .ok_or_else(|| eformat!(peer, "digest filter", offset))?;
+            filters.push(filter.to_string());
+            offset += len;
+        }
+        let mut digests = state().digests.lock().unwrap();
+        let digest = digests.entry(peer).or_insert_with(|| PeerDigest {
+            generation: envelope.seq,
+            filters: Vec::new(),
+            received: Instant::now(),
+        });
+        // The first part of a new generation replaces the digest.
+        if digest.generation != envelope.seq {
+            digest.generation = envelope.seq;
+            digest.filters.clear();
+        }
+        digest.filters.extend(filters);
+        digest.received = Instant::now();
+        Ok(())
+    }
+
+    fn on_publish(
+        peer: SocketAddr,
+        envelope: &Envelope,
+        socket: &UdpSocket,
+        client: &MqttSnClient,
+    ) -> Result<(), String> {
+        let body = &envelope.body;
+        let (flags, len) = match body.get(..3) {
+            Some(&[flags, high, low]) => {
+                (flags, u16::from_be_bytes([high, low]) as usize)
+            }
+            _ => return Err(eformat!(peer, "publish len", body.len())),
+        };
+        let topic_name = body
+            .get(3..3 + len)
+            .and_then(|topic_name| std::str::from_utf8(topic_name).ok())
+            .ok_or_else(|| eformat!(peer, "topic name", len))?
+            .to_string();
+        let offset = 3 + len;
+        let annotations_len = match body.get(offset..offset + 2) {
+            Some(&[high, low]) => u16::from_be_bytes([high, low]) as usize,
+            _ => return Err(eformat!(peer, "annotations len", body.len())),
+        };
+        let annotations = body
+            .get(offset + 2..offset + 2 + annotations_len)
+            .and_then(Cluster::decode_annotations)
+            .ok_or_else(|| eformat!(peer, "annotations", annotations_len))?;
+        let data = body.slice(offset + 2 + annotations_len..);
+        // The other peers of a partial mesh.
+        let hops = envelope.hops.saturating_add(1);
+        let max_hops = match state().node.read().unwrap().as_ref() {
+            Some((config, _socket)) => config.max_hops,
+            None => return Ok(()),
+        };
+        if hops < max_hops {
+            let bytes = Cluster::encode(&Envelope {
+                hops,
+                ..envelope.clone()
+            });
+            for other in Cluster::matching_peers(&topic_name, Some(peer)) {
+                Cluster::send_to(socket, other, &bytes);
+            }
+        }
+        Cluster::deliver(peer, topic_name, flags, data, annotations, client)
+    }
+
+    /// Send the PUBLISH of a peer to the local subscribers.
+    fn deliver(
+        peer: SocketAddr,
+        topic_name: String,
+        flags: u8,
+        data: Bytes,
+        annotations: Annotations,
+        client: &MqttSnClient,
+    ) -> Result<(), String> {
+        let topic_id = match get_topic_id_with_topic_name(topic_name.clone())
+            .or_else(|| PreDefinedTopics::id(&topic_name))
+        {
+            Some(topic_id) => topic_id,
+            // A new topic name matching a wildcard subscription.
+            None if !match_topics(&topic_name).is_empty() => {
+                try_insert_topic_name(topic_name)?
+            }
+            None => return Ok(()),
+        };
+        // QoS -1 is for the clients without a connection.
+        let qos = match flag_qos_level(flags) {
+            QOS_LEVEL_3 => QOS_LEVEL_0,
+            qos => qos,
+        };
+        let msg_id = MsgIdAllocator::next_broker();
+        let publish = Publish::new(topic_id, msg_id, qos, RETAIN_FALSE, data);
+        if flag_is_retain(flags) {
+            Retain::insert(
+                qos,
+                topic_id,
+                msg_id,
+                publish.data().clone(),
+                annotations.clone(),
+            );
+        }
+        RichPublish::forward(
+            &publish,
+            peer,
+            SystemTime::now(),
+            &annotations,
+            client,
+        );
+        Publish::send_msg_to_subscribers(
+            get_subscribers_with_topic_id(topic_id),
+            publish,
+            &annotations,
+            client,
+        )
+    }
+
+    /// Keys and values with a u16 length, without the trace id.
+    fn encode_annotations(annotations: &Annotations) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        for (key, val) in annotations.iter() {
+            if key == TRACE_ANNOTATION_KEY {
+                continue;
+            }
+            if key.len() > u16::MAX as usize || val.len() > u16::MAX as usize {
+                warn!("{}", eformat!("annotation too long", key));
+                continue;
+            }
+            put_u16_be(&mut bytes, key.len() as u16);
+            bytes.put_slice(key.as_bytes());
+            put_u16_be(&mut bytes, val.len() as u16);
+            bytes.put_slice(val.as_bytes());
+        }
+        bytes
+    }
+
+    /// None if a length or a string is invalid.
+    fn decode_annotations(buf: &[u8]) -> Option<Annotations> {
+        let mut annotations = Annotations::new();
+        let mut offset = 0;
+        while offset < buf.len() {
+            let key = Cluster::get_string(buf, &mut offset)?;
+            let val = Cluster::get_string(buf, &mut offset)?;
+            annotations.insert(key, val);
+        }
+        Some(annotations)
+    }
+
+    /// A string with a u16 length at the offset, the offset is moved after
+    /// it.
+    fn get_string<'a>(buf: &'a [u8], offset: &mut usize) -> Option<&'a str> {
+        let len = match buf.get(*offset..*offset + 2)? {
+            &[high, low] => u16::from_be_bytes([high, low]) as usize,
+            _ => return None,
+        };
+        let string = buf.get(*offset + 2..*offset + 2 + len)?;
+        *offset += 2 + len;
+        std::str::from_utf8(string).ok()
+    }
+
+    /// Returns false if the PUBLISH was already received.
+    fn first_seen(envelope: &Envelope) -> bool {
+        let key = (envelope.origin, envelope.seq);
+        let mut seen = state().seen.lock().unwrap();
+        if !seen.set.insert(key) {
+            return false;
+        }
+        seen.order.push_back(key);
+        if seen.order.len() > CLUSTER_SEEN_LEN {
+            if let Some(oldest) = seen.order.pop_front() {
+                seen.set.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    fn send_to(socket: &UdpSocket, peer: SocketAddr, bytes: &[u8]) {
+        if let Err(why) = socket.send_to(bytes, peer) {
+            error!("{}", eformat!(peer, why));
+        }
+    }
+
+    fn encode(envelope: &Envelope) -> Bytes {
+        let mut bytes =
+            BytesMut::with_capacity(CLUSTER_HEADER_LEN + envelope.body.len());
+        bytes.put_u8(CLUSTER_VERSION);
+        bytes.put_u8(envelope.kind);
+        bytes.put_u32(envelope.origin);
+        bytes.put_u32(envelope.seq);
+        bytes.put_u8(envelope.hops);
+        bytes.put_slice(&envelope.body);
+        bytes.freeze()
+    }
+
+    fn decode(bytes: &Bytes) -> Option<Envelope> {
+        if bytes.len() < CLUSTER_HEADER_LEN || bytes[0] != CLUSTER_VERSION {
+            return None;
+        }
+        Some(Envelope {
+            kind: bytes[1],
+            origin: u32::from_be_bytes([
+                bytes[2], bytes[3], bytes[4], bytes[5],
+            ]),
+            seq: u32::from_be_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]),
+            hops: bytes[10],
+            body: bytes.slice(CLUSTER_HEADER_LEN..),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_cluster_forwarding() {
+        use super::*;
+        use crate::egress::Egress;
+        use crate::flags::{QOS_LEVEL_0, QOS_LEVEL_1, RETAIN_FALSE};
+        use crate::keep_alive::KeepAliveTimeWheel;
+        use crate::listener::Listeners;
+        use crate::retransmit::RetransTimeWheel;
+        use crate::test_support::TestClient;
+        let localhost = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+        let cluster_sockets: Vec<UdpSocket> = (0..2)
+            .map(|_| UdpSocket::bind(localhost).unwrap())
+            .collect();
+        let cluster_addrs: Vec<SocketAddr> = cluster_sockets
+            .iter()
+            .map(|socket| socket.local_addr().unwrap())
+            .collect();
+        // Two brokers in one process.
+        let mut brokers = Vec::new();
+        for (index, socket) in cluster_sockets.into_iter().enumerate() {
+            let context = BrokerContext::new();
+            let _context = context.enter();
+            KeepAliveTimeWheel::init();
+            RetransTimeWheel::init();
+            let client = MqttSnClient::new().with_context(context);
+            let sockets = Listeners::bind(&[localhost]).unwrap();
+            Listeners::run(client.clone(), sockets).unwrap();
+            let egress = client.clone();
+            thread::spawn(move || {
+                let _context = egress.context.enter();
+                while let Ok((addr_vec, data)) = Egress::next(&egress) {
+                    for addr in addr_vec {
+                        Listeners::send_to(addr, &data).unwrap();
+                    }
+                }
+            });
+            let peer = cluster_addrs[1 - index];
+            let config = ClusterConfig::new(index as u32 + 1, vec![peer]);
+            Cluster::run(client, socket, config).unwrap();
+            brokers.push((context, Listeners::local_addrs()[0]));
+        }
+        let (context_a, broker_a) = brokers[0];
+        let (context_b, broker_b) = brokers[1];
+        let mut subscriber = TestClient::new(broker_b).unwrap();
+        subscriber.connect("clusterSub", 60, None).unwrap();
+        subscriber.subscribe("cluster/+/temp", QOS_LEVEL_1).unwrap();
+        {
+            let _context = context_b.enter();
+            Cluster::send_digest().unwrap();
+        }
+        {
+            let _context = context_a.enter();
+            let mut retries = 0;
+            while Cluster::remote_filters(&cluster_addrs[1]).is_empty() {
+                assert!(retries < 100);
+                retries += 1;
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(Cluster::peers(), vec![cluster_addrs[1]]);
+            assert_eq!(
+                Cluster::remote_filters(&cluster_addrs[1]),
+                vec!["cluster/+/temp".to_string()]
+            );
+        }
+        let mut publisher = TestClient::new(broker_a).unwrap();
+        publisher.connect("clusterPub", 60, None).unwrap();
+        let topic_id = publisher.register("cluster/kitchen/temp").unwrap();
+        publisher
+            .publish(topic_id, QOS_LEVEL_1, RETAIN_FALSE, b"21.5")
+            .unwrap();
+        // Registered by broker B before the PUBLISH.
+        let publish = subscriber.recv_publish().unwrap();
+        assert_eq!(publish.qos, QOS_LEVEL_1);
+        assert_eq!(&publish.payload[..], b"21.5");
+        assert_eq!(
+            subscriber.registered(publish.topic_id).unwrap(),
+            "cluster/kitchen/temp"
+        );
+        // A tiny QoS 0 message with a local subscriber, not on the fast
+        // path, the peer receives it too.
+        let mut local = TestClient::new(broker_a).unwrap();
+        local.connect("clusterLocal", 60, None).unwrap();
+        local
+            .subscribe("cluster/kitchen/temp", QOS_LEVEL_0)
+            .unwrap();
+        publisher
+            .publish(topic_id, QOS_LEVEL_0, RETAIN_FALSE, b"22.0")
+            .unwrap();
+        let publish = local.recv_publish().unwrap();
+        assert_eq!(&publish.payload[..], b"22.0");
+        let publish = subscriber.recv_publish().unwrap();
+        assert_eq!(publish.qos, QOS_LEVEL_0);
+        assert_eq!(&publish.payload[..], b"22.0");
+        local.disconnect(None).unwrap();
+        // A local topic never leaves broker A, the next message is.
+        LocalTopics::add("cluster/local/#").unwrap();
+        let local_id = publisher.register("cluster/local/temp").unwrap();
+        publisher
+            .publish(local_id, QOS_LEVEL_0, RETAIN_FALSE, b"local")
+            .unwrap();
+        publisher
+            .publish(topic_id, QOS_LEVEL_0, RETAIN_FALSE, b"22.5")
+            .unwrap();
+        let publish = subscriber.recv_publish().unwrap();
+        assert_eq!(&publish.payload[..], b"22.5");
+        LocalTopics::remove("cluster/local/#");
+        // The annotations go to the peers, except the trace id.
+        let mut annotations = Annotations::new();
+        annotations.insert("tenant", "acme");
+        annotations.insert(TRACE_ANNOTATION_KEY, "7");
+        let bytes = Cluster::encode_annotations(&annotations);
+        let decoded = Cluster::decode_annotations(&bytes).unwrap();
+        assert_eq!(decoded.get("tenant").unwrap(), "acme");
+        assert_eq!(decoded.get(TRACE_ANNOTATION_KEY), None);
+        assert_eq!(Cluster::decode_annotations(&bytes[..4]), None);
+        // A PUBLISH back to its origin or seen twice is dropped.
+        let envelope = Envelope {
+            kind: CLUSTER_PUBLISH,
+            origin: 2,
+            seq: 7,
+            hops: 1,
+            body: Bytes::new(),
+        };
+        assert_eq!(
+            Cluster::decode(&Cluster::encode(&envelope)),
+            Some(envelope.clone())
+        );
+        let _context = context_b.enter();
+        let client = MqttSnClient::new().with_context(context_b);
+        let bytes = Cluster::encode(&envelope);
+        assert!(Cluster::recv(cluster_addrs[0], bytes, &client).is_ok());
+        let envelope = Envelope {
+            origin: 1,
+            ..envelope
+        };
+        assert!(Cluster::first_seen(&envelope));
+        assert!(!Cluster::first_seen(&envelope));
+        // Not a peer.
+        let bytes = Cluster::encode(&envelope);
+        assert!(Cluster::recv(localhost, bytes, &client).is_err());
+        publisher.disconnect(None).unwrap();
+        subscriber.disconnect(None).unwrap();
+    }
+}
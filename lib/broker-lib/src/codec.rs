@@ -0,0 +1,323 @@
+/// Encoding and decoding of the messages with the mqtt-sn-codec crate.
+///
+/// All the outgoing messages are encoded by mqtt_sn_codec::Packet, the
+/// firmware and the broker share one encoder. encode_packet() returns the
+/// message in a BytesMut, with the 1-octet Length field up to 255 octets,
+/// the 3-octet Length field above, and checks the max. message size, see
+/// MsgHeader. The message structs derive try_read() from their fields with
+/// getset to decode the received messages, u16 fields are big-endian
+/// (network byte order).
+///
+/// The fixed size messages, the ACKs, the responses, SEARCHGW, ADVERTISE
+/// and the WILLTOPICREQ, WILLMSGREQ and PINGRESP, are built without a
+/// struct, the codec! macro generates encode() and decode() for them from
+/// the fields of their Packet variant:
+///
+/// codec!(PubRec, { msg_id: u16 });
+///
+/// PubRec::encode(msg_id) returns [len, msg_type, msg_id(2)] and
+/// PubRec::decode(buf, size) checks the message with Packet::decode() and
+/// returns the msg_id. Messages with more than one field return a tuple,
+/// the messages without fields, e.g. PINGRESP, have an empty field list
+/// and return ().
+///
+/// put_u16_be() and get_u16_be() write and read the u16 fields of the
+/// broker's own formats, e.g. the cluster envelope, and of the received
+/// messages. The topic ids, msg ids, durations and 3-octet lengths are in
+/// network byte order everywhere.
+use bytes::{BufMut, BytesMut};
+
+pub use mqtt_sn_codec::{Packet, Topic};
+
+use crate::{eformat, function, msg_hdr::MsgHeader};
+
+/// Append the u16 in network byte order (big-endian).
+#[inline(always)]
+pub fn put_u16_be(bytes: &mut BytesMut, val: u16) {
+    bytes.put_u16(val);
+}
+
+/// The big-endian u16 at offset, the caller checks the length of buf.
+#[inline(always)]
+pub fn get_u16_be(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+/// The message with its Length field, Err if it's longer than the max.
+/// message size.
+pub fn encode_packet(packet: &Packet) -> Result<BytesMut, String> {
+    let msg_type = packet.msg_type();
+    let len = packet
+        .encoded_len()
+        .map_err(|why| eformat!(msg_type, why))?;
+    if len > MsgHeader::max_message_size() {
+        return Err(eformat!("Message is too long", msg_type, len));
+    }
+    let mut bytes = BytesMut::new();
+    bytes.resize(len, 0);
+    packet
+        .encode(&mut bytes)
+        .map_err(|why| eformat!(msg_type, why))?;
+    Ok(bytes)
+}
+
+#[macro_export]
+/// MUST ALSO IMPORT function!()
+macro_rules! codec {
+    ($name:ident, { $($field:ident : $ty:ty),* $(,)? }) => {
+        impl $name {
+            /// Serialize the message, the fields are big-endian.
+            #[inline(always)]
+            pub fn encode($($field: $ty),*) -> bytes::BytesMut {
+                let packet = $crate::codec::Packet::$name { $($field),* };
+                // A fixed size message is always shorter than the max.
+                $crate::codec::encode_packet(&packet)
+                    .expect(stringify!($name))
+            }
+            /// Check the length and msg_type and return the fields.
+            #[inline(always)]
+            pub fn decode(
+                buf: &[u8],
+                size: usize,
+            ) -> Result<($($ty),*), String> {
+                let msg = buf.get(..size).ok_or_else(|| {
+                    $crate::eformat!(stringify!($name), "len", size)
+                })?;
+                match $crate::codec::Packet::decode(msg) {
+                    Ok($crate::codec::Packet::$name { $($field),* }) => {
+                        Ok(($($field),*))
+                    }
+                    Ok(packet) => Err($crate::eformat!(
+                        stringify!($name),
+                        packet.msg_type()
+                    )),
+                    Err(why) => Err($crate::eformat!(stringify!($name), why)),
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_codec_big_endian() {
+        use crate::{
+            ping_resp::PingResp, pub_ack::PubAck, MSG_LEN_PINGRESP,
+            MSG_LEN_PUBACK, MSG_TYPE_PINGRESP, MSG_TYPE_PUBACK,
+        };
+        let bytes = PubAck::encode(0x0102, 0x0304, 3);
+        assert_eq!(
+            &bytes[..],
+            &[MSG_LEN_PUBACK, MSG_TYPE_PUBACK, 1, 2, 3, 4, 3]
+        );
+        assert_eq!(
+            PubAck::decode(&bytes, bytes.len()),
+            Ok((0x0102, 0x0304, 3))
+        );
+        assert!(PubAck::decode(&bytes, bytes.len() - 1).is_err());
+        assert!(PubAck::decode(&bytes, bytes.len() + 1).is_err());
+        let mut wrong_type = bytes.clone();
+        wrong_type[1] = MSG_TYPE_PINGRESP;
+        assert!(PubAck::decode(&wrong_type, wrong_type.len()).is_err());
+        // Length and MsgType only.
+        let bytes = PingResp::encode();
+        assert_eq!(&bytes[..], &[MSG_LEN_PINGRESP, MSG_TYPE_PINGRESP]);
+        assert_eq!(PingResp::decode(&bytes, bytes.len()), Ok(()));
+        assert!(PingResp::decode(&[3, MSG_TYPE_PINGRESP, 0], 3).is_err());
+        assert!(PingResp::decode(&[2, MSG_TYPE_PUBACK], 2).is_err());
+    }
+
+    #[test]
+    fn test_encode_packet_len() {
+        use super::*;
+        use crate::conn::udp_conn;
+        use crate::msg_hdr::{MsgHeaderLenEnum, DEFAULT_MAX_DATAGRAM_SIZE};
+        use crate::MSG_TYPE_REGISTER;
+        use std::net::SocketAddr;
+        let conn = udp_conn();
+        let addr = "127.0.0.1:1402".parse::<SocketAddr>().unwrap();
+        // (topic name length, message length, header)
+        let cases = [
+            (0, 6, MsgHeaderLenEnum::Short),
+            (249, 255, MsgHeaderLenEnum::Short),
+            (250, 258, MsgHeaderLenEnum::Long),
+            (1392, 1400, MsgHeaderLenEnum::Long),
+        ];
+        for (name_len, len, header_len) in cases.iter() {
+            let topic_name = "t".repeat(*name_len);
+            let bytes = encode_packet(&Packet::Register {
+                topic_id: 0x1234,
+                msg_id: 0x5678,
+                topic_name: &topic_name,
+            })
+            .unwrap();
+            assert_eq!(bytes.len(), *len);
+            let msg_header =
+                MsgHeader::try_read(&bytes, *len, addr, conn.clone()).unwrap();
+            assert_eq!(msg_header.len as usize, *len);
+            assert_eq!(msg_header.msg_type, MSG_TYPE_REGISTER);
+            assert_eq!(msg_header.body_offset(), *header_len as usize);
+            let body = msg_header.body(&bytes);
+            assert_eq!(&body[..4], &[0x12, 0x34, 0x56, 0x78]);
+            assert_eq!(&body[4..], topic_name.as_bytes());
+        }
+        // Longer than the max. datagram size.
+        let data = vec![0; DEFAULT_MAX_DATAGRAM_SIZE];
+        assert!(encode_packet(&Packet::WillMsg { will_msg: &data }).is_err());
+    }
+
+    #[test]
+    fn test_codec_matches_derived() {
+        use crate::flags::{QOS_LEVEL_1, RETAIN_FALSE};
+        use crate::{pub_rec::PubRec, publish::Publish};
+        let bytes =
+            Publish::encode(0x0102, 0x0304, QOS_LEVEL_1, RETAIN_FALSE, b"abc")
+                .unwrap();
+        let (publish, _) = Publish::try_read(&bytes, bytes.len()).unwrap();
+        assert_eq!(*publish.topic_id(), 0x0102);
+        assert_eq!(*publish.msg_id(), 0x0304);
+        assert_eq!(&publish.data()[..], b"abc");
+        let bytes = PubRec::encode(0x0102);
+        let (pub_rec, _) = PubRec::try_read(&bytes, bytes.len()).unwrap();
+        assert_eq!(pub_rec.msg_id, 0x0102);
+        assert_eq!(PubRec::decode(&bytes, bytes.len()), Ok(0x0102));
+    }
+
+    #[test]
+    fn test_byte_captures() {
+        use super::*;
+        use crate::flags::{QOS_LEVEL_1, RETAIN_FALSE};
+        use crate::{
+            advertise::Advertise, pub_ack::PubAck, pub_comp::PubComp,
+            pub_rec::PubRec, pub_rel::PubRel, publish::Publish,
+            subscribe::Subscribe,
+        };
+        // Captured from other MQTT-SN implementations, topic id 0x0102 and
+        // msg id 0x0304.
+        let bytes =
+            Publish::encode(0x0102, 0x0304, QOS_LEVEL_1, RETAIN_FALSE, b"abc")
+                .unwrap();
+        assert_eq!(
+            &bytes[..],
+            &[0x0A, 0x0C, 0x20, 0x01, 0x02, 0x03, 0x04, b'a', b'b', b'c']
+        );
+        let bytes = PubAck::encode(0x0102, 0x0304, 0);
+        assert_eq!(&bytes[..], &[0x07, 0x0D, 0x01, 0x02, 0x03, 0x04, 0x00]);
+        assert_eq!(&PubRec::encode(0x0304)[..], &[0x04, 0x0F, 0x03, 0x04]);
+        assert_eq!(&PubRel::encode(0x0304)[..], &[0x04, 0x10, 0x03, 0x04]);
+        assert_eq!(&PubComp::encode(0x0304)[..], &[0x04, 0x0E, 0x03, 0x04]);
+        // gw_id 5, duration 900 s.
+        assert_eq!(
+            &Advertise::encode(5, 900)[..],
+            &[0x05, 0x00, 0x05, 0x03, 0x84]
+        );
+        // 3-octet length, 0x01 then the total length 307.
+        let topic = "t".repeat(300);
+        let bytes = Subscribe::encode(topic, QOS_LEVEL_1, RETAIN_FALSE, 0x0304)
+            .unwrap();
+        assert_eq!(bytes.len(), 307);
+        assert_eq!(&bytes[..4], &[0x01, 0x01, 0x33, 0x12]);
+        assert_eq!(&bytes[5..7], &[0x03, 0x04]);
+        let mut bytes = BytesMut::new();
+        put_u16_be(&mut bytes, 0xABCD);
+        assert_eq!(&bytes[..], &[0xAB, 0xCD]);
+        assert_eq!(get_u16_be(&[0, 0xAB, 0xCD], 1), 0xABCD);
+    }
+
+    #[test]
+    fn test_codec_crate() {
+        use super::{Packet, Topic};
+        use crate::flags::{QOS_LEVEL_1, RETAIN_FALSE};
+        use crate::{
+            advertise::Advertise, conn_ack::ConnAck, ping_resp::PingResp,
+            pub_ack::PubAck, pub_comp::PubComp, pub_rec::PubRec,
+            pub_rel::PubRel, publish::Publish, reg_ack::RegAck,
+            search_gw::SearchGw, sub_ack::SubAck, subscribe::Subscribe,
+            unsub_ack::UnsubAck, will_msg_req::WillMsgReq,
+            will_msg_resp::WillMsgResp, will_topic_req::WillTopicReq,
+            will_topic_resp::WillTopicResp,
+        };
+        // The messages of the broker decode to the Packet they're encoded
+        // from.
+        let cases = [
+            (
+                Advertise::encode(5, 900),
+                Packet::Advertise {
+                    gw_id: 5,
+                    duration: 900,
+                },
+            ),
+            (PubRec::encode(0x0304), Packet::PubRec { msg_id: 0x0304 }),
+            (PubRel::encode(0x0304), Packet::PubRel { msg_id: 0x0304 }),
+            (PubComp::encode(0x0304), Packet::PubComp { msg_id: 0x0304 }),
+            (SearchGw::encode(1), Packet::SearchGw { radius: 1 }),
+            (ConnAck::encode(0), Packet::ConnAck { return_code: 0 }),
+            (WillTopicReq::encode(), Packet::WillTopicReq),
+            (WillMsgReq::encode(), Packet::WillMsgReq),
+            (
+                RegAck::encode(0x0102, 0x0304, 0),
+                Packet::RegAck {
+                    topic_id: 0x0102,
+                    msg_id: 0x0304,
+                    return_code: 0,
+                },
+            ),
+            (
+                SubAck::encode(QOS_LEVEL_1, 0x0102, 0x0304, 0),
+                Packet::SubAck {
+                    flags: QOS_LEVEL_1,
+                    topic_id: 0x0102,
+                    msg_id: 0x0304,
+                    return_code: 0,
+                },
+            ),
+            (
+                UnsubAck::encode(0x0304),
+                Packet::UnsubAck { msg_id: 0x0304 },
+            ),
+            (PingResp::encode(), Packet::PingResp),
+            (
+                WillTopicResp::encode(0),
+                Packet::WillTopicResp { return_code: 0 },
+            ),
+            (
+                WillMsgResp::encode(3),
+                Packet::WillMsgResp { return_code: 3 },
+            ),
+        ];
+        for (bytes, packet) in cases.iter() {
+            assert_eq!(Packet::decode(bytes), Ok(*packet));
+        }
+        let bytes =
+            Publish::encode(0x0102, 0x0304, QOS_LEVEL_1, RETAIN_FALSE, b"abc")
+                .unwrap();
+        assert_eq!(
+            Packet::decode(&bytes),
+            Ok(Packet::Publish {
+                flags: QOS_LEVEL_1,
+                topic_id: 0x0102,
+                msg_id: 0x0304,
+                data: b"abc",
+            })
+        );
+        let bytes = PubAck::encode(0x0102, 0x0304, 0);
+        let mut buf = [0u8; 8];
+        let puback = Packet::decode(&bytes).unwrap();
+        assert_eq!(puback.encode(&mut buf), Ok(bytes.len()));
+        assert_eq!(&buf[..bytes.len()], &bytes[..]);
+        // 3-octet length.
+        let topic = "t".repeat(300);
+        let bytes =
+            Subscribe::encode(topic.clone(), QOS_LEVEL_1, RETAIN_FALSE, 0x0304)
+                .unwrap();
+        assert_eq!(
+            Packet::decode(&bytes),
+            Ok(Packet::Subscribe {
+                flags: QOS_LEVEL_1,
+                msg_id: 0x0304,
+                topic: Topic::Name(&topic),
+            })
+        );
+    }
+}
@@ -0,0 +1,79 @@
+/// Map and set types for the connection and filter tables, selected at
+/// compile time with cargo features:
+///
+/// map-hashbrown (default): hashbrown, fast hashing for servers.
+/// map-std:                 std::collections, no extra dependency.
+///
+/// If both features are enabled, std takes precedence over hashbrown.
+/// The tables are keyed by SocketAddr and String, a fixed capacity map
+/// like heapless needs hash32 keys, constrained devices use the client of
+/// lib/mqtt-sn-embedded instead of the broker.
+/// Use bounded_insert() instead of insert(), so the call sites handle a
+/// full map.
+use core::hash::Hash;
+
+/// Insert into a map that might be full.
+pub trait BoundedMap<K, V> {
+    /// Returns the old value if the key exists,
+    /// Err((key, val)) if the map is full.
+    fn bounded_insert(&mut self, key: K, val: V) -> Result<Option<V>, (K, V)>;
+}
+
+/// Insert into a set that might be full.
+pub trait BoundedSet<T> {
+    /// Returns false if the value exists, Err(val) if the set is full.
+    fn bounded_insert(&mut self, val: T) -> Result<bool, T>;
+}
+
+#[cfg(not(feature = "map-std"))]
+mod backend {
+    pub type ConnMap<K, V> = hashbrown::HashMap<K, V>;
+    pub type FilterMap<K, V> = hashbrown::HashMap<K, V>;
+    pub type FilterSet<T> = hashbrown::HashSet<T>;
+    pub type QoSMap<K, V> = hashbrown::HashMap<K, V>;
+}
+
+#[cfg(feature = "map-std")]
+mod backend {
+    pub type ConnMap<K, V> = std::collections::HashMap<K, V>;
+    pub type FilterMap<K, V> = std::collections::HashMap<K, V>;
+    pub type FilterSet<T> = std::collections::HashSet<T>;
+    pub type QoSMap<K, V> = std::collections::HashMap<K, V>;
+}
+
+pub use backend::*;
+
+#[cfg(not(feature = "map-std"))]
+use hashbrown::{HashMap as MapImpl, HashSet as SetImpl};
+#[cfg(feature = "map-std")]
+use std::collections::{HashMap as MapImpl, HashSet as SetImpl};
+
+impl<K: Eq + Hash, V, S: core::hash::BuildHasher> BoundedMap<K, V>
+    for MapImpl<K, V, S>
+{
+    #[inline(always)]
+    fn bounded_insert(&mut self, key: K, val: V) -> Result<Option<V>, (K, V)> {
+        Ok(self.insert(key, val))
+    }
+}
+
+impl<T: Eq + Hash, S: core::hash::BuildHasher> BoundedSet<T> for SetImpl<T, S> {
+    #[inline(always)]
+    fn bounded_insert(&mut self, val: T) -> Result<bool, T> {
+        Ok(self.insert(val))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_bounded_insert() {
+        use super::*;
+        let mut map: ConnMap<u16, u16> = ConnMap::new();
+        assert_eq!(map.bounded_insert(1, 10), Ok(None));
+        assert_eq!(map.bounded_insert(1, 11), Ok(Some(10)));
+        let mut set: FilterSet<u16> = FilterSet::new();
+        assert_eq!(set.bounded_insert(1), Ok(true));
+        assert_eq!(set.bounded_insert(1), Ok(false));
+    }
+}
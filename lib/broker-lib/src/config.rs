@@ -0,0 +1,246 @@
+/// Broker configuration, reloaded while the broker runs.
+///
+/// The configuration file is TOML:
+///
+/// log_level = "info"              # off, error, warn, info, debug or trace
+/// acl_file = "/etc/mqtt-sn/acl"   # AllowAll without an ACL file
+/// require_auth = true             # CONNECT needs a DTLS identity
+///
+/// [pre_defined_topics]
+/// 7 = "sensors/temp"
+///
+/// [topic_metadata."sensors/#"]     # see TopicMetadata
+/// content_type = "application/json"
+///
+/// [storage]                       # see StorageConfig
+/// backend = "sqlite"
+/// path = "/var/lib/mqtt-sn/broker.db"
+///
+/// ConfigWatcher::run() applies the file and polls its modification time,
+/// a changed file is applied again. A configuration with an error is
+/// rejected as a whole and the running configuration is kept. The ACL is
+/// swapped in the ReloadableAuthorizer of the broker and the pre-defined
+/// topic table is replaced in one store, a check sees either the old or
+/// the new table. So is the topic metadata table. The storage backend is
+/// opened once with StorageConfig::open() before the broker starts, a
+/// reload only checks the [storage] table.
+use hashbrown::HashMap;
+use log::*;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    authorization::{
+        AclAuthorizer, AllowAll, Authorizer, ReloadableAuthorizer,
+    },
+    broker_context::BrokerContext,
+    dtls_auth::DtlsAuth,
+    eformat,
+    filter::{has_wildcards, valid_filter},
+    function,
+    storage_backend::StorageConfig,
+    sys_topics::SysTopics,
+    topic_metadata::TopicMetadata,
+    TopicIdType,
+};
+
+pub const CONFIG_POLL_MS: u64 = 1000;
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct BrokerConfig {
+    pub log_level: Option<String>,
+    pub acl_file: Option<String>,
+    /// Topic id in decimal to topic name.
+    pub pre_defined_topics: BTreeMap<String, String>,
+    /// Topic name or filter to the payload metadata.
+    pub topic_metadata: BTreeMap<String, TopicMetadata>,
+    /// Backend of the retained messages, wills and sessions.
+    pub storage: StorageConfig,
+    /// DtlsAuth::set_required(), unchanged without it.
+    pub require_auth: Option<bool>,
+}
+
+impl BrokerConfig {
+    pub fn parse(toml_str: &str) -> Result<Self, String> {
+        toml::from_str(toml_str).map_err(|why| eformat!(why))
+    }
+    pub fn load_file(path: &str) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => BrokerConfig::parse(&text),
+            Err(why) => Err(eformat!(path, why)),
+        }
+    }
+
+    /// Check the whole configuration, then swap the ACL, the pre-defined
+    /// topics, the topic metadata and the log level.
+    pub fn apply(
+        &self,
+        authorizer: &ReloadableAuthorizer,
+    ) -> Result<(), String> {
+        let log_level = match &self.log_level {
+            Some(level) => Some(
+                LevelFilter::from_str(level)
+                    .map_err(|why| eformat!(level, why))?,
+            ),
+            None => None,
+        };
+        let acl: Arc<dyn Authorizer> = match &self.acl_file {
+            Some(path) => Arc::new(AclAuthorizer::load_file(path)?),
+            None => Arc::new(AllowAll {}),
+        };
+        let mut topics = HashMap::with_capacity(self.pre_defined_topics.len());
+        for (id, topic) in self.pre_defined_topics.iter() {
+            let topic_id =
+                id.parse::<TopicIdType>().map_err(|why| eformat!(id, why))?;
+            if !valid_filter(topic) || has_wildcards(topic) {
+                return Err(eformat!(id, "invalid topic", topic));
+            }
+            topics.insert(topic_id, topic.clone());
+        }
+        TopicMetadata::validate(&self.topic_metadata)?;
+        self.storage.validate()?;
+        authorizer.swap(acl);
+        PreDefinedTopics::replace(topics);
+        TopicMetadata::replace(self.topic_metadata.clone());
+        if let Some(level) = log_level {
+            log::set_max_level(level);
+        }
+        if let Some(required) = self.require_auth {
+            DtlsAuth::set_required(required);
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    static ref PRE_DEFINED_TOPICS: RwLock<Arc<HashMap<TopicIdType, String>>> =
+        RwLock::new(Arc::new(HashMap::new()));
+}
+
+#[derive(Debug, Clone)]
+pub struct PreDefinedTopics {}
+
+impl PreDefinedTopics {
+    /// Topic name of the pre-defined topic id, or of the $SYS topic id.
+    pub fn name(topic_id: TopicIdType) -> Option<String> {
+        PRE_DEFINED_TOPICS
+            .read()
+            .unwrap()
+            .get(&topic_id)
+            .cloned()
+            .or_else(|| SysTopics::name(topic_id).map(String::from))
+    }
+    /// Pre-defined topic id of the topic name.
+    pub fn id(topic_name: &str) -> Option<TopicIdType> {
+        PRE_DEFINED_TOPICS
+            .read()
+            .unwrap()
+            .iter()
+            .find(|(_topic_id, name)| name.as_str() == topic_name)
+            .map(|(topic_id, _name)| *topic_id)
+    }
+    /// Replace the table.
+    pub fn replace(topics: HashMap<TopicIdType, String>) {
+        *PRE_DEFINED_TOPICS.write().unwrap() = Arc::new(topics);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfigWatcher {}
+
+impl ConfigWatcher {
+    /// Apply the configuration file and reload it when it's modified.
+    pub fn run(
+        path: &str,
+        authorizer: Arc<ReloadableAuthorizer>,
+    ) -> Result<(), String> {
+        BrokerConfig::load_file(path)?.apply(&authorizer)?;
+        let path = path.to_string();
+        let mut modified = ConfigWatcher::modified(&path);
+        // Reload into the broker of the calling thread.
+        let context = BrokerContext::current();
+        let builder = thread::Builder::new().name("config_watch_thread".into());
+        let _config_watch_thread = builder.spawn(move || {
+            let _context = context.enter();
+            loop {
+                thread::sleep(Duration::from_millis(CONFIG_POLL_MS));
+                let new_modified = ConfigWatcher::modified(&path);
+                if new_modified.is_none() || new_modified == modified {
+                    continue;
+                }
+                modified = new_modified;
+                match BrokerConfig::load_file(&path)
+                    .and_then(|config| config.apply(&authorizer))
+                {
+                    Ok(()) => info!("{}: configuration reloaded", path),
+                    Err(why) => error!("{}", why),
+                }
+            }
+        });
+        Ok(())
+    }
+    fn modified(path: &str) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|meta| meta.modified()).ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_broker_config_apply() {
+        use super::*;
+        use crate::authorization::topic_of;
+        use bytes::Bytes;
+        let acl_path = std::env::temp_dir().join("broker_config_test.acl");
+        fs::write(&acl_path, "topic read sensors/#\n").unwrap();
+        let config = BrokerConfig::parse(&format!(
+            "log_level = \"trace\"\n\
+             acl_file = {:?}\n\
+             [pre_defined_topics]\n\
+             65520 = \"sensors/pre_defined\"\n\
+             [topic_metadata.\"config/+/temp\"]\n\
+             content_type = \"application/json\"\n\
+             [storage]\n\
+             backend = \"memory\"\n",
+            acl_path.to_str().unwrap()
+        ))
+        .unwrap();
+        let authorizer = ReloadableAuthorizer::new(Arc::new(AllowAll {}));
+        assert!(authorizer.allow_all());
+        config.apply(&authorizer).unwrap();
+        let client_id = Bytes::from("config");
+        assert!(!authorizer.allow_all());
+        assert!(authorizer.allow_subscribe(&client_id, "sensors/temp"));
+        assert!(!authorizer.allow_publish(&client_id, "sensors/temp"));
+        assert_eq!(topic_of(65520), "sensors/pre_defined");
+        let metadata = TopicMetadata::get("config/a/temp").unwrap();
+        assert_eq!(metadata.content_type.as_deref(), Some("application/json"));
+        assert_eq!(metadata.encoding, None);
+        // Rejected as a whole, the running configuration is kept.
+        let bad = BrokerConfig::parse(
+            "[pre_defined_topics]\n\
+             65521 = \"ok\"\n\
+             x = \"bad/id\"\n",
+        )
+        .unwrap();
+        assert!(bad.apply(&authorizer).is_err());
+        assert!(!authorizer.allow_all());
+        assert_eq!(PreDefinedTopics::name(65521), None);
+        assert_eq!(config.storage.backend.as_deref(), Some("memory"));
+        assert_eq!(config.require_auth, None);
+        let auth = BrokerConfig::parse("require_auth = false\n").unwrap();
+        assert_eq!(auth.require_auth, Some(false));
+        assert!(BrokerConfig::parse("log_level = 3").is_err());
+        BrokerConfig::default().apply(&authorizer).unwrap();
+        assert!(authorizer.allow_all());
+        assert_eq!(PreDefinedTopics::name(65520), None);
+        assert_eq!(TopicMetadata::get("config/a/temp"), None);
+        let _result = fs::remove_file(acl_path);
+    }
+}
@@ -0,0 +1,29 @@
+/// The connection a datagram was received on, passed to the message
+/// handlers with the MsgHeader.
+///
+/// With the "async-runtime" feature it's the webrtc-util Conn of the DTLS
+/// and WebSocket listeners, registered with the Hub, the replies to these
+/// clients are sent through it, see MqttSnClient::handle_egress(). The UDP
+/// clients are answered from the socket of their listener, nothing is sent
+/// through their udp_conn(). Without "async-runtime" there are only UDP
+/// clients and Conn is an empty trait, the gateway doesn't need tokio.
+use std::sync::Arc;
+
+#[cfg(feature = "async-runtime")]
+pub use util::conn::Conn;
+
+#[cfg(not(feature = "async-runtime"))]
+pub trait Conn {}
+
+/// The conn of a datagram from a UDP listener.
+#[cfg(feature = "async-runtime")]
+pub fn udp_conn() -> Arc<dyn Conn + Send + Sync> {
+    let (conn, _peer) = util::conn::conn_pipe::pipe();
+    Arc::new(conn)
+}
+#[cfg(not(feature = "async-runtime"))]
+pub fn udp_conn() -> Arc<dyn Conn + Send + Sync> {
+    struct UdpConn {}
+    impl Conn for UdpConn {}
+    Arc::new(UdpConn {})
+}
@@ -13,14 +13,20 @@ use custom_debug::Debug;
 use getset::{CopyGetters, Getters, MutGetters /* Setters */};
 
 use crate::{
+    asleep_msg_cache::AsleepMsgCache,
     broker_lib::MqttSnClient,
+    client_mode::ClientMode,
+    codec,
+    decode::Decode,
     eformat,
     function,
     msg_hdr::MsgHeader,
+    protocol::ProtocolVersion,
     retransmit::RetransTimeWheel,
+    subscription_export::SubscriptionExport,
     // flags::{flags_set, flag_qos_level, },
     MSG_LEN_CONNACK,
-    MSG_TYPE_CONNACK,
+    RETURN_CODE_ACCEPTED,
 };
 
 #[derive(Debug, thiserror::Error)]
@@ -53,6 +59,8 @@ pub struct ConnAck {
     pub return_code: u8, // use enum for print
 }
 
+codec!(ConnAck, { return_code: u8 });
+
 impl ConnAck {
     /*
     fn constraint_len(_val: &u8) -> bool {
@@ -81,16 +89,20 @@ impl ConnAck {
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
-        let (conn_ack, read_len) = ConnAck::try_read(&buf, size).unwrap();
+        let (conn_ack, read_len) =
+            Decode::read(ConnAck::try_read(&buf, size), size, &msg_header)?;
         dbg!(conn_ack.clone());
         if read_len == MSG_LEN_CONNACK as usize {
             RetransTimeWheel::cancel_timer(
                 msg_header.remote_socket_addr,
                 conn_ack.msg_type,
                 0,
-                0,
             )?;
             dbg!("connack cancel timer");
+            ClientMode::on_conn_ack(
+                &msg_header.remote_socket_addr,
+                conn_ack.return_code,
+            );
             Ok(())
         } else {
             Err(eformat!("len err", read_len))
@@ -103,22 +115,46 @@ impl ConnAck {
         msg_header: MsgHeader,
         return_code: u8,
     ) -> Result<(), String> {
-        let connack = ConnAck {
-            len: MSG_LEN_CONNACK,
-            msg_type: MSG_TYPE_CONNACK,
-            return_code,
-        };
-        let mut bytes_buf = BytesMut::with_capacity(MSG_LEN_CONNACK as usize);
-        dbg!(connack.clone());
-        connack.try_write(&mut bytes_buf);
+        let mut bytes_buf = ConnAck::encode(return_code);
+        if msg_header.version() != ProtocolVersion::V1_2 {
+            ConnAck::put_v2(&mut bytes_buf);
+        }
         dbg!(bytes_buf.clone());
         // transmit to network
-        match client
-            .egress_tx
-            .try_send((msg_header.remote_socket_addr, bytes_buf))
-        {
-            Ok(()) => Ok(()),
-            Err(err) => Err(eformat!(msg_header.remote_socket_addr, err)),
+        let remote_addr = msg_header.remote_socket_addr;
+        if let Err(err) = client.egress_tx.try_send((remote_addr, bytes_buf)) {
+            return Err(eformat!(remote_addr, err));
+        }
+        // A sleeping client reconnected, section 6.14, the messages
+        // buffered while it was asleep follow the CONNACK.
+        // The imported subscriptions of the client are registered too.
+        if return_code == RETURN_CODE_ACCEPTED {
+            let _sent = AsleepMsgCache::flush(remote_addr, client);
+            SubscriptionExport::apply(remote_addr, client);
         }
+        Ok(())
+    }
+
+    /// The 2.0 CONNACK, the SessionExpiryInterval follows the ReturnCode.
+    #[cfg(feature = "mqtt-sn-v2")]
+    fn put_v2(bytes_buf: &mut BytesMut) {
+        use crate::protocol::SESSION_EXPIRY_NEVER;
+        bytes_buf.put_u32(SESSION_EXPIRY_NEVER);
+        bytes_buf[0] = bytes_buf.len() as u8;
+    }
+    #[cfg(not(feature = "mqtt-sn-v2"))]
+    fn put_v2(_bytes_buf: &mut BytesMut) {}
+
+    /// Send a CONNACK with a rejection return code (congestion or not
+    /// supported) and return why as the error for the caller to log.
+    #[inline(always)]
+    pub fn reject(
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+        return_code: u8,
+        why: String,
+    ) -> Result<(), String> {
+        ConnAck::send(client, msg_header, return_code)?;
+        Err(why)
     }
 }
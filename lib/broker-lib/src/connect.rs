@@ -29,25 +29,37 @@ feature indicated in the CONNECT message), the GW returns a CONNACK message with
 use bytes::{BufMut, Bytes, BytesMut};
 use custom_debug::Debug;
 use getset::{CopyGetters, Getters, MutGetters};
+use log::*;
 use std::mem;
+use std::net::SocketAddr;
 use std::str;
 
 use crate::{
+    asleep_msg_cache::AsleepMsgCache,
     broker_lib::MqttSnClient,
+    client_id::ClientId,
+    codec::{encode_packet, Packet},
     conn_ack::ConnAck,
-    connection::Connection,
-    dbg_buf, eformat,
-    flags::flag_is_will,
+    connection::{ConnEvent, Connection, StateEnum2},
+    dbg_buf,
+    decode::Decode,
+    disconnect::Disconnect,
+    dtls_auth::DtlsAuth,
+    eformat,
+    events::DeniedAction,
+    filter::delete_subscribers_with_socket_addr,
+    flags::{flag_is_clean_session, flag_is_will},
     function,
     keep_alive::KeepAliveTimeWheel,
-    msg_hdr::{MsgHeader, MsgHeaderLenEnum},
+    msg_hdr::MsgHeader,
+    protocol::ProtocolVersion,
     retransmit::RetransTimeWheel,
-    will_topic_req::WillTopicReq,
-    MSG_LEN_CONNECT_HEADER, MSG_TYPE_CONNACK, MSG_TYPE_CONNECT,
-    RETURN_CODE_ACCEPTED,
+    sys_stats::SysStats,
+    will_setup::WillSetup,
+    MSG_TYPE_CONNACK, RETURN_CODE_ACCEPTED, RETURN_CODE_CONGESTION,
+    RETURN_CODE_NOT_SUPPORTED,
 };
 
-/// Connect and Connect4 are for sending CONNECT messages with different header lengths.
 #[derive(
     Debug, Clone, Getters, MutGetters, CopyGetters, Default, PartialEq,
 )]
@@ -63,23 +75,6 @@ pub struct Connect {
     pub client_id: Bytes,
 }
 
-/// Connect and Connect4 are for sending CONNECT messages with different header lengths.
-#[derive(
-    Debug, Clone, Getters, MutGetters, CopyGetters, Default, PartialEq,
-)]
-#[getset(get, set)]
-pub struct Connect4 {
-    pub one: u8,
-    pub len: u16,
-    #[debug(format = "0x{:x}")]
-    pub msg_type: u8,
-    #[debug(format = "0b{:08b}")]
-    pub flags: u8,
-    pub protocol_id: u8,
-    pub duration: u16,
-    pub client_id: Bytes,
-}
-
 impl Connect {
     #[inline(always)]
     pub fn send(
@@ -90,77 +85,30 @@ impl Connect {
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
-        let len = client_id.len() + MSG_LEN_CONNECT_HEADER as usize;
         let remote_addr = msg_header.remote_socket_addr;
-        if len < 256 {
-            let connect = Connect {
-                len: len as u8,
-                msg_type: MSG_TYPE_CONNECT,
-                flags,
-                protocol_id,
-                duration,
-                client_id,
-            };
-            let mut bytes_buf = BytesMut::with_capacity(len);
-            // serialize the con_ack struct into byte(u8) array for the network.
-            // serialize the con_ack struct into byte(u8) array for the network.
-            dbg!(connect.clone());
-            dbg!((bytes_buf.clone(), &connect));
-            connect.try_write(&mut bytes_buf);
-            dbg!(bytes_buf.clone());
-            // transmit to network
-            if let Err(err) = client
-                .egress_tx
-                .try_send((remote_addr, bytes_buf.to_owned()))
-            {
-                return Err(eformat!(remote_addr, err));
-            }
-            RetransTimeWheel::schedule_timer(
-                remote_addr,
-                MSG_TYPE_CONNACK,
-                0,
-                0,
-                1,
-                bytes_buf,
-            )?;
-            return Ok(());
-        // TODO check size 1400
-        } else if len < 1400 {
-            let connect = Connect4 {
-                one: 1,
-                len: len as u16,
-                msg_type: MSG_TYPE_CONNECT,
-                flags: 0b00000100,
-                protocol_id: 1,
-                duration,
-                client_id,
-            };
-            let mut bytes_buf = BytesMut::with_capacity(len);
-            // serialize the con_ack struct into byte(u8) array for the network.
-            // serialize the con_ack struct into byte(u8) array for the network.
-            dbg!(connect.clone());
-            dbg!((bytes_buf.clone(), &connect));
-            connect.try_write(&mut bytes_buf);
-            dbg!(bytes_buf.clone());
-            // transmit to network
-            if let Err(err) = client
-                .egress_tx
-                .try_send((remote_addr, bytes_buf.to_owned()))
-            {
-                return Err(eformat!(remote_addr, err));
-            }
-            RetransTimeWheel::schedule_timer(
-                remote_addr,
-                MSG_TYPE_CONNACK,
-                0,
-                0,
-                1,
-                bytes_buf,
-            )?;
-            return Ok(());
-        } else {
-            Err(eformat!(remote_addr, "client_id too long"))
+        let bytes_buf = encode_packet(&Packet::Connect {
+            flags,
+            protocol_id,
+            duration,
+            client_id: &client_id,
+        })?;
+        dbg!(bytes_buf.clone());
+        // transmit to network
+        if let Err(err) = client
+            .egress_tx
+            .try_send((remote_addr, bytes_buf.to_owned()))
+        {
+            return Err(eformat!(remote_addr, err));
         }
+        RetransTimeWheel::schedule_timer(
+            remote_addr,
+            MSG_TYPE_CONNACK,
+            0,
+            0,
+            1,
+            bytes_buf,
+        )?;
+        Ok(())
     }
 
     #[inline(always)]
@@ -171,33 +119,290 @@ impl Connect {
         msg_header: MsgHeader,
     ) -> Result<(), String> {
         dbg_buf!(buf, size);
-        let (connect, _read_fixed_len) = match msg_header.header_len {
-            MsgHeaderLenEnum::Short => Connect::try_read(buf, size).unwrap(),
-            MsgHeaderLenEnum::Long => {
-                // *NOTE* The len is no long valid. Use msg_header.len instead.
-                Connect::try_read(&buf[2..], size - 2).unwrap()
-            }
-        };
+        // *NOTE* The len of a 3-octet length field isn't valid in the view
+        // of MsgHeader::short_view(), use msg_header.len instead.
+        let (mut connect, _read_fixed_len) =
+            Decode::read(Connect::try_read(buf, size), size, &msg_header)?;
         // TODO check size vs len
         // dbg!(msg_header);
         dbg!(&connect);
         // Create a new connection will messages and conn_ack messages.
         let remote_addr = msg_header.remote_socket_addr;
-        Connection::try_insert(
+        // The CONNACK has the format of the version, 1.2 for an unknown
+        // protocol id.
+        let version =
+            match ProtocolVersion::from_protocol_id(connect.protocol_id) {
+                Ok(version) => version,
+                Err(why) => {
+                    return ConnAck::reject(
+                        client,
+                        msg_header,
+                        RETURN_CODE_NOT_SUPPORTED,
+                        eformat!(remote_addr, why),
+                    );
+                }
+            };
+        let msg_header = msg_header.with_version(version);
+        if let Err(why) = version.normalize(&mut connect) {
+            return ConnAck::reject(
+                client,
+                msg_header,
+                RETURN_CODE_NOT_SUPPORTED,
+                eformat!(remote_addr, why),
+            );
+        }
+        if let Err(why) = DtlsAuth::check(&remote_addr) {
+            client
+                .events
+                .on_denied(remote_addr, DeniedAction::Connect, "");
+            return ConnAck::reject(
+                client,
+                msg_header,
+                RETURN_CODE_NOT_SUPPORTED,
+                why,
+            );
+        }
+        if let Err(why) = ClientId::validate(&connect.client_id) {
+            return ConnAck::reject(
+                client,
+                msg_header,
+                RETURN_CODE_NOT_SUPPORTED,
+                eformat!(remote_addr, why),
+            );
+        }
+        if client.is_congested() {
+            let why = eformat!(remote_addr, "congested");
+            return ConnAck::reject(
+                client,
+                msg_header,
+                RETURN_CODE_CONGESTION,
+                why,
+            );
+        }
+        // A retransmitted CONNECT waits for the first one.
+        let _entry = Connection::lock_entry(remote_addr);
+        if !flag_is_clean_session(connect.flags) {
+            if let Some(old_addr) =
+                Connection::rebind(&connect.client_id, remote_addr)
+            {
+                Connect::rebind(old_addr, remote_addr, client);
+            }
+        }
+        let from = match Connection::try_insert(
             remote_addr,
             connect.flags,
             connect.protocol_id,
             connect.duration,
-            connect.client_id,
-        )?;
+            connect.client_id.clone(),
+        ) {
+            Ok(from) => from,
+            Err(why) => {
+                return ConnAck::reject(
+                    client,
+                    msg_header,
+                    RETURN_CODE_NOT_SUPPORTED,
+                    why,
+                );
+            }
+        };
+        Connect::take_over(
+            &connect.client_id,
+            remote_addr,
+            connect.flags,
+            client,
+        );
+        // The messages buffered while the client was asleep are sent after
+        // the CONNACK, without CleanSession.
+        if flag_is_clean_session(connect.flags) {
+            AsleepMsgCache::delete(remote_addr);
+        }
+        if from != StateEnum2::ACTIVE {
+            client.events.on_state_change(
+                remote_addr,
+                from,
+                StateEnum2::ACTIVE,
+            );
+        }
+        client.events.on_connect(remote_addr, &connect.client_id);
+        SysStats::inc_clients();
         KeepAliveTimeWheel::schedule(remote_addr, connect.duration)?;
         if flag_is_will(connect.flags) {
             // Client set the Will Flag, so the GW must send a Will Topic Request message.
-            WillTopicReq::send(client, msg_header)?;
+            WillSetup::start(client, msg_header)?;
         } else {
             // Client did not set the Will Flag, so the GW must send a Connect Ack message.
             ConnAck::send(client, msg_header, RETURN_CODE_ACCEPTED)?;
         }
         Ok(())
     }
+
+    /// The session moved from old_addr to remote_addr, see
+    /// Connection::rebind(). The timers of the old address are canceled and
+    /// the messages buffered for a sleeping client are moved. An ACTIVE
+    /// session is LOST at the old address, the CONNECT makes it ACTIVE.
+    fn rebind(
+        old_addr: SocketAddr,
+        remote_addr: SocketAddr,
+        client: &MqttSnClient,
+    ) {
+        RetransTimeWheel::cancel_all(old_addr);
+        let _result = KeepAliveTimeWheel::cancel(&old_addr);
+        for publish in AsleepMsgCache::delete(old_addr) {
+            if let Err(why) = AsleepMsgCache::insert(remote_addr, publish) {
+                error!("{}", why);
+            }
+        }
+        client.events.on_rebind(old_addr, remote_addr);
+        if Connection::get_state(&remote_addr) == Ok(StateEnum2::ACTIVE) {
+            let _result = Connection::transition(
+                &remote_addr,
+                ConnEvent::Expire,
+                &*client.events,
+            );
+        }
+    }
+
+    /// The client id connected from remote_addr, disconnect the sessions
+    /// with the same client id at other addresses. Connection::try_insert()
+    /// moved their subscriptions without CleanSession, the ones left are
+    /// deleted, so are the messages buffered for a sleeping old session.
+    /// The will of an old session isn't published.
+    fn take_over(
+        client_id: &Bytes,
+        remote_addr: SocketAddr,
+        flags: u8,
+        client: &MqttSnClient,
+    ) {
+        for old_addr in ClientId::get(client_id) {
+            if old_addr == remote_addr {
+                continue;
+            }
+            let buffered = AsleepMsgCache::delete(old_addr);
+            if flag_is_clean_session(flags) {
+                delete_subscribers_with_socket_addr(&old_addr);
+            } else {
+                for publish in buffered {
+                    if let Err(why) =
+                        AsleepMsgCache::insert(remote_addr, publish)
+                    {
+                        error!("{}", why);
+                    }
+                }
+            }
+            RetransTimeWheel::cancel_all(old_addr);
+            let _result = KeepAliveTimeWheel::cancel(&old_addr);
+            let _result = Connection::remove(&old_addr);
+            ClientId::rev_delete(&old_addr);
+            client.events.on_disconnect(old_addr);
+            info!("{}", eformat!(client_id, "taken over", old_addr));
+            if let Err(why) = Disconnect::send_to(client, old_addr) {
+                error!("{}", why);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_session_takeover() {
+        use super::*;
+        use crate::filter::get_subscribers_with_topic_id;
+        use crate::flags::QOS_LEVEL_0;
+        use crate::test_support::{LoopbackBroker, TestClient};
+        use crate::MSG_TYPE_DISCONNECT;
+        let broker = LoopbackBroker::addr();
+        let mut old = TestClient::new(broker).unwrap();
+        old.connect("takeover", 60, None).unwrap();
+        let (topic_id, _) =
+            old.subscribe("takeover/temp", QOS_LEVEL_0).unwrap();
+        let mut new = TestClient::new(broker).unwrap();
+        assert_eq!(new.connect("takeover", 60, None), Ok(RETURN_CODE_ACCEPTED));
+        old.expect(MSG_TYPE_DISCONNECT).unwrap();
+        let client_id = Bytes::from("takeover");
+        assert_eq!(ClientId::get(&client_id), vec![new.local_addr()]);
+        assert!(!Connection::contains_key(old.local_addr()));
+        // CleanSession, the subscription isn't moved.
+        assert!(get_subscribers_with_topic_id(topic_id).is_empty());
+        // Invalid client ids.
+        let mut other = TestClient::new(broker).unwrap();
+        let too_long = "a".repeat(24);
+        for client_id in ["", "take_over", too_long.as_str()].iter() {
+            assert_eq!(
+                other.connect(client_id, 60, None),
+                Ok(RETURN_CODE_NOT_SUPPORTED)
+            );
+        }
+        new.disconnect(None).unwrap();
+    }
+
+    #[test]
+    fn test_repeated_connect() {
+        use super::*;
+        use crate::test_support::{LoopbackBroker, TestClient};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use std::thread;
+        use std::time::Duration;
+        let mut test_client = TestClient::new(LoopbackBroker::addr()).unwrap();
+        let addr = test_client.local_addr();
+        // The CONNACK was lost, the client sends the CONNECT again.
+        for _ in 0..2 {
+            assert_eq!(
+                test_client.connect("repeated", 60, None),
+                Ok(RETURN_CODE_ACCEPTED)
+            );
+            assert_eq!(Connection::get_state(&addr), Ok(StateEnum2::ACTIVE));
+        }
+        assert_eq!(ClientId::get(&Bytes::from("repeated")), vec![addr]);
+        test_client.disconnect(None).unwrap();
+        // A second CONNECT waits for the entry of the first one.
+        let entry = Connection::lock_entry(addr);
+        let locked = Arc::new(AtomicBool::new(false));
+        let locked2 = locked.clone();
+        let waiter = thread::spawn(move || {
+            let _entry = Connection::lock_entry(addr);
+            locked2.store(true, Ordering::SeqCst);
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!locked.load(Ordering::SeqCst));
+        drop(entry);
+        waiter.join().unwrap();
+        assert!(locked.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_rebind() {
+        use super::*;
+        use crate::filter::{get_subscribers_with_topic_id, match_topics};
+        use crate::flags::QOS_LEVEL_1;
+        use crate::test_support::{LoopbackBroker, TestClient};
+        use crate::MSG_TYPE_CONNECT;
+        let broker = LoopbackBroker::addr();
+        let mut old = TestClient::new(broker).unwrap();
+        old.connect("rebind", 60, None).unwrap();
+        let (topic_id, _) = old.subscribe("rebind/temp", QOS_LEVEL_1).unwrap();
+        old.subscribe("rebind/+/humidity", QOS_LEVEL_1).unwrap();
+        // The same client from a new source port, without CleanSession.
+        let mut new = TestClient::new(broker).unwrap();
+        let mut connect = vec![0, MSG_TYPE_CONNECT, 0, 1, 0, 60];
+        connect.extend_from_slice(b"rebind");
+        connect[0] = connect.len() as u8;
+        new.send(&connect).unwrap();
+        let conn_ack = new.expect(MSG_TYPE_CONNACK).unwrap();
+        assert_eq!(conn_ack[2], RETURN_CODE_ACCEPTED);
+        let (old_addr, new_addr) = (old.local_addr(), new.local_addr());
+        assert_eq!(ClientId::get(&Bytes::from("rebind")), vec![new_addr]);
+        assert!(!Connection::contains_key(old_addr));
+        assert_eq!(Connection::get_state(&new_addr), Ok(StateEnum2::ACTIVE));
+        let subscribers = get_subscribers_with_topic_id(topic_id);
+        assert_eq!(subscribers.len(), 1);
+        assert_eq!(subscribers[0].socket_addr, new_addr);
+        assert_eq!(subscribers[0].qos, QOS_LEVEL_1);
+        assert_eq!(
+            match_topics(&"rebind/kitchen/humidity".to_string()),
+            vec![new_addr]
+        );
+        new.disconnect(None).unwrap();
+    }
 }
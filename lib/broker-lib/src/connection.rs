@@ -1,22 +1,45 @@
 use crate::{
-    broker_lib::MqttSnClient, client_id::ClientId, eformat, filter::*,
-    flags::*, function, publish::Publish, TopicIdType,
+    annotation::Annotations,
+    broker_context::BrokerContext,
+    broker_lib::MqttSnClient,
+    client_id::ClientId,
+    collections::{BoundedMap, ConnMap},
+    config::PreDefinedTopics,
+    dtls_auth::{DtlsAuth, Identity},
+    dup_filter::DupFilter,
+    eformat,
+    events::BrokerEvents,
+    filter::*,
+    flags::*,
+    function,
+    in_flight::InFlight,
+    msg_id::MsgIdAllocator,
+    ordered_delivery::OrderedDelivery,
+    publish::Publish,
+    register_on_demand::RegisterOnDemand,
+    retain::Retain,
+    storage_backend::{Persistence, StoredSession, StoredWill},
+    subscription_export::{SubscriptionExport, SubscriptionRecord},
+    topic_refs::{TopicRef, TopicRefs},
+    TopicIdType,
 };
-// use log::*;
+use log::*;
 // use rand::Rng;
 use bisetmap::BisetMap;
-use bytes::{BufMut, Bytes, BytesMut};
-use hashbrown::HashMap;
+use bytes::Bytes;
+use hashbrown::HashSet;
 use std::net::{IpAddr, SocketAddr};
 use std::time::{SystemTime, UNIX_EPOCH};
-use std::{sync::Arc, sync::Mutex};
+use std::{sync::Arc, sync::Condvar, sync::Mutex};
 use trace_caller::trace;
 use uuid::v1::{Context, Timestamp};
 use uuid::Uuid;
 
 pub type ConnId = Uuid;
 
-#[derive(Debug, Clone)]
+/// Client states, section 6.14 of the MQTT-SN 1.2 spec. The state changes
+/// only with Connection::transition().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum StateEnum2 {
     ACTIVE,
     ASLEEP,
@@ -25,6 +48,49 @@ pub enum StateEnum2 {
     LOST,
 }
 
+/// Events of the client state machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnEvent {
+    Connect,
+    Disconnect,
+    /// DISCONNECT with a sleep duration.
+    Sleep,
+    /// PINGREQ with the client id of a sleeping client.
+    Wake,
+    /// PINGRESP after the buffered messages of an awake client.
+    WakeDone,
+    /// Keep alive or sleep timer expired.
+    Expire,
+}
+
+impl StateEnum2 {
+    /// The state after the event, None if the event is illegal.
+    pub fn next(&self, event: ConnEvent) -> Option<StateEnum2> {
+        use ConnEvent::*;
+        use StateEnum2::*;
+        match (self, event) {
+            // A CONNECT from a sleeping client ends the sleep, the
+            // buffered messages are sent after the CONNACK.
+            (DISCONNECTED, Connect)
+            | (ASLEEP, Connect)
+            | (AWAKE, Connect)
+            | (LOST, Connect) => Some(ACTIVE),
+            // A DISCONNECT without duration from a sleeping client ends
+            // the session, the buffered messages are dropped.
+            (ACTIVE, Disconnect)
+            | (ASLEEP, Disconnect)
+            | (AWAKE, Disconnect)
+            | (LOST, Disconnect) => Some(DISCONNECTED),
+            // A DISCONNECT with duration while ASLEEP restarts the timer.
+            (ACTIVE, Sleep) | (ASLEEP, Sleep) | (AWAKE, Sleep) => Some(ASLEEP),
+            (ASLEEP, Wake) => Some(AWAKE),
+            (AWAKE, WakeDone) => Some(ASLEEP),
+            (ACTIVE, Expire) | (ASLEEP, Expire) | (AWAKE, Expire) => Some(LOST),
+            _ => None,
+        }
+    }
+}
+
 /// Generate a new UUID
 /// Use timestamp with nanoseconds precision
 /// Use socket_addr, 6 bytes
@@ -42,20 +108,9 @@ pub fn generate_conn_id(
     let context = Context::new(context_num);
     let time_stamp =
         Timestamp::from_unix(&context, time_stamp_secs, time_stamp_nanos);
-    let ip4_bytes: [u8; 4];
+    let ip4_bytes: [u8; 4] = ip_node_bytes(&socket_addr.ip());
     let port_bytes: [u8; 2] = socket_addr.port().to_be_bytes();
 
-    match socket_addr.ip() {
-        IpAddr::V4(ip4) => ip4_bytes = ip4.octets(),
-        IpAddr::V6(ip6) => {
-            let msg = format!(
-                "ipv6: {}, segments: {:?} not supported",
-                ip6,
-                ip6.segments()
-            );
-            return Err(msg);
-        }
-    }
     let socket_addr_bytes: [u8; 6] = [
         ip4_bytes[0],
         ip4_bytes[1],
@@ -74,15 +129,64 @@ pub fn generate_conn_id(
     Ok(uuid)
 }
 
-lazy_static! {
-    // TODO add comments!!!
-    static ref CONN_HASHMAP: Mutex<HashMap<SocketAddr, Connection>> =
-        Mutex::new(HashMap::new());
+/// 4 bytes of the IP address for the node id. IPv4-mapped addresses of a
+/// dual-stack socket use the IPv4 address, other IPv6 addresses are folded.
+fn ip_node_bytes(ip: &IpAddr) -> [u8; 4] {
+    match ip {
+        IpAddr::V4(ip4) => ip4.octets(),
+        IpAddr::V6(ip6) => match ip6.to_ipv4() {
+            Some(ip4) => ip4.octets(),
+            None => {
+                let mut bytes = [0u8; 4];
+                for (i, byte) in ip6.octets().iter().enumerate() {
+                    bytes[i % 4] ^= byte;
+                }
+                bytes
+            }
+        },
+    }
+}
 
+/// Connections of a broker, see BrokerContext.
+pub(crate) struct ConnState {
+    conn_hashmap: Mutex<ConnMap<SocketAddr, Connection>>,
     // TODO: for connection migration, when the client has a new socket_addr,
     //       use the ConnId to locate the connection.
-    static ref CONN_ID_BISET_MAP: Mutex<BisetMap<ConnId, SocketAddr>> =
-        Mutex::new(BisetMap::new());
+    conn_id_biset_map: Mutex<BisetMap<ConnId, SocketAddr>>,
+    /// Addresses with a CONNECT in progress, see Connection::lock_entry().
+    locked_entries: Mutex<HashSet<SocketAddr>>,
+    entry_unlocked: Condvar,
+}
+
+impl Default for ConnState {
+    fn default() -> Self {
+        ConnState {
+            conn_hashmap: Mutex::new(ConnMap::new()),
+            conn_id_biset_map: Mutex::new(BisetMap::new()),
+            locked_entries: Mutex::new(HashSet::new()),
+            entry_unlocked: Condvar::new(),
+        }
+    }
+}
+
+/// The connection entry of an address is locked until the guard is
+/// dropped.
+pub struct EntryGuard {
+    socket_addr: SocketAddr,
+    state: &'static ConnState,
+}
+
+impl Drop for EntryGuard {
+    fn drop(&mut self) {
+        let mut locked_entries = self.state.locked_entries.lock().unwrap();
+        locked_entries.remove(&self.socket_addr);
+        self.state.entry_unlocked.notify_all();
+    }
+}
+
+#[inline(always)]
+fn state() -> &'static ConnState {
+    &BrokerContext::current().connection
 }
 
 /// A connection is CURRENT network connection a client connects to the server.
@@ -104,6 +208,11 @@ pub struct Connection {
     pub will_topic_id: Option<TopicIdType>,
     pub will_topic: Bytes, // *NOTE: this is a Bytes, not a BytesMut.
     pub will_message: Bytes,
+    /// QoS and retain flag of the will, from the WILLTOPIC flags.
+    pub will_qos: QoSConst,
+    pub will_retain: u8,
+    /// DTLS identity, None without DTLS authentication.
+    pub identity: Option<Identity>,
     // TODO pub sleep_msg_vec: Vec<Bytes>,
 }
 
@@ -149,25 +258,58 @@ impl Connection {
             will_topic_id: None,
             will_topic: Bytes::new(),
             will_message: Bytes::new(),
+            will_qos: QOS_LEVEL_0,
+            will_retain: RETAIN_FALSE,
+            identity: DtlsAuth::identity(&socket_addr),
+        }
+    }
+    /// Lock the connection entry of the address, the CONNECT messages of
+    /// an address are processed one at a time. A retransmitted CONNECT
+    /// waits for the first one, then finds its connection.
+    pub fn lock_entry(socket_addr: SocketAddr) -> EntryGuard {
+        let state = state();
+        let mut locked_entries = state.locked_entries.lock().unwrap();
+        while locked_entries.contains(&socket_addr) {
+            locked_entries = state.entry_unlocked.wait(locked_entries).unwrap();
         }
+        locked_entries.insert(socket_addr);
+        EntryGuard { socket_addr, state }
     }
+    /// Get or insert the connection of the CONNECT, call it under
+    /// lock_entry(). Returns the previous state, DISCONNECTED for a new
+    /// connection.
     pub fn try_insert(
         socket_addr: SocketAddr,
         flags: u8,
         protocol_id: u8,
         duration: u16,
         client_id: Bytes,
-    ) -> Result<(), String> {
+    ) -> Result<StateEnum2, String> {
+        if flag_is_clean_session(flags) {
+            Persistence::forget(&client_id);
+        }
         if ClientId::contains(&client_id, &socket_addr) {
-            // An existing client with same the socket_addr reconnects
-            Connection::update_state(&socket_addr, StateEnum2::ACTIVE)?;
+            // An existing client with same the socket_addr reconnects. The
+            // CONNECT of an ACTIVE client, e.g. its CONNACK was lost, is a
+            // new connection setup and stays ACTIVE.
+            let from = {
+                let mut conn_hashmap = state().conn_hashmap.lock().unwrap();
+                let conn = match conn_hashmap.get_mut(&socket_addr) {
+                    Some(conn) => conn,
+                    None => return Err(eformat!(socket_addr, "not found.")),
+                };
+                conn.flags = flags;
+                conn.protocol_id = protocol_id;
+                conn.duration = duration;
+                let mut conn_state = conn.state.lock().unwrap();
+                let from = *conn_state;
+                *conn_state =
+                    from.next(ConnEvent::Connect).unwrap_or(StateEnum2::ACTIVE);
+                from
+            };
             if flag_is_clean_session(flags) {
                 // Delete all subscriptions
-                let topic_id_vec =
-                    delete_topic_ids_with_socket_addr(&socket_addr);
-                for topic_id in topic_id_vec {
-                    let _qos = remove_qos(&topic_id, &socket_addr);
-                }
+                delete_topic_ids_with_socket_addr(&socket_addr);
             }
             if flag_is_will(flags) {
                 // Delete will data, will_topic_id from the connection struct
@@ -176,7 +318,7 @@ impl Connection {
                     Connection::delete_will_topic_id(&socket_addr)?;
                 delete_topic_id(&will_topic_id);
             }
-            return Ok(());
+            return Ok(from);
         }
         dbg!(&socket_addr);
         // For existing client_id with different socket_addr or new client_id.
@@ -184,20 +326,21 @@ impl Connection {
         let mut will_topic_id = None;
         let mut will_topic = Bytes::new();
         let mut will_message = Bytes::new();
+        let mut will_qos = QOS_LEVEL_0;
+        let mut will_retain = RETAIN_FALSE;
         // ClientId::get() should return one old_socket_addr, but the get() returns
         // vec. Use for loop to traverse.
-        for old_socket_addr in ClientId::get(&client_id) {
+        let old_socket_addrs = ClientId::get(&client_id);
+        for old_socket_addr in old_socket_addrs.iter().copied() {
             // Existing client id with different socket_addr
             // Possible client migration or restart.
             dbg!(old_socket_addr);
             // Move existing subscriptions for non-clean session
             if !flag_is_clean_session(flags) {
                 // remove all the topic ids link to the old socket_addr
-                let topic_id_vec =
-                    delete_topic_ids_with_socket_addr(&old_socket_addr);
-                for topic_id in topic_id_vec {
-                    // remove each QoS entries
-                    let qos = remove_qos(&topic_id, &old_socket_addr).unwrap();
+                let subscriptions =
+                    take_subscriptions_with_socket_addr(&old_socket_addr);
+                for (topic_id, qos) in subscriptions {
                     // subscribe with new socket_addr
                     let _result =
                         subscribe_with_topic_id(socket_addr, topic_id, qos);
@@ -205,11 +348,14 @@ impl Connection {
             }
             // copy will data for will flag == false
             if !flag_is_will(flags) {
-                match CONN_HASHMAP.lock().unwrap().get(&old_socket_addr) {
+                match state().conn_hashmap.lock().unwrap().get(&old_socket_addr)
+                {
                     Some(conn) => {
                         will_topic_id = conn.will_topic_id;
                         will_topic = conn.will_topic.clone();
                         will_message = conn.will_message.clone();
+                        will_qos = conn.will_qos;
+                        will_retain = conn.will_retain;
                     }
                     None => {
                         return Err(eformat!(socket_addr, client_id));
@@ -217,6 +363,22 @@ impl Connection {
                 }
             }
         }
+        // A client of a session in the storage backend, e.g. before a
+        // restart of the broker.
+        if old_socket_addrs.is_empty() && !flag_is_clean_session(flags) {
+            if let Some(will) =
+                Connection::restore_session(&client_id, flag_is_will(flags))
+            {
+                will_topic_id = Some(TopicRefs::insert(
+                    will.topic.clone(),
+                    TopicRef::Will(socket_addr),
+                )?);
+                will_topic = Bytes::from(will.topic);
+                will_message = Bytes::from(will.message);
+                will_qos = will.qos;
+                will_retain = will.retain;
+            }
+        }
         // Initialize the connection with new socket_addr with
         // existing or new client_id.
         let conn = Connection {
@@ -229,94 +391,337 @@ impl Connection {
             will_topic_id,
             will_topic,
             will_message,
+            will_qos,
+            will_retain,
+            identity: DtlsAuth::identity(&socket_addr),
             // TODO  sleep_msg_vec: Vec::new(),
         };
         dbg!(&conn);
+        // The will copied from the old socket_addr.
+        if let Some(topic_id) = will_topic_id {
+            TopicRefs::acquire(topic_id, TopicRef::Will(socket_addr));
+        }
         ClientId::insert(client_id, socket_addr);
-        if let Err(why) =
-            CONN_HASHMAP.lock().unwrap().try_insert(socket_addr, conn)
+        let mut conn_hashmap = state().conn_hashmap.lock().unwrap();
+        if conn_hashmap.contains_key(&socket_addr) {
+            return Err(eformat!(socket_addr, "already exists."));
+        }
+        match conn_hashmap.bounded_insert(socket_addr, conn) {
+            // A new connection, DISCONNECTED to ACTIVE.
+            Ok(_) => Ok(StateEnum2::DISCONNECTED),
+            Err(_) => Err(eformat!(socket_addr, "connection map full.")),
+        }
+    }
+    /// Queue the subscriptions of the stored session of the client, they're
+    /// applied after the CONNACK. Returns the stored will, unless the
+    /// CONNECT has the will flag.
+    fn restore_session(
+        client_id: &Bytes,
+        with_will: bool,
+    ) -> Option<StoredWill> {
+        let backend = Persistence::backend()?;
+        match backend.get_session(client_id) {
+            Ok(Some(session)) => SubscriptionExport::add_pending(
+                client_id.clone(),
+                session.subscriptions,
+            ),
+            Ok(None) => (),
+            Err(why) => error!("{}", why),
+        }
+        if with_will {
+            return None;
+        }
+        match backend.get_will(client_id) {
+            Ok(will) => will,
+            Err(why) => {
+                error!("{}", why);
+                None
+            }
+        }
+    }
+    /// Write the session of a client without CleanSession to the storage
+    /// backend, call it before its subscriptions are deleted.
+    pub fn save_session(socket_addr: &SocketAddr) {
+        if Persistence::backend().is_none() {
+            return;
+        }
+        let conn = match Connection::get(socket_addr) {
+            Some(conn) if !flag_is_clean_session(conn.flags) => conn,
+            _ => return,
+        };
+        let client_id = String::from_utf8_lossy(&conn.client_id).to_string();
+        let subscriptions = get_subscriptions()
+            .into_iter()
+            .filter(|(addr, _topic_id, _qos)| addr == socket_addr)
+            .filter_map(|(_addr, topic_id, qos)| {
+                let topic_name = get_topic_name_with_topic_id(topic_id)
+                    .or_else(|| PreDefinedTopics::name(topic_id))?;
+                Some(SubscriptionRecord {
+                    client_id: client_id.clone(),
+                    topic_name,
+                    topic_id,
+                    qos,
+                })
+            })
+            .collect();
+        let session = StoredSession { subscriptions };
+        Persistence::write(|backend| {
+            backend.put_session(&conn.client_id, &session)
+        });
+    }
+    /// A CONNECT without CleanSession from a new address of a connected
+    /// client id, e.g. a NAT rebinding of a cellular client. The connection
+    /// is moved to socket_addr with its state, will and DTLS identity, so
+    /// are the subscriptions and the registrations. The messages in flight
+    /// to the old address are dropped. None if the client id has no single
+    /// connection at another address, or if the DTLS identity differs, the
+    /// CONNECT is then a new session. Returns the old address.
+    pub fn rebind(
+        client_id: &Bytes,
+        socket_addr: SocketAddr,
+    ) -> Option<SocketAddr> {
+        let old_addr = match ClientId::get(client_id).as_slice() {
+            [old_addr] if *old_addr != socket_addr => *old_addr,
+            _ => return None,
+        };
+        let identity = DtlsAuth::identity(&socket_addr);
         {
-            return Err(eformat!(
-                socket_addr,
-                why.entry.key(),
-                "already exists."
-            ));
+            let mut conn_hashmap = state().conn_hashmap.lock().unwrap();
+            if conn_hashmap.contains_key(&socket_addr) {
+                return None;
+            }
+            match conn_hashmap.get(&old_addr) {
+                Some(conn) if conn.identity == identity => (),
+                _ => return None,
+            }
+            let mut conn = conn_hashmap.remove(&old_addr)?;
+            conn.socket_addr = socket_addr;
+            conn.identity = identity;
+            // One entry was removed, the map isn't full.
+            let _result = conn_hashmap.bounded_insert(socket_addr, conn);
         }
-        Ok(())
+        // Not under the CONN_HASHMAP lock.
+        ClientId::rev_delete(&old_addr);
+        ClientId::insert(client_id.clone(), socket_addr);
+        for (topic_id, qos) in take_subscriptions_with_socket_addr(&old_addr) {
+            if let Err(why) =
+                subscribe_with_topic_id(socket_addr, topic_id, qos)
+            {
+                error!("{}", why);
+            }
+        }
+        TopicRefs::rebind_client(&old_addr, socket_addr);
+        MsgIdAllocator::remove(&old_addr);
+        InFlight::remove(&old_addr);
+        RegisterOnDemand::remove(&old_addr);
+        DupFilter::remove(&old_addr);
+        #[cfg(feature = "fragmentation")]
+        crate::fragment::Fragment::remove(&old_addr);
+        info!("{}", eformat!(client_id, "rebound", old_addr, socket_addr));
+        Some(old_addr)
     }
     // TODO avoid lookup by using the connection struct.
     // use method on the Connection struct.
     pub fn get_state(socket_addr: &SocketAddr) -> Result<StateEnum2, String> {
-        let conn_hashmap = CONN_HASHMAP.lock().unwrap();
+        let conn_hashmap = state().conn_hashmap.lock().unwrap();
         match conn_hashmap.get(socket_addr) {
-            Some(conn) => {
-                let state = conn.state.lock().unwrap().clone();
-                Ok(state)
-            }
+            Some(conn) => Ok(*conn.state.lock().unwrap()),
             None => Err(eformat!(socket_addr, "state not found.")),
         }
     }
-    pub fn update_state(
+    /// Apply the event to the state of the client and call
+    /// on_state_change(). An illegal event returns an error and the state is
+    /// unchanged. Returns the previous state.
+    pub fn transition(
         socket_addr: &SocketAddr,
-        new_state: StateEnum2,
-    ) -> Result<(), String> {
-        let mut conn_hashmap = CONN_HASHMAP.lock().unwrap();
-        match conn_hashmap.get_mut(socket_addr) {
+        event: ConnEvent,
+        events: &dyn BrokerEvents,
+    ) -> Result<StateEnum2, String> {
+        let (from, to) = Connection::next_state(socket_addr, event)?;
+        // Not under the CONN_HASHMAP lock.
+        events.on_state_change(*socket_addr, from, to);
+        Ok(from)
+    }
+    fn next_state(
+        socket_addr: &SocketAddr,
+        event: ConnEvent,
+    ) -> Result<(StateEnum2, StateEnum2), String> {
+        let conn_hashmap = state().conn_hashmap.lock().unwrap();
+        match conn_hashmap.get(socket_addr) {
             Some(conn) => {
-                *conn.state.lock().unwrap() = new_state;
-                Ok(())
+                let mut state = conn.state.lock().unwrap();
+                let from = *state;
+                match from.next(event) {
+                    Some(to) => {
+                        *state = to;
+                        Ok((from, to))
+                    }
+                    None => Err(eformat!(socket_addr, "illegal", from, event)),
+                }
             }
             None => Err(eformat!(socket_addr, "state not found.")),
         }
     }
+    /// Number of connections that are not DISCONNECTED or LOST.
+    pub fn count_connected() -> usize {
+        state()
+            .conn_hashmap
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|conn| match *conn.state.lock().unwrap() {
+                StateEnum2::ACTIVE | StateEnum2::ASLEEP | StateEnum2::AWAKE => {
+                    true
+                }
+                StateEnum2::DISCONNECTED | StateEnum2::LOST => false,
+            })
+            .count()
+    }
+    pub fn get_identity(socket_addr: &SocketAddr) -> Option<Identity> {
+        let conn_hashmap = state().conn_hashmap.lock().unwrap();
+        conn_hashmap.get(socket_addr)?.identity.clone()
+    }
+    pub fn get_protocol_id(socket_addr: &SocketAddr) -> Option<u8> {
+        let conn_hashmap = state().conn_hashmap.lock().unwrap();
+        conn_hashmap.get(socket_addr).map(|conn| conn.protocol_id)
+    }
+    /// Connected, not LOST or DISCONNECTED.
+    pub fn is_connected(socket_addr: &SocketAddr) -> bool {
+        matches!(
+            Connection::get_state(socket_addr),
+            Ok(StateEnum2::ACTIVE | StateEnum2::ASLEEP | StateEnum2::AWAKE)
+        )
+    }
+    /// A copy of the connection, it shares the state with the table.
+    pub fn get(socket_addr: &SocketAddr) -> Option<Connection> {
+        let conn_hashmap = state().conn_hashmap.lock().unwrap();
+        conn_hashmap.get(socket_addr).cloned()
+    }
+    /// Addresses of all the connections, including DISCONNECTED and LOST.
+    pub fn socket_addrs() -> Vec<SocketAddr> {
+        let conn_hashmap = state().conn_hashmap.lock().unwrap();
+        conn_hashmap.keys().copied().collect()
+    }
     pub fn contains_key(socket_addr: SocketAddr) -> bool {
-        CONN_HASHMAP.lock().unwrap().contains_key(&socket_addr)
+        state()
+            .conn_hashmap
+            .lock()
+            .unwrap()
+            .contains_key(&socket_addr)
     }
     #[trace]
     pub fn remove(socket_addr: &SocketAddr) -> Result<Connection, String> {
-        let mut conn_hashmap = CONN_HASHMAP.lock().unwrap();
-        match conn_hashmap.remove(socket_addr) {
+        let conn = state().conn_hashmap.lock().unwrap().remove(socket_addr);
+        // Not under the CONN_HASHMAP lock, the retransmit thread locks
+        // CONN_HASHMAP while it holds the retransmit map.
+        MsgIdAllocator::remove(socket_addr);
+        InFlight::remove(socket_addr);
+        RegisterOnDemand::remove(socket_addr);
+        TopicRefs::release_client(socket_addr);
+        DupFilter::remove(socket_addr);
+        OrderedDelivery::remove(socket_addr);
+        #[cfg(feature = "fragmentation")]
+        crate::fragment::Fragment::remove(socket_addr);
+        match conn {
             Some(val) => Ok(val),
             None => Err(eformat!(socket_addr, "not found.")),
         }
     }
-    // Update will topic to an existing connection
+    // Update will topic to an existing connection, the QoS and retain flag
+    // of the will are in the flags of the WILLTOPIC.
     pub fn update_will_topic(
         socket_addr: SocketAddr,
+        flags: u8,
         topic: String,
     ) -> Result<(), String> {
-        let mut conn_hashmap = CONN_HASHMAP.lock().unwrap();
-        match conn_hashmap.get_mut(&socket_addr) {
+        let mut conn_hashmap = state().conn_hashmap.lock().unwrap();
+        let conn = match conn_hashmap.get_mut(&socket_addr) {
             Some(conn) => {
                 conn.will_topic = Bytes::from(topic.clone());
-                let topic_id = try_insert_topic_name(topic)?;
+                conn.will_qos = flag_qos_level(flags);
+                conn.will_retain = flags & RETAIN_TRUE;
+                let topic_id =
+                    TopicRefs::insert(topic, TopicRef::Will(socket_addr))?;
+                if let Some(old_topic_id) = conn.will_topic_id {
+                    if old_topic_id != topic_id {
+                        TopicRefs::release(
+                            old_topic_id,
+                            TopicRef::Will(socket_addr),
+                        );
+                    }
+                }
                 conn.will_topic_id = Some(topic_id);
-                Ok(())
+                conn.clone()
             }
-            None => Err(eformat!(socket_addr, "not found.")),
-        }
+            None => return Err(eformat!(socket_addr, "not found.")),
+        };
+        drop(conn_hashmap);
+        conn.persist_will();
+        Ok(())
     }
     pub fn update_will_msg(
         socket_addr: SocketAddr,
         message: String,
     ) -> Result<(), String> {
-        let mut conn_hashmap = CONN_HASHMAP.lock().unwrap();
-        match conn_hashmap.get_mut(&socket_addr) {
+        let mut conn_hashmap = state().conn_hashmap.lock().unwrap();
+        let conn = match conn_hashmap.get_mut(&socket_addr) {
             Some(conn) => {
                 conn.will_message = Bytes::from(message);
-                Ok(())
+                conn.clone()
             }
-            None => Err(eformat!(socket_addr, "not found.")),
+            None => return Err(eformat!(socket_addr, "not found.")),
+        };
+        drop(conn_hashmap);
+        conn.persist_will();
+        Ok(())
+    }
+    // Delete will topic and will message, for an empty WILLTOPICUPD.
+    pub fn delete_will(socket_addr: SocketAddr) -> Result<(), String> {
+        let mut conn_hashmap = state().conn_hashmap.lock().unwrap();
+        let conn = match conn_hashmap.get_mut(&socket_addr) {
+            Some(conn) => {
+                if let Some(topic_id) = conn.will_topic_id {
+                    TopicRefs::release(topic_id, TopicRef::Will(socket_addr));
+                }
+                conn.will_topic_id = None;
+                conn.will_topic = Bytes::new();
+                conn.will_message = Bytes::new();
+                conn.will_qos = QOS_LEVEL_0;
+                conn.will_retain = RETAIN_FALSE;
+                conn.clone()
+            }
+            None => return Err(eformat!(socket_addr, "not found.")),
+        };
+        drop(conn_hashmap);
+        conn.persist_will();
+        Ok(())
+    }
+    /// Write the will to the storage backend, an empty will topic deletes
+    /// it. Not under the CONN_HASHMAP lock.
+    fn persist_will(&self) {
+        if self.will_topic.is_empty() {
+            Persistence::write(|backend| backend.remove_will(&self.client_id));
+            return;
         }
+        let will = StoredWill {
+            topic: String::from_utf8_lossy(&self.will_topic).to_string(),
+            message: self.will_message.to_vec(),
+            qos: self.will_qos,
+            retain: self.will_retain,
+        };
+        Persistence::write(|backend| backend.put_will(&self.client_id, &will));
     }
     pub fn delete_will_topic_id(
         socket_addr: &SocketAddr,
     ) -> Result<TopicIdType, String> {
-        let mut conn_hashmap = CONN_HASHMAP.lock().unwrap();
+        let mut conn_hashmap = state().conn_hashmap.lock().unwrap();
         match conn_hashmap.get_mut(socket_addr) {
             Some(conn) => {
                 let topic_id = conn.will_topic_id;
                 conn.will_topic_id = None;
+                if let Some(topic_id) = topic_id {
+                    TopicRefs::release(topic_id, TopicRef::Will(*socket_addr));
+                }
                 Ok(topic_id.unwrap())
             }
             None => Err(eformat!(socket_addr, "not found.")),
@@ -326,39 +731,57 @@ impl Connection {
         socket_addr: &SocketAddr,
         client: &MqttSnClient,
     ) -> Result<(), String> {
-        let mut conn_hashmap = CONN_HASHMAP.lock().unwrap();
-        match conn_hashmap.get_mut(socket_addr) {
+        // Copy the will out of the map, Publish::send() must not run under
+        // the CONN_HASHMAP lock.
+        let will = state()
+            .conn_hashmap
+            .lock()
+            .unwrap()
+            .get(socket_addr)
+            .cloned();
+        match will {
             Some(conn) => {
-                // let topic_id = conn.will_topic_id;
-                if let Some(topic_id) = conn.will_topic_id {
-                    let subscriber_vec =
-                        get_subscribers_with_topic_id(topic_id);
-                    for subscriber in subscriber_vec {
-                        // Can't return error, because not all subscribers will have error.
-                        // TODO error for every subscriber/message
-                        // TODO use Bytes not BytesMut to eliminate clone/copy.
-                        // TODO new tx method to reduce have try_write() run once for every subscriber.
-                        let mut msg = BytesMut::new();
-                        msg.put(conn.will_message.clone()); // TODO replace BytesMut with Bytes because clone doesn't copy data in Bytes
-                        let _result = Publish::send(
-                            topic_id,
-                            0, // TODO what is the msg_id?
-                            subscriber.qos,
-                            RETAIN_FALSE,
-                            msg,
-                            client,
-                            subscriber.socket_addr,
-                        );
-                    }
-                }
-                return Ok(());
+                conn.send_will(client);
+                Ok(())
             }
             None => Err(eformat!(socket_addr, "not found.")),
         }
     }
+    /// Publish the will to the subscribers of the will topic, at the will
+    /// QoS at most. A will with the retain flag replaces the retained
+    /// message of the topic. The will is deleted from the storage backend.
+    pub fn send_will(&self, client: &MqttSnClient) {
+        let topic_id = match self.will_topic_id {
+            Some(topic_id) => topic_id,
+            None => return,
+        };
+        Persistence::write(|backend| backend.remove_will(&self.client_id));
+        if flag_is_retain(self.will_retain) {
+            Retain::insert(
+                self.will_qos,
+                topic_id,
+                0,
+                self.will_message.clone(),
+                Annotations::new(),
+            );
+        }
+        for subscriber in get_subscribers_with_topic_id(topic_id) {
+            // Can't return error, because not all subscribers will have error.
+            if let Err(why) = Publish::send(
+                topic_id,
+                effective_qos(self.will_qos, subscriber.qos),
+                RETAIN_FALSE,
+                self.will_message.clone(),
+                client,
+                subscriber.socket_addr,
+            ) {
+                error!("{}", why);
+            }
+        }
+    }
     #[allow(unused_must_use)]
     pub fn debug() {
-        let conn_hashmap = CONN_HASHMAP.lock().unwrap();
+        let conn_hashmap = state().conn_hashmap.lock().unwrap();
         dbg!(conn_hashmap);
     }
 }
@@ -424,7 +847,12 @@ mod test {
             8080,
         );
         let id = super::generate_conn_id(socket, 0);
-        assert!(!id.is_ok());
+        assert!(id.is_ok());
+        dbg!(id);
+
+        let socket = "[fe80::1]:1200".parse::<SocketAddr>().unwrap();
+        let id = super::generate_conn_id(socket, 0);
+        assert!(id.is_ok());
         dbg!(id);
 
         let socket = "127.0.0.1:1200".parse::<SocketAddr>().unwrap();
@@ -442,4 +870,99 @@ mod test {
         assert!(id.is_ok());
         dbg!(id);
     }
+
+    #[test]
+    fn test_state_transitions() {
+        use super::*;
+        #[derive(Default)]
+        struct Recorder {
+            changes: Mutex<Vec<(StateEnum2, StateEnum2)>>,
+        }
+        impl BrokerEvents for Recorder {
+            fn on_state_change(
+                &self,
+                _remote_addr: SocketAddr,
+                from: StateEnum2,
+                to: StateEnum2,
+            ) {
+                self.changes.lock().unwrap().push((from, to));
+            }
+        }
+        assert_eq!(StateEnum2::ACTIVE.next(ConnEvent::Connect), None);
+        assert_eq!(StateEnum2::LOST.next(ConnEvent::Sleep), None);
+        let events = Recorder::default();
+        let addr = "127.0.0.1:1210".parse::<SocketAddr>().unwrap();
+        let client_id = Bytes::from("state_machine");
+        assert_eq!(
+            Connection::try_insert(addr, 0, 1, 60, client_id.clone()),
+            Ok(StateEnum2::DISCONNECTED)
+        );
+        for event in [ConnEvent::Sleep, ConnEvent::Wake].iter() {
+            Connection::transition(&addr, *event, &events).unwrap();
+        }
+        // Illegal, the state is unchanged.
+        assert!(
+            Connection::transition(&addr, ConnEvent::Wake, &events).is_err()
+        );
+        assert_eq!(Connection::get_state(&addr), Ok(StateEnum2::AWAKE));
+        for event in [ConnEvent::WakeDone, ConnEvent::Expire].iter() {
+            Connection::transition(&addr, *event, &events).unwrap();
+        }
+        // The lost client reconnects.
+        assert_eq!(
+            Connection::try_insert(addr, 0, 1, 60, client_id),
+            Ok(StateEnum2::LOST)
+        );
+        assert_eq!(Connection::get_state(&addr), Ok(StateEnum2::ACTIVE));
+        assert_eq!(
+            *events.changes.lock().unwrap(),
+            vec![
+                (StateEnum2::ACTIVE, StateEnum2::ASLEEP),
+                (StateEnum2::ASLEEP, StateEnum2::AWAKE),
+                (StateEnum2::AWAKE, StateEnum2::ASLEEP),
+                (StateEnum2::ASLEEP, StateEnum2::LOST),
+            ]
+        );
+        let _result = Connection::remove(&addr);
+        ClientId::rev_delete(&addr);
+    }
+
+    #[test]
+    fn test_will_qos_retain() {
+        use super::*;
+        use crate::client_mode::Will;
+        use crate::test_support::{LoopbackBroker, TestClient};
+        let topic = "connection/will";
+        let mut subscriber = TestClient::new(LoopbackBroker::addr()).unwrap();
+        subscriber.connect("willSub", 60, None).unwrap();
+        let (topic_id, _return_code) =
+            subscriber.subscribe(topic, QOS_LEVEL_2).unwrap();
+        let will = Will {
+            topic: topic.to_string(),
+            msg: "offline".to_string(),
+            qos: QOS_LEVEL_1,
+            retain: RETAIN_TRUE,
+        };
+        let mut willer = TestClient::new(LoopbackBroker::addr()).unwrap();
+        willer.connect("willClient", 60, Some(&will)).unwrap();
+        let willer_addr = willer.local_addr();
+        let stored = state()
+            .conn_hashmap
+            .lock()
+            .unwrap()
+            .get(&willer_addr)
+            .map(|conn| (conn.will_qos, conn.will_retain));
+        assert_eq!(stored, Some((QOS_LEVEL_1, RETAIN_TRUE)));
+        willer.disconnect(None).unwrap();
+        // At the will QoS, below the granted QoS.
+        let publish = subscriber.recv_publish().unwrap();
+        assert_eq!(publish.topic_id, topic_id);
+        assert_eq!(publish.qos, QOS_LEVEL_1);
+        assert_eq!(&publish.payload[..], b"offline");
+        let retained = Retain::get(topic_id).unwrap();
+        assert_eq!(retained.qos, QOS_LEVEL_1);
+        assert_eq!(&retained.payload[..], b"offline");
+        subscriber.disconnect(None).unwrap();
+        Retain::remove(topic_id);
+    }
 }
@@ -0,0 +1,141 @@
+/// Per-client statistics of a live broker.
+///
+/// MqttSnClient::connection_info() and MqttSnClient::list_connections()
+/// return a ConnectionInfo per connection for an operator console or a
+/// debug CLI: the state, the client id, the keep-alive, the subscriptions,
+/// the unacknowledged QoS 1 and 2 messages and the messages queued for a
+/// sleeping client. It's a snapshot, the tables are read one after the
+/// other, not under a common lock.
+use bytes::Bytes;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::{
+    asleep_msg_cache::AsleepMsgCache,
+    config::PreDefinedTopics,
+    connection::{Connection, StateEnum2},
+    filter::{get_subscriptions, get_topic_name_with_topic_id},
+    flags::QoSConst,
+    in_flight::InFlight,
+    keep_alive::KeepAliveTimeWheel,
+    TopicIdType,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubscriptionInfo {
+    pub topic_id: TopicIdType,
+    /// The topic name or filter, None if it was deleted.
+    pub topic_name: Option<String>,
+    pub qos: QoSConst,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionInfo {
+    pub socket_addr: SocketAddr,
+    pub state: StateEnum2,
+    pub client_id: Bytes,
+    /// Keep-alive duration of the CONNECT in seconds, 0 if disabled.
+    pub keep_alive: u16,
+    /// Time left before the keep-alive expires, or before the end of the
+    /// PINGREQ probe, None if disabled.
+    pub keep_alive_remaining: Option<Duration>,
+    pub subscriptions: Vec<SubscriptionInfo>,
+    /// QoS 1 and 2 messages waiting for their ACK.
+    pub in_flight: usize,
+    /// Messages waiting for room in the in-flight window.
+    pub in_flight_queued: usize,
+    /// Messages queued while the client is asleep.
+    pub asleep_queued: usize,
+}
+
+impl ConnectionInfo {
+    /// The statistics of the connection of socket_addr.
+    pub fn get(socket_addr: SocketAddr) -> Option<ConnectionInfo> {
+        let mut infos = ConnectionInfo::collect(vec![socket_addr]);
+        infos.pop()
+    }
+
+    /// The statistics of all the connections, sorted by address.
+    pub fn list() -> Vec<ConnectionInfo> {
+        let mut socket_addrs = Connection::socket_addrs();
+        socket_addrs.sort();
+        ConnectionInfo::collect(socket_addrs)
+    }
+
+    fn collect(socket_addrs: Vec<SocketAddr>) -> Vec<ConnectionInfo> {
+        // One pass over the subscriptions for all the connections.
+        let subscriptions = get_subscriptions();
+        socket_addrs
+            .into_iter()
+            .filter_map(|socket_addr| {
+                let conn = Connection::get(&socket_addr)?;
+                let state = Connection::get_state(&socket_addr).ok()?;
+                let subscriptions = subscriptions
+                    .iter()
+                    .filter(|(addr, _, _)| *addr == socket_addr)
+                    .map(|(_, topic_id, qos)| SubscriptionInfo {
+                        topic_id: *topic_id,
+                        topic_name: get_topic_name_with_topic_id(*topic_id)
+                            .or_else(|| PreDefinedTopics::name(*topic_id)),
+                        qos: *qos,
+                    })
+                    .collect();
+                Some(ConnectionInfo {
+                    socket_addr,
+                    state,
+                    client_id: conn.client_id,
+                    keep_alive: conn.duration,
+                    keep_alive_remaining: KeepAliveTimeWheel::remaining(
+                        &socket_addr,
+                    ),
+                    subscriptions,
+                    in_flight: InFlight::in_flight(&socket_addr),
+                    in_flight_queued: InFlight::queued(&socket_addr),
+                    asleep_queued: AsleepMsgCache::len(socket_addr),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_connection_info() {
+        use super::*;
+        use crate::broker_lib::MqttSnClient;
+        use crate::flags::QOS_LEVEL_1;
+        use crate::test_support::{LoopbackBroker, TestClient};
+        let broker = LoopbackBroker::addr();
+        let mut subscriber = TestClient::new(broker).unwrap();
+        subscriber.connect("infoSub", 30, None).unwrap();
+        let (topic_id, _return_code) = subscriber
+            .subscribe("connection_info/temp", QOS_LEVEL_1)
+            .unwrap();
+        let client = MqttSnClient::new();
+        let socket_addr = subscriber.local_addr();
+        let info = client.connection_info(socket_addr).unwrap();
+        assert_eq!(info.state, StateEnum2::ACTIVE);
+        assert_eq!(&info.client_id[..], b"infoSub");
+        assert_eq!(info.keep_alive, 30);
+        let remaining = info.keep_alive_remaining.unwrap();
+        assert!(remaining <= Duration::from_secs(30));
+        assert!(remaining > Duration::from_secs(20));
+        assert_eq!(
+            info.subscriptions,
+            vec![SubscriptionInfo {
+                topic_id,
+                topic_name: Some("connection_info/temp".to_string()),
+                qos: QOS_LEVEL_1,
+            }]
+        );
+        assert_eq!((info.in_flight, info.asleep_queued), (0, 0));
+        assert!(client
+            .list_connections()
+            .iter()
+            .any(|info| info.socket_addr == socket_addr));
+        // The connection is removed by the DISCONNECT.
+        subscriber.disconnect(None).unwrap();
+        assert!(client.connection_info(socket_addr).is_none());
+    }
+}
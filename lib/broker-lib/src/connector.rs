@@ -0,0 +1,501 @@
+/// Connectors mirror the PUBLISH messages of selected topics into a stream
+/// platform, e.g. Kafka topics or NATS subjects, without a bridge process.
+///
+/// Connectors::start() takes a ConnectorSink and the routes, a topic filter
+/// and the target of its messages, e.g. the Kafka topic or the prefix of
+/// the NATS subjects. A message matching a route is queued in the bounded
+/// channel of the connector, RichPublish::forward() calls
+/// Connectors::forward(), except the local topics, see LocalTopics. The
+/// broker never waits for a connector: a full queue drops the message and
+/// counts it, Connectors::dropped().
+///
+/// The thread of the connector sends the messages in batches of up to
+/// batch_size, or the messages queued within linger. A failed batch is sent
+/// again after a backoff doubled from retry_backoff, max_retries times,
+/// then dropped. The queue fills while a batch is retried.
+///
+/// KafkaSink (connector-kafka feature) produces to the target topic with
+/// the MQTT-SN topic name as the key and the TopicMetadata as headers. With
+/// ConnectorConfig::timestamps, the records have the time the broker
+/// received the PUBLISH, KafkaSink adds it as the received-at header in ms
+/// since the Unix epoch. The annotations of the message, see Annotations,
+/// are in the records too, KafkaSink adds them as headers.
+/// NatsSink (connector-nats feature) publishes to the target prefix and the
+/// topic name with the '/' replaced by '.', e.g. "sensors.room1.temp".
+use bytes::Bytes;
+use crossbeam::channel::{
+    bounded, Receiver, RecvTimeoutError, Sender, TrySendError,
+};
+use log::*;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{
+    annotation::Annotations,
+    eformat,
+    filter::{match_topic, valid_filter},
+    function,
+    local_topics::LocalTopics,
+    topic_metadata::TopicMetadata,
+};
+
+pub const CONNECTOR_BATCH_SIZE: usize = 100;
+pub const CONNECTOR_LINGER: Duration = Duration::from_millis(50);
+pub const CONNECTOR_QUEUE_LEN: usize = 10_000;
+pub const CONNECTOR_MAX_RETRIES: u32 = 5;
+pub const CONNECTOR_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// A message for the sink.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectorRecord {
+    /// Target of the route, see ConnectorConfig::routes.
+    pub target: String,
+    pub topic_name: String,
+    pub data: Bytes,
+    pub publisher: SocketAddr,
+    pub metadata: Option<TopicMetadata>,
+    pub annotations: Annotations,
+    /// With ConnectorConfig::timestamps.
+    pub received_at: Option<SystemTime>,
+}
+
+impl ConnectorRecord {
+    /// received_at in ms since the Unix epoch.
+    pub fn received_at_ms(&self) -> Option<u64> {
+        let received_at = self.received_at?;
+        let duration = received_at.duration_since(UNIX_EPOCH).ok()?;
+        Some(duration.as_millis() as u64)
+    }
+}
+
+/// A stream platform, the connector thread owns it.
+pub trait ConnectorSink: Send {
+    fn name(&self) -> &str;
+    /// Send the batch, in order. The whole batch is sent again after an
+    /// error.
+    fn send_batch(&mut self, records: &[ConnectorRecord])
+        -> Result<(), String>;
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectorConfig {
+    /// (topic filter, target), the first matching route is used.
+    pub routes: Vec<(String, String)>,
+    pub batch_size: usize,
+    pub linger: Duration,
+    pub queue_len: usize,
+    pub max_retries: u32,
+    pub retry_backoff: Duration,
+    /// Add the receive time of the messages to the records.
+    pub timestamps: bool,
+}
+
+impl Default for ConnectorConfig {
+    fn default() -> Self {
+        ConnectorConfig {
+            routes: Vec::new(),
+            batch_size: CONNECTOR_BATCH_SIZE,
+            linger: CONNECTOR_LINGER,
+            queue_len: CONNECTOR_QUEUE_LEN,
+            max_retries: CONNECTOR_MAX_RETRIES,
+            retry_backoff: CONNECTOR_RETRY_BACKOFF,
+            timestamps: false,
+        }
+    }
+}
+
+struct ConnectorHandle {
+    routes: Vec<(String, String)>,
+    timestamps: bool,
+    tx: Sender<ConnectorRecord>,
+}
+
+lazy_static! {
+    static ref ENABLED: AtomicBool = AtomicBool::new(false);
+    static ref CONNECTORS: Mutex<Vec<ConnectorHandle>> = Mutex::new(Vec::new());
+    static ref DROPPED: AtomicU64 = AtomicU64::new(0);
+}
+
+#[derive(Debug, Clone)]
+pub struct Connectors {}
+
+impl Connectors {
+    /// Start the thread of the sink and forward the messages of the routes
+    /// to it.
+    pub fn start(
+        config: ConnectorConfig,
+        sink: Box<dyn ConnectorSink>,
+    ) -> Result<(), String> {
+        if config.routes.is_empty() || config.batch_size == 0 {
+            return Err(eformat!(sink.name(), "no routes or batch size 0"));
+        }
+        if let Some((filter, _)) = config
+            .routes
+            .iter()
+            .find(|(filter, _)| !valid_filter(filter))
+        {
+            return Err(eformat!(sink.name(), "invalid filter", filter));
+        }
+        let (tx, rx) = bounded(config.queue_len);
+        let name = format!("connector_{}_thread", sink.name());
+        let routes = config.routes.clone();
+        let timestamps = config.timestamps;
+        let builder = thread::Builder::new().name(name);
+        if let Err(why) =
+            builder.spawn(move || Connectors::run(config, sink, rx))
+        {
+            return Err(eformat!(why));
+        }
+        CONNECTORS.lock().unwrap().push(ConnectorHandle {
+            routes,
+            timestamps,
+            tx,
+        });
+        ENABLED.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Stop the connectors, their threads send the queued messages first.
+    pub fn stop_all() {
+        ENABLED.store(false, Ordering::Relaxed);
+        CONNECTORS.lock().unwrap().clear();
+    }
+
+    /// A connector is started, messages go through RichPublish::forward().
+    #[inline(always)]
+    pub fn is_enabled() -> bool {
+        ENABLED.load(Ordering::Relaxed)
+    }
+
+    /// Messages dropped by a full queue or after the retries.
+    pub fn dropped() -> u64 {
+        DROPPED.load(Ordering::Relaxed)
+    }
+
+    /// Queue the message for the connectors with a matching route.
+    #[inline(always)]
+    pub fn forward(
+        topic_name: Option<&str>,
+        data: &Bytes,
+        publisher: SocketAddr,
+        received_at: SystemTime,
+        annotations: &Annotations,
+    ) {
+        if !Connectors::is_enabled() {
+            return;
+        }
+        // The pre-defined topic ids without a name aren't mirrored.
+        let topic_name = match topic_name {
+            Some(topic_name) => topic_name,
+            None => return,
+        };
+        if LocalTopics::is_local_topic(topic_name) {
+            return;
+        }
+        let connectors = CONNECTORS.lock().unwrap();
+        for connector in connectors.iter() {
+            let target = match connector
+                .routes
+                .iter()
+                .find(|(filter, _)| match_topic(topic_name, filter))
+            {
+                Some((_, target)) => target,
+                None => continue,
+            };
+            let record = ConnectorRecord {
+                target: target.clone(),
+                topic_name: topic_name.to_string(),
+                data: data.clone(),
+                publisher,
+                metadata: TopicMetadata::get(topic_name),
+                annotations: annotations.clone(),
+                received_at: if connector.timestamps {
+                    Some(received_at)
+                } else {
+                    None
+                },
+            };
+            match connector.tx.try_send(record) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    DROPPED.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    error!("{}", eformat!(topic_name, "connector stopped"));
+                }
+            }
+        }
+    }
+
+    fn run(
+        config: ConnectorConfig,
+        mut sink: Box<dyn ConnectorSink>,
+        rx: Receiver<ConnectorRecord>,
+    ) {
+        let mut batch = Vec::with_capacity(config.batch_size);
+        // Until all the senders are dropped and the queue is empty.
+        while let Ok(record) = rx.recv() {
+            batch.push(record);
+            let deadline = Instant::now() + config.linger;
+            while batch.len() < config.batch_size {
+                let timeout =
+                    deadline.saturating_duration_since(Instant::now());
+                match rx.recv_timeout(timeout) {
+                    Ok(record) => batch.push(record),
+                    Err(RecvTimeoutError::Timeout)
+                    | Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            Connectors::send_with_retry(&config, sink.as_mut(), &batch);
+            batch.clear();
+        }
+        info!("{}", eformat!(sink.name(), "connector stopped"));
+    }
+
+    fn send_with_retry(
+        config: &ConnectorConfig,
+        sink: &mut dyn ConnectorSink,
+        batch: &[ConnectorRecord],
+    ) {
+        let mut backoff = config.retry_backoff;
+        for attempt in 0..=config.max_retries {
+            match sink.send_batch(batch) {
+                Ok(()) => return,
+                Err(why) => {
+                    warn!("{}", eformat!(sink.name(), attempt, why));
+                }
+            }
+            if attempt < config.max_retries {
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+        }
+        DROPPED.fetch_add(batch.len() as u64, Ordering::Relaxed);
+        error!("{}", eformat!(sink.name(), "batch dropped", batch.len()));
+    }
+}
+
+#[cfg(feature = "connector-kafka")]
+pub struct KafkaSink {
+    producer: rdkafka::producer::BaseProducer,
+    timeout: Duration,
+}
+
+#[cfg(feature = "connector-kafka")]
+impl KafkaSink {
+    /// bootstrap_servers is a comma-separated host:port list.
+    pub fn new(
+        bootstrap_servers: &str,
+        timeout: Duration,
+    ) -> Result<Self, String> {
+        use rdkafka::config::ClientConfig;
+        use rdkafka::producer::BaseProducer;
+        let producer: BaseProducer = ClientConfig::new()
+            .set("bootstrap.servers", bootstrap_servers)
+            .create()
+            .map_err(|why| eformat!(bootstrap_servers, why))?;
+        Ok(KafkaSink { producer, timeout })
+    }
+}
+
+#[cfg(feature = "connector-kafka")]
+impl ConnectorSink for KafkaSink {
+    fn name(&self) -> &str {
+        "kafka"
+    }
+    fn send_batch(
+        &mut self,
+        records: &[ConnectorRecord],
+    ) -> Result<(), String> {
+        use rdkafka::message::OwnedHeaders;
+        use rdkafka::producer::{BaseRecord, Producer};
+        for record in records {
+            let mut headers = OwnedHeaders::new();
+            if let Some(metadata) = &record.metadata {
+                if let Some(content_type) = &metadata.content_type {
+                    headers =
+                        headers.add("content-type", content_type.as_str());
+                }
+                if let Some(encoding) = &metadata.encoding {
+                    headers =
+                        headers.add("content-encoding", encoding.as_str());
+                }
+            }
+            for (key, val) in record.annotations.iter() {
+                headers = headers.add(key.as_str(), val.as_str());
+            }
+            if let Some(received_at) = record.received_at_ms() {
+                headers = headers
+                    .add("received-at", received_at.to_string().as_str());
+            }
+            let base_record = BaseRecord::to(&record.target)
+                .key(record.topic_name.as_str())
+                .payload(&record.data[..])
+                .headers(headers);
+            if let Err((why, _record)) = self.producer.send(base_record) {
+                return Err(eformat!(record.target, why));
+            }
+        }
+        self.producer.flush(self.timeout);
+        match self.producer.in_flight_count() {
+            0 => Ok(()),
+            count => Err(eformat!("not delivered", count)),
+        }
+    }
+}
+
+#[cfg(feature = "connector-nats")]
+pub struct NatsSink {
+    connection: nats::Connection,
+}
+
+#[cfg(feature = "connector-nats")]
+impl NatsSink {
+    pub fn new(url: &str) -> Result<Self, String> {
+        let connection =
+            nats::connect(url).map_err(|why| eformat!(url, why))?;
+        Ok(NatsSink { connection })
+    }
+    /// The subject of the topic name under the prefix.
+    pub fn subject(prefix: &str, topic_name: &str) -> String {
+        let topic = topic_name.trim_matches('/').replace('/', ".");
+        if prefix.is_empty() {
+            topic
+        } else {
+            format!("{}.{}", prefix, topic)
+        }
+    }
+}
+
+#[cfg(feature = "connector-nats")]
+impl ConnectorSink for NatsSink {
+    fn name(&self) -> &str {
+        "nats"
+    }
+    fn send_batch(
+        &mut self,
+        records: &[ConnectorRecord],
+    ) -> Result<(), String> {
+        for record in records {
+            let subject = NatsSink::subject(&record.target, &record.topic_name);
+            self.connection
+                .publish(&subject, &record.data[..])
+                .map_err(|why| eformat!(subject, why))?;
+        }
+        self.connection.flush().map_err(|why| eformat!(why))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Fails the first batches, then hands the batches to the test.
+    struct TestSink {
+        failures: u32,
+        batch_tx: Sender<Vec<ConnectorRecord>>,
+    }
+
+    impl ConnectorSink for TestSink {
+        fn name(&self) -> &str {
+            "test"
+        }
+        fn send_batch(
+            &mut self,
+            records: &[ConnectorRecord],
+        ) -> Result<(), String> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(eformat!("unavailable"));
+            }
+            self.batch_tx.send(records.to_vec()).unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_connector() {
+        use crossbeam::channel::unbounded;
+        let (batch_tx, batch_rx) = unbounded();
+        let config = ConnectorConfig {
+            routes: vec![("connector/+/temp".to_string(), "temps".to_string())],
+            batch_size: 3,
+            linger: Duration::from_millis(200),
+            retry_backoff: Duration::from_millis(1),
+            timestamps: true,
+            ..Default::default()
+        };
+        let sink = TestSink {
+            failures: 2,
+            batch_tx,
+        };
+        let invalid = ConnectorConfig {
+            routes: vec![("connector/#/temp".to_string(), "x".to_string())],
+            ..Default::default()
+        };
+        let (unused_tx, _unused_rx) = unbounded();
+        assert!(Connectors::start(
+            invalid,
+            Box::new(TestSink {
+                failures: 0,
+                batch_tx: unused_tx,
+            })
+        )
+        .is_err());
+        assert!(!Connectors::is_enabled());
+        Connectors::start(config, Box::new(sink)).unwrap();
+        assert!(Connectors::is_enabled());
+        let publisher = "127.0.0.1:1600".parse::<SocketAddr>().unwrap();
+        let now = SystemTime::now();
+        let mut annotations = Annotations::new();
+        annotations.insert("schema", "v2");
+        // Not in the second batch.
+        LocalTopics::add("connector/e/#").unwrap();
+        for (index, topic) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+            let topic_name = format!("connector/{}/temp", topic);
+            let data = Bytes::from(index.to_string());
+            Connectors::forward(
+                Some(&topic_name),
+                &data,
+                publisher,
+                now,
+                &annotations,
+            );
+        }
+        Connectors::forward(
+            Some("connector/a/humidity"),
+            &"x".into(),
+            publisher,
+            now,
+            &annotations,
+        );
+        Connectors::forward(None, &"x".into(), publisher, now, &annotations);
+        // A full batch after 2 retries, then the rest after linger.
+        let timeout = Duration::from_secs(5);
+        let batch = batch_rx.recv_timeout(timeout).unwrap();
+        let topics: Vec<&str> = batch
+            .iter()
+            .map(|record| record.topic_name.as_str())
+            .collect();
+        assert_eq!(
+            topics,
+            vec!["connector/a/temp", "connector/b/temp", "connector/c/temp"]
+        );
+        assert!(batch.iter().all(|record| record.target == "temps"));
+        let batch = batch_rx.recv_timeout(timeout).unwrap();
+        assert_eq!(batch.len(), 1);
+        assert_eq!(&batch[0].data[..], b"3");
+        assert_eq!(batch[0].publisher, publisher);
+        assert_eq!(batch[0].received_at, Some(now));
+        assert_eq!(batch[0].annotations.get("schema").unwrap(), "v2");
+        assert!(batch[0].received_at_ms().unwrap() > 0);
+        Connectors::stop_all();
+        assert!(!Connectors::is_enabled());
+        LocalTopics::remove("connector/e/#");
+        #[cfg(feature = "connector-nats")]
+        assert_eq!(NatsSink::subject("mqttsn", "a/b/c"), "mqttsn.a.b.c");
+    }
+}
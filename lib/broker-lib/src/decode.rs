@@ -0,0 +1,119 @@
+/// Bounds-checked decoding of the received messages.
+///
+/// The datagrams are attacker-controlled, the recv functions return a
+/// protocol error instead of panicking the rx thread. The derived try_read()
+/// returns None for a short message, Decode::read() turns it into an error.
+/// A topic name is checked with Decode::utf8() before it's read into a
+/// String. The topic name field of a pre-defined or short topic id is 2
+/// bytes, not necessarily UTF-8, Decode::topic_id() reads them.
+use crate::{
+    codec::get_u16_be, eformat, function, msg_hdr::MsgHeader, TopicIdType,
+};
+
+#[derive(Debug, Clone)]
+pub struct Decode {}
+
+impl Decode {
+    /// The message and the length read by try_read().
+    #[inline(always)]
+    pub fn read<T>(
+        read: Option<(T, usize)>,
+        size: usize,
+        msg_header: &MsgHeader,
+    ) -> Result<(T, usize), String> {
+        read.ok_or_else(|| {
+            eformat!(
+                msg_header.remote_socket_addr,
+                msg_header.msg_type,
+                "len err",
+                size
+            )
+        })
+    }
+
+    /// buf has size bytes, size is at least min_len.
+    #[inline(always)]
+    pub fn check_len(
+        buf: &[u8],
+        size: usize,
+        min_len: usize,
+        msg_header: &MsgHeader,
+    ) -> Result<(), String> {
+        if size < min_len || buf.len() < size {
+            return Err(eformat!(
+                msg_header.remote_socket_addr,
+                msg_header.msg_type,
+                "len err",
+                size
+            ));
+        }
+        Ok(())
+    }
+
+    /// The bytes from offset to size, e.g. a topic name.
+    pub fn utf8<'a>(
+        buf: &'a [u8],
+        offset: usize,
+        size: usize,
+        msg_header: &MsgHeader,
+    ) -> Result<&'a str, String> {
+        Decode::check_len(buf, size, offset, msg_header)?;
+        std::str::from_utf8(&buf[offset..size]).map_err(|why| {
+            eformat!(msg_header.remote_socket_addr, msg_header.msg_type, why)
+        })
+    }
+
+    /// Topic id in the topic name field from offset to size.
+    pub fn topic_id(
+        buf: &[u8],
+        offset: usize,
+        size: usize,
+        msg_header: &MsgHeader,
+    ) -> Result<TopicIdType, String> {
+        Decode::check_len(buf, size, offset, msg_header)?;
+        match buf[offset..size] {
+            [_, _] => Ok(get_u16_be(buf, offset)),
+            _ => Err(eformat!(
+                msg_header.remote_socket_addr,
+                "topic id len",
+                size - offset
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_decode() {
+        use super::*;
+        use crate::conn::udp_conn;
+        use crate::{
+            broker_lib::MqttSnClient, subscribe::Subscribe, MSG_TYPE_SUBSCRIBE,
+        };
+        use std::net::SocketAddr;
+        let addr = "127.0.0.1:1890".parse::<SocketAddr>().unwrap();
+        let buf = [9, 0x12, 0b0000_0001, 0, 1, 0xFF, 0x00];
+        let msg_header = MsgHeader::new(addr, udp_conn(), MSG_TYPE_SUBSCRIBE);
+        assert!(Decode::check_len(&buf, 7, 5, &msg_header).is_ok());
+        assert!(Decode::check_len(&buf, 9, 5, &msg_header).is_err());
+        assert!(Decode::check_len(&buf, 4, 5, &msg_header).is_err());
+        // Not UTF-8.
+        assert!(Decode::utf8(&buf, 5, 7, &msg_header).is_err());
+        assert_eq!(Decode::utf8(b"xxtemp", 2, 6, &msg_header), Ok("temp"));
+        assert_eq!(Decode::topic_id(&buf, 5, 7, &msg_header), Ok(0xFF00));
+        assert!(Decode::topic_id(&buf, 4, 7, &msg_header).is_err());
+        assert!(Decode::topic_id(&buf, 8, 7, &msg_header).is_err());
+        let read: Option<(u8, usize)> = None;
+        assert!(Decode::read(read, 7, &msg_header).is_err());
+        // Malformed SUBSCRIBEs are errors, not panics.
+        let client = MqttSnClient::new();
+        let truncated = [4, MSG_TYPE_SUBSCRIBE, 0, 0];
+        let not_utf8 = [7, MSG_TYPE_SUBSCRIBE, 0, 0, 1, 0xC3, 0x28];
+        for buf in [&truncated[..], &not_utf8[..]].iter() {
+            let result =
+                Subscribe::recv(buf, buf.len(), &client, msg_header.clone());
+            assert!(result.is_err());
+        }
+    }
+}
@@ -0,0 +1,154 @@
+/// Demo mode, a simulated sensor and a console subscriber in the broker.
+///
+/// The sensor publishes QoS 0 readings to DEMO_TOPICS every
+/// DEMO_INTERVAL_MS, the messages go through Publish::recv() like messages
+/// from the network. The console subscriber is a LocalConsumer and prints
+/// the messages, network clients can subscribe to the same topics.
+use log::*;
+use rand::Rng;
+use std::net::SocketAddr;
+use std::str;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::{
+    annotation::Annotations,
+    broker_lib::MqttSnClient,
+    conn::{udp_conn, Conn},
+    filter::{get_topic_name_with_topic_id, try_insert_topic_name},
+    flags::{QOS_LEVEL_0, RETAIN_FALSE},
+    local_consumer::{ConsumerId, Delivery, LocalConsumer, NackPolicy},
+    msg_hdr::MsgHeader,
+    publish::Publish,
+    TopicIdType,
+};
+
+pub const DEMO_TOPICS: [&str; 3] = [
+    "demo/sensor/temperature",
+    "demo/sensor/humidity",
+    "demo/sensor/battery",
+];
+pub const DEMO_INTERVAL_MS: u64 = 1000;
+/// Source address of the simulated sensor.
+pub const DEMO_SENSOR_ADDR: &str = "127.0.0.1:61999";
+
+#[derive(Debug, Clone)]
+pub struct Broker {}
+
+impl Broker {
+    /// Start the simulated sensor and the console subscriber.
+    /// The broker must be started, e.g. with broker_rx_loop().
+    pub fn demo(client: &MqttSnClient) -> Result<(), String> {
+        let _context = client.context.enter();
+        let mut topic_ids = Vec::with_capacity(DEMO_TOPICS.len());
+        for topic in DEMO_TOPICS.iter() {
+            let topic_id = try_insert_topic_name(topic.to_string())?;
+            LocalConsumer::register(
+                topic_id,
+                Broker::print_publish,
+                NackPolicy::default(),
+            );
+            topic_ids.push(topic_id);
+        }
+        let sensor_addr = DEMO_SENSOR_ADDR.parse::<SocketAddr>().unwrap();
+        let client = client.clone();
+        let builder = thread::Builder::new().name("demo_sensor_thread".into());
+        let _demo_sensor_thread = builder.spawn(move || {
+            let _context = client.context.enter();
+            // Nothing is sent back to the sensor, the peer isn't read.
+            let conn = udp_conn();
+            let mut rng = rand::thread_rng();
+            let mut battery = 100.0;
+            let mut msg_id: u16 = 0;
+            loop {
+                battery = f64::max(battery - 0.1, 0.0);
+                let readings = [
+                    20.0 + rng.gen_range(-2.0..2.0),
+                    50.0 + rng.gen_range(-10.0..10.0),
+                    battery,
+                ];
+                for (topic_id, value) in topic_ids.iter().zip(readings.iter()) {
+                    msg_id = msg_id.wrapping_add(1);
+                    let data = format!("{:.1}", value);
+                    if let Err(why) = Broker::sensor_publish(
+                        *topic_id,
+                        msg_id,
+                        data.as_bytes(),
+                        &client,
+                        sensor_addr,
+                        &conn,
+                    ) {
+                        error!("{}", why);
+                    }
+                }
+                thread::sleep(Duration::from_millis(DEMO_INTERVAL_MS));
+            }
+        });
+        Ok(())
+    }
+
+    /// Receive a PUBLISH message from the simulated sensor.
+    fn sensor_publish(
+        topic_id: TopicIdType,
+        msg_id: u16,
+        data: &[u8],
+        client: &MqttSnClient,
+        sensor_addr: SocketAddr,
+        conn: &Arc<dyn Conn + Send + Sync>,
+    ) -> Result<(), String> {
+        let buf =
+            Publish::encode(topic_id, msg_id, QOS_LEVEL_0, RETAIN_FALSE, data)?;
+        let len = buf.len();
+        let msg_header =
+            MsgHeader::try_read(&buf, len, sensor_addr, conn.clone())?;
+        Publish::recv(&buf, len, client, msg_header)
+    }
+
+    fn print_publish(
+        _consumer_id: ConsumerId,
+        publish: &Publish,
+        _annotations: &Annotations,
+    ) -> Delivery {
+        let topic_id = *publish.topic_id();
+        let topic = get_topic_name_with_topic_id(topic_id)
+            .unwrap_or_else(|| topic_id.to_string());
+        match str::from_utf8(publish.data()) {
+            Ok(text) => println!("{}: {}", topic, text),
+            Err(_) => println!("{}: {:?}", topic, publish.data()),
+        }
+        Delivery::Ack
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_demo_sensor_publish() {
+        use super::*;
+        let client = MqttSnClient::new();
+        let conn = udp_conn();
+        let sensor_addr = DEMO_SENSOR_ADDR.parse::<SocketAddr>().unwrap();
+        let topic_id =
+            try_insert_topic_name("demo/test/temperature".to_string()).unwrap();
+        let result = Broker::sensor_publish(
+            topic_id,
+            1,
+            b"21.5",
+            &client,
+            sensor_addr,
+            &conn,
+        );
+        assert_eq!(result, Ok(()));
+        let data = [b'x'; 255];
+        assert!(Broker::sensor_publish(
+            topic_id,
+            2,
+            &data,
+            &client,
+            sensor_addr,
+            &conn
+        )
+        .is_err());
+    }
+}
@@ -22,19 +22,20 @@ use bytes::{BufMut, BytesMut};
 use custom_debug::Debug;
 use getset::{CopyGetters, Getters, MutGetters};
 use std::mem;
+use std::net::SocketAddr;
 
 use crate::{
+    asleep_msg_cache::AsleepMsgCache,
     broker_lib::MqttSnClient,
     client_id::ClientId,
+    client_mode::ClientMode,
     connection::Connection,
-    connection::StateEnum2,
+    connection::{ConnEvent, StateEnum2},
+    decode::Decode,
     eformat,
-    filter::get_subscribers_with_topic_id,
-    flags::RETAIN_FALSE,
     function,
     keep_alive::KeepAliveTimeWheel,
     msg_hdr::MsgHeader,
-    publish::Publish,
     MSG_LEN_DISCONNECT,
     MSG_LEN_DISCONNECT_DURATION,
     // flags::{flags_set, flag_qos_level, },
@@ -81,54 +82,38 @@ impl Disconnect {
         msg_header: MsgHeader,
     ) -> Result<(), String> {
         let remote_addr = msg_header.remote_socket_addr;
+        // Client mode, the gateway ends the session.
+        if ClientMode::remove(&remote_addr) {
+            return Ok(());
+        }
         if size == MSG_LEN_DISCONNECT as usize {
-            let (disconnect, _read_len) =
-                Disconnect::try_read(buf, size).unwrap();
+            let (disconnect, _read_len) = Decode::read(
+                Disconnect::try_read(buf, size),
+                size,
+                &msg_header,
+            )?;
             dbg!(disconnect.clone());
-            Connection::debug();
-            let publish_will;
-            match Connection::get_state(&remote_addr) {
-                Ok(state) => match state {
-                    StateEnum2::ACTIVE => publish_will = true,
-                    _ => publish_will = false,
-                },
-                Err(why) => return Err(eformat!(why, &remote_addr)),
-            }
-            let conn = Connection::remove(&remote_addr)?;
-            ClientId::rev_delete(&remote_addr);
-            KeepAliveTimeWheel::cancel(&remote_addr)?;
-            Connection::debug();
-            Disconnect::send(client, msg_header)?;
-            if publish_will == false {
-                return Ok(());
-            }
-            if let Some(topic_id) = conn.will_topic_id {
-                let subscriber_vec = get_subscribers_with_topic_id(topic_id);
-                for subscriber in subscriber_vec {
-                    // Can't return error, because not all subscribers will have error.
-                    // TODO error for every subscriber/message
-                    // TODO use Bytes not BytesMut to eliminate clone/copy.
-                    // TODO new tx method to reduce have try_write() run once for every subscriber.
-                    let mut msg = BytesMut::new();
-                    msg.put(conn.will_message.clone()); // TODO replace BytesMut with Bytes because clone doesn't copy data in Bytes
-                    let _result = Publish::send(
-                        topic_id,
-                        0, // TODO what is the msg_id?
-                        subscriber.qos,
-                        RETAIN_FALSE,
-                        msg,
-                        client,
-                        subscriber.socket_addr,
-                    );
-                }
-            }
-            Ok(())
+            Disconnect::end_session(client, msg_header)
         } else if size == MSG_LEN_DISCONNECT_DURATION as usize {
             // *NOTE* Section 6.14 of the MQTT-SN 1.2 spec.
-            let (disconnect, _read_len) =
-                DisconnWithDuration::try_read(buf, size).unwrap();
+            let (disconnect, _read_len) = Decode::read(
+                DisconnWithDuration::try_read(buf, size),
+                size,
+                &msg_header,
+            )?;
             dbg!(disconnect.clone());
-            Connection::update_state(&remote_addr, StateEnum2::ASLEEP)?;
+            // A sleep timer of 0 never expires, it ends the session like a
+            // DISCONNECT without duration.
+            if disconnect.duration == 0 {
+                return Disconnect::end_session(client, msg_header);
+            }
+            // ACTIVE and AWAKE go to sleep, ASLEEP restarts the sleep timer
+            // with the new duration.
+            Connection::transition(
+                &remote_addr,
+                ConnEvent::Sleep,
+                &*client.events,
+            )?;
             KeepAliveTimeWheel::schedule(remote_addr, disconnect.duration)?;
             Disconnect::send(client, msg_header)?;
             Ok(())
@@ -137,15 +122,55 @@ impl Disconnect {
         }
     }
 
+    /// DISCONNECT without duration: the connection, the sleep timer and the
+    /// messages buffered for a sleeping client are removed. The will is
+    /// only published for an ACTIVE client, a sleeping client doesn't have
+    /// a will to publish.
+    fn end_session(
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+    ) -> Result<(), String> {
+        let remote_addr = msg_header.remote_socket_addr;
+        Connection::debug();
+        let publish_will = match Connection::transition(
+            &remote_addr,
+            ConnEvent::Disconnect,
+            &*client.events,
+        ) {
+            Ok(from) => from == StateEnum2::ACTIVE,
+            Err(why) => return Err(eformat!(why, &remote_addr)),
+        };
+        Connection::save_session(&remote_addr);
+        let conn = Connection::remove(&remote_addr)?;
+        ClientId::rev_delete(&remote_addr);
+        KeepAliveTimeWheel::cancel(&remote_addr)?;
+        AsleepMsgCache::delete(remote_addr);
+        Connection::debug();
+        client.events.on_disconnect(remote_addr);
+        Disconnect::send(client, msg_header)?;
+        if publish_will == false {
+            return Ok(());
+        }
+        conn.send_will(client);
+        Ok(())
+    }
+
     pub fn send(
         client: &MqttSnClient,
         msg_header: MsgHeader,
+    ) -> Result<(), String> {
+        Disconnect::send_to(client, msg_header.remote_socket_addr)
+    }
+
+    /// DISCONNECT initiated by the broker, e.g. a session taken over.
+    pub fn send_to(
+        client: &MqttSnClient,
+        remote_addr: SocketAddr,
     ) -> Result<(), String> {
         let disconnect = Disconnect {
             len: MSG_LEN_DISCONNECT as u8,
             msg_type: MSG_TYPE_DISCONNECT,
         };
-        let remote_addr = msg_header.remote_socket_addr;
         let mut bytes_buf =
             BytesMut::with_capacity(MSG_LEN_DISCONNECT as usize);
         dbg!(disconnect.clone());
@@ -162,3 +187,75 @@ impl Disconnect {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_disconnect_asleep() {
+        use super::*;
+        use crate::flags::{CLEAN_SESSION_FALSE, QOS_LEVEL_0, RETAIN_FALSE};
+        use crate::test_support::{LoopbackBroker, TestClient};
+        use crate::MSG_TYPE_CONNACK;
+        use crate::MSG_TYPE_CONNECT;
+        use std::thread;
+        use std::time::Duration;
+        let wait_buffered = |addr: SocketAddr| {
+            for _ in 0..200 {
+                if AsleepMsgCache::len(addr) == 1 {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("not buffered");
+        };
+        let topic = "disconnect/asleep";
+        let broker = LoopbackBroker::addr();
+        let mut publisher = TestClient::new(broker).unwrap();
+        publisher.connect("asleepPub", 60, None).unwrap();
+        let topic_id = publisher.register(topic).unwrap();
+        let mut sleeper = TestClient::new(broker).unwrap();
+        sleeper.connect("asleepSub", 60, None).unwrap();
+        sleeper.subscribe(topic, QOS_LEVEL_0).unwrap();
+        let addr = sleeper.local_addr();
+        // DISCONNECT with duration while ASLEEP restarts the sleep timer.
+        sleeper.disconnect(Some(10)).unwrap();
+        assert_eq!(Connection::get_state(&addr), Ok(StateEnum2::ASLEEP));
+        sleeper.disconnect(Some(600)).unwrap();
+        assert_eq!(Connection::get_state(&addr), Ok(StateEnum2::ASLEEP));
+        let remaining = KeepAliveTimeWheel::remaining(&addr).unwrap();
+        assert!(remaining > Duration::from_secs(500));
+        // CONNECT while ASLEEP, the buffered message follows the CONNACK.
+        publisher
+            .publish(topic_id, QOS_LEVEL_0, RETAIN_FALSE, b"buffered")
+            .unwrap();
+        wait_buffered(addr);
+        let mut connect = BytesMut::new();
+        connect.put_u8(6 + "asleepSub".len() as u8);
+        connect.put_u8(MSG_TYPE_CONNECT);
+        connect.put_u8(CLEAN_SESSION_FALSE);
+        connect.put_u8(1);
+        connect.put_u16(60);
+        connect.put_slice(b"asleepSub");
+        sleeper.send(&connect).unwrap();
+        sleeper.expect(MSG_TYPE_CONNACK).unwrap();
+        let publish = sleeper.recv_publish().unwrap();
+        assert_eq!(&publish.payload[..], b"buffered");
+        assert_eq!(Connection::get_state(&addr), Ok(StateEnum2::ACTIVE));
+        // DISCONNECT while ASLEEP removes the session and the buffered
+        // messages.
+        sleeper.disconnect(Some(600)).unwrap();
+        publisher
+            .publish(topic_id, QOS_LEVEL_0, RETAIN_FALSE, b"dropped")
+            .unwrap();
+        wait_buffered(addr);
+        sleeper.disconnect(None).unwrap();
+        assert!(Connection::get(&addr).is_none());
+        assert_eq!(AsleepMsgCache::len(addr), 0);
+        assert!(KeepAliveTimeWheel::remaining(&addr).is_none());
+        // A sleep duration of 0 is a DISCONNECT.
+        sleeper.connect("asleepSub", 60, None).unwrap();
+        sleeper.disconnect(Some(0)).unwrap();
+        assert!(Connection::get(&addr).is_none());
+        publisher.disconnect(None).unwrap();
+    }
+}
@@ -0,0 +1,290 @@
+/// Client authentication with DTLS pre-shared keys or client certificates.
+///
+/// with_psk() and with_client_cert() update the DTLS server Config.
+/// AuthListener::accept() calls DtlsAuth::register() with
+/// DTLSConn::connection_state() after the handshake, before the accept
+/// loop registers the connection with the Hub. The DTLSListener of
+/// webrtc_dtls returns the conn without its state. The authenticated
+/// identity is stored with the peer address, copied into the Connection
+/// struct by CONNECT and removed when the Hub closes the conn.
+/// With DtlsAuth::set_required(true), CONNECT from a peer without an
+/// identity is rejected. The handshake config, with_psk(),
+/// with_client_cert(), register() and AuthListener, is behind the "dtls"
+/// feature, the identities of another transport are set with insert().
+///
+/// The PSK file of parse_psk() has one client per line, the identity and
+/// the key in hex:
+///
+/// # identity key
+/// sensor1 abc1ff00
+use bytes::Bytes;
+use hashbrown::HashMap;
+#[cfg(feature = "dtls")]
+use log::*;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "dtls")]
+use std::sync::Arc;
+use std::sync::Mutex;
+#[cfg(feature = "dtls")]
+use std::{future::Future, pin::Pin};
+#[cfg(feature = "dtls")]
+use util::conn::{conn_udp_listener::ListenConfig, Conn, Listener};
+#[cfg(feature = "dtls")]
+use webrtc_dtls::{
+    cipher_suite::CipherSuiteId,
+    config::{ClientAuthType, Config},
+    conn::DTLSConn,
+    state::State,
+    Error,
+};
+
+use crate::{eformat, function};
+
+/// Authenticated DTLS identity of a peer.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Identity {
+    /// PSK identity sent by the client.
+    Psk(Bytes),
+    /// DER encoded leaf certificate of the client.
+    Certificate(Bytes),
+}
+
+lazy_static! {
+    static ref REQUIRED: AtomicBool = AtomicBool::new(false);
+    static ref IDENTITIES: Mutex<HashMap<SocketAddr, Identity>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone)]
+pub struct DtlsAuth {}
+
+impl DtlsAuth {
+    /// Use the pre-shared keys, identity -> key, for the handshake.
+    #[cfg(feature = "dtls")]
+    pub fn with_psk(
+        mut config: Config,
+        keys: HashMap<Vec<u8>, Vec<u8>>,
+        identity_hint: &[u8],
+    ) -> Config {
+        config.psk = Some(Arc::new(move |identity: &[u8]| {
+            keys.get(identity).cloned().ok_or(Error::ErrIdentityNoPsk)
+        }));
+        config.psk_identity_hint = Some(identity_hint.to_vec());
+        config.cipher_suites = vec![
+            CipherSuiteId::Tls_Psk_With_Aes_128_Ccm_8,
+            CipherSuiteId::Tls_Psk_With_Aes_128_Gcm_Sha256,
+        ];
+        config
+    }
+    /// Require a client certificate signed by config.client_cas.
+    #[cfg(feature = "dtls")]
+    pub fn with_client_cert(mut config: Config) -> Config {
+        config.client_auth = ClientAuthType::RequireAndVerifyClientCert;
+        config
+    }
+
+    /// Reject CONNECT from peers without an authenticated identity.
+    pub fn set_required(required: bool) {
+        REQUIRED.store(required, Ordering::Relaxed);
+    }
+    pub fn is_required() -> bool {
+        REQUIRED.load(Ordering::Relaxed)
+    }
+
+    /// Identity -> key of a PSK file.
+    pub fn parse_psk(text: &str) -> Result<HashMap<Vec<u8>, Vec<u8>>, String> {
+        let mut keys = HashMap::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (identity, hex) = match (fields.next(), fields.next()) {
+                (Some(identity), Some(hex)) if fields.next().is_none() => {
+                    (identity, hex)
+                }
+                _ => return Err(eformat!(index + 1, "identity and key", line)),
+            };
+            if hex.is_empty() || hex.len() % 2 != 0 {
+                return Err(eformat!(index + 1, "invalid key", identity));
+            }
+            let key = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                .collect::<Result<Vec<u8>, _>>()
+                .map_err(|why| eformat!(index + 1, identity, why))?;
+            keys.insert(identity.as_bytes().to_vec(), key);
+        }
+        Ok(keys)
+    }
+
+    /// Save the identity from the state of a finished handshake, a session
+    /// without one drops the identity of the previous session.
+    #[cfg(feature = "dtls")]
+    pub fn register(
+        remote_addr: SocketAddr,
+        state: &State,
+    ) -> Option<Identity> {
+        let identity = if let Some(cert) = state.peer_certificates.first() {
+            Identity::Certificate(Bytes::from(cert.clone()))
+        } else if !state.identity_hint.is_empty() {
+            // On the server, identity_hint is the PSK identity of the client.
+            Identity::Psk(Bytes::from(state.identity_hint.clone()))
+        } else {
+            DtlsAuth::remove(&remote_addr);
+            return None;
+        };
+        DtlsAuth::insert(remote_addr, identity.clone());
+        Some(identity)
+    }
+    pub fn insert(remote_addr: SocketAddr, identity: Identity) {
+        IDENTITIES.lock().unwrap().insert(remote_addr, identity);
+    }
+    pub fn identity(remote_addr: &SocketAddr) -> Option<Identity> {
+        IDENTITIES.lock().unwrap().get(remote_addr).cloned()
+    }
+    /// Forget the identity when the DTLS connection is closed.
+    pub fn remove(remote_addr: &SocketAddr) -> Option<Identity> {
+        IDENTITIES.lock().unwrap().remove(remote_addr)
+    }
+
+    /// Ok if authentication is not required or the peer has an identity.
+    pub fn check(remote_addr: &SocketAddr) -> Result<Option<Identity>, String> {
+        let identity = DtlsAuth::identity(remote_addr);
+        if identity.is_none() && DtlsAuth::is_required() {
+            return Err(eformat!(remote_addr, "not authenticated"));
+        }
+        Ok(identity)
+    }
+}
+
+/// ContentType of a DTLS handshake record, the first octet.
+#[cfg(feature = "dtls")]
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+
+/// DTLS listener registering the identity of each peer.
+#[cfg(feature = "dtls")]
+pub struct AuthListener {
+    parent: Box<dyn Listener + Send + Sync>,
+    config: Config,
+}
+
+#[cfg(feature = "dtls")]
+impl AuthListener {
+    pub async fn bind(laddr: &str, config: Config) -> Result<Self, String> {
+        let mut listen_config = ListenConfig {
+            // Only a handshake opens a conn, like the DTLSListener.
+            accept_filter: Some(Box::new(
+                |packet: &[u8]| -> Pin<Box<dyn Future<Output = bool> + Send>> {
+                    let handshake =
+                        packet.first() == Some(&CONTENT_TYPE_HANDSHAKE);
+                    Box::pin(async move { handshake })
+                },
+            )),
+            ..Default::default()
+        };
+        let parent = listen_config
+            .listen(laddr)
+            .await
+            .map_err(|why| eformat!(laddr, why))?;
+        Ok(AuthListener {
+            parent: Box::new(parent),
+            config,
+        })
+    }
+
+    /// The next conn after its handshake and DtlsAuth::register(). A failed
+    /// handshake, e.g. an unknown PSK identity, is logged and skipped, Err
+    /// if the listener is closed.
+    pub async fn accept(
+        &self,
+    ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr), String> {
+        loop {
+            let (conn, remote_addr) =
+                self.parent.accept().await.map_err(|why| eformat!(why))?;
+            match DTLSConn::new(conn, self.config.clone(), false, None).await {
+                Ok(dtls_conn) => {
+                    let state = dtls_conn.connection_state().await;
+                    DtlsAuth::register(remote_addr, &state);
+                    return Ok((Arc::new(dtls_conn), remote_addr));
+                }
+                Err(why) => error!("{}", eformat!(remote_addr, why)),
+            }
+        }
+    }
+
+    pub async fn local_addr(&self) -> Result<SocketAddr, String> {
+        self.parent.addr().await.map_err(|why| eformat!(why))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_dtls_auth() {
+        use super::*;
+        let addr = "127.0.0.1:1300".parse::<SocketAddr>().unwrap();
+        let addr2 = "127.0.0.2:1300".parse::<SocketAddr>().unwrap();
+        DtlsAuth::insert(addr, Identity::Psk(Bytes::from("sensor1")));
+        assert_eq!(
+            DtlsAuth::check(&addr),
+            Ok(Some(Identity::Psk(Bytes::from("sensor1"))))
+        );
+        assert_eq!(DtlsAuth::check(&addr2), Ok(None));
+
+        #[cfg(feature = "dtls")]
+        {
+            let mut keys = HashMap::new();
+            keys.insert(b"sensor1".to_vec(), vec![0xAB, 0xC1]);
+            let config = DtlsAuth::with_psk(Config::default(), keys, b"gw");
+            let psk = config.psk.unwrap();
+            assert_eq!(psk(b"sensor1").unwrap(), vec![0xAB, 0xC1]);
+            assert!(psk(b"sensor2").is_err());
+        }
+        assert!(DtlsAuth::remove(&addr).is_some());
+        let keys =
+            DtlsAuth::parse_psk("# identity key\nsensor1 abc1\n\n").unwrap();
+        assert_eq!(keys.get(&b"sensor1"[..]), Some(&vec![0xAB, 0xC1]));
+        assert!(DtlsAuth::parse_psk("sensor1").is_err());
+        assert!(DtlsAuth::parse_psk("sensor1 abc").is_err());
+        assert!(DtlsAuth::parse_psk("sensor1 zz").is_err());
+    }
+
+    #[cfg(feature = "dtls")]
+    #[test]
+    fn test_dtls_auth_handshake() {
+        use super::*;
+        use tokio::net::UdpSocket;
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let keys = DtlsAuth::parse_psk("sensor9 abc1").unwrap();
+            let config = DtlsAuth::with_psk(Config::default(), keys, b"gw");
+            let listener =
+                AuthListener::bind("127.0.0.1:0", config).await.unwrap();
+            let server_addr = listener.local_addr().await.unwrap();
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            socket.connect(server_addr).await.unwrap();
+            let client_addr = socket.local_addr().unwrap();
+            let client_config = Config {
+                psk: Some(Arc::new(|_hint: &[u8]| Ok(vec![0xAB, 0xC1]))),
+                psk_identity_hint: Some(b"sensor9".to_vec()),
+                cipher_suites: vec![CipherSuiteId::Tls_Psk_With_Aes_128_Ccm_8],
+                ..Default::default()
+            };
+            let client = tokio::spawn(async move {
+                DTLSConn::new(Arc::new(socket), client_config, true, None).await
+            });
+            let (_conn, remote_addr) = listener.accept().await.unwrap();
+            assert_eq!(remote_addr, client_addr);
+            assert!(client.await.unwrap().is_ok());
+            // CONNECT of the peer gets the identity of the handshake.
+            assert_eq!(
+                DtlsAuth::check(&client_addr),
+                Ok(Some(Identity::Psk(Bytes::from("sensor9"))))
+            );
+            assert!(DtlsAuth::remove(&client_addr).is_some());
+        });
+    }
+}
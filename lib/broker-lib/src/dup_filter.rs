@@ -0,0 +1,101 @@
+/// Duplicate detection for retransmitted QoS 1 and 2 PUBLISH messages.
+///
+/// A publisher retransmits a PUBLISH with the DUP flag when the PUBACK or
+/// PUBREC is lost, the broker must acknowledge it again without sending it
+/// to the subscribers twice. The msg_ids of the last DupFilter::window()
+/// messages of each publisher are kept in LRU order, a PUBLISH with the DUP
+/// flag and a msg_id in the window is a duplicate. The window and the
+/// msg_ids are per broker, see BrokerContext.
+use hashbrown::HashMap;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::broker_context::BrokerContext;
+
+pub const DEFAULT_DUP_WINDOW: usize = 64;
+
+/// Duplicate filter of a broker, see BrokerContext.
+pub(crate) struct DupFilterState {
+    window: AtomicUsize,
+    seen_msg_ids: Mutex<HashMap<SocketAddr, VecDeque<u16>>>,
+}
+
+impl Default for DupFilterState {
+    fn default() -> Self {
+        DupFilterState {
+            window: AtomicUsize::new(DEFAULT_DUP_WINDOW),
+            seen_msg_ids: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[inline(always)]
+fn state() -> &'static DupFilterState {
+    &BrokerContext::current().dup_filter
+}
+
+#[derive(Debug, Clone)]
+pub struct DupFilter {}
+
+impl DupFilter {
+    /// Number of msg_ids per publisher, 0 disables the filter.
+    pub fn set_window(window: usize) {
+        state().window.store(window, Ordering::Relaxed);
+    }
+    pub fn window() -> usize {
+        state().window.load(Ordering::Relaxed)
+    }
+
+    /// Record the msg_id, returns true if the message is a duplicate.
+    pub fn is_dup(remote_addr: SocketAddr, msg_id: u16, dup: bool) -> bool {
+        let window = DupFilter::window();
+        if window == 0 {
+            return false;
+        }
+        let mut seen_map = state().seen_msg_ids.lock().unwrap();
+        let seen = seen_map.entry(remote_addr).or_insert_with(VecDeque::new);
+        let found = match seen.iter().position(|id| *id == msg_id) {
+            Some(index) => {
+                // Most recently used at the back.
+                seen.remove(index);
+                true
+            }
+            None => false,
+        };
+        seen.push_back(msg_id);
+        while seen.len() > window {
+            seen.pop_front();
+        }
+        // Without the DUP flag, the msg_id has wrapped around.
+        found && dup
+    }
+    /// Forget the publisher when the connection is removed.
+    pub fn remove(remote_addr: &SocketAddr) {
+        state().seen_msg_ids.lock().unwrap().remove(remote_addr);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_dup_filter() {
+        use super::*;
+        let _context = BrokerContext::new().enter();
+        let addr = "127.0.0.1:1800".parse::<SocketAddr>().unwrap();
+        assert!(!DupFilter::is_dup(addr, 1, false));
+        assert!(DupFilter::is_dup(addr, 1, true));
+        // Reused msg_id without the DUP flag is a new message.
+        assert!(!DupFilter::is_dup(addr, 1, false));
+        // msg_id 2 falls out of the window.
+        for msg_id in 2..(DEFAULT_DUP_WINDOW as u16 + 3) {
+            assert!(!DupFilter::is_dup(addr, msg_id, false));
+        }
+        assert!(!DupFilter::is_dup(addr, 2, true));
+        assert!(DupFilter::is_dup(addr, 1 + DEFAULT_DUP_WINDOW as u16, true));
+        DupFilter::remove(&addr);
+        assert!(!DupFilter::is_dup(addr, 1, true));
+        DupFilter::remove(&addr);
+    }
+}
@@ -0,0 +1,97 @@
+/// Two-priority egress queue.
+///
+/// The protocol messages, e.g. CONNACK, PINGRESP, SUBACK and the acks, are
+/// sent to MqttSnClient::egress_tx, the PUBLISH messages and the retransmits
+/// to egress_batch_tx. Egress::next() returns the messages of egress_rx
+/// first, the keep-alive and the handshakes aren't delayed behind a burst of
+/// fan-out messages. A data message is sent when no protocol message is
+/// waiting.
+use bytes::Bytes;
+use crossbeam::channel::select;
+use std::net::SocketAddr;
+
+use crate::{
+    broker_lib::{EgressBatchChannelType, MqttSnClient},
+    eformat, function,
+};
+
+#[derive(Debug, Clone)]
+pub struct Egress {}
+
+impl Egress {
+    /// Next message to send, waits until there is one. Returns an error if
+    /// the channels are disconnected.
+    #[inline(always)]
+    pub fn next(
+        client: &MqttSnClient,
+    ) -> Result<EgressBatchChannelType, String> {
+        if let Ok((addr, data)) = client.egress_rx.try_recv() {
+            return Ok((vec![addr], data.freeze()));
+        }
+        select! {
+            recv(client.egress_rx) -> msg => match msg {
+                Ok((addr, data)) => Ok((vec![addr], data.freeze())),
+                Err(why) => Err(eformat!(why)),
+            },
+            recv(client.egress_batch_rx) -> msg => match msg {
+                Ok(batch) => Ok(batch),
+                Err(why) => Err(eformat!(why)),
+            },
+        }
+    }
+
+    /// The datagrams of the message to addr, the fragments of a message
+    /// longer than the max. datagram size, see Fragment::split().
+    #[cfg(feature = "fragmentation")]
+    #[inline(always)]
+    pub fn datagrams(
+        addr: SocketAddr,
+        data: Bytes,
+    ) -> Result<Vec<Bytes>, String> {
+        crate::fragment::Fragment::split(addr, data)
+    }
+
+    /// The message itself, rejected if it's longer than the max. datagram
+    /// size.
+    #[cfg(not(feature = "fragmentation"))]
+    #[inline(always)]
+    pub fn datagrams(
+        addr: SocketAddr,
+        data: Bytes,
+    ) -> Result<Vec<Bytes>, String> {
+        if data.len() > crate::msg_hdr::MsgHeader::max_datagram_size() {
+            return Err(eformat!(addr, "message too long", data.len()));
+        }
+        Ok(vec![data])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_egress_priority() {
+        use super::*;
+        use crate::flags::{QOS_LEVEL_0, RETAIN_FALSE};
+        use crate::MSG_TYPE_PINGRESP;
+        use crate::{ping_resp::PingResp, publish::Publish};
+        use bytes::Bytes;
+        use std::net::SocketAddr;
+        let client = MqttSnClient::new();
+        let addr = "127.0.0.1:2500".parse::<SocketAddr>().unwrap();
+        for _ in 0..3 {
+            let data = Bytes::from_static(b"bulk");
+            Publish::send(1, QOS_LEVEL_0, RETAIN_FALSE, data, &client, addr)
+                .unwrap();
+        }
+        PingResp::send_to(&client, addr).unwrap();
+        // The PINGRESP preempts the PUBLISH messages.
+        let (addr_vec, bytes) = Egress::next(&client).unwrap();
+        assert_eq!(addr_vec, vec![addr]);
+        assert_eq!(bytes[1], MSG_TYPE_PINGRESP);
+        for _ in 0..3 {
+            let (_, bytes) = Egress::next(&client).unwrap();
+            assert_eq!(&bytes[bytes.len() - 4..], b"bulk");
+        }
+        assert!(client.egress_batch_rx.is_empty());
+    }
+}
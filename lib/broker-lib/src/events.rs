@@ -0,0 +1,204 @@
+/// Broker event callbacks for embedders.
+///
+/// The BrokerEvents in MqttSnClient is called when a client connects,
+/// disconnects, subscribes, publishes, changes its state or its keep alive
+/// timer expires, and when a subscriber acknowledges a QoS 1 or 2 PUBLISH
+/// or its retransmits are exhausted. on_denied() is called when the
+/// Authorizer or the DTLS identity check refuses a client, see AuditLog.
+/// on_rebind() is called when the session of a client moves to a new
+/// address, see Connection::rebind(), on_connect() follows.
+/// The callbacks run on the broker threads, they must return quickly,
+/// e.g. send the event to a channel for the external system.
+use bytes::Bytes;
+use std::net::SocketAddr;
+
+use crate::{
+    connection::StateEnum2, flags::QoSConst, publish::Publish, MsgIdType,
+    TopicIdType,
+};
+
+/// Outcome of a QoS 1 or 2 PUBLISH sent to a subscriber.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    /// PUBACK with RETURN_CODE_ACCEPTED, or PUBCOMP.
+    Acknowledged,
+    /// PUBACK with the return code of a rejection.
+    Rejected(u8),
+    /// No ack after the retransmits, or the subscriber isn't active.
+    Expired,
+}
+
+/// Action refused to a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeniedAction {
+    /// The DTLS identity isn't allowed to connect.
+    Connect,
+    Publish,
+    Subscribe,
+}
+
+pub trait BrokerEvents: Send + Sync {
+    fn on_connect(&self, _remote_addr: SocketAddr, _client_id: &Bytes) {}
+    fn on_disconnect(&self, _remote_addr: SocketAddr) {}
+    fn on_subscribe(
+        &self,
+        _remote_addr: SocketAddr,
+        _topic_id: TopicIdType,
+        _qos: QoSConst,
+    ) {
+    }
+    fn on_publish(&self, _remote_addr: SocketAddr, _publish: &Publish) {}
+    fn on_keepalive_expired(&self, _remote_addr: SocketAddr) {}
+    fn on_rebind(&self, _old_addr: SocketAddr, _new_addr: SocketAddr) {}
+    /// The topic is empty for DeniedAction::Connect.
+    fn on_denied(
+        &self,
+        _remote_addr: SocketAddr,
+        _action: DeniedAction,
+        _topic: &str,
+    ) {
+    }
+    /// The msg_id is the msg_id of the PUBLISH sent to the subscriber.
+    fn on_delivery(
+        &self,
+        _remote_addr: SocketAddr,
+        _msg_id: MsgIdType,
+        _status: DeliveryStatus,
+    ) {
+    }
+    fn on_state_change(
+        &self,
+        _remote_addr: SocketAddr,
+        _from: StateEnum2,
+        _to: StateEnum2,
+    ) {
+    }
+    /// false if none of the callbacks are implemented, the broker can skip
+    /// building the arguments.
+    fn is_enabled(&self) -> bool {
+        true
+    }
+}
+
+/// Default, no callbacks.
+#[derive(Debug, Clone, Default)]
+pub struct NoEvents {}
+
+impl BrokerEvents for NoEvents {
+    fn is_enabled(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_broker_events() {
+        use super::*;
+        use crate::broker_lib::MqttSnClient;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        #[derive(Default)]
+        struct Counter {
+            connects: AtomicUsize,
+        }
+        impl BrokerEvents for Counter {
+            fn on_connect(&self, _remote_addr: SocketAddr, _client_id: &Bytes) {
+                self.connects.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let client = MqttSnClient::new();
+        assert!(!client.events.is_enabled());
+        let counter = Arc::new(Counter::default());
+        let client = client.with_events(counter.clone());
+        assert!(client.events.is_enabled());
+        let addr = "127.0.0.1:1400".parse::<SocketAddr>().unwrap();
+        client.events.on_connect(addr, &Bytes::from("c1"));
+        client.events.on_disconnect(addr);
+        assert_eq!(counter.connects.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_delivery_events() {
+        use super::*;
+        use crate::broker_context::BrokerContext;
+        use crate::broker_lib::MqttSnClient;
+        use crate::egress::Egress;
+        use crate::flags::{QOS_LEVEL_1, QOS_LEVEL_2, RETAIN_FALSE};
+        use crate::keep_alive::KeepAliveTimeWheel;
+        use crate::listener::Listeners;
+        use crate::retransmit::RetransTimeWheel;
+        use crate::test_support::TestClient;
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+
+        #[derive(Default)]
+        struct Recorder {
+            deliveries: Mutex<Vec<(SocketAddr, MsgIdType, DeliveryStatus)>>,
+        }
+        impl BrokerEvents for Recorder {
+            fn on_delivery(
+                &self,
+                remote_addr: SocketAddr,
+                msg_id: MsgIdType,
+                status: DeliveryStatus,
+            ) {
+                let mut deliveries = self.deliveries.lock().unwrap();
+                deliveries.push((remote_addr, msg_id, status));
+            }
+        }
+        let context = BrokerContext::new();
+        let _context = context.enter();
+        KeepAliveTimeWheel::init();
+        RetransTimeWheel::init();
+        let recorder = Arc::new(Recorder::default());
+        let client = MqttSnClient::new()
+            .with_context(context)
+            .with_events(recorder.clone());
+        let localhost = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+        let sockets = Listeners::bind(&[localhost]).unwrap();
+        Listeners::run(client.clone(), sockets).unwrap();
+        let broker = Listeners::local_addrs()[0];
+        let egress = client.clone();
+        thread::spawn(move || {
+            let _context = egress.context.enter();
+            while let Ok((addr_vec, data)) = Egress::next(&egress) {
+                for addr in addr_vec {
+                    Listeners::send_to(addr, &data).unwrap();
+                }
+            }
+        });
+        let mut subscriber = TestClient::new(broker).unwrap();
+        subscriber.connect("deliverySub", 60, None).unwrap();
+        subscriber.subscribe("delivery/qos1", QOS_LEVEL_1).unwrap();
+        subscriber.subscribe("delivery/qos2", QOS_LEVEL_2).unwrap();
+        let mut publisher = TestClient::new(broker).unwrap();
+        publisher.connect("deliveryPub", 60, None).unwrap();
+        let mut msg_ids = Vec::new();
+        for (topic, qos) in [
+            ("delivery/qos1", QOS_LEVEL_1),
+            ("delivery/qos2", QOS_LEVEL_2),
+        ]
+        .iter()
+        {
+            let topic_id = publisher.register(topic).unwrap();
+            publisher
+                .publish(topic_id, *qos, RETAIN_FALSE, b"1")
+                .unwrap();
+            // Acknowledged by recv_publish().
+            msg_ids.push(subscriber.recv_publish().unwrap().msg_id);
+        }
+        // The PUBACK and the PUBCOMP were processed before the PINGREQ.
+        subscriber.ping(None).unwrap();
+        let sub_addr = subscriber.local_addr();
+        let deliveries = recorder.deliveries.lock().unwrap().clone();
+        let expected: Vec<_> = msg_ids
+            .into_iter()
+            .map(|msg_id| (sub_addr, msg_id, DeliveryStatus::Acknowledged))
+            .collect();
+        assert_eq!(deliveries, expected);
+        publisher.disconnect(None).unwrap();
+        subscriber.disconnect(None).unwrap();
+    }
+}
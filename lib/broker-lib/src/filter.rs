@@ -1,4 +1,4 @@
-use hashbrown::{HashMap, HashSet};
+use core::hash::Hash;
 use std::sync::{Arc, Mutex};
 
 use bisetmap::BisetMap;
@@ -7,10 +7,20 @@ use crate::TopicIdType;
 
 // use crate::Connection::ConnId;
 use std::net::SocketAddr;
+use std::str::Split;
 //use uuid::v1::{Context, Timestamp};
 //use uuid::Uuid;
 
-use crate::{eformat, flags::QoSConst, function};
+use crate::{
+    broker_context::BrokerContext,
+    collections::{BoundedMap, BoundedSet, FilterMap, FilterSet},
+    config::PreDefinedTopics,
+    eformat,
+    flags::QoSConst,
+    function,
+    subscription_store::{SubscriptionStore, SUBSCRIPTION_SHARDS},
+    topic_refs::{TopicRef, TopicRefs},
+};
 
 /// Checks if a topic or topic filter has wildcards
 #[inline(always)]
@@ -21,22 +31,24 @@ pub fn has_wildcards(filter: &str) -> bool {
 // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718106
 // A subscription topic filter can contain # or + to allow the client to
 // subscribe to multiple topics at once.
+/// A wildcard occupies a whole level, e.g. "+/a" and "a/+/c", and # is the
+/// last level, e.g. "#" and "a/#". "a/b+/c", "a#" and "a/#/c" are invalid.
+/// The levels can be empty, e.g. "a//b" and "a/".
 #[inline(always)]
 pub fn valid_filter(filter: &str) -> bool {
-    if !filter.is_empty() {
-        if has_wildcards(filter) {
-            // Verify multi level wildcards.
-            if filter.find('#') == Some(filter.len() - 1)
-                && filter.ends_with("/#")
-            {
-                return true;
-            }
-        // TODO verify single level wildcards.
-        } else {
-            return true;
+    if filter.is_empty() {
+        return false;
+    }
+    let mut levels = filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        match level {
+            "+" => {}
+            "#" if levels.peek().is_none() => {}
+            _ if has_wildcards(level) => return false,
+            _ => {}
         }
     }
-    false
+    true
 }
 
 // XXX copy from rumqtt
@@ -81,12 +93,328 @@ pub fn match_topic(topic: &str, filter: &str) -> bool {
     true
 }
 
+pub type SubscriberSet = Arc<Mutex<FilterSet<SocketAddr>>>;
+
+/// A filter and its subscribers.
+type FilterEntry = Option<(String, FilterSet<SocketAddr>)>;
+
+#[derive(Debug, Default)]
+struct TopicNode {
+    /// The next levels, without wildcards.
+    children: FilterMap<String, Box<TopicNode>>,
+    /// The "+" level.
+    plus: Option<Box<TopicNode>>,
+    /// The filter with "#" after this level, it matches this level too.
+    hash: FilterEntry,
+    /// The filter ending at this level.
+    filter: FilterEntry,
+}
+
+impl TopicNode {
+    #[inline(always)]
+    fn is_empty(&self) -> bool {
+        self.children.is_empty()
+            && self.plus.is_none()
+            && self.hash.is_none()
+            && self.filter.is_none()
+    }
+}
+
+/// Topic filters in a tree of levels, "+" and "#" are special children of a
+/// level. A topic is matched level by level, the cost depends on the depth
+/// of the topic and the wildcards on its path, not on the number of
+/// filters. Same results as match_topic(), e.g. a topic starting with '$'
+/// doesn't match.
+#[derive(Debug, Default)]
+pub struct TopicTree {
+    root: TopicNode,
+    /// Number of filters.
+    len: usize,
+}
+
+impl TopicTree {
+    pub fn new() -> Self {
+        TopicTree::default()
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Insert the subscriber of the filter, Ok if it's already there.
+    pub fn insert(
+        &mut self,
+        filter: &str,
+        socket_addr: SocketAddr,
+    ) -> Result<(), String> {
+        if !valid_filter(filter) {
+            return Err(eformat!(socket_addr, "invalid filter", filter));
+        }
+        let (levels, hash) = TopicTree::levels(filter);
+        let mut node = &mut self.root;
+        for level in levels {
+            node = match TopicTree::child_or_insert(node, level) {
+                Some(child) => child,
+                None => {
+                    return Err(eformat!(
+                        socket_addr,
+                        "filter map full",
+                        filter
+                    ))
+                }
+            };
+        }
+        let entry = if hash {
+            &mut node.hash
+        } else {
+            &mut node.filter
+        };
+        if entry.is_none() {
+            *entry = Some((filter.to_string(), FilterSet::new()));
+            self.len += 1;
+        }
+        let (_, addr_set) = entry.as_mut().unwrap();
+        match addr_set.bounded_insert(socket_addr) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(eformat!(socket_addr, "filter set full", filter)),
+        }
+    }
+
+    /// Remove the subscriber of the filter, the empty levels are removed.
+    /// Returns false if the filter isn't in the tree.
+    pub fn remove(&mut self, filter: &str, socket_addr: &SocketAddr) -> bool {
+        let (levels, hash) = TopicTree::levels(filter);
+        TopicTree::remove_in(
+            &mut self.root,
+            &levels,
+            hash,
+            socket_addr,
+            &mut self.len,
+        )
+    }
+
+    /// Remove the subscriber from all the filters.
+    pub fn remove_addr(&mut self, socket_addr: &SocketAddr) {
+        TopicTree::remove_addr_in(&mut self.root, socket_addr, &mut self.len);
+    }
+
+    /// Call f with each filter matching the topic and its subscribers.
+    pub fn for_each_match<'a, F>(&'a self, topic: &str, mut f: F)
+    where
+        F: FnMut(&'a str, &'a FilterSet<SocketAddr>),
+    {
+        if topic.starts_with('$') {
+            return;
+        }
+        TopicTree::visit(&self.root, topic.split('/'), &mut f);
+    }
+
+    /// The filters matching the topic.
+    pub fn match_filters(&self, topic: &str) -> Vec<String> {
+        let mut filters = Vec::new();
+        self.for_each_match(topic, |filter, _addr_set| {
+            filters.push(filter.to_string())
+        });
+        filters
+    }
+
+    /// The filter has subscribers.
+    pub fn contains(&self, filter: &str) -> bool {
+        let (levels, hash) = TopicTree::levels(filter);
+        let mut node = &self.root;
+        for level in levels {
+            let child = if level == "+" {
+                node.plus.as_ref()
+            } else {
+                node.children.get(level)
+            };
+            node = match child {
+                Some(child) => &**child,
+                None => return false,
+            };
+        }
+        if hash {
+            node.hash.is_some()
+        } else {
+            node.filter.is_some()
+        }
+    }
+
+    /// The levels before "#" and whether the filter ends with "#".
+    #[inline(always)]
+    fn levels(filter: &str) -> (Vec<&str>, bool) {
+        let mut levels: Vec<&str> = filter.split('/').collect();
+        let hash = levels.last() == Some(&"#");
+        if hash {
+            levels.pop();
+        }
+        (levels, hash)
+    }
+
+    #[inline(always)]
+    fn child_or_insert<'a>(
+        node: &'a mut TopicNode,
+        level: &str,
+    ) -> Option<&'a mut TopicNode> {
+        if level == "+" {
+            return Some(&mut **node.plus.get_or_insert_with(Box::default));
+        }
+        if node.children.get(level).is_none()
+            && node
+                .children
+                .bounded_insert(level.to_string(), Box::default())
+                .is_err()
+        {
+            return None;
+        }
+        node.children.get_mut(level).map(|child| &mut **child)
+    }
+
+    fn visit<'a, F>(node: &'a TopicNode, mut levels: Split<'_, char>, f: &mut F)
+    where
+        F: FnMut(&'a str, &'a FilterSet<SocketAddr>),
+    {
+        if let Some((filter, addr_set)) = &node.hash {
+            f(filter, addr_set);
+        }
+        match levels.next() {
+            None => {
+                if let Some((filter, addr_set)) = &node.filter {
+                    f(filter, addr_set);
+                }
+            }
+            // A topic with wildcards only matches "#".
+            Some("#") => {}
+            Some(level) => {
+                if let Some(child) = node.children.get(level) {
+                    TopicTree::visit(child, levels.clone(), f);
+                }
+                if let Some(plus) = &node.plus {
+                    TopicTree::visit(plus, levels, f);
+                }
+            }
+        }
+    }
+
+    /// Remove the subscriber from the entry, None once it's empty.
+    #[inline(always)]
+    fn remove_from(
+        entry: &mut FilterEntry,
+        socket_addr: &SocketAddr,
+        len: &mut usize,
+    ) -> bool {
+        let (removed, empty) = match entry {
+            Some((_, addr_set)) => {
+                (addr_set.remove(socket_addr), addr_set.is_empty())
+            }
+            None => return false,
+        };
+        if empty {
+            *entry = None;
+            *len -= 1;
+        }
+        removed
+    }
+
+    fn remove_in(
+        node: &mut TopicNode,
+        levels: &[&str],
+        hash: bool,
+        socket_addr: &SocketAddr,
+        len: &mut usize,
+    ) -> bool {
+        let (level, rest) = match levels.split_first() {
+            Some(next) => next,
+            None => {
+                let entry = if hash {
+                    &mut node.hash
+                } else {
+                    &mut node.filter
+                };
+                if entry.is_none() {
+                    return false;
+                }
+                TopicTree::remove_from(entry, socket_addr, len);
+                return true;
+            }
+        };
+        if *level == "+" {
+            let found = match node.plus.as_mut() {
+                Some(plus) => {
+                    TopicTree::remove_in(plus, rest, hash, socket_addr, len)
+                }
+                None => false,
+            };
+            if node.plus.as_ref().map_or(false, |plus| plus.is_empty()) {
+                node.plus = None;
+            }
+            return found;
+        }
+        let found = match node.children.get_mut(*level) {
+            Some(child) => {
+                TopicTree::remove_in(child, rest, hash, socket_addr, len)
+            }
+            None => false,
+        };
+        if node
+            .children
+            .get(*level)
+            .map_or(false, |child| child.is_empty())
+        {
+            node.children.remove(*level);
+        }
+        found
+    }
+
+    fn remove_addr_in(
+        node: &mut TopicNode,
+        socket_addr: &SocketAddr,
+        len: &mut usize,
+    ) {
+        TopicTree::remove_from(&mut node.hash, socket_addr, len);
+        TopicTree::remove_from(&mut node.filter, socket_addr, len);
+        if let Some(plus) = node.plus.as_mut() {
+            TopicTree::remove_addr_in(plus, socket_addr, len);
+            if plus.is_empty() {
+                node.plus = None;
+            }
+        }
+        let mut empty_levels = Vec::new();
+        for (level, child) in node.children.iter_mut() {
+            TopicTree::remove_addr_in(child, socket_addr, len);
+            if child.is_empty() {
+                empty_levels.push(level.clone());
+            }
+        }
+        for level in empty_levels {
+            node.children.remove(&level);
+        }
+    }
+}
+
+/// Get the subscriber set of the key, insert an empty set if not found.
+/// Returns None if the map is full.
+#[inline(always)]
+fn get_or_insert_set<K: Eq + Hash>(
+    map: &mut FilterMap<K, SubscriberSet>,
+    key: K,
+) -> Option<SubscriberSet> {
+    if let Some(conn_set) = map.get(&key) {
+        return Some(conn_set.clone());
+    }
+    let conn_set = Arc::new(Mutex::new(FilterSet::new()));
+    map.bounded_insert(key, conn_set.clone()).ok()?;
+    Some(conn_set)
+}
+
 #[derive(Debug, Clone)]
 pub struct Filter {
-    wildcard_topics: HashMap<String, Arc<Mutex<HashSet<SocketAddr>>>>,
-    wildcard_filters: HashMap<String, Arc<Mutex<HashSet<SocketAddr>>>>,
-    concrete_topics: HashMap<String, Arc<Mutex<HashSet<SocketAddr>>>>,
-    id_topics: HashMap<u16, Arc<Mutex<HashSet<SocketAddr>>>>, // only MQTT-SN
+    wildcard_topics: FilterMap<String, SubscriberSet>,
+    wildcard_filters: FilterMap<String, SubscriberSet>,
+    concrete_topics: FilterMap<String, SubscriberSet>,
+    id_topics: FilterMap<u16, SubscriberSet>, // only MQTT-SN
 }
 
 #[derive(Debug, Clone)]
@@ -114,10 +442,10 @@ impl Filter2 {
 impl Filter {
     pub fn new() -> Self {
         Filter {
-            wildcard_topics: HashMap::new(),
-            wildcard_filters: HashMap::new(),
-            concrete_topics: HashMap::new(),
-            id_topics: HashMap::new(), // only MQTT-SN
+            wildcard_topics: FilterMap::new(),
+            wildcard_filters: FilterMap::new(),
+            concrete_topics: FilterMap::new(),
+            id_topics: FilterMap::new(), // only MQTT-SN
         }
     }
     /// only MQTT-SN
@@ -127,16 +455,16 @@ impl Filter {
         id: u16,
         socket_addr: SocketAddr,
     ) -> Result<(), String> {
-        let conn_set = self
-            .id_topics
-            .entry(id)
-            .or_insert(Arc::new(Mutex::new(HashSet::new())));
+        let conn_set = get_or_insert_set(&mut self.id_topics, id)
+            .ok_or_else(|| eformat!(socket_addr, "topic map full", id))?;
         let mut conn_set = conn_set.lock().unwrap();
-        if conn_set.insert(socket_addr) {
-            Ok(())
-        } else {
+        match conn_set.bounded_insert(socket_addr) {
+            Ok(true) => Ok(()),
             // duplicate entry
-            Err(eformat!(socket_addr, "already subscribed to", id))
+            Ok(false) => {
+                Err(eformat!(socket_addr, "already subscribed to", id))
+            }
+            Err(_) => Err(eformat!(socket_addr, "subscriber set full", id)),
         }
     }
     /// Insert a new filter/subscription string from a connection subscription.
@@ -148,27 +476,41 @@ impl Filter {
     ) -> Result<(), String> {
         if valid_filter(filter) {
             if has_wildcards(filter) {
-                let conn_set = self
-                    .wildcard_filters
-                    .entry(filter.to_string())
-                    .or_insert(Arc::new(Mutex::new(HashSet::new())));
+                let conn_set = get_or_insert_set(
+                    &mut self.wildcard_filters,
+                    filter.to_string(),
+                )
+                .ok_or_else(|| {
+                    eformat!(socket_addr, "filter map full", filter)
+                })?;
                 let mut conn_set = conn_set.lock().unwrap();
-                if conn_set.insert(socket_addr) {
-                    return Ok(());
-                } else {
+                match conn_set.bounded_insert(socket_addr) {
+                    Ok(true) => return Ok(()),
                     // duplicate entry
-                    return Err(eformat!(socket_addr, "duplicate", filter));
+                    Ok(false) => {
+                        return Err(eformat!(socket_addr, "duplicate", filter))
+                    }
+                    Err(_) => {
+                        return Err(eformat!(socket_addr, "set full", filter))
+                    }
                 }
             } else {
-                let conn_set = self
-                    .concrete_topics
-                    .entry(filter.to_string())
-                    .or_insert(Arc::new(Mutex::new(HashSet::new())));
+                let conn_set = get_or_insert_set(
+                    &mut self.concrete_topics,
+                    filter.to_string(),
+                )
+                .ok_or_else(|| {
+                    eformat!(socket_addr, "topic map full", filter)
+                })?;
                 let mut conn_set = conn_set.lock().unwrap();
-                if conn_set.insert(socket_addr) {
-                    return Ok(());
-                } else {
-                    return Err(eformat!(socket_addr, "duplicate", filter));
+                match conn_set.bounded_insert(socket_addr) {
+                    Ok(true) => return Ok(()),
+                    Ok(false) => {
+                        return Err(eformat!(socket_addr, "duplicate", filter))
+                    }
+                    Err(_) => {
+                        return Err(eformat!(socket_addr, "set full", filter))
+                    }
                 }
             }
         }
@@ -179,7 +521,7 @@ impl Filter {
     pub fn match_topic_id(
         &mut self,
         topic: u16,
-    ) -> Option<HashSet<SocketAddr>> {
+    ) -> Option<FilterSet<SocketAddr>> {
         if let Some(id_set) = self.id_topics.get(&topic) {
             return Some(id_set.lock().unwrap().clone());
         }
@@ -190,7 +532,7 @@ impl Filter {
     pub fn match_topic_concrete(
         &mut self,
         topic: &str,
-    ) -> Option<HashSet<SocketAddr>> {
+    ) -> Option<FilterSet<SocketAddr>> {
         if let Some(id_set) = self.concrete_topics.get(topic) {
             return Some(id_set.lock().unwrap().clone());
         }
@@ -201,7 +543,7 @@ impl Filter {
     pub fn match_topic_wildcard(
         &mut self,
         topic: &str,
-    ) -> Option<HashSet<SocketAddr>> {
+    ) -> Option<FilterSet<SocketAddr>> {
         // Topic is in the wildcard_topics map.
         if let Some(id_set) = self.wildcard_topics.get(topic) {
             return Some(id_set.lock().unwrap().clone());
@@ -216,8 +558,10 @@ impl Filter {
                 // dbg!((filter, id_set));
                 if match_topic(topic, filter) {
                     // dbg!((filter, id_set));
-                    self.wildcard_topics
-                        .insert(topic.to_string(), id_set.clone());
+                    // The topic isn't cached if the map is full.
+                    let _result = self
+                        .wildcard_topics
+                        .bounded_insert(topic.to_string(), id_set.clone());
                 }
             }
             // Return the topic's wildcard_topics set.
@@ -229,31 +573,39 @@ impl Filter {
     }
 
     // Doesn't work correctly.
-    pub fn match_topic(&mut self, topic: &str) -> Option<HashSet<SocketAddr>> {
+    pub fn match_topic(
+        &mut self,
+        topic: &str,
+    ) -> Option<FilterSet<SocketAddr>> {
         // Publish topic shouldn't have wildcards.
         if has_wildcards(topic) {
             return None;
         }
 
-        let mut new_set: HashSet<SocketAddr> = HashSet::new();
+        let mut new_set: FilterSet<SocketAddr> = FilterSet::new();
         if let Some(socket_set) = self.wildcard_topics.get(topic) {
             // return Some(socket_set.lock().unwrap().clone());
             let wildcard_set = socket_set.lock().unwrap().clone();
-            new_set.extend(&wildcard_set);
+            for socket_addr in &wildcard_set {
+                let _result = new_set.bounded_insert(*socket_addr);
+            }
         } else {
             for (filter, socket_set) in &self.wildcard_filters {
                 dbg!((filter, socket_set));
                 if match_topic(topic, filter) {
                     dbg!((filter, socket_set));
-                    self.wildcard_topics
-                        .insert(topic.to_string(), socket_set.clone());
+                    let _result = self
+                        .wildcard_topics
+                        .bounded_insert(topic.to_string(), socket_set.clone());
                 }
             }
         }
         if let Some(socket_set) = self.concrete_topics.get(topic) {
             // return Some(socket_set.lock().unwrap().clone());
             let concrete_set = socket_set.lock().unwrap().clone();
-            new_set.extend(&concrete_set);
+            for socket_addr in &concrete_set {
+                let _result = new_set.bounded_insert(*socket_addr);
+            }
         }
         if !new_set.is_empty() {
             return Some(new_set);
@@ -262,62 +614,91 @@ impl Filter {
     }
 }
 
-lazy_static! {
-    pub static ref FILTERS: Mutex<Filter> = Mutex::new(Filter::new());
-    pub static ref CONCRETE_TOPICS: Mutex<BisetMap<String, SocketAddr>> =
-        Mutex::new(BisetMap::new());
-    pub static ref WILDCARD_TOPICS: Mutex<BisetMap<String, SocketAddr>> =
-        Mutex::new(BisetMap::new());
-    pub static ref WILDCARD_FILTERS: Mutex<BisetMap<String, SocketAddr>> =
-        Mutex::new(BisetMap::new());
-    /// topic_id <-> SocketAddr/subscribers
-    pub static ref TOPIC_IDS: Mutex<BisetMap<TopicIdType, SocketAddr>> =
-        Mutex::new(BisetMap::new());
-    /// store QoS for each top_id/subscriber
-    pub static ref TOPIC_IDS_QOS: Mutex<HashMap<(TopicIdType, SocketAddr), QoSConst>> =
-        Mutex::new(HashMap::new());
-    /// Topic name to topic id map is 1:1. Using a BisetMap to allow access from both sides.
-    pub static ref TOPIC_NAME_TO_IDS: Mutex<BisetMap<String, TopicIdType>> =
-        Mutex::new(BisetMap::new());
-    pub static ref TOPIC_ID_COUNTER: Mutex<TopicIdType> = Mutex::new(0);
-}
-// Delete QoS data
+/// Topic names and subscriptions of a broker, see BrokerContext.
+pub(crate) struct FilterState {
+    filters: Mutex<Filter>,
+    /// Subscriptions by topic id and topic filter.
+    subscriptions: SubscriptionStore,
+    /// Topic name to topic id map is 1:1. Using a BisetMap to allow access
+    /// from both sides.
+    topic_name_to_ids: Mutex<BisetMap<String, TopicIdType>>,
+    topic_id_counter: Mutex<TopicIdType>,
+}
+
+impl Default for FilterState {
+    fn default() -> Self {
+        FilterState {
+            filters: Mutex::new(Filter::new()),
+            subscriptions: SubscriptionStore::new(SUBSCRIPTION_SHARDS),
+            topic_name_to_ids: Mutex::new(BisetMap::new()),
+            topic_id_counter: Mutex::new(0),
+        }
+    }
+}
+
+#[inline(always)]
+fn state() -> &'static FilterState {
+    &BrokerContext::current().filter
+}
+// Delete the subscription, returns its QoS.
 pub fn remove_qos(
     topic_id: &TopicIdType,
     socket_addr: &SocketAddr,
 ) -> Option<QoSConst> {
-    TOPIC_IDS_QOS
-        .lock()
-        .unwrap()
-        .remove(&(*topic_id, *socket_addr))
+    let qos = state().subscriptions.unsubscribe(*topic_id, socket_addr)?;
+    TopicRefs::release(*topic_id, TopicRef::Subscription(*socket_addr));
+    Some(qos)
 }
 
 // Delete subscribers to this topic_id, and their QoS data
 pub fn delete_topic_id(topic_id: &TopicIdType) {
-    let sub_vec = TOPIC_IDS.lock().unwrap().delete(topic_id);
-    let mut map = TOPIC_IDS_QOS.lock().unwrap();
-    for sub in sub_vec {
-        map.remove(&(*topic_id, sub));
+    for sub in state().subscriptions.delete_topic_id(*topic_id) {
+        TopicRefs::release(*topic_id, TopicRef::Subscription(sub));
     }
 }
+// Delete the subscriptions of this subscriber, their QoS data and filters.
+pub fn delete_subscribers_with_socket_addr(socket_addr: &SocketAddr) {
+    delete_topic_ids_with_socket_addr(socket_addr);
+    delete_filter(*socket_addr);
+}
+/// Topic id of the topic name or filter, one lookup in the BisetMap.
+pub fn get_topic_id(topic_name: &str) -> Option<TopicIdType> {
+    let topic_ids = state()
+        .topic_name_to_ids
+        .lock()
+        .unwrap()
+        .get(&topic_name.to_string());
+    topic_ids.first().copied()
+}
+/// Topic name or filter of the topic id, the reverse lookup of
+/// get_topic_id(). The pre-defined topics aren't in the map, see
+/// PreDefinedTopics::name().
+pub fn get_topic_name(topic_id: TopicIdType) -> Option<String> {
+    let topic_names =
+        state().topic_name_to_ids.lock().unwrap().rev_get(&topic_id);
+    topic_names.into_iter().next()
+}
 pub fn get_topic_id_with_topic_name(topic_name: String) -> Option<TopicIdType> {
-    let topic_ids = TOPIC_NAME_TO_IDS.lock().unwrap().get(&topic_name);
-    if topic_ids.is_empty() {
-        None
-    } else {
-        Some(topic_ids[0])
-    }
+    get_topic_id(&topic_name)
+}
+/// All the topic names and filters with their topic ids.
+pub fn get_topic_names() -> Vec<(String, TopicIdType)> {
+    state().topic_name_to_ids.lock().unwrap().flat_collect()
+}
+pub fn get_topic_name_with_topic_id(topic_id: TopicIdType) -> Option<String> {
+    get_topic_name(topic_id)
 }
 
 pub fn try_register_topic_name(
     topic_name: String,
     topic_id: TopicIdType,
 ) -> Result<TopicIdType, String> {
-    let topic_ids = TOPIC_NAME_TO_IDS.lock().unwrap().get(&topic_name);
+    let topic_ids = state().topic_name_to_ids.lock().unwrap().get(&topic_name);
     // If topic name is already in the map, return the existing topic id,
     // otherwise insert the topic name and topic id into the map.
     if topic_ids.is_empty() {
-        TOPIC_NAME_TO_IDS
+        state()
+            .topic_name_to_ids
             .lock()
             .unwrap()
             .insert(topic_name, topic_id);
@@ -337,20 +718,28 @@ pub fn try_register_topic_name(
     }
 }
 
-/// Try to insert a NEW topic name, topic id is assigned using TOPIC_ID_COUNTER
+/// Try to insert a NEW topic name for the broker, the topic id is pinned
+/// and never reclaimed. Clients use TopicRefs::insert() with their
+/// reference.
 pub fn try_insert_topic_name(
     topic_name: String,
 ) -> Result<TopicIdType, String> {
-    let topic_ids = TOPIC_NAME_TO_IDS.lock().unwrap().get(&topic_name);
+    TopicRefs::insert(topic_name, TopicRef::Broker)
+}
+
+/// Insert the topic name if it's new, called by TopicRefs with the
+/// reference table locked.
+pub fn insert_topic_name(topic_name: String) -> Result<TopicIdType, String> {
+    let topic_name_to_ids = state().topic_name_to_ids.lock().unwrap();
+    let topic_ids = topic_name_to_ids.get(&topic_name);
     // If topic name is already in the map, return the existing topic id,
     // otherwise insert the topic name and topic id into the map.
     if topic_ids.is_empty() {
-        let topic_id = *TOPIC_ID_COUNTER.lock().unwrap();
-        TOPIC_NAME_TO_IDS
-            .lock()
-            .unwrap()
-            .insert(topic_name, topic_id);
-        *TOPIC_ID_COUNTER.lock().unwrap() = topic_id + 1;
+        let topic_id = alloc_topic_id(
+            &topic_name_to_ids,
+            &mut state().topic_id_counter.lock().unwrap(),
+        )?;
+        topic_name_to_ids.insert(topic_name, topic_id);
         Ok(topic_id)
     } else {
         // Topic name is already in the map with only one topic id.
@@ -358,16 +747,47 @@ pub fn try_insert_topic_name(
     }
 }
 
+/// Next topic id from the counter. The counter wraps around, the topic
+/// ids in use and the pre-defined topic ids are skipped.
+fn alloc_topic_id(
+    topic_name_to_ids: &BisetMap<String, TopicIdType>,
+    counter: &mut TopicIdType,
+) -> Result<TopicIdType, String> {
+    for _ in 0..=TopicIdType::MAX as u32 {
+        let topic_id = *counter;
+        *counter = counter.wrapping_add(1);
+        if topic_name_to_ids.rev_get(&topic_id).is_empty()
+            && PreDefinedTopics::name(topic_id).is_none()
+        {
+            return Ok(topic_id);
+        }
+    }
+    Err(eformat!("no topic id left"))
+}
+
+/// Remove the topic name of a reclaimed topic id.
+pub fn remove_topic_id(topic_id: TopicIdType) {
+    state()
+        .topic_name_to_ids
+        .lock()
+        .unwrap()
+        .rev_delete(&topic_id);
+}
+
 #[inline(always)]
 pub fn subscribe_with_topic_name(
     socket_addr: SocketAddr,
     topic_name: String,
     qos: QoSConst,
 ) -> Result<TopicIdType, String> {
-    match try_insert_topic_name(topic_name.clone()) {
+    match TopicRefs::insert(
+        topic_name.clone(),
+        TopicRef::Subscription(socket_addr),
+    ) {
         Ok(id) => {
-            TOPIC_IDS.lock().unwrap().insert(id, socket_addr);
-            TOPIC_IDS_QOS.lock().unwrap().insert((id, socket_addr), qos);
+            if let Err(why) = subscribe_with_topic_id(socket_addr, id, qos) {
+                return Err(eformat!(why, topic_name));
+            }
             Ok(id)
         }
         Err(why) => Err(eformat!(socket_addr, why, topic_name)),
@@ -380,18 +800,71 @@ pub fn subscribe_with_topic_id(
     id: TopicIdType,
     qos: QoSConst,
 ) -> Result<(), String> {
-    TOPIC_IDS.lock().unwrap().insert(id, socket_addr);
-    TOPIC_IDS_QOS.lock().unwrap().insert((id, socket_addr), qos);
+    if let Err(why) = state().subscriptions.subscribe(id, socket_addr, qos) {
+        TopicRefs::release(id, TopicRef::Subscription(socket_addr));
+        return Err(why);
+    }
+    TopicRefs::acquire(id, TopicRef::Subscription(socket_addr));
+    // The topics of the PUBLISH messages are matched against the filter.
+    if let Some(filter) = get_topic_name_with_topic_id(id) {
+        if has_wildcards(&filter) {
+            if let Err(why) =
+                state().subscriptions.insert_filter(filter, socket_addr)
+            {
+                unsubscribe_with_topic_id(socket_addr, id)?;
+                return Err(why);
+            }
+        }
+    }
     Ok(())
 }
 
+/// Subscribe to several topic ids, e.g. a batched SUBSCRIBE. The wildcard
+/// filters are inserted with one lock of the filter table. Returns a result
+/// per topic id.
+pub fn subscribe_batch_with_topic_ids(
+    socket_addr: SocketAddr,
+    subscriptions: &[(TopicIdType, QoSConst)],
+) -> Vec<Result<(), String>> {
+    let mut results = Vec::with_capacity(subscriptions.len());
+    // Index of the subscription and its filter.
+    let mut filters = Vec::new();
+    for (index, (id, qos)) in subscriptions.iter().enumerate() {
+        if let Err(why) =
+            state().subscriptions.subscribe(*id, socket_addr, *qos)
+        {
+            TopicRefs::release(*id, TopicRef::Subscription(socket_addr));
+            results.push(Err(why));
+            continue;
+        }
+        TopicRefs::acquire(*id, TopicRef::Subscription(socket_addr));
+        results.push(Ok(()));
+        if let Some(filter) = get_topic_name_with_topic_id(*id) {
+            if has_wildcards(&filter) {
+                filters.push((index, filter));
+            }
+        }
+    }
+    let (indexes, filters): (Vec<usize>, Vec<String>) =
+        filters.into_iter().unzip();
+    let inserted = state().subscriptions.insert_filters(filters, socket_addr);
+    for (index, result) in indexes.into_iter().zip(inserted) {
+        if let Err(why) = result {
+            let _result =
+                unsubscribe_with_topic_id(socket_addr, subscriptions[index].0);
+            results[index] = Err(why);
+        }
+    }
+    results
+}
+
 #[inline(always)]
 pub fn unsubscribe_with_topic_name(
     socket_addr: SocketAddr,
     topic_name: String,
 ) -> Result<(), String> {
     // Get the topic id from the topic name.
-    let topic_ids = TOPIC_NAME_TO_IDS.lock().unwrap().get(&topic_name);
+    let topic_ids = state().topic_name_to_ids.lock().unwrap().get(&topic_name);
     if !topic_ids.is_empty() {
         // Remove socket_addr from the topic id map.
         let topic_id = topic_ids[0];
@@ -407,7 +880,13 @@ pub fn unsubscribe_with_topic_id(
     socket_addr: SocketAddr,
     id: TopicIdType,
 ) -> Result<(), String> {
-    TOPIC_IDS.lock().unwrap().remove(&id, &socket_addr);
+    state().subscriptions.unsubscribe(id, &socket_addr);
+    if let Some(filter) = get_topic_name_with_topic_id(id) {
+        if has_wildcards(&filter) {
+            state().subscriptions.remove_filter(&filter, &socket_addr);
+        }
+    }
+    TopicRefs::release(id, TopicRef::Subscription(socket_addr));
     Ok(())
 }
 
@@ -417,29 +896,83 @@ pub struct Subscriber {
     pub qos: QoSConst,
 }
 
-/// Get the vector of subscribers with the topic_id key.
+/// Get the vector of subscribers with the topic_id key, and the subscribers
+/// of the wildcard filters matching its topic name. A subscriber matched
+/// more than once gets the highest QoS.
 #[inline(always)]
 pub fn get_subscribers_with_topic_id(id: u16) -> Vec<Subscriber> {
-    // Get the list of socket_addr that subscribed to the topic_id.
-    let sock_vec = TOPIC_IDS.lock().unwrap().get(&id);
-    let mut return_vec: Vec<Subscriber> = Vec::new();
-    // Get the QoS of each socket_addr subscribed to the topic_id.
-    for socket_addr in sock_vec {
-        for qos in TOPIC_IDS_QOS.lock().unwrap().get(&(id, socket_addr)) {
-            return_vec.push(Subscriber {
-                socket_addr: socket_addr,
-                qos: *qos,
-            });
+    let mut subscriber_vec = state().subscriptions.subscribers(id);
+    if !state().subscriptions.has_filters() {
+        return subscriber_vec;
+    }
+    let topic = match get_topic_name_with_topic_id(id) {
+        Some(topic) if !has_wildcards(&topic) => topic,
+        _ => return subscriber_vec,
+    };
+    for filter in state().subscriptions.match_filters(&topic) {
+        let filter_id = match get_topic_id_with_topic_name(filter) {
+            Some(filter_id) => filter_id,
+            None => continue,
+        };
+        for subscriber in state().subscriptions.subscribers(filter_id) {
+            match subscriber_vec
+                .iter_mut()
+                .find(|other| other.socket_addr == subscriber.socket_addr)
+            {
+                Some(other) => other.qos = other.qos.max(subscriber.qos),
+                None => subscriber_vec.push(subscriber),
+            }
         }
     }
-    return_vec
+    subscriber_vec
+}
+
+/// Number of subscribers of the topic id, without the wildcard filters
+/// matching it.
+pub fn count_subscribers(topic_id: TopicIdType) -> usize {
+    state().subscriptions.subscriber_count(topic_id)
+}
+/// Number of topic ids subscribed by the subscriber.
+pub fn count_subscriptions(socket_addr: &SocketAddr) -> usize {
+    state().subscriptions.subscription_count(socket_addr)
+}
+pub fn is_subscribed(socket_addr: &SocketAddr, topic_id: TopicIdType) -> bool {
+    state().subscriptions.is_subscribed(topic_id, socket_addr)
+}
+/// Number of wildcard filters with subscribers.
+pub fn count_filters() -> usize {
+    state().subscriptions.filter_count()
+}
+pub fn has_filter(filter: &str) -> bool {
+    state().subscriptions.has_filter(filter)
+}
+
+/// All the subscriptions, the subscriber, the topic id and the QoS.
+pub fn get_subscriptions() -> Vec<(SocketAddr, TopicIdType, QoSConst)> {
+    state().subscriptions.subscriptions()
 }
 
 #[inline(always)]
 pub fn delete_topic_ids_with_socket_addr(
     socket_addr: &SocketAddr,
 ) -> Vec<TopicIdType> {
-    TOPIC_IDS.lock().unwrap().rev_delete(socket_addr)
+    take_subscriptions_with_socket_addr(socket_addr)
+        .into_iter()
+        .map(|(topic_id, _qos)| topic_id)
+        .collect()
+}
+
+/// Delete the subscriptions of the subscriber, returns the topic ids and
+/// their QoS.
+pub fn take_subscriptions_with_socket_addr(
+    socket_addr: &SocketAddr,
+) -> Vec<(TopicIdType, QoSConst)> {
+    let subscriptions = state().subscriptions.delete_socket_addr(socket_addr);
+    state().subscriptions.delete_filters(socket_addr);
+    for (topic_id, _qos) in subscriptions.iter() {
+        TopicRefs::release(*topic_id, TopicRef::Subscription(*socket_addr));
+    }
+    subscriptions
 }
 
 #[inline(always)]
@@ -447,58 +980,30 @@ pub fn insert_filter(
     filter: String,
     socket_addr: SocketAddr,
 ) -> Result<(), String> {
-    if valid_filter(&filter[..]) {
-        if has_wildcards(&filter[..]) {
-            WILDCARD_FILTERS.lock().unwrap().insert(filter, socket_addr);
-        } else {
-            CONCRETE_TOPICS.lock().unwrap().insert(filter, socket_addr);
-        }
-        return Ok(());
-    }
-    Err(eformat!(socket_addr, "invalid filter", filter))
+    state().subscriptions.insert_filter(filter, socket_addr)
 }
 
-/// Remove topics and filters from the bisetmaps using the rev_delete()
+/// Remove the subscriber from the topics and filters.
 #[inline(always)]
 pub fn delete_filter(socket_addr: SocketAddr) {
-    WILDCARD_FILTERS.lock().unwrap().rev_delete(&socket_addr);
-    CONCRETE_TOPICS.lock().unwrap().rev_delete(&socket_addr);
-    WILDCARD_TOPICS.lock().unwrap().rev_delete(&socket_addr);
+    state().subscriptions.delete_filters(&socket_addr);
 }
 
 #[inline(always)]
 pub fn match_concrete_topics(topic: &String) -> Vec<SocketAddr> {
-    CONCRETE_TOPICS.lock().unwrap().get(topic)
+    state().subscriptions.match_concrete(topic)
 }
 
 #[inline(always)]
 pub fn match_topics(topic: &String) -> Vec<SocketAddr> {
-    let sock_vec = WILDCARD_TOPICS.lock().unwrap().get(topic);
-    if sock_vec.is_empty() {
-        // The topic doesn't match any wildcard topics.
-        // Matching the topic against all wildcard filters.
-        for (filter, socket_vec) in WILDCARD_FILTERS.lock().unwrap().collect() {
-            if match_topic(topic, &filter) {
-                // Insert each socket_addr into the matching wildcard_topics.
-                for sock in socket_vec {
-                    WILDCARD_TOPICS.lock().unwrap().insert(topic.clone(), sock);
-                }
-            }
-        }
-    }
-    let wildcards = WILDCARD_TOPICS.lock().unwrap().get(topic);
-    let mut concretes = CONCRETE_TOPICS.lock().unwrap().get(topic);
-    concretes.append(&mut wildcards.clone());
-    concretes.sort();
-    concretes.dedup();
-    concretes
+    state().subscriptions.match_topics(topic)
 }
 
 pub fn global_filter_insert(
     filter: &str,
     socket_addr: SocketAddr,
 ) -> Result<(), String> {
-    let mut filters = FILTERS.lock().unwrap();
+    let mut filters = state().filters.lock().unwrap();
     filters.insert(filter, socket_addr)?;
     // dbg!(filters);
     Ok(())
@@ -507,6 +1012,52 @@ pub fn global_filter_insert(
 #[cfg(test)]
 mod test {
 
+    #[test]
+    fn test_topic_tree() {
+        use super::*;
+        let addr = "127.0.0.1:1500".parse::<SocketAddr>().unwrap();
+        let addr2 = "127.0.0.2:1500".parse::<SocketAddr>().unwrap();
+        let filters = [
+            "#", "a/#", "a/+", "a/b", "+/b", "+/+", "a/+/c", "a//c", "+",
+            "a/b/#", "$SYS/#",
+        ];
+        let mut tree = TopicTree::new();
+        for filter in filters.iter() {
+            tree.insert(filter, addr).unwrap();
+        }
+        tree.insert("a/+", addr2).unwrap();
+        assert!(tree.insert("a/#/c", addr).is_err());
+        assert_eq!(tree.len(), filters.len());
+        // Same matches as match_topic().
+        for topic in
+            ["a", "a/b", "a/b/c", "a//c", "b", "", "c/b", "$SYS/a", "a/#"]
+                .iter()
+        {
+            let mut matched = tree.match_filters(topic);
+            matched.sort();
+            let mut expected: Vec<String> = filters
+                .iter()
+                .filter(|filter| match_topic(topic, filter))
+                .map(|filter| filter.to_string())
+                .collect();
+            expected.sort();
+            assert_eq!(matched, expected, "{}", topic);
+        }
+        // The empty levels are removed.
+        assert!(tree.remove("a/+/c", &addr));
+        assert!(!tree.remove("a/+/c", &addr));
+        assert!(!tree.remove("x/#", &addr));
+        assert!(tree.contains("a/+") && tree.contains("a/b/#"));
+        assert!(!tree.contains("a/+/c") && !tree.contains("x/#"));
+        assert_eq!(tree.match_filters("x/b/c"), vec!["#".to_string()]);
+        tree.remove_addr(&addr);
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree.match_filters("a/b"), vec!["a/+".to_string()]);
+        assert!(tree.remove("a/+", &addr2));
+        assert!(tree.is_empty());
+        assert!(tree.root.is_empty());
+    }
+
     #[test]
     fn test_topic_name_and_id() {
         let topic_id =
@@ -518,8 +1069,12 @@ mod test {
         let topic_id =
             super::try_insert_topic_name("test/now".to_string()).unwrap();
         assert_eq!(topic_id, 1);
-        dbg!(super::TOPIC_NAME_TO_IDS.lock().unwrap());
-        dbg!(super::TOPIC_ID_COUNTER.lock().unwrap());
+        assert_eq!(super::get_topic_id("test/now"), Some(topic_id));
+        assert_eq!(super::get_topic_name(topic_id), Some("test/now".into()));
+        assert_eq!(super::get_topic_id("test/later"), None);
+        assert_eq!(super::get_topic_name(0xFFFE), None);
+        dbg!(super::state().topic_name_to_ids.lock().unwrap());
+        dbg!(super::state().topic_id_counter.lock().unwrap());
     }
     #[test]
     fn test_topic_id() {
@@ -787,6 +1342,15 @@ mod test {
         assert!(super::valid_filter("correct/filter/"));
         assert!(super::valid_filter("correct/filter"));
         assert!(!super::valid_filter(""));
+        assert!(super::valid_filter("#"));
+        assert!(super::valid_filter("+"));
+        assert!(super::valid_filter("+/a"));
+        assert!(super::valid_filter("a/+/c"));
+        assert!(super::valid_filter("+/+/#"));
+        assert!(!super::valid_filter("a/b+/c"));
+        assert!(!super::valid_filter("a/+b"));
+        assert!(!super::valid_filter("a/++"));
+        assert!(!super::valid_filter("#/a"));
     }
 
     #[test] // TODO learn more about this from rumqtt
@@ -0,0 +1,185 @@
+/*
+5.5 Forwarder Encapsulation
+Length MsgType Ctrl Wireless Node Id MQTT-SN message
+(octet 0) (1) (2) (3:n) (n+1:m)
+Table 29: Encapsulated MQTT-SN Frame
+• Length: 1-octet long, specifies the number of octets up to the end of the
+  “Wireless Node Id” field (incl. the Length octet itself)
+• MsgType: coded “0xFE”, see Table 3
+• Ctrl: The Ctrl octet contains control information exchanged between the GW
+  and the forwarder. Its format is shown in Table 30:
+  – Radius: broadcast radius (only relevant in direction GW to forwarder)
+  – All remaining bits are reserved
+• Wireless Node Id: identifies the wireless node which has sent or should
+  receive the encapsulated MQTT-SN message. The mapping between this Id and
+  the address of the wireless node is implemented by the forwarder, if needed.
+• MQTT-SN message: the MQTT-SN message.
+*/
+/// The broker keys the connection state on SocketAddr, the nodes behind one
+/// forwarder share its address. Forwarder::decapsulate() maps each
+/// (forwarder, node id) to a virtual address in fd00:4d51:534e::/48, the
+/// inner message is processed as if it was received from the virtual
+/// address. Forwarder::encapsulate() wraps the messages to a virtual address
+/// and returns the address of the forwarder. The mapping is kept after the
+/// disconnect, a node reconnecting through the same forwarder gets the same
+/// address.
+use bytes::{BufMut, Bytes, BytesMut};
+use hashbrown::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+
+use crate::{eformat, function, MSG_LEN_ENCAP_HEADER, MSG_TYPE_ENCAP_MSG};
+
+#[derive(Debug, Default)]
+struct Nodes {
+    /// (forwarder, node id) to virtual address.
+    virtual_addrs: HashMap<(SocketAddr, Bytes), SocketAddr>,
+    /// Virtual address to (forwarder, node id).
+    nodes: HashMap<SocketAddr, (SocketAddr, Bytes)>,
+    next_index: u32,
+}
+
+lazy_static! {
+    static ref NODES: Mutex<Nodes> = Mutex::new(Nodes::default());
+}
+
+#[derive(Debug, Clone)]
+pub struct Forwarder {}
+
+impl Forwarder {
+    /// The frame starts with the encapsulation header.
+    #[inline(always)]
+    pub fn is_encapsulated(buf: &[u8]) -> bool {
+        buf.len() >= MSG_LEN_ENCAP_HEADER as usize
+            && buf[0] != 1
+            && buf[1] == MSG_TYPE_ENCAP_MSG
+    }
+
+    /// Returns the virtual address of the wireless node and the
+    /// encapsulated MQTT-SN message.
+    pub fn decapsulate(
+        forwarder: SocketAddr,
+        bytes: &Bytes,
+    ) -> Result<(SocketAddr, Bytes), String> {
+        let len = bytes[0] as usize;
+        // The node id isn't empty and the message has at least 2 bytes.
+        if len <= MSG_LEN_ENCAP_HEADER as usize || bytes.len() < len + 2 {
+            return Err(eformat!(forwarder, "encapsulation len", len));
+        }
+        let node_id = bytes.slice(MSG_LEN_ENCAP_HEADER as usize..len);
+        let addr = Forwarder::virtual_addr(forwarder, node_id);
+        Ok((addr, bytes.slice(len..)))
+    }
+
+    /// Encapsulate the message if addr is a wireless node, returns the
+    /// address of the forwarder and the frame.
+    pub fn encapsulate(
+        addr: SocketAddr,
+        data: &[u8],
+    ) -> Option<(SocketAddr, BytesMut)> {
+        let (forwarder, node_id) = Forwarder::node_of(&addr)?;
+        let len = MSG_LEN_ENCAP_HEADER as usize + node_id.len();
+        let mut bytes = BytesMut::with_capacity(len + data.len());
+        bytes.put_u8(len as u8);
+        bytes.put_u8(MSG_TYPE_ENCAP_MSG);
+        // Radius 0, the message isn't broadcast.
+        bytes.put_u8(0);
+        bytes.put_slice(&node_id);
+        bytes.put_slice(data);
+        Some((forwarder, bytes))
+    }
+
+    /// The virtual address of a node, None before its first frame.
+    pub fn node_addr(
+        forwarder: SocketAddr,
+        node_id: &[u8],
+    ) -> Option<SocketAddr> {
+        let key = (forwarder, Bytes::copy_from_slice(node_id));
+        NODES.lock().unwrap().virtual_addrs.get(&key).copied()
+    }
+
+    /// The forwarder address and the node id of a virtual address.
+    pub fn node_of(addr: &SocketAddr) -> Option<(SocketAddr, Bytes)> {
+        if !Forwarder::is_virtual(addr) {
+            return None;
+        }
+        NODES.lock().unwrap().nodes.get(addr).cloned()
+    }
+
+    #[inline(always)]
+    fn is_virtual(addr: &SocketAddr) -> bool {
+        match addr.ip() {
+            IpAddr::V6(ip) => ip.segments()[..3] == [0xfd00, 0x4d51, 0x534e],
+            IpAddr::V4(_) => false,
+        }
+    }
+
+    fn virtual_addr(forwarder: SocketAddr, node_id: Bytes) -> SocketAddr {
+        let mut nodes = NODES.lock().unwrap();
+        if let Some(addr) =
+            nodes.virtual_addrs.get(&(forwarder, node_id.clone()))
+        {
+            return *addr;
+        }
+        let index = nodes.next_index;
+        nodes.next_index = index.wrapping_add(1);
+        let ip = Ipv6Addr::new(
+            0xfd00,
+            0x4d51, // "MQ"
+            0x534e, // "SN"
+            0,
+            0,
+            0,
+            (index >> 16) as u16,
+            index as u16,
+        );
+        let addr = SocketAddr::new(IpAddr::V6(ip), forwarder.port());
+        nodes
+            .virtual_addrs
+            .insert((forwarder, node_id.clone()), addr);
+        nodes.nodes.insert(addr, (forwarder, node_id));
+        addr
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_forwarder_encapsulation() {
+        use super::*;
+        use crate::{MSG_LEN_PINGRESP, MSG_TYPE_PINGREQ, MSG_TYPE_PINGRESP};
+        let forwarder = "127.0.0.1:2000".parse::<SocketAddr>().unwrap();
+        // PINGREQ from node 0x0A0B.
+        let frame =
+            Bytes::from_static(&[5, 0xFE, 0, 0x0A, 0x0B, 2, MSG_TYPE_PINGREQ]);
+        assert!(Forwarder::is_encapsulated(&frame));
+        let (addr, msg) = Forwarder::decapsulate(forwarder, &frame).unwrap();
+        assert!(Forwarder::is_virtual(&addr));
+        assert_eq!(&msg[..], &[2, MSG_TYPE_PINGREQ]);
+        // Same node, same address. Other node, other address.
+        let (addr2, _) = Forwarder::decapsulate(forwarder, &frame).unwrap();
+        assert_eq!(addr, addr2);
+        assert_eq!(Forwarder::node_addr(forwarder, &[0x0A, 0x0B]), Some(addr));
+        let frame2 =
+            Bytes::from_static(&[4, 0xFE, 0, 0x0C, 2, MSG_TYPE_PINGREQ]);
+        let (addr3, _) = Forwarder::decapsulate(forwarder, &frame2).unwrap();
+        assert_ne!(addr, addr3);
+        // The reply is encapsulated and sent to the forwarder.
+        let (to, bytes) = Forwarder::encapsulate(
+            addr,
+            &[MSG_LEN_PINGRESP, MSG_TYPE_PINGRESP],
+        )
+        .unwrap();
+        assert_eq!(to, forwarder);
+        assert_eq!(
+            &bytes[..],
+            &[5, 0xFE, 0, 0x0A, 0x0B, MSG_LEN_PINGRESP, MSG_TYPE_PINGRESP]
+        );
+        assert!(Forwarder::encapsulate(forwarder, &[2, 0x17]).is_none());
+        // Empty node id, missing message.
+        let frame = Bytes::from_static(&[3, 0xFE, 0, 2, MSG_TYPE_PINGREQ]);
+        assert!(Forwarder::decapsulate(forwarder, &frame).is_err());
+        let frame = Bytes::from_static(&[4, 0xFE, 0, 0x0C, 2]);
+        assert!(Forwarder::decapsulate(forwarder, &frame).is_err());
+    }
+}
@@ -0,0 +1,383 @@
+/*
+Fragmentation, a private extension of MQTT-SN, see msg_hdr.rs.
+
+Length    MsgType Id    Index Count Data
+(octet 0) (1)     (2-3) (4)   (5)   (6:n)
+FRAGMENT message, 0xF0 in the reserved range.
+
+• Id: identifies the fragments of one message, per sender.
+• Index: 0 to Count - 1.
+• Count: number of fragments, 0 for a capability announcement, Data is then
+  the max. message length of the sender as a u16.
+• Data: the bytes of the message from Index * fragment size.
+*/
+/// A message longer than the max. datagram size, e.g. a PUBLISH with a
+/// large payload, is sent as numbered FRAGMENT messages and reassembled by
+/// the peer before dispatch(). The layer is built with the fragmentation
+/// feature and enabled per broker with Fragment::enable().
+///
+/// A peer announces its support with a capability FRAGMENT, the broker
+/// answers with its own. The messages to a peer without the announcement,
+/// or above its max. message length, are rejected instead of being
+/// fragmented. The fragments of a message missing after FRAGMENT_TIMEOUT
+/// are dropped.
+use bytes::{BufMut, Bytes, BytesMut};
+use hashbrown::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{
+    broker_context::BrokerContext,
+    broker_lib::MqttSnClient,
+    codec::{get_u16_be, put_u16_be},
+    eformat, function,
+    msg_hdr::MsgHeader,
+    MSG_TYPE_FRAGMENT,
+};
+
+/// Length (1 or 3 octets), MsgType, Id, Index and Count.
+const FRAGMENT_MAX_HEADER_LEN: usize = 8;
+/// Id, Index and Count.
+const FRAGMENT_FIELDS_LEN: usize = 4;
+pub const FRAGMENT_MAX_COUNT: usize = u8::MAX as usize;
+pub const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Messages being reassembled, per broker.
+pub const FRAGMENT_MAX_PARTIAL: usize = 1024;
+
+#[derive(Debug)]
+struct Partial {
+    parts: Vec<Option<Bytes>>,
+    received: usize,
+    len: usize,
+    started: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Fragments {
+    /// Max. message length, 0 if disabled.
+    max_message_size: usize,
+    /// Max. message length of the peers supporting fragmentation.
+    peers: HashMap<SocketAddr, usize>,
+    partials: HashMap<(SocketAddr, u16), Partial>,
+    next_id: u16,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct FragmentState {
+    fragments: Mutex<Fragments>,
+}
+
+#[inline(always)]
+fn state() -> &'static FragmentState {
+    &BrokerContext::current().fragment
+}
+
+#[derive(Debug, Clone)]
+pub struct Fragment {}
+
+impl Fragment {
+    /// Accept and send messages up to max_message_size bytes, clamped to
+    /// 65535, the max. length of the 3-octet format.
+    pub fn enable(max_message_size: usize) {
+        state().fragments.lock().unwrap().max_message_size =
+            max_message_size.min(u16::MAX as usize);
+    }
+    pub fn disable() {
+        let mut fragments = state().fragments.lock().unwrap();
+        *fragments = Fragments::default();
+    }
+    pub fn is_enabled() -> bool {
+        state().fragments.lock().unwrap().max_message_size != 0
+    }
+    /// The max. message length, the max. datagram size if disabled.
+    pub fn max_message_size() -> usize {
+        let max_message_size =
+            state().fragments.lock().unwrap().max_message_size;
+        max_message_size.max(MsgHeader::max_datagram_size())
+    }
+    /// The peer announced the fragmentation support.
+    pub fn supports(socket_addr: &SocketAddr) -> bool {
+        state()
+            .fragments
+            .lock()
+            .unwrap()
+            .peers
+            .contains_key(socket_addr)
+    }
+    /// Forget the peer and its partial messages, e.g. on disconnect.
+    pub fn remove(socket_addr: &SocketAddr) {
+        let mut fragments = state().fragments.lock().unwrap();
+        fragments.peers.remove(socket_addr);
+        fragments
+            .partials
+            .retain(|(addr, _), _| addr != socket_addr);
+    }
+
+    /// The datagram is a FRAGMENT message.
+    #[inline(always)]
+    pub fn is_fragment(buf: &[u8]) -> bool {
+        match Fragment::header_len(buf) {
+            Some(header_len) => buf[header_len - 1] == MSG_TYPE_FRAGMENT,
+            None => false,
+        }
+    }
+
+    /// Announce the support to the peer, e.g. a gateway after the CONNACK.
+    pub fn announce(
+        client: &MqttSnClient,
+        socket_addr: SocketAddr,
+    ) -> Result<(), String> {
+        let max_message_size = Fragment::max_message_size();
+        let mut bytes_buf = BytesMut::with_capacity(8);
+        Fragment::put_header(&mut bytes_buf, 0, 0, 0, 2)?;
+        put_u16_be(&mut bytes_buf, max_message_size as u16);
+        if let Err(why) = client.egress_tx.try_send((socket_addr, bytes_buf)) {
+            return Err(eformat!(socket_addr, why));
+        }
+        Ok(())
+    }
+
+    /// Store the fragment, returns the message once all its fragments are
+    /// received. A capability announcement is answered with the support
+    /// of the broker. Rejected if fragmentation is disabled.
+    pub fn recv(
+        client: &MqttSnClient,
+        socket_addr: SocketAddr,
+        buf: &[u8],
+    ) -> Result<Option<Bytes>, String> {
+        let header_len = match Fragment::header_len(buf) {
+            Some(header_len) => header_len,
+            None => return Err(eformat!(socket_addr, "invalid header")),
+        };
+        let offset = header_len + FRAGMENT_FIELDS_LEN - 2;
+        if !Fragment::is_enabled() {
+            return Err(eformat!(socket_addr, "fragmentation not supported"));
+        }
+        if buf.len() < offset + 2 {
+            return Err(eformat!(socket_addr, "fragment too short", buf.len()));
+        }
+        let id = get_u16_be(buf, header_len);
+        let index = buf[header_len + 2] as usize;
+        let count = buf[header_len + 3] as usize;
+        let data = &buf[offset + 2..];
+        let mut fragments = state().fragments.lock().unwrap();
+        if count == 0 {
+            // Capability announcement.
+            if data.len() < 2 {
+                return Err(eformat!(socket_addr, "invalid capability"));
+            }
+            let max_message_size = get_u16_be(data, 0) as usize;
+            let known = fragments
+                .peers
+                .insert(socket_addr, max_message_size)
+                .is_some();
+            drop(fragments);
+            if !known {
+                Fragment::announce(client, socket_addr)?;
+            }
+            return Ok(None);
+        }
+        if index >= count {
+            return Err(eformat!(socket_addr, "invalid index", index, count));
+        }
+        let max_message_size = fragments.max_message_size;
+        if !fragments.partials.contains_key(&(socket_addr, id)) {
+            let now = Instant::now();
+            fragments
+                .partials
+                .retain(|_, partial| now - partial.started < FRAGMENT_TIMEOUT);
+            if fragments.partials.len() >= FRAGMENT_MAX_PARTIAL {
+                return Err(eformat!(socket_addr, "too many partial messages"));
+            }
+            fragments.partials.insert(
+                (socket_addr, id),
+                Partial {
+                    parts: vec![None; count],
+                    received: 0,
+                    len: 0,
+                    started: now,
+                },
+            );
+        }
+        let partial = fragments.partials.get_mut(&(socket_addr, id)).unwrap();
+        if partial.parts.len() != count {
+            fragments.partials.remove(&(socket_addr, id));
+            return Err(eformat!(socket_addr, "count mismatch", id, count));
+        }
+        // A duplicate replaces the previous fragment.
+        if let Some(previous) = &partial.parts[index] {
+            partial.len -= previous.len();
+        } else {
+            partial.received += 1;
+        }
+        partial.len += data.len();
+        partial.parts[index] = Some(Bytes::copy_from_slice(data));
+        if partial.len > max_message_size {
+            fragments.partials.remove(&(socket_addr, id));
+            return Err(eformat!(socket_addr, "message too long", id));
+        }
+        if partial.received < count {
+            return Ok(None);
+        }
+        let partial = fragments.partials.remove(&(socket_addr, id)).unwrap();
+        let mut message = BytesMut::with_capacity(partial.len);
+        for part in partial.parts.into_iter().flatten() {
+            message.extend_from_slice(&part);
+        }
+        Ok(Some(message.freeze()))
+    }
+
+    /// The datagrams of the message to the peer, the message itself if it
+    /// fits in a datagram. Rejected if the peer doesn't support
+    /// fragmentation or the message is longer than its max.
+    pub fn split(
+        socket_addr: SocketAddr,
+        message: Bytes,
+    ) -> Result<Vec<Bytes>, String> {
+        let max_datagram_size = MsgHeader::max_datagram_size();
+        if message.len() <= max_datagram_size {
+            return Ok(vec![message]);
+        }
+        let id = {
+            let mut fragments = state().fragments.lock().unwrap();
+            match fragments.peers.get(&socket_addr) {
+                Some(max) if *max >= message.len() => {}
+                Some(_) => {
+                    return Err(eformat!(
+                        socket_addr,
+                        "message too long for the peer",
+                        message.len()
+                    ))
+                }
+                None => {
+                    return Err(eformat!(
+                        socket_addr,
+                        "fragmentation not supported by the peer",
+                        message.len()
+                    ))
+                }
+            }
+            fragments.next_id = fragments.next_id.wrapping_add(1);
+            fragments.next_id
+        };
+        let size = max_datagram_size.saturating_sub(FRAGMENT_MAX_HEADER_LEN);
+        let count = if size == 0 {
+            usize::MAX
+        } else {
+            (message.len() + size - 1) / size
+        };
+        if count > FRAGMENT_MAX_COUNT {
+            return Err(eformat!(socket_addr, "too many fragments", count));
+        }
+        message
+            .chunks(size)
+            .enumerate()
+            .map(|(index, data)| {
+                let mut bytes_buf =
+                    BytesMut::with_capacity(FRAGMENT_MAX_HEADER_LEN + size);
+                Fragment::put_header(
+                    &mut bytes_buf,
+                    id,
+                    index as u8,
+                    count as u8,
+                    data.len(),
+                )?;
+                bytes_buf.put_slice(data);
+                Ok(bytes_buf.freeze())
+            })
+            .collect()
+    }
+
+    #[inline(always)]
+    fn put_header(
+        bytes_buf: &mut BytesMut,
+        id: u16,
+        index: u8,
+        count: u8,
+        data_len: usize,
+    ) -> Result<(), String> {
+        // A fragment is never longer than a datagram.
+        let short_len = 2 + FRAGMENT_FIELDS_LEN + data_len;
+        if short_len < 256 {
+            bytes_buf.put_u8(short_len as u8);
+        } else {
+            bytes_buf.put_u8(1);
+            put_u16_be(bytes_buf, (short_len + 2) as u16);
+        }
+        bytes_buf.put_u8(MSG_TYPE_FRAGMENT);
+        put_u16_be(bytes_buf, id);
+        bytes_buf.put_u8(index);
+        bytes_buf.put_u8(count);
+        Ok(())
+    }
+
+    /// Length of the Length and MsgType fields if the length field matches
+    /// the datagram.
+    #[inline(always)]
+    fn header_len(buf: &[u8]) -> Option<usize> {
+        let (header_len, len) = match buf {
+            [1, high, low, _, ..] => {
+                (4, u16::from_be_bytes([*high, *low]) as usize)
+            }
+            [len, _, ..] if *len != 1 => (2, *len as usize),
+            _ => return None,
+        };
+        if len != buf.len() {
+            return None;
+        }
+        Some(header_len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_fragment() {
+        use super::*;
+        let context = BrokerContext::new();
+        let _context = context.enter();
+        let client = MqttSnClient::new().with_context(context);
+        let peer = "127.0.0.1:2600".parse::<SocketAddr>().unwrap();
+        let message =
+            Bytes::from((0..5000).map(|i| i as u8).collect::<Vec<u8>>());
+        // Disabled, the fragments and the announcements are rejected.
+        let mut capability = BytesMut::new();
+        Fragment::put_header(&mut capability, 0, 0, 0, 2).unwrap();
+        put_u16_be(&mut capability, 8192);
+        assert!(Fragment::is_fragment(&capability));
+        assert!(Fragment::recv(&client, peer, &capability).is_err());
+        assert_eq!(
+            Fragment::max_message_size(),
+            MsgHeader::max_datagram_size()
+        );
+        Fragment::enable(8192);
+        // The peer didn't announce its support.
+        assert!(Fragment::split(peer, message.clone()).is_err());
+        assert_eq!(Fragment::recv(&client, peer, &capability), Ok(None));
+        assert!(Fragment::supports(&peer));
+        // Answered with the capability of the broker.
+        let (addr, answer) = client.egress_rx.try_recv().unwrap();
+        assert_eq!((addr, answer.len()), (peer, 8));
+        let frames = Fragment::split(peer, message.clone()).unwrap();
+        assert_eq!(frames.len(), 4);
+        for frame in frames.iter() {
+            assert!(frame.len() <= MsgHeader::max_datagram_size());
+            assert!(Fragment::is_fragment(frame));
+        }
+        // Out of order, with a duplicate.
+        for index in [3, 1, 1, 0].iter() {
+            let result = Fragment::recv(&client, peer, &frames[*index]);
+            assert_eq!(result, Ok(None));
+        }
+        let result = Fragment::recv(&client, peer, &frames[2]);
+        assert_eq!(result, Ok(Some(message)));
+        // A message fitting in a datagram isn't fragmented.
+        let short = Bytes::from_static(b"short");
+        assert_eq!(Fragment::split(peer, short.clone()), Ok(vec![short]));
+        // Longer than the max. of the peer.
+        let long = Bytes::from(vec![0; 9000]);
+        assert!(Fragment::split(peer, long).is_err());
+        Fragment::disable();
+    }
+}
@@ -0,0 +1,237 @@
+/// Client side gateway discovery.
+///
+/// GatewayDiscovery::search() broadcasts a SEARCHGW message after a random
+/// delay up to SEARCH_GW_DELAY_MAX_MS, the clients starting together don't
+/// flood the network. The SEARCHGW is sent again while no gateway answered,
+/// the interval doubles from SEARCH_GW_RETRY_MS. The GWINFO responses are
+/// collected until the timeout, the responses of a gateway are aggregated,
+/// a GWINFO from the gateway itself replaces one relayed by a client. The
+/// gateways are returned ranked:
+/// 1. Responses sent by the gateway itself before responses relayed by
+///    other clients (with GwAdd).
+/// 2. Lower response time first.
+/// 3. More responses first.
+use bytes::{BufMut, BytesMut};
+use hashbrown::HashMap;
+use log::*;
+use rand::Rng;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::str;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{
+    eformat, function, multicast::multicast_socket, MSG_LEN_GW_INFO_HEADER,
+    MSG_LEN_SEARCH_GW, MSG_TYPE_GW_INFO, MSG_TYPE_SEARCH_GW,
+};
+
+pub const SEARCH_GW_DELAY_MAX_MS: u64 = 500;
+pub const SEARCH_GW_RETRY_MS: u64 = 1000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredGateway {
+    pub gw_id: u8,
+    /// Source address of the first GWINFO.
+    pub addr: SocketAddr,
+    /// GwAdd, only present if the GWINFO was sent by a client.
+    pub gw_addr: Option<String>,
+    /// Time from SEARCHGW to the first GWINFO.
+    pub response_time: Duration,
+    pub responses: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct GatewayDiscovery {}
+
+impl GatewayDiscovery {
+    /// Broadcast SEARCHGW and collect GWINFO responses until the timeout.
+    pub fn search(
+        multicast_addr: SocketAddr,
+        radius: u8,
+        timeout: Duration,
+    ) -> Result<Vec<DiscoveredGateway>, String> {
+        let socket = multicast_socket(&multicast_addr)
+            .map_err(|why| eformat!(multicast_addr, why))?;
+        let delay = rand::thread_rng().gen_range(0..=SEARCH_GW_DELAY_MAX_MS);
+        let delay = Duration::from_millis(delay).min(timeout);
+        thread::sleep(delay);
+        let timeout = timeout - delay;
+        let start = Instant::now();
+        GatewayDiscovery::send_search_gw(&socket, multicast_addr, radius)?;
+        let mut retry = Duration::from_millis(SEARCH_GW_RETRY_MS);
+        let mut next_search = start + retry;
+        let mut gw_map: HashMap<u8, DiscoveredGateway> = HashMap::new();
+        let mut buf = [0u8; 1400]; // receive buffer
+        while start.elapsed() < timeout {
+            if gw_map.is_empty() && Instant::now() >= next_search {
+                GatewayDiscovery::send_search_gw(
+                    &socket,
+                    multicast_addr,
+                    radius,
+                )?;
+                retry *= 2;
+                next_search = Instant::now() + retry;
+            }
+            match socket.recv_from(&mut buf) {
+                Ok((size, remote_addr)) => {
+                    let (gw_id, gw_addr) =
+                        match GatewayDiscovery::parse_gw_info(&buf[..size]) {
+                            Some(gw_info) => gw_info,
+                            None => {
+                                error!(
+                                    "{}",
+                                    eformat!(remote_addr, "not GWINFO")
+                                );
+                                continue;
+                            }
+                        };
+                    GatewayDiscovery::aggregate(
+                        &mut gw_map,
+                        gw_id,
+                        remote_addr,
+                        gw_addr,
+                        start.elapsed(),
+                    );
+                }
+                Err(err) => {
+                    // The socket read timeout, keep looping.
+                    if err.kind() != io::ErrorKind::WouldBlock
+                        && err.kind() != io::ErrorKind::TimedOut
+                    {
+                        return Err(eformat!(multicast_addr, err));
+                    }
+                }
+            }
+        }
+        Ok(GatewayDiscovery::rank(gw_map.into_iter().map(|(_, gw)| gw)))
+    }
+
+    fn send_search_gw(
+        socket: &UdpSocket,
+        multicast_addr: SocketAddr,
+        radius: u8,
+    ) -> Result<(), String> {
+        let mut bytes = BytesMut::with_capacity(MSG_LEN_SEARCH_GW as usize);
+        let buf: &[u8] = &[MSG_LEN_SEARCH_GW, MSG_TYPE_SEARCH_GW, radius];
+        bytes.put(buf);
+        match socket.send_to(&bytes[..], &multicast_addr) {
+            Ok(size) if size == bytes.len() => Ok(()),
+            Ok(size) => {
+                Err(eformat!(multicast_addr, "bytes sent", size, bytes.len()))
+            }
+            Err(why) => Err(eformat!(multicast_addr, why)),
+        }
+    }
+
+    /// Add a GWINFO to the responses of the gateway. The GWINFO of the
+    /// gateway itself replaces a GWINFO relayed by a client.
+    pub fn aggregate(
+        gw_map: &mut HashMap<u8, DiscoveredGateway>,
+        gw_id: u8,
+        addr: SocketAddr,
+        gw_addr: Option<String>,
+        response_time: Duration,
+    ) {
+        let gw = gw_map.entry(gw_id).or_insert(DiscoveredGateway {
+            gw_id,
+            addr,
+            gw_addr: gw_addr.clone(),
+            response_time,
+            responses: 0,
+        });
+        gw.responses += 1;
+        if gw.gw_addr.is_some() && gw_addr.is_none() {
+            gw.addr = addr;
+            gw.gw_addr = None;
+        }
+    }
+
+    /// Parse a GWINFO message, returns (gw_id, GwAdd).
+    pub fn parse_gw_info(buf: &[u8]) -> Option<(u8, Option<String>)> {
+        let header_len = MSG_LEN_GW_INFO_HEADER as usize;
+        if buf.len() < header_len
+            || buf[0] as usize != buf.len()
+            || buf[1] != MSG_TYPE_GW_INFO
+        {
+            return None;
+        }
+        let gw_addr = if buf.len() > header_len {
+            Some(str::from_utf8(&buf[header_len..]).ok()?.to_string())
+        } else {
+            None
+        };
+        Some((buf[2], gw_addr))
+    }
+
+    /// Sort the gateways, best first.
+    pub fn rank(
+        gateways: impl Iterator<Item = DiscoveredGateway>,
+    ) -> Vec<DiscoveredGateway> {
+        let mut gw_vec: Vec<DiscoveredGateway> = gateways.collect();
+        gw_vec.sort_by(|a, b| {
+            a.gw_addr
+                .is_some()
+                .cmp(&b.gw_addr.is_some())
+                .then(a.response_time.cmp(&b.response_time))
+                .then(b.responses.cmp(&a.responses))
+        });
+        gw_vec
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_gateway_discovery() {
+        use super::*;
+        assert_eq!(
+            GatewayDiscovery::parse_gw_info(&[3, MSG_TYPE_GW_INFO, 7]),
+            Some((7, None))
+        );
+        let mut buf = vec![0, MSG_TYPE_GW_INFO, 8];
+        buf.extend_from_slice(b"10.0.0.1:1883");
+        buf[0] = buf.len() as u8;
+        assert_eq!(
+            GatewayDiscovery::parse_gw_info(&buf[..]),
+            Some((8, Some("10.0.0.1:1883".to_string())))
+        );
+        // wrong length and message type.
+        assert_eq!(
+            GatewayDiscovery::parse_gw_info(&[4, MSG_TYPE_GW_INFO, 7]),
+            None
+        );
+        assert_eq!(GatewayDiscovery::parse_gw_info(&[3, 0, 7]), None);
+
+        let addr = "127.0.0.1:1883".parse::<SocketAddr>().unwrap();
+        let gw =
+            |gw_id, gw_addr: Option<String>, ms, responses| DiscoveredGateway {
+                gw_id,
+                addr,
+                gw_addr,
+                response_time: Duration::from_millis(ms),
+                responses,
+            };
+        let ranked = GatewayDiscovery::rank(
+            vec![
+                gw(1, Some("10.0.0.1:1883".to_string()), 1, 1),
+                gw(2, None, 20, 1),
+                gw(3, None, 5, 1),
+                gw(4, None, 5, 3),
+            ]
+            .into_iter(),
+        );
+        let ids: Vec<u8> = ranked.iter().map(|gw| gw.gw_id).collect();
+        assert_eq!(ids, vec![4, 3, 2, 1]);
+        // Relayed by a client, then answered by the gateway.
+        let client = "127.0.0.1:2000".parse::<SocketAddr>().unwrap();
+        let mut gw_map = HashMap::new();
+        let relayed = Some("10.0.0.1:1883".to_string());
+        let ms = Duration::from_millis;
+        GatewayDiscovery::aggregate(&mut gw_map, 5, client, relayed, ms(3));
+        GatewayDiscovery::aggregate(&mut gw_map, 5, addr, None, ms(9));
+        GatewayDiscovery::aggregate(&mut gw_map, 6, addr, None, ms(4));
+        assert_eq!(gw_map[&5], gw(5, None, 3, 2));
+        assert_eq!(gw_map[&6].responses, 1);
+    }
+}
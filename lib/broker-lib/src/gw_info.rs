@@ -13,16 +13,35 @@ id of the sending GW; otherwise, if sent by a client, it also includes the addre
 Like the SEARCHGW message the broadcast radius for this message is also indicated to the underlying
 network layer when MQTT-SN gives this message for transmission.
 */
+/// The gateway answers a SEARCHGW with GwInfoResponder: the GWINFO is sent
+/// after a random delay up to GW_INFO_DELAY_MAX_MS, the gateways answering
+/// the same SEARCHGW don't collide, and the radius of the SEARCHGW is the
+/// IP hop limit of the GWINFO. A client with a pending GWINFO isn't
+/// answered twice. A congested gateway doesn't answer, the client finds a
+/// gateway with room.
 use crate::{
-    broker_lib::MqttSnClient, eformat, function, msg_hdr::MsgHeader, multicast,
-    multicast::new_udp_socket, MSG_LEN_GW_INFO_HEADER, MSG_TYPE_GW_INFO,
+    broker_lib::MqttSnClient,
+    codec::{encode_packet, Packet},
+    decode::Decode,
+    eformat, function,
+    msg_hdr::MsgHeader,
+    multicast,
+    multicast::new_udp_socket,
+    search_gw::SearchGw,
 };
 use bytes::{BufMut, BytesMut};
 use custom_debug::Debug;
 use getset::{CopyGetters, Getters, MutGetters};
 use log::*;
+use rand::Rng;
 use std::net::SocketAddr;
 use std::str; // NOTE: needed for MutGetters
+use std::time::{Duration, Instant};
+
+/// Max random delay of a GWINFO.
+pub const GW_INFO_DELAY_MAX_MS: u64 = 500;
+/// Max number of pending GWINFO replies, the SEARCHGWs above are dropped.
+pub const GW_INFO_PENDING_MAX: usize = 256;
 
 #[derive(
     // NOTE: must include std::str for MutGetters
@@ -45,25 +64,33 @@ pub struct GwInfo {
     pub gw_addr: String,
 }
 impl GwInfo {
-    pub fn run(socket_addr: SocketAddr) {
-        multicast::gw_info_listen_loop(socket_addr);
+    pub fn run(socket_addr: SocketAddr, client: MqttSnClient) {
+        multicast::gw_info_listen_loop(socket_addr, client);
     }
+    /// Send a GWINFO with the hop limit of the radius, a gateway sends an
+    /// empty gw_addr.
     pub fn send(
         gw_id: u8,
         gw_addr: String,
         socket_addr: &SocketAddr,
+        radius: u8,
     ) -> Result<(), String> {
-        let len = MSG_LEN_GW_INFO_HEADER as usize + gw_addr.len() as usize;
-        if len > 255 {
-            return Err(format!("gw_addr too long: {}", len));
-        }
         // *NOTE*: this return value can be cached.
-        let mut bytes = BytesMut::with_capacity(len);
-        let buf: &[u8] = &[len as u8, MSG_TYPE_GW_INFO, gw_id];
-        bytes.put(buf);
-        bytes.put(gw_addr.as_bytes());
+        let bytes = encode_packet(&Packet::GwInfo {
+            gw_id,
+            gw_add: gw_addr.as_bytes(),
+        })?;
         dbg!(&bytes);
-        match new_udp_socket(socket_addr) {
+        let hop_limit = SearchGw::hop_limit(radius);
+        let socket = new_udp_socket(socket_addr).and_then(|udp_socket| {
+            if socket_addr.is_ipv4() {
+                udp_socket.set_ttl(hop_limit)?;
+            } else {
+                udp_socket.set_unicast_hops_v6(hop_limit)?;
+            }
+            Ok(udp_socket)
+        });
+        match socket {
             Ok(udp_socket) => {
                 match udp_socket
                     .send_to(&bytes[..], &socket2::SockAddr::from(*socket_addr))
@@ -85,7 +112,8 @@ impl GwInfo {
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
-        let (gw_info, _read_fixed_len) = GwInfo::try_read(buf, size).unwrap();
+        let (gw_info, _read_fixed_len) =
+            Decode::read(GwInfo::try_read(buf, size), size, &msg_header)?;
         info!(
             "{}: {} with {}",
             msg_header.remote_socket_addr, gw_info.gw_id, gw_info.gw_addr
@@ -93,3 +121,119 @@ impl GwInfo {
         Ok(())
     }
 }
+
+#[derive(Debug, Clone)]
+struct PendingGwInfo {
+    due: Instant,
+    remote_addr: SocketAddr,
+    radius: u8,
+}
+
+/// GWINFO replies of the gateway waiting for their random delay.
+#[derive(Debug, Clone)]
+pub struct GwInfoResponder {
+    gw_id: u8,
+    pending: Vec<PendingGwInfo>,
+}
+
+impl GwInfoResponder {
+    pub fn new(gw_id: u8) -> Self {
+        GwInfoResponder {
+            gw_id,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Schedule the GWINFO to a SEARCHGW, returns false if it isn't
+    /// answered.
+    pub fn schedule(
+        &mut self,
+        remote_addr: SocketAddr,
+        radius: u8,
+        congested: bool,
+    ) -> bool {
+        if congested {
+            info!("{}", eformat!(remote_addr, "congested, no GWINFO"));
+            return false;
+        }
+        if self.pending.len() >= GW_INFO_PENDING_MAX
+            || self
+                .pending
+                .iter()
+                .any(|pending| pending.remote_addr == remote_addr)
+        {
+            return false;
+        }
+        let delay = rand::thread_rng().gen_range(0..=GW_INFO_DELAY_MAX_MS);
+        self.pending.push(PendingGwInfo {
+            due: Instant::now() + Duration::from_millis(delay),
+            remote_addr,
+            radius,
+        });
+        true
+    }
+
+    /// Send the GWINFOs with their delay elapsed, returns their number.
+    pub fn send_due(&mut self) -> usize {
+        let now = Instant::now();
+        let (due, pending): (Vec<PendingGwInfo>, Vec<PendingGwInfo>) = self
+            .pending
+            .drain(..)
+            .partition(|pending| pending.due <= now);
+        self.pending = pending;
+        for gw_info in due.iter() {
+            if let Err(why) = GwInfo::send(
+                self.gw_id,
+                String::new(),
+                &gw_info.remote_addr,
+                gw_info.radius,
+            ) {
+                error!("{}", why);
+            }
+        }
+        due.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_gw_info_responder() {
+        use super::*;
+        use crate::gateway_discovery::GatewayDiscovery;
+        use std::net::UdpSocket;
+        use std::thread;
+        let searcher = UdpSocket::bind("127.0.0.1:0").unwrap();
+        searcher
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let remote_addr = searcher.local_addr().unwrap();
+        let mut responder = GwInfoResponder::new(9);
+        // A congested gateway doesn't answer.
+        assert!(!responder.schedule(remote_addr, 1, true));
+        assert!(responder.schedule(remote_addr, 1, false));
+        // Answered once.
+        assert!(!responder.schedule(remote_addr, 1, false));
+        assert_eq!(responder.len(), 1);
+        thread::sleep(Duration::from_millis(GW_INFO_DELAY_MAX_MS + 10));
+        assert_eq!(responder.send_due(), 1);
+        assert!(responder.is_empty());
+        let mut buf = [0u8; 64];
+        let (size, _gateway) = searcher.recv_from(&mut buf).unwrap();
+        // Sent by the gateway, without GwAdd.
+        assert_eq!(
+            GatewayDiscovery::parse_gw_info(&buf[..size]),
+            Some((9, None))
+        );
+        assert_eq!(SearchGw::hop_limit(0), 64);
+        assert_eq!(SearchGw::hop_limit(2), 2);
+    }
+}
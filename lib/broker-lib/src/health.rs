@@ -0,0 +1,286 @@
+/// Health and readiness of the broker over HTTP, for container probes.
+///
+/// The ingress, transmit, egress, keep-alive and retransmit workers hold a
+/// WorkerGuard from Health::start() while they run, the guard is dropped
+/// when a worker ends or panics. The time wheel threads beat every tick,
+/// a wheel without a beat for TIMER_STALL_MS is stalled. Health::serve()
+/// answers:
+///
+/// GET /health   200 unless a started worker ended or a wheel is stalled
+/// GET /ready    200 once the listeners are bound and all the workers run
+///
+/// with the HealthReport as JSON, e.g.
+///
+/// {"live":true,"ready":true,"listeners":["0.0.0.0:60000"],
+///  "workers":[{"name":"ingress","running":true,"idle_ms":null},...],
+///  "queues":{"ingress":0,"transmit":0,"egress":0,"egress_batch":0}}
+///
+/// 503 otherwise. Behind the "health" feature.
+use log::*;
+use serde::Serialize;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    broker_context::BrokerContext, broker_lib::MqttSnClient, eformat, function,
+    listener::Listeners,
+};
+
+/// A time wheel thread without a beat for longer is stalled.
+pub const TIMER_STALL_MS: u64 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Worker {
+    Ingress = 0,
+    Transmit,
+    Egress,
+    KeepAlive,
+    Retransmit,
+}
+
+const WORKER_LEN: usize = 5;
+
+/// In the Worker order.
+const WORKER_NAMES: [&str; WORKER_LEN] =
+    ["ingress", "transmit", "egress", "keep_alive", "retransmit"];
+
+/// Workers of a broker, see BrokerContext.
+#[derive(Debug, Default)]
+pub(crate) struct HealthState {
+    started: [AtomicBool; WORKER_LEN],
+    running: [AtomicBool; WORKER_LEN],
+    /// Unix time in ms of the last beat, 0 before the first one.
+    beats: [AtomicU64; WORKER_LEN],
+}
+
+#[inline(always)]
+fn state() -> &'static HealthState {
+    &BrokerContext::current().health
+}
+
+/// Marks the worker as ended when dropped.
+pub struct WorkerGuard {
+    state: &'static HealthState,
+    worker: Worker,
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        self.state.running[self.worker as usize]
+            .store(false, Ordering::Relaxed);
+        if thread::panicking() {
+            let name = WORKER_NAMES[self.worker as usize];
+            error!("{}", eformat!(name, "panicked"));
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WorkerReport {
+    pub name: &'static str,
+    pub running: bool,
+    /// ms since the last beat of a time wheel.
+    pub idle_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueueReport {
+    pub ingress: usize,
+    pub transmit: usize,
+    pub egress: usize,
+    pub egress_batch: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HealthReport {
+    pub live: bool,
+    pub ready: bool,
+    pub listeners: Vec<String>,
+    pub workers: Vec<WorkerReport>,
+    pub queues: QueueReport,
+}
+
+#[derive(Debug, Clone)]
+pub struct Health {}
+
+impl Health {
+    /// The worker runs until the guard is dropped. The context is passed,
+    /// the egress task doesn't enter it across an await.
+    pub fn start(
+        context: &'static BrokerContext,
+        worker: Worker,
+    ) -> WorkerGuard {
+        let state = &context.health;
+        state.started[worker as usize].store(true, Ordering::Relaxed);
+        state.running[worker as usize].store(true, Ordering::Relaxed);
+        WorkerGuard { state, worker }
+    }
+
+    /// A tick of a time wheel thread.
+    #[inline(always)]
+    pub fn beat(worker: Worker) {
+        state().beats[worker as usize].store(now_ms(), Ordering::Relaxed);
+    }
+
+    /// The report of the broker, in its context.
+    pub fn report(client: &MqttSnClient) -> HealthReport {
+        let _context = client.context.enter();
+        let state = state();
+        let now = now_ms();
+        let workers: Vec<WorkerReport> = WORKER_NAMES
+            .iter()
+            .enumerate()
+            .map(|(index, name)| {
+                let beat = state.beats[index].load(Ordering::Relaxed);
+                WorkerReport {
+                    name: *name,
+                    running: state.running[index].load(Ordering::Relaxed),
+                    idle_ms: match beat {
+                        0 => None,
+                        beat => Some(now.saturating_sub(beat)),
+                    },
+                }
+            })
+            .collect();
+        let stalled = workers
+            .iter()
+            .any(|worker| worker.idle_ms.unwrap_or(0) > TIMER_STALL_MS);
+        let ended = (0..WORKER_LEN).any(|index| {
+            state.started[index].load(Ordering::Relaxed)
+                && !state.running[index].load(Ordering::Relaxed)
+        });
+        let listeners: Vec<String> = Listeners::local_addrs()
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect();
+        let live = !ended && !stalled;
+        HealthReport {
+            live,
+            ready: live
+                && !listeners.is_empty()
+                && workers.iter().all(|worker| worker.running),
+            listeners,
+            workers,
+            queues: QueueReport {
+                ingress: client.ingress_rx.len(),
+                transmit: client.transmit_rx.len(),
+                egress: client.egress_rx.len(),
+                egress_batch: client.egress_batch_rx.len(),
+            },
+        }
+    }
+
+    /// Serve GET /health and GET /ready over HTTP.
+    pub fn serve(
+        socket_addr: SocketAddr,
+        client: MqttSnClient,
+    ) -> Result<(), String> {
+        let listener = match TcpListener::bind(socket_addr) {
+            Ok(listener) => listener,
+            Err(why) => return Err(eformat!(socket_addr, why)),
+        };
+        let builder = thread::Builder::new().name("health_http_thread".into());
+        let result = builder.spawn(move || {
+            let _context = client.context.enter();
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(why) = Health::handle_http(stream, &client) {
+                            error!("{}", why);
+                        }
+                    }
+                    Err(why) => {
+                        error!("{}", eformat!(socket_addr, why));
+                    }
+                }
+            }
+        });
+        match result {
+            Ok(_health_http_thread) => Ok(()),
+            Err(why) => Err(eformat!(socket_addr, why)),
+        }
+    }
+
+    fn handle_http(
+        mut stream: TcpStream,
+        client: &MqttSnClient,
+    ) -> Result<(), String> {
+        let mut buf = [0u8; 1024];
+        let size = stream.read(&mut buf).map_err(|why| eformat!(why))?;
+        let request = &buf[..size];
+        let response = if request.starts_with(b"GET /health ")
+            || request.starts_with(b"GET /ready ")
+        {
+            let report = Health::report(client);
+            let ok = if request.starts_with(b"GET /health ") {
+                report.live
+            } else {
+                report.ready
+            };
+            let status = if ok {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            let body =
+                serde_json::to_string(&report).map_err(|why| eformat!(why))?;
+            format!(
+                "HTTP/1.1 {}\r\n\
+                 Content-Type: application/json\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+        } else {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\
+             Connection: close\r\n\r\n"
+                .to_string()
+        };
+        stream
+            .write_all(response.as_bytes())
+            .map_err(|why| eformat!(why))
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_health_report() {
+        use super::*;
+        let client = MqttSnClient::new().with_context(BrokerContext::new());
+        let report = Health::report(&client);
+        assert!(report.live);
+        // No listener, no worker.
+        assert!(!report.ready);
+        assert_eq!(report.workers.len(), WORKER_LEN);
+        let guard = Health::start(client.context, Worker::Ingress);
+        {
+            let _context = client.context.enter();
+            Health::beat(Worker::KeepAlive);
+        }
+        let report = Health::report(&client);
+        assert!(report.live);
+        assert!(report.workers[Worker::Ingress as usize].running);
+        assert!(report.workers[Worker::KeepAlive as usize].idle_ms.is_some());
+        assert_eq!(report.workers[Worker::Egress as usize].idle_ms, None);
+        // The ingress worker ended.
+        drop(guard);
+        let report = Health::report(&client);
+        assert!(!report.live && !report.ready);
+        let json = serde_json::to_string(&report).unwrap();
+        assert!(json.contains("\"name\":\"ingress\",\"running\":false"));
+        assert!(json.contains("\"queues\":{\"ingress\":0,"));
+    }
+}
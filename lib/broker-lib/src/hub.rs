@@ -1,121 +1,311 @@
+/// DTLS and WebSocket connections of the broker.
+///
+/// The DTLS listener and WsTransport::serve() register a conn per peer, the
+/// messages go to the ingress channel and the replies go out through the
+/// conn, see MqttSnClient::handle_egress(): the raw UDP socket isn't used
+/// for a peer with a DTLS session. A new session from the same address
+/// replaces the old one. The conns are reference counted, Hub::get_conn()
+/// returns a clone and a conn in use by a sender isn't evicted.
+/// Hub::evict_idle() closes the conns idle for longer than max_idle without
+/// an MQTT-SN connection, e.g. after the keep-alive expired, see
+/// MqttSnClient::run_hub_eviction().
+///
+/// A DTLS session registered with Hub::register_with_lifetime() is closed
+/// by Hub::rekey() when its lifetime is over, the client handshakes again
+/// and the session gets new keys, DTLS 1.2 has no renegotiation. The
+/// MQTT-SN connection of the address is kept. Session resumption needs the
+/// session ids of the DTLS transport, every handshake is a full one.
+///
+/// The address of a conn closed by the hub stays secure until it registers
+/// again or for HUB_IDLE_TIMEOUT, Hub::is_secure(), the replies to it are
+/// dropped instead of going out in plaintext on the UDP socket. Its DTLS
+/// identity is forgotten, see DtlsAuth.
 use bytes::Bytes;
 use crossbeam::channel::Sender;
 use hashbrown::HashMap;
+use log::*;
 use std::io::{BufRead, BufReader};
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use util::Conn;
 
-use webrtc_dtls::Error;
-// use async_channel::*;
+use crate::{dtls_auth::DtlsAuth, eformat, function, recv_pool::RECV_POOL};
 
-const BUF_SIZE: usize = 8192;
+/// Idle time of a conn without an MQTT-SN connection before it's closed.
+pub const HUB_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+pub const HUB_EVICTION_INTERVAL: Duration = Duration::from_secs(10);
+/// Default lifetime of the keys of a DTLS session.
+pub const HUB_REKEY_INTERVAL: Duration = Duration::from_secs(3600);
+/// References of an idle conn, the hub and the read loop.
+const HUB_REFS: usize = 2;
+
+type HubConn = Arc<dyn Conn + Send + Sync>;
+
+struct HubEntry {
+    conn: HubConn,
+    /// Last message received from the peer.
+    last_seen: Instant,
+    /// The session is closed after it to renew its keys.
+    rekey_at: Option<Instant>,
+}
 
 /// Hub sends messages from ingress to processing channels.
 #[derive(Clone)]
 pub struct Hub {
-    channel_tx: Arc<Sender<(SocketAddr, Bytes, Arc<dyn Conn + Send + Sync>)>>,
-    conns: Arc<Mutex<HashMap<String, Arc<dyn Conn + Send + Sync>>>>,
+    channel_tx: Arc<Sender<(SocketAddr, Bytes, HubConn)>>,
+    conns: Arc<Mutex<HashMap<SocketAddr, HubEntry>>>,
+    /// Addresses of the closed conns and when they were closed.
+    closed: Arc<Mutex<HashMap<SocketAddr, Instant>>>,
+}
+
+#[inline(always)]
+fn same_conn(a: &HubConn, b: &HubConn) -> bool {
+    Arc::as_ptr(a) as *const u8 == Arc::as_ptr(b) as *const u8
 }
 
 impl Hub {
     /// new builds a new hub
-    pub fn new(
-        channel_tx: Arc<
-            Sender<(SocketAddr, Bytes, Arc<dyn Conn + Send + Sync>)>,
-        >,
-    ) -> Self {
-        // pub fn new() -> Self {
+    pub fn new(channel_tx: Arc<Sender<(SocketAddr, Bytes, HubConn)>>) -> Self {
         Hub {
             conns: Arc::new(Mutex::new(HashMap::new())),
+            closed: Arc::new(Mutex::new(HashMap::new())),
             channel_tx,
         }
     }
 
-    /// register adds a new conn to the Hub
-    pub async fn register(&self, conn: Arc<dyn Conn + Send + Sync>) {
-        println!("Connected to {}", conn.remote_addr().await.unwrap());
+    /// register adds a new conn to the Hub and starts its read loop, the
+    /// old conn of the address is closed.
+    pub async fn register(&self, conn: HubConn) {
+        self.register_session(conn, None).await
+    }
 
-        if let Some(remote_addr) = conn.remote_addr().await {
-            let mut conns = self.conns.lock().await;
-            conns.insert(remote_addr.to_string(), Arc::clone(&conn));
-        }
+    /// Register a DTLS session, it's closed by rekey() after lifetime.
+    pub async fn register_with_lifetime(
+        &self,
+        conn: HubConn,
+        lifetime: Duration,
+    ) {
+        let rekey_at = Instant::now() + lifetime;
+        self.register_session(conn, Some(rekey_at)).await
+    }
 
-        let conns = Arc::clone(&self.conns);
-        let channel_tx = Arc::clone(&self.channel_tx);
+    async fn register_session(&self, conn: HubConn, rekey_at: Option<Instant>) {
+        let remote_addr = match conn.remote_addr().await {
+            Some(remote_addr) => remote_addr,
+            None => {
+                error!("{}", eformat!("no remote address"));
+                return;
+            }
+        };
+        println!("Connected to {}", remote_addr);
+        let entry = HubEntry {
+            conn: Arc::clone(&conn),
+            last_seen: Instant::now(),
+            rekey_at,
+        };
+        let replaced = self.conns.lock().unwrap().insert(remote_addr, entry);
+        self.closed.lock().unwrap().remove(&remote_addr);
+        if let Some(old) = replaced {
+            let _result = old.conn.close().await;
+        }
+        let hub = self.clone();
         tokio::spawn(async move {
-            let _ = Hub::read_loop(
-                conn.remote_addr().await.unwrap(),
-                channel_tx,
-                conns,
-                conn,
-            )
-            .await;
+            hub.read_loop(remote_addr, conn).await;
         });
     }
 
-    /// register adds a new conn to the Hub
-    pub async fn get_conn(
-        &self,
-        socket_addr: SocketAddr,
-    ) -> Option<Arc<dyn Conn + Send + Sync>> {
-        let conns = self.conns.lock().await;
-        match conns.get(&socket_addr.to_string()) {
-            Some(conn) => Some(Arc::clone(conn)),
-            None => None,
+    /// The conn of the address, None for a UDP client.
+    pub fn get_conn(&self, socket_addr: SocketAddr) -> Option<HubConn> {
+        let conns = self.conns.lock().unwrap();
+        conns.get(&socket_addr).map(|entry| Arc::clone(&entry.conn))
+    }
+
+    pub fn contains(&self, socket_addr: &SocketAddr) -> bool {
+        self.conns.lock().unwrap().contains_key(socket_addr)
+    }
+
+    /// True if the address has a conn or had one closed by the hub less than
+    /// HUB_IDLE_TIMEOUT ago, its messages don't go to the UDP socket.
+    pub fn is_secure(&self, socket_addr: &SocketAddr) -> bool {
+        if self.contains(socket_addr) {
+            return true;
+        }
+        let closed = self.closed.lock().unwrap();
+        match closed.get(socket_addr) {
+            Some(closed_at) => closed_at.elapsed() < HUB_IDLE_TIMEOUT,
+            None => false,
         }
     }
 
-    async fn read_loop(
-        remote_addr: SocketAddr,
-        channel_tx: Arc<
-            Sender<(SocketAddr, Bytes, Arc<dyn Conn + Send + Sync>)>,
-        >,
-        conns: Arc<Mutex<HashMap<String, Arc<dyn Conn + Send + Sync>>>>,
-        conn: Arc<dyn Conn + Send + Sync>,
-    ) -> Result<(), Error> {
-        let mut b = vec![0u8; BUF_SIZE];
+    fn close_addr(&self, socket_addr: SocketAddr) {
+        DtlsAuth::remove(&socket_addr);
+        let mut closed = self.closed.lock().unwrap();
+        closed.retain(|_, closed_at| closed_at.elapsed() < HUB_IDLE_TIMEOUT);
+        closed.insert(socket_addr, Instant::now());
+    }
 
-        while let Ok(n) = conn.recv(&mut b).await {
-            let msg = String::from_utf8(b[..n].to_vec())?;
-            let bytes = Bytes::from(msg.to_owned());
-            let conn2 = Arc::clone(&conn);
-            // let result = channel_tx.send((remote_addr, bytes, conn2)).await;
-            let result = channel_tx.send((remote_addr, bytes, conn2));
-            dbg!(result);
-            print!("Got message: {}", msg);
+    /// References of the conn: the hub, the read loop and the senders.
+    pub fn refs(&self, socket_addr: &SocketAddr) -> usize {
+        let conns = self.conns.lock().unwrap();
+        conns
+            .get(socket_addr)
+            .map_or(0, |entry| Arc::strong_count(&entry.conn))
+    }
+
+    pub fn len(&self) -> usize {
+        self.conns.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conns.lock().unwrap().is_empty()
+    }
+
+    /// Close the conns idle for longer than max_idle, without a sender and
+    /// without an MQTT-SN connection. Returns their addresses.
+    pub async fn evict_idle<F>(
+        &self,
+        max_idle: Duration,
+        is_connected: F,
+    ) -> Vec<SocketAddr>
+    where
+        F: Fn(&SocketAddr) -> bool,
+    {
+        let idle: Vec<SocketAddr> = {
+            let conns = self.conns.lock().unwrap();
+            conns
+                .iter()
+                .filter(|(_, entry)| entry.last_seen.elapsed() >= max_idle)
+                .map(|(socket_addr, _)| *socket_addr)
+                .collect()
+        };
+        // Not under the conns lock, is_connected() locks the connections.
+        let idle: Vec<SocketAddr> = idle
+            .into_iter()
+            .filter(|socket_addr| !is_connected(socket_addr))
+            .collect();
+        let evicted: Vec<(SocketAddr, HubConn)> = {
+            let mut conns = self.conns.lock().unwrap();
+            idle.into_iter()
+                .filter(|socket_addr| match conns.get(socket_addr) {
+                    Some(entry) => {
+                        entry.last_seen.elapsed() >= max_idle
+                            && Arc::strong_count(&entry.conn) <= HUB_REFS
+                    }
+                    None => false,
+                })
+                .filter_map(|socket_addr| {
+                    let entry = conns.remove(&socket_addr)?;
+                    Some((socket_addr, entry.conn))
+                })
+                .collect()
+        };
+        let mut socket_addrs = Vec::with_capacity(evicted.len());
+        for (socket_addr, conn) in evicted {
+            if let Err(why) = conn.close().await {
+                error!("{}", eformat!(socket_addr, why));
+            }
+            self.close_addr(socket_addr);
+            info!("Evicted: {}", socket_addr);
+            socket_addrs.push(socket_addr);
         }
+        socket_addrs
+    }
 
-        Hub::unregister(conns, conn).await
+    /// Close the sessions past their lifetime, the clients handshake again
+    /// with new keys. Returns their addresses.
+    pub async fn rekey(&self) -> Vec<SocketAddr> {
+        let now = Instant::now();
+        let expired: Vec<(SocketAddr, HubConn)> = {
+            let mut conns = self.conns.lock().unwrap();
+            let socket_addrs: Vec<SocketAddr> = conns
+                .iter()
+                .filter(|(_, entry)| match entry.rekey_at {
+                    Some(rekey_at) => rekey_at <= now,
+                    None => false,
+                })
+                .map(|(socket_addr, _)| *socket_addr)
+                .collect();
+            socket_addrs
+                .into_iter()
+                .filter_map(|socket_addr| {
+                    let entry = conns.remove(&socket_addr)?;
+                    Some((socket_addr, entry.conn))
+                })
+                .collect()
+        };
+        let mut socket_addrs = Vec::with_capacity(expired.len());
+        for (socket_addr, conn) in expired {
+            if let Err(why) = conn.close().await {
+                error!("{}", eformat!(socket_addr, why));
+            }
+            self.close_addr(socket_addr);
+            info!("Rekey: {}", socket_addr);
+            socket_addrs.push(socket_addr);
+        }
+        socket_addrs
     }
 
-    async fn unregister(
-        conns: Arc<Mutex<HashMap<String, Arc<dyn Conn + Send + Sync>>>>,
-        conn: Arc<dyn Conn + Send + Sync>,
-    ) -> Result<(), Error> {
-        if let Some(remote_addr) = conn.remote_addr().await {
+    async fn read_loop(self, remote_addr: SocketAddr, conn: HubConn) {
+        loop {
+            let mut buf = RECV_POOL.take();
+            let n = match conn.recv(&mut buf).await {
+                Ok(n) => n,
+                Err(_) => break,
+            };
+            self.touch(remote_addr, &conn);
+            // The messages are parsed from the pool buffer without a copy.
+            let bytes: Bytes = RECV_POOL.freeze(buf, n);
+            let conn2 = Arc::clone(&conn);
+            if let Err(why) = self.channel_tx.send((remote_addr, bytes, conn2))
             {
-                let mut cs = conns.lock().await;
-                cs.remove(&remote_addr.to_string());
+                error!("{}", eformat!(remote_addr, why));
+                break;
             }
+        }
+        self.unregister(remote_addr, conn).await
+    }
 
-            if let Err(err) = conn.close().await {
-                println!(
-                    "Failed to disconnect: {} with err {}",
-                    remote_addr, err
-                );
-            } else {
-                println!("Disconnected: {} ", remote_addr);
+    fn touch(&self, remote_addr: SocketAddr, conn: &HubConn) {
+        let mut conns = self.conns.lock().unwrap();
+        if let Some(entry) = conns.get_mut(&remote_addr) {
+            if same_conn(&entry.conn, conn) {
+                entry.last_seen = Instant::now();
             }
         }
+    }
 
-        Ok(())
+    /// Remove the conn unless it was replaced or evicted.
+    async fn unregister(&self, remote_addr: SocketAddr, conn: HubConn) {
+        let removed = {
+            let mut conns = self.conns.lock().unwrap();
+            match conns.get(&remote_addr) {
+                Some(entry) if same_conn(&entry.conn, &conn) => {
+                    conns.remove(&remote_addr).is_some()
+                }
+                _ => false,
+            }
+        };
+        if !removed {
+            return;
+        }
+        self.close_addr(remote_addr);
+        if let Err(err) = conn.close().await {
+            println!("Failed to disconnect: {} with err {}", remote_addr, err);
+        } else {
+            println!("Disconnected: {} ", remote_addr);
+        }
     }
 
     async fn broadcast(&self, msg: &[u8]) {
-        let conns = self.conns.lock().await;
-        for conn in conns.values() {
+        let conns: Vec<HubConn> = {
+            let conns = self.conns.lock().unwrap();
+            conns
+                .values()
+                .map(|entry| Arc::clone(&entry.conn))
+                .collect()
+        };
+        for conn in conns {
             if let Err(err) = conn.send(msg).await {
                 println!(
                     "Failed to write message to {:?}: {}",
@@ -147,3 +337,106 @@ impl Hub {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "async-runtime")]
+    #[test]
+    fn test_hub_eviction() {
+        use super::*;
+        use crate::ws_transport::WsTransport;
+        use crate::MSG_TYPE_PINGREQ;
+        use crossbeam::channel::unbounded;
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::{connect_async, tungstenite::Message};
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (ingress_tx, ingress_rx) = unbounded();
+            let hub = Arc::new(Hub::new(Arc::new(ingress_tx)));
+            let listen_addr = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+            let addr = WsTransport::serve(listen_addr, Arc::clone(&hub))
+                .await
+                .unwrap();
+            let url = format!("ws://{}", addr);
+            let (mut ws_stream, _response) = connect_async(url).await.unwrap();
+            ws_stream
+                .send(Message::Binary(vec![2, MSG_TYPE_PINGREQ]))
+                .await
+                .unwrap();
+            let (remote_addr, _bytes, conn): (_, Bytes, _) =
+                tokio::task::spawn_blocking(move || ingress_rx.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+            drop(conn);
+            assert!(hub.contains(&remote_addr));
+            assert_eq!(hub.refs(&remote_addr), HUB_REFS);
+            let idle = Duration::from_secs(0);
+            // Used by a sender.
+            let sender = hub.get_conn(remote_addr).unwrap();
+            assert_eq!(hub.refs(&remote_addr), HUB_REFS + 1);
+            assert!(hub.evict_idle(idle, |_| false).await.is_empty());
+            drop(sender);
+            // The MQTT-SN connection is alive.
+            assert!(hub.evict_idle(idle, |_| true).await.is_empty());
+            assert!(hub
+                .evict_idle(HUB_IDLE_TIMEOUT, |_| false)
+                .await
+                .is_empty());
+            assert_eq!(
+                hub.evict_idle(idle, |_| false).await,
+                vec![remote_addr]
+            );
+            assert!(hub.get_conn(remote_addr).is_none());
+            assert!(hub.is_empty());
+            // No plaintext fallback for the evicted address.
+            assert!(hub.is_secure(&remote_addr));
+            // The peer sees the close.
+            match ws_stream.next().await {
+                Some(Ok(Message::Close(_))) | None => {}
+                other => panic!("{:?}", other),
+            }
+        });
+    }
+
+    #[cfg(feature = "async-runtime")]
+    #[test]
+    fn test_hub_rekey() {
+        use super::*;
+        use crate::ws_transport::WsConn;
+        use crossbeam::channel::unbounded;
+        use futures_util::StreamExt;
+        use tokio::net::TcpListener;
+        use tokio_tungstenite::{connect_async, tungstenite::Message};
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (ingress_tx, _ingress_rx) = unbounded();
+            let hub = Arc::new(Hub::new(Arc::new(ingress_tx)));
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}", listener.local_addr().unwrap());
+            let hub2 = Arc::clone(&hub);
+            let accepted = tokio::spawn(async move {
+                let (tcp_stream, _remote_addr) =
+                    listener.accept().await.unwrap();
+                let conn = WsConn::accept(tcp_stream).await.unwrap();
+                hub2.register_with_lifetime(
+                    Arc::new(conn),
+                    Duration::from_secs(0),
+                )
+                .await;
+            });
+            let (mut ws_stream, _response) = connect_async(url).await.unwrap();
+            accepted.await.unwrap();
+            assert_eq!(hub.len(), 1);
+            let rekeyed = hub.rekey().await;
+            assert_eq!(rekeyed.len(), 1);
+            assert!(hub.is_empty());
+            assert!(hub.rekey().await.is_empty());
+            // The client handshakes again.
+            match ws_stream.next().await {
+                Some(Ok(Message::Close(_))) | None => {}
+                other => panic!("{:?}", other),
+            }
+        });
+    }
+}
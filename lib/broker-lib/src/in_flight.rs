@@ -0,0 +1,233 @@
+/// In-flight window per client for QoS 1 and 2 PUBLISH messages.
+///
+/// Like the receive maximum of MQTT 5, at most InFlightLimits::window
+/// messages to a client wait for a PUBACK or PUBCOMP. Publish::send() and
+/// Publish::send_batch() queue the excess messages in a per-client outbound
+/// queue. InFlight::release() is called when the ACK arrives or the
+/// retransmit gives up, and sends the oldest queued message. The release of
+/// the last message ends the awake window of a sleeping client.
+use hashbrown::{HashMap, HashSet};
+use log::*;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use crate::{
+    broker_context::BrokerContext,
+    broker_lib::MqttSnClient,
+    eformat,
+    flags::{flag_qos_level, RETAIN_TRUE},
+    function,
+    msg_id::MsgIdAllocator,
+    ping_req::PingReq,
+    publish::Publish,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InFlightLimits {
+    /// Max. unacknowledged messages per client, 0 for no limit.
+    pub window: usize,
+    /// Max. queued messages per client, new messages are rejected.
+    pub max_queued: usize,
+}
+
+impl Default for InFlightLimits {
+    fn default() -> Self {
+        InFlightLimits {
+            window: 32,
+            max_queued: 256,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Window {
+    msg_ids: HashSet<u16>,
+    queue: VecDeque<Publish>,
+}
+
+impl Window {
+    /// Room for one more message, queued messages go first.
+    fn has_room(&self, limits: &InFlightLimits) -> bool {
+        self.queue.is_empty()
+            && (limits.window == 0 || self.msg_ids.len() < limits.window)
+    }
+}
+
+/// In-flight windows of a broker, see BrokerContext.
+#[derive(Default)]
+pub(crate) struct InFlightState {
+    in_flight: Mutex<HashMap<SocketAddr, Window>>,
+    limits: Mutex<InFlightLimits>,
+}
+
+#[inline(always)]
+fn state() -> &'static InFlightState {
+    &BrokerContext::current().in_flight
+}
+
+#[derive(Debug, Clone)]
+pub struct InFlight {}
+
+impl InFlight {
+    pub fn set_limits(limits: InFlightLimits) {
+        *state().limits.lock().unwrap() = limits;
+    }
+    pub fn limits() -> InFlightLimits {
+        *state().limits.lock().unwrap()
+    }
+
+    /// Allocate a msg_id for a new message, None if the window is full.
+    pub fn acquire(remote_addr: SocketAddr) -> Result<Option<u16>, String> {
+        let limits = InFlight::limits();
+        let mut map = state().in_flight.lock().unwrap();
+        let window = map.entry(remote_addr).or_insert_with(Window::default);
+        if !window.has_room(&limits) {
+            return Ok(None);
+        }
+        let msg_id = MsgIdAllocator::next(remote_addr)?;
+        window.msg_ids.insert(msg_id);
+        Ok(Some(msg_id))
+    }
+    /// Queue the message until the window has room, the msg_id is
+    /// allocated when it's sent.
+    pub fn enqueue(
+        remote_addr: SocketAddr,
+        publish: Publish,
+    ) -> Result<(), String> {
+        let limits = InFlight::limits();
+        let mut map = state().in_flight.lock().unwrap();
+        let window = map.entry(remote_addr).or_insert_with(Window::default);
+        if window.queue.len() >= limits.max_queued {
+            return Err(eformat!(
+                remote_addr,
+                "queue full",
+                window.queue.len()
+            ));
+        }
+        window.queue.push_back(publish);
+        Ok(())
+    }
+
+    /// The message is acknowledged or dropped, send the next queued message.
+    pub fn release(
+        remote_addr: SocketAddr,
+        msg_id: u16,
+        client: &MqttSnClient,
+    ) {
+        let (next, idle) = {
+            let mut map = state().in_flight.lock().unwrap();
+            let next = match map.get_mut(&remote_addr) {
+                Some(window) if window.msg_ids.remove(&msg_id) => {
+                    match window.queue.pop_front() {
+                        Some(publish) => {
+                            match MsgIdAllocator::next(remote_addr) {
+                                Ok(msg_id) => {
+                                    window.msg_ids.insert(msg_id);
+                                    Some((msg_id, publish))
+                                }
+                                Err(why) => {
+                                    window.queue.push_front(publish);
+                                    error!("{}", why);
+                                    None
+                                }
+                            }
+                        }
+                        None => None,
+                    }
+                }
+                _ => return,
+            };
+            let idle = map.get(&remote_addr).map_or(true, |window| {
+                window.msg_ids.is_empty() && window.queue.is_empty()
+            });
+            (next, idle)
+        };
+        // The last message of the awake window of a sleeping client.
+        if idle {
+            if let Err(why) = PingReq::try_sleep(remote_addr, client) {
+                error!("{}", why);
+            }
+        }
+        // Send without the IN_FLIGHT lock.
+        if let Some((msg_id, publish)) = next {
+            let flags = *publish.flags();
+            if let Err(why) = Publish::send_in_flight(
+                *publish.topic_id(),
+                msg_id,
+                flag_qos_level(flags),
+                flags & RETAIN_TRUE,
+                publish.data(),
+                client,
+                remote_addr,
+            ) {
+                error!("{}", why);
+            }
+        }
+    }
+
+    /// Number of unacknowledged messages to the client.
+    pub fn in_flight(remote_addr: &SocketAddr) -> usize {
+        let map = state().in_flight.lock().unwrap();
+        map.get(remote_addr)
+            .map_or(0, |window| window.msg_ids.len())
+    }
+    /// Number of messages waiting for room in the window.
+    pub fn queued(remote_addr: &SocketAddr) -> usize {
+        let map = state().in_flight.lock().unwrap();
+        map.get(remote_addr).map_or(0, |window| window.queue.len())
+    }
+    /// Drop the window and the queued messages of a removed connection.
+    pub fn remove(remote_addr: &SocketAddr) {
+        state().in_flight.lock().unwrap().remove(remote_addr);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_in_flight_window() {
+        use super::*;
+        use crate::flags::{QOS_LEVEL_1, RETAIN_FALSE};
+        use crate::retransmit::RetransTimeWheel;
+        use bytes::BytesMut;
+        RetransTimeWheel::init();
+        let client = MqttSnClient::new();
+        let addr = "127.0.0.1:1700".parse::<SocketAddr>().unwrap();
+        let window = InFlightLimits {
+            window: 2,
+            max_queued: 1,
+        };
+        // Not with set_limits(), the other tests use the default limits.
+        let mut map = state().in_flight.lock().unwrap();
+        let entry = map.entry(addr).or_insert_with(Window::default);
+        assert!(entry.has_room(&window));
+        entry.msg_ids.insert(1);
+        entry.msg_ids.insert(2);
+        assert!(!entry.has_room(&window));
+        drop(map);
+        assert_eq!(InFlight::in_flight(&addr), 2);
+        let publish = Publish::new(
+            5,
+            0,
+            QOS_LEVEL_1,
+            RETAIN_FALSE,
+            BytesMut::from(&b"queued"[..]),
+        );
+        InFlight::enqueue(addr, publish).unwrap();
+        assert_eq!(InFlight::queued(&addr), 1);
+        // Queued messages go first.
+        assert_eq!(InFlight::acquire(addr), Ok(None));
+        // The ACK of msg_id 1 sends the queued message.
+        InFlight::release(addr, 1, &client);
+        assert_eq!(InFlight::queued(&addr), 0);
+        assert_eq!(InFlight::in_flight(&addr), 2);
+        let (_, bytes) = client.egress_batch_rx.try_recv().unwrap();
+        assert_eq!(&bytes[bytes.len() - 6..], b"queued");
+        // Unknown msg_id, nothing is released.
+        InFlight::release(addr, 9, &client);
+        assert_eq!(InFlight::in_flight(&addr), 2);
+        InFlight::remove(&addr);
+        assert_eq!(InFlight::in_flight(&addr), 0);
+    }
+}
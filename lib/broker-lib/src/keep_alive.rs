@@ -1,13 +1,32 @@
+/// Keep-alive and sleep timers of the clients.
+///
+/// A client is LOST when it sends nothing during the tolerance of its
+/// keep alive duration, KeepAliveGrace::timeout(). The MQTT-SN 1.2 spec
+/// suggests 1.5 times the duration above one minute and 10 seconds more
+/// below, the default. The client stays in the connection table as LOST,
+/// only then its will is published and its subscriptions are purged, see
+/// KeepAliveTimeWheel::expire(). A CONNECT makes it ACTIVE again.
 use crate::{
-    broker_lib::MqttSnClient, connection::Connection, connection::StateEnum2,
-    eformat, function,
+    broker_context::BrokerContext,
+    broker_lib::MqttSnClient,
+    clock::Clock,
+    connection::Connection,
+    connection::{ConnEvent, StateEnum2},
+    eformat,
+    filter::delete_subscribers_with_socket_addr,
+    function,
+    in_flight::InFlight,
+    metrics::{Counter, Metrics},
+    ping_req::PingReq,
+    register_on_demand::RegisterOnDemand,
+    retransmit::RetransTimeWheel,
+    timer_wheel::{TimerWheel, TimerWheelConfig, TIMER_WHEEL_RANGE},
 };
 use core::fmt::Debug;
 use core::hash::Hash;
-use hashbrown::HashMap;
 use log::*;
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
@@ -21,146 +40,207 @@ pub struct KeepAliveKey {
 #[derive(Debug, Clone)]
 struct KeepAliveVal {
     latest_counter: usize,
-    conn_duration: u16,
+    /// Keep-alive duration in ticks.
+    conn_duration: usize,
+    /// Counter of the probe timeout, set when the PINGREQ probe is sent.
+    probe_deadline: Option<usize>,
 }
 
-#[derive(Debug, Clone)]
-struct Slot {
-    pub entries: Arc<Mutex<Vec<SocketAddr>>>,
+#[derive(Debug, Clone, PartialEq)]
+enum SlotAction {
+    /// Not expired, the new counter.
+    Reschedule(usize),
+    /// Expired, send a PINGREQ and wait until the counter.
+    Probe(usize),
+    Expire,
 }
 
-impl Slot {
-    pub fn new() -> Self {
-        Slot {
-            entries: Arc::new(Mutex::new(Vec::new())),
+impl KeepAliveVal {
+    /// Counter of the expiration, or of the end of the probe.
+    fn deadline(&self) -> usize {
+        match self.probe_deadline {
+            Some(deadline) => deadline,
+            None => self.latest_counter + self.conn_duration,
+        }
+    }
+    /// Action when the entry is popped from its slot.
+    fn on_timeout(
+        &mut self,
+        cur_counter: usize,
+        probe_slots: usize,
+    ) -> SlotAction {
+        let deadline = self.deadline();
+        if deadline > cur_counter {
+            SlotAction::Reschedule(deadline)
+        } else if probe_slots > 0 && self.probe_deadline.is_none() {
+            let deadline = cur_counter + probe_slots;
+            self.probe_deadline = Some(deadline);
+            SlotAction::Probe(deadline)
+        } else {
+            SlotAction::Expire
         }
     }
 }
 
-static SLEEP_DURATION: usize = 100;
-static MAX_SLOT: usize = (1000 / SLEEP_DURATION) * 64 * 2;
+pub const MAX_PROBE_TIMEOUT: u16 = 60;
 
-// TODO use lazy_static for easy access from any code without
-// attaching to a structure.
-lazy_static! {
-    static ref CURRENT_COUNTER: AtomicU64 = AtomicU64::new(0);
-    static ref SLOT_VEC: Mutex<Vec<Slot>> =
-        Mutex::new(Vec::with_capacity(MAX_SLOT));
-    static ref TIME_WHEEL_MAP: Mutex<HashMap<SocketAddr, KeepAliveVal>> =
-        Mutex::new(HashMap::new());
+/// Tolerance of the keep alive and sleep durations of the clients.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeepAliveGrace {
+    /// Factor of a duration longer than short_secs, at least 1.
+    pub multiplier: f32,
+    /// Seconds added to a duration up to short_secs.
+    pub extra_secs: u16,
+    pub short_secs: u16,
 }
 
-// TODO only for retransmit timing wheel.
-// The initial duration is set to TIME_WHEEL_INIT_DURATION, but can be
-// changed to reflect the network the client is on, (LAN or WAN),
-// or the latency pattern.
+impl Default for KeepAliveGrace {
+    fn default() -> Self {
+        KeepAliveGrace {
+            multiplier: 1.5,
+            extra_secs: 10,
+            short_secs: 60,
+        }
+    }
+}
 
-// clients use 100 milli seconds
-// brokers use 10 milli seconds
-/// static TIME_WHEEL_SLEEP_DURATION:usize = 100; // in milli seconds
-// For 64 seconds at 100 ms sleeping interval, we need 10 * 64 slots, (1000/100 = 10)
-// For 64 seconds at 10 ms sleeping interval, we need 100 * 64 slots, (1000/10 = 100)
+impl KeepAliveGrace {
+    /// No tolerance, the client is LOST at its duration.
+    pub fn exact() -> Self {
+        KeepAliveGrace {
+            multiplier: 1.0,
+            extra_secs: 0,
+            short_secs: 0,
+        }
+    }
+    /// Seconds without a message before the client is LOST.
+    pub fn timeout(&self, conn_duration: u16) -> Duration {
+        let secs = if conn_duration > self.short_secs {
+            (conn_duration as f32 * self.multiplier.max(1.0)) as u64
+        } else {
+            conn_duration as u64 + self.extra_secs as u64
+        };
+        Duration::from_secs(secs)
+    }
+}
+
+/// Keep-alive time wheel of a broker, see BrokerContext.
+pub(crate) struct KeepAliveState {
+    time_wheel: Mutex<TimerWheel<SocketAddr, KeepAliveVal>>,
+    /// Seconds to wait for the PINGRESP of the probe, 0 disables the probe.
+    probe_timeout: AtomicU16,
+    grace: Mutex<KeepAliveGrace>,
+}
+
+impl Default for KeepAliveState {
+    fn default() -> Self {
+        KeepAliveState {
+            time_wheel: Mutex::new(
+                TimerWheel::new(TimerWheelConfig::default()),
+            ),
+            probe_timeout: AtomicU16::new(0),
+            grace: Mutex::new(KeepAliveGrace::default()),
+        }
+    }
+}
 
-// The maximum timeout duration is 64 seconds
-// The last one might be over 64, rounding up.
-/// static TIME_WHEEL_MAX_SLOTS:usize = (1000 / TIME_WHEEL_SLEEP_DURATION) * 64 * 2;
-// Initial timeout duration is 300 ms
-// static TIME_WHEEL_DEFAULT_DURATION_MS: usize = 300;
+#[inline(always)]
+fn state() -> &'static KeepAliveState {
+    &BrokerContext::current().keep_alive
+}
 
 /// Timing wheel for keep alive.
-/// The wheel is divided into MAX_SLOT slots.
-/// Each slot is a vector of SocketAddr.
-/// The data is stored in a HashMap indexed by the SocketAddr.
+/// The timers of the TimerWheel are indexed by the SocketAddr, the
+/// counters of KeepAliveVal are ticks of the wheel.
 pub struct KeepAliveTimeWheel {}
 
 impl KeepAliveTimeWheel {
+    /// Set the default tick and precision.
     pub fn init() {
-        let mut slot_vec = SLOT_VEC.lock().unwrap();
-        for _ in 0..MAX_SLOT {
-            slot_vec.push(Slot::new());
-        }
+        KeepAliveTimeWheel::init_with(TimerWheelConfig::default());
+    }
+    /// Set the tick and the precision of the wheel, call before run().
+    pub fn init_with(config: TimerWheelConfig) {
+        state().time_wheel.lock().unwrap().set_config(config);
+    }
+    /// Replace the clock of the wheel, e.g. a MockClock in a test.
+    pub fn set_clock(clock: Arc<dyn Clock>) {
+        state().time_wheel.lock().unwrap().set_clock(clock);
+    }
+    /// Send a PINGREQ to an expired ACTIVE client and wait timeout_sec for
+    /// any message before the lost connection procedure, 0 disables it.
+    pub fn set_probe_timeout(timeout_sec: u16) {
+        state()
+            .probe_timeout
+            .store(timeout_sec.min(MAX_PROBE_TIMEOUT), Ordering::Relaxed);
+    }
+    pub fn probe_timeout() -> u16 {
+        state().probe_timeout.load(Ordering::Relaxed)
     }
-    /// Schedule a keep alive event for a connection.
-    /// Insert the connection address(key) into the corresponding slot.
-    /// Insert data into the TIME_WHEEL_MAP.
+    /// Set the tolerance of the durations scheduled after the call.
+    pub fn set_grace(grace: KeepAliveGrace) {
+        *state().grace.lock().unwrap() = grace;
+    }
+    pub fn grace() -> KeepAliveGrace {
+        *state().grace.lock().unwrap()
+    }
+    /// Schedule a keep alive event for a connection, conn_duration is in
+    /// seconds, the timer expires after its tolerance. 0 disables the keep
+    /// alive, the timer waits for the range of the wheel.
     #[inline(always)]
     // #[trace_var(index, slot, hash)]
     pub fn schedule(key: SocketAddr, conn_duration: u16) -> Result<(), String> {
-        // store the key in a slot of the timing wheel
-        // TODO XXX change value 10 to a constant
-        let conn_duration = conn_duration * 10;
-        let cur_counter = CURRENT_COUNTER.load(Ordering::Relaxed) as usize;
-        let index = (cur_counter + conn_duration as usize) % MAX_SLOT;
-        match TIME_WHEEL_MAP.try_lock() {
-            Ok(mut time_wheel_map) => {
-                time_wheel_map.insert(
+        let timeout = KeepAliveTimeWheel::grace().timeout(conn_duration);
+        match state().time_wheel.try_lock() {
+            Ok(mut time_wheel) => {
+                let conn_duration = match conn_duration {
+                    0 => TIMER_WHEEL_RANGE,
+                    _ => time_wheel.ticks(timeout),
+                };
+                let cur_counter = time_wheel.now() as usize;
+                time_wheel.insert(
                     key,
+                    conn_duration,
                     KeepAliveVal {
                         latest_counter: cur_counter,
-                        conn_duration: conn_duration,
+                        conn_duration: conn_duration as usize,
+                        probe_deadline: None,
                     },
                 );
+                Ok(())
             }
-            Err(why) => {
-                return Err(eformat!(why.to_string()));
-            }
+            Err(why) => Err(eformat!(why.to_string())),
         }
-        match SLOT_VEC.try_lock() {
-            Ok(mut slot_vec) => {
-                let slot = &mut slot_vec[index];
-                match slot.entries.try_lock() {
-                    Ok(mut entries) => {
-                        entries.push(key);
-                    }
-                    Err(why) => {
-                        // unwind: remove the inserted key from the time_wheel_map
-                        if let None =
-                            TIME_WHEEL_MAP.lock().unwrap().remove(&key)
-                        {
-                            return Err(eformat!("key not found"));
-                        }
-                        return Err(eformat!(why.to_string()));
-                    }
-                }
-            }
-            Err(why) => {
-                // unwind: remove the inserted key from the time_wheel_map
-                if let None = TIME_WHEEL_MAP.lock().unwrap().remove(&key) {
-                    return Err(eformat!("key not found"));
-                }
-                return Err(eformat!(why.to_string()));
-            }
-        }
-        return Ok(());
     }
     /// Cancel a keep alive event.
     /// Call when it received a DISCONNECT message from the sender.
     #[inline(always)]
     #[trace_var(index, slot, hash, vec)]
     pub fn cancel(socket_addr: &SocketAddr) -> Result<(), String> {
-        match TIME_WHEEL_MAP.try_lock() {
-            Ok(mut time_wheel_map) => {
-                match time_wheel_map.remove(socket_addr) {
-                    Some(_) => Ok(()),
-                    None => Err(eformat!(socket_addr)),
-                }
-            }
+        match state().time_wheel.try_lock() {
+            Ok(mut time_wheel) => match time_wheel.cancel(socket_addr) {
+                Some(_) => Ok(()),
+                None => Err(eformat!(socket_addr)),
+            },
             Err(why) => Err(eformat!(socket_addr, why.to_string())),
         }
     }
     /// Reschedule a keep alive event when it received a message from the sender.
-    /// Modify the latest_counter in the TIME_WHEEL_MAP to the current counter.
+    /// Modify the latest_counter of the timer to the current tick, the
+    /// timer is moved when it expires.
     #[inline(always)]
     #[trace_var(index, slot, hash, vec)]
     pub fn reschedule(socket_addr: SocketAddr) -> Result<(), String> {
-        let latest_counter = CURRENT_COUNTER.load(Ordering::Relaxed) as usize;
-        match TIME_WHEEL_MAP.try_lock() {
-            Ok(mut time_wheel_map) => {
-                match time_wheel_map.get_mut(&socket_addr) {
+        match state().time_wheel.try_lock() {
+            Ok(mut time_wheel) => {
+                let latest_counter = time_wheel.now() as usize;
+                match time_wheel.get_mut(&socket_addr) {
                     Some(conn) => {
                         dbg!(&conn);
                         dbg!(&latest_counter);
                         conn.latest_counter = latest_counter;
+                        // The client answered the probe.
+                        conn.probe_deadline = None;
                         dbg!(&latest_counter);
                         dbg!(&conn);
                         Ok(())
@@ -171,99 +251,255 @@ impl KeepAliveTimeWheel {
             Err(why) => Err(eformat!(socket_addr, why.to_string())),
         }
     }
+    /// Time left before the keep alive of the connection expires, or before
+    /// the end of the PINGREQ probe. None without a keep alive timer or if
+    /// the keep alive is disabled.
+    pub fn remaining(socket_addr: &SocketAddr) -> Option<Duration> {
+        let time_wheel = state().time_wheel.lock().unwrap();
+        let conn = time_wheel.get(socket_addr)?;
+        if conn.conn_duration as u64 >= TIMER_WHEEL_RANGE {
+            return None;
+        }
+        let ticks = conn.deadline().saturating_sub(time_wheel.now() as usize);
+        Some(time_wheel.tick() * ticks as u32)
+    }
+    /// Lost connection procedure, MQTT-SN 1.2 spec page 25, at the end of
+    /// the tolerance. The client is moved to the LOST state, the will is
+    /// published, and the subscriptions, filters and pending retransmits
+    /// are removed. The connection is kept for a CONNECT from the client.
+    pub fn expire(
+        socket_addr: SocketAddr,
+        client: &MqttSnClient,
+    ) -> Result<(), String> {
+        Metrics::inc(Counter::KeepAliveExpirations);
+        client.events.on_keepalive_expired(socket_addr);
+        Connection::transition(
+            &socket_addr,
+            ConnEvent::Expire,
+            &*client.events,
+        )?;
+        let result = Connection::publish_will(&socket_addr, client);
+        Connection::save_session(&socket_addr);
+        delete_subscribers_with_socket_addr(&socket_addr);
+        let canceled = RetransTimeWheel::cancel_all(socket_addr);
+        InFlight::remove(&socket_addr);
+        RegisterOnDemand::remove(&socket_addr);
+        info!("Connection Timeout: {:?} {}", socket_addr, canceled);
+        result
+    }
     /// When the address(key) is expired in the timing wheel, it compare the latest_counter
     /// with the current counter. If the latest_counter is less than the current counter,
     /// the address(key) is expired. Otherwise, put it back to a new slot.
     pub fn run(client: MqttSnClient) {
-        // When the keep_alive timing wheel entry is accessed,
-        // this code determines if the connection is expired.
-        // If the hash entry has been updated to a new counter,
-        // then reschedule the connection in the timing wheel.
-        //
         // TODO replace lock with try_lock
         let _keep_alive_expire_thread = thread::spawn(move || {
+            let _context = client.context.enter();
+            #[cfg(feature = "health")]
+            let _worker = crate::health::Health::start(
+                client.context,
+                crate::health::Worker::KeepAlive,
+            );
             loop {
+                #[cfg(feature = "health")]
+                crate::health::Health::beat(crate::health::Worker::KeepAlive);
                 // The sleep() has to be outside of the mutex lock block for
                 // the lock to be unlocked while the thread is sleeping.
-                thread::sleep(Duration::from_millis(SLEEP_DURATION as u64));
-                {
-                    let cur_counter: usize;
-                    cur_counter = CURRENT_COUNTER
-                        .fetch_add(1, Ordering::Relaxed)
-                        as usize;
-                    let index = cur_counter % MAX_SLOT;
-                    // dbg!(&cur_slot);
-                    // dbg!(cur_counter);
-                    let slot_vec = SLOT_VEC.lock().unwrap();
-                    let mut slot = slot_vec[index].entries.lock().unwrap();
-                    let mut time_wheel_map = TIME_WHEEL_MAP.lock().unwrap();
-                    // process the expired connections
-                    while let Some(socket_addr) = slot.pop() {
-                        dbg!(index);
-                        dbg!(socket_addr);
-                        if let Some(conn) = time_wheel_map.get(&socket_addr) {
-                            dbg!(&conn);
-                            let new_counter = conn.latest_counter as usize
-                                + conn.conn_duration as usize;
-                            dbg!(&conn);
-                            if new_counter > cur_counter {
-                                // Not expired, reschedule
-                                // The new duration starts from the latest_counter,
-                                // not the cur_counter. Subtract cur_counter is needed.
-                                let mut new_index = new_counter % MAX_SLOT;
-                                dbg!(&conn);
-                                if new_index == index {
-                                    // Can't lock the same slot twice
-                                    // Even without lock, push() to the same slot will be popped
-                                    // in the while loop, so it's an infinite loop.
-                                    // Use the next slot instead.
-                                    new_index = (index + 1) % MAX_SLOT;
-                                }
-                                let mut new_slot =
-                                    slot_vec[new_index].entries.lock().unwrap();
-                                new_slot.push(socket_addr);
-                            } else {
-                                // Client timeout, move from ACTIVE to LOST state.
-                                // MQTT-SN 1.2 spec page 25
-                                // The entry was pop() from the timing wheel slot.
-                                //    client_reschedule.set_state(STATE_LOST);
-                                // Remove it from the hashmap.
-                                // TODO XXX change connection state to LOST.
-                                // remove socket_add from keep alive HashMap
-                                if let Some(conn) =
-                                    time_wheel_map.remove(&socket_addr)
-                                {
-                                    dbg!(&conn);
-                                    dbg!(&time_wheel_map);
-                                    dbg!(&socket_addr);
-                                    match Connection::update_state(
-                                        &socket_addr,
-                                        StateEnum2::LOST,
-                                    ) {
-                                        Ok(_) => {
-                                            let _result =
-                                                Connection::publish_will(
-                                                    &socket_addr,
-                                                    &client,
-                                                );
-                                        }
-                                        Err(why) => {
-                                            error!(
-                                                "{}",
-                                                eformat!(
-                                                    socket_addr,
-                                                    why.to_string()
-                                                )
-                                            );
-                                        }
-                                    }
-                                }
-                                info!("Connection Timeout: {:?}", socket_addr);
+                let tick = state().time_wheel.lock().unwrap().tick();
+                thread::sleep(tick);
+                KeepAliveTimeWheel::poll(&client);
+            }
+        });
+    }
+    /// Process the ticks elapsed on the clock of the wheel, run() calls it
+    /// every tick.
+    pub fn poll(client: &MqttSnClient) {
+        // When the keep_alive timing wheel entry is accessed,
+        // this code determines if the connection is expired.
+        // If the hash entry has been updated to a new counter,
+        // then reschedule the connection in the timing wheel.
+        //
+        // Expired connections, processed after the locks are
+        // released, publishing the will locks other maps.
+        let mut expired = Vec::new();
+        let mut probes = Vec::new();
+        {
+            let mut time_wheel = state().time_wheel.lock().unwrap();
+            let probe_slots = time_wheel.ticks(Duration::from_secs(
+                KeepAliveTimeWheel::probe_timeout() as u64,
+            )) as usize;
+            while time_wheel.is_due() {
+                let cur_counter = time_wheel.now() as usize;
+                // process the expired connections
+                for (socket_addr, mut conn) in time_wheel.advance() {
+                    dbg!(socket_addr);
+                    dbg!(&conn);
+                    let new_counter =
+                        match conn.on_timeout(cur_counter, probe_slots) {
+                            // Not expired, reschedule
+                            // The new duration starts from the
+                            // latest_counter, not the cur_counter.
+                            SlotAction::Reschedule(new_counter) => new_counter,
+                            SlotAction::Probe(new_counter) => {
+                                probes.push(socket_addr);
+                                new_counter
+                            }
+                            SlotAction::Expire => {
+                                // Client timeout, move from ACTIVE to
+                                // LOST state. The timer was removed
+                                // from the wheel.
+                                expired.push(socket_addr);
+                                continue;
                             }
-                        }
+                        };
+                    time_wheel.insert_at(socket_addr, new_counter as u64, conn);
+                }
+            }
+        }
+        for socket_addr in probes {
+            match Connection::get_state(&socket_addr) {
+                Ok(StateEnum2::ACTIVE) => {
+                    // Any message from the client reschedules it.
+                    if let Err(why) = PingReq::probe(client, socket_addr) {
+                        error!("{}", why);
                     }
                 }
+                _ => {
+                    // Sleeping clients aren't probed.
+                    let _result = KeepAliveTimeWheel::cancel(&socket_addr);
+                    expired.push(socket_addr);
+                }
             }
-        });
+        }
+        for socket_addr in expired {
+            if let Err(why) = KeepAliveTimeWheel::expire(socket_addr, client) {
+                error!("{}", why);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_keep_alive_expire() {
+        use super::*;
+        use crate::filter::{
+            get_subscribers_with_topic_id, insert_filter,
+            match_concrete_topics, subscribe_with_topic_id,
+            try_insert_topic_name,
+        };
+        use crate::flags::{CLEAN_SESSION_TRUE, QOS_LEVEL_1};
+        use crate::MSG_TYPE_PUBACK;
+        use bytes::Bytes;
+        RetransTimeWheel::init();
+        let client = MqttSnClient::new();
+        let addr = "127.0.0.1:2100".parse::<SocketAddr>().unwrap();
+        Connection::try_insert(
+            addr,
+            CLEAN_SESSION_TRUE,
+            1,
+            10,
+            Bytes::from("expire"),
+        )
+        .unwrap();
+        let topic = "keep_alive/expire".to_string();
+        let topic_id = try_insert_topic_name(topic.clone()).unwrap();
+        subscribe_with_topic_id(addr, topic_id, QOS_LEVEL_1).unwrap();
+        insert_filter(topic.clone(), addr).unwrap();
+        RetransTimeWheel::schedule_timer(addr, MSG_TYPE_PUBACK, 0, 5, 1, "x")
+            .unwrap();
+        KeepAliveTimeWheel::expire(addr, &client).unwrap();
+        assert!(matches!(Connection::get_state(&addr), Ok(StateEnum2::LOST)));
+        assert!(get_subscribers_with_topic_id(topic_id).is_empty());
+        assert!(match_concrete_topics(&topic).is_empty());
+        assert!(!RetransTimeWheel::in_flight(addr, 5));
+        let _result = Connection::remove(&addr);
+    }
+
+    #[test]
+    fn test_keep_alive_probe() {
+        use super::*;
+        use crate::{MSG_LEN_PINGREQ_HEADER, MSG_TYPE_PINGREQ};
+        let mut conn = KeepAliveVal {
+            latest_counter: 100,
+            conn_duration: 50,
+            probe_deadline: None,
+        };
+        assert_eq!(conn.on_timeout(120, 0), SlotAction::Reschedule(150));
+        assert_eq!(conn.on_timeout(150, 0), SlotAction::Expire);
+        // The probe waits once, then expires.
+        assert_eq!(conn.on_timeout(150, 30), SlotAction::Probe(180));
+        assert_eq!(conn.on_timeout(160, 30), SlotAction::Reschedule(180));
+        assert_eq!(conn.on_timeout(180, 30), SlotAction::Expire);
+        // The client answered the probe.
+        conn.latest_counter = 170;
+        conn.probe_deadline = None;
+        assert_eq!(conn.on_timeout(180, 30), SlotAction::Reschedule(220));
+        KeepAliveTimeWheel::set_probe_timeout(1000);
+        assert_eq!(KeepAliveTimeWheel::probe_timeout(), MAX_PROBE_TIMEOUT);
+        KeepAliveTimeWheel::set_probe_timeout(0);
+        let client = MqttSnClient::new();
+        let addr = "127.0.0.1:2101".parse::<SocketAddr>().unwrap();
+        PingReq::probe(&client, addr).unwrap();
+        let (to, bytes) = client.egress_rx.try_recv().unwrap();
+        assert_eq!(to, addr);
+        assert_eq!(&bytes[..], &[MSG_LEN_PINGREQ_HEADER, MSG_TYPE_PINGREQ]);
+    }
+
+    #[test]
+    fn test_keep_alive_grace() {
+        use super::*;
+        let grace = KeepAliveGrace::default();
+        assert_eq!(grace.timeout(30), Duration::from_secs(40));
+        assert_eq!(grace.timeout(60), Duration::from_secs(70));
+        assert_eq!(grace.timeout(600), Duration::from_secs(900));
+        assert_eq!(KeepAliveGrace::exact().timeout(600).as_secs(), 600);
+        let grace = KeepAliveGrace {
+            multiplier: 0.5,
+            ..KeepAliveGrace::default()
+        };
+        assert_eq!(grace.timeout(600).as_secs(), 600);
+        // The tolerance of the scheduled timer.
+        let addr = "127.0.0.1:2102".parse::<SocketAddr>().unwrap();
+        KeepAliveTimeWheel::schedule(addr, 600).unwrap();
+        let remaining = KeepAliveTimeWheel::remaining(&addr).unwrap();
+        assert!(remaining > Duration::from_secs(800));
+        KeepAliveTimeWheel::cancel(&addr).unwrap();
+    }
+
+    #[test]
+    fn test_keep_alive_clock() {
+        use super::*;
+        use crate::clock::MockClock;
+        use crate::flags::CLEAN_SESSION_TRUE;
+        use bytes::Bytes;
+        let context = BrokerContext::new();
+        let _context = context.enter();
+        let client = MqttSnClient::new().with_context(context);
+        let clock = Arc::new(MockClock::new());
+        KeepAliveTimeWheel::set_clock(clock.clone());
+        KeepAliveTimeWheel::set_grace(KeepAliveGrace::exact());
+        let addr = "127.0.0.1:2103".parse::<SocketAddr>().unwrap();
+        Connection::try_insert(
+            addr,
+            CLEAN_SESSION_TRUE,
+            1,
+            10,
+            Bytes::from("clock"),
+        )
+        .unwrap();
+        KeepAliveTimeWheel::schedule(addr, 10).unwrap();
+        clock.advance(Duration::from_secs(9));
+        KeepAliveTimeWheel::poll(&client);
+        assert_eq!(Connection::get_state(&addr), Ok(StateEnum2::ACTIVE));
+        // A message from the client, the keep alive starts again.
+        KeepAliveTimeWheel::reschedule(addr).unwrap();
+        clock.advance(Duration::from_secs(9));
+        KeepAliveTimeWheel::poll(&client);
+        assert_eq!(Connection::get_state(&addr), Ok(StateEnum2::ACTIVE));
+        clock.advance(Duration::from_millis(1100));
+        KeepAliveTimeWheel::poll(&client);
+        assert_eq!(Connection::get_state(&addr), Ok(StateEnum2::LOST));
+        assert!(KeepAliveTimeWheel::remaining(&addr).is_none());
     }
 }
@@ -0,0 +1,183 @@
+/// Last-value cache, the latest payload of each topic.
+///
+/// Unlike the retained messages, every PUBLISH sent to the subscribers
+/// updates the cache, with or without the retain flag, and an empty payload
+/// is a value. The cache is disabled by default, LastValueCache::enable()
+/// sets the limits. An entry older than max_age is expired, with more than
+/// max_topics topics the least recently updated one is evicted. A cached
+/// topic id holds a TopicRef::LastValue and isn't reclaimed.
+///
+/// The lock order is LAST_VALUES, then TOPIC_REFS.
+use bytes::Bytes;
+use hashbrown::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{
+    flags::{flag_qos_level, QoSConst},
+    publish::Publish,
+    topic_refs::{TopicRef, TopicRefs},
+    TopicIdType,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LastValue {
+    pub topic_id: TopicIdType,
+    pub qos: QoSConst,
+    pub payload: Bytes,
+    pub updated_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct LastValueStore {
+    map: HashMap<TopicIdType, LastValue>,
+    /// 0 is unlimited.
+    max_topics: usize,
+    /// None never expires.
+    max_age: Option<Duration>,
+}
+
+impl LastValueStore {
+    fn update(&mut self, last_value: LastValue) {
+        let topic_id = last_value.topic_id;
+        if self.map.insert(topic_id, last_value).is_none() {
+            TopicRefs::acquire(topic_id, TopicRef::LastValue);
+            self.evict();
+        }
+    }
+    fn get(
+        &mut self,
+        topic_id: TopicIdType,
+        now: Instant,
+    ) -> Option<LastValue> {
+        let last_value = self.map.get(&topic_id)?;
+        if self.is_expired(last_value, now) {
+            self.remove(topic_id);
+            return None;
+        }
+        Some(last_value.clone())
+    }
+    fn remove(&mut self, topic_id: TopicIdType) {
+        if self.map.remove(&topic_id).is_some() {
+            TopicRefs::release(topic_id, TopicRef::LastValue);
+        }
+    }
+    fn is_expired(&self, last_value: &LastValue, now: Instant) -> bool {
+        match self.max_age {
+            Some(max_age) => {
+                now.saturating_duration_since(last_value.updated_at) >= max_age
+            }
+            None => false,
+        }
+    }
+    /// Remove the least recently updated topics above max_topics.
+    fn evict(&mut self) {
+        while self.max_topics > 0 && self.map.len() > self.max_topics {
+            let oldest = self
+                .map
+                .values()
+                .min_by_key(|last_value| last_value.updated_at)
+                .map(|last_value| last_value.topic_id);
+            match oldest {
+                Some(topic_id) => self.remove(topic_id),
+                None => break,
+            }
+        }
+    }
+    fn clear(&mut self) {
+        let topic_ids: Vec<TopicIdType> = self.map.keys().copied().collect();
+        for topic_id in topic_ids {
+            self.remove(topic_id);
+        }
+    }
+}
+
+lazy_static! {
+    static ref LAST_VALUE_ENABLED: AtomicBool = AtomicBool::new(false);
+    static ref LAST_VALUES: Mutex<LastValueStore> =
+        Mutex::new(LastValueStore::default());
+}
+
+#[derive(Debug, Clone)]
+pub struct LastValueCache {}
+
+impl LastValueCache {
+    /// Cache the topics, max_topics 0 is unlimited, max_age None never
+    /// expires.
+    pub fn enable(max_topics: usize, max_age: Option<Duration>) {
+        let mut store = LAST_VALUES.lock().unwrap();
+        store.max_topics = max_topics;
+        store.max_age = max_age;
+        store.evict();
+        LAST_VALUE_ENABLED.store(true, Ordering::Relaxed);
+    }
+    /// Stop caching and remove the cached values.
+    pub fn disable() {
+        LAST_VALUE_ENABLED.store(false, Ordering::Relaxed);
+        LAST_VALUES.lock().unwrap().clear();
+    }
+    #[inline(always)]
+    pub fn is_enabled() -> bool {
+        LAST_VALUE_ENABLED.load(Ordering::Relaxed)
+    }
+    /// Replace the value of the topic of the publish.
+    #[inline(always)]
+    pub fn update(publish: &Publish) {
+        if !LastValueCache::is_enabled() {
+            return;
+        }
+        let last_value = LastValue {
+            topic_id: *publish.topic_id(),
+            qos: flag_qos_level(*publish.flags()),
+            payload: publish.data().clone(),
+            updated_at: Instant::now(),
+        };
+        LAST_VALUES.lock().unwrap().update(last_value);
+    }
+    pub fn get(topic_id: TopicIdType) -> Option<LastValue> {
+        LAST_VALUES.lock().unwrap().get(topic_id, Instant::now())
+    }
+    pub fn len() -> usize {
+        LAST_VALUES.lock().unwrap().map.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_last_value_store() {
+        use super::*;
+        use crate::flags::{QOS_LEVEL_0, QOS_LEVEL_1};
+        let now = Instant::now();
+        let mut store = LastValueStore {
+            max_topics: 2,
+            ..LastValueStore::default()
+        };
+        for (topic_id, secs) in [(2001, 1), (2002, 2), (2001, 3)].iter() {
+            store.update(LastValue {
+                topic_id: *topic_id,
+                qos: QOS_LEVEL_0,
+                payload: Bytes::from(format!("{}", secs)),
+                updated_at: now + Duration::from_secs(*secs),
+            });
+        }
+        assert_eq!(&store.get(2001, now).unwrap().payload[..], b"3");
+        // The least recently updated topic is evicted.
+        store.update(LastValue {
+            topic_id: 2003,
+            qos: QOS_LEVEL_1,
+            payload: Bytes::new(),
+            updated_at: now + Duration::from_secs(4),
+        });
+        assert_eq!(store.get(2002, now), None);
+        assert_eq!(TopicRefs::count(2002), 0);
+        assert_eq!(store.get(2003, now).unwrap().qos, QOS_LEVEL_1);
+        // Expired.
+        store.max_age = Some(Duration::from_secs(10));
+        assert!(store.get(2001, now + Duration::from_secs(13)).is_none());
+        assert!(store.get(2003, now + Duration::from_secs(13)).is_some());
+        store.clear();
+        assert_eq!(TopicRefs::count(2003), 0);
+    }
+}
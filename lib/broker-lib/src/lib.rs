@@ -1,17 +1,33 @@
 #[warn(non_snake_case)]
 #[macro_use]
-extern crate arrayref;
-#[macro_use]
 extern crate lazy_static;
 
 // TODO fix non_snake_case.
+pub mod admin;
 pub mod advertise;
+pub mod advertise_listener;
+pub mod alert;
+pub mod annotation;
 pub mod asleep_msg_cache;
+pub mod audit;
+pub mod authorization;
+pub mod broker_context;
 pub mod broker_lib;
 pub mod client_id;
+pub mod client_mode;
+pub mod clock;
+#[cfg(feature = "bridge")]
+pub mod cluster;
+pub mod codec;
+pub mod collections;
+pub mod config;
+pub mod conn;
 pub mod conn_ack;
 pub mod connect;
 pub mod connection;
+pub mod connection_info;
+#[cfg(feature = "connector")]
+pub mod connector;
 // pub mod ConnectionDb;
 #[allow(non_snake_case)]
 pub mod MsgType;
@@ -21,40 +37,85 @@ pub mod StateMachine;
 pub mod SubscriberDb;
 #[allow(non_snake_case)]
 pub mod TopicDb;
+pub mod decode;
+pub mod demo;
 pub mod disconnect;
+pub mod dtls_auth;
+pub mod dup_filter;
+pub mod egress;
+pub mod events;
 pub mod filter;
 pub mod flags;
+pub mod forwarder;
+#[cfg(feature = "fragmentation")]
+pub mod fragment;
+pub mod gateway_discovery;
 pub mod gw_info;
+#[cfg(feature = "health")]
+pub mod health;
+#[cfg(feature = "async-runtime")]
 pub mod hub;
+pub mod in_flight;
 pub mod keep_alive;
+pub mod last_value;
+pub mod listener;
+pub mod local_consumer;
+pub mod local_topics;
+pub mod metrics;
 pub mod msg_hdr;
+pub mod msg_id;
+pub mod msg_span;
+pub mod msg_trace;
 pub mod multicast;
+pub mod ordered_delivery;
 pub mod ping_req;
 pub mod ping_resp;
+pub mod protocol;
 pub mod pub_ack;
 pub mod pub_comp;
 pub mod pub_msg_cache;
 pub mod pub_rec;
 pub mod pub_rel;
 pub mod publish;
+pub mod recv_pool;
 pub mod reg_ack;
 pub mod register;
+pub mod register_on_demand;
 pub mod retain;
 pub mod retransmit;
+pub mod rich_publish;
 pub mod search_gw;
+pub mod storage_backend;
 pub mod sub_ack;
 pub mod subscribe;
+pub mod subscribe_batch;
+pub mod subscription_export;
+pub mod subscription_limits;
+pub mod subscription_store;
+pub mod sys_stats;
+pub mod sys_topics;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+pub mod test_topics;
+#[cfg(feature = "async-runtime")]
 pub mod tikv;
+pub mod timer_wheel;
+pub mod topic_metadata;
+pub mod topic_refs;
 pub mod unsub_ack;
 pub mod unsubscribe;
+pub mod wake;
 pub mod will_msg;
 pub mod will_msg_req;
 pub mod will_msg_resp;
 pub mod will_msg_upd;
+pub mod will_setup;
 pub mod will_topic;
 pub mod will_topic_req;
 pub mod will_topic_resp;
 pub mod will_topic_upd;
+#[cfg(feature = "async-runtime")]
+pub mod ws_transport;
 
 // pub mod BrokerLib;
 // #[allow(non_snake_case)]
@@ -65,40 +126,16 @@ pub const MTU: usize = 1500;
 pub type TopicIdType = u16;
 pub type MsgIdType = u16;
 
-pub type MsgTypeConst = u8;
-pub const MSG_TYPE_ADVERTISE: MsgTypeConst = 0x0;
-pub const MSG_TYPE_SEARCH_GW: MsgTypeConst = 0x1;
-pub const MSG_TYPE_GW_INFO: MsgTypeConst = 0x2;
-pub const MSG_TYPE_CONNECT: MsgTypeConst = 0x4;
-pub const MSG_TYPE_CONNACK: MsgTypeConst = 0x5;
-pub const MSG_TYPE_SUBSCRIBE: MsgTypeConst = 0x12;
-pub const MSG_TYPE_SUBACK: MsgTypeConst = 0x13;
-pub const MSG_TYPE_UNSUBSCRIBE: MsgTypeConst = 0x14;
-pub const MSG_TYPE_UNSUBACK: MsgTypeConst = 0x15;
-pub const MSG_TYPE_PUBLISH: MsgTypeConst = 0xC; // should be 0, most popular
-pub const MSG_TYPE_PUBACK: MsgTypeConst = 0xD;
-pub const MSG_TYPE_PUBCOMP: MsgTypeConst = 0xE;
-pub const MSG_TYPE_PUBREC: MsgTypeConst = 0xF;
-pub const MSG_TYPE_PUBREL: MsgTypeConst = 0x10;
-pub const MSG_TYPE_DISCONNECT: MsgTypeConst = 0x18;
-pub const MSG_TYPE_WILL_TOPIC_REQ: MsgTypeConst = 0x06;
-pub const MSG_TYPE_WILL_TOPIC: MsgTypeConst = 0x07;
-pub const MSG_TYPE_WILL_MSG_REQ: MsgTypeConst = 0x08;
-pub const MSG_TYPE_WILL_MSG: MsgTypeConst = 0x09;
-pub const MSG_TYPE_WILL_TOPIC_RESP: MsgTypeConst = 0x1B;
-pub const MSG_TYPE_WILL_MSG_RESP: MsgTypeConst = 0x1D;
-pub const MSG_TYPE_WILL_TOPIC_UPD: MsgTypeConst = 0x1A;
-pub const MSG_TYPE_WILL_MSG_UPD: MsgTypeConst = 0x1C;
-pub const MSG_TYPE_PINGREQ: MsgTypeConst = 0x16;
-pub const MSG_TYPE_PINGRESP: MsgTypeConst = 0x17;
-pub const MSG_TYPE_REGISTER: MsgTypeConst = 0x0A;
-pub const MSG_TYPE_REGACK: MsgTypeConst = 0x0B;
+// The message types, lengths and return codes of the spec, shared with
+// firmware through the codec crate.
+pub use mqtt_sn_codec::consts::*;
 
 // TODO fill in the rest
 pub const MSG_TYPE_WILLMSGRESP: MsgTypeConst = 0x1D; // 29
 
 // 0x1E-0xFD reserved
-pub const MSG_TYPE_ENCAP_MSG: MsgTypeConst = 0xFE;
+// Private extension in the reserved range, see src/fragment.rs
+pub const MSG_TYPE_FRAGMENT: MsgTypeConst = 0xF0;
 // XXX not an optimal choice because, array of MsgTypeConst
 // must include 256 entries.
 // For the 2x2 array [0..6][0..255] states,
@@ -110,43 +147,6 @@ pub const MSG_TYPE_MAX: usize = 256;
 
 pub const STATE_ENUM_LEN: usize = 5;
 
-pub type MsgLenConst = u8;
-pub const MSG_LEN_ADVERTISE: MsgLenConst = 5;
-pub const MSG_LEN_SEARCH_GW: MsgLenConst = 3;
-pub const MSG_LEN_PUBACK: MsgLenConst = 7;
-pub const MSG_LEN_PUBREC: MsgLenConst = 4;
-pub const MSG_LEN_PUBREL: MsgLenConst = 4;
-pub const MSG_LEN_PUBCOMP: MsgLenConst = 4;
-pub const MSG_LEN_SUBACK: MsgLenConst = 8;
-pub const MSG_LEN_REGACK: MsgLenConst = 7;
-pub const MSG_LEN_CONNACK: MsgLenConst = 3;
-pub const MSG_LEN_DISCONNECT: MsgLenConst = 2;
-pub const MSG_LEN_DISCONNECT_DURATION: MsgLenConst = 4;
-pub const MSG_LEN_WILL_TOPIC_REQ: MsgLenConst = 2;
-pub const MSG_LEN_WILL_MSG_REQ: MsgLenConst = 2;
-pub const MSG_LEN_WILL_TOPIC_RESP: MsgLenConst = 3;
-pub const MSG_LEN_WILL_MSG_RESP: MsgLenConst = 3;
-pub const MSG_LEN_PINGRESP: MsgLenConst = 2;
-pub const MSG_LEN_UNSUBACK: MsgLenConst = 4;
-
-pub const MSG_LEN_GW_INFO_HEADER: MsgLenConst = 3;
-pub const MSG_LEN_WILL_TOPIC_HEADER: MsgLenConst = 3;
-pub const MSG_LEN_WILL_MSG_HEADER: MsgLenConst = 2;
-pub const MSG_LEN_WILL_TOPIC_UPD_HEADER: MsgLenConst = 3;
-pub const MSG_LEN_WILL_MSG_UPD_HEADER: MsgLenConst = 2;
-pub const MSG_LEN_PUBLISH_HEADER: MsgLenConst = 7;
-pub const MSG_LEN_CONNECT_HEADER: MsgLenConst = 6;
-pub const MSG_LEN_PINGREQ_HEADER: MsgLenConst = 2;
-pub const MSG_LEN_SUBSCRIBE_HEADER: MsgLenConst = 7;
-pub const MSG_LEN_UNSUBSCRIBE_HEADER: MsgLenConst = 7;
-pub const MSG_LEN_REGISTER_HEADER: MsgLenConst = 6;
-
-type ReturnCodeConst = u8;
-const RETURN_CODE_ACCEPTED: ReturnCodeConst = 0;
-// const RETURN_CODE_CONGESTION: ReturnCodeConst = 1;
-const RETURN_CODE_INVALID_TOPIC_ID: ReturnCodeConst = 2;
-// const RETURN_CODE_NOT_SUPPORTED: ReturnCodeConst = 3;
-
 #[macro_export]
 macro_rules! function {
     () => {{
@@ -0,0 +1,211 @@
+/// UDP listener sockets of a multi-homed gateway.
+///
+/// A gateway with several interfaces, e.g. eth0, wlan0 and a 6LoWPAN border
+/// router, binds a socket per interface. Listeners::run() starts an rx
+/// thread per socket, the datagrams go through MqttSnClient::dispatch(), and
+/// the socket of the last datagram from a remote address is recorded. The
+/// replies go out through Listeners::send_to(), from the socket the client
+/// is reachable on, the first socket if the address isn't known. The
+/// routes of the addresses without a connection are dropped when the table
+/// reaches MAX_ROUTES, a reply after the DISCONNECT still has its route.
+///
+/// The DTLS and WebSocket clients are answered through their Conn in the
+/// Hub, see MqttSnClient::handle_egress().
+use hashbrown::HashMap;
+use log::*;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+
+use crate::{
+    broker_context::BrokerContext, broker_lib::MqttSnClient,
+    client_mode::ClientMode, conn::udp_conn, connection::Connection, eformat,
+    function, multicast::udp_bind, recv_pool::RECV_POOL,
+};
+
+pub const MAX_ROUTES: usize = 65_536;
+
+/// Sockets and routes of a broker, see BrokerContext.
+#[derive(Default)]
+pub(crate) struct ListenerState {
+    sockets: RwLock<Vec<Arc<UdpSocket>>>,
+    /// Index of the socket of a remote address.
+    routes: Mutex<HashMap<SocketAddr, usize>>,
+}
+
+#[inline(always)]
+fn state() -> &'static ListenerState {
+    &BrokerContext::current().listener
+}
+
+#[derive(Debug, Clone)]
+pub struct Listeners {}
+
+impl Listeners {
+    /// Bind a socket to each address.
+    pub fn bind(addrs: &[SocketAddr]) -> Result<Vec<UdpSocket>, String> {
+        addrs
+            .iter()
+            .map(|addr| udp_bind(*addr).map_err(|why| eformat!(addr, why)))
+            .collect()
+    }
+
+    /// Start an rx thread per socket, the first socket is the default
+    /// route.
+    pub fn run(
+        client: MqttSnClient,
+        sockets: Vec<UdpSocket>,
+    ) -> Result<(), String> {
+        let _context = client.context.enter();
+        let first = state().sockets.read().unwrap().len();
+        for (offset, socket) in sockets.into_iter().enumerate() {
+            let index = first + offset;
+            let local_addr =
+                socket.local_addr().map_err(|why| eformat!(why))?;
+            let socket = Arc::new(socket);
+            state().sockets.write().unwrap().push(socket.clone());
+            let client = client.clone();
+            let builder =
+                thread::Builder::new().name(format!("listener_{}", index));
+            let spawned = builder.spawn(move || {
+                let _context = client.context.enter();
+                // Nothing is sent through the conn, the egress uses the
+                // socket.
+                let conn = udp_conn();
+                loop {
+                    let mut buf = RECV_POOL.take();
+                    let (size, remote_addr) = match socket.recv_from(&mut buf) {
+                        Ok(received) => received,
+                        Err(why) => {
+                            error!("{}", eformat!(local_addr, why));
+                            continue;
+                        }
+                    };
+                    Listeners::record(remote_addr, index);
+                    client.dispatch(
+                        remote_addr,
+                        RECV_POOL.freeze(buf, size),
+                        conn.clone(),
+                    );
+                }
+            });
+            if let Err(why) = spawned {
+                return Err(eformat!(local_addr, why));
+            }
+        }
+        Ok(())
+    }
+
+    /// Local addresses of the sockets.
+    pub fn local_addrs() -> Vec<SocketAddr> {
+        state()
+            .sockets
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|socket| socket.local_addr().ok())
+            .collect()
+    }
+
+    /// Local address of the socket the remote address is reachable on.
+    pub fn route(remote_addr: &SocketAddr) -> Option<SocketAddr> {
+        let index = *state().routes.lock().unwrap().get(remote_addr)?;
+        state().sockets.read().unwrap()[index].local_addr().ok()
+    }
+
+    /// Send the datagram from the socket of the remote address.
+    pub fn send_to(remote_addr: SocketAddr, data: &[u8]) -> Result<(), String> {
+        let index = state()
+            .routes
+            .lock()
+            .unwrap()
+            .get(&remote_addr)
+            .copied()
+            .unwrap_or(0);
+        let socket = match state().sockets.read().unwrap().get(index) {
+            Some(socket) => socket.clone(),
+            None => return Err(eformat!(remote_addr, "no listener")),
+        };
+        match socket.send_to(data, remote_addr) {
+            Ok(size) if size == data.len() => Ok(()),
+            Ok(size) => Err(eformat!(remote_addr, "sent", size, data.len())),
+            Err(why) => Err(eformat!(remote_addr, why)),
+        }
+    }
+
+    #[inline(always)]
+    fn record(remote_addr: SocketAddr, index: usize) {
+        let full = {
+            let mut routes = state().routes.lock().unwrap();
+            if routes.get(&remote_addr) == Some(&index) {
+                return;
+            }
+            routes.insert(remote_addr, index);
+            routes.len() >= MAX_ROUTES
+        };
+        if full {
+            Listeners::prune();
+        }
+    }
+
+    /// Drop the routes of the addresses without a connection.
+    fn prune() {
+        // Not under the routes lock, the lock order is CONN_HASHMAP first.
+        let remote_addrs: Vec<SocketAddr> =
+            state().routes.lock().unwrap().keys().copied().collect();
+        let stale: Vec<SocketAddr> = remote_addrs
+            .into_iter()
+            .filter(|addr| {
+                !Connection::contains_key(*addr) && !ClientMode::contains(addr)
+            })
+            .collect();
+        let mut routes = state().routes.lock().unwrap();
+        for remote_addr in stale.iter() {
+            routes.remove(remote_addr);
+        }
+        dbg!((stale.len(), routes.len()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_listeners() {
+        use super::*;
+        use crate::keep_alive::KeepAliveTimeWheel;
+        use crate::retransmit::RetransTimeWheel;
+        use crate::test_support::TestClient;
+        use crate::RETURN_CODE_ACCEPTED;
+        let context = BrokerContext::new();
+        let _context = context.enter();
+        KeepAliveTimeWheel::init();
+        RetransTimeWheel::init();
+        let client = MqttSnClient::new().with_context(context);
+        let localhost = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+        let sockets = Listeners::bind(&[localhost, localhost]).unwrap();
+        Listeners::run(client.clone(), sockets).unwrap();
+        let local_addrs = Listeners::local_addrs();
+        assert_eq!(local_addrs.len(), 2);
+        // The egress thread of the test.
+        let egress = client.clone();
+        thread::spawn(move || {
+            let _context = egress.context.enter();
+            while let Ok((addr, data)) = egress.egress_rx.recv() {
+                Listeners::send_to(addr, &data).unwrap();
+            }
+        });
+        let mut test_client = TestClient::new(local_addrs[1]).unwrap();
+        let return_code =
+            test_client.connect("listenerClient", 60, None).unwrap();
+        assert_eq!(return_code, RETURN_CODE_ACCEPTED);
+        let remote_addr = test_client.local_addr();
+        assert_eq!(Listeners::route(&remote_addr), Some(local_addrs[1]));
+        // The DISCONNECT is answered from the same socket.
+        test_client.disconnect(None).unwrap();
+        assert_eq!(Listeners::route(&remote_addr), Some(local_addrs[1]));
+        // Unknown addresses use the first socket.
+        let unknown = "127.0.0.1:9".parse::<SocketAddr>().unwrap();
+        assert_eq!(Listeners::route(&unknown), None);
+        assert!(Listeners::send_to(unknown, &[2, 0x17]).is_ok());
+    }
+}
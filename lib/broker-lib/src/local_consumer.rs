@@ -0,0 +1,295 @@
+/// In-process consumers, embedded applications receive PUBLISH messages
+/// without a network connection.
+///
+/// A consumer is a callback registered for a topic id, the callback
+/// returns Delivery::Ack or Delivery::Nack. A NACKed message is retried
+/// after NackPolicy::retry_delay_ms, up to NackPolicy::max_retries times,
+/// then it is moved to the dead letter queue. This gives the consumer
+/// at-least-once semantics comparable to QoS 1 for network clients.
+///
+/// The retries are processed by the thread started with LocalConsumer::run().
+use chrono::{DateTime, Local};
+use hashbrown::HashMap;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{
+    annotation::Annotations, broker_context::BrokerContext, publish::Publish,
+    TopicIdType,
+};
+
+pub type ConsumerId = u32;
+
+/// Result of a delivery to an in-process consumer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delivery {
+    Ack,
+    Nack,
+}
+
+pub type ConsumerCallback = fn(ConsumerId, &Publish, &Annotations) -> Delivery;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NackPolicy {
+    /// Number of retries after the first delivery, 0 to dead letter the
+    /// message on the first NACK.
+    pub max_retries: u8,
+    pub retry_delay_ms: u64,
+}
+
+impl Default for NackPolicy {
+    fn default() -> Self {
+        NackPolicy {
+            max_retries: 3,
+            retry_delay_ms: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeadLetter {
+    pub consumer_id: ConsumerId,
+    pub publish: Publish,
+    pub attempts: u8,
+    pub time: DateTime<Local>,
+}
+
+#[derive(Debug, Clone)]
+struct Consumer {
+    topic_id: TopicIdType,
+    callback: ConsumerCallback,
+    policy: NackPolicy,
+}
+
+#[derive(Debug, Clone)]
+struct RetryEntry {
+    consumer_id: ConsumerId,
+    publish: Publish,
+    annotations: Annotations,
+    attempts: u8,
+    due: Instant,
+}
+
+static SLEEP_DURATION: u64 = 100;
+pub const MAX_DEAD_LETTERS: usize = 256;
+
+lazy_static! {
+    static ref CONSUMER_ID_COUNTER: AtomicU32 = AtomicU32::new(0);
+    static ref CONSUMERS: Mutex<HashMap<ConsumerId, Consumer>> =
+        Mutex::new(HashMap::new());
+    static ref RETRY_QUEUE: Mutex<Vec<RetryEntry>> = Mutex::new(Vec::new());
+    static ref DEAD_LETTERS: Mutex<VecDeque<DeadLetter>> =
+        Mutex::new(VecDeque::new());
+}
+
+#[derive(Debug, Clone)]
+pub struct LocalConsumer {}
+
+impl LocalConsumer {
+    /// Register a consumer for a topic id, returns the consumer id.
+    pub fn register(
+        topic_id: TopicIdType,
+        callback: ConsumerCallback,
+        policy: NackPolicy,
+    ) -> ConsumerId {
+        let consumer_id = CONSUMER_ID_COUNTER.fetch_add(1, Ordering::Relaxed);
+        CONSUMERS.lock().unwrap().insert(
+            consumer_id,
+            Consumer {
+                topic_id,
+                callback,
+                policy,
+            },
+        );
+        consumer_id
+    }
+    /// Remove the consumer and its pending retries.
+    pub fn unregister(consumer_id: ConsumerId) {
+        CONSUMERS.lock().unwrap().remove(&consumer_id);
+        RETRY_QUEUE
+            .lock()
+            .unwrap()
+            .retain(|entry| entry.consumer_id != consumer_id);
+    }
+
+    #[inline(always)]
+    pub fn is_empty() -> bool {
+        CONSUMERS.lock().unwrap().is_empty()
+    }
+
+    /// Deliver a message to the consumers of the topic id.
+    #[inline(always)]
+    pub fn deliver(publish: &Publish, annotations: &Annotations) {
+        // Copy the consumers, so a callback can register consumers
+        // without deadlock.
+        let consumer_vec: Vec<(ConsumerId, Consumer)> = {
+            let consumers = CONSUMERS.lock().unwrap();
+            if consumers.is_empty() {
+                return;
+            }
+            consumers
+                .iter()
+                .filter(|(_, consumer)| {
+                    consumer.topic_id == *publish.topic_id()
+                })
+                .map(|(id, consumer)| (*id, consumer.clone()))
+                .collect()
+        };
+        for (consumer_id, consumer) in consumer_vec {
+            LocalConsumer::attempt(
+                consumer_id,
+                &consumer,
+                publish.clone(),
+                annotations.clone(),
+                1,
+            );
+        }
+    }
+
+    /// Call the consumer, on NACK schedule a retry or dead letter it.
+    fn attempt(
+        consumer_id: ConsumerId,
+        consumer: &Consumer,
+        publish: Publish,
+        annotations: Annotations,
+        attempts: u8,
+    ) {
+        if (consumer.callback)(consumer_id, &publish, &annotations)
+            == Delivery::Ack
+        {
+            return;
+        }
+        if attempts > consumer.policy.max_retries {
+            LocalConsumer::dead_letter(consumer_id, publish, attempts);
+            return;
+        }
+        let due = Instant::now()
+            + Duration::from_millis(consumer.policy.retry_delay_ms);
+        RETRY_QUEUE.lock().unwrap().push(RetryEntry {
+            consumer_id,
+            publish,
+            annotations,
+            attempts,
+            due,
+        });
+    }
+
+    fn dead_letter(consumer_id: ConsumerId, publish: Publish, attempts: u8) {
+        let mut dead_letters = DEAD_LETTERS.lock().unwrap();
+        if dead_letters.len() >= MAX_DEAD_LETTERS {
+            // drop the oldest dead letter.
+            dead_letters.pop_front();
+        }
+        dead_letters.push_back(DeadLetter {
+            consumer_id,
+            publish,
+            attempts,
+            time: Local::now(),
+        });
+    }
+
+    /// Retry the NACKed messages that are due.
+    pub fn retry_due(now: Instant) {
+        let due_vec: Vec<RetryEntry> = {
+            let mut queue = RETRY_QUEUE.lock().unwrap();
+            let (due_vec, pending): (Vec<RetryEntry>, Vec<RetryEntry>) =
+                queue.drain(..).partition(|entry| entry.due <= now);
+            *queue = pending;
+            due_vec
+        };
+        for entry in due_vec {
+            let consumer =
+                match CONSUMERS.lock().unwrap().get(&entry.consumer_id) {
+                    Some(consumer) => consumer.clone(),
+                    None => continue, // unregistered, drop the message.
+                };
+            LocalConsumer::attempt(
+                entry.consumer_id,
+                &consumer,
+                entry.publish,
+                entry.annotations,
+                entry.attempts + 1,
+            );
+        }
+    }
+
+    /// Remove and return all dead letters.
+    pub fn take_dead_letters() -> Vec<DeadLetter> {
+        DEAD_LETTERS.lock().unwrap().drain(..).collect()
+    }
+
+    pub fn run() {
+        let context = BrokerContext::current();
+        let builder =
+            thread::Builder::new().name("local_consumer_thread".into());
+        let _local_consumer_thread = builder.spawn(move || {
+            let _context = context.enter();
+            loop {
+                thread::sleep(Duration::from_millis(SLEEP_DURATION));
+                LocalConsumer::retry_due(Instant::now());
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_local_consumer_nack() {
+        use super::*;
+        use bytes::BytesMut;
+        use std::sync::atomic::AtomicUsize;
+
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        fn nack_twice(
+            _id: ConsumerId,
+            _publish: &Publish,
+            _annotations: &Annotations,
+        ) -> Delivery {
+            if CALLS.fetch_add(1, Ordering::Relaxed) < 2 {
+                Delivery::Nack
+            } else {
+                Delivery::Ack
+            }
+        }
+        fn always_nack(
+            _id: ConsumerId,
+            _publish: &Publish,
+            _annotations: &Annotations,
+        ) -> Delivery {
+            Delivery::Nack
+        }
+        let policy = NackPolicy {
+            max_retries: 2,
+            retry_delay_ms: 0,
+        };
+        let id = LocalConsumer::register(1001, nack_twice, policy);
+        let id2 = LocalConsumer::register(1002, always_nack, policy);
+        let annotations = Annotations::new();
+
+        // Acked on the third attempt, no dead letter.
+        let publish = Publish::new(1001, 1, 1, 0, BytesMut::from(&b"a"[..]));
+        LocalConsumer::deliver(&publish, &annotations);
+        LocalConsumer::retry_due(Instant::now());
+        LocalConsumer::retry_due(Instant::now());
+        LocalConsumer::retry_due(Instant::now());
+        assert_eq!(CALLS.load(Ordering::Relaxed), 3);
+
+        // Dead letter after 1 + 2 attempts.
+        let publish = Publish::new(1002, 2, 1, 0, BytesMut::from(&b"b"[..]));
+        LocalConsumer::deliver(&publish, &annotations);
+        LocalConsumer::retry_due(Instant::now());
+        LocalConsumer::retry_due(Instant::now());
+        let dead_letters: Vec<DeadLetter> = LocalConsumer::take_dead_letters()
+            .into_iter()
+            .filter(|dead_letter| dead_letter.consumer_id == id2)
+            .collect();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].attempts, 3);
+        LocalConsumer::unregister(id);
+        LocalConsumer::unregister(id2);
+    }
+}
@@ -0,0 +1,123 @@
+/// Local-only topics that never leave the gateway.
+///
+/// Messages on topics matching a local filter are delivered to the
+/// subscribers connected to this gateway only, they are never bridged
+/// upstream or federated. Bridges and connectors must check
+/// LocalTopics::is_local() before sending a message off the gateway.
+///
+/// The local filters file has one topic filter per line, lines starting
+/// with # are comments:
+///
+/// # intra-site coordination
+/// site/coord/#
+/// site/heartbeat
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::{
+    eformat,
+    filter::{get_topic_name_with_topic_id, match_topic, valid_filter},
+    function, TopicIdType,
+};
+
+lazy_static! {
+    static ref ENABLED: AtomicBool = AtomicBool::new(false);
+    static ref LOCAL_FILTERS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+}
+
+#[derive(Debug, Clone)]
+pub struct LocalTopics {}
+
+impl LocalTopics {
+    /// Mark the topic filter local.
+    pub fn add(filter: &str) -> Result<(), String> {
+        if !valid_filter(filter) {
+            return Err(eformat!(filter, "invalid filter"));
+        }
+        let mut filters = LOCAL_FILTERS.lock().unwrap();
+        if !filters.iter().any(|local| local == filter) {
+            filters.push(filter.to_string());
+        }
+        ENABLED.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+    /// Returns false if the filter is not local.
+    pub fn remove(filter: &str) -> bool {
+        let mut filters = LOCAL_FILTERS.lock().unwrap();
+        let len = filters.len();
+        filters.retain(|local| local != filter);
+        ENABLED.store(!filters.is_empty(), Ordering::Relaxed);
+        filters.len() != len
+    }
+    /// Replace the local filters with the filters in the file.
+    pub fn load_file(path: &str) -> Result<usize, String> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(why) => return Err(eformat!(path, why)),
+        };
+        let mut filters = Vec::new();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if !valid_filter(line) {
+                return Err(eformat!(path, "invalid filter", line));
+            }
+            filters.push(line.to_string());
+        }
+        let len = filters.len();
+        ENABLED.store(len > 0, Ordering::Relaxed);
+        *LOCAL_FILTERS.lock().unwrap() = filters;
+        Ok(len)
+    }
+    #[inline(always)]
+    pub fn is_empty() -> bool {
+        !ENABLED.load(Ordering::Relaxed)
+    }
+
+    /// The topic name matches a local filter.
+    pub fn is_local_topic(topic_name: &str) -> bool {
+        if LocalTopics::is_empty() {
+            return false;
+        }
+        LOCAL_FILTERS
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|filter| match_topic(topic_name, filter))
+    }
+    /// The topic id is registered with a topic name that matches a local
+    /// filter. Pre-defined and unknown topic ids are not local.
+    pub fn is_local(topic_id: TopicIdType) -> bool {
+        if LocalTopics::is_empty() {
+            return false;
+        }
+        match get_topic_name_with_topic_id(topic_id) {
+            Some(topic_name) => LocalTopics::is_local_topic(&topic_name),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_local_topics() {
+        use super::*;
+        use crate::filter::try_insert_topic_name;
+
+        LocalTopics::add("local_test/coord/#").unwrap();
+        assert!(LocalTopics::add("local_test/#/x").is_err());
+        assert!(LocalTopics::is_local_topic("local_test/coord/leader"));
+        assert!(!LocalTopics::is_local_topic("local_test/telemetry"));
+        let local_id =
+            try_insert_topic_name("local_test/coord/leader".to_string())
+                .unwrap();
+        let remote_id =
+            try_insert_topic_name("local_test/telemetry".to_string()).unwrap();
+        assert!(LocalTopics::is_local(local_id));
+        assert!(!LocalTopics::is_local(remote_id));
+        assert!(LocalTopics::remove("local_test/coord/#"));
+        assert!(!LocalTopics::is_local(local_id));
+    }
+}
@@ -0,0 +1,217 @@
+/// Broker metrics, atomic counters exported in the Prometheus text format.
+///
+/// The counters are updated in Publish, Subscribe, RetransTimeWheel and
+/// KeepAliveTimeWheel. The connects and the publishes are the $SYS totals
+/// of SysStats, counted once for both exporters.
+/// Metrics::serve() starts an optional HTTP server for the /metrics
+/// endpoint, Metrics::render() returns the same text for other exporters.
+/// Without the "metrics" feature, Metrics::inc() is a no-op and there is no
+/// endpoint.
+#[cfg(feature = "metrics")]
+use log::*;
+#[cfg(feature = "metrics")]
+use std::fmt::Write as FmtWrite;
+#[cfg(feature = "metrics")]
+use std::io::{Read, Write};
+#[cfg(feature = "metrics")]
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "metrics")]
+use std::thread;
+#[cfg(feature = "metrics")]
+use std::time::Duration;
+
+#[cfg(feature = "metrics")]
+use crate::{
+    broker_context::BrokerContext,
+    connection::Connection,
+    eformat,
+    filter::{count_filters, get_subscriptions},
+    function,
+    sys_stats::SysStats,
+};
+
+/// A client of the /metrics endpoint sending nothing is dropped after this
+/// time, the requests are served one at a time.
+#[cfg(feature = "metrics")]
+pub const METRICS_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Counter {
+    Subscribes = 0,
+    Retransmits,
+    RetransmitTimeouts,
+    KeepAliveExpirations,
+    Duplicates,
+    SubscriptionLimitRejections,
+}
+
+const COUNTER_LEN: usize = 6;
+
+/// (name, help) for each counter, in the Counter order.
+#[cfg(feature = "metrics")]
+const COUNTER_INFO: [(&str, &str); COUNTER_LEN] = [
+    ("mqttsn_subscribes_total", "SUBSCRIBE messages received."),
+    ("mqttsn_retransmits_total", "Messages retransmitted."),
+    (
+        "mqttsn_retransmit_timeouts_total",
+        "Messages dropped after the last retransmit.",
+    ),
+    (
+        "mqttsn_keep_alive_expirations_total",
+        "Clients lost after the keep alive timeout.",
+    ),
+    (
+        "mqttsn_duplicates_total",
+        "Retransmitted PUBLISH messages acknowledged, not forwarded.",
+    ),
+    (
+        "mqttsn_subscription_limit_rejections_total",
+        "SUBSCRIBE messages rejected by the subscription limits.",
+    ),
+];
+
+lazy_static! {
+    static ref COUNTERS: [AtomicU64; COUNTER_LEN] = Default::default();
+}
+
+#[derive(Debug, Clone)]
+pub struct Metrics {}
+
+impl Metrics {
+    #[inline(always)]
+    pub fn inc(counter: Counter) {
+        #[cfg(feature = "metrics")]
+        COUNTERS[counter as usize].fetch_add(1, Ordering::Relaxed);
+        #[cfg(not(feature = "metrics"))]
+        let _counter = counter;
+    }
+    pub fn get(counter: Counter) -> u64 {
+        COUNTERS[counter as usize].load(Ordering::Relaxed)
+    }
+
+    /// Metrics in the Prometheus text format.
+    #[cfg(feature = "metrics")]
+    pub fn render() -> String {
+        let mut text = String::new();
+        let totals = SysStats::totals();
+        let derived = [
+            (
+                "mqttsn_connects_total",
+                "CONNECT messages accepted.",
+                totals.clients_total,
+            ),
+            (
+                "mqttsn_publishes_total",
+                "PUBLISH messages received.",
+                totals.messages_received,
+            ),
+        ];
+        for (name, help, val) in derived.iter() {
+            let _result = write!(
+                text,
+                "# HELP {} {}\n# TYPE {} counter\n{} {}\n",
+                name, help, name, name, val
+            );
+        }
+        for (index, (name, help)) in COUNTER_INFO.iter().enumerate() {
+            let val = COUNTERS[index].load(Ordering::Relaxed);
+            let _result = write!(
+                text,
+                "# HELP {} {}\n# TYPE {} counter\n{} {}\n",
+                name, help, name, name, val
+            );
+        }
+        let _result = write!(
+            text,
+            "# HELP mqttsn_clients_connected Clients connected.\n\
+             # TYPE mqttsn_clients_connected gauge\n\
+             mqttsn_clients_connected {}\n",
+            Connection::count_connected()
+        );
+        let _result = write!(
+            text,
+            "# HELP mqttsn_subscriptions Subscriptions to topic ids.\n\
+             # TYPE mqttsn_subscriptions gauge\n\
+             mqttsn_subscriptions {}\n\
+             # HELP mqttsn_filters Wildcard filters with subscribers.\n\
+             # TYPE mqttsn_filters gauge\n\
+             mqttsn_filters {}\n",
+            get_subscriptions().len(),
+            count_filters()
+        );
+        text
+    }
+
+    /// Serve GET /metrics over HTTP.
+    #[cfg(feature = "metrics")]
+    pub fn serve(socket_addr: SocketAddr) -> Result<(), String> {
+        let listener = match TcpListener::bind(socket_addr) {
+            Ok(listener) => listener,
+            Err(why) => return Err(eformat!(socket_addr, why)),
+        };
+        // Serve the broker of the calling thread.
+        let context = BrokerContext::current();
+        let builder = thread::Builder::new().name("metrics_http_thread".into());
+        let _metrics_http_thread = builder.spawn(move || {
+            let _context = context.enter();
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(why) = Metrics::handle_http(stream) {
+                            error!("{}", why);
+                        }
+                    }
+                    Err(why) => {
+                        error!("{}", eformat!(socket_addr, why));
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    #[cfg(feature = "metrics")]
+    fn handle_http(mut stream: TcpStream) -> Result<(), String> {
+        stream
+            .set_read_timeout(Some(METRICS_READ_TIMEOUT))
+            .map_err(|why| eformat!(why))?;
+        let mut buf = [0u8; 1024];
+        let size = stream.read(&mut buf).map_err(|why| eformat!(why))?;
+        let response = if buf[..size].starts_with(b"GET /metrics ") {
+            let body = Metrics::render();
+            format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        } else {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\
+             Connection: close\r\n\r\n"
+                .to_string()
+        };
+        stream
+            .write_all(response.as_bytes())
+            .map_err(|why| eformat!(why))
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod test {
+    #[test]
+    fn test_metrics_render() {
+        use super::*;
+        let before = Metrics::get(Counter::Retransmits);
+        Metrics::inc(Counter::Retransmits);
+        assert!(Metrics::get(Counter::Retransmits) > before);
+        let text = Metrics::render();
+        assert!(text.contains("# TYPE mqttsn_retransmits_total counter\n"));
+        assert!(text.contains("# TYPE mqttsn_publishes_total counter\n"));
+        assert!(text.contains("# TYPE mqttsn_clients_connected gauge\n"));
+        assert!(text.contains("# TYPE mqttsn_filters gauge\n"));
+        assert_eq!(text.matches("# HELP").count(), COUNTER_LEN + 5);
+    }
+}
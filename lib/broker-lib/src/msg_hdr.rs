@@ -12,11 +12,26 @@ length that could be used in a network is governed by the maximum packet size th
 and not by the maximum length that could be encoded by MQTT-SN.
 */
 
-use crate::{eformat, function};
+use crate::{
+    codec::{get_u16_be, put_u16_be},
+    conn::Conn,
+    eformat, function,
+    protocol::ProtocolVersion,
+};
+use bytes::{BufMut, BytesMut};
 use custom_debug::Debug;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use util::conn::*;
+
+/// Messages longer than the max. datagram size are rejected, 1400 bytes
+/// fit in the UDP payload of a 1500 bytes MTU.
+pub const DEFAULT_MAX_DATAGRAM_SIZE: usize = 1400;
+
+lazy_static! {
+    static ref MAX_DATAGRAM_SIZE: AtomicUsize =
+        AtomicUsize::new(DEFAULT_MAX_DATAGRAM_SIZE);
+}
 
 #[derive(Debug, Copy, Clone)]
 pub enum MsgHeaderLenEnum {
@@ -32,9 +47,27 @@ pub struct MsgHeader {
     // #[debug(format = "0x{:x}")]
     pub msg_type: u8,
     pub header_len: MsgHeaderLenEnum,
+    version: ProtocolVersion,
 }
 
 impl MsgHeader {
+    /// Header for sending to remote_socket_addr, e.g. in client mode where
+    /// no message was received yet.
+    pub fn new(
+        remote_socket_addr: SocketAddr,
+        conn: Arc<dyn Conn + Send + Sync>,
+        msg_type: u8,
+    ) -> Self {
+        MsgHeader {
+            remote_socket_addr,
+            conn,
+            len: 0,
+            msg_type,
+            header_len: MsgHeaderLenEnum::Short,
+            version: ProtocolVersion::V1_2,
+        }
+    }
+
     pub fn try_read(
         buf: &[u8],
         size: usize,
@@ -49,10 +82,15 @@ impl MsgHeader {
             if buf[0] != 1 {
                 len = buf[0] as u16;
                 msg_type = buf[1] as u8;
-            } else {
-                len = (buf[1] as u16) << 8 | buf[2] as u16;
+            } else if size >= 4 {
+                len = get_u16_be(buf, 1);
                 msg_type = buf[3] as u8;
                 header_len = MsgHeaderLenEnum::Long;
+            } else {
+                return Err(eformat!("Long header is too short", size));
+            }
+            if len as usize > MsgHeader::max_message_size() {
+                return Err(eformat!("Message is too long", len));
             }
             if size == len as usize {
                 return Ok(MsgHeader {
@@ -61,6 +99,7 @@ impl MsgHeader {
                     len,
                     header_len,
                     msg_type,
+                    version: MsgHeader::version_of(&remote_socket_addr),
                 });
             }
             return Err(eformat!(
@@ -71,6 +110,234 @@ impl MsgHeader {
             return Err(eformat!("Message is too short", size));
         }
     }
+
+    /// The protocol version of the client, see protocol.rs.
+    #[inline(always)]
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    /// The header of a CONNECT, with the version of its protocol id.
+    pub fn with_version(mut self, version: ProtocolVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// The version of the connection, no lookup with 1.2 only.
+    #[cfg(feature = "mqtt-sn-v2")]
+    fn version_of(remote_socket_addr: &SocketAddr) -> ProtocolVersion {
+        ProtocolVersion::of(remote_socket_addr)
+    }
+    #[cfg(not(feature = "mqtt-sn-v2"))]
+    #[inline(always)]
+    fn version_of(_remote_socket_addr: &SocketAddr) -> ProtocolVersion {
+        ProtocolVersion::V1_2
+    }
+
+    /// Length of the header, the length field and the message type.
+    #[inline(always)]
+    pub fn body_offset(&self) -> usize {
+        self.header_len as usize
+    }
+
+    /// The message after the header, e.g. the flags of a SUBSCRIBE.
+    #[inline(always)]
+    pub fn body<'a>(&self, buf: &'a [u8]) -> &'a [u8] {
+        &buf[self.body_offset()..]
+    }
+
+    /// The message with the layout of the 1-octet length field, the recv
+    /// functions read the fields after the message type at the same offsets
+    /// for both header lengths. The first octet of the view of a 3-octet
+    /// length field isn't the length, msg_header.len is. Returns the view
+    /// and its size.
+    #[inline(always)]
+    pub fn short_view<'a>(
+        &self,
+        buf: &'a [u8],
+        size: usize,
+    ) -> (&'a [u8], usize) {
+        let offset = self.body_offset() - MsgHeaderLenEnum::Short as usize;
+        (&buf[offset..size], size - offset)
+    }
+
+    /// Clamped to 65535, the max. length of the 3-octet format.
+    pub fn set_max_datagram_size(size: usize) {
+        MAX_DATAGRAM_SIZE.store(size.min(u16::MAX as usize), Ordering::Relaxed);
+    }
+    pub fn max_datagram_size() -> usize {
+        MAX_DATAGRAM_SIZE.load(Ordering::Relaxed)
+    }
+    /// The max. datagram size, or the max. length of the reassembled
+    /// messages if the broker fragments, see Fragment::enable().
+    #[cfg(feature = "fragmentation")]
+    pub fn max_message_size() -> usize {
+        crate::fragment::Fragment::max_message_size()
+    }
+    #[cfg(not(feature = "fragmentation"))]
+    #[inline(always)]
+    pub fn max_message_size() -> usize {
+        MsgHeader::max_datagram_size()
+    }
+
+    /// Length field of the 3-octet format, short_len is the message length
+    /// with the 1-octet format.
+    pub fn long_len(short_len: usize) -> Result<u16, String> {
+        let len = short_len + 2;
+        if len > MsgHeader::max_message_size() {
+            return Err(eformat!("Message is too long", len));
+        }
+        Ok(len as u16)
+    }
+
+    /// Write the 1-octet length field if short_len < 256, otherwise the
+    /// 3-octet length field. Returns the message length.
+    pub fn put_len(
+        buf: &mut BytesMut,
+        short_len: usize,
+    ) -> Result<usize, String> {
+        if short_len < 256 {
+            buf.put_u8(short_len as u8);
+            Ok(short_len)
+        } else {
+            let len = MsgHeader::long_len(short_len)?;
+            buf.put_u8(1);
+            put_u16_be(buf, len);
+            Ok(len as usize)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_long_len_boundaries() {
+        use super::*;
+        use crate::conn::udp_conn;
+        let conn = udp_conn();
+        let addr = "127.0.0.1:1400".parse::<SocketAddr>().unwrap();
+        assert_eq!(MsgHeader::max_datagram_size(), DEFAULT_MAX_DATAGRAM_SIZE);
+        // (short_len, message length)
+        for (short_len, len) in [(255, 255), (256, 258), (1398, 1400)].iter() {
+            let mut buf = BytesMut::new();
+            assert_eq!(MsgHeader::put_len(&mut buf, *short_len), Ok(*len));
+            buf.put_u8(0x0C);
+            buf.resize(*len, 0);
+            let msg_header =
+                MsgHeader::try_read(&buf, *len, addr, conn.clone()).unwrap();
+            assert_eq!(msg_header.len as usize, *len);
+            assert_eq!(msg_header.msg_type, 0x0C);
+        }
+        // 1399 and 1400 don't fit with the 3-octet length field.
+        for short_len in [1399, 1400].iter() {
+            let mut buf = BytesMut::new();
+            assert!(MsgHeader::put_len(&mut buf, *short_len).is_err());
+        }
+        let mut buf = BytesMut::new();
+        buf.put_u8(1);
+        put_u16_be(&mut buf, 1401);
+        buf.put_u8(0x0C);
+        buf.resize(1401, 0);
+        assert!(MsgHeader::try_read(&buf, 1401, addr, conn.clone()).is_err());
+        assert!(MsgHeader::try_read(&[1, 0], 2, addr, conn).is_err());
+    }
+
+    /// The message with the 3-octet length field.
+    fn long(short: &[u8]) -> Vec<u8> {
+        let len = (short.len() + 2) as u16;
+        let mut buf = vec![1];
+        buf.extend_from_slice(&len.to_be_bytes());
+        buf.extend_from_slice(&short[1..]);
+        buf
+    }
+
+    #[test]
+    fn test_short_view() {
+        use super::*;
+        use crate::conn::udp_conn;
+        let conn = udp_conn();
+        let addr = "127.0.0.1:1401".parse::<SocketAddr>().unwrap();
+        // Every message type, with an empty, a short and a long body.
+        for msg_type in 0x00..=0x1Du8 {
+            for body_len in [0, 5, 300].iter() {
+                let body: Vec<u8> =
+                    (0..*body_len).map(|i| (i as u8) ^ msg_type).collect();
+                // The length octet is fixed by long().
+                let mut short = vec![0, msg_type];
+                short.extend_from_slice(&body);
+                let mut messages = vec![(long(&short), MsgHeaderLenEnum::Long)];
+                if short.len() < 256 {
+                    short[0] = short.len() as u8;
+                    messages.push((short, MsgHeaderLenEnum::Short));
+                }
+                for (buf, header_len) in messages.iter() {
+                    let size = buf.len();
+                    let msg_header =
+                        MsgHeader::try_read(buf, size, addr, conn.clone())
+                            .unwrap();
+                    assert_eq!(msg_header.msg_type, msg_type);
+                    assert_eq!(msg_header.len as usize, size);
+                    assert_eq!(msg_header.body_offset(), *header_len as usize);
+                    assert_eq!(msg_header.body(buf), &body[..]);
+                    let (view, view_size) = msg_header.short_view(buf, size);
+                    assert_eq!(view_size, body.len() + 2);
+                    assert_eq!(view.len(), view_size);
+                    assert_eq!(view[1], msg_type);
+                    assert_eq!(&view[2..], &body[..]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_long_header_recv() {
+        use crate::flags::QOS_LEVEL_1;
+        use crate::test_support::{LoopbackBroker, TestClient};
+        use crate::{
+            MSG_TYPE_PINGREQ, MSG_TYPE_PINGRESP, MSG_TYPE_PUBACK,
+            MSG_TYPE_PUBLISH, MSG_TYPE_REGACK, MSG_TYPE_REGISTER,
+            MSG_TYPE_SUBACK, MSG_TYPE_SUBSCRIBE, MSG_TYPE_UNSUBACK,
+            MSG_TYPE_UNSUBSCRIBE,
+        };
+        let mut test_client = TestClient::new(LoopbackBroker::addr()).unwrap();
+        test_client.connect("longHeader", 60, None).unwrap();
+        let topic = b"msg_hdr/long";
+        // REGISTER, topic_id 0, msg_id 1.
+        let mut register = vec![0, MSG_TYPE_REGISTER, 0, 0, 0, 1];
+        register.extend_from_slice(topic);
+        register[0] = register.len() as u8;
+        test_client.send(&long(&register)).unwrap();
+        let reg_ack = test_client.expect(MSG_TYPE_REGACK).unwrap();
+        assert_eq!(&reg_ack[4..], &[0, 1, 0]);
+        let topic_id = [reg_ack[2], reg_ack[3]];
+        // SUBSCRIBE with the topic name, msg_id 2.
+        let mut subscribe = vec![0, MSG_TYPE_SUBSCRIBE, QOS_LEVEL_1, 0, 2];
+        subscribe.extend_from_slice(topic);
+        subscribe[0] = subscribe.len() as u8;
+        test_client.send(&long(&subscribe)).unwrap();
+        let sub_ack = test_client.expect(MSG_TYPE_SUBACK).unwrap();
+        assert_eq!(&sub_ack[3..], &[topic_id[0], topic_id[1], 0, 2, 0]);
+        // QoS 1 PUBLISH, msg_id 3.
+        let mut publish = vec![0, MSG_TYPE_PUBLISH, QOS_LEVEL_1];
+        publish.extend_from_slice(&[topic_id[0], topic_id[1], 0, 3]);
+        publish.extend_from_slice(b"21.5");
+        publish[0] = publish.len() as u8;
+        test_client.send(&long(&publish)).unwrap();
+        let pub_ack = test_client.expect(MSG_TYPE_PUBACK).unwrap();
+        assert_eq!(&pub_ack[2..], &[topic_id[0], topic_id[1], 0, 3, 0]);
+        let received = test_client.recv_publish().unwrap();
+        assert_eq!(&received.payload[..], b"21.5");
+        // UNSUBSCRIBE, msg_id 4.
+        let mut unsubscribe = vec![0, MSG_TYPE_UNSUBSCRIBE, 0, 0, 4];
+        unsubscribe.extend_from_slice(topic);
+        unsubscribe[0] = unsubscribe.len() as u8;
+        test_client.send(&long(&unsubscribe)).unwrap();
+        let unsub_ack = test_client.expect(MSG_TYPE_UNSUBACK).unwrap();
+        assert_eq!(&unsub_ack[2..4], &[0, 4]);
+        test_client.send(&long(&[2, MSG_TYPE_PINGREQ])).unwrap();
+        test_client.expect(MSG_TYPE_PINGRESP).unwrap();
+        test_client.disconnect(None).unwrap();
+    }
 }
 /*
 #[cfg(test)]
@@ -0,0 +1,92 @@
+/// Message id allocator per connection.
+///
+/// The in-flight messages of a peer are keyed on (msg_type, msg_id) in the
+/// retransmit time wheel, two messages in flight to the same peer with the
+/// same msg_id would cancel each other's timers. MsgIdAllocator::next()
+/// returns increasing msg_ids for each peer address, wrapping from 0xFFFF
+/// to 1 (0 is not used), and skips the msg_ids still waiting for an ACK in
+/// the time wheel.
+/// MsgIdAllocator::next_broker() numbers the messages published by the
+/// broker itself, e.g. from a cluster peer or an embedder. Like the messages
+/// of a client, each QoS 1 & 2 subscriber gets its own msg_id from
+/// InFlight::acquire() when the message is sent.
+use hashbrown::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Mutex;
+
+use crate::{
+    broker_context::BrokerContext, eformat, function,
+    retransmit::RetransTimeWheel,
+};
+
+/// Last msg_ids of a broker, see BrokerContext.
+#[derive(Default)]
+pub(crate) struct MsgIdState {
+    last_msg_id: Mutex<HashMap<SocketAddr, u16>>,
+    broker_msg_id: AtomicU16,
+}
+
+#[inline(always)]
+fn state() -> &'static MsgIdState {
+    &BrokerContext::current().msg_id
+}
+
+#[derive(Debug, Clone)]
+pub struct MsgIdAllocator {}
+
+impl MsgIdAllocator {
+    /// Next free msg_id for the peer, Err if all msg_ids are in flight.
+    pub fn next(remote_addr: SocketAddr) -> Result<u16, String> {
+        let mut last_map = state().last_msg_id.lock().unwrap();
+        let last = last_map.entry(remote_addr).or_insert(0);
+        for _ in 0..u16::MAX {
+            *last = match last.wrapping_add(1) {
+                0 => 1,
+                msg_id => msg_id,
+            };
+            if !RetransTimeWheel::in_flight(remote_addr, *last) {
+                return Ok(*last);
+            }
+        }
+        Err(eformat!(remote_addr, "no free msg_id"))
+    }
+    /// msg_id of a message published by the broker, never 0.
+    pub fn next_broker() -> u16 {
+        loop {
+            let msg_id = state().broker_msg_id.fetch_add(1, Ordering::Relaxed);
+            if msg_id != 0 {
+                return msg_id;
+            }
+        }
+    }
+    /// Forget the peer when the connection is removed.
+    pub fn remove(remote_addr: &SocketAddr) {
+        state().last_msg_id.lock().unwrap().remove(remote_addr);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_msg_id_allocator() {
+        use super::*;
+        use crate::MSG_TYPE_PUBACK;
+        let addr = "127.0.0.1:1600".parse::<SocketAddr>().unwrap();
+        let addr2 = "127.0.0.2:1600".parse::<SocketAddr>().unwrap();
+        assert_eq!(MsgIdAllocator::next(addr), Ok(1));
+        assert_eq!(MsgIdAllocator::next(addr), Ok(2));
+        assert_eq!(MsgIdAllocator::next(addr2), Ok(1));
+        // 3 is in flight, skip it.
+        RetransTimeWheel::init();
+        RetransTimeWheel::schedule_timer(addr, MSG_TYPE_PUBACK, 0, 3, 1, "x")
+            .unwrap();
+        assert_eq!(MsgIdAllocator::next(addr), Ok(4));
+        RetransTimeWheel::cancel_timer(addr, MSG_TYPE_PUBACK, 3).unwrap();
+        // Wrap around from 0xFFFF to 1.
+        state().last_msg_id.lock().unwrap().insert(addr, u16::MAX);
+        assert_eq!(MsgIdAllocator::next(addr), Ok(1));
+        MsgIdAllocator::remove(&addr);
+        MsgIdAllocator::remove(&addr2);
+    }
+}
@@ -0,0 +1,70 @@
+/// Tracing spans of the connections and the messages.
+///
+/// dispatch() enters a "conn" span with the client address for each
+/// datagram. PUBLISH, PUBACK, PUBREC, PUBREL, PUBCOMP and the
+/// RetransTimeWheel enter a "msg" span with the address, the msg_id and the
+/// message type, the steps of a QoS 2 handshake share the address and the
+/// msg_id. The log macros called in a span are recorded in it when the
+/// subscriber captures the log records, e.g. tracing_subscriber with the
+/// tracing-log feature. The env filter selects the spans, e.g.
+/// RUST_LOG='broker_lib[msg{msg_id=7}]=trace'.
+use std::net::SocketAddr;
+use tracing::{debug_span, Span};
+
+#[derive(Debug, Clone)]
+pub struct MsgSpan {}
+
+impl MsgSpan {
+    /// Span of a datagram from addr.
+    #[inline(always)]
+    pub fn conn(addr: SocketAddr) -> Span {
+        debug_span!("conn", %addr)
+    }
+    /// Span of a message from or to addr, msg_type is the type of the
+    /// message, or of the ACK for the retransmit timers.
+    #[inline(always)]
+    pub fn msg(addr: SocketAddr, msg_type: u8, msg_id: u16) -> Span {
+        debug_span!("msg", %addr, msg_id, msg_type)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_msg_span() {
+        use super::*;
+        use crate::MSG_TYPE_PUBREC;
+        use std::io::{self, Write};
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct Captured(Arc<Mutex<Vec<u8>>>);
+        impl Write for Captured {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let addr = "127.0.0.1:1884".parse::<SocketAddr>().unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            let _conn_span = MsgSpan::conn(addr).entered();
+            let _msg_span = MsgSpan::msg(addr, MSG_TYPE_PUBREC, 7).entered();
+            tracing::debug!("PUBREC received");
+        });
+        let text =
+            String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(text.contains("conn{addr=127.0.0.1:1884}"));
+        let msg_span = "msg{addr=127.0.0.1:1884 msg_id=7 msg_type=15}";
+        assert!(text.contains(msg_span));
+        assert!(text.contains("PUBREC received"));
+    }
+}
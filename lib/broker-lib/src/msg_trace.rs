@@ -0,0 +1,318 @@
+/// Delivery tracing, follow one PUBLISH message end to end.
+///
+/// Tracing is armed for a topic id with MsgTrace::arm(), or by publishing
+/// the topic id (decimal string) to the $trace/arm flag topic.
+/// The next PUBLISH to the armed topic is tagged with a trace id, the
+/// trace id is carried in the "trace_id" annotation to the fan-out stage.
+/// Every stage the message passes is recorded in a TraceReport:
+/// decode, match, per-subscriber egress, acks and retransmits.
+///
+/// The reports are kept in memory per broker, see BrokerContext, the oldest
+/// report is dropped after MAX_TRACE_REPORTS reports.
+use chrono::{DateTime, Local};
+use hashbrown::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::{
+    annotation::Annotations, broker_context::BrokerContext,
+    filter::try_insert_topic_name, publish::Publish, MsgIdType, TopicIdType,
+};
+
+pub type TraceId = u32;
+
+pub const TRACE_TOPIC_ARM: &str = "$trace/arm";
+pub const TRACE_ANNOTATION_KEY: &str = "trace_id";
+pub const MAX_TRACE_REPORTS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceStage {
+    /// PUBLISH received and decoded from the publisher.
+    Decode,
+    /// Number of subscribers matched.
+    Match(usize),
+    /// PUBLISH sent to a subscriber.
+    Egress,
+    /// PUBLISH cached for an asleep subscriber.
+    Asleep,
+    /// Ack received, with the message type, e.g. PUBACK, PUBREC.
+    Ack(u8),
+    /// Message retransmitted, with the expected ack message type.
+    Retransmit(u8),
+    /// Retransmit gave up, with the expected ack message type.
+    Timeout(u8),
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceEvent {
+    pub time: DateTime<Local>,
+    pub addr: SocketAddr,
+    pub stage: TraceStage,
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceReport {
+    pub trace_id: TraceId,
+    pub topic_id: TopicIdType,
+    pub msg_id: MsgIdType,
+    pub publisher: SocketAddr,
+    pub events: Vec<TraceEvent>,
+}
+
+/// Traces of a broker, see BrokerContext.
+#[derive(Default)]
+pub(crate) struct MsgTraceState {
+    /// Number of active (addr, msg_id) entries, fast path check.
+    active_count: AtomicUsize,
+    trace_id_counter: Mutex<TraceId>,
+    arm_topic_id: Mutex<Option<TopicIdType>>,
+    armed_topics: Mutex<HashSet<TopicIdType>>,
+    /// (publisher or subscriber addr, msg_id) -> trace id
+    active: Mutex<HashMap<(SocketAddr, MsgIdType), TraceId>>,
+    trace_reports: Mutex<HashMap<TraceId, TraceReport>>,
+}
+
+#[inline(always)]
+fn state() -> &'static MsgTraceState {
+    &BrokerContext::current().msg_trace
+}
+
+#[derive(Debug, Clone)]
+pub struct MsgTrace {}
+
+impl MsgTrace {
+    /// Trace the next PUBLISH message to the topic id.
+    pub fn arm(topic_id: TopicIdType) {
+        state().armed_topics.lock().unwrap().insert(topic_id);
+    }
+    /// No topic is armed and the flag topic is not registered.
+    #[inline(always)]
+    pub fn is_idle() -> bool {
+        state().armed_topics.lock().unwrap().is_empty()
+            && state().arm_topic_id.lock().unwrap().is_none()
+    }
+    /// Register the $trace/arm flag topic, returns its topic id.
+    pub fn enable_flag_topic() -> Result<TopicIdType, String> {
+        let topic_id = try_insert_topic_name(TRACE_TOPIC_ARM.to_string())?;
+        *state().arm_topic_id.lock().unwrap() = Some(topic_id);
+        Ok(topic_id)
+    }
+    /// Arm tracing if the publish is to the $trace/arm flag topic.
+    /// Returns true if the message was consumed.
+    #[inline(always)]
+    pub fn try_arm(publish: &Publish) -> bool {
+        match *state().arm_topic_id.lock().unwrap() {
+            Some(topic_id) if topic_id == *publish.topic_id() => (),
+            _ => return false,
+        }
+        if let Ok(text) = str::from_utf8(&publish.data()[..]) {
+            if let Ok(topic_id) = text.trim().parse::<TopicIdType>() {
+                MsgTrace::arm(topic_id);
+            }
+        }
+        true
+    }
+
+    /// Start a trace if the topic is armed, the trace id is added to the
+    /// annotations.
+    #[inline(always)]
+    pub fn start(
+        publisher: SocketAddr,
+        publish: &Publish,
+        annotations: &mut Annotations,
+    ) -> Option<TraceId> {
+        let topic_id = *publish.topic_id();
+        {
+            let mut armed = state().armed_topics.lock().unwrap();
+            if armed.is_empty() || !armed.remove(&topic_id) {
+                return None;
+            }
+        }
+        let trace_id = {
+            let mut counter = state().trace_id_counter.lock().unwrap();
+            *counter = counter.wrapping_add(1);
+            *counter
+        };
+        let msg_id = *publish.msg_id();
+        let report = TraceReport {
+            trace_id,
+            topic_id,
+            msg_id,
+            publisher,
+            events: vec![TraceEvent {
+                time: Local::now(),
+                addr: publisher,
+                stage: TraceStage::Decode,
+            }],
+        };
+        {
+            let mut reports = state().trace_reports.lock().unwrap();
+            if reports.len() >= MAX_TRACE_REPORTS {
+                // drop the oldest report.
+                if let Some(oldest) = reports.keys().min().cloned() {
+                    reports.remove(&oldest);
+                }
+            }
+            reports.insert(trace_id, report);
+        }
+        MsgTrace::add_target(trace_id, publisher, msg_id);
+        annotations.insert(TRACE_ANNOTATION_KEY, &trace_id.to_string());
+        Some(trace_id)
+    }
+
+    /// Get the trace id from the annotations.
+    #[inline(always)]
+    pub fn trace_id(annotations: &Annotations) -> Option<TraceId> {
+        annotations
+            .get(TRACE_ANNOTATION_KEY)?
+            .parse::<TraceId>()
+            .ok()
+    }
+
+    /// Follow the (addr, msg_id) for acks and retransmits.
+    pub fn add_target(trace_id: TraceId, addr: SocketAddr, msg_id: MsgIdType) {
+        if state()
+            .active
+            .lock()
+            .unwrap()
+            .insert((addr, msg_id), trace_id)
+            .is_none()
+        {
+            state().active_count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Record a stage for a trace id.
+    pub fn record_trace(
+        trace_id: TraceId,
+        addr: SocketAddr,
+        stage: TraceStage,
+    ) {
+        if let Some(report) =
+            state().trace_reports.lock().unwrap().get_mut(&trace_id)
+        {
+            report.events.push(TraceEvent {
+                time: Local::now(),
+                addr,
+                stage,
+            });
+        }
+    }
+
+    /// Record a stage for a traced (addr, msg_id), no-op if the pair isn't
+    /// traced.
+    #[inline(always)]
+    pub fn record(addr: SocketAddr, msg_id: MsgIdType, stage: TraceStage) {
+        if state().active_count.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let trace_id = match state().active.lock().unwrap().get(&(addr, msg_id))
+        {
+            Some(trace_id) => *trace_id,
+            None => return,
+        };
+        MsgTrace::record_trace(trace_id, addr, stage);
+    }
+
+    /// Stop following the (addr, msg_id), called after the last ack.
+    #[inline(always)]
+    pub fn finish(addr: SocketAddr, msg_id: MsgIdType) {
+        if state().active_count.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        if state()
+            .active
+            .lock()
+            .unwrap()
+            .remove(&(addr, msg_id))
+            .is_some()
+        {
+            state().active_count.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn report(trace_id: TraceId) -> Option<TraceReport> {
+        state()
+            .trace_reports
+            .lock()
+            .unwrap()
+            .get(&trace_id)
+            .cloned()
+    }
+    /// All reports, sorted by trace id.
+    pub fn reports() -> Vec<TraceReport> {
+        let mut report_vec: Vec<TraceReport> = state()
+            .trace_reports
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        report_vec.sort_by_key(|report| report.trace_id);
+        report_vec
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_msg_trace() {
+        use super::*;
+        use crate::{MSG_TYPE_PUBACK, MSG_TYPE_PUBLISH};
+        use bytes::BytesMut;
+        let _context = BrokerContext::new().enter();
+
+        let publisher = "127.0.0.1:1300".parse::<SocketAddr>().unwrap();
+        let subscriber = "127.0.0.2:1300".parse::<SocketAddr>().unwrap();
+        let bytes = BytesMut::from(&b"hello"[..]);
+        let publish = Publish::new(901, 33, 1, 0, bytes);
+        let mut annotations = Annotations::new();
+
+        // Not armed, no trace.
+        assert_eq!(
+            MsgTrace::start(publisher, &publish, &mut annotations),
+            None
+        );
+        MsgTrace::arm(901);
+        let trace_id =
+            MsgTrace::start(publisher, &publish, &mut annotations).unwrap();
+        assert_eq!(MsgTrace::trace_id(&annotations), Some(trace_id));
+        // Only the next message is traced.
+        let mut annotations2 = Annotations::new();
+        assert_eq!(
+            MsgTrace::start(publisher, &publish, &mut annotations2),
+            None
+        );
+
+        MsgTrace::record(publisher, 33, TraceStage::Match(1));
+        MsgTrace::add_target(trace_id, subscriber, 33);
+        MsgTrace::record(subscriber, 33, TraceStage::Egress);
+        MsgTrace::record(
+            subscriber,
+            33,
+            TraceStage::Retransmit(MSG_TYPE_PUBACK),
+        );
+        MsgTrace::record(subscriber, 33, TraceStage::Ack(MSG_TYPE_PUBACK));
+        MsgTrace::finish(subscriber, 33);
+        MsgTrace::finish(publisher, 33);
+        // Not recorded after finish.
+        MsgTrace::record(subscriber, 33, TraceStage::Ack(MSG_TYPE_PUBLISH));
+
+        let report = MsgTrace::report(trace_id).unwrap();
+        let stages: Vec<TraceStage> =
+            report.events.iter().map(|e| e.stage.clone()).collect();
+        assert_eq!(
+            stages,
+            vec![
+                TraceStage::Decode,
+                TraceStage::Match(1),
+                TraceStage::Egress,
+                TraceStage::Retransmit(MSG_TYPE_PUBACK),
+                TraceStage::Ack(MSG_TYPE_PUBACK),
+            ]
+        );
+        dbg!(report);
+    }
+}
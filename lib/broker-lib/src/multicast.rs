@@ -2,12 +2,15 @@
 /// * use socket2::SockAddr::from(socket_addr) to convert.
 extern crate socket2;
 
-use crate::{function, search_gw::SearchGw};
+use crate::{
+    broker_lib::MqttSnClient, function, gw_info::GwInfoResponder,
+    search_gw::SearchGw,
+};
 
 use bytes::Bytes;
 use log::*;
 use std::io;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread::JoinHandle;
@@ -17,14 +20,51 @@ use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 
 pub const PORT: u16 = 7645;
 pub const SOCKET_READ_TIMEOUT_MS: u64 = 100;
+pub const ADVERTISE_PORT: u16 = 61000;
+pub const GW_INFO_PORT: u16 = 62000;
+pub const MULTICAST_ADDR_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 123);
+/// Link-local scope, same group id as MULTICAST_ADDR_V4.
+pub const MULTICAST_ADDR_V6: Ipv6Addr =
+    Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 123);
 
-fn multicast_socket(multicast_addr: &SocketAddr) -> io::Result<UdpSocket> {
-    dbg!(multicast_addr);
-    let domain = if multicast_addr.is_ipv4() {
-        Domain::ipv4()
+/// ADVERTISE and SEARCHGW/GWINFO groups of the socket's address family.
+pub fn multicast_groups(local_addr: &SocketAddr) -> (SocketAddr, SocketAddr) {
+    let ip: IpAddr = if local_addr.is_ipv4() {
+        MULTICAST_ADDR_V4.into()
     } else {
-        return Err(io::Error::new(io::ErrorKind::Other, "V6 not supported"));
+        MULTICAST_ADDR_V6.into()
     };
+    (
+        SocketAddr::new(ip, ADVERTISE_PORT),
+        SocketAddr::new(ip, GW_INFO_PORT),
+    )
+}
+
+/// Bind a UDP socket, an unspecified IPv6 address ("[::]:port") accepts
+/// IPv4 too, the IPv4 peers have IPv4-mapped addresses (::ffff:a.b.c.d).
+pub fn udp_bind(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(domain_of(&addr), Type::dgram(), None)?;
+    if let IpAddr::V6(ip) = addr.ip() {
+        socket.set_only_v6(!ip.is_unspecified())?;
+    }
+    socket.bind(&SockAddr::from(addr))?;
+    Ok(socket.into_udp_socket())
+}
+
+#[inline(always)]
+fn domain_of(addr: &SocketAddr) -> Domain {
+    if addr.is_ipv4() {
+        Domain::ipv4()
+    } else {
+        Domain::ipv6()
+    }
+}
+
+pub(crate) fn multicast_socket(
+    multicast_addr: &SocketAddr,
+) -> io::Result<UdpSocket> {
+    dbg!(multicast_addr);
+    let domain = domain_of(multicast_addr);
     if !multicast_addr.ip().is_multicast() {
         return Err(io::Error::new(
             io::ErrorKind::Other,
@@ -35,11 +75,15 @@ fn multicast_socket(multicast_addr: &SocketAddr) -> io::Result<UdpSocket> {
     // set read timeouts so that we don't hang waiting for packets
     // it allows the thread to perform other tasks while waiting for packets
     socket.set_read_timeout(Some(Duration::from_millis(100)))?;
-    socket.set_multicast_if_v4(&Ipv4Addr::new(0, 0, 0, 0))?;
-    socket.bind(&SockAddr::from(SocketAddr::new(
-        Ipv4Addr::new(0, 0, 0, 0).into(),
-        0,
-    )))?;
+    let unspecified: IpAddr = if multicast_addr.is_ipv4() {
+        socket.set_multicast_if_v4(&Ipv4Addr::UNSPECIFIED)?;
+        Ipv4Addr::UNSPECIFIED.into()
+    } else {
+        // Interface 0, the default interface.
+        socket.set_multicast_if_v6(0)?;
+        Ipv6Addr::UNSPECIFIED.into()
+    };
+    socket.bind(&SockAddr::from(SocketAddr::new(unspecified, 0)))?;
     // convert to UDP sockets
     Ok(socket.into_udp_socket())
 }
@@ -76,14 +120,8 @@ pub fn broadcast_loop(
 
 // this will be common for all our sockets
 pub fn new_udp_socket(addr: &SocketAddr) -> io::Result<Socket> {
-    let domain = if addr.is_ipv4() {
-        Domain::ipv4()
-    } else {
-        // Domain::ipv6()
-        return Err(io::Error::new(io::ErrorKind::Other, "V6 not supported"));
-    };
-
-    let socket = Socket::new(domain, Type::dgram(), Some(Protocol::udp()))?;
+    let socket =
+        Socket::new(domain_of(addr), Type::dgram(), Some(Protocol::udp()))?;
 
     // read timeouts, don't hang waiting for packets
     socket.set_read_timeout(Some(Duration::from_millis(
@@ -93,13 +131,18 @@ pub fn new_udp_socket(addr: &SocketAddr) -> io::Result<Socket> {
     Ok(socket)
 }
 
-pub fn gw_info_listen_loop(multicast_addr: SocketAddr) -> JoinHandle<()> {
+pub fn gw_info_listen_loop(
+    multicast_addr: SocketAddr,
+    client: MqttSnClient,
+) -> JoinHandle<()> {
     let join_handle = std::thread::Builder::new()
         .name(function!().to_string())
         .spawn(move || {
+            let _context = client.context.enter();
             // socket creation will go here...
             let listener = multicast_bind(multicast_addr).unwrap();
             println!("server: joined: {}", multicast_addr);
+            let mut responder = GwInfoResponder::new(client.gw_id);
 
             // use while loop to check for condition
             loop {
@@ -110,10 +153,15 @@ pub fn gw_info_listen_loop(multicast_addr: SocketAddr) -> JoinHandle<()> {
                 match listener.recv_from(&mut buf) {
                     Ok((len, remote_addr)) => {
                         let data = &buf[..len];
-                        if let Err(why) =
-                            SearchGw::recv(data, len, &remote_addr)
-                        {
-                            error!("{:?}", why);
+                        match SearchGw::recv(data, len, &remote_addr) {
+                            Ok(radius) => {
+                                responder.schedule(
+                                    remote_addr,
+                                    radius,
+                                    client.is_congested(),
+                                );
+                            }
+                            Err(why) => error!("{:?}", why),
                         }
                     }
                     Err(err) => {
@@ -126,12 +174,15 @@ pub fn gw_info_listen_loop(multicast_addr: SocketAddr) -> JoinHandle<()> {
                         }
                     }
                 }
+                responder.send_due();
             }
         })
         .unwrap();
     join_handle
 }
-fn multicast_bind(multicast_addr: SocketAddr) -> io::Result<UdpSocket> {
+pub(crate) fn multicast_bind(
+    multicast_addr: SocketAddr,
+) -> io::Result<UdpSocket> {
     let ip_addr = multicast_addr.ip();
     if !ip_addr.is_multicast() {
         return Err(io::Error::new(
@@ -139,17 +190,11 @@ fn multicast_bind(multicast_addr: SocketAddr) -> io::Result<UdpSocket> {
             "Not a multicast IP address",
         ));
     }
-    let domain = if multicast_addr.is_ipv4() {
-        Domain::ipv4()
-    } else {
-        // Domain::ipv6()
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "IPV6 is not supported",
-        ));
-    };
-
-    let socket = Socket::new(domain, Type::dgram(), Some(Protocol::udp()))?;
+    let socket = Socket::new(
+        domain_of(&multicast_addr),
+        Type::dgram(),
+        Some(Protocol::udp()),
+    )?;
 
     // read timeouts so that we don't hang waiting for packets
     socket.set_read_timeout(Some(Duration::from_millis(100)))?;
@@ -158,15 +203,19 @@ fn multicast_bind(multicast_addr: SocketAddr) -> io::Result<UdpSocket> {
         IpAddr::V4(ref addr_v4) => {
             dbg!(addr_v4);
             socket.join_multicast_v4(addr_v4, &Ipv4Addr::new(0, 0, 0, 0))?;
+            socket.bind(&socket2::SockAddr::from(multicast_addr))?;
         }
-        IpAddr::V6(ref _addr_v6) => {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "IPV6 is not supported",
-            ));
+        IpAddr::V6(ref addr_v6) => {
+            dbg!(addr_v6);
+            socket.join_multicast_v6(addr_v6, 0)?;
+            socket.set_only_v6(true)?;
+            // The link-local group needs a scope id to bind, bind to [::].
+            socket.bind(&socket2::SockAddr::from(SocketAddr::new(
+                Ipv6Addr::UNSPECIFIED.into(),
+                multicast_addr.port(),
+            )))?;
         }
     };
-    socket.bind(&socket2::SockAddr::from(multicast_addr))?;
     // convert to standard UDP sockets
     Ok(socket.into_udp_socket())
 }
@@ -240,3 +289,29 @@ fn test_ipv6_multicast() {
     test_multicast("ipv6", *IPV6);
 }
 */
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_multicast_groups() {
+        use super::*;
+        let v4 = "0.0.0.0:60000".parse::<SocketAddr>().unwrap();
+        let (advertise, gw_info) = multicast_groups(&v4);
+        assert_eq!(
+            advertise,
+            "224.0.0.123:61000".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(gw_info, "224.0.0.123:62000".parse::<SocketAddr>().unwrap());
+        let v6 = "[::]:60000".parse::<SocketAddr>().unwrap();
+        let (advertise, gw_info) = multicast_groups(&v6);
+        assert_eq!(
+            advertise,
+            "[ff02::7b]:61000".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(gw_info, "[ff02::7b]:62000".parse::<SocketAddr>().unwrap());
+        assert!(advertise.ip().is_multicast());
+        let socket = udp_bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        assert!(socket.local_addr().unwrap().is_ipv4());
+        assert!(multicast_socket(&v6).is_err());
+    }
+}
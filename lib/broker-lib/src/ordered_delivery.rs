@@ -0,0 +1,274 @@
+/// Ordered delivery, the messages of a publisher on a topic are sent to
+/// the subscribers in the order they were received.
+///
+/// A QoS 2 PUBLISH is sent when its PUBREL is received, a later QoS 0 or 1
+/// PUBLISH of the same publisher on the same topic would overtake it. With
+/// OrderedDelivery::enable(), the QoS 2 PUBLISH is held until its PUBREL
+/// and the later messages on the topic are queued behind it. They are sent
+/// when the messages before them are, see release(). A QoS 2 PUBLISH
+/// without a PUBREL before the last retransmit is aborted and the messages
+/// behind it are sent. The queue of a publisher is dropped with its
+/// connection. Disabled by default, the fast path is off while enabled.
+use hashbrown::HashMap;
+use log::*;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::{
+    broker_lib::MqttSnClient, pub_msg_cache::PubMsgCache, publish::Publish,
+    rich_publish::RichPublish, MsgIdType, TopicIdType,
+};
+
+#[derive(Debug)]
+enum Entry {
+    /// QoS 2 PUBLISH waiting for its PUBREL.
+    Held(MsgIdType),
+    /// Sent when the entries before it are.
+    Ready(PubMsgCache),
+}
+
+type Queues = HashMap<(SocketAddr, TopicIdType), VecDeque<Entry>>;
+
+lazy_static! {
+    static ref ORDERED_ENABLED: AtomicBool = AtomicBool::new(false);
+    static ref ORDERED_QUEUES: Mutex<Queues> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone)]
+pub struct OrderedDelivery {}
+
+impl OrderedDelivery {
+    pub fn enable() {
+        ORDERED_ENABLED.store(true, Ordering::Relaxed);
+    }
+    /// Stop holding, the queued messages are sent on their PUBREL.
+    pub fn disable() {
+        ORDERED_ENABLED.store(false, Ordering::Relaxed);
+    }
+    #[inline(always)]
+    pub fn is_enabled() -> bool {
+        ORDERED_ENABLED.load(Ordering::Relaxed)
+    }
+
+    /// Hold the messages of the topic behind the QoS 2 PUBLISH until its
+    /// PUBREL.
+    pub fn hold(
+        remote_addr: SocketAddr,
+        topic_id: TopicIdType,
+        msg_id: MsgIdType,
+    ) {
+        if !OrderedDelivery::is_enabled() {
+            return;
+        }
+        let mut queues = ORDERED_QUEUES.lock().unwrap();
+        queues
+            .entry((remote_addr, topic_id))
+            .or_insert_with(VecDeque::new)
+            .push_back(Entry::Held(msg_id));
+    }
+
+    /// A QoS 2 PUBLISH of the publisher is held on the topic.
+    #[inline(always)]
+    pub fn is_held(remote_addr: SocketAddr, topic_id: TopicIdType) -> bool {
+        OrderedDelivery::is_enabled()
+            && ORDERED_QUEUES
+                .lock()
+                .unwrap()
+                .contains_key(&(remote_addr, topic_id))
+    }
+
+    /// Queue the message behind the held messages of its topic, or send it
+    /// if they were released meanwhile.
+    pub fn queue(
+        remote_addr: SocketAddr,
+        cache: PubMsgCache,
+        client: &MqttSnClient,
+    ) -> Result<(), String> {
+        let key = (remote_addr, cache.publish.topic_id);
+        {
+            let mut queues = ORDERED_QUEUES.lock().unwrap();
+            if let Some(queue) = queues.get_mut(&key) {
+                queue.push_back(Entry::Ready(cache));
+                return Ok(());
+            }
+        }
+        OrderedDelivery::send(cache, remote_addr, client)
+    }
+
+    /// The PUBREL of the QoS 2 PUBLISH, send it and the messages queued
+    /// behind it up to the next held message.
+    pub fn release(
+        remote_addr: SocketAddr,
+        msg_id: MsgIdType,
+        cache: PubMsgCache,
+        client: &MqttSnClient,
+    ) -> Result<(), String> {
+        let key = (remote_addr, cache.publish.topic_id);
+        let mut result = Ok(());
+        for cache in OrderedDelivery::take(key, msg_id, Some(cache)) {
+            if let Err(why) = OrderedDelivery::send(cache, remote_addr, client)
+            {
+                error!("{}", why);
+                result = Err(why);
+            }
+        }
+        result
+    }
+
+    /// No PUBREL before the last retransmit, the message is dropped and
+    /// the messages queued behind it are sent.
+    pub fn abort(
+        remote_addr: SocketAddr,
+        msg_id: MsgIdType,
+        client: &MqttSnClient,
+    ) {
+        let key = {
+            let queues = ORDERED_QUEUES.lock().unwrap();
+            queues
+                .iter()
+                .find(|((addr, _topic_id), queue)| {
+                    *addr == remote_addr
+                        && OrderedDelivery::position(queue, msg_id).is_some()
+                })
+                .map(|(key, _queue)| *key)
+        };
+        let key = match key {
+            Some(key) => key,
+            None => return,
+        };
+        for cache in OrderedDelivery::take(key, msg_id, None) {
+            if let Err(why) = OrderedDelivery::send(cache, remote_addr, client)
+            {
+                error!("{}", why);
+            }
+        }
+    }
+
+    /// Drop the queues of the publisher.
+    pub fn remove(remote_addr: &SocketAddr) {
+        ORDERED_QUEUES
+            .lock()
+            .unwrap()
+            .retain(|(addr, _topic_id), _queue| addr != remote_addr);
+    }
+
+    /// Replace the held msg_id with the released message, or remove it
+    /// without one, and pop the entries ready at the front. A message
+    /// without a hold is returned alone.
+    fn take(
+        key: (SocketAddr, TopicIdType),
+        msg_id: MsgIdType,
+        released: Option<PubMsgCache>,
+    ) -> Vec<PubMsgCache> {
+        let mut queues = ORDERED_QUEUES.lock().unwrap();
+        let queue = match queues.get_mut(&key) {
+            Some(queue) => queue,
+            None => return released.into_iter().collect(),
+        };
+        match (OrderedDelivery::position(queue, msg_id), released) {
+            (Some(index), Some(cache)) => queue[index] = Entry::Ready(cache),
+            (Some(index), None) => {
+                queue.remove(index);
+            }
+            (None, released) => return released.into_iter().collect(),
+        }
+        let mut ready = Vec::new();
+        while let Some(Entry::Ready(_)) = queue.front() {
+            if let Some(Entry::Ready(cache)) = queue.pop_front() {
+                ready.push(cache);
+            }
+        }
+        if queue.is_empty() {
+            queues.remove(&key);
+        }
+        ready
+    }
+
+    fn position(queue: &VecDeque<Entry>, msg_id: MsgIdType) -> Option<usize> {
+        queue.iter().position(|entry| match entry {
+            Entry::Held(held) => *held == msg_id,
+            Entry::Ready(_) => false,
+        })
+    }
+
+    fn send(
+        cache: PubMsgCache,
+        remote_addr: SocketAddr,
+        client: &MqttSnClient,
+    ) -> Result<(), String> {
+        RichPublish::forward(
+            &cache.publish,
+            remote_addr,
+            cache.received_at,
+            &cache.annotations,
+            client,
+        );
+        #[cfg(feature = "bridge")]
+        crate::cluster::Cluster::forward(&cache.publish, &cache.annotations);
+        Publish::send_msg_to_subscribers(
+            cache.subscriber_vec,
+            cache.publish,
+            &cache.annotations,
+            client,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_ordered_delivery() {
+        use super::*;
+        use crate::annotation::Annotations;
+        use crate::flags::{QOS_LEVEL_0, QOS_LEVEL_2, RETAIN_FALSE};
+        use bytes::Bytes;
+        let addr = "10.8.0.1:1884".parse::<SocketAddr>().unwrap();
+        let cache = |msg_id, qos| PubMsgCache {
+            publish: Publish::new(
+                70,
+                msg_id,
+                qos,
+                RETAIN_FALSE,
+                Bytes::from("ordered"),
+            ),
+            subscriber_vec: Vec::new(),
+            annotations: Annotations::new(),
+            received_at: std::time::SystemTime::now(),
+        };
+        let msg_ids = |ready: Vec<PubMsgCache>| -> Vec<MsgIdType> {
+            ready.iter().map(|cache| cache.publish.msg_id).collect()
+        };
+        OrderedDelivery::enable();
+        // QoS 2 msg_id 1, QoS 0 msg_id 2, QoS 2 msg_id 3, QoS 0 msg_id 4.
+        OrderedDelivery::hold(addr, 70, 1);
+        assert!(OrderedDelivery::is_held(addr, 70));
+        assert!(!OrderedDelivery::is_held(addr, 71));
+        let key = (addr, 70);
+        let push = |cache| {
+            let mut queues = ORDERED_QUEUES.lock().unwrap();
+            queues.get_mut(&key).unwrap().push_back(Entry::Ready(cache));
+        };
+        push(cache(2, QOS_LEVEL_0));
+        OrderedDelivery::hold(addr, 70, 3);
+        push(cache(4, QOS_LEVEL_0));
+        // The PUBREL of 3 first, it waits for 1.
+        let ready = OrderedDelivery::take(key, 3, Some(cache(3, QOS_LEVEL_2)));
+        assert!(ready.is_empty());
+        let ready = OrderedDelivery::take(key, 1, Some(cache(1, QOS_LEVEL_2)));
+        assert_eq!(msg_ids(ready), vec![1, 2, 3, 4]);
+        assert!(!OrderedDelivery::is_held(addr, 70));
+        // Aborted, the message behind is ready.
+        OrderedDelivery::hold(addr, 70, 5);
+        push(cache(6, QOS_LEVEL_0));
+        assert_eq!(msg_ids(OrderedDelivery::take(key, 5, None)), vec![6]);
+        // Not held.
+        let ready = OrderedDelivery::take(key, 7, Some(cache(7, QOS_LEVEL_2)));
+        assert_eq!(msg_ids(ready), vec![7]);
+        OrderedDelivery::hold(addr, 70, 8);
+        OrderedDelivery::remove(&addr);
+        assert!(!OrderedDelivery::is_held(addr, 70));
+        OrderedDelivery::disable();
+    }
+}
@@ -21,15 +21,25 @@ PINGRESP message, returns the client back to the asleep state, and restarts the
 
 */
 
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use custom_debug::Debug;
 use getset::{CopyGetters, Getters, MutGetters};
 use std::mem;
+use std::net::SocketAddr;
 use std::str; // NOTE: needed for MutGetters
 
 use crate::{
-    broker_lib::MqttSnClient, eformat, function, msg_hdr::MsgHeader,
-    msg_hdr::*, ping_resp::PingResp, MSG_LEN_PINGREQ_HEADER, MSG_TYPE_PINGREQ,
+    asleep_msg_cache::AsleepMsgCache,
+    broker_lib::MqttSnClient,
+    client_id::ClientId,
+    codec::{encode_packet, Packet},
+    connection::{ConnEvent, Connection, StateEnum2},
+    decode::Decode,
+    eformat, function,
+    in_flight::InFlight,
+    msg_hdr::MsgHeader,
+    ping_resp::PingResp,
+    MSG_LEN_PINGREQ_HEADER, MSG_TYPE_PINGREQ,
 };
 
 #[derive(Debug, Clone, Getters, MutGetters, CopyGetters, Default)]
@@ -41,17 +51,6 @@ pub struct PingReq {
     client_id: String,
 }
 
-#[derive(Debug, Clone, Getters, MutGetters, CopyGetters, Default)]
-#[getset(get, set)]
-struct PingReq4 {
-    // NOTE: no pub
-    one: u8,
-    len: u16,
-    #[debug(format = "0x{:x}")]
-    msg_type: u8,
-    client_id: String,
-}
-
 impl PingReq {
     #[inline(always)]
     pub fn recv(
@@ -60,21 +59,83 @@ impl PingReq {
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
-        match msg_header.header_len {
-            MsgHeaderLenEnum::Short => {
-                // TODO update ping timer.
-                let (_ping_req, _read_fixed_len) =
-                    PingReq::try_read(buf, size).unwrap();
-            }
-            MsgHeaderLenEnum::Long => {
-                // TODO update ping timer.
-                let (_ping_req, _read_fixed_len) =
-                    PingReq4::try_read(buf, size).unwrap();
-            }
+        // TODO update ping timer.
+        let (ping_req, _read_fixed_len) =
+            Decode::read(PingReq::try_read(buf, size), size, &msg_header)?;
+        let client_id = ping_req.client_id;
+        let remote_socket_addr = msg_header.remote_socket_addr;
+        // A PINGREQ with the client id is from a sleeping client, section 6.14.
+        if client_id.is_empty()
+            || !PingReq::wake(client_id, client, remote_socket_addr)?
+        {
+            return PingResp::send(client, msg_header);
         }
-        PingResp::send(client, msg_header)?;
+        PingReq::try_sleep(remote_socket_addr, client)?;
         Ok(())
     }
+
+    /// The PINGRESP ends the transfer of the buffered messages, the awake
+    /// client goes back to sleep once the QoS 1 and 2 messages sent to it
+    /// are acknowledged or dropped. InFlight::release() calls it again when
+    /// the last one is. Returns true if the client is asleep again.
+    pub fn try_sleep(
+        remote_socket_addr: SocketAddr,
+        client: &MqttSnClient,
+    ) -> Result<bool, String> {
+        if InFlight::in_flight(&remote_socket_addr) > 0
+            || InFlight::queued(&remote_socket_addr) > 0
+        {
+            return Ok(false);
+        }
+        match Connection::get_state(&remote_socket_addr) {
+            Ok(StateEnum2::AWAKE) => {}
+            _ => return Ok(false),
+        }
+        Connection::transition(
+            &remote_socket_addr,
+            ConnEvent::WakeDone,
+            &*client.events,
+        )?;
+        PingResp::send_to(client, remote_socket_addr)?;
+        Ok(true)
+    }
+
+    /// Send the buffered messages to the awake client, oldest first.
+    /// Returns false if the client is not asleep.
+    fn wake(
+        client_id: String,
+        client: &MqttSnClient,
+        remote_socket_addr: SocketAddr,
+    ) -> Result<bool, String> {
+        let client_id = Bytes::from(client_id);
+        if !ClientId::contains(&client_id, &remote_socket_addr) {
+            return Err(eformat!(remote_socket_addr, "unknown", client_id));
+        }
+        match Connection::get_state(&remote_socket_addr)? {
+            StateEnum2::ASLEEP => {}
+            _ => return Ok(false),
+        }
+        Connection::transition(
+            &remote_socket_addr,
+            ConnEvent::Wake,
+            &*client.events,
+        )?;
+        let _sent = AsleepMsgCache::flush(remote_socket_addr, client);
+        Ok(true)
+    }
+    /// Broker-initiated PINGREQ without the client id, probes an idle client
+    /// before the lost connection procedure.
+    pub fn probe(
+        client: &MqttSnClient,
+        remote_socket_addr: SocketAddr,
+    ) -> Result<(), String> {
+        let buf: &[u8] = &[MSG_LEN_PINGREQ_HEADER, MSG_TYPE_PINGREQ];
+        let bytes = BytesMut::from(buf);
+        match client.egress_tx.try_send((remote_socket_addr, bytes)) {
+            Ok(()) => Ok(()),
+            Err(err) => Err(eformat!(remote_socket_addr, err)),
+        }
+    }
     #[inline(always)]
     pub fn send(
         client_id: String,
@@ -82,24 +143,49 @@ impl PingReq {
         msg_header: MsgHeader,
     ) -> Result<(), String> {
         let remote_socket_addr = msg_header.remote_socket_addr;
-        let len = client_id.len() + MSG_LEN_PINGREQ_HEADER as usize;
-        let mut bytes = BytesMut::with_capacity(len);
-        if len < 256 {
-            let ping_req = PingReq {
-                len: len as u8,
-                msg_type: MSG_TYPE_PINGREQ,
-                client_id,
-            };
-            ping_req.try_write(&mut bytes);
-            match client
-                .egress_tx
-                .try_send((remote_socket_addr, bytes.to_owned()))
-            {
-                Ok(_) => Ok(()),
-                Err(err) => Err(eformat!(remote_socket_addr, err)),
-            }
-        } else {
-            Err(eformat!(remote_socket_addr, "len too long", len))
+        let bytes = encode_packet(&Packet::PingReq {
+            client_id: client_id.as_bytes(),
+        })?;
+        match client.egress_tx.try_send((remote_socket_addr, bytes)) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(eformat!(remote_socket_addr, err)),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_awake_window() {
+        use super::*;
+        use crate::flags::{QOS_LEVEL_1, QOS_LEVEL_2, RETAIN_FALSE};
+        use crate::test_support::{LoopbackBroker, TestClient};
+        let broker = LoopbackBroker::addr();
+        let mut sleeper = TestClient::new(broker).unwrap();
+        sleeper.connect("awakeSleeper", 60, None).unwrap();
+        let (topic_id, _) =
+            sleeper.subscribe("awake/temp", QOS_LEVEL_2).unwrap();
+        sleeper.disconnect(Some(60)).unwrap();
+        let mut publisher = TestClient::new(broker).unwrap();
+        publisher.connect("awakePublisher", 60, None).unwrap();
+        assert_eq!(publisher.register("awake/temp"), Ok(topic_id));
+        publisher
+            .publish(topic_id, QOS_LEVEL_1, RETAIN_FALSE, b"one")
+            .unwrap();
+        publisher
+            .publish(topic_id, QOS_LEVEL_2, RETAIN_FALSE, b"two")
+            .unwrap();
+        // The PINGRESP follows the PUBACK and the PUBCOMP.
+        let publishes = sleeper.ping(Some("awakeSleeper")).unwrap();
+        let payloads: Vec<&[u8]> = publishes
+            .iter()
+            .map(|publish| &publish.payload[..])
+            .collect();
+        assert_eq!(payloads, vec![&b"one"[..], &b"two"[..]]);
+        assert_eq!(publishes[1].qos, QOS_LEVEL_2);
+        let addr = sleeper.local_addr();
+        assert_eq!(Connection::get_state(&addr), Ok(StateEnum2::ASLEEP));
+        assert_eq!(InFlight::in_flight(&addr), 0);
+        publisher.disconnect(None).unwrap();
+    }
+}
@@ -11,12 +11,13 @@ messages for that client, see Section 6.14 for further details.
 */
 
 use crate::{
-    broker_lib::MqttSnClient, eformat, function, msg_hdr::MsgHeader,
-    MSG_LEN_PINGRESP, MSG_TYPE_PINGRESP,
+    broker_lib::MqttSnClient, client_mode::ClientMode, codec, eformat,
+    function, msg_hdr::MsgHeader,
 };
 use bytes::{BufMut, BytesMut};
 use custom_debug::Debug;
 use getset::{CopyGetters, Getters, MutGetters};
+use std::net::SocketAddr;
 
 #[derive(
     Debug,
@@ -36,6 +37,8 @@ pub struct PingResp {
     pub msg_type: u8,
 }
 
+codec!(PingResp, {});
+
 impl PingResp {
     pub fn recv(
         buf: &[u8],
@@ -44,20 +47,30 @@ impl PingResp {
         msg_header: MsgHeader,
     ) -> Result<(), String> {
         let remote_socket_addr = msg_header.remote_socket_addr;
-        if size == MSG_LEN_PINGRESP as usize && buf[0] == MSG_LEN_PINGRESP {
-            // TODO update ping timer.
-            Ok(())
-        } else {
-            Err(eformat!(remote_socket_addr, "len err", size))
+        match PingResp::decode(buf, size) {
+            // The answer to a keep alive probe, the ingress has already
+            // rescheduled the keep alive of the client.
+            Ok(()) => {
+                ClientMode::on_ping_resp(&remote_socket_addr);
+                Ok(())
+            }
+            Err(why) => Err(eformat!(remote_socket_addr, why)),
         }
     }
     pub fn send(
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
-        let remote_socket_addr = msg_header.remote_socket_addr;
-        let buf: &[u8] = &[MSG_LEN_PINGRESP, MSG_TYPE_PINGRESP];
-        let bytes = BytesMut::from(buf);
+        PingResp::send_to(client, msg_header.remote_socket_addr)
+    }
+
+    /// PINGRESP without a received PINGREQ, e.g. at the end of the awake
+    /// window of a sleeping client.
+    pub fn send_to(
+        client: &MqttSnClient,
+        remote_socket_addr: SocketAddr,
+    ) -> Result<(), String> {
+        let bytes = PingResp::encode();
         match client.egress_tx.try_send((remote_socket_addr, bytes)) {
             Ok(()) => Ok(()),
             Err(err) => Err(eformat!(remote_socket_addr, err)),
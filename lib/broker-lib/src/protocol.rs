@@ -0,0 +1,205 @@
+/// Protocol versions of the broker, negotiated from the ProtocolId of the
+/// CONNECT.
+///
+/// MQTT-SN 1.2 is always served. With the mqtt-sn-v2 feature the broker
+/// serves the clients of the OASIS MQTT-SN 2.0 draft too, ProtocolId 0x02:
+///
+/// CONNECT: Length, MsgType, Flags, ProtocolId, KeepAlive (2 octets),
+/// SessionExpiryInterval (4 octets), MaxPacketSize (2 octets), ClientId.
+///
+/// The Flags of 2.0 are Auth (bit 3), Will (bit 2) and CleanStart (bit 1).
+/// The CONNECT of a 2.0 client is mapped to the 1.2 layout by
+/// ProtocolVersion::normalize(), the connection, the will setup and the
+/// sessions are the same for both versions. The CONNACK of a 2.0 client has
+/// the SessionExpiryInterval after the ReturnCode, the broker keeps the
+/// sessions until a CleanStart, SESSION_EXPIRY_NEVER. The version of a
+/// message is the one of the connection, see MsgHeader::version().
+///
+/// Not supported yet: the AUTH exchange, a CONNECT with the Auth flag is
+/// rejected, and the long topic names of the 2.0 PUBLISH. The MaxPacketSize
+/// of the client isn't enforced, the broker doesn't send messages longer
+/// than MsgHeader::max_datagram_size().
+use std::net::SocketAddr;
+
+#[cfg(feature = "mqtt-sn-v2")]
+use bytes::Buf;
+
+use crate::{connect::Connect, connection::Connection, eformat, function};
+
+/// MQTT-SN 1.2.
+pub const PROTOCOL_ID_V1_2: u8 = 0x01;
+/// MQTT-SN 2.0, OASIS draft.
+#[cfg(feature = "mqtt-sn-v2")]
+pub const PROTOCOL_ID_V2_0: u8 = 0x02;
+
+/// CONNECT flags of 2.0.
+#[cfg(feature = "mqtt-sn-v2")]
+pub const V2_FLAG_AUTH: u8 = 0b0000_1000;
+#[cfg(feature = "mqtt-sn-v2")]
+pub const V2_FLAG_WILL: u8 = 0b0000_0100;
+#[cfg(feature = "mqtt-sn-v2")]
+pub const V2_FLAG_CLEAN_START: u8 = 0b0000_0010;
+/// SessionExpiry and MaxPacketSize of the 2.0 CONNECT.
+#[cfg(feature = "mqtt-sn-v2")]
+const V2_CONNECT_EXT_LEN: usize = 6;
+/// SessionExpiryInterval of the 2.0 CONNACK, the session doesn't expire.
+#[cfg(feature = "mqtt-sn-v2")]
+pub const SESSION_EXPIRY_NEVER: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    V1_2,
+    #[cfg(feature = "mqtt-sn-v2")]
+    V2_0,
+}
+
+impl Default for ProtocolVersion {
+    fn default() -> Self {
+        ProtocolVersion::V1_2
+    }
+}
+
+impl ProtocolVersion {
+    pub fn from_protocol_id(protocol_id: u8) -> Result<Self, String> {
+        match protocol_id {
+            PROTOCOL_ID_V1_2 => Ok(ProtocolVersion::V1_2),
+            #[cfg(feature = "mqtt-sn-v2")]
+            PROTOCOL_ID_V2_0 => Ok(ProtocolVersion::V2_0),
+            _ => Err(eformat!("protocol id", protocol_id)),
+        }
+    }
+
+    pub fn protocol_id(self) -> u8 {
+        match self {
+            ProtocolVersion::V1_2 => PROTOCOL_ID_V1_2,
+            #[cfg(feature = "mqtt-sn-v2")]
+            ProtocolVersion::V2_0 => PROTOCOL_ID_V2_0,
+        }
+    }
+
+    /// The version of the client connected from socket_addr, 1.2 without
+    /// a connection.
+    pub fn of(socket_addr: &SocketAddr) -> Self {
+        Connection::get_protocol_id(socket_addr)
+            .and_then(|protocol_id| {
+                ProtocolVersion::from_protocol_id(protocol_id).ok()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Map the CONNECT to the 1.2 layout: the flags, and the client id
+    /// without the fields of 2.0.
+    pub fn normalize(self, connect: &mut Connect) -> Result<(), String> {
+        match self {
+            ProtocolVersion::V1_2 => Ok(()),
+            #[cfg(feature = "mqtt-sn-v2")]
+            ProtocolVersion::V2_0 => {
+                use crate::flags::{CLEAN_SESSION_TRUE, WILL_TRUE};
+                if connect.flags & V2_FLAG_AUTH != 0 {
+                    return Err(eformat!("AUTH not supported"));
+                }
+                if connect.client_id.len() < V2_CONNECT_EXT_LEN {
+                    return Err(eformat!(
+                        "CONNECT too short",
+                        connect.client_id.len()
+                    ));
+                }
+                let mut ext = connect.client_id.split_to(V2_CONNECT_EXT_LEN);
+                let session_expiry = ext.get_u32();
+                let max_packet_size = ext.get_u16();
+                dbg!((session_expiry, max_packet_size));
+                let mut flags = 0;
+                if connect.flags & V2_FLAG_WILL != 0 {
+                    flags |= WILL_TRUE;
+                }
+                if connect.flags & V2_FLAG_CLEAN_START != 0 {
+                    flags |= CLEAN_SESSION_TRUE;
+                }
+                connect.flags = flags;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_protocol_version() {
+        use super::*;
+        use bytes::Bytes;
+        assert_eq!(
+            ProtocolVersion::from_protocol_id(PROTOCOL_ID_V1_2),
+            Ok(ProtocolVersion::V1_2)
+        );
+        assert!(ProtocolVersion::from_protocol_id(0x00).is_err());
+        let addr = "127.0.0.1:1883".parse::<SocketAddr>().unwrap();
+        assert_eq!(ProtocolVersion::of(&addr), ProtocolVersion::V1_2);
+        let mut connect = Connect {
+            flags: 0b0000_1100,
+            protocol_id: PROTOCOL_ID_V1_2,
+            client_id: Bytes::from("v1"),
+            ..Default::default()
+        };
+        ProtocolVersion::V1_2.normalize(&mut connect).unwrap();
+        assert_eq!(connect.flags, 0b0000_1100);
+        assert_eq!(connect.client_id, Bytes::from("v1"));
+        #[cfg(feature = "mqtt-sn-v2")]
+        {
+            use crate::flags::{flag_is_clean_session, flag_is_will};
+            let mut client_id = vec![0, 0, 0x0e, 0x10, 0x05, 0x00];
+            client_id.extend_from_slice(b"v2");
+            let mut connect = Connect {
+                flags: V2_FLAG_CLEAN_START,
+                protocol_id: PROTOCOL_ID_V2_0,
+                client_id: Bytes::from(client_id),
+                ..Default::default()
+            };
+            let version =
+                ProtocolVersion::from_protocol_id(connect.protocol_id).unwrap();
+            assert_eq!(version.protocol_id(), PROTOCOL_ID_V2_0);
+            version.normalize(&mut connect).unwrap();
+            assert!(flag_is_clean_session(connect.flags));
+            assert!(!flag_is_will(connect.flags));
+            assert_eq!(connect.client_id, Bytes::from("v2"));
+            let mut connect = Connect {
+                flags: V2_FLAG_AUTH,
+                protocol_id: PROTOCOL_ID_V2_0,
+                client_id: Bytes::from(vec![0; 8]),
+                ..Default::default()
+            };
+            assert!(version.normalize(&mut connect).is_err());
+            // A 2.0 client, the CONNACK with the SessionExpiryInterval.
+            use crate::test_support::{LoopbackBroker, TestClient};
+            use crate::{
+                MSG_TYPE_CONNACK, MSG_TYPE_CONNECT, RETURN_CODE_ACCEPTED,
+            };
+            let mut test_client =
+                TestClient::new(LoopbackBroker::addr()).unwrap();
+            let mut connect = vec![0, MSG_TYPE_CONNECT, V2_FLAG_CLEAN_START];
+            connect.extend_from_slice(&[PROTOCOL_ID_V2_0, 0, 60]);
+            connect.extend_from_slice(&[0, 0, 0x0e, 0x10, 0x05, 0x00]);
+            connect.extend_from_slice(b"protocolV2");
+            connect[0] = connect.len() as u8;
+            test_client.send(&connect).unwrap();
+            let conn_ack = test_client.expect(MSG_TYPE_CONNACK).unwrap();
+            assert_eq!(
+                &conn_ack[..],
+                &[
+                    7,
+                    MSG_TYPE_CONNACK,
+                    RETURN_CODE_ACCEPTED,
+                    255,
+                    255,
+                    255,
+                    255
+                ]
+            );
+            assert_eq!(
+                ProtocolVersion::of(&test_client.local_addr()),
+                ProtocolVersion::V2_0
+            );
+            test_client.disconnect(None).unwrap();
+        }
+    }
+}
@@ -12,20 +12,29 @@ case of an error; the error reason is then indicated in the ReturnCode field. It
 • ReturnCode: “accepted”, or rejection reason.
 */
 
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use custom_debug::Debug;
 use getset::{CopyGetters, Getters, MutGetters};
+use log::*;
 use std::mem;
+use std::net::SocketAddr;
 
 use crate::{
     broker_lib::MqttSnClient,
+    codec,
+    decode::Decode,
     eformat,
+    events::DeliveryStatus,
     function,
+    in_flight::InFlight,
     msg_hdr::MsgHeader,
+    msg_span::MsgSpan,
+    msg_trace::{MsgTrace, TraceStage},
     retransmit::RetransTimeWheel,
     // flags::{flags_set, flag_qos_level, },
     MSG_LEN_PUBACK,
     MSG_TYPE_PUBACK,
+    RETURN_CODE_ACCEPTED,
 };
 #[derive(
     Debug,
@@ -46,6 +55,12 @@ pub struct PubAck {
     pub return_code: u8,
 }
 
+codec!(PubAck, {
+    topic_id: u16,
+    msg_id: u16,
+    return_code: u8,
+});
+
 impl PubAck {
     /*
     fn constraint_len(_val: &u8) -> bool {
@@ -78,20 +93,104 @@ impl PubAck {
         msg_header: MsgHeader,
     ) -> Result<(), String> {
         let remote_socket_addr = msg_header.remote_socket_addr;
-        let (pub_ack, read_len) = PubAck::try_read(buf, size).unwrap();
+        let (pub_ack, read_len) =
+            Decode::read(PubAck::try_read(buf, size), size, &msg_header)?;
         dbg!(pub_ack.clone());
         if read_len == MSG_LEN_PUBACK as usize {
+            let _msg_span = MsgSpan::msg(
+                remote_socket_addr,
+                MSG_TYPE_PUBACK,
+                pub_ack.msg_id,
+            )
+            .entered();
+            tracing::debug!(
+                return_code = pub_ack.return_code,
+                "PUBACK received"
+            );
+            MsgTrace::record(
+                remote_socket_addr,
+                pub_ack.msg_id,
+                TraceStage::Ack(MSG_TYPE_PUBACK),
+            );
+            MsgTrace::finish(remote_socket_addr, pub_ack.msg_id);
+            InFlight::release(remote_socket_addr, pub_ack.msg_id, client);
             RetransTimeWheel::cancel_timer(
                 remote_socket_addr,
                 pub_ack.msg_type,
-                pub_ack.topic_id,
                 pub_ack.msg_id,
             )?;
+            let status = match pub_ack.return_code {
+                RETURN_CODE_ACCEPTED => DeliveryStatus::Acknowledged,
+                return_code => DeliveryStatus::Rejected(return_code),
+            };
+            client.events.on_delivery(
+                remote_socket_addr,
+                pub_ack.msg_id,
+                status,
+            );
             Ok(())
         } else {
             Err(eformat!(remote_socket_addr, "len err", read_len))
         }
     }
+    /// A datagram of one PUBACK with the short header, handle_ingress()
+    /// batches them.
+    #[inline(always)]
+    pub fn is_puback(buf: &[u8]) -> bool {
+        buf.len() == MSG_LEN_PUBACK as usize
+            && buf[0] == MSG_LEN_PUBACK
+            && buf[1] == MSG_TYPE_PUBACK
+    }
+    /// The PUBACKs of a burst from connected clients, see
+    /// MqttSnClient::handle_ingress(). The retransmit timers are canceled
+    /// under one lock of the time wheel.
+    pub fn recv_batch(acks: &[(SocketAddr, Bytes)], client: &MqttSnClient) {
+        let pub_acks: Vec<(SocketAddr, PubAck)> = acks
+            .iter()
+            .filter_map(|(addr, buf)| match PubAck::try_read(buf, buf.len()) {
+                Some((pub_ack, read_len))
+                    if read_len == MSG_LEN_PUBACK as usize =>
+                {
+                    Some((*addr, pub_ack))
+                }
+                _ => {
+                    error!("{}", eformat!(addr, "len err", buf.len()));
+                    None
+                }
+            })
+            .collect();
+        for (addr, pub_ack) in pub_acks.iter() {
+            let _msg_span =
+                MsgSpan::msg(*addr, MSG_TYPE_PUBACK, pub_ack.msg_id).entered();
+            tracing::debug!(
+                return_code = pub_ack.return_code,
+                "PUBACK received"
+            );
+            MsgTrace::record(
+                *addr,
+                pub_ack.msg_id,
+                TraceStage::Ack(MSG_TYPE_PUBACK),
+            );
+            MsgTrace::finish(*addr, pub_ack.msg_id);
+            InFlight::release(*addr, pub_ack.msg_id, client);
+        }
+        let timers: Vec<(SocketAddr, u8, u16)> = pub_acks
+            .iter()
+            .map(|(addr, pub_ack)| (*addr, pub_ack.msg_type, pub_ack.msg_id))
+            .collect();
+        let results = RetransTimeWheel::cancel_many(&timers);
+        for ((addr, pub_ack), result) in pub_acks.iter().zip(results) {
+            if let Err(why) = result {
+                error!("{}", why);
+                continue;
+            }
+            let status = match pub_ack.return_code {
+                RETURN_CODE_ACCEPTED => DeliveryStatus::Acknowledged,
+                return_code => DeliveryStatus::Rejected(return_code),
+            };
+            client.events.on_delivery(*addr, pub_ack.msg_id, status);
+        }
+    }
     #[inline(always)]
     pub fn send(
         topic_id: u16,
@@ -101,31 +200,33 @@ impl PubAck {
         msg_header: MsgHeader,
     ) -> Result<(), String> {
         let remote_socket_addr = msg_header.remote_socket_addr;
-        // faster implementation
-        // TODO verify big-endian or little-endian for u16 numbers
-        let msg_id_byte_1 = msg_id as u8;
-        let topic_id_byte_1 = topic_id as u8;
-        let msg_id_byte_0 = (msg_id >> 8) as u8;
-        let topic_id_byte_0 = (topic_id >> 8) as u8;
         // message format
         // PUBACK:[len(0), msg_type(1),
         //         topic_id(2,3), msg_id(4,5),
         //         return_code(6)]
-        let mut bytes = BytesMut::with_capacity(MSG_LEN_PUBACK as usize);
-        let buf: &[u8] = &[
-            MSG_LEN_PUBACK,
-            MSG_TYPE_PUBACK,
-            topic_id_byte_0,
-            topic_id_byte_1,
-            msg_id_byte_0,
-            msg_id_byte_1,
-            return_code,
-        ];
-        bytes.put(buf);
+        let bytes = PubAck::encode(topic_id, msg_id, return_code);
+        let _msg_span =
+            MsgSpan::msg(remote_socket_addr, MSG_TYPE_PUBACK, msg_id).entered();
+        tracing::debug!(return_code, "PUBACK sent");
         match client.egress_tx.try_send((remote_socket_addr, bytes)) {
             Ok(()) => Ok(()),
             Err(err) => return Err(eformat!(remote_socket_addr, err)),
         }
     }
+
+    /// Send a PUBACK with a rejection return code for the topic id of the
+    /// PUBLISH, then return why as the error for the caller to log.
+    #[inline(always)]
+    pub fn reject(
+        topic_id: u16,
+        msg_id: u16,
+        return_code: u8,
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+        why: String,
+    ) -> Result<(), String> {
+        PubAck::send(topic_id, msg_id, return_code, client, msg_header)?;
+        Err(why)
+    }
 }
 // NOTE: puback_tx is inlined hard coded for performance.
@@ -15,13 +15,16 @@ use std::mem;
 
 use crate::{
     broker_lib::MqttSnClient,
+    codec,
     eformat,
+    events::DeliveryStatus,
     function,
+    in_flight::InFlight,
     msg_hdr::MsgHeader,
+    msg_span::MsgSpan,
+    msg_trace::{MsgTrace, TraceStage},
     retransmit::RetransTimeWheel,
     // flags::{flags_set, flag_qos_level, },
-    MSG_LEN_PUBCOMP,
-
     MSG_TYPE_PUBCOMP,
 };
 #[derive(Debug, Clone, Getters, MutGetters, CopyGetters, Default)]
@@ -33,6 +36,8 @@ pub struct PubComp {
     pub msg_id: u16,
 }
 
+codec!(PubComp, { msg_id: u16 });
+
 impl PubComp {
     /*
     fn constraint_len(_val: &u8) -> bool {
@@ -54,22 +59,14 @@ impl PubComp {
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
-        // faster implementation
-        // TODO verify big-endian or little-endian for u16 numbers
-        // XXX order of statements performance
-        let msg_id_byte_0 = msg_id as u8;
-        let msg_id_byte_1 = (msg_id >> 8) as u8;
         // message format
-        // PUBACK:[len(0), msg_type(1), msg_id(2,3)]
-        let mut bytes = BytesMut::with_capacity(MSG_LEN_PUBCOMP as usize);
+        // PUBCOMP:[len(0), msg_type(1), msg_id(2,3)]
+        let bytes = PubComp::encode(msg_id);
         let remote_socket_addr = msg_header.remote_socket_addr;
-        let buf: &[u8] = &[
-            MSG_LEN_PUBCOMP,
-            MSG_TYPE_PUBCOMP,
-            msg_id_byte_1,
-            msg_id_byte_0,
-        ];
-        bytes.put(buf);
+        let _msg_span =
+            MsgSpan::msg(remote_socket_addr, MSG_TYPE_PUBCOMP, msg_id)
+                .entered();
+        tracing::debug!("PUBCOMP sent");
         match client.egress_tx.try_send((remote_socket_addr, bytes)) {
             Ok(()) => Ok(()),
             Err(err) => Err(eformat!(remote_socket_addr, err)),
@@ -83,21 +80,97 @@ impl PubComp {
         msg_header: MsgHeader,
     ) -> Result<(), String> {
         let remote_socket_addr = msg_header.remote_socket_addr;
-        if buf[0] == MSG_LEN_PUBCOMP
-            && buf[1] == MSG_TYPE_PUBCOMP
-            && size == MSG_LEN_PUBCOMP as usize
-        {
-            // TODO verify as Big Endian
-            let msg_id = buf[3] as u16 + ((buf[2] as u16) << 8);
-            RetransTimeWheel::cancel_timer(
+        if let Ok(msg_id) = PubComp::decode(buf, size) {
+            let _msg_span =
+                MsgSpan::msg(remote_socket_addr, MSG_TYPE_PUBCOMP, msg_id)
+                    .entered();
+            tracing::debug!("PUBCOMP received");
+            MsgTrace::record(
+                remote_socket_addr,
+                msg_id,
+                TraceStage::Ack(MSG_TYPE_PUBCOMP),
+            );
+            MsgTrace::finish(remote_socket_addr, msg_id);
+            // Stop retransmitting the PUBREL before the next message of
+            // the in-flight window is sent.
+            if RetransTimeWheel::cancel_timer(
                 remote_socket_addr,
                 MSG_TYPE_PUBCOMP,
-                0,
                 msg_id,
-            )?;
+            )
+            .is_err()
+            {
+                // A PUBCOMP of a retransmitted PUBREL, already released.
+                tracing::debug!("PUBCOMP duplicate");
+                return Ok(());
+            }
+            InFlight::release(remote_socket_addr, msg_id, client);
+            client.events.on_delivery(
+                remote_socket_addr,
+                msg_id,
+                DeliveryStatus::Acknowledged,
+            );
             Ok(())
         } else {
             Err(eformat!(remote_socket_addr, "size", buf[0]))
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_qos2_retransmit() {
+        use super::*;
+        use crate::flags::{QOS_LEVEL_2, RETAIN_FALSE};
+        use crate::pub_rec::PubRec;
+        use crate::pub_rel::PubRel;
+        use crate::publish::Publish;
+        use crate::test_support::{LoopbackBroker, TestClient};
+        use crate::{MSG_TYPE_PUBLISH, MSG_TYPE_PUBREC, MSG_TYPE_PUBREL};
+        let topic = "qos2_retransmit/temp";
+        let mut subscriber = TestClient::new(LoopbackBroker::addr()).unwrap();
+        subscriber.connect("qos2RetransSub", 60, None).unwrap();
+        subscriber.subscribe(topic, QOS_LEVEL_2).unwrap();
+        let mut publisher = TestClient::new(LoopbackBroker::addr()).unwrap();
+        publisher.connect("qos2RetransPub", 60, None).unwrap();
+        let topic_id = publisher.register(topic).unwrap();
+        // Broker as the receiver, the PUBREL is lost.
+        let msg_id = 100;
+        let publish =
+            Publish::encode(topic_id, msg_id, QOS_LEVEL_2, RETAIN_FALSE, b"2")
+                .unwrap();
+        publisher.send(&publish).unwrap();
+        publisher.expect(MSG_TYPE_PUBREC).unwrap();
+        // PUBREC retransmitted until the PUBREL.
+        publisher.expect(MSG_TYPE_PUBREC).unwrap();
+        publisher.send(&PubRel::encode(msg_id)).unwrap();
+        publisher.expect(MSG_TYPE_PUBCOMP).unwrap();
+        // The PUBCOMP is lost, the retransmitted PUBREL is answered again.
+        publisher.send(&PubRel::encode(msg_id)).unwrap();
+        publisher.expect(MSG_TYPE_PUBCOMP).unwrap();
+        // Broker as the sender, the PUBREC is lost.
+        let bytes = subscriber.expect(MSG_TYPE_PUBLISH).unwrap();
+        let sub_msg_id = u16::from_be_bytes([bytes[5], bytes[6]]);
+        let bytes = subscriber.expect(MSG_TYPE_PUBLISH).unwrap();
+        assert_eq!(u16::from_be_bytes([bytes[5], bytes[6]]), sub_msg_id);
+        subscriber.send(&PubRec::encode(sub_msg_id)).unwrap();
+        subscriber.expect(MSG_TYPE_PUBREL).unwrap();
+        // The PUBCOMP is lost, PUBREL retransmitted until the PUBCOMP.
+        subscriber.expect(MSG_TYPE_PUBREL).unwrap();
+        // A duplicate PUBREC is answered with the PUBREL.
+        subscriber.send(&PubRec::encode(sub_msg_id)).unwrap();
+        subscriber.expect(MSG_TYPE_PUBREL).unwrap();
+        let sub_addr = subscriber.local_addr();
+        assert!(RetransTimeWheel::in_flight(sub_addr, sub_msg_id));
+        subscriber.send(&PubComp::encode(sub_msg_id)).unwrap();
+        // The PUBCOMP of a retransmitted PUBREL is ignored.
+        subscriber.send(&PubComp::encode(sub_msg_id)).unwrap();
+        // Answered in order, the PUBCOMPs were processed.
+        subscriber.ping(None).unwrap();
+        assert!(!RetransTimeWheel::in_flight(sub_addr, sub_msg_id));
+        assert!(subscriber.expect(MSG_TYPE_PUBLISH).is_err());
+        publisher.disconnect(None).unwrap();
+        subscriber.disconnect(None).unwrap();
+    }
+}
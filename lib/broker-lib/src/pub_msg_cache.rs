@@ -1,24 +1,35 @@
 /// Cache for published messages
 use hashbrown::HashMap;
 use std::sync::Mutex;
+use std::time::SystemTime;
 
 use crate::MsgIdType;
 
+use crate::annotation::Annotations;
+use crate::broker_context::BrokerContext;
 use crate::filter::Subscriber;
 use crate::publish::Publish;
 
 use crate::{eformat, function};
 use std::net::SocketAddr;
 
-lazy_static! {
-    static ref PUB_MSG_CACHE: Mutex<HashMap<(SocketAddr, MsgIdType), PubMsgCache>> =
-        Mutex::new(HashMap::new());
+/// QoS 2 messages of a broker waiting for the PUBREL, see BrokerContext.
+#[derive(Default)]
+pub(crate) struct PubMsgCacheState {
+    cache: Mutex<HashMap<(SocketAddr, MsgIdType), PubMsgCache>>,
+}
+
+#[inline(always)]
+fn state() -> &'static PubMsgCacheState {
+    &BrokerContext::current().pub_msg_cache
 }
 
 #[derive(Debug, Clone)]
 pub struct PubMsgCache {
     pub publish: Publish, // headers and msg are stored
     pub subscriber_vec: Vec<Subscriber>,
+    pub annotations: Annotations, // annotations from the hooks
+    pub received_at: SystemTime,  // see RichPublish::received_at
 }
 
 impl PubMsgCache {
@@ -32,7 +43,7 @@ impl PubMsgCache {
         key: (SocketAddr, MsgIdType),
         value: PubMsgCache,
     ) -> Result<(), String> {
-        let mut pub_cache = PUB_MSG_CACHE.lock().unwrap();
+        let mut pub_cache = state().cache.lock().unwrap();
         match pub_cache.try_insert(key, value) {
             Ok(_) => Ok(()),
             Err(_e) => Err(eformat!(key.0, key.1, "already exists.")),
@@ -41,13 +52,13 @@ impl PubMsgCache {
 
     pub fn remove(key: (SocketAddr, MsgIdType)) -> Option<PubMsgCache> {
         // mut is needed to remove the entry.
-        let mut pub_cache = PUB_MSG_CACHE.lock().unwrap();
+        let mut pub_cache = state().cache.lock().unwrap();
         let val = pub_cache.remove(&key)?;
         Some(val)
     }
 
     pub fn get(key: (SocketAddr, MsgIdType)) -> Option<PubMsgCache> {
-        let pub_cache = PUB_MSG_CACHE.lock().unwrap();
+        let pub_cache = state().cache.lock().unwrap();
         let val = pub_cache.get(&key)?;
         // need to clone the value because the value is borrowed.
         Some(val.clone())
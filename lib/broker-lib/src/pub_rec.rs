@@ -15,12 +15,16 @@ use std::mem;
 
 use crate::{
     broker_lib::MqttSnClient,
+    codec,
     eformat,
     function,
     msg_hdr::MsgHeader,
+    msg_span::MsgSpan,
+    msg_trace::{MsgTrace, TraceStage},
+    pub_rel::PubRel,
     retransmit::RetransTimeWheel,
     // flags::{flags_set, flag_qos_level, },
-    MSG_LEN_PUBREC,
+    MSG_TYPE_PUBCOMP,
     MSG_TYPE_PUBREC,
 };
 #[derive(
@@ -40,6 +44,8 @@ pub struct PubRec {
     pub msg_id: u16,
 }
 
+codec!(PubRec, { msg_id: u16 });
+
 impl PubRec {
     /*
     fn constraint_len(_val: &u8) -> bool {
@@ -58,27 +64,48 @@ impl PubRec {
     #[inline(always)]
     pub fn recv(
         buf: &[u8],
-        _size: usize,
+        size: usize,
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
         let remote_socket_addr = msg_header.remote_socket_addr;
-        if buf[0] == MSG_LEN_PUBREC && buf[1] == MSG_TYPE_PUBREC {
-            // TODO verify as Big Endian
-            let msg_id = buf[2] as u16 + ((buf[3] as u16) << 8);
-            // TODO verify need to cancel the retransmission timer
-            match RetransTimeWheel::cancel_timer(
+        let msg_id = match PubRec::decode(buf, size) {
+            Ok(msg_id) => msg_id,
+            Err(why) => return Err(eformat!(remote_socket_addr, why)),
+        };
+        let _msg_span =
+            MsgSpan::msg(remote_socket_addr, MSG_TYPE_PUBREC, msg_id).entered();
+        tracing::debug!("PUBREC received");
+        MsgTrace::record(
+            remote_socket_addr,
+            msg_id,
+            TraceStage::Ack(MSG_TYPE_PUBREC),
+        );
+        // The subscriber received the PUBLISH, stop retransmitting it.
+        let received = RetransTimeWheel::cancel_timer(
+            remote_socket_addr,
+            MSG_TYPE_PUBREC,
+            msg_id,
+        )
+        .is_ok();
+        // A PUBREC for a PUBREL in flight, the PUBREL was lost.
+        if !received && !RetransTimeWheel::in_flight(remote_socket_addr, msg_id)
+        {
+            return Err(eformat!(remote_socket_addr, "unknown msg_id", msg_id));
+        }
+        let bytes = PubRel::send(msg_id, client, msg_header)?;
+        if received {
+            // PUBCOMP cancels the retransmit of the PUBREL.
+            RetransTimeWheel::schedule_timer(
                 remote_socket_addr,
-                MSG_TYPE_PUBREC,
+                MSG_TYPE_PUBCOMP,
                 0,
                 msg_id,
-            ) {
-                Ok(()) => Ok(()),
-                Err(err) => Err(err),
-            }
-        } else {
-            Err(eformat!(remote_socket_addr, "size", buf[0]))
+                1,
+                bytes,
+            )?;
         }
+        Ok(())
     }
     #[inline(always)]
     pub fn send(
@@ -86,25 +113,15 @@ impl PubRec {
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<BytesMut, String> {
-        // faster implementation
-        // TODO verify big-endian or little-endian for u16 numbers
-        // XXX order of statements performance
-        let msg_id_byte_0 = msg_id as u8;
-        let msg_id_byte_1 = (msg_id >> 8) as u8;
         // message format
-        // PUBACK:[len(0), msg_type(1), msg_id(2,3)]
-        let mut bytes = BytesMut::with_capacity(MSG_LEN_PUBREC as usize);
-        let buf: &[u8] = &[
-            MSG_LEN_PUBREC,
-            MSG_TYPE_PUBREC,
-            msg_id_byte_1,
-            msg_id_byte_0,
-        ];
-        dbg!(&buf);
+        // PUBREC:[len(0), msg_type(1), msg_id(2,3)]
+        let bytes = PubRec::encode(msg_id);
+        dbg!(&bytes);
         let remote_socket_addr = msg_header.remote_socket_addr;
-        bytes.put(buf);
+        let _msg_span =
+            MsgSpan::msg(remote_socket_addr, MSG_TYPE_PUBREC, msg_id).entered();
+        tracing::debug!("PUBREC sent");
         // TODO replace BytesMut with Bytes to eliminate clone as copy
-        dbg!(&buf);
         match client
             .egress_tx
             .try_send((remote_socket_addr, bytes.clone()))
@@ -14,9 +14,17 @@ use getset::{CopyGetters, Getters, MutGetters};
 use std::mem;
 
 use crate::{
-    broker_lib::MqttSnClient, eformat, function, msg_hdr::MsgHeader,
-    pub_comp::PubComp, pub_msg_cache::PubMsgCache, publish::Publish,
-    retransmit::RetransTimeWheel, MSG_LEN_PUBREL, MSG_TYPE_PUBREL,
+    broker_lib::MqttSnClient,
+    codec, eformat, function,
+    msg_hdr::MsgHeader,
+    msg_span::MsgSpan,
+    msg_trace::{MsgTrace, TraceStage},
+    ordered_delivery::OrderedDelivery,
+    pub_comp::PubComp,
+    pub_msg_cache::PubMsgCache,
+    retransmit::RetransTimeWheel,
+    test_topics::TestTopics,
+    MSG_TYPE_PUBREL,
 };
 
 #[derive(
@@ -30,6 +38,8 @@ pub struct PubRel {
     pub msg_id: u16,
 }
 
+codec!(PubRel, { msg_id: u16 });
+
 impl PubRel {
     /*
     fn constraint_len(_val: &u8) -> bool {
@@ -48,35 +58,63 @@ impl PubRel {
     #[inline(always)]
     pub fn recv(
         buf: &[u8],
-        _size: usize,
+        size: usize,
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
         let remote_socket_addr = msg_header.remote_socket_addr;
-        if buf[0] == MSG_LEN_PUBREL && buf[1] == MSG_TYPE_PUBREL {
-            // TODO verify as Big Endian
-            let msg_id = buf[3] as u16 + ((buf[2] as u16) << 8);
+        if let Ok(msg_id) = PubRel::decode(buf, size) {
+            let _msg_span =
+                MsgSpan::msg(remote_socket_addr, MSG_TYPE_PUBREL, msg_id)
+                    .entered();
+            tracing::debug!("PUBREL received");
+            MsgTrace::record(
+                remote_socket_addr,
+                msg_id,
+                TraceStage::Ack(MSG_TYPE_PUBREL),
+            );
+            MsgTrace::finish(remote_socket_addr, msg_id);
             // Send PUBCOMP to publisher
             PubComp::send(msg_id, client, msg_header)?;
             // Send publish message to subscribers.
             match PubMsgCache::remove((remote_socket_addr, msg_id)) {
                 Some(pub_msg_cache) => {
                     dbg!(&pub_msg_cache);
-                    Publish::send_msg_to_subscribers(
-                        pub_msg_cache.subscriber_vec,
-                        pub_msg_cache.publish,
+                    // Built-in test topics are answered by the broker.
+                    if TestTopics::try_handle(
+                        &pub_msg_cache.publish,
+                        client,
+                        remote_socket_addr,
+                    )? {
+                        OrderedDelivery::abort(
+                            remote_socket_addr,
+                            msg_id,
+                            client,
+                        );
+                        return RetransTimeWheel::cancel_timer(
+                            remote_socket_addr,
+                            MSG_TYPE_PUBREL,
+                            msg_id,
+                        );
+                    }
+                    // With the messages queued behind it.
+                    OrderedDelivery::release(
+                        remote_socket_addr,
+                        msg_id,
+                        pub_msg_cache,
                         client,
                     )?;
                 }
+                // A retransmitted PUBREL, the PUBCOMP was lost and the
+                // message was already sent.
                 None => {
-                    // TODO return error or no subscribers?
-                    {}
+                    tracing::debug!("PUBREL retransmitted");
+                    return Ok(());
                 }
             }
             match RetransTimeWheel::cancel_timer(
                 remote_socket_addr,
                 MSG_TYPE_PUBREL,
-                0,
                 msg_id,
             ) {
                 Ok(()) => Ok(()),
@@ -91,31 +129,20 @@ impl PubRel {
         msg_id: u16,
         client: &MqttSnClient,
         msg_header: MsgHeader,
-    ) -> Result<(), String> {
-        // faster implementation
-        // TODO verify big-endian or little-endian for u16 numbers
-        // XXX order of statements performance
+    ) -> Result<BytesMut, String> {
         let remote_socket_addr = msg_header.remote_socket_addr;
-        let msg_id_byte_0 = msg_id as u8;
-        let msg_id_byte_1 = (msg_id >> 8) as u8;
         // message format
-        // PUBACK:[len(0), msg_type(1), msg_id(2,3)]
-        let mut bytes = BytesMut::with_capacity(MSG_LEN_PUBREL as usize);
-        let buf: &[u8] = &[
-            MSG_LEN_PUBREL,
-            MSG_TYPE_PUBREL,
-            msg_id_byte_1,
-            msg_id_byte_0,
-        ];
-        bytes.put(buf);
-        match client.egress_tx.send((remote_socket_addr, bytes)) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(format!(
-                "{}-{}:",
-                //function!(),
-                remote_socket_addr,
-                e
-            )),
+        // PUBREL:[len(0), msg_type(1), msg_id(2,3)]
+        let bytes = PubRel::encode(msg_id);
+        let _msg_span =
+            MsgSpan::msg(remote_socket_addr, MSG_TYPE_PUBREL, msg_id).entered();
+        tracing::debug!("PUBREL sent");
+        match client
+            .egress_tx
+            .try_send((remote_socket_addr, bytes.clone()))
+        {
+            Ok(()) => Ok(bytes),
+            Err(err) => Err(eformat!(remote_socket_addr, err)),
         }
     }
 }
@@ -19,13 +19,14 @@ coded 0x0000.
 • Data: the published data.
 */
 #![allow(unused_imports)]
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use custom_debug::Debug;
 use getset::{CopyGetters, Getters, MutGetters};
 use log::*;
-use std::mem;
 use std::net::SocketAddr;
 use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
 
 extern crate trace_caller;
 use hashbrown::HashMap;
@@ -33,15 +34,53 @@ use std::sync::Mutex;
 use trace_caller::trace;
 
 use crate::{
-    asleep_msg_cache::AsleepMsgCache, broker_lib::MqttSnClient, connection::*,
-    eformat, filter::*, flags::*, function, msg_hdr::*, pub_ack::PubAck,
-    pub_msg_cache::PubMsgCache, pub_rec::PubRec, retain::Retain,
-    retransmit::RetransTimeWheel, MSG_LEN_PUBACK, MSG_LEN_PUBLISH_HEADER,
-    MSG_LEN_PUBREC, MSG_TYPE_CONNACK, MSG_TYPE_CONNECT, MSG_TYPE_PUBACK,
-    MSG_TYPE_PUBCOMP, MSG_TYPE_PUBLISH, MSG_TYPE_PUBREC, MSG_TYPE_PUBREL,
-    MSG_TYPE_SUBACK, MSG_TYPE_SUBSCRIBE, RETURN_CODE_ACCEPTED,
+    alert::AlertRules,
+    annotation::Annotations,
+    asleep_msg_cache::AsleepMsgCache,
+    authorization::{client_id_of, topic_of},
+    broker_lib::MqttSnClient,
+    client_mode::ClientMode,
+    codec::{encode_packet, get_u16_be, Packet},
+    config::PreDefinedTopics,
+    connection::*,
+    dup_filter::DupFilter,
+    eformat,
+    events::DeniedAction,
+    filter::*,
+    flags::*,
+    function,
+    in_flight::InFlight,
+    last_value::LastValueCache,
+    local_consumer::LocalConsumer,
+    metrics::{Counter, Metrics},
+    msg_hdr::*,
+    msg_id::MsgIdAllocator,
+    msg_span::MsgSpan,
+    msg_trace::*,
+    ordered_delivery::OrderedDelivery,
+    pub_ack::PubAck,
+    pub_msg_cache::PubMsgCache,
+    pub_rec::PubRec,
+    register_on_demand::RegisterOnDemand,
+    retain::Retain,
+    retransmit::RetransTimeWheel,
+    rich_publish::RichPublish,
+    sys_stats::SysStats,
+    test_topics::TestTopics,
+    TopicIdType, MSG_LEN_PUBACK, MSG_LEN_PUBLISH_HEADER, MSG_LEN_PUBREC,
+    MSG_TYPE_CONNACK, MSG_TYPE_CONNECT, MSG_TYPE_PUBACK, MSG_TYPE_PUBCOMP,
+    MSG_TYPE_PUBLISH, MSG_TYPE_PUBREC, MSG_TYPE_PUBREL, MSG_TYPE_SUBACK,
+    MSG_TYPE_SUBSCRIBE, RETURN_CODE_ACCEPTED, RETURN_CODE_CONGESTION,
+    RETURN_CODE_INVALID_TOPIC_ID, RETURN_CODE_NOT_SUPPORTED,
 };
 
+/// Max payload length for the QoS 0 fast path, the common sensor case.
+pub const FAST_PATH_MAX_DATA_LEN: usize = 64;
+
+lazy_static! {
+    static ref FAST_PATH_ENABLED: AtomicBool = AtomicBool::new(true);
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct PublishRecv {
     pub topic_id: u16,
@@ -79,7 +118,8 @@ pub struct Publish {
     flags: u8,
     topic_id: u16,
     msg_id: u16,
-    data: BytesMut, // TODO: use Bytes.
+    /// A slice of the received datagram, shared by the subscribers.
+    data: Bytes,
 }
 
 impl Publish {
@@ -88,8 +128,9 @@ impl Publish {
         msg_id: u16,
         qos: u8,
         retain: u8,
-        data: BytesMut,
+        data: impl Into<Bytes>,
     ) -> Self {
+        let data = data.into();
         let len = (data.len() + 7) as u8;
         let flags = flags_set(
             DUP_FALSE,
@@ -137,6 +178,7 @@ impl Publish {
     }
     */
 
+    /// PUBLISH from a buffer without the received Bytes, the data is copied.
     #[inline(always)]
     pub fn recv(
         buf: &[u8],
@@ -144,21 +186,105 @@ impl Publish {
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
-        let (mut publish, _read_fixed_len) = match msg_header.header_len {
-            MsgHeaderLenEnum::Short => Publish::try_read(buf, size).unwrap(),
-            MsgHeaderLenEnum::Long => {
-                Publish::try_read(&buf[2..], size - 2).unwrap()
-            }
-        };
-        // * NOTE: don't use publish.len from this arm, because the
-        // * shift to eliminate the need the long struct.
-        // * Use the len from the msg_header.
-        publish.len = 0;
+        let bytes = Bytes::copy_from_slice(&buf[..size]);
+        Publish::recv_bytes(&bytes, client, msg_header)
+    }
+
+    /// PUBLISH from the received datagram, the data is a slice of bytes.
+    #[inline(always)]
+    pub fn recv_bytes(
+        bytes: &Bytes,
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+    ) -> Result<(), String> {
+        let size = bytes.len();
+        if let Some(result) =
+            Publish::try_recv_fast(bytes, client, msg_header.remote_socket_addr)
+        {
+            return result;
+        }
+        let received_at = SystemTime::now();
+        let mut publish = Publish::read_bytes(bytes, &msg_header)?;
         let remote_socket_addr = msg_header.remote_socket_addr;
-        dbg!((size, _read_fixed_len));
+        let _msg_span =
+            MsgSpan::msg(remote_socket_addr, MSG_TYPE_PUBLISH, publish.msg_id)
+                .entered();
+        tracing::debug!(
+            topic_id = publish.topic_id,
+            flags = publish.flags,
+            len = publish.data.len(),
+            "PUBLISH received"
+        );
+        if ClientMode::contains(&remote_socket_addr) {
+            // Client mode, the message is from the gateway.
+            if Publish::try_ack_dup(&publish, client, &msg_header)? {
+                return Ok(());
+            }
+            return ClientMode::on_publish(&publish, client, msg_header);
+        }
+        // Before the authorization, the short topic name is resolved.
+        let topic_id_type = flag_topic_id_type(publish.flags);
+        if flag_qos_level(publish.flags) == QOS_LEVEL_3
+            && topic_id_type == TOPIC_ID_TYPE_SHORT
+        {
+            Publish::resolve_short_topic(&mut publish)?;
+        }
+        SysStats::inc_messages();
+        if !client.authorizer.allow_all() {
+            let client_id = client_id_of(&remote_socket_addr);
+            let topic = topic_of(publish.topic_id);
+            if !client.authorizer.allow_publish(&client_id, &topic) {
+                client.events.on_denied(
+                    remote_socket_addr,
+                    DeniedAction::Publish,
+                    &topic,
+                );
+                // No ack for QoS 0 and -1, the message is dropped.
+                match flag_qos_level(publish.flags) {
+                    QOS_LEVEL_1 | QOS_LEVEL_2 => PubAck::send(
+                        publish.topic_id,
+                        publish.msg_id,
+                        RETURN_CODE_NOT_SUPPORTED,
+                        client,
+                        msg_header,
+                    )?,
+                    _ => {}
+                }
+                return Err(eformat!(remote_socket_addr, "not allowed", topic));
+            }
+        }
+        // Before the duplicate filter, a rejected msg_id isn't recorded.
+        if let Some((return_code, why)) =
+            Publish::check_rejection(&publish, client, remote_socket_addr)
+        {
+            return PubAck::reject(
+                publish.topic_id,
+                publish.msg_id,
+                return_code,
+                client,
+                msg_header,
+                why,
+            );
+        }
+        if Publish::try_ack_dup(&publish, client, &msg_header)? {
+            return Ok(());
+        }
+        client.events.on_publish(remote_socket_addr, &publish);
+        dbg!(size);
         dbg!(publish.clone());
+        // Hooks attach annotations once, later stages reuse them.
+        let mut annotations =
+            Annotations::run_hooks(&remote_socket_addr, &publish);
+        let _trace_id =
+            MsgTrace::start(remote_socket_addr, &publish, &mut annotations);
+        AlertRules::evaluate(publish.topic_id, &publish.data, client);
         let subscriber_vec = get_subscribers_with_topic_id(publish.topic_id);
         dbg!(&subscriber_vec);
+        MsgTrace::record(
+            remote_socket_addr,
+            publish.msg_id,
+            TraceStage::Match(subscriber_vec.len()),
+        );
         // TODO check QoS, https://www.hivemq.com/blog/mqtt-essentials-
         // part-6-mqtt-quality-of-service-levels/
         match flag_qos_level(publish.flags) {
@@ -174,9 +300,14 @@ impl Publish {
                 // 4. Send PUBLISH message to subscribers from PUBREL.rx.
 
                 //dbg!(&client);
+                // Before the PUBREC, the PUBREL releases the hold.
+                OrderedDelivery::hold(
+                    remote_socket_addr,
+                    publish.topic_id,
+                    publish.msg_id,
+                );
                 let bytes = PubRec::send(publish.msg_id, client, msg_header)?;
-                // PUBREL message doesn't have topic id.
-                // For the time wheel hash, default to 0.
+                // PUBREL message doesn't have topic id, 0.
                 RetransTimeWheel::schedule_timer(
                     remote_socket_addr,
                     MSG_TYPE_PUBREL,
@@ -192,6 +323,8 @@ impl Publish {
                 let cache = PubMsgCache {
                     publish,
                     subscriber_vec,
+                    annotations,
+                    received_at,
                 };
                 PubMsgCache::try_insert((remote_socket_addr, msg_id), cache)?;
                 return Ok(());
@@ -208,50 +341,358 @@ impl Publish {
             }
             QOS_LEVEL_0 => {}
             QOS_LEVEL_3 => {
-                return Err(eformat!(
+                // QoS -1, the client doesn't need a connection, section 6.8.
+                // Only pre-defined topic ids and short topic names are
+                // supported, no ack, no retain.
+                if topic_id_type != TOPIC_ID_TYPE_PRE_DEFINED
+                    && topic_id_type != TOPIC_ID_TYPE_SHORT
+                {
+                    return Err(eformat!(
+                        remote_socket_addr,
+                        "QoS -1 requires a pre-defined or short topic id",
+                        publish.topic_id
+                    ));
+                }
+                RichPublish::forward(
+                    &publish,
                     remote_socket_addr,
-                    "QoS level 3 is not supported"
-                ));
+                    received_at,
+                    &annotations,
+                    client,
+                );
+                #[cfg(feature = "bridge")]
+                crate::cluster::Cluster::forward(&publish, &annotations);
+                return Publish::send_msg_to_subscribers(
+                    subscriber_vec,
+                    publish,
+                    &annotations,
+                    client,
+                );
             }
             _ => {
                 // Should never happen because flag_qos_level() filters for 4 cases only.
                 {}
             }
         }
+        // Built-in test topics are answered by the broker.
+        if TestTopics::try_handle(&publish, client, remote_socket_addr)? {
+            return Ok(());
+        }
+        if MsgTrace::try_arm(&publish) {
+            return Ok(());
+        }
         if flag_is_retain(publish.flags) {
             Retain::insert(
                 flag_qos_level(publish.flags),
                 publish.topic_id,
                 publish.msg_id,
                 publish.data.clone(),
+                annotations.clone(),
             );
         }
-        Publish::send_msg_to_subscribers(subscriber_vec, publish, client)?;
+        let msg_id = publish.msg_id;
+        // Sent after the QoS 2 message of the publisher on the topic.
+        if OrderedDelivery::is_held(remote_socket_addr, publish.topic_id) {
+            let cache = PubMsgCache {
+                publish,
+                subscriber_vec,
+                annotations,
+                received_at,
+            };
+            MsgTrace::finish(remote_socket_addr, msg_id);
+            return OrderedDelivery::queue(remote_socket_addr, cache, client);
+        }
+        RichPublish::forward(
+            &publish,
+            remote_socket_addr,
+            received_at,
+            &annotations,
+            client,
+        );
+        #[cfg(feature = "bridge")]
+        crate::cluster::Cluster::forward(&publish, &annotations);
+        Publish::send_msg_to_subscribers(
+            subscriber_vec,
+            publish,
+            &annotations,
+            client,
+        )?;
+        // No ack from the publisher for QoS 0 and 1.
+        MsgTrace::finish(remote_socket_addr, msg_id);
+        Ok(())
+    }
+
+    /// Parse the fixed fields, the data is a slice of bytes, not a copy.
+    /// The len is 0, the length is in the msg_header.
+    fn read_bytes(
+        bytes: &Bytes,
+        msg_header: &MsgHeader,
+    ) -> Result<Publish, String> {
+        // flags, topic_id and msg_id follow the message header.
+        let (buf, offset) = (&bytes[..], msg_header.body_offset());
+        if buf.len() < offset + 5 {
+            return Err(eformat!(
+                msg_header.remote_socket_addr,
+                "len err",
+                bytes.len()
+            ));
+        }
+        Ok(Publish {
+            len: 0,
+            msg_type: MSG_TYPE_PUBLISH,
+            flags: buf[offset],
+            topic_id: get_u16_be(buf, offset + 1),
+            msg_id: get_u16_be(buf, offset + 3),
+            data: bytes.slice(offset + 5..),
+        })
+    }
 
-        // TODO check dup, likely not dup
-        //
+    /// The 2 characters of a short topic name are in the topic id field,
+    /// replace them with the topic id of the topic name. The topic name is
+    /// inserted like a REGISTER, the wildcard subscriptions match it.
+    fn resolve_short_topic(publish: &mut Publish) -> Result<(), String> {
+        let short_name = publish.topic_id.to_be_bytes();
+        let topic_name = match str::from_utf8(&short_name) {
+            Ok(topic_name) => topic_name.to_string(),
+            Err(why) => return Err(eformat!(publish.topic_id, why)),
+        };
+        publish.topic_id = try_insert_topic_name(topic_name)?;
+        // clear the topic id type, TOPIC_ID_TYPE_NORMAL.
+        publish.flags &= !0b11;
         Ok(())
     }
 
-    /// Publish a message
-    /// 1. Format a message with Publish struct.
-    /// 2. Serialize into a byte stream.
-    /// 3. Send it to the channel.
-    /// 4. Schedule retransmit for QoS Level 1 & 2.
+    /// The PUBACK return code and the reason to reject the PUBLISH: an
+    /// unknown topic id, or congestion for QoS 1 and 2.
+    fn check_rejection(
+        publish: &Publish,
+        client: &MqttSnClient,
+        remote_socket_addr: SocketAddr,
+    ) -> Option<(u8, String)> {
+        let qos = flag_qos_level(publish.flags);
+        if qos == QOS_LEVEL_3 {
+            return None;
+        }
+        // QoS 0 too, the client registers the topic again.
+        if flag_topic_id_type(publish.flags) == TOPIC_ID_TYPE_NORMAL
+            && get_topic_name(publish.topic_id).is_none()
+        {
+            let why =
+                eformat!(remote_socket_addr, "unknown topic", publish.topic_id);
+            return Some((RETURN_CODE_INVALID_TOPIC_ID, why));
+        }
+        if qos == QOS_LEVEL_0 {
+            return None;
+        }
+        if client.is_congested() {
+            let why = eformat!(remote_socket_addr, "congested");
+            return Some((RETURN_CODE_CONGESTION, why));
+        }
+        None
+    }
+
+    /// Acknowledge a retransmitted QoS 1 or 2 message again without sending
+    /// it to the subscribers. Returns true for a duplicate.
+    fn try_ack_dup(
+        publish: &Publish,
+        client: &MqttSnClient,
+        msg_header: &MsgHeader,
+    ) -> Result<bool, String> {
+        let remote_socket_addr = msg_header.remote_socket_addr;
+        let qos = flag_qos_level(publish.flags);
+        if qos != QOS_LEVEL_1 && qos != QOS_LEVEL_2 {
+            return Ok(false);
+        }
+        let dup = flag_is_dup(publish.flags);
+        if !DupFilter::is_dup(remote_socket_addr, publish.msg_id, dup) {
+            return Ok(false);
+        }
+        Metrics::inc(Counter::Duplicates);
+        if qos == QOS_LEVEL_1 {
+            PubAck::send(
+                publish.topic_id,
+                publish.msg_id,
+                RETURN_CODE_ACCEPTED,
+                client,
+                msg_header.clone(),
+            )?;
+        } else {
+            // The PUBREL retransmit timer is still scheduled, or the
+            // PUBREL was received and the message was sent.
+            PubRec::send(publish.msg_id, client, msg_header.clone())?;
+        }
+        Ok(true)
+    }
+
+    /// Enable or disable the fast path, enabled by default.
+    pub fn set_fast_path(enabled: bool) {
+        FAST_PATH_ENABLED.store(enabled, Ordering::Relaxed);
+    }
+
+    /// The fast path skips the stages of the full path, it's allowed when
+    /// none of them is active: hooks, traces, test topics, local consumers,
+    /// alert rules, authorization, events, library subscriptions, client
+    /// mode, the last-value cache, ordered delivery, cluster peers and
+    /// connectors. A new stage of the full path must be checked here.
     #[inline(always)]
-    #[trace]
-    pub fn send(
+    fn fast_path_allowed(client: &MqttSnClient) -> bool {
+        FAST_PATH_ENABLED.load(Ordering::Relaxed)
+            && !Annotations::has_hooks()
+            && MsgTrace::is_idle()
+            && !TestTopics::is_enabled()
+            && LocalConsumer::is_empty()
+            && AlertRules::is_empty()
+            && client.authorizer.allow_all()
+            && !client.events.is_enabled()
+            && RichPublish::is_empty()
+            && !ClientMode::is_enabled()
+            && !LastValueCache::is_enabled()
+            && !OrderedDelivery::is_enabled()
+            && !Publish::is_bridged()
+            && !Publish::has_connectors()
+    }
+
+    /// Peer gateways may subscribe to the topic, see Cluster::forward().
+    #[cfg(feature = "bridge")]
+    #[inline(always)]
+    fn is_bridged() -> bool {
+        crate::cluster::Cluster::is_enabled()
+    }
+    #[cfg(not(feature = "bridge"))]
+    #[inline(always)]
+    fn is_bridged() -> bool {
+        false
+    }
+
+    /// Connectors mirror the message, see RichPublish::forward().
+    #[cfg(feature = "connector")]
+    #[inline(always)]
+    fn has_connectors() -> bool {
+        crate::connector::Connectors::is_enabled()
+    }
+    #[cfg(not(feature = "connector"))]
+    #[inline(always)]
+    fn has_connectors() -> bool {
+        false
+    }
+
+    /// Fast path for tiny QoS 0 messages without retain, to a normal
+    /// topic id. The payload is sent from the receive buffer without
+    /// building a Publish struct, unless a subscriber is asleep.
+    /// Large, QoS 1/2 messages, pre-defined or short topic ids, malformed
+    /// messages and messages when fast_path_allowed() is false return None
+    /// for the full path.
+    #[inline(always)]
+    pub fn try_recv_fast(
+        bytes: &Bytes,
+        client: &MqttSnClient,
+        remote_socket_addr: SocketAddr,
+    ) -> Option<Result<(), String>> {
+        let (buf, size) = (&bytes[..], bytes.len());
+        let header_len = MSG_LEN_PUBLISH_HEADER as usize;
+        // Short header only, buf[0] == 1 is a 3 bytes length. The Length
+        // field must match the datagram, the full path reports the error.
+        if size < header_len
+            || size > header_len + FAST_PATH_MAX_DATA_LEN
+            || buf[0] as usize != size
+        {
+            return None;
+        }
+        let flags = buf[2];
+        if flag_qos_level(flags) != QOS_LEVEL_0
+            || flag_is_retain(flags)
+            || flag_topic_id_type(flags) != TOPIC_ID_TYPE_NORMAL
+        {
+            return None;
+        }
+        if !Publish::fast_path_allowed(client) {
+            return None;
+        }
+        let topic_id = get_u16_be(buf, 3);
+        let msg_id = get_u16_be(buf, 5);
+        let subscriber_vec = get_subscribers_with_topic_id(topic_id);
+        // No subscriber, the slow path checks the topic id.
+        if subscriber_vec.is_empty() {
+            return None;
+        }
+        let data = bytes.slice(header_len..);
+        SysStats::inc_messages();
+        // QoS 0 for all the subscribers, whatever QoS they are granted.
+        let mut addr_vec = Vec::new();
+        for subscriber in subscriber_vec {
+            match Connection::get_state(&subscriber.socket_addr) {
+                Ok(StateEnum2::ACTIVE) => {
+                    // Sent after the REGACK if the topic id is unknown.
+                    match RegisterOnDemand::try_defer(
+                        subscriber.socket_addr,
+                        topic_id,
+                        QOS_LEVEL_0,
+                        &data,
+                        client,
+                    ) {
+                        Ok(false) => addr_vec.push(subscriber.socket_addr),
+                        Ok(true) => {}
+                        Err(why) => error!("{}", why),
+                    }
+                }
+                // Sent on the next PINGREQ, see PingReq::try_sleep().
+                Ok(StateEnum2::ASLEEP) | Ok(StateEnum2::AWAKE) => {
+                    let publish = Publish::new(
+                        topic_id,
+                        msg_id,
+                        QOS_LEVEL_0,
+                        RETAIN_FALSE,
+                        data.clone(),
+                    );
+                    if let Err(why) =
+                        AsleepMsgCache::insert(subscriber.socket_addr, publish)
+                    {
+                        error!("{}", why);
+                    }
+                }
+                Ok(_) => {}
+                Err(why) => {
+                    error!("{}", why);
+                }
+            }
+        }
+        if !addr_vec.is_empty() {
+            if let Err(why) = Publish::send_batch(
+                topic_id,
+                QOS_LEVEL_0,
+                RETAIN_FALSE,
+                &data,
+                client,
+                addr_vec,
+                None,
+            ) {
+                error!("{}", eformat!(remote_socket_addr, why));
+            }
+        }
+        Some(Ok(()))
+    }
+
+    /// QoS -1 PUBLISH messages can be sent without a connection.
+    #[inline(always)]
+    pub fn is_qos_minus_one(buf: &[u8], msg_header: &MsgHeader) -> bool {
+        if msg_header.msg_type != MSG_TYPE_PUBLISH {
+            return false;
+        }
+        // The flags follow the message header.
+        let index = msg_header.body_offset();
+        buf.len() > index && flag_qos_level(buf[index]) == QOS_LEVEL_3
+    }
+
+    /// Serialize a PUBLISH message into a byte stream,
+    /// with 2 or 4 bytes header depending on the length.
+    #[inline(always)]
+    pub fn encode(
         topic_id: u16,
         msg_id: u16,
         qos: u8,
         retain: u8,
-        data: BytesMut,
-        client: &MqttSnClient, // contains the address of the publisher
-        remote_addr: SocketAddr, // address of the subscriber
-    ) -> Result<(), String> {
-        let len = data.len() + MSG_LEN_PUBLISH_HEADER as usize;
-        let mut bytes_buf = BytesMut::with_capacity(len);
-        // TODO verify that this is correct
+        data: &[u8],
+    ) -> Result<BytesMut, String> {
         let flags = flags_set(
             DUP_FALSE,
             qos,
@@ -261,43 +702,24 @@ impl Publish {
             TOPIC_ID_TYPE_NORMAL,
         ); // default for now
 
-        // TODO verify big-endian or little-endian for u16 numbers
-        // XXX order of statements performance
-        let msg_id_byte_1 = msg_id as u8;
-        let topic_id_byte_1 = topic_id as u8;
-        let msg_id_byte_0 = (msg_id >> 8) as u8;
-        let topic_id_byte_0 = (topic_id >> 8) as u8;
-
-        if len < 256 {
-            let buf: &[u8] = &[
-                len as u8,
-                MSG_TYPE_PUBLISH,
-                flags,
-                msg_id_byte_0,
-                msg_id_byte_1,
-                topic_id_byte_0,
-                topic_id_byte_1,
-            ];
-            bytes_buf.put(buf);
-        } else if len < 1400 {
-            let buf: &[u8] = &[
-                1,
-                (len >> 8) as u8,
-                len as u8,
-                MSG_TYPE_PUBLISH,
-                flags,
-                msg_id_byte_0,
-                msg_id_byte_1,
-                topic_id_byte_0,
-                topic_id_byte_1,
-            ];
-            bytes_buf.put(buf);
-        } else {
-            return Err(eformat!(remote_addr, "len too long", len));
-        }
-        bytes_buf.put(data);
-        // TODO: let bytes = bytes_buf.freeze(); // no copy on clone.
+        // Same field order and byte order as the derived try_read():
+        // len, msg_type, flags, topic_id, msg_id, data, u16 big-endian.
+        encode_packet(&Packet::Publish {
+            flags,
+            topic_id,
+            msg_id,
+            data,
+        })
+    }
 
+    /// Schedule retransmit for QoS Level 1 & 2.
+    #[inline(always)]
+    fn schedule_retransmit(
+        remote_addr: SocketAddr,
+        qos: u8,
+        msg_id: u16,
+        bytes: Bytes,
+    ) -> Result<(), String> {
         dbg!(&qos);
         match qos {
             // For level 1, schedule a message for retransmit,
@@ -310,7 +732,7 @@ impl Publish {
                     0,
                     msg_id,
                     10,
-                    bytes_buf.clone(),
+                    bytes,
                 )?;
             }
             QOS_LEVEL_2 => {
@@ -325,8 +747,7 @@ impl Publish {
                 //      cancel restransmit of PUBLISH
                 // 4. Receive PUBCOMP - in PubComp module
                 //      cancel retransmit of PUBREL
-                // PUBREC message doesn't have topic id.
-                // For the time wheel hash, default to 0.
+                // PUBREC message doesn't have topic id, 0.
                 dbg!(&qos);
                 RetransTimeWheel::schedule_timer(
                     remote_addr,
@@ -334,7 +755,7 @@ impl Publish {
                     0,
                     msg_id,
                     1,
-                    bytes_buf.clone(),
+                    bytes,
                 )?;
             }
             // no restransmit for Level 0 & 3.
@@ -343,44 +764,306 @@ impl Publish {
                 // TODO return error
             }
         }
-        // transmit message to remote address
-        match client.egress_tx.try_send((remote_addr, bytes_buf)) {
+        Ok(())
+    }
+
+    /// Publish a message
+    /// 1. Allocate a msg_id for the subscriber, 0 for QoS Level 0.
+    ///    Queue the message if the in-flight window of the subscriber is full.
+    /// 2. Format a message with Publish struct.
+    /// 3. Serialize into a byte stream.
+    /// 4. Send it to the channel.
+    /// 5. Schedule retransmit for QoS Level 1 & 2.
+    #[inline(always)]
+    #[trace]
+    pub fn send(
+        topic_id: u16,
+        qos: u8,
+        retain: u8,
+        data: Bytes,
+        client: &MqttSnClient, // contains the address of the publisher
+        remote_addr: SocketAddr, // address of the subscriber
+    ) -> Result<(), String> {
+        let msg_id = match qos {
+            QOS_LEVEL_1 | QOS_LEVEL_2 => {
+                match InFlight::acquire(remote_addr)? {
+                    Some(msg_id) => msg_id,
+                    None => {
+                        // Sent by InFlight::release() when an ACK arrives.
+                        let publish =
+                            Publish::new(topic_id, 0, qos, retain, data);
+                        return InFlight::enqueue(remote_addr, publish);
+                    }
+                }
+            }
+            _ => 0,
+        };
+        Publish::send_in_flight(
+            topic_id,
+            msg_id,
+            qos,
+            retain,
+            &data[..],
+            client,
+            remote_addr,
+        )
+    }
+
+    /// Send a message with the msg_id from the in-flight window.
+    #[inline(always)]
+    pub fn send_in_flight(
+        topic_id: u16,
+        msg_id: u16,
+        qos: u8,
+        retain: u8,
+        data: &[u8],
+        client: &MqttSnClient,
+        remote_addr: SocketAddr,
+    ) -> Result<(), String> {
+        let bytes_buf =
+            match Publish::encode(topic_id, msg_id, qos, retain, data) {
+                Ok(bytes_buf) => bytes_buf,
+                Err(why) => return Err(eformat!(remote_addr, why)),
+            };
+        let _msg_span =
+            MsgSpan::msg(remote_addr, MSG_TYPE_PUBLISH, msg_id).entered();
+        tracing::debug!(topic_id, qos, "PUBLISH sent");
+        SysStats::add_sent(1);
+        Publish::schedule_retransmit(
+            remote_addr,
+            qos,
+            msg_id,
+            Bytes::copy_from_slice(&bytes_buf[..]),
+        )?;
+        // transmit message to remote address, after the protocol messages
+        let bytes = bytes_buf.freeze();
+        match client.egress_batch_tx.try_send((vec![remote_addr], bytes)) {
             Ok(_) => Ok(()),
             Err(why) => Err(eformat!(remote_addr, why)),
         }
     }
+
+    /// Publish from the broker to the subscribers of the topic name, the
+    /// topic name is inserted if it's new. The subscribers that don't know
+    /// the topic id get a REGISTER first, see RegisterOnDemand. Returns the
+    /// topic id.
+    pub fn send_by_name(
+        topic_name: &str,
+        qos: u8,
+        retain: u8,
+        data: Bytes,
+        client: &MqttSnClient,
+    ) -> Result<TopicIdType, String> {
+        if !valid_filter(topic_name) || has_wildcards(topic_name) {
+            return Err(eformat!("invalid topic name", topic_name));
+        }
+        let topic_id = match PreDefinedTopics::id(topic_name) {
+            Some(topic_id) => topic_id,
+            None => try_insert_topic_name(topic_name.to_string())?,
+        };
+        let msg_id = match qos {
+            QOS_LEVEL_1 | QOS_LEVEL_2 => MsgIdAllocator::next_broker(),
+            _ => 0,
+        };
+        let publish = Publish::new(topic_id, msg_id, qos, retain, data);
+        if flag_is_retain(publish.flags) {
+            Retain::insert(
+                qos,
+                topic_id,
+                msg_id,
+                publish.data.clone(),
+                Annotations::new(),
+            );
+        }
+        #[cfg(feature = "bridge")]
+        crate::cluster::Cluster::forward(&publish, &Annotations::new());
+        Publish::send_msg_to_subscribers(
+            get_subscribers_with_topic_id(topic_id),
+            publish,
+            &Annotations::new(),
+            client,
+        )?;
+        Ok(topic_id)
+    }
+
+    /// Publish the same message to many subscribers with the same QoS.
+    /// For QoS 0 the message is serialized once into a frozen Bytes with
+    /// msg_id 0, and one fan-out entry is sent to the egress channel. For
+    /// QoS 1 & 2 each subscriber gets its own msg_id, see
+    /// send_in_flight_batch().
+    #[inline(always)]
+    pub fn send_batch(
+        topic_id: u16,
+        qos: u8,
+        retain: u8,
+        data: &Bytes,
+        client: &MqttSnClient,
+        addr_vec: Vec<SocketAddr>, // addresses of the subscribers
+        trace_id: Option<TraceId>,
+    ) -> Result<(), String> {
+        if qos == QOS_LEVEL_1 || qos == QOS_LEVEL_2 {
+            Publish::send_in_flight_batch(
+                topic_id, qos, retain, data, client, addr_vec, trace_id,
+            );
+            return Ok(());
+        }
+        if addr_vec.is_empty() {
+            return Ok(());
+        }
+        let msg_id = 0;
+        let bytes =
+            Publish::encode(topic_id, msg_id, qos, retain, data)?.freeze();
+        SysStats::add_sent(addr_vec.len());
+        for remote_addr in &addr_vec {
+            tracing::debug!(
+                parent: &MsgSpan::msg(*remote_addr, MSG_TYPE_PUBLISH, msg_id),
+                topic_id,
+                qos,
+                "PUBLISH sent"
+            );
+            // Can't return error, because not all subscribers will have error.
+            // Bytes clone() doesn't copy the data.
+            if let Err(why) = Publish::schedule_retransmit(
+                *remote_addr,
+                qos,
+                msg_id,
+                bytes.clone(),
+            ) {
+                error!("{}", why);
+            }
+        }
+        match client.egress_batch_tx.try_send((addr_vec, bytes)) {
+            Ok(_) => Ok(()),
+            Err(why) => Err(eformat!(topic_id, msg_id, why)),
+        }
+    }
+
+    /// QoS 1 & 2 subscribers with room in the in-flight window get a msg_id
+    /// of their own, the msg_id of the publisher could collide with the
+    /// other messages in flight to the subscriber. The message is queued
+    /// for the other subscribers.
+    fn send_in_flight_batch(
+        topic_id: u16,
+        qos: u8,
+        retain: u8,
+        data: &Bytes,
+        client: &MqttSnClient,
+        addr_vec: Vec<SocketAddr>,
+        trace_id: Option<TraceId>,
+    ) {
+        for remote_addr in addr_vec {
+            let result = match InFlight::acquire(remote_addr) {
+                Ok(Some(msg_id)) => {
+                    if let Some(trace_id) = trace_id {
+                        // Follow the acks of the subscriber's msg_id.
+                        MsgTrace::add_target(trace_id, remote_addr, msg_id);
+                    }
+                    Publish::send_in_flight(
+                        topic_id,
+                        msg_id,
+                        qos,
+                        retain,
+                        &data[..],
+                        client,
+                        remote_addr,
+                    )
+                }
+                Ok(None) => {
+                    // Queued with a shared reference to the data.
+                    let publish =
+                        Publish::new(topic_id, 0, qos, retain, data.clone());
+                    InFlight::enqueue(remote_addr, publish)
+                }
+                Err(why) => Err(why),
+            };
+            // Can't return error, because not all subscribers will have error.
+            if let Err(why) = result {
+                error!("{}", why);
+            }
+        }
+    }
+
     /// send PUBLISH messages to subscribers
+    /// Subscribers rejected by the annotation predicates are skipped.
+    /// The topic id is registered first to the subscribers that don't know
+    /// it, see RegisterOnDemand.
+    /// Active subscribers with the same QoS share one serialized message.
+    /// The queued and the cached messages share the data of the publish.
+    /// In-process consumers are called before the network subscribers.
     pub fn send_msg_to_subscribers(
         subscriber_vec: Vec<Subscriber>,
         publish: Publish,
+        annotations: &Annotations,
         client: &MqttSnClient,
     ) -> Result<(), String> {
+        LocalConsumer::deliver(&publish, annotations);
+        LastValueCache::update(&publish);
+        let subscriber_vec = annotations.filter_subscribers(subscriber_vec);
+        let trace_id = MsgTrace::trace_id(annotations);
+        let publish_qos = flag_qos_level(publish.flags);
+        // Active subscribers grouped by QoS.
+        let mut qos_map: HashMap<QoSConst, Vec<SocketAddr>> = HashMap::new();
         // send PUBLISH messages to subscribers
         for subscriber in subscriber_vec {
+            // Never above the QoS of the PUBLISH.
+            let qos = effective_qos(publish_qos, subscriber.qos);
             // Can't return error, because not all subscribers will have error.
             // TODO error for every subscriber/message
-            // TODO new tx method to reduce have try_write() run once for every subscriber.
             match Connection::get_state(&subscriber.socket_addr) {
                 Ok(state) => match state {
                     StateEnum2::ACTIVE => {
-                        // Send now
-                        let _result = Publish::send(
+                        // Registered first if the topic id is unknown, e.g.
+                        // for a wildcard subscription.
+                        match RegisterOnDemand::try_defer(
+                            subscriber.socket_addr,
+                            publish.topic_id,
+                            qos,
+                            &publish.data,
+                            client,
+                        ) {
+                            Ok(false) => {}
+                            Ok(true) => continue,
+                            Err(why) => {
+                                error!("{}", why);
+                                continue;
+                            }
+                        }
+                        if let Some(trace_id) = trace_id {
+                            MsgTrace::record_trace(
+                                trace_id,
+                                subscriber.socket_addr,
+                                TraceStage::Egress,
+                            );
+                        }
+                        // Send after all subscribers are grouped.
+                        qos_map
+                            .entry(qos)
+                            .or_insert_with(Vec::new)
+                            .push(subscriber.socket_addr);
+                    }
+                    StateEnum2::ASLEEP | StateEnum2::AWAKE => {
+                        if let Some(trace_id) = trace_id {
+                            MsgTrace::record_trace(
+                                trace_id,
+                                subscriber.socket_addr,
+                                TraceStage::Asleep,
+                            );
+                        }
+                        // Cache the publish instance at the effective QoS,
+                        // send it when the client sends a PingRequest.
+                        let asleep_publish = Publish::new(
                             publish.topic_id,
                             publish.msg_id,
-                            subscriber.qos,
+                            qos,
                             RETAIN_FALSE,
                             publish.data.clone(),
-                            client,
-                            subscriber.socket_addr,
                         );
-                    }
-                    StateEnum2::ASLEEP => {
-                        // Cache the publish instance,
-                        // send it when the client sends a PingRequest.
-                        AsleepMsgCache::insert(
+                        if let Err(why) = AsleepMsgCache::insert(
                             subscriber.socket_addr,
-                            publish.clone(),
-                        );
+                            asleep_publish,
+                        ) {
+                            error!("{}", why);
+                        }
                     }
                     _ => {}
                 },
@@ -388,9 +1071,221 @@ impl Publish {
                     error!("{}", why);
                 }
             }
-            //      }
-            //     _ => { ;
+        }
+        for (qos, addr_vec) in qos_map {
+            if let Err(why) = Publish::send_batch(
+                publish.topic_id,
+                qos,
+                RETAIN_FALSE,
+                &publish.data,
+                client,
+                addr_vec,
+                trace_id,
+            ) {
+                error!("{}", why);
+            }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_qos_downgrade() {
+        use super::*;
+        use crate::test_support::{LoopbackBroker, TestClient};
+        assert_eq!(effective_qos(QOS_LEVEL_0, QOS_LEVEL_2), QOS_LEVEL_0);
+        assert_eq!(effective_qos(QOS_LEVEL_2, QOS_LEVEL_1), QOS_LEVEL_1);
+        assert_eq!(effective_qos(QOS_LEVEL_1, QOS_LEVEL_1), QOS_LEVEL_1);
+        assert_eq!(effective_qos(QOS_LEVEL_3, QOS_LEVEL_2), QOS_LEVEL_0);
+        let topic = "qos_downgrade/temp";
+        let mut subscribers = Vec::new();
+        for (client_id, qos) in
+            [("qosDown1", QOS_LEVEL_1), ("qosDown2", QOS_LEVEL_2)].iter()
+        {
+            let mut subscriber =
+                TestClient::new(LoopbackBroker::addr()).unwrap();
+            subscriber.connect(client_id, 60, None).unwrap();
+            subscriber.subscribe(topic, *qos).unwrap();
+            subscribers.push(subscriber);
+        }
+        let mut publisher = TestClient::new(LoopbackBroker::addr()).unwrap();
+        publisher.connect("qosDownPub", 60, None).unwrap();
+        let topic_id = publisher.register(topic).unwrap();
+        // QoS 0 PUBLISH, no handshake with the QoS 1 and 2 subscribers.
+        publisher
+            .publish(topic_id, QOS_LEVEL_0, RETAIN_FALSE, b"zero")
+            .unwrap();
+        for subscriber in subscribers.iter_mut() {
+            assert_eq!(subscriber.recv_publish().unwrap().qos, QOS_LEVEL_0);
+        }
+        // QoS 2 PUBLISH, at the granted QoS.
+        publisher
+            .publish(topic_id, QOS_LEVEL_2, RETAIN_FALSE, b"two")
+            .unwrap();
+        let granted = [QOS_LEVEL_1, QOS_LEVEL_2];
+        for (subscriber, qos) in subscribers.iter_mut().zip(granted.iter()) {
+            let publish = subscriber.recv_publish().unwrap();
+            assert_eq!(&publish.payload[..], b"two");
+            assert_eq!(publish.qos, *qos);
+        }
+        publisher.disconnect(None).unwrap();
+        for subscriber in subscribers.iter_mut() {
+            subscriber.disconnect(None).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_msg_id_per_subscriber() {
+        use super::*;
+        use crate::test_support::{LoopbackBroker, TestClient};
+        let topic = "msg_id_per_sub/temp";
+        let mut subscriber = TestClient::new(LoopbackBroker::addr()).unwrap();
+        subscriber.connect("msgIdPerSub", 60, None).unwrap();
+        subscriber.subscribe(topic, QOS_LEVEL_1).unwrap();
+        // Both publishers use msg_id 1.
+        let mut publishers = Vec::new();
+        for client_id in ["msgIdPub1", "msgIdPub2"].iter() {
+            let mut publisher =
+                TestClient::new(LoopbackBroker::addr()).unwrap();
+            publisher.connect(client_id, 60, None).unwrap();
+            let topic_id = publisher.register(topic).unwrap();
+            publisher
+                .publish(topic_id, QOS_LEVEL_1, RETAIN_FALSE, b"one")
+                .unwrap();
+            publishers.push(publisher);
+        }
+        let first = subscriber.recv_publish().unwrap();
+        let second = subscriber.recv_publish().unwrap();
+        assert_ne!(first.msg_id, second.msg_id);
+        // Not acknowledged yet, both are in flight.
+        assert_eq!(InFlight::in_flight(&subscriber.local_addr()), 2);
+        for publisher in publishers.iter_mut() {
+            publisher.disconnect(None).unwrap();
+        }
+        subscriber.disconnect(None).unwrap();
+    }
+
+    #[test]
+    fn test_publish_by_name() {
+        use super::*;
+        use crate::test_support::{LoopbackBroker, TestClient};
+        let mut subscriber = TestClient::new(LoopbackBroker::addr()).unwrap();
+        subscriber.connect("byNameSub", 60, None).unwrap();
+        subscriber.subscribe("by_name/#", QOS_LEVEL_1).unwrap();
+        let client = MqttSnClient::new();
+        let topic_id = client
+            .publish_by_name("by_name/temp", QOS_LEVEL_1, RETAIN_FALSE, b"7")
+            .unwrap();
+        // The REGISTER is acknowledged before the PUBLISH is received.
+        let publish = subscriber.recv_publish().unwrap();
+        assert_eq!(publish.topic_id, topic_id);
+        assert_eq!(publish.qos, QOS_LEVEL_1);
+        assert_eq!(&publish.payload[..], b"7");
+        assert_eq!(subscriber.registered(topic_id).unwrap(), "by_name/temp");
+        // Same topic id the next time.
+        let topic_id2 = client
+            .publish_by_name("by_name/temp", QOS_LEVEL_0, RETAIN_FALSE, b"8")
+            .unwrap();
+        assert_eq!(topic_id2, topic_id);
+        assert_eq!(&subscriber.recv_publish().unwrap().payload[..], b"8");
+        assert!(client
+            .publish_by_name("by_name/+", QOS_LEVEL_0, RETAIN_FALSE, b"9")
+            .is_err());
+        subscriber.disconnect(None).unwrap();
+    }
+
+    #[test]
+    fn test_fast_path_malformed() {
+        use super::*;
+        use crate::broker_context::BrokerContext;
+        let context = BrokerContext::new();
+        let _context = context.enter();
+        let client = MqttSnClient::new().with_context(context);
+        let publisher = "127.0.0.1:1400".parse::<SocketAddr>().unwrap();
+        let subscriber = "127.0.0.2:1400".parse::<SocketAddr>().unwrap();
+        let topic_id =
+            try_insert_topic_name("fast_path/temp".to_string()).unwrap();
+        subscribe_with_topic_id(subscriber, topic_id, QOS_LEVEL_0).unwrap();
+        let bytes =
+            Publish::encode(topic_id, 0, QOS_LEVEL_0, RETAIN_FALSE, b"21")
+                .unwrap();
+        // The Length field doesn't match the datagram.
+        let mut malformed = bytes.clone();
+        malformed[0] += 1;
+        let malformed = malformed.freeze();
+        assert!(
+            Publish::try_recv_fast(&malformed, &client, publisher).is_none()
+        );
+        // Pre-defined and short topic ids aren't normal topic ids.
+        for topic_id_type in
+            [TOPIC_ID_TYPE_PRE_DEFINED, TOPIC_ID_TYPE_SHORT].iter()
+        {
+            let mut other_type = bytes.clone();
+            other_type[2] |= topic_id_type;
+            let other_type = other_type.freeze();
+            assert!(Publish::try_recv_fast(&other_type, &client, publisher)
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn test_qos_minus_one() {
+        use super::*;
+        use crate::codec::put_u16_be;
+        use crate::test_support::{LoopbackBroker, TestClient};
+        use crate::{MSG_TYPE_SUBACK, MSG_TYPE_SUBSCRIBE};
+        // Pre-defined topic id, known to the clients without a REGISTER.
+        let topic_id = 0xFE10;
+        let mut subscriber = TestClient::new(LoopbackBroker::addr()).unwrap();
+        subscriber.connect("qosMinusOneSub", 60, None).unwrap();
+        let mut bytes = BytesMut::new();
+        bytes.put_u8(7);
+        bytes.put_u8(MSG_TYPE_SUBSCRIBE);
+        bytes.put_u8(QOS_LEVEL_0 | TOPIC_ID_TYPE_PRE_DEFINED);
+        put_u16_be(&mut bytes, 1);
+        put_u16_be(&mut bytes, topic_id);
+        subscriber.send(&bytes).unwrap();
+        let suback = subscriber.expect(MSG_TYPE_SUBACK).unwrap();
+        assert_eq!(suback[7], RETURN_CODE_ACCEPTED);
+        // QoS -1 from a client without a connection.
+        let publisher = TestClient::new(LoopbackBroker::addr()).unwrap();
+        let send = |topic_id_type, payload: &[u8]| {
+            let mut bytes = Publish::encode(
+                topic_id,
+                0,
+                QOS_LEVEL_3,
+                RETAIN_FALSE,
+                payload,
+            )
+            .unwrap();
+            bytes[2] |= topic_id_type;
+            publisher.send(&bytes).unwrap();
+        };
+        // A normal topic id is rejected, the message isn't forwarded.
+        send(TOPIC_ID_TYPE_NORMAL, b"normal");
+        send(TOPIC_ID_TYPE_PRE_DEFINED, b"pre-defined");
+        let publish = subscriber.recv_publish().unwrap();
+        assert_eq!(publish.topic_id, topic_id);
+        assert_eq!(publish.qos, QOS_LEVEL_0);
+        assert_eq!(&publish.payload[..], b"pre-defined");
+        // A short topic name, the 2 characters in the topic id field.
+        let (short_id, _) = subscriber.subscribe("q1", QOS_LEVEL_0).unwrap();
+        let mut bytes = Publish::encode(
+            u16::from_be_bytes(*b"q1"),
+            0,
+            QOS_LEVEL_3,
+            RETAIN_FALSE,
+            b"short",
+        )
+        .unwrap();
+        bytes[2] |= TOPIC_ID_TYPE_SHORT;
+        publisher.send(&bytes).unwrap();
+        let publish = subscriber.recv_publish().unwrap();
+        assert_eq!(publish.topic_id, short_id);
+        assert_eq!(&publish.payload[..], b"short");
+        assert!(!Connection::contains_key(publisher.local_addr()));
+        subscriber.disconnect(None).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+/// Pool of the receive buffers of the ingress.
+///
+/// A datagram is received into a buffer from the pool and frozen into a
+/// Bytes of the received size, the parsed messages slice it without a copy,
+/// e.g. the data of a PUBLISH. The rest of the buffer goes back to the
+/// pool. When the buffer is taken again, the allocation is reclaimed if all
+/// the slices of the previous datagram are dropped, or a new one is
+/// allocated. The pool is a FIFO, the oldest buffer has the best chance to
+/// be reclaimed.
+use bytes::{Bytes, BytesMut};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Largest datagram, the read buffer of the DTLS connections.
+pub const RECV_BUF_SIZE: usize = 8192;
+/// Buffers kept in RECV_POOL.
+pub const RECV_POOL_SIZE: usize = 256;
+
+lazy_static! {
+    pub static ref RECV_POOL: RecvPool = RecvPool::new(RECV_POOL_SIZE);
+}
+
+#[derive(Debug)]
+pub struct RecvPool {
+    bufs: Mutex<VecDeque<BytesMut>>,
+    max_bufs: usize,
+}
+
+impl RecvPool {
+    pub fn new(max_bufs: usize) -> Self {
+        RecvPool {
+            bufs: Mutex::new(VecDeque::with_capacity(max_bufs)),
+            max_bufs,
+        }
+    }
+
+    /// A zeroed buffer of RECV_BUF_SIZE bytes to receive a datagram.
+    pub fn take(&self) -> BytesMut {
+        let buf = self.bufs.lock().unwrap().pop_front();
+        let mut buf = match buf {
+            // Reuses the allocation if the previous slices are dropped.
+            Some(mut buf) => {
+                buf.reserve(RECV_BUF_SIZE);
+                buf
+            }
+            None => BytesMut::with_capacity(RECV_BUF_SIZE),
+        };
+        buf.resize(RECV_BUF_SIZE, 0);
+        buf
+    }
+
+    /// The size bytes received in buf, the buffer goes back to the pool.
+    pub fn freeze(&self, mut buf: BytesMut, size: usize) -> Bytes {
+        let bytes = buf.split_to(size).freeze();
+        buf.clear();
+        let mut bufs = self.bufs.lock().unwrap();
+        if bufs.len() < self.max_bufs {
+            bufs.push_back(buf);
+        }
+        bytes
+    }
+
+    /// Number of buffers in the pool.
+    pub fn available(&self) -> usize {
+        self.bufs.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_recv_pool() {
+        use super::*;
+        let pool = RecvPool::new(1);
+        let mut buf = pool.take();
+        assert_eq!(buf.len(), RECV_BUF_SIZE);
+        buf[..5].copy_from_slice(b"hello");
+        let ptr = buf.as_ptr();
+        let bytes = pool.freeze(buf, 5);
+        assert_eq!(&bytes[..], b"hello");
+        assert_eq!(pool.available(), 1);
+        // The slice shares the buffer.
+        let data = bytes.slice(1..);
+        assert_eq!(data.as_ptr(), unsafe { ptr.add(1) });
+        drop(bytes);
+        drop(data);
+        // Reclaimed, the slices are dropped.
+        let buf = pool.take();
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(pool.available(), 0);
+        // A full pool drops the buffer.
+        let extra = pool.take();
+        let _first = pool.freeze(buf, 1);
+        let _second = pool.freeze(extra, 1);
+        assert_eq!(pool.available(), 1);
+    }
+}
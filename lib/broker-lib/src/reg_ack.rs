@@ -17,8 +17,9 @@ use getset::{CopyGetters, Getters, MutGetters};
 use std::mem;
 
 use crate::{
-    broker_lib::MqttSnClient, eformat, function, msg_hdr::MsgHeader,
-    retransmit::RetransTimeWheel, MSG_LEN_REGACK, MSG_TYPE_REGACK,
+    broker_lib::MqttSnClient, codec, decode::Decode, eformat, function,
+    msg_hdr::MsgHeader, register_on_demand::RegisterOnDemand,
+    retransmit::RetransTimeWheel, MSG_LEN_REGACK,
 };
 
 #[derive(Debug, Clone, Getters, MutGetters, CopyGetters, Default)]
@@ -31,6 +32,13 @@ pub struct RegAck {
     pub msg_id: u16,
     pub return_code: u8,
 }
+
+codec!(RegAck, {
+    topic_id: u16,
+    msg_id: u16,
+    return_code: u8,
+});
+
 impl RegAck {
     pub fn recv(
         buf: &[u8],
@@ -38,20 +46,25 @@ impl RegAck {
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
-        let (reg_ack, read_len) = RegAck::try_read(buf, size).unwrap();
+        let (reg_ack, read_len) =
+            Decode::read(RegAck::try_read(buf, size), size, &msg_header)?;
         dbg!(reg_ack.clone());
 
         let remote_socket_addr = msg_header.remote_socket_addr;
         if read_len == MSG_LEN_REGACK as usize {
-            match RetransTimeWheel::cancel_timer(
+            RetransTimeWheel::cancel_timer(
                 remote_socket_addr,
                 reg_ack.msg_type,
+                reg_ack.msg_id,
+            )?;
+            // Send the messages waiting for the topic id.
+            RegisterOnDemand::on_reg_ack(
+                remote_socket_addr,
                 reg_ack.topic_id,
                 reg_ack.msg_id,
-            ) {
-                Ok(()) => Ok(()),
-                Err(err) => Err(err),
-            }
+                reg_ack.return_code,
+                client,
+            )
         } else {
             Err(eformat!(remote_socket_addr, "size", buf[0]))
         }
@@ -64,16 +77,7 @@ impl RegAck {
         msg_header: MsgHeader,
     ) -> Result<(), String> {
         let remote_socket_addr = msg_header.remote_socket_addr;
-        let reg_ack = RegAck {
-            len: MSG_LEN_REGACK,
-            msg_type: MSG_TYPE_REGACK,
-            topic_id,
-            msg_id,
-            return_code,
-        };
-        let mut bytes_buf = BytesMut::with_capacity(MSG_LEN_REGACK as usize);
-        dbg!(reg_ack.clone());
-        reg_ack.try_write(&mut bytes_buf);
+        let bytes_buf = RegAck::encode(topic_id, msg_id, return_code);
         dbg!(bytes_buf.clone());
         dbg!(remote_socket_addr);
         // transmit to network
@@ -85,4 +89,18 @@ impl RegAck {
             Err(err) => Err(eformat!(remote_socket_addr, err)),
         }
     }
+
+    /// Send a REGACK with a rejection return code and topic id 0, then
+    /// return why as the error for the caller to log.
+    #[inline(always)]
+    pub fn reject(
+        msg_id: u16,
+        return_code: u8,
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+        why: String,
+    ) -> Result<(), String> {
+        RegAck::send(0, msg_id, return_code, client, msg_header)?;
+        Err(why)
+    }
 }
@@ -18,13 +18,22 @@ use custom_debug::Debug;
 use getset::{CopyGetters, Getters, MutGetters};
 use log::*;
 use std::mem;
+use std::net::SocketAddr;
 use std::str;
 
 use crate::{
-    broker_lib::MqttSnClient, eformat, filter::get_topic_id_with_topic_name,
-    function, msg_hdr::*, reg_ack::RegAck, retransmit::RetransTimeWheel,
-    MSG_LEN_REGISTER_HEADER, MSG_TYPE_REGACK, MSG_TYPE_REGISTER,
-    RETURN_CODE_ACCEPTED, RETURN_CODE_INVALID_TOPIC_ID,
+    broker_lib::MqttSnClient,
+    codec::{encode_packet, Packet},
+    decode::Decode,
+    eformat,
+    filter::{has_wildcards, valid_filter},
+    function,
+    msg_hdr::*,
+    reg_ack::RegAck,
+    retransmit::RetransTimeWheel,
+    topic_refs::{TopicRef, TopicRefs},
+    MSG_TYPE_REGACK, RETURN_CODE_ACCEPTED, RETURN_CODE_CONGESTION,
+    RETURN_CODE_NOT_SUPPORTED,
 };
 #[derive(Debug, Clone, Getters, MutGetters, CopyGetters, Default)]
 #[getset(get, set)]
@@ -66,39 +75,56 @@ impl Register {
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
-        let register: Register;
-        let _read_fixed_len: usize;
-        match msg_header.header_len {
-            MsgHeaderLenEnum::Short => {
-                (register, _read_fixed_len) =
-                    Register::try_read(buf, size).unwrap();
-            }
-            MsgHeaderLenEnum::Long => {
-                (register, _read_fixed_len) =
-                    Register::try_read(&buf[3..], size).unwrap();
-            }
+        // topic_id and msg_id follow the header, then the topic name.
+        Decode::utf8(buf, 6, size, &msg_header)?;
+        let (register, _read_fixed_len) =
+            Decode::read(Register::try_read(buf, size), size, &msg_header)?;
+        let remote_socket_addr = msg_header.remote_socket_addr;
+        // A topic name, not a filter.
+        if !valid_filter(&register.topic_name)
+            || has_wildcards(&register.topic_name)
+        {
+            let why =
+                eformat!(remote_socket_addr, "topic name", register.topic_name);
+            return RegAck::reject(
+                register.msg_id,
+                RETURN_CODE_NOT_SUPPORTED,
+                client,
+                msg_header,
+                why,
+            );
+        }
+        if client.is_congested() {
+            let why = eformat!(remote_socket_addr, "congested");
+            return RegAck::reject(
+                register.msg_id,
+                RETURN_CODE_CONGESTION,
+                client,
+                msg_header,
+                why,
+            );
+        }
+        // The existing topic id or a new one.
+        match TopicRefs::insert(
+            register.topic_name,
+            TopicRef::Registration(remote_socket_addr),
+        ) {
+            Ok(topic_id) => RegAck::send(
+                topic_id,
+                register.msg_id,
+                RETURN_CODE_ACCEPTED,
+                client,
+                msg_header,
+            ),
+            // No topic id left.
+            Err(why) => RegAck::reject(
+                register.msg_id,
+                RETURN_CODE_CONGESTION,
+                client,
+                msg_header,
+                why,
+            ),
         }
-        match get_topic_id_with_topic_name(register.topic_name) {
-            Some(topic_id) => {
-                RegAck::send(
-                    topic_id,
-                    register.msg_id,
-                    RETURN_CODE_ACCEPTED,
-                    client,
-                    msg_header,
-                )?;
-            }
-            None => {
-                RegAck::send(
-                    0,
-                    register.msg_id,
-                    RETURN_CODE_INVALID_TOPIC_ID,
-                    client,
-                    msg_header,
-                )?;
-            }
-        };
-        Ok(())
     }
     pub fn send(
         topic_id: u16,
@@ -107,26 +133,27 @@ impl Register {
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
-        // new way to format a message
-        let len = MSG_LEN_REGISTER_HEADER as usize + topic_name.len() as usize;
-        let mut buf = BytesMut::with_capacity(len);
-        // TODO optimize by initializing an array of header fields
-        // then buf.put_slice().
-        if len < 256 {
-            // 2-byte header
-            buf.put_u8(len as u8);
-        } else if len < 1400 {
-            // 4-byte header
-            buf.put_u8(1);
-            buf.put_u16(len as u16);
-        } else {
-            return Err(eformat!("len is too big", len));
-        }
-        let remote_socket_addr = msg_header.remote_socket_addr;
-        buf.put_u8(MSG_TYPE_REGISTER);
-        buf.put_u16(topic_id);
-        buf.put_u16(msg_id);
-        buf.put_slice(topic_name.as_bytes());
+        Register::send_to(
+            topic_id,
+            msg_id,
+            topic_name,
+            client,
+            msg_header.remote_socket_addr,
+        )
+    }
+    /// REGISTER to a client without a received message, e.g. a subscriber.
+    pub fn send_to(
+        topic_id: u16,
+        msg_id: u16,
+        topic_name: String,
+        client: &MqttSnClient,
+        remote_socket_addr: SocketAddr,
+    ) -> Result<(), String> {
+        let buf = encode_packet(&Packet::Register {
+            topic_id,
+            msg_id,
+            topic_name: &topic_name,
+        })?;
         // transmit to network
         // transmit message to remote address
         if let Err(err) = client
@@ -143,7 +170,7 @@ impl Register {
             1,
             buf,
         ) {
-            Ok(()) => Ok(()),
+            Ok(_token) => Ok(()),
             Err(err) => Err(err),
         }
     }
@@ -0,0 +1,231 @@
+/// REGISTER on demand for the topics a subscriber doesn't know.
+///
+/// A client subscribed to a wildcard filter only knows the topic id of the
+/// filter, section 6.10 of the spec requires the gateway to REGISTER the
+/// topic id of a matching topic before the first PUBLISH with it. The
+/// publishes to a subscriber without a reference to the topic id, see
+/// TopicRefs::is_known(), are queued, a REGISTER is sent with retransmit,
+/// and the queue is sent when the REGACK arrives. A rejected or timed out
+/// REGISTER drops the queue. The pre-defined topic ids are known.
+use bytes::Bytes;
+use hashbrown::HashMap;
+use log::*;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use crate::{
+    broker_context::BrokerContext,
+    broker_lib::MqttSnClient,
+    config::PreDefinedTopics,
+    eformat,
+    filter::get_topic_name,
+    flags::{flag_qos_level, QoSConst, RETAIN_FALSE, RETAIN_TRUE},
+    function,
+    in_flight::InFlight,
+    msg_id::MsgIdAllocator,
+    publish::Publish,
+    register::Register,
+    topic_refs::{TopicRef, TopicRefs},
+    TopicIdType, RETURN_CODE_ACCEPTED,
+};
+
+#[derive(Debug, Default)]
+struct PendingTopic {
+    /// msg_id of the REGISTER.
+    msg_id: u16,
+    queue: VecDeque<Publish>,
+}
+
+/// Pending REGISTERs of a broker, see BrokerContext.
+#[derive(Default)]
+pub(crate) struct RegisterOnDemandState {
+    pending: Mutex<HashMap<SocketAddr, HashMap<TopicIdType, PendingTopic>>>,
+}
+
+#[inline(always)]
+fn state() -> &'static RegisterOnDemandState {
+    &BrokerContext::current().register_on_demand
+}
+
+#[derive(Debug, Clone)]
+pub struct RegisterOnDemand {}
+
+impl RegisterOnDemand {
+    /// Queue the message if the subscriber doesn't know the topic id yet,
+    /// the first one sends the REGISTER. Returns false if the message can
+    /// be sent now.
+    pub fn try_defer(
+        remote_addr: SocketAddr,
+        topic_id: TopicIdType,
+        qos: QoSConst,
+        data: &Bytes,
+        client: &MqttSnClient,
+    ) -> Result<bool, String> {
+        let publish =
+            || Publish::new(topic_id, 0, qos, RETAIN_FALSE, data.clone());
+        let (topic_name, msg_id) = {
+            let mut pending = state().pending.lock().unwrap();
+            if let Some(topic) = pending
+                .get_mut(&remote_addr)
+                .and_then(|topics| topics.get_mut(&topic_id))
+            {
+                let max_queued = InFlight::limits().max_queued;
+                if topic.queue.len() >= max_queued {
+                    return Err(eformat!(remote_addr, "queue full", topic_id));
+                }
+                topic.queue.push_back(publish());
+                return Ok(true);
+            }
+            if PreDefinedTopics::name(topic_id).is_some()
+                || TopicRefs::is_known(topic_id, &remote_addr)
+            {
+                return Ok(false);
+            }
+            // Removed topic, nothing to register.
+            let topic_name = match get_topic_name(topic_id) {
+                Some(topic_name) => topic_name,
+                None => return Ok(false),
+            };
+            let msg_id = MsgIdAllocator::next(remote_addr)?;
+            let mut queue = VecDeque::new();
+            queue.push_back(publish());
+            pending
+                .entry(remote_addr)
+                .or_default()
+                .insert(topic_id, PendingTopic { msg_id, queue });
+            (topic_name, msg_id)
+        };
+        // Known from now on, the later messages wait in the queue.
+        TopicRefs::acquire(topic_id, TopicRef::Registration(remote_addr));
+        if let Err(why) =
+            Register::send_to(topic_id, msg_id, topic_name, client, remote_addr)
+        {
+            RegisterOnDemand::abort(remote_addr, topic_id);
+            return Err(why);
+        }
+        Ok(true)
+    }
+
+    /// REGACK of a REGISTER, send the queued messages if accepted.
+    pub fn on_reg_ack(
+        remote_addr: SocketAddr,
+        topic_id: TopicIdType,
+        msg_id: u16,
+        return_code: u8,
+        client: &MqttSnClient,
+    ) -> Result<(), String> {
+        let queue = {
+            let mut pending = state().pending.lock().unwrap();
+            let topics = match pending.get_mut(&remote_addr) {
+                Some(topics) => topics,
+                None => return Ok(()),
+            };
+            match topics.get(&topic_id) {
+                Some(topic) if topic.msg_id == msg_id => {}
+                // Not a REGISTER on demand.
+                _ => return Ok(()),
+            }
+            let topic = topics.remove(&topic_id).unwrap();
+            if topics.is_empty() {
+                pending.remove(&remote_addr);
+            }
+            topic.queue
+        };
+        if return_code != RETURN_CODE_ACCEPTED {
+            TopicRefs::release(topic_id, TopicRef::Registration(remote_addr));
+            return Err(eformat!(
+                remote_addr,
+                "REGISTER rejected",
+                topic_id,
+                return_code,
+                queue.len()
+            ));
+        }
+        for publish in queue {
+            let flags = *publish.flags();
+            if let Err(why) = Publish::send(
+                topic_id,
+                flag_qos_level(flags),
+                flags & RETAIN_TRUE,
+                publish.data().clone(),
+                client,
+                remote_addr,
+            ) {
+                error!("{}", why);
+            }
+        }
+        Ok(())
+    }
+
+    /// The REGISTER timed out, drop the queued messages.
+    pub fn abort(remote_addr: SocketAddr, topic_id: TopicIdType) {
+        let dropped = {
+            let mut pending = state().pending.lock().unwrap();
+            let topics = match pending.get_mut(&remote_addr) {
+                Some(topics) => topics,
+                None => return,
+            };
+            let dropped = topics.remove(&topic_id);
+            if topics.is_empty() {
+                pending.remove(&remote_addr);
+            }
+            dropped
+        };
+        if let Some(topic) = dropped {
+            TopicRefs::release(topic_id, TopicRef::Registration(remote_addr));
+            warn!(
+                "{}",
+                eformat!(remote_addr, topic_id, "dropped", topic.queue.len())
+            );
+        }
+    }
+
+    /// Number of messages waiting for a REGACK from the client.
+    pub fn queued(remote_addr: &SocketAddr) -> usize {
+        let pending = state().pending.lock().unwrap();
+        pending.get(remote_addr).map_or(0, |topics| {
+            topics.values().map(|topic| topic.queue.len()).sum()
+        })
+    }
+    /// Drop the queues of a removed connection.
+    pub fn remove(remote_addr: &SocketAddr) {
+        state().pending.lock().unwrap().remove(remote_addr);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_register_on_demand() {
+        use super::*;
+        use crate::flags::{QOS_LEVEL_0, QOS_LEVEL_1};
+        use crate::test_support::{LoopbackBroker, TestClient};
+        let mut subscriber = TestClient::new(LoopbackBroker::addr()).unwrap();
+        subscriber.connect("onDemandSub", 60, None).unwrap();
+        let (filter_id, _return_code) =
+            subscriber.subscribe("on_demand/#", QOS_LEVEL_1).unwrap();
+        let mut publisher = TestClient::new(LoopbackBroker::addr()).unwrap();
+        publisher.connect("onDemandPub", 60, None).unwrap();
+        let topic_id = publisher.register("on_demand/temp").unwrap();
+        assert_ne!(topic_id, filter_id);
+        assert!(subscriber.registered(topic_id).is_none());
+        publisher
+            .publish(topic_id, QOS_LEVEL_1, RETAIN_FALSE, b"21.5")
+            .unwrap();
+        // The REGISTER is acknowledged before the PUBLISH is received.
+        let publish = subscriber.recv_publish().unwrap();
+        assert_eq!(publish.topic_id, topic_id);
+        assert_eq!(&publish.payload[..], b"21.5");
+        assert_eq!(subscriber.registered(topic_id).unwrap(), "on_demand/temp");
+        assert_eq!(RegisterOnDemand::queued(&subscriber.local_addr()), 0);
+        // Known, sent without a REGISTER.
+        publisher
+            .publish(topic_id, QOS_LEVEL_0, RETAIN_FALSE, b"22.0")
+            .unwrap();
+        let publish = subscriber.recv_publish().unwrap();
+        assert_eq!(&publish.payload[..], b"22.0");
+        publisher.disconnect(None).unwrap();
+        subscriber.disconnect(None).unwrap();
+    }
+}
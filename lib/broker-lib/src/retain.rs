@@ -1,18 +1,173 @@
-use bytes::BytesMut;
+/// Retained messages, one per topic.
+///
+/// A retained PUBLISH replaces the message of its topic, a retained PUBLISH
+/// with an empty payload clears it. A message expires after its TTL, the
+/// RETAIN_TTL_ANNOTATION annotation in seconds set by a hook, or the
+/// default TTL. Expired messages are removed when they are read or when the
+/// store is full. With size caps, the least recently used messages are
+/// evicted first. A new subscriber gets the message of its topic, or the
+/// messages of all matching topics for a wildcard filter.
+///
+/// With a storage backend, the changes of the store are written in its
+/// order under the store lock, Retain::restore() loads the messages when
+/// the broker starts.
+use bytes::Bytes;
 use hashbrown::HashMap;
 use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::{
+    annotation::Annotations,
+    broker_context::BrokerContext,
+    config::PreDefinedTopics,
+    filter::{
+        get_topic_name_with_topic_id, match_topic, try_insert_topic_name,
+    },
     flags::QoSConst,
+    storage_backend::{Persistence, StoredRetain},
+    topic_refs::{TopicRef, TopicRefs},
     MsgIdType,
     // eformat,
     // function,
     TopicIdType,
 };
 
-lazy_static! {
-    pub static ref RETAIN_MAP: Mutex<HashMap<TopicIdType, Retain>> =
-        Mutex::new(HashMap::new());
+/// Annotation key of the TTL in seconds.
+pub const RETAIN_TTL_ANNOTATION: &str = "retain-ttl";
+
+#[derive(Debug, Default)]
+struct RetainStore {
+    map: HashMap<TopicIdType, Retain>,
+    /// Sum of the payload lengths.
+    bytes: usize,
+    /// LRU clock.
+    tick: u64,
+    /// 0 is unlimited.
+    max_messages: usize,
+    /// 0 is unlimited.
+    max_bytes: usize,
+    default_ttl: Option<Duration>,
+}
+
+impl RetainStore {
+    fn insert(&mut self, retain: Retain, now: Instant) {
+        let topic = retain.topic.clone();
+        let previous = self.take(retain.topic_id);
+        match self.insert_entry(retain, now) {
+            Some(stored) => Persistence::write(|backend| {
+                backend.put_retained(&topic, &stored)
+            }),
+            None => {
+                if let Some(previous) = previous {
+                    previous.unpersist();
+                }
+            }
+        }
+        self.evict(now);
+    }
+    /// Insert without a write to the storage backend, returns the record
+    /// to write, None if the message isn't stored or has no topic name.
+    fn insert_entry(
+        &mut self,
+        mut retain: Retain,
+        now: Instant,
+    ) -> Option<StoredRetain> {
+        let topic_id = retain.topic_id;
+        if retain.payload.is_empty()
+            || (self.max_bytes > 0 && retain.payload.len() > self.max_bytes)
+        {
+            return None;
+        }
+        retain.expires_at = Retain::ttl(&retain.annotations, self.default_ttl)
+            .map(|ttl| now + ttl);
+        let stored = if retain.topic.is_empty() {
+            None
+        } else {
+            Some(retain.to_stored(now))
+        };
+        self.tick += 1;
+        retain.last_used = self.tick;
+        self.bytes += retain.payload.len();
+        self.map.insert(topic_id, retain);
+        TopicRefs::acquire(topic_id, TopicRef::Retained);
+        stored
+    }
+    fn get(&mut self, topic_id: TopicIdType, now: Instant) -> Option<Retain> {
+        if self.map.get(&topic_id)?.is_expired(now) {
+            self.remove(topic_id);
+            return None;
+        }
+        self.tick += 1;
+        let tick = self.tick;
+        let retain = self.map.get_mut(&topic_id)?;
+        retain.last_used = tick;
+        Some(retain.clone())
+    }
+    /// Mark the messages of topic_ids as used, they are evicted last.
+    fn touch(&mut self, topic_ids: &[TopicIdType]) {
+        self.tick += 1;
+        let tick = self.tick;
+        for topic_id in topic_ids {
+            if let Some(retain) = self.map.get_mut(topic_id) {
+                retain.last_used = tick;
+            }
+        }
+    }
+    fn remove(&mut self, topic_id: TopicIdType) -> Option<Retain> {
+        let retain = self.take(topic_id)?;
+        retain.unpersist();
+        Some(retain)
+    }
+    /// Remove without a write to the storage backend.
+    fn take(&mut self, topic_id: TopicIdType) -> Option<Retain> {
+        let retain = self.map.remove(&topic_id)?;
+        self.bytes -= retain.payload.len();
+        TopicRefs::release(topic_id, TopicRef::Retained);
+        Some(retain)
+    }
+    fn is_full(&self) -> bool {
+        (self.max_messages > 0 && self.map.len() > self.max_messages)
+            || (self.max_bytes > 0 && self.bytes > self.max_bytes)
+    }
+    /// Remove the expired messages, then the least recently used ones.
+    fn evict(&mut self, now: Instant) {
+        if !self.is_full() {
+            return;
+        }
+        let expired: Vec<TopicIdType> = self
+            .map
+            .values()
+            .filter(|retain| retain.is_expired(now))
+            .map(|retain| retain.topic_id)
+            .collect();
+        for topic_id in expired {
+            self.remove(topic_id);
+        }
+        while self.is_full() {
+            let lru = self
+                .map
+                .values()
+                .min_by_key(|retain| retain.last_used)
+                .map(|retain| retain.topic_id);
+            match lru {
+                Some(topic_id) => {
+                    self.remove(topic_id);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// Retained messages of a broker, see BrokerContext.
+#[derive(Default)]
+pub(crate) struct RetainState {
+    retain_store: Mutex<RetainStore>,
+}
+
+#[inline(always)]
+fn state() -> &'static RetainState {
+    &BrokerContext::current().retain
 }
 
 #[derive(Debug, Clone)]
@@ -20,7 +175,13 @@ pub struct Retain {
     pub qos: QoSConst,
     pub topic_id: TopicIdType,
     pub msg_id: MsgIdType,
-    pub payload: BytesMut,
+    pub payload: Bytes,
+    pub annotations: Annotations,
+    /// None never expires.
+    pub expires_at: Option<Instant>,
+    last_used: u64,
+    /// Key of the storage backend, empty if the message isn't written.
+    topic: String,
 }
 
 impl Retain {
@@ -28,38 +189,275 @@ impl Retain {
         qos: QoSConst,
         topic_id: TopicIdType,
         msg_id: MsgIdType,
-        payload: BytesMut,
+        payload: Bytes,
+        annotations: Annotations,
     ) -> Self {
         Self {
             qos,
             topic_id,
             msg_id,
             payload,
+            annotations,
+            expires_at: None,
+            last_used: 0,
+            topic: String::new(),
+        }
+    }
+    #[inline(always)]
+    fn is_expired(&self, now: Instant) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= now,
+            None => false,
+        }
+    }
+    fn to_stored(&self, now: Instant) -> StoredRetain {
+        let expires_at = self.expires_at.map(|expires_at| {
+            let remaining = expires_at.saturating_duration_since(now);
+            match (SystemTime::now() + remaining).duration_since(UNIX_EPOCH) {
+                Ok(since_epoch) => since_epoch.as_secs(),
+                Err(_) => 0,
+            }
+        });
+        StoredRetain {
+            qos: self.qos,
+            msg_id: self.msg_id,
+            payload: self.payload.to_vec(),
+            expires_at,
         }
     }
+    /// Delete the message from the storage backend.
+    fn unpersist(&self) {
+        if !self.topic.is_empty() {
+            Persistence::write(|backend| backend.remove_retained(&self.topic));
+        }
+    }
+    /// The TTL annotation, or the default TTL.
+    fn ttl(
+        annotations: &Annotations,
+        default_ttl: Option<Duration>,
+    ) -> Option<Duration> {
+        match annotations.get(RETAIN_TTL_ANNOTATION) {
+            Some(secs) => match secs.parse::<u64>() {
+                Ok(secs) => Some(Duration::from_secs(secs)),
+                Err(_) => default_ttl,
+            },
+            None => default_ttl,
+        }
+    }
+
+    /// Limit the number of messages and the sum of the payload lengths,
+    /// 0 is unlimited.
+    pub fn set_limits(max_messages: usize, max_bytes: usize) {
+        let mut store = state().retain_store.lock().unwrap();
+        store.max_messages = max_messages;
+        store.max_bytes = max_bytes;
+        store.evict(Instant::now());
+    }
+    /// TTL of the messages without the TTL annotation, None never expires.
+    pub fn set_default_ttl(ttl: Option<Duration>) {
+        state().retain_store.lock().unwrap().default_ttl = ttl;
+    }
+    /// Replace the retained message of the topic, an empty payload clears
+    /// it.
     pub fn insert(
         qos: QoSConst,
         topic_id: TopicIdType,
         msg_id: MsgIdType,
-        payload: BytesMut,
+        payload: Bytes,
+        annotations: Annotations,
     ) {
-        let mut retain_map = RETAIN_MAP.lock().unwrap();
-        // if the topic_id is already in the map, replace the old retain with the new one
-        // TODO check error
-        retain_map
-            .insert(topic_id, Retain::new(qos, topic_id, msg_id, payload));
-        dbg!(&retain_map);
+        let mut retain =
+            Retain::new(qos, topic_id, msg_id, payload, annotations);
+        if Persistence::backend().is_some() {
+            // Resolved before the store lock.
+            retain.topic = get_topic_name_with_topic_id(topic_id)
+                .or_else(|| PreDefinedTopics::name(topic_id))
+                .unwrap_or_default();
+        }
+        state()
+            .retain_store
+            .lock()
+            .unwrap()
+            .insert(retain, Instant::now());
     }
-    pub fn get(topic_id: TopicIdType) -> Option<Retain> {
-        let retain_map = RETAIN_MAP.lock().unwrap();
-        match retain_map.get(&topic_id) {
-            Some(retain) => Some(retain.clone()),
-            None => None,
+    /// Load the messages of the storage backend, the expired ones are
+    /// skipped. Returns the number of messages loaded.
+    pub fn restore() -> Result<usize, String> {
+        let backend = match Persistence::backend() {
+            Some(backend) => backend,
+            None => return Ok(0),
+        };
+        let now = Instant::now();
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut count = 0;
+        for (topic, stored) in backend.retained()? {
+            let mut annotations = Annotations::new();
+            if let Some(expires_at) = stored.expires_at {
+                if expires_at <= since_epoch {
+                    backend.remove_retained(&topic)?;
+                    continue;
+                }
+                let ttl = (expires_at - since_epoch).to_string();
+                annotations.insert(RETAIN_TTL_ANNOTATION, &ttl);
+            }
+            let topic_id = match PreDefinedTopics::id(&topic) {
+                Some(topic_id) => topic_id,
+                None => try_insert_topic_name(topic.clone())?,
+            };
+            let mut retain = Retain::new(
+                stored.qos,
+                topic_id,
+                stored.msg_id,
+                Bytes::from(stored.payload),
+                annotations,
+            );
+            retain.topic = topic;
+            let mut store = state().retain_store.lock().unwrap();
+            store.take(topic_id);
+            if store.insert_entry(retain, now).is_some() {
+                count += 1;
+            }
+            store.evict(now);
         }
+        Ok(count)
+    }
+    pub fn get(topic_id: TopicIdType) -> Option<Retain> {
+        state()
+            .retain_store
+            .lock()
+            .unwrap()
+            .get(topic_id, Instant::now())
+    }
+    pub fn remove(topic_id: TopicIdType) -> bool {
+        state()
+            .retain_store
+            .lock()
+            .unwrap()
+            .remove(topic_id)
+            .is_some()
+    }
+    /// The retained messages of the topics matching the filter, with the
+    /// topic names. Only the matching messages are marked as used, a
+    /// wildcard SUBSCRIBE doesn't keep the others from the LRU eviction.
+    pub fn matching(filter: &str) -> Vec<(String, Retain)> {
+        let now = Instant::now();
+        let retains: Vec<Retain> = state()
+            .retain_store
+            .lock()
+            .unwrap()
+            .map
+            .values()
+            .filter(|retain| !retain.is_expired(now))
+            .cloned()
+            .collect();
+        // The topic names are looked up without the store lock.
+        let matching: Vec<(String, Retain)> = retains
+            .into_iter()
+            .filter_map(|retain| {
+                let topic = get_topic_name_with_topic_id(retain.topic_id)
+                    .or_else(|| PreDefinedTopics::name(retain.topic_id))?;
+                if match_topic(&topic, filter) {
+                    Some((topic, retain))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let topic_ids: Vec<TopicIdType> =
+            matching.iter().map(|(_, retain)| retain.topic_id).collect();
+        state().retain_store.lock().unwrap().touch(&topic_ids);
+        matching
+    }
+    pub fn len() -> usize {
+        state().retain_store.lock().unwrap().map.len()
     }
 }
 #[cfg(test)]
 mod test {
+    #[test]
+    fn test_retain_store() {
+        use super::*;
+        use crate::filter::try_insert_topic_name;
+        use crate::flags::QOS_LEVEL_1;
+        let no_ttl = Annotations::new();
+        let mut short_ttl = Annotations::new();
+        short_ttl.insert(RETAIN_TTL_ANNOTATION, "0");
+        let id1 = try_insert_topic_name("retain/a/1".to_string()).unwrap();
+        let id2 = try_insert_topic_name("retain/b/2".to_string()).unwrap();
+        let id3 = try_insert_topic_name("retain/a/3".to_string()).unwrap();
+        let payload = Bytes::from_static(b"hello");
+        Retain::insert(QOS_LEVEL_1, id1, 1, payload.clone(), no_ttl.clone());
+        Retain::insert(QOS_LEVEL_1, id2, 2, payload.clone(), no_ttl.clone());
+        assert_eq!(Retain::get(id1).unwrap().msg_id, 1);
+        // Replaced, then cleared by an empty payload.
+        Retain::insert(QOS_LEVEL_1, id2, 3, payload.clone(), no_ttl.clone());
+        assert_eq!(Retain::get(id2).unwrap().msg_id, 3);
+        Retain::insert(QOS_LEVEL_1, id2, 4, Bytes::new(), no_ttl.clone());
+        assert!(Retain::get(id2).is_none());
+        // Expired.
+        Retain::insert(QOS_LEVEL_1, id3, 5, payload.clone(), short_ttl);
+        assert!(Retain::get(id3).is_none());
+        Retain::insert(QOS_LEVEL_1, id2, 6, payload.clone(), no_ttl.clone());
+        Retain::insert(QOS_LEVEL_1, id3, 7, payload.clone(), no_ttl.clone());
+        let last_used = |topic_id| {
+            state().retain_store.lock().unwrap().map[&topic_id].last_used
+        };
+        let (used1, used2) = (last_used(id1), last_used(id2));
+        let mut topics: Vec<String> = Retain::matching("retain/a/+")
+            .into_iter()
+            .map(|(topic, _)| topic)
+            .collect();
+        topics.sort();
+        assert_eq!(topics, vec!["retain/a/1", "retain/a/3"]);
+        // Only the matching messages are marked as used.
+        assert!(last_used(id1) > used1);
+        assert_eq!(last_used(id2), used2);
+        assert!(Retain::remove(id1));
+        assert!(Retain::remove(id2));
+        assert!(Retain::remove(id3));
+        assert!(!Retain::remove(id1));
+        // LRU eviction in a store of 2 messages or 10 bytes.
+        let mut store = RetainStore::default();
+        store.max_messages = 2;
+        store.max_bytes = 10;
+        let now = Instant::now();
+        for topic_id in 1..=2 {
+            store.insert(
+                Retain::new(
+                    QOS_LEVEL_1,
+                    topic_id,
+                    0,
+                    payload.clone(),
+                    no_ttl.clone(),
+                ),
+                now,
+            );
+        }
+        assert!(store.get(1, now).is_some());
+        store.insert(
+            Retain::new(QOS_LEVEL_1, 3, 0, payload.clone(), no_ttl.clone()),
+            now,
+        );
+        assert!(store.get(2, now).is_none());
+        assert!(store.get(1, now).is_some());
+        assert_eq!(store.bytes, 10);
+        // Larger than the cap, not stored.
+        store.insert(
+            Retain::new(
+                QOS_LEVEL_1,
+                4,
+                0,
+                Bytes::from_static(&[0u8; 11]),
+                no_ttl,
+            ),
+            now,
+        );
+        assert!(store.get(4, now).is_none());
+        assert_eq!(store.map.len(), 2);
+    }
     /*
         #[test]
         fn test_retain() {
@@ -1,102 +1,134 @@
-use crate::{broker_lib::MqttSnClient, connection::*, eformat, function};
-use bytes::BytesMut;
+use crate::{
+    broker_context::BrokerContext,
+    broker_lib::MqttSnClient,
+    clock::Clock,
+    connection::*,
+    eformat,
+    events::DeliveryStatus,
+    function,
+    in_flight::InFlight,
+    metrics::{Counter, Metrics},
+    msg_span::MsgSpan,
+    msg_trace::{MsgTrace, TraceStage},
+    ordered_delivery::OrderedDelivery,
+    register_on_demand::RegisterOnDemand,
+    timer_wheel::{TimerWheel, TimerWheelConfig},
+    will_setup::WillSetup,
+    MSG_TYPE_PUBACK, MSG_TYPE_PUBCOMP, MSG_TYPE_PUBREC, MSG_TYPE_PUBREL,
+    MSG_TYPE_REGACK, MSG_TYPE_WILL_MSG, MSG_TYPE_WILL_TOPIC,
+};
+use bytes::Bytes;
 // use core::fmt::Debug;
 use core::hash::Hash;
 use custom_debug::Debug;
 use hashbrown::HashMap;
 use log::*;
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 use trace_var::trace_var;
 
-/// RetransmitHeader is stored in:
-/// (1) HashMap for cancellation from an ACK
-/// (2) timing wheel slots for timeouts.
-/// When the wheel reads a slot, it iterates all entries in the vector.
-/// Using the RetransmitHeader to get/remove the RetransmitData
-/// in the HashMap.
-/// If the new duration is greater that the maximum timeout period
-/// the HashMap entry will be removed.
-/// To cancel a scheduled event, remove the HashMap entry with the
-/// RetransmitHeader. Don't need to remove the entry in the slot
-/// because the slot entry lookup ignores missing HashMap entries.
+/// Opaque token of a retransmit timer, the key of the timer in the
+/// TimerWheel. schedule_timer() returns it and stores it with the in-flight
+/// messages of the connection, by the msg_type of the expected ACK and the
+/// msg_id. The ACK cancels the timer with its msg_type and msg_id, the
+/// topic id of the ACK isn't part of the key.
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
+pub struct RetransToken(u64);
+
+/// The message waiting for an ACK.
+/// When the timer expires, the message is sent again and the timer is
+/// scheduled with twice the duration, until the duration reaches
+/// RETRANSMIT_MAX_TIMEOUT.
 #[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
 struct RetransmitHeader {
     pub addr: SocketAddr,
     #[debug(format = "0x{:x}")]
     pub msg_type: u8,
-    pub topic_id: u16, // for pub and sub, default 0
+    pub topic_id: u16, // for REGISTER, default 0
     pub msg_id: u16,   // for pub and sub, default 0
 }
 
 #[derive(Debug, Clone)]
 struct RetransmitData {
-    pub bytes: BytesMut, // TODO use Bytes instead.
+    pub header: RetransmitHeader,
+    pub bytes: Bytes, // no copy on clone.
+    /// Duration of the timer in ticks.
+    pub duration: u64,
 }
 
-#[derive(Debug, Clone)]
-struct Slot {
-    pub entries: Arc<Mutex<Vec<(RetransmitHeader, u16)>>>,
+/// The timers and the tokens of the in-flight messages of each connection,
+/// under one lock.
+struct RetransTimers {
+    time_wheel: TimerWheel<RetransToken, RetransmitData>,
+    /// (msg_type, msg_id) of the expected ACK to the token of the timer.
+    in_flight: HashMap<SocketAddr, HashMap<(u8, u16), RetransToken>>,
+    next_token: u64,
 }
 
-impl Slot {
-    pub fn new() -> Self {
-        Slot {
-            entries: Arc::new(Mutex::new(Vec::new())),
+impl RetransTimers {
+    /// Remove the token of the in-flight message.
+    fn forget(&mut self, header: &RetransmitHeader) {
+        if let Some(tokens) = self.in_flight.get_mut(&header.addr) {
+            tokens.remove(&(header.msg_type, header.msg_id));
+            if tokens.is_empty() {
+                self.in_flight.remove(&header.addr);
+            }
         }
     }
 }
 
-static SLEEP_DURATION: usize = 100;
-static MAX_SLOT: usize = (1000 / SLEEP_DURATION) * 64 * 2;
+/// A message isn't retransmitted after this duration.
+pub const RETRANSMIT_MAX_TIMEOUT: Duration = Duration::from_secs(128);
 
-// TODO use lazy_static for easy access from any code without
-// attaching to a structure.
-lazy_static! {
-    static ref CURRENT_COUNTER: AtomicU64 = AtomicU64::new(0);
-    static ref SLOT_VEC: Mutex<Vec<Slot>> =
-        Mutex::new(Vec::with_capacity(MAX_SLOT));
-    static ref TIME_WHEEL_MAP: Mutex<HashMap<RetransmitHeader, RetransmitData>> =
-        Mutex::new(HashMap::new());
+/// Retransmit time wheel of a broker, see BrokerContext.
+pub(crate) struct RetransState {
+    timers: Mutex<RetransTimers>,
 }
 
-// TODO only for retransmit timing wheel.
-// The initial duration is set to TIME_WHEEL_INIT_DURATION, but can be
-// changed to reflect the network the client is on, (LAN or WAN),
-// or the latency pattern.
-
-// clients use 100 milli seconds
-// brokers use 10 milli seconds
-/// static TIME_WHEEL_SLEEP_DURATION:usize = 100; // in milli seconds
-// For 64 seconds at 100 ms sleeping interval, we need 10 * 64 slots, (1000/100 = 10)
-// For 64 seconds at 10 ms sleeping interval, we need 100 * 64 slots, (1000/10 = 100)
+impl Default for RetransState {
+    fn default() -> Self {
+        RetransState {
+            timers: Mutex::new(RetransTimers {
+                time_wheel: TimerWheel::new(TimerWheelConfig::default()),
+                in_flight: HashMap::new(),
+                next_token: 0,
+            }),
+        }
+    }
+}
 
-// The maximum timeout duration is 64 seconds
-// The last one might be over 64, rounding up.
-/// static TIME_WHEEL_MAX_SLOTS:usize = (1000 / TIME_WHEEL_SLEEP_DURATION) * 64 * 2;
-// Initial timeout duration is 300 ms
-// static TIME_WHEEL_DEFAULT_DURATION_MS: usize = 300;
+#[inline(always)]
+fn state() -> &'static RetransState {
+    &BrokerContext::current().retransmit
+}
 
-/// Timing wheel for keep alive.
-/// The wheel is divided into MAX_SLOT slots.
-/// Each slot is a vector of SocketAddr.
-/// The data is stored in a HashMap indexed by the SocketAddr.
+/// Timing wheel for retransmits.
+/// The timers of the TimerWheel are indexed by their RetransToken.
 pub struct RetransTimeWheel {}
 
 impl RetransTimeWheel {
+    /// Set the default tick and precision.
     pub fn init() {
-        let mut slot_vec = SLOT_VEC.lock().unwrap();
-        for _ in 0..MAX_SLOT {
-            slot_vec.push(Slot::new());
-        }
+        RetransTimeWheel::init_with(TimerWheelConfig::default());
+    }
+    /// Set the tick and the precision of the wheel, call before run().
+    pub fn init_with(config: TimerWheelConfig) {
+        state().timers.lock().unwrap().time_wheel.set_config(config);
+    }
+    /// Replace the clock of the wheel, e.g. a MockClock in a test.
+    pub fn set_clock(clock: Arc<dyn Clock>) {
+        state().timers.lock().unwrap().time_wheel.set_clock(clock);
     }
 
     // The initial duration is set to TIME_WHEEL_INIT_DURATION, but can be
     // changed to reflect the network the client is on, (LAN or WAN),
     // or the latency pattern.
+    /// Schedule the retransmit of bytes after duration seconds, until the
+    /// ACK of msg_type with the msg_id. The timer of the same in-flight
+    /// message is replaced. The topic_id is for the REGISTER, the pending
+    /// messages are aborted without REGACK, 0 otherwise.
     #[inline(always)]
     pub fn schedule_timer(
         addr: SocketAddr,
@@ -104,82 +136,144 @@ impl RetransTimeWheel {
         topic_id: u16,
         msg_id: u16,
         duration: u16,
-        bytes: BytesMut,
-    ) -> Result<(), String> {
-        // store the retrans_hdr in a slot of the timing wheel
-        // TODO XXX change value 10 to a constant
+        bytes: impl Into<Bytes>,
+    ) -> Result<RetransToken, String> {
         let retrans_hdr = RetransmitHeader {
             addr,
             msg_type,
             topic_id,
             msg_id,
         };
-        let val = RetransmitData { bytes };
-        let duration = duration * 10;
-        let cur_counter = CURRENT_COUNTER.load(Ordering::Relaxed) as usize;
-        let index = (cur_counter + duration as usize) % MAX_SLOT;
-        match TIME_WHEEL_MAP.try_lock() {
-            Ok(mut map) => {
-                map.insert(retrans_hdr, val);
-            }
-            Err(why) => {
-                return Err(eformat!(retrans_hdr, why.to_string()));
-            }
-        }
-        match SLOT_VEC.try_lock() {
-            Ok(mut slot_vec) => {
-                let slot = &mut slot_vec[index];
-                match slot.entries.try_lock() {
-                    Ok(mut entries) => {
-                        entries.push((retrans_hdr, duration));
-                    }
-                    Err(why) => {
-                        // unwind: remove the inserted retrans_hdr from the map
-                        if let None =
-                            TIME_WHEEL_MAP.lock().unwrap().remove(&retrans_hdr)
-                        {
-                            return Err(eformat!(retrans_hdr, "key not found"));
-                        }
-                        return Err(eformat!(retrans_hdr, why.to_string()));
-                    }
+        tracing::trace!(
+            parent: &MsgSpan::msg(addr, msg_type, msg_id),
+            duration,
+            "retransmit timer scheduled"
+        );
+        match state().timers.try_lock() {
+            Ok(mut timers) => {
+                let token = RetransToken(timers.next_token);
+                timers.next_token += 1;
+                let duration = timers
+                    .time_wheel
+                    .ticks(Duration::from_secs(duration as u64));
+                let val = RetransmitData {
+                    header: retrans_hdr,
+                    bytes: bytes.into(),
+                    duration,
+                };
+                timers.time_wheel.insert(token, duration, val);
+                let replaced = timers
+                    .in_flight
+                    .entry(addr)
+                    .or_insert_with(HashMap::new)
+                    .insert((msg_type, msg_id), token);
+                if let Some(old_token) = replaced {
+                    timers.time_wheel.cancel(&old_token);
                 }
+                Ok(token)
             }
-            Err(why) => {
-                // unwind: remove the inserted retrans_hdr from the map
-                if let None =
-                    TIME_WHEEL_MAP.lock().unwrap().remove(&retrans_hdr)
-                {
-                    return Err(eformat!("key not found"));
-                }
-                return Err(eformat!(why.to_string()));
-            }
+            Err(why) => Err(eformat!(retrans_hdr, why.to_string())),
         }
-        return Ok(());
     }
-    /// Reschedule a keep alive event when it received a message from the sender.
-    /// Modify the latest_counter in the TIME_WHEEL_MAP to the current counter.
+    /// Cancel the retransmit when the ACK of msg_type with the msg_id is
+    /// received.
     #[inline(always)]
     #[trace_var(index, slot, hash, vec)]
     pub fn cancel_timer(
         addr: SocketAddr,
         msg_type: u8,
-        topic_id: u16,
         msg_id: u16,
     ) -> Result<(), String> {
-        let retrans_hdr = RetransmitHeader {
-            addr,
-            msg_type,
-            topic_id,
-            msg_id,
-        };
-        match TIME_WHEEL_MAP.try_lock() {
-            Ok(mut map) => {
-                if let None = map.remove(&retrans_hdr) {
-                    return Err(eformat!(retrans_hdr, "not found."));
-                }
+        tracing::trace!(
+            parent: &MsgSpan::msg(addr, msg_type, msg_id),
+            "retransmit timer canceled"
+        );
+        match state().timers.try_lock() {
+            Ok(mut timers) => RetransTimeWheel::cancel_ack_locked(
+                &mut timers,
+                addr,
+                msg_type,
+                msg_id,
+            ),
+            Err(why) => Err(eformat!(addr, msg_type, msg_id, why.to_string())),
+        }
+    }
+    /// cancel_timer() for several ACKs under one lock, e.g. the PUBACKs of
+    /// a QoS 1 fan-out. Returns a result per ACK.
+    pub fn cancel_many(
+        acks: &[(SocketAddr, u8, u16)],
+    ) -> Vec<Result<(), String>> {
+        for (addr, msg_type, msg_id) in acks.iter() {
+            tracing::trace!(
+                parent: &MsgSpan::msg(*addr, *msg_type, *msg_id),
+                "retransmit timer canceled"
+            );
+        }
+        let mut timers = state().timers.lock().unwrap();
+        acks.iter()
+            .map(|(addr, msg_type, msg_id)| {
+                RetransTimeWheel::cancel_ack_locked(
+                    &mut timers,
+                    *addr,
+                    *msg_type,
+                    *msg_id,
+                )
+            })
+            .collect()
+    }
+    fn cancel_ack_locked(
+        timers: &mut RetransTimers,
+        addr: SocketAddr,
+        msg_type: u8,
+        msg_id: u16,
+    ) -> Result<(), String> {
+        let token = timers
+            .in_flight
+            .get(&addr)
+            .and_then(|tokens| tokens.get(&(msg_type, msg_id)))
+            .copied();
+        match token {
+            Some(token) => RetransTimeWheel::cancel_locked(timers, token),
+            None => Err(eformat!(addr, msg_type, msg_id, "not found.")),
+        }
+    }
+    /// Cancel the timer of the token returned by schedule_timer().
+    pub fn cancel(token: RetransToken) -> Result<(), String> {
+        let mut timers = state().timers.lock().unwrap();
+        RetransTimeWheel::cancel_locked(&mut timers, token)
+    }
+    fn cancel_locked(
+        timers: &mut RetransTimers,
+        token: RetransToken,
+    ) -> Result<(), String> {
+        match timers.time_wheel.cancel(&token) {
+            Some(retrans_data) => {
+                timers.forget(&retrans_data.header);
                 Ok(())
             }
-            Err(why) => Err(eformat!(retrans_hdr, why.to_string())),
+            None => Err(eformat!(token, "not found.")),
+        }
+    }
+    /// Cancel all the timers of addr, e.g. for a lost connection.
+    /// Returns the number of canceled timers.
+    pub fn cancel_all(addr: SocketAddr) -> usize {
+        let mut timers = state().timers.lock().unwrap();
+        let tokens = match timers.in_flight.remove(&addr) {
+            Some(tokens) => tokens,
+            None => return 0,
+        };
+        // The slot entries are ignored without the timers.
+        tokens
+            .values()
+            .filter(|token| timers.time_wheel.cancel(token).is_some())
+            .count()
+    }
+    /// True if a message to addr with the msg_id is waiting for an ACK.
+    pub fn in_flight(addr: SocketAddr, msg_id: u16) -> bool {
+        let timers = state().timers.lock().unwrap();
+        match timers.in_flight.get(&addr) {
+            Some(tokens) => tokens.keys().any(|(_, id)| *id == msg_id),
+            None => false,
         }
     }
 
@@ -187,86 +281,264 @@ impl RetransTimeWheel {
     /// with the current counter. If the latest_counter is less than the current counter,
     /// the address(key) is expired. Otherwise, put it back to a new slot.
     pub fn run(client: MqttSnClient) {
-        // When the keep_alive timing wheel entry is accessed,
-        // this code determines if the connection is expired.
-        // If the hash entry has been updated to a new counter,
-        // then reschedule the connection in the timing wheel.
-        //
         // TODO replace lock with try_lock
         let _retrans_expire_thread = thread::spawn(move || {
+            let _context = client.context.enter();
+            #[cfg(feature = "health")]
+            let _worker = crate::health::Health::start(
+                client.context,
+                crate::health::Worker::Retransmit,
+            );
             loop {
+                #[cfg(feature = "health")]
+                crate::health::Health::beat(crate::health::Worker::Retransmit);
                 // The sleep() has to be outside of the mutex lock block for
                 // the lock to be unlocked while the thread is sleeping.
-                thread::sleep(Duration::from_millis(SLEEP_DURATION as u64));
-                {
-                    let cur_counter: usize;
-                    cur_counter = CURRENT_COUNTER
-                        .fetch_add(1, Ordering::Relaxed)
-                        as usize;
-                    // dbg!(&cur_slot);
-                    // dbg!(cur_counter);
-                    let slot_vec = SLOT_VEC.lock().unwrap();
-                    let index = cur_counter % MAX_SLOT;
-                    let mut slot = slot_vec[index].entries.lock().unwrap();
-                    let mut map = TIME_WHEEL_MAP.lock().unwrap();
-                    // process the expired connections
-                    while let Some((retrans_hdr, mut duration)) = slot.pop() {
-                        match Connection::get_state(&retrans_hdr.addr) {
-                            Ok(state) => match state {
-                                StateEnum2::ACTIVE => (), // drop through
-                                _ => {
-                                    map.remove(&retrans_hdr);
-                                    info!("Retransmit Timer Cancel: incorrect state: {:?} {:?}",
-                                    state, retrans_hdr);
-                                }
-                            },
-                            Err(why) => {
-                                map.remove(&retrans_hdr);
-                                error!(
-                                    "Retransmit Timer Cancel: {} {:?}",
-                                    why, retrans_hdr
+                let tick = state().timers.lock().unwrap().time_wheel.tick();
+                thread::sleep(tick);
+                RetransTimeWheel::poll(&client);
+            }
+        });
+    }
+    /// Process the ticks elapsed on the clock of the wheel, run() calls it
+    /// every tick.
+    pub fn poll(client: &MqttSnClient) {
+        // Dropped PUBLISH messages, released from the in-flight
+        // window after the locks are released, and timed out will
+        // requests.
+        let mut dropped = Vec::new();
+        {
+            let mut timers = state().timers.lock().unwrap();
+            let max_duration = timers.time_wheel.ticks(RETRANSMIT_MAX_TIMEOUT);
+            while timers.time_wheel.is_due() {
+                // process the expired timers, removed from the wheel
+                for (token, mut retrans_data) in timers.time_wheel.advance() {
+                    let retrans_hdr = retrans_data.header;
+                    match Connection::get_state(&retrans_hdr.addr) {
+                        Ok(state) => match state {
+                            StateEnum2::ACTIVE => (), // drop through
+                            _ => {
+                                timers.forget(&retrans_hdr);
+                                dropped.push(retrans_hdr);
+                                info!(
+                                    "Retransmit Timer Cancel: {:?} {:?}",
+                                    state, retrans_hdr
                                 );
+                                continue;
                             }
+                        },
+                        Err(why) => {
+                            timers.forget(&retrans_hdr);
+                            dropped.push(retrans_hdr);
+                            error!(
+                                "Retransmit Timer Cancel: {} {:?}",
+                                why, retrans_hdr
+                            );
+                            continue;
                         }
-                        dbg!(index);
-                        duration *= 2;
-                        dbg!((duration, MAX_SLOT));
-                        if duration < (MAX_SLOT as u16) {
-                            // not expired, reschedule to new slot, don't remove hash entry
-                            if let Some(retrans_data) = map.get(&retrans_hdr) {
-                                let mut new_index = (cur_counter
-                                    + duration as usize)
-                                    % MAX_SLOT;
-                                dbg!((new_index, index));
-                                if new_index == index {
-                                    // Can't lock the same slot twice
-                                    // Even without lock, push() to the same slot will be popped
-                                    // in the while loop, so it's an infinite loop.
-                                    // Use the next slot instead.
-                                    new_index = (index + 1) % MAX_SLOT;
-                                }
-                                dbg!((new_index, index));
-                                let mut new_slot =
-                                    slot_vec[new_index].entries.lock().unwrap();
-                                new_slot.push((retrans_hdr, duration));
-                                // Retransmit the message to the receiver.
-                                if let Err(err) = client.egress_tx.send((
-                                    retrans_hdr.addr,
-                                    retrans_data.bytes.clone(),
-                                )) {
-                                    error!("{:?} {:?}", err, retrans_hdr);
-                                    dbg!((new_index, index));
-                                }
-                                dbg!(retrans_hdr);
-                            }
-                        } else {
-                            // The connection is expired, remove the hash entry
-                            map.remove(&retrans_hdr);
-                            info!("Retransmit Timeout: {:?}", retrans_hdr);
+                    }
+                    retrans_data.duration *= 2;
+                    let duration = retrans_data.duration;
+                    dbg!((duration, max_duration));
+                    if duration < max_duration {
+                        // not expired, reschedule with twice the duration
+                        let bytes = retrans_data.bytes.clone();
+                        timers.time_wheel.insert(token, duration, retrans_data);
+                        tracing::debug!(
+                            parent: &MsgSpan::msg(
+                                retrans_hdr.addr,
+                                retrans_hdr.msg_type,
+                                retrans_hdr.msg_id,
+                            ),
+                            duration,
+                            "retransmit"
+                        );
+                        Metrics::inc(Counter::Retransmits);
+                        MsgTrace::record(
+                            retrans_hdr.addr,
+                            retrans_hdr.msg_id,
+                            TraceStage::Retransmit(retrans_hdr.msg_type),
+                        );
+                        // Retransmit the message to the receiver.
+                        if let Err(err) = client
+                            .egress_batch_tx
+                            .send((vec![retrans_hdr.addr], bytes))
+                        {
+                            error!("{:?} {:?}", err, retrans_hdr);
                         }
+                        dbg!(retrans_hdr);
+                    } else {
+                        // The message is expired, the timer was removed
+                        timers.forget(&retrans_hdr);
+                        dropped.push(retrans_hdr);
+                        Metrics::inc(Counter::RetransmitTimeouts);
+                        MsgTrace::record(
+                            retrans_hdr.addr,
+                            retrans_hdr.msg_id,
+                            TraceStage::Timeout(retrans_hdr.msg_type),
+                        );
+                        MsgTrace::finish(retrans_hdr.addr, retrans_hdr.msg_id);
+                        info!("Retransmit Timeout: {:?}", retrans_hdr);
                     }
                 }
             }
-        });
+        }
+        for retrans_hdr in dropped {
+            tracing::warn!(
+                parent: &MsgSpan::msg(
+                    retrans_hdr.addr,
+                    retrans_hdr.msg_type,
+                    retrans_hdr.msg_id,
+                ),
+                "retransmit dropped"
+            );
+            match retrans_hdr.msg_type {
+                MSG_TYPE_PUBACK | MSG_TYPE_PUBREC | MSG_TYPE_PUBCOMP => {
+                    InFlight::release(
+                        retrans_hdr.addr,
+                        retrans_hdr.msg_id,
+                        client,
+                    );
+                    client.events.on_delivery(
+                        retrans_hdr.addr,
+                        retrans_hdr.msg_id,
+                        DeliveryStatus::Expired,
+                    );
+                }
+                // No PUBREL, send the messages queued behind the PUBLISH.
+                MSG_TYPE_PUBREL => {
+                    OrderedDelivery::abort(
+                        retrans_hdr.addr,
+                        retrans_hdr.msg_id,
+                        client,
+                    );
+                }
+                MSG_TYPE_REGACK => {
+                    RegisterOnDemand::abort(
+                        retrans_hdr.addr,
+                        retrans_hdr.topic_id,
+                    );
+                }
+                MSG_TYPE_WILL_TOPIC | MSG_TYPE_WILL_MSG => {
+                    WillSetup::abort(retrans_hdr.addr, retrans_hdr.msg_type);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_retransmit_tokens() {
+        use super::*;
+        let _context = BrokerContext::new().enter();
+        let addr = "127.0.0.1:1884".parse::<SocketAddr>().unwrap();
+        let addr2 = "127.0.0.1:1885".parse::<SocketAddr>().unwrap();
+        // QoS 1 PUBLISH with their topic id, a QoS 2 PUBLISH and another
+        // peer with the same msg_ids.
+        let mut tokens = Vec::new();
+        for (topic_id, msg_id) in [(7, 1), (8, 2), (9, 3)].iter() {
+            let token = RetransTimeWheel::schedule_timer(
+                addr,
+                MSG_TYPE_PUBACK,
+                *topic_id,
+                *msg_id,
+                1,
+                "x",
+            )
+            .unwrap();
+            tokens.push(token);
+        }
+        RetransTimeWheel::schedule_timer(addr, MSG_TYPE_PUBREC, 0, 2, 1, "x")
+            .unwrap();
+        RetransTimeWheel::schedule_timer(addr2, MSG_TYPE_PUBACK, 0, 2, 1, "x")
+            .unwrap();
+        // The PUBACK cancels its msg_id only, whatever its topic id.
+        RetransTimeWheel::cancel_timer(addr, MSG_TYPE_PUBACK, 2).unwrap();
+        assert!(
+            RetransTimeWheel::cancel_timer(addr, MSG_TYPE_PUBACK, 2).is_err()
+        );
+        assert!(RetransTimeWheel::in_flight(addr, 2));
+        assert!(RetransTimeWheel::in_flight(addr2, 2));
+        // The timer of a message scheduled again is replaced.
+        let token = RetransTimeWheel::schedule_timer(
+            addr,
+            MSG_TYPE_PUBACK,
+            9,
+            3,
+            1,
+            "x",
+        )
+        .unwrap();
+        assert_ne!(token, tokens[2]);
+        assert!(RetransTimeWheel::cancel(tokens[2]).is_err());
+        RetransTimeWheel::cancel(token).unwrap();
+        assert!(!RetransTimeWheel::in_flight(addr, 3));
+        RetransTimeWheel::cancel(tokens[0]).unwrap();
+        assert!(
+            RetransTimeWheel::cancel_timer(addr, MSG_TYPE_PUBACK, 1).is_err()
+        );
+        assert!(!RetransTimeWheel::in_flight(addr, 1));
+        // The PUBREC of msg_id 2 is left.
+        assert_eq!(RetransTimeWheel::cancel_all(addr), 1);
+        assert_eq!(RetransTimeWheel::cancel_all(addr2), 1);
+        assert!(!RetransTimeWheel::in_flight(addr, 2));
+        assert_eq!(RetransTimeWheel::cancel_all(addr), 0);
+        // A burst of PUBACKs, one of them unknown.
+        for msg_id in 1..=3 {
+            RetransTimeWheel::schedule_timer(
+                addr,
+                MSG_TYPE_PUBACK,
+                0,
+                msg_id,
+                1,
+                "x",
+            )
+            .unwrap();
+        }
+        let acks: Vec<(SocketAddr, u8, u16)> = (1..=4)
+            .map(|msg_id| (addr, MSG_TYPE_PUBACK, msg_id))
+            .collect();
+        let results = RetransTimeWheel::cancel_many(&acks);
+        assert!(results[..3].iter().all(|result| result.is_ok()));
+        assert!(results[3].is_err());
+        assert_eq!(RetransTimeWheel::cancel_all(addr), 0);
+    }
+
+    #[test]
+    fn test_retransmit_clock() {
+        use super::*;
+        use crate::clock::MockClock;
+        use crate::flags::CLEAN_SESSION_TRUE;
+        let context = BrokerContext::new();
+        let _context = context.enter();
+        let client = MqttSnClient::new().with_context(context);
+        let clock = Arc::new(MockClock::new());
+        RetransTimeWheel::set_clock(clock.clone());
+        let addr = "127.0.0.1:1886".parse::<SocketAddr>().unwrap();
+        Connection::try_insert(
+            addr,
+            CLEAN_SESSION_TRUE,
+            1,
+            60,
+            Bytes::from("retransClock"),
+        )
+        .unwrap();
+        RetransTimeWheel::schedule_timer(addr, MSG_TYPE_PUBACK, 0, 1, 1, "x")
+            .unwrap();
+        clock.advance(Duration::from_millis(900));
+        RetransTimeWheel::poll(&client);
+        assert!(client.egress_batch_rx.try_recv().is_err());
+        clock.advance(Duration::from_millis(200));
+        RetransTimeWheel::poll(&client);
+        let (addrs, bytes) = client.egress_batch_rx.try_recv().unwrap();
+        assert_eq!((addrs, &bytes[..]), (vec![addr], &b"x"[..]));
+        // Twice the duration each time, dropped at RETRANSMIT_MAX_TIMEOUT.
+        clock.advance(RETRANSMIT_MAX_TIMEOUT);
+        RetransTimeWheel::poll(&client);
+        assert_eq!(client.egress_batch_rx.try_iter().count(), 5);
+        assert!(!RetransTimeWheel::in_flight(addr, 1));
     }
 }
@@ -0,0 +1,200 @@
+/// PUBLISH messages for library consumers of the broker.
+///
+/// MqttSnClient::subscribe() registers a topic filter, the messages
+/// published to matching topics are sent to subscribe_rx as RichPublish,
+/// with the topic name, QoS, retain flag, publisher address and payload
+/// metadata resolved, so the consumer doesn't need the global filter maps.
+/// QoS 2 messages are forwarded when the PUBREL is received. received_at is
+/// the time the broker received the PUBLISH, forwarded_at the time of the
+/// fan-out to subscribe_rx, e.g. for the latency of a telemetry pipeline.
+/// The annotations of the hooks come with the message, see Annotations.
+use bytes::Bytes;
+use log::*;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::{
+    annotation::Annotations,
+    broker_lib::MqttSnClient,
+    eformat,
+    filter::{get_topic_name_with_topic_id, match_topic, valid_filter},
+    flags::{flag_is_retain, flag_qos_level, QoSConst},
+    function,
+    publish::Publish,
+    topic_metadata::TopicMetadata,
+    TopicIdType,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RichPublish {
+    pub topic_id: TopicIdType,
+    /// None for pre-defined topic ids.
+    pub topic_name: Option<String>,
+    pub msg_id: u16,
+    pub qos: QoSConst,
+    pub retain: bool,
+    pub data: Bytes,
+    pub publisher: SocketAddr,
+    /// Content type and encoding of the data, see TopicMetadata.
+    pub metadata: Option<TopicMetadata>,
+    pub annotations: Annotations,
+    /// The PUBLISH was received by the broker.
+    pub received_at: SystemTime,
+    /// The message was sent to subscribe_rx.
+    pub forwarded_at: SystemTime,
+}
+
+lazy_static! {
+    static ref ENABLED: AtomicBool = AtomicBool::new(false);
+    static ref FILTERS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+}
+
+impl RichPublish {
+    pub fn new(
+        publish: &Publish,
+        publisher: SocketAddr,
+        received_at: SystemTime,
+        annotations: &Annotations,
+    ) -> Self {
+        let topic_id = *publish.topic_id();
+        let topic_name = get_topic_name_with_topic_id(topic_id);
+        let metadata = topic_name.as_deref().and_then(TopicMetadata::get);
+        RichPublish {
+            topic_id,
+            topic_name,
+            msg_id: *publish.msg_id(),
+            qos: flag_qos_level(*publish.flags()),
+            retain: flag_is_retain(*publish.flags()),
+            data: publish.data().clone(),
+            publisher,
+            metadata,
+            annotations: annotations.clone(),
+            received_at,
+            forwarded_at: SystemTime::now(),
+        }
+    }
+
+    /// Forward the messages matching the filter to subscribe_rx.
+    pub fn subscribe(filter: &str) -> Result<(), String> {
+        if !valid_filter(filter) {
+            return Err(eformat!(filter, "invalid filter"));
+        }
+        let mut filters = FILTERS.lock().unwrap();
+        if !filters.iter().any(|old| old == filter) {
+            filters.push(filter.to_string());
+        }
+        ENABLED.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+    /// Returns false if the filter was not subscribed.
+    pub fn unsubscribe(filter: &str) -> bool {
+        let mut filters = FILTERS.lock().unwrap();
+        let len = filters.len();
+        filters.retain(|old| old != filter);
+        ENABLED.store(!filters.is_empty(), Ordering::Relaxed);
+        filters.len() != len
+    }
+    #[inline(always)]
+    pub fn is_empty() -> bool {
+        !ENABLED.load(Ordering::Relaxed)
+    }
+
+    /// Send the message to subscribe_tx if the topic matches a filter.
+    #[inline(always)]
+    pub fn forward(
+        publish: &Publish,
+        publisher: SocketAddr,
+        received_at: SystemTime,
+        annotations: &Annotations,
+        client: &MqttSnClient,
+    ) {
+        #[cfg(feature = "connector")]
+        crate::connector::Connectors::forward(
+            get_topic_name_with_topic_id(*publish.topic_id()).as_deref(),
+            publish.data(),
+            publisher,
+            received_at,
+            annotations,
+        );
+        if RichPublish::is_empty() {
+            return;
+        }
+        let rich =
+            RichPublish::new(publish, publisher, received_at, annotations);
+        let topic = match &rich.topic_name {
+            Some(topic_name) => topic_name.clone(),
+            None => rich.topic_id.to_string(),
+        };
+        let matched = FILTERS
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|filter| filter == &topic || match_topic(&topic, filter));
+        if matched {
+            if let Err(why) = client.subscribe_tx.send(rich) {
+                error!("{}", eformat!(publisher, why));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_rich_publish() {
+        use super::*;
+        use crate::filter::try_insert_topic_name;
+        use crate::flags::{QOS_LEVEL_1, RETAIN_TRUE};
+        use bytes::BytesMut;
+
+        let client = MqttSnClient::new();
+        let publisher = "127.0.0.1:1500".parse::<SocketAddr>().unwrap();
+        let topic_id =
+            try_insert_topic_name("rich/test/temp".to_string()).unwrap();
+        let publish = Publish::new(
+            topic_id,
+            9,
+            QOS_LEVEL_1,
+            RETAIN_TRUE,
+            BytesMut::from(&b"21.5"[..]),
+        );
+        let metadata = TopicMetadata {
+            content_type: Some("text/plain".to_string()),
+            encoding: Some("utf-8".to_string()),
+        };
+        TopicMetadata::set("rich/test/+", metadata.clone()).unwrap();
+        RichPublish::subscribe("rich/test/#").unwrap();
+        let received_at = SystemTime::now();
+        let mut annotations = Annotations::new();
+        annotations.insert("tenant", "acme");
+        RichPublish::forward(
+            &publish,
+            publisher,
+            received_at,
+            &annotations,
+            &client,
+        );
+        let rich = client.subscribe_rx.try_recv().unwrap();
+        assert_eq!(rich.annotations, annotations);
+        assert_eq!(rich.received_at, received_at);
+        assert!(rich.forwarded_at >= received_at);
+        assert_eq!(rich.metadata, Some(metadata));
+        TopicMetadata::remove("rich/test/+");
+        assert_eq!(rich.topic_name, Some("rich/test/temp".to_string()));
+        assert_eq!(rich.qos, QOS_LEVEL_1);
+        assert!(rich.retain);
+        assert_eq!(rich.publisher, publisher);
+        assert_eq!(&rich.data[..], b"21.5");
+        assert!(RichPublish::unsubscribe("rich/test/#"));
+        RichPublish::forward(
+            &publish,
+            publisher,
+            received_at,
+            &annotations,
+            &client,
+        );
+        assert!(client.subscribe_rx.try_recv().is_err());
+    }
+}
@@ -12,10 +12,7 @@ The format of a SEARCHGW message is illustrated in Table 7:
 The broadcast radius is also indicated to the underlying network layer when MQTT-SN gives this message for
 transmission.
 */
-use crate::{
-    eformat, function, gw_info::GwInfo, multicast, MSG_LEN_SEARCH_GW,
-    MSG_TYPE_SEARCH_GW,
-};
+use crate::{codec, eformat, function, multicast, MSG_LEN_SEARCH_GW};
 use bytes::{BufMut, BytesMut};
 use custom_debug::Debug;
 use getset::{CopyGetters, Getters, MutGetters};
@@ -24,6 +21,8 @@ use std::net::SocketAddr;
 use std::str;
 
 pub const SEARCH_RADIUS_MAX: u8 = 2;
+/// Hop limit of the GWINFO to a SEARCHGW with radius 0, the whole network.
+pub const SEARCH_RADIUS_ALL_HOPS: u32 = 64;
 
 #[derive(
     Debug, Clone, Getters, /*Setters,*/ MutGetters, CopyGetters, Default,
@@ -35,20 +34,23 @@ pub struct SearchGw {
     pub msg_type: u8,
     pub radius: u8,
 }
+
+codec!(SearchGw, { radius: u8 });
+
 impl SearchGw {
     // for client to multicast
     pub fn run(socket_addr: SocketAddr, radius: u8, duration: u16) {
-        let mut bytes = BytesMut::with_capacity(MSG_LEN_SEARCH_GW as usize);
-        let buf: &[u8] = &[MSG_LEN_SEARCH_GW, MSG_TYPE_SEARCH_GW, radius];
-        bytes.put(buf);
-        dbg!(&buf);
+        let bytes = SearchGw::encode(radius);
+        dbg!(&bytes);
         multicast::broadcast_loop(bytes.freeze(), socket_addr, duration);
     }
+    /// Returns the radius of the SEARCHGW, the GWINFO is sent by
+    /// GwInfoResponder.
     pub fn recv(
         buf: &[u8],
         size: usize,
         socket_addr: &SocketAddr,
-    ) -> Result<(), String> {
+    ) -> Result<u8, String> {
         match SearchGw::try_read(buf, size) {
             Some((search_gw, size)) if size == MSG_LEN_SEARCH_GW as usize => {
                 info!(
@@ -61,13 +63,7 @@ impl SearchGw {
                         socket_addr, search_gw.radius, SEARCH_RADIUS_MAX
                     );
                 }
-                // TODO use configure gateway ip address/port.
-                if let Err(why) =
-                    GwInfo::send(1, "124.0.0.5:61000".to_string(), socket_addr)
-                {
-                    error!("{}", why);
-                }
-                Ok(())
+                Ok(search_gw.radius)
             }
             Some((_, size)) => Err(format!(
                 "{}: search gw: {} bytes received, but {} bytes expected",
@@ -76,4 +72,13 @@ impl SearchGw {
             None => Err(eformat!(socket_addr)),
         }
     }
+
+    /// IP hop limit of the GWINFO, the broadcast radius of the SEARCHGW.
+    #[inline(always)]
+    pub fn hop_limit(radius: u8) -> u32 {
+        match radius {
+            0 => SEARCH_RADIUS_ALL_HOPS,
+            radius => radius as u32,
+        }
+    }
 }
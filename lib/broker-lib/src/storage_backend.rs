@@ -0,0 +1,577 @@
+/// Durable copy of the retained messages, the wills and the sessions.
+///
+/// A StorageBackend keeps the state a client expects after a broker
+/// restart. MemoryBackend keeps it in the process, e.g. for a broker
+/// restarted by its embedder, SqliteBackend in a sqlite database with the
+/// storage-sqlite feature. The backend is chosen in the [storage] table of
+/// BrokerConfig and set with MqttSnClient::with_storage(), no backend is
+/// installed by default and nothing is written.
+///
+/// The retained messages are written when they change and keyed by topic
+/// name, the topic ids of the broker aren't stable across restarts. The
+/// will of a client is written when it changes and deleted when it's
+/// published. The session of a client without CleanSession, its
+/// subscriptions, is written when it disconnects or is lost. After a
+/// restart the retained messages are loaded when the broker starts, the
+/// will and the subscriptions of a client when it connects again without
+/// CleanSession. A CONNECT with CleanSession deletes them.
+///
+/// The cumulative $SYS counters are kept by name with put_counter(), see
+/// SysStats::restore().
+use hashbrown::HashMap;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::{
+    broker_context::BrokerContext, eformat, flags::QoSConst, function,
+    subscription_export::SubscriptionRecord, MsgIdType,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredRetain {
+    pub qos: QoSConst,
+    pub msg_id: MsgIdType,
+    pub payload: Vec<u8>,
+    /// Seconds since the UNIX epoch, None never expires.
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredWill {
+    pub topic: String,
+    pub message: Vec<u8>,
+    pub qos: QoSConst,
+    pub retain: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct StoredSession {
+    pub subscriptions: Vec<SubscriptionRecord>,
+}
+
+pub trait StorageBackend: Send + Sync {
+    /// Replace the retained message of the topic.
+    fn put_retained(
+        &self,
+        topic: &str,
+        retain: &StoredRetain,
+    ) -> Result<(), String>;
+    fn remove_retained(&self, topic: &str) -> Result<(), String>;
+    /// All the retained messages with their topic names.
+    fn retained(&self) -> Result<Vec<(String, StoredRetain)>, String>;
+    fn put_will(
+        &self,
+        client_id: &[u8],
+        will: &StoredWill,
+    ) -> Result<(), String>;
+    fn get_will(&self, client_id: &[u8]) -> Result<Option<StoredWill>, String>;
+    fn remove_will(&self, client_id: &[u8]) -> Result<(), String>;
+    fn put_session(
+        &self,
+        client_id: &[u8],
+        session: &StoredSession,
+    ) -> Result<(), String>;
+    fn get_session(
+        &self,
+        client_id: &[u8],
+    ) -> Result<Option<StoredSession>, String>;
+    fn remove_session(&self, client_id: &[u8]) -> Result<(), String>;
+    /// Replace the value of the counter.
+    fn put_counter(&self, name: &str, val: u64) -> Result<(), String>;
+    fn get_counter(&self, name: &str) -> Result<Option<u64>, String>;
+}
+
+/// Storage backend of a broker, see BrokerContext.
+#[derive(Default)]
+pub(crate) struct StorageBackendState {
+    backend: RwLock<Option<Arc<dyn StorageBackend>>>,
+}
+
+#[inline(always)]
+fn state() -> &'static StorageBackendState {
+    &BrokerContext::current().storage_backend
+}
+
+#[derive(Debug, Clone)]
+pub struct Persistence {}
+
+impl Persistence {
+    /// Use the backend in the current context, None stops the writes.
+    pub fn install(backend: Option<Arc<dyn StorageBackend>>) {
+        *state().backend.write().unwrap() = backend;
+    }
+    #[inline(always)]
+    pub fn backend() -> Option<Arc<dyn StorageBackend>> {
+        state().backend.read().unwrap().clone()
+    }
+    /// Run the write on the installed backend, an error is logged, the
+    /// broker goes on with its state in memory.
+    pub(crate) fn write<F>(write: F)
+    where
+        F: FnOnce(&dyn StorageBackend) -> Result<(), String>,
+    {
+        if let Some(backend) = Persistence::backend() {
+            if let Err(why) = write(&*backend) {
+                error!("{}", why);
+            }
+        }
+    }
+    /// Delete the will and the session of the client, for a CONNECT with
+    /// CleanSession.
+    pub(crate) fn forget(client_id: &[u8]) {
+        Persistence::write(|backend| {
+            backend.remove_will(client_id)?;
+            backend.remove_session(client_id)
+        });
+    }
+}
+
+#[derive(Debug, Default)]
+struct MemoryTables {
+    retained: HashMap<String, StoredRetain>,
+    wills: HashMap<Vec<u8>, StoredWill>,
+    sessions: HashMap<Vec<u8>, StoredSession>,
+    counters: HashMap<String, u64>,
+}
+
+/// The records in the process, they live as long as the backend.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    tables: Mutex<MemoryTables>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        MemoryBackend::default()
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn put_retained(
+        &self,
+        topic: &str,
+        retain: &StoredRetain,
+    ) -> Result<(), String> {
+        let mut tables = self.tables.lock().unwrap();
+        tables.retained.insert(topic.to_string(), retain.clone());
+        Ok(())
+    }
+    fn remove_retained(&self, topic: &str) -> Result<(), String> {
+        self.tables.lock().unwrap().retained.remove(topic);
+        Ok(())
+    }
+    fn retained(&self) -> Result<Vec<(String, StoredRetain)>, String> {
+        let tables = self.tables.lock().unwrap();
+        Ok(tables
+            .retained
+            .iter()
+            .map(|(topic, retain)| (topic.clone(), retain.clone()))
+            .collect())
+    }
+    fn put_will(
+        &self,
+        client_id: &[u8],
+        will: &StoredWill,
+    ) -> Result<(), String> {
+        let mut tables = self.tables.lock().unwrap();
+        tables.wills.insert(client_id.to_vec(), will.clone());
+        Ok(())
+    }
+    fn get_will(&self, client_id: &[u8]) -> Result<Option<StoredWill>, String> {
+        Ok(self.tables.lock().unwrap().wills.get(client_id).cloned())
+    }
+    fn remove_will(&self, client_id: &[u8]) -> Result<(), String> {
+        self.tables.lock().unwrap().wills.remove(client_id);
+        Ok(())
+    }
+    fn put_session(
+        &self,
+        client_id: &[u8],
+        session: &StoredSession,
+    ) -> Result<(), String> {
+        let mut tables = self.tables.lock().unwrap();
+        tables.sessions.insert(client_id.to_vec(), session.clone());
+        Ok(())
+    }
+    fn get_session(
+        &self,
+        client_id: &[u8],
+    ) -> Result<Option<StoredSession>, String> {
+        Ok(self.tables.lock().unwrap().sessions.get(client_id).cloned())
+    }
+    fn remove_session(&self, client_id: &[u8]) -> Result<(), String> {
+        self.tables.lock().unwrap().sessions.remove(client_id);
+        Ok(())
+    }
+    fn put_counter(&self, name: &str, val: u64) -> Result<(), String> {
+        let mut tables = self.tables.lock().unwrap();
+        tables.counters.insert(name.to_string(), val);
+        Ok(())
+    }
+    fn get_counter(&self, name: &str) -> Result<Option<u64>, String> {
+        Ok(self.tables.lock().unwrap().counters.get(name).copied())
+    }
+}
+
+/// The records in a sqlite database, one table per kind, the records are
+/// encoded with bincode.
+#[cfg(feature = "storage-sqlite")]
+pub struct SqliteBackend {
+    path: String,
+    conn: Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "storage-sqlite")]
+impl SqliteBackend {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let conn = rusqlite::Connection::open(path)
+            .map_err(|why| eformat!(path, why))?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS retained (
+                 topic TEXT PRIMARY KEY, record BLOB NOT NULL);
+             CREATE TABLE IF NOT EXISTS wills (
+                 client_id BLOB PRIMARY KEY, record BLOB NOT NULL);
+             CREATE TABLE IF NOT EXISTS sessions (
+                 client_id BLOB PRIMARY KEY, record BLOB NOT NULL);
+             CREATE TABLE IF NOT EXISTS counters (
+                 name TEXT PRIMARY KEY, record BLOB NOT NULL);",
+        )
+        .map_err(|why| eformat!(path, why))?;
+        Ok(SqliteBackend {
+            path: path.to_string(),
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn put<K: rusqlite::ToSql, V: Serialize>(
+        &self,
+        sql: &str,
+        key: K,
+        val: &V,
+    ) -> Result<(), String> {
+        let record =
+            bincode::serialize(val).map_err(|why| eformat!(self.path, why))?;
+        let conn = self.conn.lock().unwrap();
+        match conn.execute(sql, rusqlite::params![key, record]) {
+            Ok(_) => Ok(()),
+            Err(why) => Err(eformat!(self.path, why)),
+        }
+    }
+
+    fn get<K: rusqlite::ToSql, V: serde::de::DeserializeOwned>(
+        &self,
+        sql: &str,
+        key: K,
+    ) -> Result<Option<V>, String> {
+        use rusqlite::OptionalExtension;
+        let record: Option<Vec<u8>> = {
+            let conn = self.conn.lock().unwrap();
+            conn.query_row(sql, rusqlite::params![key], |row| row.get(0))
+                .optional()
+                .map_err(|why| eformat!(self.path, why))?
+        };
+        match record {
+            Some(record) => bincode::deserialize(&record)
+                .map(Some)
+                .map_err(|why| eformat!(self.path, why)),
+            None => Ok(None),
+        }
+    }
+
+    fn delete<K: rusqlite::ToSql>(
+        &self,
+        sql: &str,
+        key: K,
+    ) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+        match conn.execute(sql, rusqlite::params![key]) {
+            Ok(_) => Ok(()),
+            Err(why) => Err(eformat!(self.path, why)),
+        }
+    }
+}
+
+#[cfg(feature = "storage-sqlite")]
+impl StorageBackend for SqliteBackend {
+    fn put_retained(
+        &self,
+        topic: &str,
+        retain: &StoredRetain,
+    ) -> Result<(), String> {
+        self.put(
+            "INSERT OR REPLACE INTO retained (topic, record) VALUES (?1, ?2)",
+            topic,
+            retain,
+        )
+    }
+    fn remove_retained(&self, topic: &str) -> Result<(), String> {
+        self.delete("DELETE FROM retained WHERE topic = ?1", topic)
+    }
+    fn retained(&self) -> Result<Vec<(String, StoredRetain)>, String> {
+        let rows: Vec<(String, Vec<u8>)> = {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn
+                .prepare("SELECT topic, record FROM retained")
+                .map_err(|why| eformat!(self.path, why))?;
+            let rows = stmt
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .map_err(|why| eformat!(self.path, why))?;
+            rows.collect::<Result<_, _>>()
+                .map_err(|why| eformat!(self.path, why))?
+        };
+        rows.into_iter()
+            .map(|(topic, record)| match bincode::deserialize(&record) {
+                Ok(retain) => Ok((topic, retain)),
+                Err(why) => Err(eformat!(self.path, topic, why)),
+            })
+            .collect()
+    }
+    fn put_will(
+        &self,
+        client_id: &[u8],
+        will: &StoredWill,
+    ) -> Result<(), String> {
+        self.put(
+            "INSERT OR REPLACE INTO wills (client_id, record) VALUES (?1, ?2)",
+            client_id,
+            will,
+        )
+    }
+    fn get_will(&self, client_id: &[u8]) -> Result<Option<StoredWill>, String> {
+        self.get("SELECT record FROM wills WHERE client_id = ?1", client_id)
+    }
+    fn remove_will(&self, client_id: &[u8]) -> Result<(), String> {
+        self.delete("DELETE FROM wills WHERE client_id = ?1", client_id)
+    }
+    fn put_session(
+        &self,
+        client_id: &[u8],
+        session: &StoredSession,
+    ) -> Result<(), String> {
+        self.put(
+            "INSERT OR REPLACE INTO sessions (client_id, record) \
+             VALUES (?1, ?2)",
+            client_id,
+            session,
+        )
+    }
+    fn get_session(
+        &self,
+        client_id: &[u8],
+    ) -> Result<Option<StoredSession>, String> {
+        self.get(
+            "SELECT record FROM sessions WHERE client_id = ?1",
+            client_id,
+        )
+    }
+    fn remove_session(&self, client_id: &[u8]) -> Result<(), String> {
+        self.delete("DELETE FROM sessions WHERE client_id = ?1", client_id)
+    }
+    fn put_counter(&self, name: &str, val: u64) -> Result<(), String> {
+        self.put(
+            "INSERT OR REPLACE INTO counters (name, record) VALUES (?1, ?2)",
+            name,
+            &val,
+        )
+    }
+    fn get_counter(&self, name: &str) -> Result<Option<u64>, String> {
+        self.get("SELECT record FROM counters WHERE name = ?1", name)
+    }
+}
+
+/// The [storage] table of the broker configuration:
+///
+/// [storage]
+/// backend = "sqlite"              # memory or sqlite, none by default
+/// path = "/var/lib/mqtt-sn/broker.db"
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub backend: Option<String>,
+    /// Database file of the sqlite backend.
+    pub path: Option<String>,
+}
+
+impl StorageConfig {
+    /// Check the backend name and its path.
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.backend.as_deref(), &self.path) {
+            (None, _) | (Some("memory"), _) => Ok(()),
+            (Some("sqlite"), None) => Err(eformat!("sqlite", "no path")),
+            #[cfg(feature = "storage-sqlite")]
+            (Some("sqlite"), Some(_)) => Ok(()),
+            #[cfg(not(feature = "storage-sqlite"))]
+            (Some("sqlite"), Some(_)) => {
+                Err(eformat!("sqlite", "storage-sqlite feature disabled"))
+            }
+            (Some(backend), _) => Err(eformat!(backend, "unknown backend")),
+        }
+    }
+    /// Open the backend, None without a backend.
+    pub fn open(&self) -> Result<Option<Arc<dyn StorageBackend>>, String> {
+        self.validate()?;
+        match (self.backend.as_deref(), &self.path) {
+            (Some("memory"), _) => Ok(Some(Arc::new(MemoryBackend::new()))),
+            #[cfg(feature = "storage-sqlite")]
+            (Some("sqlite"), Some(path)) => {
+                Ok(Some(Arc::new(SqliteBackend::open(path)?)))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_storage_backend() {
+        use super::*;
+        use crate::{
+            annotation::Annotations,
+            client_id::ClientId,
+            connection::Connection,
+            filter::{
+                get_topic_id_with_topic_name, subscribe_with_topic_name,
+                try_insert_topic_name,
+            },
+            flags::{CLEAN_SESSION_FALSE, CLEAN_SESSION_TRUE, QOS_LEVEL_1},
+            retain::Retain,
+        };
+        use bytes::Bytes;
+        use std::net::SocketAddr;
+        let mut backends: Vec<Arc<dyn StorageBackend>> =
+            vec![Arc::new(MemoryBackend::new())];
+        #[cfg(feature = "storage-sqlite")]
+        backends.push(Arc::new(SqliteBackend::open(":memory:").unwrap()));
+        let retain = StoredRetain {
+            qos: QOS_LEVEL_1,
+            msg_id: 7,
+            payload: b"21.5".to_vec(),
+            expires_at: None,
+        };
+        let will = StoredWill {
+            topic: "storage/will".to_string(),
+            message: b"gone".to_vec(),
+            qos: QOS_LEVEL_1,
+            retain: 0,
+        };
+        let session = StoredSession {
+            subscriptions: vec![SubscriptionRecord {
+                client_id: "storage".to_string(),
+                topic_name: "storage/+/temp".to_string(),
+                topic_id: 9,
+                qos: QOS_LEVEL_1,
+            }],
+        };
+        for backend in backends {
+            backend.put_retained("storage/temp", &retain).unwrap();
+            assert_eq!(
+                backend.retained(),
+                Ok(vec![("storage/temp".to_string(), retain.clone())])
+            );
+            backend.remove_retained("storage/temp").unwrap();
+            assert_eq!(backend.retained(), Ok(vec![]));
+            backend.put_will(b"storage", &will).unwrap();
+            assert_eq!(backend.get_will(b"storage"), Ok(Some(will.clone())));
+            backend.remove_will(b"storage").unwrap();
+            assert_eq!(backend.get_will(b"storage"), Ok(None));
+            backend.put_session(b"storage", &session).unwrap();
+            assert_eq!(
+                backend.get_session(b"storage"),
+                Ok(Some(session.clone()))
+            );
+            backend.remove_session(b"storage").unwrap();
+            assert_eq!(backend.get_session(b"storage"), Ok(None));
+            assert_eq!(backend.get_counter("storage/count"), Ok(None));
+            backend.put_counter("storage/count", 7).unwrap();
+            backend.put_counter("storage/count", 8).unwrap();
+            assert_eq!(backend.get_counter("storage/count"), Ok(Some(8)));
+        }
+        let config = StorageConfig {
+            backend: Some("memory".to_string()),
+            path: None,
+        };
+        assert!(config.open().unwrap().is_some());
+        assert!(StorageConfig::default().open().unwrap().is_none());
+        let config = StorageConfig {
+            backend: Some("sqlite".to_string()),
+            path: None,
+        };
+        assert!(config.validate().is_err());
+        let config = StorageConfig {
+            backend: Some("rocksdb".to_string()),
+            path: None,
+        };
+        assert!(config.validate().is_err());
+        // A broker writes, another broker with the backend restores.
+        let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+        let addr = "10.6.0.1:1234".parse::<SocketAddr>().unwrap();
+        let client_id = Bytes::from("storage");
+        {
+            let _context = BrokerContext::new().enter();
+            Persistence::install(Some(backend.clone()));
+            let topic = "storage/retained".to_string();
+            let topic_id = try_insert_topic_name(topic).unwrap();
+            let payload = Bytes::from("on");
+            Retain::insert(
+                QOS_LEVEL_1,
+                topic_id,
+                3,
+                payload,
+                Annotations::new(),
+            );
+            Connection::try_insert(
+                addr,
+                CLEAN_SESSION_FALSE,
+                1,
+                60,
+                client_id.clone(),
+            )
+            .unwrap();
+            let topic = "storage/+/temp".to_string();
+            subscribe_with_topic_name(addr, topic, QOS_LEVEL_1).unwrap();
+            Connection::update_will_topic(
+                addr,
+                QOS_LEVEL_1,
+                "storage/will".into(),
+            )
+            .unwrap();
+            Connection::save_session(&addr);
+        }
+        assert_eq!(backend.retained().unwrap().len(), 1);
+        let session = backend.get_session(b"storage").unwrap().unwrap();
+        assert_eq!(session.subscriptions[0].topic_name, "storage/+/temp");
+        assert_eq!(
+            backend.get_will(b"storage").unwrap().unwrap().qos,
+            QOS_LEVEL_1
+        );
+        let _context = BrokerContext::new().enter();
+        Persistence::install(Some(backend.clone()));
+        assert_eq!(Retain::restore(), Ok(1));
+        let topic = "storage/retained".to_string();
+        let topic_id = get_topic_id_with_topic_name(topic).unwrap();
+        assert_eq!(Retain::get(topic_id).unwrap().payload, Bytes::from("on"));
+        assert!(Retain::remove(topic_id));
+        assert_eq!(backend.retained(), Ok(vec![]));
+        Connection::try_insert(
+            addr,
+            CLEAN_SESSION_FALSE,
+            1,
+            60,
+            client_id.clone(),
+        )
+        .unwrap();
+        let will = Connection::get(&addr).unwrap();
+        assert_eq!(will.will_topic, Bytes::from("storage/will"));
+        Connection::remove(&addr).unwrap();
+        ClientId::rev_delete(&addr);
+        // CleanSession deletes the will and the session.
+        Connection::try_insert(addr, CLEAN_SESSION_TRUE, 1, 60, client_id)
+            .unwrap();
+        assert_eq!(backend.get_will(b"storage"), Ok(None));
+        assert_eq!(backend.get_session(b"storage"), Ok(None));
+        Connection::remove(&addr).unwrap();
+        ClientId::rev_delete(&addr);
+    }
+}
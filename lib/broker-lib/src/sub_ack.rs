@@ -20,8 +20,9 @@ contains wildcard characters)
 • ReturnCode: “accepted”, or rejection reason.
 */
 use crate::{
-    broker_lib::MqttSnClient, eformat, function, msg_hdr::MsgHeader,
-    retransmit::RetransTimeWheel, MSG_LEN_SUBACK, MSG_TYPE_SUBACK,
+    broker_lib::MqttSnClient, client_mode::ClientMode, codec, decode::Decode,
+    eformat, function, msg_hdr::MsgHeader, retransmit::RetransTimeWheel,
+    MSG_LEN_SUBACK,
 };
 use bytes::{BufMut, BytesMut};
 use custom_debug::Debug;
@@ -43,6 +44,13 @@ pub struct SubAck {
     pub return_code: u8,
 }
 
+codec!(SubAck, {
+    flags: u8,
+    topic_id: u16,
+    msg_id: u16,
+    return_code: u8,
+});
+
 impl SubAck {
     /*
         fn constraint_len(_val: &u8) -> bool {
@@ -76,7 +84,8 @@ impl SubAck {
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
-        let (sub_ack, read_len) = SubAck::try_read(buf, size).unwrap();
+        let (sub_ack, read_len) =
+            Decode::read(SubAck::try_read(buf, size), size, &msg_header)?;
         let remote_socket_addr = msg_header.remote_socket_addr;
         dbg!(sub_ack.clone());
 
@@ -85,15 +94,18 @@ impl SubAck {
             //     No topic_id passing to send for now.
             //     because the subscribe message might not contain it.
             //     The retransmision was scheduled with 0.
-            match RetransTimeWheel::cancel_timer(
+            RetransTimeWheel::cancel_timer(
                 remote_socket_addr,
                 sub_ack.msg_type,
-                0,
                 sub_ack.msg_id,
-            ) {
-                Ok(_) => Ok(()),
-                Err(err) => Err(err),
-            }
+            )?;
+            ClientMode::on_sub_ack(
+                &remote_socket_addr,
+                sub_ack.msg_id,
+                sub_ack.topic_id,
+                sub_ack.return_code,
+            );
+            Ok(())
             // TODO check QoS in flags
             // TODO check flags
         } else {
@@ -110,18 +122,8 @@ impl SubAck {
         msg_id: u16,
         return_code: u8,
     ) -> Result<(), String> {
-        let sub_ack = SubAck {
-            len: MSG_LEN_SUBACK,
-            msg_type: MSG_TYPE_SUBACK,
-            flags,
-            topic_id,
-            msg_id,
-            return_code,
-        };
         let remote_socket_addr = msg_header.remote_socket_addr;
-        let mut bytes_buf = BytesMut::with_capacity(MSG_LEN_SUBACK as usize);
-        dbg!(sub_ack.clone());
-        sub_ack.try_write(&mut bytes_buf);
+        let bytes_buf = SubAck::encode(flags, topic_id, msg_id, return_code);
         dbg!(bytes_buf.clone());
         dbg!(remote_socket_addr);
         // transmit to network
@@ -133,4 +135,19 @@ impl SubAck {
             Err(err) => Err(eformat!(remote_socket_addr, err)),
         }
     }
+
+    /// Send a SUBACK with a rejection return code and topic id 0, then
+    /// return why as the error for the caller to log.
+    #[inline(always)]
+    pub fn reject<T>(
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+        flags: u8,
+        msg_id: u16,
+        return_code: u8,
+        why: String,
+    ) -> Result<T, String> {
+        SubAck::send(client, msg_header, flags, 0, msg_id, return_code)?;
+        Err(why)
+    }
 }
@@ -30,9 +30,29 @@ extern crate trace_caller;
 use trace_caller::trace;
 
 use crate::{
-    broker_lib::MqttSnClient, eformat, filter::*, flags::*, function,
-    msg_hdr::*, publish::Publish, retain::Retain, retransmit::RetransTimeWheel,
-    sub_ack::SubAck, MSG_TYPE_SUBACK, MSG_TYPE_SUBSCRIBE, RETURN_CODE_ACCEPTED,
+    authorization::{client_id_of, topic_of},
+    broker_lib::MqttSnClient,
+    codec::{encode_packet, get_u16_be, Packet, Topic},
+    config::PreDefinedTopics,
+    decode::Decode,
+    eformat,
+    events::DeniedAction,
+    filter::*,
+    flags::*,
+    function,
+    metrics::{Counter, Metrics},
+    msg_hdr::*,
+    msg_id::MsgIdAllocator,
+    publish::Publish,
+    register::Register,
+    retain::Retain,
+    retransmit::RetransTimeWheel,
+    sub_ack::SubAck,
+    subscription_limits::SubscriptionLimits,
+    topic_refs::{TopicRef, TopicRefs},
+    TopicIdType, MSG_TYPE_SUBACK, MSG_TYPE_SUBSCRIBE, RETURN_CODE_ACCEPTED,
+    RETURN_CODE_CONGESTION, RETURN_CODE_INVALID_TOPIC_ID,
+    RETURN_CODE_NOT_SUPPORTED,
 };
 
 #[derive(
@@ -100,22 +120,19 @@ impl Subscribe {
     }
     */
 
-    // TODO error checking and return
+    /// Returns the msg_id of the SUBSCRIBE message.
     #[inline(always)]
     #[trace]
     pub fn send(
         topic: String,
-        msg_id: u16,
         qos: u8,
         retain: u8,
         client: &MqttSnClient,
         msg_header: MsgHeader,
-    ) -> Result<(), String> {
-        let subscribe = Subscribe::new(qos, retain, msg_id, topic);
+    ) -> Result<u16, String> {
         let remote_socket_addr = msg_header.remote_socket_addr;
-        dbg!(&subscribe);
-        let mut bytes_buf = BytesMut::with_capacity(subscribe.len as usize);
-        subscribe.try_write(&mut bytes_buf);
+        let msg_id = MsgIdAllocator::next(remote_socket_addr)?;
+        let bytes_buf = Subscribe::encode(topic, qos, retain, msg_id)?;
         // transmit to network
         if let Err(err) = client
             .egress_tx
@@ -123,17 +140,32 @@ impl Subscribe {
         {
             return Err(eformat!(remote_socket_addr, err));
         }
-        match RetransTimeWheel::schedule_timer(
+        RetransTimeWheel::schedule_timer(
             remote_socket_addr,
             MSG_TYPE_SUBACK,
             0,
-            0,
+            msg_id,
             1,
             bytes_buf,
-        ) {
-            Ok(()) => Ok(()),
-            Err(err) => Err(err),
-        }
+        )?;
+        Ok(msg_id)
+    }
+
+    /// The SUBSCRIBE message with a 2 or 4-byte header.
+    pub fn encode(
+        topic: String,
+        qos: u8,
+        retain: u8,
+        msg_id: u16,
+    ) -> Result<BytesMut, String> {
+        let subscribe = Subscribe::new(qos, retain, msg_id, topic);
+        dbg!(&subscribe);
+        // The u8 len of Subscribe::new() is only valid below 256.
+        encode_packet(&Packet::Subscribe {
+            flags: subscribe.flags,
+            msg_id: subscribe.msg_id,
+            topic: Topic::Name(&subscribe.topic_name),
+        })
     }
 
     #[inline(always)]
@@ -144,123 +176,312 @@ impl Subscribe {
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
-        // TODO replace unwrap
-        let (subscribe, read_fixed_len) = match msg_header.header_len {
-            MsgHeaderLenEnum::Short => Subscribe::try_read(buf, size).unwrap(),
-            MsgHeaderLenEnum::Long => {
-                Subscribe::try_read(&buf[2..], size - 2).unwrap()
-            }
-        };
+        let (subscribe, topic_id) =
+            Subscribe::resolve(buf, size, client, &msg_header)?;
+        if let Err(why) = subscribe_with_topic_id(
+            msg_header.remote_socket_addr,
+            topic_id,
+            flag_qos_level(subscribe.flags),
+        ) {
+            // The subscription or the filter tables are full.
+            return SubAck::reject(
+                client,
+                msg_header,
+                subscribe.flags,
+                subscribe.msg_id,
+                RETURN_CODE_CONGESTION,
+                why,
+            );
+        }
+        Subscribe::accept(&subscribe, topic_id, client, msg_header)
+    }
+
+    /// Read and check the SUBSCRIBE and find its topic id, a normal topic
+    /// name is inserted with a topic ref of the subscriber. A rejected
+    /// SUBSCRIBE is answered with a SUBACK and returns Err.
+    pub(crate) fn resolve(
+        buf: &[u8],
+        size: usize,
+        client: &MqttSnClient,
+        msg_header: &MsgHeader,
+    ) -> Result<(Subscribe, TopicIdType), String> {
+        let subscribe = Subscribe::read(buf, size, msg_header)?;
         let remote_socket_addr = msg_header.remote_socket_addr;
+        Metrics::inc(Counter::Subscribes);
         dbg!(subscribe.clone());
-        dbg!(subscribe.clone().topic_name);
-        let read_len = read_fixed_len + subscribe.topic_name.len();
-
-        dbg!((size, read_len));
         dbg!(flag_topic_id_type(subscribe.flags));
+        let reject = |return_code: u8, why: String| {
+            SubAck::reject::<(Subscribe, TopicIdType)>(
+                client,
+                msg_header.clone(),
+                subscribe.flags,
+                subscribe.msg_id,
+                return_code,
+                why,
+            )
+        };
 
         // TODO check QoS, https://www.hivemq.com/blog/mqtt-essentials-
         // part-6-mqtt-quality-of-service-levels/
-        if read_len == size {
-            match flag_topic_id_type(subscribe.flags) {
-                TOPIC_ID_TYPE_NORMAL => {
-                    // Normal topic type(string): assign topic_id from existing
-                    // or new.
-                    let topic_id = try_insert_topic_name(subscribe.topic_name)?;
-                    subscribe_with_topic_id(
-                        remote_socket_addr,
-                        topic_id,
-                        flag_qos_level(subscribe.flags),
-                    )?;
-                    dbg!(topic_id);
-                    // Because only QoS flag is used and other flags are not used,
-                    // return the same flags as received.
-                    SubAck::send(
-                        client,
-                        msg_header,
-                        subscribe.flags,
-                        topic_id,
-                        subscribe.msg_id,
-                        RETURN_CODE_ACCEPTED,
-                    )?;
-                    return Ok(());
-                }
-                TOPIC_ID_TYPE_PRE_DEFINED => {
-                    // Pre-defined topic type(u16/2 bytes) in the topic_id field.
-                    // The struct has topic_name field only. We have to convert it to
-                    // topic_id.
-                    let id = subscribe.topic_name.chars().as_str();
-                    dbg!(id);
-                    dbg!(id.len());
-                    if id.len() != 2 {
-                        return Err(eformat!(
-                            remote_socket_addr,
-                            "Invalid topic_name length: {}",
-                            id.len()
-                        ));
-                    }
-                    let mut topic_id: u16 = 0;
-                    for char in id.chars() {
-                        topic_id = (topic_id << 8) + char as u16;
-                    }
-                    dbg!(topic_id);
-                    // Pre-defined topic type(integer): save remote_addr and
-                    // topic_id to the hash map.
-                    subscribe_with_topic_id(
-                        remote_socket_addr,
-                        topic_id,
-                        flag_qos_level(subscribe.flags),
-                    )?;
-                    dbg!(topic_id);
-                    SubAck::send(
-                        client,
-                        msg_header,
-                        subscribe.flags,
-                        topic_id,
-                        subscribe.msg_id,
-                        RETURN_CODE_ACCEPTED,
-                    )?;
-                    dbg!(topic_id);
-                    if let Some(msg) = Retain::get(topic_id) {
-                        dbg!(topic_id);
-                        Publish::send(
-                            msg.topic_id,
-                            msg.msg_id,
-                            msg.qos,
-                            RETAIN_FALSE,
-                            msg.payload,
-                            client,
+        if client.is_congested() {
+            return reject(
+                RETURN_CODE_CONGESTION,
+                eformat!(remote_socket_addr, "congested"),
+            );
+        }
+        let topic_id = match flag_topic_id_type(subscribe.flags) {
+            TOPIC_ID_TYPE_NORMAL => {
+                // Normal topic type(string): assign topic_id from existing
+                // or new.
+                if !valid_filter(&subscribe.topic_name) {
+                    return reject(
+                        RETURN_CODE_INVALID_TOPIC_ID,
+                        eformat!(
                             remote_socket_addr,
-                        )?;
-                    }
-                    return Ok(());
-                }
-                TOPIC_ID_TYPE_SHORT => {
-                    dbg!(flag_topic_id_type(subscribe.flags));
-                    return Err(eformat!(
-                        remote_socket_addr,
-                        "topic Id short topic name not supported"
-                    ));
+                            "invalid filter",
+                            subscribe.topic_name
+                        ),
+                    );
                 }
-                TOPIC_ID_TYPE_RESERVED => {
-                    dbg!(flag_topic_id_type(subscribe.flags));
-                    return Err(eformat!(
-                        remote_socket_addr,
-                        "topic Id reserved type"
-                    ));
+                Subscribe::authorize(
+                    &subscribe,
+                    &subscribe.topic_name,
+                    client,
+                    msg_header,
+                )?;
+                match TopicRefs::insert(
+                    subscribe.topic_name.clone(),
+                    TopicRef::Subscription(remote_socket_addr),
+                ) {
+                    Ok(topic_id) => topic_id,
+                    // No topic id left.
+                    Err(why) => return reject(RETURN_CODE_CONGESTION, why),
                 }
-                _ => {
-                    dbg!(flag_topic_id_type(subscribe.flags));
-                    return Err(eformat!(
+            }
+            TOPIC_ID_TYPE_PRE_DEFINED => {
+                // Pre-defined topic type(u16/2 bytes) in the topic name
+                // field, not UTF-8.
+                let topic_id = match Decode::topic_id(buf, 5, size, msg_header)
+                {
+                    Ok(topic_id) => topic_id,
+                    Err(why) => {
+                        return reject(RETURN_CODE_INVALID_TOPIC_ID, why)
+                    }
+                };
+                Subscribe::authorize(
+                    &subscribe,
+                    &topic_of(topic_id),
+                    client,
+                    msg_header,
+                )?;
+                topic_id
+            }
+            TOPIC_ID_TYPE_SHORT => {
+                return reject(
+                    RETURN_CODE_NOT_SUPPORTED,
+                    eformat!(
                         remote_socket_addr,
-                        "topic Id unknown type"
-                    ));
-                }
-            };
+                        "topic Id short topic name not supported"
+                    ),
+                );
+            }
+            TOPIC_ID_TYPE_RESERVED => {
+                return reject(
+                    RETURN_CODE_NOT_SUPPORTED,
+                    eformat!(remote_socket_addr, "topic Id reserved type"),
+                );
+            }
+            _ => {
+                return Err(eformat!(
+                    remote_socket_addr,
+                    "topic Id unknown type"
+                ));
+            }
+        };
+        dbg!(topic_id);
+        if let Err(why) =
+            SubscriptionLimits::check(remote_socket_addr, topic_id)
+        {
+            // The topic ref of a normal topic name.
+            TopicRefs::release(
+                topic_id,
+                TopicRef::Subscription(remote_socket_addr),
+            );
+            return reject(RETURN_CODE_CONGESTION, why);
+        }
+        Ok((subscribe, topic_id))
+    }
+
+    /// SUBACK of the subscribed topic id, then the retained messages.
+    pub(crate) fn accept(
+        subscribe: &Subscribe,
+        topic_id: TopicIdType,
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+    ) -> Result<(), String> {
+        let remote_socket_addr = msg_header.remote_socket_addr;
+        let granted_qos = flag_qos_level(subscribe.flags);
+        client
+            .events
+            .on_subscribe(remote_socket_addr, topic_id, granted_qos);
+        // Because only QoS flag is used and other flags are not used,
+        // return the same flags as received.
+        SubAck::send(
+            client,
+            msg_header.clone(),
+            subscribe.flags,
+            topic_id,
+            subscribe.msg_id,
+            RETURN_CODE_ACCEPTED,
+        )?;
+        // The topic name of a pre-defined topic id is empty.
+        Subscribe::send_retained(
+            &subscribe.topic_name,
+            topic_id,
+            granted_qos,
+            client,
+            msg_header,
+        )
+    }
+
+    /// SUBSCRIBE from the short view of the datagram, see
+    /// MsgHeader::short_view(). The topic name is empty unless the topic id
+    /// type is normal, see Decode.
+    fn read(
+        buf: &[u8],
+        size: usize,
+        msg_header: &MsgHeader,
+    ) -> Result<Subscribe, String> {
+        // flags and msg_id follow the header, then the topic name.
+        let offset = 2;
+        Decode::check_len(buf, size, offset + 3, msg_header)?;
+        let flags = buf[offset];
+        let topic_name = if flag_topic_id_type(flags) == TOPIC_ID_TYPE_NORMAL {
+            Decode::utf8(buf, offset + 3, size, msg_header)?.to_string()
         } else {
-            // TODO clean up, length check is not needed,
-            // if it's check else where, it's not needed here.
-            return Err(eformat!(remote_socket_addr, "wrong size"));
+            String::new()
+        };
+        Ok(Subscribe {
+            len: msg_header.len as u8,
+            msg_type: MSG_TYPE_SUBSCRIBE,
+            flags,
+            msg_id: get_u16_be(buf, offset + 1),
+            topic_name,
+        })
+    }
+
+    /// Send the retained message of the topic, or the retained messages of
+    /// the matching topics for a wildcard filter. The topic ids of the
+    /// matching topics are registered first, the client only knows the
+    /// topic id of the filter. The messages are sent at the granted QoS at
+    /// most.
+    fn send_retained(
+        topic_name: &str,
+        topic_id: u16,
+        granted_qos: QoSConst,
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+    ) -> Result<(), String> {
+        let remote_socket_addr = msg_header.remote_socket_addr;
+        if !has_wildcards(topic_name) {
+            if let Some(msg) = Retain::get(topic_id) {
+                Publish::send(
+                    msg.topic_id,
+                    effective_qos(msg.qos, granted_qos),
+                    RETAIN_FALSE,
+                    msg.payload,
+                    client,
+                    remote_socket_addr,
+                )?;
+            }
+            return Ok(());
+        }
+        for (topic, msg) in Retain::matching(topic_name) {
+            // The client knows the pre-defined topic ids.
+            if PreDefinedTopics::name(msg.topic_id).is_none() {
+                TopicRefs::acquire(
+                    msg.topic_id,
+                    TopicRef::Registration(remote_socket_addr),
+                );
+                let msg_id = MsgIdAllocator::next(remote_socket_addr)?;
+                Register::send(
+                    msg.topic_id,
+                    msg_id,
+                    topic,
+                    client,
+                    msg_header.clone(),
+                )?;
+            }
+            Publish::send(
+                msg.topic_id,
+                effective_qos(msg.qos, granted_qos),
+                RETAIN_FALSE,
+                msg.payload,
+                client,
+                remote_socket_addr,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Reply with SUBACK and return Err if the authorizer denies the topic.
+    fn authorize(
+        subscribe: &Subscribe,
+        topic: &str,
+        client: &MqttSnClient,
+        msg_header: &MsgHeader,
+    ) -> Result<(), String> {
+        if client.authorizer.allow_all() {
+            return Ok(());
+        }
+        let remote_socket_addr = msg_header.remote_socket_addr;
+        let client_id = client_id_of(&remote_socket_addr);
+        if client.authorizer.allow_subscribe(&client_id, topic) {
+            return Ok(());
+        }
+        client.events.on_denied(
+            remote_socket_addr,
+            DeniedAction::Subscribe,
+            topic,
+        );
+        SubAck::reject(
+            client,
+            msg_header.clone(),
+            subscribe.flags,
+            subscribe.msg_id,
+            RETURN_CODE_NOT_SUPPORTED,
+            eformat!(remote_socket_addr, "not allowed", topic),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_subscribe_filter_validation() {
+        use super::*;
+        use crate::test_support::{LoopbackBroker, TestClient};
+        let mut subscriber = TestClient::new(LoopbackBroker::addr()).unwrap();
+        subscriber.connect("filterSub", 60, None).unwrap();
+        for filter in [
+            "+/filter_validation",
+            "filter_validation/+/c",
+            "filter_validation/#",
+        ]
+        .iter()
+        {
+            let (_topic_id, return_code) =
+                subscriber.subscribe(filter, QOS_LEVEL_0).unwrap();
+            assert_eq!(return_code, RETURN_CODE_ACCEPTED, "{}", filter);
+        }
+        for filter in
+            ["filter_validation/b+/c", "filter_validation#", "#/a"].iter()
+        {
+            let (topic_id, return_code) =
+                subscriber.subscribe(filter, QOS_LEVEL_0).unwrap();
+            assert_eq!(return_code, RETURN_CODE_INVALID_TOPIC_ID, "{}", filter);
+            assert_eq!(topic_id, 0);
         }
+        subscriber.disconnect(None).unwrap();
     }
 }
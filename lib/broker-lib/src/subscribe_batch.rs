@@ -0,0 +1,238 @@
+/// Batched SUBSCRIBE, an opt-in extension of MQTT-SN.
+///
+/// A client subscribing to many topics in one awake window sends the
+/// SUBSCRIBE messages back to back in one datagram, each with its own
+/// length field and msg_id. A standard gateway drops such a datagram, its
+/// length doesn't match the first message, the client only batches with a
+/// broker known to support it. The broker answers each SUBSCRIBE with its
+/// own SUBACK and msg_id, like separate datagrams, the wildcard filters of
+/// the batch are inserted with one lock of the filter table.
+use bytes::BytesMut;
+use log::*;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::{
+    broker_lib::MqttSnClient,
+    conn::Conn,
+    connection::Connection,
+    eformat,
+    filter::subscribe_batch_with_topic_ids,
+    flags::{flag_qos_level, QoSConst},
+    function,
+    msg_hdr::MsgHeader,
+    msg_id::MsgIdAllocator,
+    retransmit::RetransTimeWheel,
+    sub_ack::SubAck,
+    subscribe::Subscribe,
+    MSG_TYPE_SUBACK, MSG_TYPE_SUBSCRIBE, RETURN_CODE_CONGESTION,
+};
+
+/// Max. SUBSCRIBE messages in a datagram.
+pub const SUBSCRIBE_BATCH_MAX: usize = 16;
+
+pub struct SubscribeBatch {}
+
+impl SubscribeBatch {
+    /// The offset and the length of each message if the datagram is a batch
+    /// of 2 or more SUBSCRIBE messages, None otherwise.
+    pub fn split(buf: &[u8]) -> Option<Vec<(usize, usize)>> {
+        let mut messages = Vec::new();
+        let mut offset = 0;
+        while offset < buf.len() {
+            let rest = &buf[offset..];
+            // 1-octet or 3-octet length field.
+            let (header_len, len, msg_type) = match rest {
+                [1, high, low, msg_type, ..] => {
+                    (4, u16::from_be_bytes([*high, *low]) as usize, *msg_type)
+                }
+                [len, msg_type, ..] if *len != 1 => {
+                    (2, *len as usize, *msg_type)
+                }
+                _ => return None,
+            };
+            // flags and msg_id follow the header.
+            if msg_type != MSG_TYPE_SUBSCRIBE
+                || len < header_len + 3
+                || len > rest.len()
+                || messages.len() == SUBSCRIBE_BATCH_MAX
+            {
+                return None;
+            }
+            messages.push((offset, len));
+            offset += len;
+        }
+        if messages.len() < 2 {
+            return None;
+        }
+        Some(messages)
+    }
+
+    /// Subscribe the connected client to the topics of the batch, a SUBACK
+    /// per SUBSCRIBE.
+    pub fn recv(
+        buf: &[u8],
+        messages: &[(usize, usize)],
+        client: &MqttSnClient,
+        remote_socket_addr: SocketAddr,
+        conn: Arc<dyn Conn + Send + Sync>,
+    ) -> Result<(), String> {
+        if !Connection::contains_key(remote_socket_addr) {
+            return Err(eformat!(remote_socket_addr, "No connection found"));
+        }
+        let mut resolved = Vec::with_capacity(messages.len());
+        for (offset, len) in messages {
+            let message = &buf[*offset..*offset + *len];
+            let msg_header = MsgHeader::try_read(
+                message,
+                *len,
+                remote_socket_addr,
+                conn.clone(),
+            )?;
+            let (message, size) = msg_header.short_view(message, *len);
+            // A rejected SUBSCRIBE is already answered.
+            match Subscribe::resolve(message, size, client, &msg_header) {
+                Ok((subscribe, topic_id)) => {
+                    resolved.push((subscribe, topic_id, msg_header))
+                }
+                Err(why) => error!("{}", why),
+            }
+        }
+        let subscriptions: Vec<_> = resolved
+            .iter()
+            .map(|(subscribe, topic_id, _)| {
+                (*topic_id, flag_qos_level(subscribe.flags))
+            })
+            .collect();
+        let results =
+            subscribe_batch_with_topic_ids(remote_socket_addr, &subscriptions);
+        for ((subscribe, topic_id, msg_header), result) in
+            resolved.into_iter().zip(results)
+        {
+            let result = match result {
+                Ok(()) => {
+                    Subscribe::accept(&subscribe, topic_id, client, msg_header)
+                }
+                // The subscription or the filter tables are full.
+                Err(why) => SubAck::reject(
+                    client,
+                    msg_header,
+                    subscribe.flags,
+                    subscribe.msg_id,
+                    RETURN_CODE_CONGESTION,
+                    why,
+                ),
+            };
+            if let Err(why) = result {
+                error!("{}", why);
+            }
+        }
+        Ok(())
+    }
+
+    /// Send the SUBSCRIBE messages of the topics in one datagram, returns
+    /// their msg_id in the same order. Each SUBSCRIBE is retransmitted
+    /// alone until its SUBACK.
+    pub fn send(
+        topics: &[(String, QoSConst)],
+        retain: u8,
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+    ) -> Result<Vec<u16>, String> {
+        let remote_socket_addr = msg_header.remote_socket_addr;
+        if topics.is_empty() || topics.len() > SUBSCRIBE_BATCH_MAX {
+            return Err(eformat!(
+                remote_socket_addr,
+                "invalid batch size",
+                topics.len()
+            ));
+        }
+        let mut encoded = Vec::with_capacity(topics.len());
+        for (topic, qos) in topics {
+            let msg_id = MsgIdAllocator::next(remote_socket_addr)?;
+            let bytes_buf =
+                Subscribe::encode(topic.clone(), *qos, retain, msg_id)?;
+            encoded.push((msg_id, bytes_buf));
+        }
+        let len: usize =
+            encoded.iter().map(|(_, bytes_buf)| bytes_buf.len()).sum();
+        if len > MsgHeader::max_datagram_size() {
+            return Err(eformat!(remote_socket_addr, "batch too long", len));
+        }
+        let mut datagram = BytesMut::with_capacity(len);
+        for (_, bytes_buf) in &encoded {
+            datagram.extend_from_slice(bytes_buf);
+        }
+        // transmit to network
+        if let Err(err) =
+            client.egress_tx.try_send((remote_socket_addr, datagram))
+        {
+            return Err(eformat!(remote_socket_addr, err));
+        }
+        let mut msg_ids = Vec::with_capacity(encoded.len());
+        for (msg_id, bytes_buf) in encoded {
+            RetransTimeWheel::schedule_timer(
+                remote_socket_addr,
+                MSG_TYPE_SUBACK,
+                0,
+                msg_id,
+                1,
+                bytes_buf,
+            )?;
+            msg_ids.push(msg_id);
+        }
+        Ok(msg_ids)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_subscribe_batch() {
+        use super::*;
+        use crate::flags::{QOS_LEVEL_0, QOS_LEVEL_1, RETAIN_FALSE};
+        use crate::test_support::{LoopbackBroker, TestClient};
+        use crate::{RETURN_CODE_ACCEPTED, RETURN_CODE_INVALID_TOPIC_ID};
+        let mut subscriber = TestClient::new(LoopbackBroker::addr()).unwrap();
+        subscriber.connect("batchSub", 60, None).unwrap();
+        let topics = [
+            ("subscribe_batch/temp", QOS_LEVEL_1, RETURN_CODE_ACCEPTED),
+            ("subscribe_batch/#", QOS_LEVEL_0, RETURN_CODE_ACCEPTED),
+            (
+                "subscribe_batch/b+",
+                QOS_LEVEL_0,
+                RETURN_CODE_INVALID_TOPIC_ID,
+            ),
+        ];
+        let mut datagram = BytesMut::new();
+        for (index, (topic, qos, _)) in topics.iter().enumerate() {
+            let msg_id = 0x5b00 + index as u16;
+            let bytes_buf = Subscribe::encode(
+                topic.to_string(),
+                *qos,
+                RETAIN_FALSE,
+                msg_id,
+            )
+            .unwrap();
+            datagram.extend_from_slice(&bytes_buf);
+        }
+        let messages = SubscribeBatch::split(&datagram).unwrap();
+        assert_eq!(messages.len(), topics.len());
+        assert!(SubscribeBatch::split(&datagram[..messages[1].0]).is_none());
+        subscriber.send(&datagram).unwrap();
+        // A SUBACK per SUBSCRIBE, matched by msg_id.
+        let mut return_codes = vec![None; topics.len()];
+        for _ in 0..topics.len() {
+            let bytes = subscriber.expect(MSG_TYPE_SUBACK).unwrap();
+            let topic_id = u16::from_be_bytes([bytes[3], bytes[4]]);
+            let msg_id = u16::from_be_bytes([bytes[5], bytes[6]]);
+            let index = (msg_id - 0x5b00) as usize;
+            assert_eq!(topic_id != 0, bytes[7] == RETURN_CODE_ACCEPTED);
+            return_codes[index] = Some(bytes[7]);
+        }
+        let expected: Vec<_> =
+            topics.iter().map(|(_, _, rc)| Some(*rc)).collect();
+        assert_eq!(return_codes, expected);
+        subscriber.disconnect(None).unwrap();
+    }
+}
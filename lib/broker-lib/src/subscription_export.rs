@@ -0,0 +1,248 @@
+/// Export and import of the subscriptions as JSON.
+///
+/// A record is the client id, the topic name or filter, the topic id and
+/// the QoS. MqttSnClient::export_subscriptions() writes the subscriptions of
+/// the connected clients and the imported ones not applied yet, another
+/// broker imports them with MqttSnClient::import_subscriptions(), e.g. to
+/// migrate the clients between nodes, or to pre-provision the subscriptions
+/// of known devices.
+///
+/// The records of a client are applied after its next accepted CONNACK, or
+/// when they're imported if the client is connected. The topic id of the
+/// record is kept if it's free in the broker, and a REGISTER tells the
+/// client the topic id of a topic name without wildcards. The pre-defined
+/// topic ids are subscribed without a topic name.
+use bytes::Bytes;
+use hashbrown::HashMap;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use crate::{
+    broker_context::BrokerContext,
+    broker_lib::MqttSnClient,
+    client_id::ClientId,
+    config::PreDefinedTopics,
+    eformat,
+    filter::{
+        get_subscriptions, get_topic_id_with_topic_name,
+        get_topic_name_with_topic_id, has_wildcards, subscribe_with_topic_id,
+        subscribe_with_topic_name, try_register_topic_name,
+    },
+    flags::QoSConst,
+    function,
+    msg_id::MsgIdAllocator,
+    register::Register,
+    TopicIdType,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SubscriptionRecord {
+    pub client_id: String,
+    pub topic_name: String,
+    pub topic_id: TopicIdType,
+    pub qos: QoSConst,
+}
+
+/// Imported records waiting for their client, see BrokerContext.
+#[derive(Default)]
+pub(crate) struct SubscriptionExportState {
+    pending: Mutex<HashMap<Bytes, Vec<SubscriptionRecord>>>,
+}
+
+#[inline(always)]
+fn state() -> &'static SubscriptionExportState {
+    &BrokerContext::current().subscription_export
+}
+
+#[derive(Debug, Clone)]
+pub struct SubscriptionExport {}
+
+impl SubscriptionExport {
+    /// The subscriptions of the connected clients and the pending records.
+    pub fn records() -> Vec<SubscriptionRecord> {
+        let mut records: Vec<SubscriptionRecord> = get_subscriptions()
+            .into_iter()
+            .filter_map(|(socket_addr, topic_id, qos)| {
+                let client_id = ClientId::rev_get(&socket_addr).pop()?;
+                let topic_name = get_topic_name_with_topic_id(topic_id)
+                    .or_else(|| PreDefinedTopics::name(topic_id))?;
+                Some(SubscriptionRecord {
+                    client_id: String::from_utf8_lossy(&client_id).into(),
+                    topic_name,
+                    topic_id,
+                    qos,
+                })
+            })
+            .collect();
+        for pending in state().pending.lock().unwrap().values() {
+            records.extend(pending.iter().cloned());
+        }
+        records.sort_by(|a, b| {
+            (&a.client_id, a.topic_id).cmp(&(&b.client_id, b.topic_id))
+        });
+        records
+    }
+
+    pub fn export() -> Result<String, String> {
+        serde_json::to_string_pretty(&SubscriptionExport::records())
+            .map_err(|why| eformat!(why))
+    }
+
+    /// Import the records, returns their number. The records of the
+    /// connected clients are applied now.
+    pub fn import(json: &str, client: &MqttSnClient) -> Result<usize, String> {
+        let records: Vec<SubscriptionRecord> =
+            serde_json::from_str(json).map_err(|why| eformat!(why))?;
+        let len = records.len();
+        // All or nothing.
+        for record in records.iter() {
+            ClientId::validate(&Bytes::from(record.client_id.clone()))?;
+        }
+        let mut client_ids = Vec::new();
+        {
+            let mut pending = state().pending.lock().unwrap();
+            for record in records {
+                let client_id = Bytes::from(record.client_id.clone());
+                if !client_ids.contains(&client_id) {
+                    client_ids.push(client_id.clone());
+                }
+                pending.entry(client_id).or_default().push(record);
+            }
+        }
+        for client_id in client_ids {
+            for socket_addr in ClientId::get(&client_id) {
+                SubscriptionExport::apply(socket_addr, client);
+            }
+        }
+        Ok(len)
+    }
+
+    /// Queue the records of a client not connected yet, e.g. its session
+    /// in the storage backend.
+    pub(crate) fn add_pending(
+        client_id: Bytes,
+        records: Vec<SubscriptionRecord>,
+    ) {
+        if records.is_empty() {
+            return;
+        }
+        let mut pending = state().pending.lock().unwrap();
+        pending.entry(client_id).or_default().extend(records);
+    }
+
+    /// Subscribe the client connected from socket_addr with its pending
+    /// records, called after an accepted CONNACK.
+    pub fn apply(socket_addr: SocketAddr, client: &MqttSnClient) {
+        let records = {
+            let mut pending = state().pending.lock().unwrap();
+            if pending.is_empty() {
+                return;
+            }
+            let mut records = Vec::new();
+            for client_id in ClientId::rev_get(&socket_addr) {
+                records.extend(pending.remove(&client_id).unwrap_or_default());
+            }
+            records
+        };
+        for record in records {
+            if let Err(why) =
+                SubscriptionExport::subscribe(socket_addr, record, client)
+            {
+                error!("{}", why);
+            }
+        }
+    }
+
+    fn subscribe(
+        socket_addr: SocketAddr,
+        record: SubscriptionRecord,
+        client: &MqttSnClient,
+    ) -> Result<(), String> {
+        let SubscriptionRecord {
+            topic_name,
+            topic_id,
+            qos,
+            ..
+        } = record;
+        if PreDefinedTopics::name(topic_id).as_ref() == Some(&topic_name) {
+            return subscribe_with_topic_id(socket_addr, topic_id, qos);
+        }
+        // Keep the topic id of the other broker if it's free.
+        if get_topic_id_with_topic_name(topic_name.clone()).is_none()
+            && get_topic_name_with_topic_id(topic_id).is_none()
+            && PreDefinedTopics::name(topic_id).is_none()
+        {
+            try_register_topic_name(topic_name.clone(), topic_id)?;
+        }
+        let topic_id =
+            subscribe_with_topic_name(socket_addr, topic_name.clone(), qos)?;
+        if has_wildcards(&topic_name) {
+            return Ok(());
+        }
+        let msg_id = MsgIdAllocator::next(socket_addr)?;
+        Register::send_to(topic_id, msg_id, topic_name, client, socket_addr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_subscription_export() {
+        use super::*;
+        use crate::flags::{QOS_LEVEL_1, RETAIN_FALSE};
+        use crate::test_support::{LoopbackBroker, TestClient};
+        let broker = LoopbackBroker::addr();
+        let mut subscriber = TestClient::new(broker).unwrap();
+        subscriber.connect("exportSub", 60, None).unwrap();
+        let (topic_id, _return_code) = subscriber
+            .subscribe("subscription_export/temp", QOS_LEVEL_1)
+            .unwrap();
+        let json = SubscriptionExport::export().unwrap();
+        let records: Vec<SubscriptionRecord> =
+            serde_json::from_str(&json).unwrap();
+        let exported = SubscriptionRecord {
+            client_id: "exportSub".to_string(),
+            topic_name: "subscription_export/temp".to_string(),
+            topic_id,
+            qos: QOS_LEVEL_1,
+        };
+        assert!(records.contains(&exported));
+        subscriber.disconnect(None).unwrap();
+        // Pre-provisioned device, subscribed when it connects.
+        let provisioned = SubscriptionRecord {
+            client_id: "provisionedDev".to_string(),
+            topic_name: "subscription_export/cmd".to_string(),
+            topic_id: 0xF00D,
+            qos: QOS_LEVEL_1,
+        };
+        let json = serde_json::to_string(&vec![provisioned.clone()]).unwrap();
+        let client = MqttSnClient::new();
+        assert_eq!(SubscriptionExport::import(&json, &client), Ok(1));
+        assert!(SubscriptionExport::records().contains(&provisioned));
+        let mut device = TestClient::new(broker).unwrap();
+        device.connect("provisionedDev", 60, None).unwrap();
+        let mut publisher = TestClient::new(broker).unwrap();
+        publisher.connect("exportPub", 60, None).unwrap();
+        let topic_id = publisher.register("subscription_export/cmd").unwrap();
+        // The topic id of the record was free.
+        assert_eq!(topic_id, 0xF00D);
+        publisher
+            .publish(topic_id, QOS_LEVEL_1, RETAIN_FALSE, b"on")
+            .unwrap();
+        // The REGISTER was received with the CONNACK.
+        let publish = device.recv_publish().unwrap();
+        assert_eq!(
+            device.registered(topic_id).unwrap(),
+            "subscription_export/cmd"
+        );
+        assert_eq!(&publish.payload[..], b"on");
+        // Invalid client id.
+        let json =
+            r#"[{"client_id":"","topic_name":"a","topic_id":1,"qos":0}]"#;
+        assert!(SubscriptionExport::import(json, &client).is_err());
+        publisher.disconnect(None).unwrap();
+        device.disconnect(None).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+/// Limits of the subscriptions, to bound the memory of a small gateway.
+///
+/// Subscribe::resolve() checks a new subscription against the limits of the
+/// broker: the subscriptions of the client, the subscribers of the topic
+/// name or filter, and the wildcard filters of the broker. A SUBSCRIBE over
+/// a limit is answered with a SUBACK with RETURN_CODE_CONGESTION, the QoS
+/// update of an existing subscription is always accepted. The SUBSCRIBE
+/// messages of a batch are checked before the batch is subscribed. The
+/// rejections are counted in Counter::SubscriptionLimitRejections, see
+/// Metrics::render() for the totals.
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use crate::{
+    broker_context::BrokerContext,
+    eformat,
+    filter::{
+        count_filters, count_subscribers, count_subscriptions, get_topic_name,
+        has_filter, has_wildcards, is_subscribed,
+    },
+    function,
+    metrics::{Counter, Metrics},
+    TopicIdType,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SubscriptionLimits {
+    /// Max. subscriptions per client, 0 for no limit.
+    pub max_per_client: usize,
+    /// Max. subscribers per topic name or filter, 0 for no limit.
+    pub max_per_topic: usize,
+    /// Max. wildcard filters of the broker, 0 for no limit.
+    pub max_filters: usize,
+}
+
+/// Subscription limits of a broker, see BrokerContext.
+#[derive(Default)]
+pub(crate) struct SubscriptionLimitsState {
+    limits: Mutex<SubscriptionLimits>,
+}
+
+#[inline(always)]
+fn state() -> &'static SubscriptionLimitsState {
+    &BrokerContext::current().subscription_limits
+}
+
+impl SubscriptionLimits {
+    pub fn set(limits: SubscriptionLimits) {
+        *state().limits.lock().unwrap() = limits;
+    }
+    pub fn get() -> SubscriptionLimits {
+        *state().limits.lock().unwrap()
+    }
+
+    /// Err if the new subscription of the client to the topic id is over a
+    /// limit.
+    pub fn check(
+        socket_addr: SocketAddr,
+        topic_id: TopicIdType,
+    ) -> Result<(), String> {
+        let limits = SubscriptionLimits::get();
+        if limits == SubscriptionLimits::default()
+            || is_subscribed(&socket_addr, topic_id)
+        {
+            return Ok(());
+        }
+        let result = if limits.max_per_client > 0
+            && count_subscriptions(&socket_addr) >= limits.max_per_client
+        {
+            Err(eformat!(
+                socket_addr,
+                "max. subscriptions per client",
+                limits.max_per_client
+            ))
+        } else if limits.max_per_topic > 0
+            && count_subscribers(topic_id) >= limits.max_per_topic
+        {
+            Err(eformat!(
+                socket_addr,
+                "max. subscribers per topic",
+                topic_id,
+                limits.max_per_topic
+            ))
+        } else if limits.max_filters > 0
+            && SubscriptionLimits::is_new_filter(topic_id)
+            && count_filters() >= limits.max_filters
+        {
+            Err(eformat!(socket_addr, "max. filters", limits.max_filters))
+        } else {
+            Ok(())
+        };
+        if result.is_err() {
+            Metrics::inc(Counter::SubscriptionLimitRejections);
+        }
+        result
+    }
+
+    /// A wildcard filter without subscribers.
+    fn is_new_filter(topic_id: TopicIdType) -> bool {
+        match get_topic_name(topic_id) {
+            Some(filter) => has_wildcards(&filter) && !has_filter(&filter),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_subscription_limits() {
+        use super::*;
+        use crate::broker_lib::MqttSnClient;
+        use crate::filter::{subscribe_with_topic_name, try_insert_topic_name};
+        use crate::flags::QOS_LEVEL_0;
+        let client = MqttSnClient::new().with_context(BrokerContext::new());
+        let _context = client.context.enter();
+        let addr = "10.6.0.1:1884".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.6.0.2:1884".parse::<SocketAddr>().unwrap();
+        let temp = try_insert_topic_name("limits/temp".to_string()).unwrap();
+        let filter = try_insert_topic_name("limits/+".to_string()).unwrap();
+        // No limits.
+        assert_eq!(SubscriptionLimits::check(addr, temp), Ok(()));
+        SubscriptionLimits::set(SubscriptionLimits {
+            max_per_client: 1,
+            max_per_topic: 1,
+            max_filters: 1,
+        });
+        subscribe_with_topic_name(addr, "limits/temp".into(), QOS_LEVEL_0)
+            .unwrap();
+        // A QoS update.
+        assert_eq!(SubscriptionLimits::check(addr, temp), Ok(()));
+        assert!(SubscriptionLimits::check(addr, filter).is_err());
+        assert!(SubscriptionLimits::check(addr2, temp).is_err());
+        assert_eq!(SubscriptionLimits::check(addr2, filter), Ok(()));
+        subscribe_with_topic_name(addr2, "limits/+".into(), QOS_LEVEL_0)
+            .unwrap();
+        let other = try_insert_topic_name("other/#".to_string()).unwrap();
+        let addr3 = "10.6.0.3:1884".parse::<SocketAddr>().unwrap();
+        assert!(SubscriptionLimits::check(addr3, other).is_err());
+        SubscriptionLimits::set(SubscriptionLimits::default());
+        assert_eq!(SubscriptionLimits::check(addr3, other), Ok(()));
+    }
+}
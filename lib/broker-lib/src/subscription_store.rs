@@ -0,0 +1,498 @@
+/// Subscriptions and topic filters in sharded maps.
+///
+/// The subscribers of a topic id are read on every PUBLISH, a single map
+/// behind one Mutex serializes the fan-out of all the ingress threads. The
+/// maps are split in shards, each behind its own lock, keyed by the topic
+/// id, the topic name or the subscriber address. Lookups take a read lock
+/// on one shard, publishes to different topics don't contend.
+///
+/// No operation holds two shard locks at the same time. The reverse index
+/// from the subscriber to its topic ids is updated after the topic id
+/// shard, a concurrent delete leaves at most a stale reverse entry that the
+/// next delete removes.
+use hashbrown::hash_map::DefaultHashBuilder;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::{Mutex, RwLock};
+
+use crate::{
+    collections::{
+        BoundedMap, BoundedSet, ConnMap, FilterMap, FilterSet, QoSMap,
+    },
+    eformat,
+    filter::{has_wildcards, valid_filter, Subscriber, TopicTree},
+    flags::QoSConst,
+    function, TopicIdType,
+};
+
+/// Number of shards of the broker's store, a power of 2.
+pub const SUBSCRIPTION_SHARDS: usize = 16;
+
+type AddrSet = FilterSet<SocketAddr>;
+type TopicIdShard = FilterMap<TopicIdType, QoSMap<SocketAddr, QoSConst>>;
+type SocketAddrShard = ConnMap<SocketAddr, FilterSet<TopicIdType>>;
+
+#[derive(Debug)]
+pub struct SubscriptionStore {
+    hasher: DefaultHashBuilder,
+    /// Topic id to the subscribers and their QoS, sharded by topic id.
+    topic_ids: Vec<RwLock<TopicIdShard>>,
+    /// Subscriber to its topic ids, sharded by address.
+    socket_addrs: Vec<Mutex<SocketAddrShard>>,
+    /// Topic name without wildcards to the subscribers, sharded by name.
+    concrete_topics: Vec<RwLock<FilterMap<String, AddrSet>>>,
+    /// Topics matched against the wildcard filters, sharded by name.
+    wildcard_topics: Vec<RwLock<FilterMap<String, AddrSet>>>,
+    /// Wildcard filters and their subscribers, matched level by level
+    /// for a new topic.
+    wildcard_filters: RwLock<TopicTree>,
+}
+
+impl SubscriptionStore {
+    pub fn new(shards: usize) -> Self {
+        let shards = shards.max(1);
+        SubscriptionStore {
+            hasher: DefaultHashBuilder::default(),
+            topic_ids: (0..shards)
+                .map(|_| RwLock::new(FilterMap::new()))
+                .collect(),
+            socket_addrs: (0..shards)
+                .map(|_| Mutex::new(ConnMap::new()))
+                .collect(),
+            concrete_topics: (0..shards)
+                .map(|_| RwLock::new(FilterMap::new()))
+                .collect(),
+            wildcard_topics: (0..shards)
+                .map(|_| RwLock::new(FilterMap::new()))
+                .collect(),
+            wildcard_filters: RwLock::new(TopicTree::new()),
+        }
+    }
+
+    #[inline(always)]
+    fn shard_of<K: Hash + ?Sized>(&self, key: &K, shards: usize) -> usize {
+        let mut hasher = self.hasher.build_hasher();
+        key.hash(&mut hasher);
+        hasher.finish() as usize % shards
+    }
+    #[inline(always)]
+    fn topic_id_shard(&self, topic_id: TopicIdType) -> usize {
+        topic_id as usize % self.topic_ids.len()
+    }
+
+    /// Subscribe or update the QoS of an existing subscription.
+    pub fn subscribe(
+        &self,
+        topic_id: TopicIdType,
+        socket_addr: SocketAddr,
+        qos: QoSConst,
+    ) -> Result<(), String> {
+        {
+            let index = self.topic_id_shard(topic_id);
+            let mut shard = self.topic_ids[index].write().unwrap();
+            if shard.get(&topic_id).is_none()
+                && shard.bounded_insert(topic_id, QoSMap::new()).is_err()
+            {
+                return Err(eformat!(
+                    socket_addr,
+                    "topic id map full",
+                    topic_id
+                ));
+            }
+            let subscribers = shard.get_mut(&topic_id).unwrap();
+            if subscribers.bounded_insert(socket_addr, qos).is_err() {
+                return Err(eformat!(socket_addr, "QoS map full", topic_id));
+            }
+        }
+        let index = self.shard_of(&socket_addr, self.socket_addrs.len());
+        let mut shard = self.socket_addrs[index].lock().unwrap();
+        if shard.get(&socket_addr).is_none()
+            && shard.bounded_insert(socket_addr, FilterSet::new()).is_err()
+        {
+            drop(shard);
+            self.remove_subscriber(topic_id, &socket_addr);
+            return Err(eformat!(socket_addr, "subscriber map full"));
+        }
+        let topic_id_set = shard.get_mut(&socket_addr).unwrap();
+        if topic_id_set.bounded_insert(topic_id).is_err() {
+            drop(shard);
+            self.remove_subscriber(topic_id, &socket_addr);
+            return Err(eformat!(socket_addr, "topic id set full", topic_id));
+        }
+        Ok(())
+    }
+
+    /// Returns the QoS of the removed subscription.
+    pub fn unsubscribe(
+        &self,
+        topic_id: TopicIdType,
+        socket_addr: &SocketAddr,
+    ) -> Option<QoSConst> {
+        let qos = self.remove_subscriber(topic_id, socket_addr);
+        let index = self.shard_of(socket_addr, self.socket_addrs.len());
+        let mut shard = self.socket_addrs[index].lock().unwrap();
+        if let Some(topic_id_set) = shard.get_mut(socket_addr) {
+            topic_id_set.remove(&topic_id);
+            if topic_id_set.is_empty() {
+                shard.remove(socket_addr);
+            }
+        }
+        qos
+    }
+
+    /// Remove from the topic id shard only.
+    fn remove_subscriber(
+        &self,
+        topic_id: TopicIdType,
+        socket_addr: &SocketAddr,
+    ) -> Option<QoSConst> {
+        let index = self.topic_id_shard(topic_id);
+        let mut shard = self.topic_ids[index].write().unwrap();
+        let subscribers = shard.get_mut(&topic_id)?;
+        let qos = subscribers.remove(socket_addr);
+        if subscribers.is_empty() {
+            shard.remove(&topic_id);
+        }
+        qos
+    }
+
+    #[inline(always)]
+    pub fn subscribers(&self, topic_id: TopicIdType) -> Vec<Subscriber> {
+        let index = self.topic_id_shard(topic_id);
+        match self.topic_ids[index].read().unwrap().get(&topic_id) {
+            Some(subscribers) => subscribers
+                .iter()
+                .map(|(socket_addr, qos)| Subscriber {
+                    socket_addr: *socket_addr,
+                    qos: *qos,
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Number of subscribers of the topic id.
+    pub fn subscriber_count(&self, topic_id: TopicIdType) -> usize {
+        let index = self.topic_id_shard(topic_id);
+        self.topic_ids[index]
+            .read()
+            .unwrap()
+            .get(&topic_id)
+            .map_or(0, |subscribers| subscribers.len())
+    }
+    /// Number of topic ids subscribed by the subscriber.
+    pub fn subscription_count(&self, socket_addr: &SocketAddr) -> usize {
+        let index = self.shard_of(socket_addr, self.socket_addrs.len());
+        self.socket_addrs[index]
+            .lock()
+            .unwrap()
+            .get(socket_addr)
+            .map_or(0, |topic_id_set| topic_id_set.len())
+    }
+    pub fn is_subscribed(
+        &self,
+        topic_id: TopicIdType,
+        socket_addr: &SocketAddr,
+    ) -> bool {
+        let index = self.topic_id_shard(topic_id);
+        self.topic_ids[index]
+            .read()
+            .unwrap()
+            .get(&topic_id)
+            .map_or(false, |subscribers| subscribers.contains_key(socket_addr))
+    }
+
+    /// All the subscriptions, the subscriber, the topic id and the QoS.
+    pub fn subscriptions(&self) -> Vec<(SocketAddr, TopicIdType, QoSConst)> {
+        let mut subscriptions = Vec::new();
+        for shard in self.topic_ids.iter() {
+            for (topic_id, subscribers) in shard.read().unwrap().iter() {
+                subscriptions.extend(
+                    subscribers.iter().map(|(socket_addr, qos)| {
+                        (*socket_addr, *topic_id, *qos)
+                    }),
+                );
+            }
+        }
+        subscriptions
+    }
+
+    /// Delete all subscribers of the topic id, returns their addresses.
+    pub fn delete_topic_id(&self, topic_id: TopicIdType) -> Vec<SocketAddr> {
+        let index = self.topic_id_shard(topic_id);
+        let socket_addr_vec: Vec<SocketAddr> =
+            match self.topic_ids[index].write().unwrap().remove(&topic_id) {
+                Some(subscribers) => subscribers.keys().copied().collect(),
+                None => return Vec::new(),
+            };
+        for socket_addr in socket_addr_vec.iter() {
+            let index = self.shard_of(socket_addr, self.socket_addrs.len());
+            let mut shard = self.socket_addrs[index].lock().unwrap();
+            if let Some(topic_id_set) = shard.get_mut(socket_addr) {
+                topic_id_set.remove(&topic_id);
+                if topic_id_set.is_empty() {
+                    shard.remove(socket_addr);
+                }
+            }
+        }
+        socket_addr_vec
+    }
+
+    /// Delete all subscriptions of the subscriber, returns the topic ids
+    /// and their QoS.
+    pub fn delete_socket_addr(
+        &self,
+        socket_addr: &SocketAddr,
+    ) -> Vec<(TopicIdType, QoSConst)> {
+        let index = self.shard_of(socket_addr, self.socket_addrs.len());
+        let topic_id_set = match self.socket_addrs[index]
+            .lock()
+            .unwrap()
+            .remove(socket_addr)
+        {
+            Some(topic_id_set) => topic_id_set,
+            None => return Vec::new(),
+        };
+        topic_id_set
+            .iter()
+            .filter_map(|topic_id| {
+                let qos = self.remove_subscriber(*topic_id, socket_addr)?;
+                Some((*topic_id, qos))
+            })
+            .collect()
+    }
+
+    /// Insert a topic name or a wildcard filter.
+    pub fn insert_filter(
+        &self,
+        filter: String,
+        socket_addr: SocketAddr,
+    ) -> Result<(), String> {
+        if !valid_filter(&filter) {
+            return Err(eformat!(socket_addr, "invalid filter", filter));
+        }
+        if has_wildcards(&filter) {
+            self.wildcard_filters
+                .write()
+                .unwrap()
+                .insert(&filter, socket_addr)?;
+            // The cached topics might match the new filter.
+            for shard in self.wildcard_topics.iter() {
+                shard.write().unwrap().clear();
+            }
+            Ok(())
+        } else {
+            let index = self.shard_of(&filter, self.concrete_topics.len());
+            SubscriptionStore::insert_addr(
+                &mut self.concrete_topics[index].write().unwrap(),
+                filter,
+                socket_addr,
+            )
+        }
+    }
+
+    /// Insert the wildcard filters of a subscriber under one lock of the
+    /// filter table, a result per filter.
+    pub fn insert_filters(
+        &self,
+        filters: Vec<String>,
+        socket_addr: SocketAddr,
+    ) -> Vec<Result<(), String>> {
+        let results: Vec<Result<(), String>> = {
+            let mut wildcard_filters = self.wildcard_filters.write().unwrap();
+            filters
+                .into_iter()
+                .map(|filter| {
+                    if !valid_filter(&filter) || !has_wildcards(&filter) {
+                        return Err(eformat!(
+                            socket_addr,
+                            "invalid filter",
+                            filter
+                        ));
+                    }
+                    wildcard_filters.insert(&filter, socket_addr)
+                })
+                .collect()
+        };
+        // The cached topics might match the new filters.
+        if results.iter().any(|result| result.is_ok()) {
+            for shard in self.wildcard_topics.iter() {
+                shard.write().unwrap().clear();
+            }
+        }
+        results
+    }
+
+    /// Remove the subscriber from a wildcard filter.
+    pub fn remove_filter(&self, filter: &str, socket_addr: &SocketAddr) {
+        if !self
+            .wildcard_filters
+            .write()
+            .unwrap()
+            .remove(filter, socket_addr)
+        {
+            return;
+        }
+        // The cached topics might contain the subscriber.
+        for shard in self.wildcard_topics.iter() {
+            shard.write().unwrap().clear();
+        }
+    }
+
+    #[inline(always)]
+    pub fn has_filters(&self) -> bool {
+        !self.wildcard_filters.read().unwrap().is_empty()
+    }
+    /// Wildcard filters matching the topic name.
+    pub fn match_filters(&self, topic: &str) -> Vec<String> {
+        self.wildcard_filters.read().unwrap().match_filters(topic)
+    }
+    /// Number of wildcard filters.
+    pub fn filter_count(&self) -> usize {
+        self.wildcard_filters.read().unwrap().len()
+    }
+    pub fn has_filter(&self, filter: &str) -> bool {
+        self.wildcard_filters.read().unwrap().contains(filter)
+    }
+
+    fn insert_addr(
+        map: &mut FilterMap<String, AddrSet>,
+        key: String,
+        socket_addr: SocketAddr,
+    ) -> Result<(), String> {
+        if map.get(&key).is_none()
+            && map.bounded_insert(key.clone(), AddrSet::new()).is_err()
+        {
+            return Err(eformat!(socket_addr, "filter map full", key));
+        }
+        match map.get_mut(&key).unwrap().bounded_insert(socket_addr) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(eformat!(socket_addr, "filter set full", key)),
+        }
+    }
+
+    /// Remove the subscriber from all topics and filters.
+    pub fn delete_filters(&self, socket_addr: &SocketAddr) {
+        self.wildcard_filters
+            .write()
+            .unwrap()
+            .remove_addr(socket_addr);
+        for shard in self.concrete_topics.iter().chain(&self.wildcard_topics) {
+            SubscriptionStore::remove_addr(
+                &mut shard.write().unwrap(),
+                socket_addr,
+            );
+        }
+    }
+
+    fn remove_addr(
+        map: &mut FilterMap<String, AddrSet>,
+        socket_addr: &SocketAddr,
+    ) {
+        let mut empty_keys = Vec::new();
+        for (key, addr_set) in map.iter_mut() {
+            if addr_set.remove(socket_addr) && addr_set.is_empty() {
+                empty_keys.push(key.clone());
+            }
+        }
+        for key in empty_keys {
+            map.remove(&key);
+        }
+    }
+
+    #[inline(always)]
+    pub fn match_concrete(&self, topic: &str) -> Vec<SocketAddr> {
+        let index = self.shard_of(topic, self.concrete_topics.len());
+        match self.concrete_topics[index].read().unwrap().get(topic) {
+            Some(addr_set) => addr_set.iter().copied().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Subscribers of the topic name and of the matching wildcard filters,
+    /// sorted without duplicates.
+    pub fn match_topics(&self, topic: &str) -> Vec<SocketAddr> {
+        let index = self.shard_of(topic, self.wildcard_topics.len());
+        let cached = self.wildcard_topics[index]
+            .read()
+            .unwrap()
+            .get(topic)
+            .map(|addr_set| addr_set.iter().copied().collect::<Vec<_>>());
+        let mut socket_addr_vec = match cached {
+            Some(socket_addr_vec) => socket_addr_vec,
+            None => {
+                // Match the topic against the wildcard filters once.
+                let mut matched = AddrSet::new();
+                self.wildcard_filters.read().unwrap().for_each_match(
+                    topic,
+                    |_filter, addr_set| {
+                        for socket_addr in addr_set.iter() {
+                            let _result = matched.bounded_insert(*socket_addr);
+                        }
+                    },
+                );
+                let socket_addr_vec = matched.iter().copied().collect();
+                if !matched.is_empty() {
+                    // The topic isn't cached if the map is full.
+                    let _result = self.wildcard_topics[index]
+                        .write()
+                        .unwrap()
+                        .bounded_insert(topic.to_string(), matched);
+                }
+                socket_addr_vec
+            }
+        };
+        socket_addr_vec.append(&mut self.match_concrete(topic));
+        socket_addr_vec.sort();
+        socket_addr_vec.dedup();
+        socket_addr_vec
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_subscription_store() {
+        use super::*;
+        use crate::flags::{QOS_LEVEL_0, QOS_LEVEL_1, QOS_LEVEL_2};
+        let store = SubscriptionStore::new(4);
+        let addr = "127.0.0.1:1400".parse::<SocketAddr>().unwrap();
+        let addr2 = "127.0.0.2:1400".parse::<SocketAddr>().unwrap();
+        store.subscribe(1, addr, QOS_LEVEL_1).unwrap();
+        store.subscribe(1, addr2, QOS_LEVEL_0).unwrap();
+        store.subscribe(6, addr, QOS_LEVEL_2).unwrap();
+        let mut subscribers: Vec<(SocketAddr, QoSConst)> = store
+            .subscribers(1)
+            .iter()
+            .map(|subscriber| (subscriber.socket_addr, subscriber.qos))
+            .collect();
+        subscribers.sort();
+        assert_eq!(
+            subscribers,
+            vec![(addr, QOS_LEVEL_1), (addr2, QOS_LEVEL_0)]
+        );
+        assert_eq!(store.unsubscribe(1, &addr2), Some(QOS_LEVEL_0));
+        assert_eq!(store.unsubscribe(1, &addr2), None);
+        let mut deleted = store.delete_socket_addr(&addr);
+        deleted.sort();
+        assert_eq!(deleted, vec![(1, QOS_LEVEL_1), (6, QOS_LEVEL_2)]);
+        assert!(store.subscribers(1).is_empty());
+        store.subscribe(2, addr, QOS_LEVEL_0).unwrap();
+        assert_eq!(store.delete_topic_id(2), vec![addr]);
+        assert!(store.delete_socket_addr(&addr).is_empty());
+        // Topic names and wildcard filters.
+        store.insert_filter("a/b".to_string(), addr).unwrap();
+        store.insert_filter("a/#".to_string(), addr2).unwrap();
+        assert!(store.insert_filter("a/#/b".to_string(), addr).is_err());
+        let mut both = vec![addr, addr2];
+        both.sort();
+        assert_eq!(store.match_topics("a/b"), both);
+        assert_eq!(store.match_topics("a/c"), vec![addr2]);
+        // A new filter clears the cached matches.
+        store.insert_filter("+/#".to_string(), addr).unwrap();
+        assert_eq!(store.match_topics("a/c"), both);
+        store.delete_filters(&addr2);
+        assert_eq!(store.match_topics("a/b"), vec![addr]);
+        assert_eq!(store.match_concrete("a/b"), vec![addr]);
+    }
+}
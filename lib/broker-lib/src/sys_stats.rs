@@ -0,0 +1,149 @@
+/// Cumulative $SYS counters: total messages received and sent, total bytes
+/// and total connections.
+///
+/// The counters are persisted with StorageBackend::put_counter(), so the
+/// totals don't reset to zero when the broker restarts or is upgraded.
+/// Call SysStats::restore() with the backend before the broker starts,
+/// the counters are written every SYS_PERSIST_INTERVAL_SEC seconds
+/// by the thread started with SysStats::run().
+use log::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::broker_context::BrokerContext;
+use crate::storage_backend::StorageBackend;
+
+pub const SYS_MESSAGES_RECEIVED: &str = "$SYS/broker/messages/received";
+pub const SYS_MESSAGES_SENT: &str = "$SYS/broker/messages/sent";
+pub const SYS_BYTES_RECEIVED: &str = "$SYS/broker/bytes/received";
+pub const SYS_CLIENTS_TOTAL: &str = "$SYS/broker/clients/total";
+pub const SYS_PERSIST_INTERVAL_SEC: u64 = 10;
+
+lazy_static! {
+    static ref MESSAGES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+    static ref MESSAGES_SENT: AtomicU64 = AtomicU64::new(0);
+    static ref BYTES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+    static ref CLIENTS_TOTAL: AtomicU64 = AtomicU64::new(0);
+    static ref STORAGE: Mutex<Option<Arc<dyn StorageBackend>>> =
+        Mutex::new(None);
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SysTotals {
+    pub messages_received: u64,
+    pub messages_sent: u64,
+    pub bytes_received: u64,
+    pub clients_total: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SysStats {}
+
+impl SysStats {
+    /// PUBLISH message received.
+    #[inline(always)]
+    pub fn inc_messages() {
+        MESSAGES_RECEIVED.fetch_add(1, Ordering::Relaxed);
+    }
+    /// PUBLISH messages sent to count subscribers.
+    #[inline(always)]
+    pub fn add_sent(count: usize) {
+        MESSAGES_SENT.fetch_add(count as u64, Ordering::Relaxed);
+    }
+    /// Datagram received.
+    #[inline(always)]
+    pub fn add_bytes(size: usize) {
+        BYTES_RECEIVED.fetch_add(size as u64, Ordering::Relaxed);
+    }
+    /// Client connected.
+    #[inline(always)]
+    pub fn inc_clients() {
+        CLIENTS_TOTAL.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn totals() -> SysTotals {
+        SysTotals {
+            messages_received: MESSAGES_RECEIVED.load(Ordering::Relaxed),
+            messages_sent: MESSAGES_SENT.load(Ordering::Relaxed),
+            bytes_received: BYTES_RECEIVED.load(Ordering::Relaxed),
+            clients_total: CLIENTS_TOTAL.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Load the persisted totals and use the storage for persist().
+    /// The persisted totals are added to the counters, so the messages
+    /// received before restore() are not lost.
+    pub fn restore(
+        storage: Arc<dyn StorageBackend>,
+    ) -> Result<SysTotals, String> {
+        for (key, counter) in SysStats::counters() {
+            if let Some(val) = storage.get_counter(key)? {
+                counter.fetch_add(val, Ordering::Relaxed);
+            }
+        }
+        *STORAGE.lock().unwrap() = Some(storage);
+        Ok(SysStats::totals())
+    }
+
+    /// Write the totals to the storage, no-op without storage.
+    pub fn persist() -> Result<(), String> {
+        let storage = match &*STORAGE.lock().unwrap() {
+            Some(storage) => storage.clone(),
+            None => return Ok(()),
+        };
+        for (key, counter) in SysStats::counters() {
+            storage.put_counter(key, counter.load(Ordering::Relaxed))?;
+        }
+        Ok(())
+    }
+
+    pub fn run() {
+        let context = BrokerContext::current();
+        let builder = thread::Builder::new().name("sys_stats_thread".into());
+        let _sys_stats_thread = builder.spawn(move || {
+            let _context = context.enter();
+            loop {
+                thread::sleep(Duration::from_secs(SYS_PERSIST_INTERVAL_SEC));
+                if let Err(why) = SysStats::persist() {
+                    error!("{}", why);
+                }
+            }
+        });
+    }
+
+    fn counters() -> [(&'static str, &'static AtomicU64); 4] {
+        [
+            (SYS_MESSAGES_RECEIVED, &MESSAGES_RECEIVED),
+            (SYS_MESSAGES_SENT, &MESSAGES_SENT),
+            (SYS_BYTES_RECEIVED, &BYTES_RECEIVED),
+            (SYS_CLIENTS_TOTAL, &CLIENTS_TOTAL),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_sys_stats_restore() {
+        use super::*;
+        use crate::storage_backend::MemoryBackend;
+
+        let storage = Arc::new(MemoryBackend::new());
+        storage.put_counter(SYS_MESSAGES_RECEIVED, 100).unwrap();
+        storage.put_counter(SYS_CLIENTS_TOTAL, 7).unwrap();
+        let before = SysStats::totals();
+        let totals = SysStats::restore(storage.clone()).unwrap();
+        assert_eq!(totals.messages_received, before.messages_received + 100);
+        assert_eq!(totals.clients_total, before.clients_total + 7);
+
+        SysStats::inc_messages();
+        SysStats::add_bytes(10);
+        SysStats::persist().unwrap();
+        let val = storage.get_counter(SYS_MESSAGES_RECEIVED).unwrap();
+        assert!(val.unwrap() > totals.messages_received);
+        let val = storage.get_counter(SYS_BYTES_RECEIVED).unwrap();
+        assert!(val.unwrap() >= 10);
+    }
+}
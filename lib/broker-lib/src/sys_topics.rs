@@ -0,0 +1,169 @@
+/// Broker statistics published on the $SYS topics.
+///
+/// The $SYS topics have pre-defined topic ids, a client subscribes with the
+/// topic id, or with the topic name, SysTopics::init() registers the names
+/// with the same ids. The thread started with SysTopics::run() publishes
+/// the values every MqttSnClient::sys_interval seconds with QoS 0, the
+/// payload is the value in decimal. The topics start with '$' and don't
+/// match the wildcard filters, see match_topic().
+use bytes::Bytes;
+use log::*;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{
+    annotation::Annotations,
+    broker_lib::MqttSnClient,
+    connection::Connection,
+    filter::{get_subscribers_with_topic_id, try_register_topic_name},
+    flags::{QOS_LEVEL_0, RETAIN_FALSE},
+    publish::Publish,
+    sys_stats::{
+        SysStats, SYS_BYTES_RECEIVED, SYS_CLIENTS_TOTAL, SYS_MESSAGES_RECEIVED,
+        SYS_MESSAGES_SENT,
+    },
+    topic_refs::{TopicRef, TopicRefs},
+    TopicIdType,
+};
+
+pub const SYS_CLIENTS_CONNECTED: &str = "$SYS/broker/clients/connected";
+pub const SYS_UPTIME: &str = "$SYS/broker/uptime";
+
+pub const SYS_TOPIC_ID_CLIENTS_CONNECTED: TopicIdType = 0xFF00;
+pub const SYS_TOPIC_ID_CLIENTS_TOTAL: TopicIdType = 0xFF01;
+pub const SYS_TOPIC_ID_MESSAGES_RECEIVED: TopicIdType = 0xFF02;
+pub const SYS_TOPIC_ID_MESSAGES_SENT: TopicIdType = 0xFF03;
+pub const SYS_TOPIC_ID_BYTES_RECEIVED: TopicIdType = 0xFF04;
+pub const SYS_TOPIC_ID_UPTIME: TopicIdType = 0xFF05;
+
+const SYS_TOPICS: [(TopicIdType, &str); 6] = [
+    (SYS_TOPIC_ID_CLIENTS_CONNECTED, SYS_CLIENTS_CONNECTED),
+    (SYS_TOPIC_ID_CLIENTS_TOTAL, SYS_CLIENTS_TOTAL),
+    (SYS_TOPIC_ID_MESSAGES_RECEIVED, SYS_MESSAGES_RECEIVED),
+    (SYS_TOPIC_ID_MESSAGES_SENT, SYS_MESSAGES_SENT),
+    (SYS_TOPIC_ID_BYTES_RECEIVED, SYS_BYTES_RECEIVED),
+    (SYS_TOPIC_ID_UPTIME, SYS_UPTIME),
+];
+
+#[derive(Debug, Clone)]
+pub struct SysTopics {}
+
+impl SysTopics {
+    /// Topic name of a $SYS topic id.
+    pub fn name(topic_id: TopicIdType) -> Option<&'static str> {
+        SYS_TOPICS
+            .iter()
+            .find(|(sys_topic_id, _)| *sys_topic_id == topic_id)
+            .map(|(_, topic_name)| *topic_name)
+    }
+
+    /// Register the topic names with their pre-defined ids in the context
+    /// of the broker, the ids are pinned.
+    pub fn init() -> Result<(), String> {
+        for (topic_id, topic_name) in SYS_TOPICS.iter() {
+            try_register_topic_name(topic_name.to_string(), *topic_id)?;
+            TopicRefs::acquire(*topic_id, TopicRef::Broker);
+        }
+        Ok(())
+    }
+
+    /// Current values of the $SYS topics.
+    pub fn values(uptime: Duration) -> [(TopicIdType, u64); 6] {
+        let totals = SysStats::totals();
+        [
+            (
+                SYS_TOPIC_ID_CLIENTS_CONNECTED,
+                Connection::count_connected() as u64,
+            ),
+            (SYS_TOPIC_ID_CLIENTS_TOTAL, totals.clients_total),
+            (SYS_TOPIC_ID_MESSAGES_RECEIVED, totals.messages_received),
+            (SYS_TOPIC_ID_MESSAGES_SENT, totals.messages_sent),
+            (SYS_TOPIC_ID_BYTES_RECEIVED, totals.bytes_received),
+            (SYS_TOPIC_ID_UPTIME, uptime.as_secs()),
+        ]
+    }
+
+    /// Publish the values to the subscribers.
+    pub fn publish(uptime: Duration, client: &MqttSnClient) {
+        for (topic_id, value) in SysTopics::values(uptime).iter() {
+            let publish = Publish::new(
+                *topic_id,
+                0,
+                QOS_LEVEL_0,
+                RETAIN_FALSE,
+                Bytes::from(value.to_string()),
+            );
+            if let Err(why) = Publish::send_msg_to_subscribers(
+                get_subscribers_with_topic_id(*topic_id),
+                publish,
+                &Annotations::new(),
+                client,
+            ) {
+                error!("{}", why);
+            }
+        }
+    }
+
+    /// Publish the values every client.sys_interval seconds.
+    pub fn run(client: MqttSnClient) {
+        let started = Instant::now();
+        let builder = thread::Builder::new().name("sys_topics_thread".into());
+        let _sys_topics_thread = builder.spawn(move || {
+            let _context = client.context.enter();
+            if let Err(why) = SysTopics::init() {
+                error!("{}", why);
+                return;
+            }
+            let interval = Duration::from_secs(client.sys_interval as u64);
+            loop {
+                thread::sleep(interval);
+                SysTopics::publish(started.elapsed(), &client);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_sys_topics() {
+        use super::*;
+        use crate::broker_context::BrokerContext;
+        use crate::config::PreDefinedTopics;
+        use crate::filter::{
+            get_topic_id_with_topic_name, subscribe_with_topic_id,
+            subscribe_with_topic_name,
+        };
+        use std::net::SocketAddr;
+        let context = BrokerContext::new();
+        let _context = context.enter();
+        let client = MqttSnClient::new().with_context(context);
+        SysTopics::init().unwrap();
+        assert_eq!(
+            get_topic_id_with_topic_name(SYS_UPTIME.to_string()),
+            Some(SYS_TOPIC_ID_UPTIME)
+        );
+        assert_eq!(
+            PreDefinedTopics::name(SYS_TOPIC_ID_MESSAGES_SENT).unwrap(),
+            SYS_MESSAGES_SENT
+        );
+        let addr = "127.0.0.1:2400".parse::<SocketAddr>().unwrap();
+        let wildcard_addr = "127.0.0.2:2400".parse::<SocketAddr>().unwrap();
+        for (socket_addr, client_id) in
+            [(addr, "sys_topics"), (wildcard_addr, "sys_topics_all")].iter()
+        {
+            Connection::try_insert(*socket_addr, 0, 1, 60, (*client_id).into())
+                .unwrap();
+        }
+        subscribe_with_topic_id(addr, SYS_TOPIC_ID_UPTIME, QOS_LEVEL_0)
+            .unwrap();
+        // $ topics don't match the wildcard filters.
+        let filter = "$SYS/#".to_string();
+        subscribe_with_topic_name(wildcard_addr, filter, QOS_LEVEL_0).unwrap();
+        SysTopics::publish(Duration::from_secs(42), &client);
+        let (addr_vec, bytes) = client.egress_batch_rx.try_recv().unwrap();
+        assert_eq!(addr_vec, vec![addr]);
+        assert_eq!(&bytes[7..], b"42");
+        assert!(client.egress_batch_rx.try_recv().is_err());
+    }
+}
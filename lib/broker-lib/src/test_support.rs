@@ -0,0 +1,616 @@
+/// End-to-end test support: a broker and scripted MQTT-SN clients over
+/// loopback UDP.
+///
+/// LoopbackBroker::addr() starts one broker per process on 127.0.0.1, the
+/// datagrams go through MqttSnClient::dispatch() like the datagrams from a
+/// DTLS connection, and the egress messages are sent back with the same UDP
+/// socket. The broker state is global, the tests share the broker and use
+/// their own client ids and topics.
+///
+/// TestClient is a blocking client, each call sends a message and waits for
+/// the answer, e.g. publish() with QoS 2 runs PUBLISH, PUBREC, PUBREL and
+/// PUBCOMP. The messages received while waiting for another message type
+/// are kept for the next calls, a REGISTER from the broker is acknowledged
+/// when it's received.
+///
+/// Built for the unit tests, and with the test-support feature for the
+/// benches and apps/soak.
+use bytes::{BufMut, Bytes, BytesMut};
+use hashbrown::HashMap;
+use log::*;
+use std::collections::VecDeque;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{
+    broker_lib::MqttSnClient,
+    client_mode::Will,
+    codec::{get_u16_be, put_u16_be},
+    conn::udp_conn,
+    eformat,
+    egress::Egress,
+    flags::{
+        flag_is_retain, flag_qos_level, QoSConst, RetainConst,
+        CLEAN_SESSION_TRUE, QOS_LEVEL_1, QOS_LEVEL_2, WILL_TRUE,
+    },
+    function,
+    keep_alive::KeepAliveTimeWheel,
+    msg_hdr::MsgHeader,
+    publish::Publish,
+    recv_pool::RECV_POOL,
+    retransmit::RetransTimeWheel,
+    MSG_LEN_DISCONNECT, MSG_LEN_DISCONNECT_DURATION, MSG_LEN_PUBACK,
+    MSG_LEN_PUBCOMP, MSG_LEN_PUBREC, MSG_LEN_PUBREL, MSG_LEN_REGACK,
+    MSG_TYPE_CONNACK, MSG_TYPE_CONNECT, MSG_TYPE_DISCONNECT, MSG_TYPE_PINGREQ,
+    MSG_TYPE_PINGRESP, MSG_TYPE_PUBACK, MSG_TYPE_PUBCOMP, MSG_TYPE_PUBLISH,
+    MSG_TYPE_PUBREC, MSG_TYPE_PUBREL, MSG_TYPE_REGACK, MSG_TYPE_REGISTER,
+    MSG_TYPE_SUBACK, MSG_TYPE_SUBSCRIBE, MSG_TYPE_WILL_MSG,
+    MSG_TYPE_WILL_MSG_REQ, MSG_TYPE_WILL_TOPIC, MSG_TYPE_WILL_TOPIC_REQ,
+    RETURN_CODE_ACCEPTED,
+};
+
+pub const TEST_CLIENT_TIMEOUT_MS: u64 = 2000;
+const PROTOCOL_ID: u8 = 1;
+
+lazy_static! {
+    static ref LOOPBACK_BROKER: SocketAddr =
+        LoopbackBroker::spawn().expect("failed to start the loopback broker");
+}
+
+#[derive(Debug, Clone)]
+pub struct LoopbackBroker {}
+
+impl LoopbackBroker {
+    /// Address of the broker, started on the first call.
+    pub fn addr() -> SocketAddr {
+        *LOOPBACK_BROKER
+    }
+
+    fn spawn() -> Result<SocketAddr, String> {
+        let socket =
+            UdpSocket::bind("127.0.0.1:0").map_err(|why| eformat!(why))?;
+        let addr = socket.local_addr().map_err(|why| eformat!(why))?;
+        let socket_tx = socket.try_clone().map_err(|why| eformat!(why))?;
+        let client = MqttSnClient::new();
+        KeepAliveTimeWheel::init();
+        KeepAliveTimeWheel::run(client.clone());
+        RetransTimeWheel::init();
+        RetransTimeWheel::run(client.clone());
+        let client_rx = client.clone();
+        let builder = thread::Builder::new().name("loopback_rx_thread".into());
+        let _loopback_rx_thread = builder.spawn(move || {
+            let _context = client_rx.context.enter();
+            // Nothing is sent through the conn, the egress uses the socket.
+            let conn = udp_conn();
+            loop {
+                let mut buf = RECV_POOL.take();
+                match socket.recv_from(&mut buf) {
+                    Ok((size, remote_addr)) => client_rx.dispatch(
+                        remote_addr,
+                        RECV_POOL.freeze(buf, size),
+                        conn.clone(),
+                    ),
+                    Err(why) => error!("{}", eformat!(addr, why)),
+                }
+            }
+        });
+        let builder = thread::Builder::new().name("loopback_tx_thread".into());
+        let _loopback_tx_thread = builder.spawn(move || {
+            let _context = client.context.enter();
+            loop {
+                let (addr_vec, data) = match Egress::next(&client) {
+                    Ok(next) => next,
+                    Err(_) => break,
+                };
+                for remote_addr in addr_vec {
+                    if let Err(why) = socket_tx.send_to(&data[..], remote_addr)
+                    {
+                        error!("{}", eformat!(remote_addr, why));
+                    }
+                }
+            }
+        });
+        Ok(addr)
+    }
+}
+
+/// A PUBLISH received by a TestClient.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestPublish {
+    pub topic_id: u16,
+    pub msg_id: u16,
+    pub qos: QoSConst,
+    pub retain: bool,
+    pub payload: Bytes,
+}
+
+#[derive(Debug)]
+pub struct TestClient {
+    socket: UdpSocket,
+    broker: SocketAddr,
+    msg_id: u16,
+    timeout: Duration,
+    /// Messages received while waiting for another message type.
+    pending: VecDeque<Bytes>,
+    /// Topic ids registered by the broker.
+    registered: HashMap<u16, String>,
+}
+
+impl TestClient {
+    pub fn new(broker: SocketAddr) -> Result<Self, String> {
+        let socket =
+            UdpSocket::bind("127.0.0.1:0").map_err(|why| eformat!(why))?;
+        socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .map_err(|why| eformat!(why))?;
+        Ok(TestClient {
+            socket,
+            broker,
+            msg_id: 0,
+            timeout: Duration::from_millis(TEST_CLIENT_TIMEOUT_MS),
+            pending: VecDeque::new(),
+            registered: HashMap::new(),
+        })
+    }
+    pub fn local_addr(&self) -> SocketAddr {
+        self.socket.local_addr().unwrap()
+    }
+    /// Time to wait for each answer.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+    /// Topic name of a topic id registered by the broker.
+    pub fn registered(&self, topic_id: u16) -> Option<&String> {
+        self.registered.get(&topic_id)
+    }
+
+    /// CONNECT with the clean session flag, answers the will requests.
+    /// Returns the CONNACK return code.
+    pub fn connect(
+        &mut self,
+        client_id: &str,
+        duration: u16,
+        will: Option<&Will>,
+    ) -> Result<u8, String> {
+        let flags = match will {
+            Some(_) => CLEAN_SESSION_TRUE | WILL_TRUE,
+            None => CLEAN_SESSION_TRUE,
+        };
+        self.send_connect(client_id, duration, flags)?;
+        if let Some(will) = will {
+            self.expect(MSG_TYPE_WILL_TOPIC_REQ)?;
+            let mut bytes = BytesMut::new();
+            TestClient::put_header(
+                &mut bytes,
+                1 + will.topic.len(),
+                MSG_TYPE_WILL_TOPIC,
+            )?;
+            bytes.put_u8(will.qos | will.retain);
+            bytes.put_slice(will.topic.as_bytes());
+            self.send(&bytes)?;
+            self.expect(MSG_TYPE_WILL_MSG_REQ)?;
+            let mut bytes = BytesMut::new();
+            TestClient::put_header(
+                &mut bytes,
+                will.msg.len(),
+                MSG_TYPE_WILL_MSG,
+            )?;
+            bytes.put_slice(will.msg.as_bytes());
+            self.send(&bytes)?;
+        }
+        let connack = self.expect(MSG_TYPE_CONNACK)?;
+        Ok(connack[connack.len() - 1])
+    }
+
+    /// CONNECT without the clean session flag, the subscriptions are kept,
+    /// e.g. an ASLEEP client going back to ACTIVE. Returns the CONNACK
+    /// return code.
+    pub fn reconnect(
+        &mut self,
+        client_id: &str,
+        duration: u16,
+    ) -> Result<u8, String> {
+        self.send_connect(client_id, duration, 0)?;
+        let connack = self.expect(MSG_TYPE_CONNACK)?;
+        Ok(connack[connack.len() - 1])
+    }
+
+    fn send_connect(
+        &self,
+        client_id: &str,
+        duration: u16,
+        flags: u8,
+    ) -> Result<(), String> {
+        let mut bytes = BytesMut::new();
+        TestClient::put_header(
+            &mut bytes,
+            4 + client_id.len(),
+            MSG_TYPE_CONNECT,
+        )?;
+        bytes.put_u8(flags);
+        bytes.put_u8(PROTOCOL_ID);
+        put_u16_be(&mut bytes, duration);
+        bytes.put_slice(client_id.as_bytes());
+        self.send(&bytes)
+    }
+
+    /// REGISTER the topic name, returns the topic id.
+    pub fn register(&mut self, topic: &str) -> Result<u16, String> {
+        let msg_id = self.next_msg_id();
+        let mut bytes = BytesMut::new();
+        TestClient::put_header(&mut bytes, 4 + topic.len(), MSG_TYPE_REGISTER)?;
+        put_u16_be(&mut bytes, 0);
+        put_u16_be(&mut bytes, msg_id);
+        bytes.put_slice(topic.as_bytes());
+        self.send(&bytes)?;
+        let regack = self.expect(MSG_TYPE_REGACK)?;
+        match (TestClient::u16_at(&regack, 2), regack[6]) {
+            (topic_id, RETURN_CODE_ACCEPTED) => Ok(topic_id),
+            (_, return_code) => Err(eformat!(topic, return_code)),
+        }
+    }
+
+    /// SUBSCRIBE to a topic name, returns the topic id and the return code.
+    pub fn subscribe(
+        &mut self,
+        topic: &str,
+        qos: QoSConst,
+    ) -> Result<(u16, u8), String> {
+        let msg_id = self.next_msg_id();
+        let mut bytes = BytesMut::new();
+        TestClient::put_header(
+            &mut bytes,
+            3 + topic.len(),
+            MSG_TYPE_SUBSCRIBE,
+        )?;
+        bytes.put_u8(qos);
+        put_u16_be(&mut bytes, msg_id);
+        bytes.put_slice(topic.as_bytes());
+        self.send(&bytes)?;
+        let suback = self.expect(MSG_TYPE_SUBACK)?;
+        Ok((TestClient::u16_at(&suback, 3), suback[7]))
+    }
+
+    /// PUBLISH and run the QoS 1 or QoS 2 handshake.
+    pub fn publish(
+        &mut self,
+        topic_id: u16,
+        qos: QoSConst,
+        retain: RetainConst,
+        payload: &[u8],
+    ) -> Result<(), String> {
+        let msg_id = match qos {
+            QOS_LEVEL_1 | QOS_LEVEL_2 => self.next_msg_id(),
+            _ => 0,
+        };
+        let bytes = Publish::encode(topic_id, msg_id, qos, retain, payload)?;
+        self.send(&bytes)?;
+        match qos {
+            QOS_LEVEL_1 => {
+                let puback = self.expect(MSG_TYPE_PUBACK)?;
+                match puback[6] {
+                    RETURN_CODE_ACCEPTED => Ok(()),
+                    return_code => Err(eformat!(topic_id, return_code)),
+                }
+            }
+            QOS_LEVEL_2 => {
+                self.expect(MSG_TYPE_PUBREC)?;
+                self.send(&TestClient::msg_id_ack(
+                    MSG_LEN_PUBREL,
+                    MSG_TYPE_PUBREL,
+                    msg_id,
+                ))?;
+                self.expect(MSG_TYPE_PUBCOMP)?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Wait for a PUBLISH and acknowledge it.
+    pub fn recv_publish(&mut self) -> Result<TestPublish, String> {
+        let bytes = self.expect(MSG_TYPE_PUBLISH)?;
+        self.ack_publish(&bytes)
+    }
+
+    /// PINGREQ, with the client id when the client is asleep. Returns the
+    /// PUBLISH messages received before the PINGRESP.
+    pub fn ping(
+        &mut self,
+        client_id: Option<&str>,
+    ) -> Result<Vec<TestPublish>, String> {
+        let client_id = client_id.unwrap_or("");
+        let mut bytes = BytesMut::new();
+        TestClient::put_header(&mut bytes, client_id.len(), MSG_TYPE_PINGREQ)?;
+        bytes.put_slice(client_id.as_bytes());
+        self.send(&bytes)?;
+        let mut publishes = Vec::new();
+        loop {
+            let bytes =
+                self.expect_any(&[MSG_TYPE_PUBLISH, MSG_TYPE_PINGRESP])?;
+            if TestClient::msg_type(&bytes) == MSG_TYPE_PINGRESP {
+                return Ok(publishes);
+            }
+            publishes.push(self.ack_publish(&bytes)?);
+        }
+    }
+
+    /// DISCONNECT, with a duration the client goes to sleep.
+    pub fn disconnect(&mut self, duration: Option<u16>) -> Result<(), String> {
+        let mut bytes = BytesMut::new();
+        match duration {
+            Some(duration) => {
+                bytes.put_u8(MSG_LEN_DISCONNECT_DURATION);
+                bytes.put_u8(MSG_TYPE_DISCONNECT);
+                put_u16_be(&mut bytes, duration);
+            }
+            None => {
+                bytes.put_u8(MSG_LEN_DISCONNECT);
+                bytes.put_u8(MSG_TYPE_DISCONNECT);
+            }
+        }
+        self.send(&bytes)?;
+        self.expect(MSG_TYPE_DISCONNECT)?;
+        Ok(())
+    }
+
+    /// Send a datagram to the broker.
+    pub fn send(&self, bytes: &[u8]) -> Result<(), String> {
+        match self.socket.send_to(bytes, self.broker) {
+            Ok(size) if size == bytes.len() => Ok(()),
+            Ok(size) => Err(eformat!(self.broker, "sent", size, bytes.len())),
+            Err(why) => Err(eformat!(self.broker, why)),
+        }
+    }
+
+    /// Wait for a message of the type.
+    pub fn expect(&mut self, msg_type: u8) -> Result<Bytes, String> {
+        self.expect_any(&[msg_type])
+    }
+
+    fn expect_any(&mut self, msg_types: &[u8]) -> Result<Bytes, String> {
+        if let Some(index) = self
+            .pending
+            .iter()
+            .position(|bytes| msg_types.contains(&TestClient::msg_type(bytes)))
+        {
+            return Ok(self.pending.remove(index).unwrap());
+        }
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = [0u8; 1500];
+        while Instant::now() < deadline {
+            let size = match self.socket.recv_from(&mut buf) {
+                Ok((size, _addr)) if size >= 2 => size,
+                Ok(_) => continue,
+                Err(why)
+                    if why.kind() == io::ErrorKind::WouldBlock
+                        || why.kind() == io::ErrorKind::TimedOut =>
+                {
+                    continue
+                }
+                Err(why) => return Err(eformat!(self.broker, why)),
+            };
+            let bytes = Bytes::copy_from_slice(&buf[..size]);
+            let msg_type = TestClient::msg_type(&bytes);
+            if msg_types.contains(&msg_type) {
+                return Ok(bytes);
+            }
+            if msg_type == MSG_TYPE_REGISTER {
+                self.ack_register(&bytes)?;
+            } else {
+                self.pending.push_back(bytes);
+            }
+        }
+        Err(eformat!(self.local_addr(), "timeout", msg_types))
+    }
+
+    fn ack_publish(&mut self, bytes: &Bytes) -> Result<TestPublish, String> {
+        let offset = if bytes[0] == 1 { 2 } else { 0 };
+        let flags = bytes[offset + 2];
+        let publish = TestPublish {
+            topic_id: TestClient::u16_at(bytes, offset + 3),
+            msg_id: TestClient::u16_at(bytes, offset + 5),
+            qos: flag_qos_level(flags),
+            retain: flag_is_retain(flags),
+            payload: bytes.slice(offset + 7..),
+        };
+        match publish.qos {
+            QOS_LEVEL_1 => {
+                let mut bytes = BytesMut::new();
+                bytes.put_u8(MSG_LEN_PUBACK);
+                bytes.put_u8(MSG_TYPE_PUBACK);
+                put_u16_be(&mut bytes, publish.topic_id);
+                put_u16_be(&mut bytes, publish.msg_id);
+                bytes.put_u8(RETURN_CODE_ACCEPTED);
+                self.send(&bytes)?;
+            }
+            QOS_LEVEL_2 => {
+                self.send(&TestClient::msg_id_ack(
+                    MSG_LEN_PUBREC,
+                    MSG_TYPE_PUBREC,
+                    publish.msg_id,
+                ))?;
+                self.expect(MSG_TYPE_PUBREL)?;
+                self.send(&TestClient::msg_id_ack(
+                    MSG_LEN_PUBCOMP,
+                    MSG_TYPE_PUBCOMP,
+                    publish.msg_id,
+                ))?;
+            }
+            _ => {}
+        }
+        Ok(publish)
+    }
+
+    fn ack_register(&mut self, bytes: &Bytes) -> Result<(), String> {
+        let topic_id = TestClient::u16_at(bytes, 2);
+        let msg_id = TestClient::u16_at(bytes, 4);
+        let topic = String::from_utf8_lossy(&bytes[6..]).to_string();
+        self.registered.insert(topic_id, topic);
+        let mut regack = BytesMut::new();
+        regack.put_u8(MSG_LEN_REGACK);
+        regack.put_u8(MSG_TYPE_REGACK);
+        put_u16_be(&mut regack, topic_id);
+        put_u16_be(&mut regack, msg_id);
+        regack.put_u8(RETURN_CODE_ACCEPTED);
+        self.send(&regack)
+    }
+
+    fn next_msg_id(&mut self) -> u16 {
+        // msg_id 0 isn't used.
+        self.msg_id = self.msg_id.wrapping_add(1).max(1);
+        self.msg_id
+    }
+    /// Length and msg_type, len is the length of the variable part.
+    fn put_header(
+        bytes: &mut BytesMut,
+        len: usize,
+        msg_type: u8,
+    ) -> Result<(), String> {
+        MsgHeader::put_len(bytes, len + 2)?;
+        bytes.put_u8(msg_type);
+        Ok(())
+    }
+    fn msg_id_ack(len: u8, msg_type: u8, msg_id: u16) -> BytesMut {
+        let mut bytes = BytesMut::with_capacity(len as usize);
+        bytes.put_u8(len);
+        bytes.put_u8(msg_type);
+        put_u16_be(&mut bytes, msg_id);
+        bytes
+    }
+    fn msg_type(bytes: &[u8]) -> u8 {
+        if bytes[0] == 1 && bytes.len() > 3 {
+            bytes[3]
+        } else {
+            bytes[1]
+        }
+    }
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        get_u16_be(bytes, offset)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_loopback_end_to_end() {
+        use super::*;
+        use crate::flags::{QOS_LEVEL_0, RETAIN_FALSE};
+        let broker = LoopbackBroker::addr();
+        let mut subscriber = TestClient::new(broker).unwrap();
+        let mut publisher = TestClient::new(broker).unwrap();
+        assert_eq!(
+            subscriber.connect("loopbackSub", 60, None),
+            Ok(RETURN_CODE_ACCEPTED)
+        );
+        assert_eq!(
+            publisher.connect("loopbackPub", 60, None),
+            Ok(RETURN_CODE_ACCEPTED)
+        );
+        let (topic_id, return_code) =
+            subscriber.subscribe("loopback/qos", QOS_LEVEL_2).unwrap();
+        assert_eq!(return_code, RETURN_CODE_ACCEPTED);
+        assert_eq!(publisher.register("loopback/qos"), Ok(topic_id));
+        // QoS 1 and QoS 2 handshakes on both sides.
+        for &(qos, payload) in
+            [(QOS_LEVEL_1, "one"), (QOS_LEVEL_2, "two")].iter()
+        {
+            publisher
+                .publish(topic_id, qos, RETAIN_FALSE, payload.as_bytes())
+                .unwrap();
+            let publish = subscriber.recv_publish().unwrap();
+            assert_eq!(publish.topic_id, topic_id);
+            assert_eq!(publish.qos, qos);
+            assert_eq!(&publish.payload[..], payload.as_bytes());
+        }
+        // The messages to a sleeping client are sent on its PINGREQ.
+        subscriber.disconnect(Some(60)).unwrap();
+        publisher
+            .publish(topic_id, QOS_LEVEL_0, RETAIN_FALSE, b"asleep")
+            .unwrap();
+        let publishes = subscriber.ping(Some("loopbackSub")).unwrap();
+        assert_eq!(publishes.len(), 1);
+        assert_eq!(&publishes[0].payload[..], b"asleep");
+        publisher.disconnect(None).unwrap();
+    }
+
+    #[test]
+    fn test_loopback_will() {
+        use super::*;
+        use crate::flags::{QOS_LEVEL_0, RETAIN_FALSE};
+        let broker = LoopbackBroker::addr();
+        let mut watcher = TestClient::new(broker).unwrap();
+        let mut sensor = TestClient::new(broker).unwrap();
+        watcher.connect("loopbackWatcher", 60, None).unwrap();
+        let (topic_id, _) =
+            watcher.subscribe("loopback/will", QOS_LEVEL_0).unwrap();
+        let will = Will {
+            topic: "loopback/will".to_string(),
+            msg: "gone".to_string(),
+            qos: QOS_LEVEL_0,
+            retain: RETAIN_FALSE,
+        };
+        // The sensor goes silent, the keep alive expires after 1 second.
+        assert_eq!(
+            sensor.connect("loopbackSensor", 1, Some(&will)),
+            Ok(RETURN_CODE_ACCEPTED)
+        );
+        watcher.set_timeout(Duration::from_secs(5));
+        let publish = watcher.recv_publish().unwrap();
+        assert_eq!(publish.topic_id, topic_id);
+        assert_eq!(&publish.payload[..], b"gone");
+        watcher.disconnect(None).unwrap();
+    }
+
+    #[test]
+    fn test_loopback_rejections() {
+        use super::*;
+        use crate::flags::{QOS_LEVEL_0, QOS_LEVEL_1, RETAIN_FALSE};
+        use crate::{
+            MSG_TYPE_PUBACK, RETURN_CODE_INVALID_TOPIC_ID,
+            RETURN_CODE_NOT_SUPPORTED,
+        };
+        let broker = LoopbackBroker::addr();
+        let mut client = TestClient::new(broker).unwrap();
+        client.connect("loopbackReject", 60, None).unwrap();
+        // REGISTER assigns a topic id, filters are not supported.
+        let topic_id = client.register("loopback/reject").unwrap();
+        assert!(client.register("loopback/+").is_err());
+        assert_eq!(
+            client.subscribe("loopback/#/x", QOS_LEVEL_1),
+            Ok((0, RETURN_CODE_INVALID_TOPIC_ID))
+        );
+        client
+            .publish(topic_id, QOS_LEVEL_1, RETAIN_FALSE, b"known")
+            .unwrap();
+        // PUBACK with the topic id of the PUBLISH.
+        let bytes =
+            Publish::encode(0xFFF0, 1, QOS_LEVEL_1, RETAIN_FALSE, b"unknown")
+                .unwrap();
+        client.send(&bytes).unwrap();
+        let puback = client.expect(MSG_TYPE_PUBACK).unwrap();
+        assert_eq!(
+            &puback[2..],
+            &[0xFF, 0xF0, 0, 1, RETURN_CODE_INVALID_TOPIC_ID]
+        );
+        // QoS 0 too, msg_id 0.
+        let bytes =
+            Publish::encode(0xFFF1, 0, QOS_LEVEL_0, RETAIN_FALSE, b"unknown")
+                .unwrap();
+        client.send(&bytes).unwrap();
+        let puback = client.expect(MSG_TYPE_PUBACK).unwrap();
+        assert_eq!(
+            &puback[2..],
+            &[0xFF, 0xF1, 0, 0, RETURN_CODE_INVALID_TOPIC_ID]
+        );
+        // MQTT-SN 1.2 only.
+        let mut other = TestClient::new(broker).unwrap();
+        let connect = [8, MSG_TYPE_CONNECT, 0, 2, 0, 60, b'v', b'2'];
+        other.send(&connect).unwrap();
+        let connack = other.expect(MSG_TYPE_CONNACK).unwrap();
+        assert_eq!(connack[2], RETURN_CODE_NOT_SUPPORTED);
+        client.disconnect(None).unwrap();
+    }
+}
@@ -0,0 +1,279 @@
+/// Built-in test topics for field commissioning.
+/// Installers can verify link quality and QoS behavior from a handheld
+/// client without any backend.
+///
+/// $test/echo: the broker publishes the message back to the sender with
+///             the same QoS.
+/// $test/seq:  the payload is "count,interval_ms", the broker publishes
+///             count messages with the sequence number as payload,
+///             interval_ms apart, to the sender. A client runs one
+///             generator at a time, and a broker at most
+///             TEST_SEQ_MAX_GENERATORS, the other requests are rejected.
+///
+/// The topics are disabled by default, call TestTopics::enable(). They're
+/// enabled per broker, see BrokerContext.
+use bytes::Bytes;
+use hashbrown::HashSet;
+use log::*;
+use std::net::SocketAddr;
+use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::{
+    broker_context::BrokerContext,
+    broker_lib::MqttSnClient,
+    eformat,
+    filter::try_insert_topic_name,
+    flags::{flag_qos_level, QoSConst, QOS_LEVEL_1, QOS_LEVEL_3, RETAIN_FALSE},
+    function,
+    publish::Publish,
+    TopicIdType,
+};
+
+pub const TEST_TOPIC_ECHO: &str = "$test/echo";
+pub const TEST_TOPIC_SEQ: &str = "$test/seq";
+/// Limit the number of generated messages per request.
+pub const TEST_SEQ_MAX_COUNT: u32 = 1000;
+/// Minimum interval between generated messages.
+pub const TEST_SEQ_MIN_INTERVAL_MS: u64 = 10;
+/// Limit the number of running generators of a broker.
+pub const TEST_SEQ_MAX_GENERATORS: usize = 16;
+
+/// Test topics of a broker, see BrokerContext.
+#[derive(Default)]
+pub(crate) struct TestTopicsState {
+    enabled: AtomicBool,
+    /// (echo topic id, seq topic id)
+    topic_ids: Mutex<Option<(TopicIdType, TopicIdType)>>,
+    /// Clients with a running generator.
+    generators: Mutex<HashSet<SocketAddr>>,
+}
+
+#[inline(always)]
+fn state() -> &'static TestTopicsState {
+    &BrokerContext::current().test_topics
+}
+
+#[derive(Debug, Clone)]
+pub struct TestTopics {}
+
+impl TestTopics {
+    /// Register the test topic names and enable the test topics.
+    /// Returns the (echo, seq) topic ids.
+    pub fn enable() -> Result<(TopicIdType, TopicIdType), String> {
+        let echo_id = try_insert_topic_name(TEST_TOPIC_ECHO.to_string())?;
+        let seq_id = try_insert_topic_name(TEST_TOPIC_SEQ.to_string())?;
+        *state().topic_ids.lock().unwrap() = Some((echo_id, seq_id));
+        state().enabled.store(true, Ordering::Relaxed);
+        Ok((echo_id, seq_id))
+    }
+    /// The running generators stop before their next message.
+    pub fn disable() {
+        state().enabled.store(false, Ordering::Relaxed);
+    }
+    #[inline(always)]
+    pub fn is_enabled() -> bool {
+        state().enabled.load(Ordering::Relaxed)
+    }
+    /// Number of running generators.
+    pub fn generators() -> usize {
+        state().generators.lock().unwrap().len()
+    }
+
+    /// Handle a publish to the test topics.
+    /// Returns Ok(false) if the topic is not a test topic.
+    #[inline(always)]
+    pub fn try_handle(
+        publish: &Publish,
+        client: &MqttSnClient,
+        remote_addr: SocketAddr,
+    ) -> Result<bool, String> {
+        if !TestTopics::is_enabled() {
+            return Ok(false);
+        }
+        let (echo_id, seq_id) = match *state().topic_ids.lock().unwrap() {
+            Some(ids) => ids,
+            None => return Ok(false),
+        };
+        let topic_id = *publish.topic_id();
+        // Before the QoS check, e.g. a QoS -1 publish to another topic goes
+        // on to the subscribers.
+        if topic_id != echo_id && topic_id != seq_id {
+            return Ok(false);
+        }
+        let mut qos = flag_qos_level(*publish.flags());
+        if qos == QOS_LEVEL_3 {
+            // QoS -1 clients don't have a connection to reply to.
+            return Err(eformat!(
+                remote_addr,
+                "QoS -1 not supported",
+                topic_id
+            ));
+        }
+        if topic_id == echo_id {
+            Publish::send(
+                topic_id,
+                qos,
+                RETAIN_FALSE,
+                publish.data().clone(),
+                client,
+                remote_addr,
+            )?;
+            return Ok(true);
+        }
+        if topic_id == seq_id {
+            let (count, interval) = TestTopics::parse_seq(publish.data())
+                .ok_or_else(|| eformat!(remote_addr, "invalid seq request"))?;
+            // Generated messages use QoS 0 or 1 to keep the load predictable.
+            if qos > QOS_LEVEL_1 {
+                qos = QOS_LEVEL_1;
+            }
+            TestTopics::try_claim(remote_addr)?;
+            TestTopics::generate_seq(
+                seq_id,
+                qos,
+                count,
+                interval,
+                client.clone(),
+                remote_addr,
+            )?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Parse "count,interval_ms", both values are limited.
+    pub fn parse_seq(data: &[u8]) -> Option<(u32, u64)> {
+        let text = str::from_utf8(data).ok()?;
+        let mut iter = text.trim().split(',');
+        let count: u32 = iter.next()?.trim().parse().ok()?;
+        let interval: u64 = match iter.next() {
+            Some(val) => val.trim().parse().ok()?,
+            None => 1000,
+        };
+        if iter.next().is_some() || count == 0 {
+            return None;
+        }
+        Some((
+            count.min(TEST_SEQ_MAX_COUNT),
+            interval.max(TEST_SEQ_MIN_INTERVAL_MS),
+        ))
+    }
+
+    /// Reserve the generator of the client, within the limits.
+    fn try_claim(remote_addr: SocketAddr) -> Result<(), String> {
+        let mut generators = state().generators.lock().unwrap();
+        if generators.contains(&remote_addr) {
+            return Err(eformat!(remote_addr, "seq already running"));
+        }
+        if generators.len() >= TEST_SEQ_MAX_GENERATORS {
+            return Err(eformat!(
+                remote_addr,
+                "too many seq generators",
+                generators.len()
+            ));
+        }
+        generators.insert(remote_addr);
+        Ok(())
+    }
+
+    fn release(remote_addr: &SocketAddr) {
+        state().generators.lock().unwrap().remove(remote_addr);
+    }
+
+    fn generate_seq(
+        topic_id: TopicIdType,
+        qos: QoSConst,
+        count: u32,
+        interval: u64,
+        client: MqttSnClient,
+        remote_addr: SocketAddr,
+    ) -> Result<(), String> {
+        let builder = thread::Builder::new().name("test_seq_thread".into());
+        let spawned = builder.spawn(move || {
+            let _context = client.context.enter();
+            for seq in 1..=count {
+                if !TestTopics::is_enabled() {
+                    break;
+                }
+                let data = Bytes::from(seq.to_string());
+                if let Err(why) = Publish::send(
+                    topic_id,
+                    qos,
+                    RETAIN_FALSE,
+                    data,
+                    &client,
+                    remote_addr,
+                ) {
+                    error!("{}", why);
+                    break;
+                }
+                thread::sleep(Duration::from_millis(interval));
+            }
+            TestTopics::release(&remote_addr);
+        });
+        if let Err(why) = spawned {
+            TestTopics::release(&remote_addr);
+            return Err(eformat!(remote_addr, why));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_parse_seq() {
+        use super::*;
+        assert_eq!(TestTopics::parse_seq(b"10,100"), Some((10, 100)));
+        assert_eq!(TestTopics::parse_seq(b" 5 "), Some((5, 1000)));
+        assert_eq!(
+            TestTopics::parse_seq(b"100000,1"),
+            Some((TEST_SEQ_MAX_COUNT, TEST_SEQ_MIN_INTERVAL_MS))
+        );
+        assert_eq!(TestTopics::parse_seq(b"0,100"), None);
+        assert_eq!(TestTopics::parse_seq(b"a,b"), None);
+        assert_eq!(TestTopics::parse_seq(b"1,2,3"), None);
+    }
+
+    #[test]
+    fn test_try_handle_other_topic() {
+        use super::*;
+        let client = MqttSnClient::new();
+        let addr = "127.0.0.1:1950".parse::<SocketAddr>().unwrap();
+        let (echo_id, _seq_id) = TestTopics::enable().unwrap();
+        let topic_id =
+            try_insert_topic_name("test_topics/other".to_string()).unwrap();
+        // Not a test topic, whatever the QoS.
+        let publish =
+            Publish::new(topic_id, 0, QOS_LEVEL_3, RETAIN_FALSE, "21.5");
+        assert_eq!(TestTopics::try_handle(&publish, &client, addr), Ok(false));
+        // QoS -1 to a test topic, no connection to reply to.
+        let publish = Publish::new(echo_id, 0, QOS_LEVEL_3, RETAIN_FALSE, "x");
+        assert!(TestTopics::try_handle(&publish, &client, addr).is_err());
+        TestTopics::disable();
+    }
+
+    #[test]
+    fn test_seq_generator_limits() {
+        use super::*;
+        // Not seen by the brokers of the other tests.
+        let _context = BrokerContext::new().enter();
+        let addr = "127.0.0.1:1951".parse::<SocketAddr>().unwrap();
+        TestTopics::try_claim(addr).unwrap();
+        // One generator per client.
+        assert!(TestTopics::try_claim(addr).is_err());
+        for port in 1..TEST_SEQ_MAX_GENERATORS as u16 {
+            let other = SocketAddr::new(addr.ip(), 2000 + port);
+            TestTopics::try_claim(other).unwrap();
+        }
+        assert_eq!(TestTopics::generators(), TEST_SEQ_MAX_GENERATORS);
+        let other = "127.0.0.1:1952".parse::<SocketAddr>().unwrap();
+        assert!(TestTopics::try_claim(other).is_err());
+        TestTopics::release(&addr);
+        TestTopics::try_claim(other).unwrap();
+    }
+}
@@ -0,0 +1,313 @@
+/// Hierarchical timer wheel of the keep-alive and retransmit timers.
+///
+/// A timer is a key, a deadline in ticks and a value. The wheel has
+/// TIMER_WHEEL_LEVELS levels of TIMER_WHEEL_SLOTS slots, a slot of level n
+/// spans TIMER_WHEEL_SLOTS^n ticks. A timer is put in the lowest level that
+/// reaches its deadline and moves down a level when the wheel gets to its
+/// slot, 2^24 ticks are covered by 256 slots instead of a slot per tick.
+/// The timers are in a HashMap indexed by the key, insert and cancel are
+/// O(1): a canceled or rescheduled timer stays in its slot and is skipped
+/// when its deadline doesn't match the HashMap entry.
+///
+/// The tick is the resolution of the timers. The precision coalesces them,
+/// the deadlines are rounded up to a multiple of the precision, the timers
+/// of many connections fall in fewer slots. A broker owns a wheel per timer
+/// kind in its BrokerContext, see KeepAliveTimeWheel and RetransTimeWheel,
+/// configured with MqttSnClient::with_timer_config(). The ticks elapse on
+/// the Clock of the wheel, SystemClock by default.
+use core::hash::Hash;
+use hashbrown::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, SystemClock};
+
+pub const TIMER_WHEEL_SLOT_BITS: u32 = 6;
+pub const TIMER_WHEEL_SLOTS: usize = 1 << TIMER_WHEEL_SLOT_BITS;
+pub const TIMER_WHEEL_LEVELS: usize = 4;
+/// Ticks covered by the wheel, later deadlines wait in the last level.
+pub const TIMER_WHEEL_RANGE: u64 =
+    1 << (TIMER_WHEEL_SLOT_BITS * TIMER_WHEEL_LEVELS as u32);
+const SLOT_MASK: u64 = TIMER_WHEEL_SLOTS as u64 - 1;
+const MIN_TICK: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimerWheelConfig {
+    /// Duration of a tick, the resolution of the timers.
+    pub tick: Duration,
+    /// The deadlines are rounded up to a multiple of the precision.
+    pub precision: Duration,
+}
+
+impl Default for TimerWheelConfig {
+    /// 100 ms ticks, no coalescing.
+    fn default() -> Self {
+        TimerWheelConfig {
+            tick: Duration::from_millis(100),
+            precision: Duration::from_millis(100),
+        }
+    }
+}
+
+impl TimerWheelConfig {
+    pub fn new(tick: Duration, precision: Duration) -> Self {
+        TimerWheelConfig { tick, precision }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Timer<V> {
+    deadline: u64,
+    value: V,
+}
+
+#[derive(Debug)]
+pub struct TimerWheel<K, V> {
+    config: TimerWheelConfig,
+    /// Ticks of the precision, at least 1.
+    granularity: u64,
+    /// The next tick to process.
+    now: u64,
+    /// Slots of each level, the entries are the key and the deadline.
+    levels: Vec<Vec<Vec<(K, u64)>>>,
+    timers: HashMap<K, Timer<V>>,
+    clock: Arc<dyn Clock>,
+    /// Instant of the origin_tick on the clock.
+    origin: Instant,
+    origin_tick: u64,
+}
+
+impl<K: Hash + Eq + Copy, V> TimerWheel<K, V> {
+    pub fn new(config: TimerWheelConfig) -> Self {
+        let mut wheel = TimerWheel {
+            config,
+            granularity: 1,
+            now: 0,
+            levels: (0..TIMER_WHEEL_LEVELS)
+                .map(|_| (0..TIMER_WHEEL_SLOTS).map(|_| Vec::new()).collect())
+                .collect(),
+            timers: HashMap::new(),
+            clock: Arc::new(SystemClock {}),
+            origin: Instant::now(),
+            origin_tick: 0,
+        };
+        wheel.set_config(config);
+        wheel
+    }
+    /// Replace the clock, the next tick is due one tick from its now.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+        self.reset_origin();
+    }
+    /// Change the tick and the precision, the scheduled timers keep their
+    /// deadline in ticks.
+    pub fn set_config(&mut self, config: TimerWheelConfig) {
+        self.config = TimerWheelConfig {
+            tick: config.tick.max(MIN_TICK),
+            precision: config.precision,
+        };
+        self.granularity = self.ticks(config.precision).max(1);
+        self.reset_origin();
+    }
+    pub fn config(&self) -> TimerWheelConfig {
+        self.config
+    }
+    #[inline(always)]
+    pub fn tick(&self) -> Duration {
+        self.config.tick
+    }
+    /// The next tick to process.
+    #[inline(always)]
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+    /// True if the clock reached the end of the next tick, advance() it.
+    #[inline(always)]
+    pub fn is_due(&self) -> bool {
+        let elapsed = self.clock.now().saturating_duration_since(self.origin);
+        let elapsed = elapsed.as_nanos() / self.config.tick.as_nanos();
+        self.origin_tick + elapsed as u64 > self.now
+    }
+    /// The duration in ticks, rounded up.
+    #[inline(always)]
+    pub fn ticks(&self, duration: Duration) -> u64 {
+        let tick = self.config.tick.as_nanos();
+        ((duration.as_nanos() + tick - 1) / tick) as u64
+    }
+    pub fn len(&self) -> usize {
+        self.timers.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.timers.is_empty()
+    }
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.timers.contains_key(key)
+    }
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.timers.get(key).map(|timer| &timer.value)
+    }
+    /// The value can be changed, not the deadline.
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.timers.get_mut(key).map(|timer| &mut timer.value)
+    }
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.timers.keys()
+    }
+    /// The deadline of the timer of the key.
+    pub fn deadline(&self, key: &K) -> Option<u64> {
+        self.timers.get(key).map(|timer| timer.deadline)
+    }
+    /// Schedule the timer of the key delay ticks from now, the previous
+    /// timer of the key is replaced, its value returned.
+    #[inline(always)]
+    pub fn insert(&mut self, key: K, delay: u64, value: V) -> Option<V> {
+        self.insert_at(key, self.now + delay, value)
+    }
+    /// Schedule the timer of the key at the deadline tick, a past deadline
+    /// expires with the next tick.
+    pub fn insert_at(&mut self, key: K, deadline: u64, value: V) -> Option<V> {
+        let deadline = self.coalesce(deadline).max(self.now);
+        self.place(key, deadline);
+        self.timers
+            .insert(key, Timer { deadline, value })
+            .map(|timer| timer.value)
+    }
+    /// Cancel the timer of the key, its slot entry is skipped.
+    #[inline(always)]
+    pub fn cancel(&mut self, key: &K) -> Option<V> {
+        self.timers.remove(key).map(|timer| timer.value)
+    }
+    /// Keep the timers for which f returns true.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        self.timers.retain(|key, timer| f(key, &mut timer.value));
+    }
+    /// Process the current tick, the expired timers are removed and
+    /// returned.
+    pub fn advance(&mut self) -> Vec<(K, V)> {
+        let now = self.now;
+        // The upper levels first, a timer can move down several levels.
+        for level in (1..TIMER_WHEEL_LEVELS).rev() {
+            let span = TIMER_WHEEL_SLOT_BITS * level as u32;
+            if now & ((1 << span) - 1) != 0 {
+                continue;
+            }
+            let index = ((now >> span) & SLOT_MASK) as usize;
+            let entries = std::mem::take(&mut self.levels[level][index]);
+            for (key, deadline) in entries {
+                if self.is_live(&key, deadline) {
+                    self.place(key, deadline);
+                }
+            }
+        }
+        let index = (now & SLOT_MASK) as usize;
+        let entries = std::mem::take(&mut self.levels[0][index]);
+        let mut expired = Vec::new();
+        for (key, deadline) in entries {
+            if !self.is_live(&key, deadline) {
+                continue;
+            }
+            if let Some(timer) = self.timers.remove(&key) {
+                expired.push((key, timer.value));
+            }
+        }
+        self.now += 1;
+        expired
+    }
+
+    fn reset_origin(&mut self) {
+        self.origin = self.clock.now();
+        self.origin_tick = self.now;
+    }
+
+    #[inline(always)]
+    fn coalesce(&self, deadline: u64) -> u64 {
+        let granularity = self.granularity;
+        (deadline + granularity - 1) / granularity * granularity
+    }
+
+    #[inline(always)]
+    fn is_live(&self, key: &K, deadline: u64) -> bool {
+        match self.timers.get(key) {
+            Some(timer) => timer.deadline == deadline,
+            None => false,
+        }
+    }
+
+    /// Put the entry in the lowest level reaching the deadline.
+    #[inline(always)]
+    fn place(&mut self, key: K, deadline: u64) {
+        let delta = deadline.saturating_sub(self.now);
+        let mut level = 0;
+        while level + 1 < TIMER_WHEEL_LEVELS
+            && delta >> (TIMER_WHEEL_SLOT_BITS * (level + 1) as u32) != 0
+        {
+            level += 1;
+        }
+        // Beyond the range, moved down again at the end of the range.
+        let target = deadline.min(self.now + TIMER_WHEEL_RANGE - 1);
+        let span = TIMER_WHEEL_SLOT_BITS * level as u32;
+        let index = ((target >> span) & SLOT_MASK) as usize;
+        self.levels[level][index].push((key, deadline));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_timer_wheel() {
+        use super::*;
+        let mut wheel: TimerWheel<u32, &str> =
+            TimerWheel::new(TimerWheelConfig::default());
+        assert_eq!(wheel.ticks(Duration::from_secs(3)), 30);
+        assert_eq!(wheel.ticks(Duration::from_millis(150)), 2);
+        // Every level, and beyond the range.
+        let delays = [0, 1, 63, 64, 65, 4095, 4096, 300_000, TIMER_WHEEL_RANGE];
+        for (key, delay) in delays.iter().enumerate() {
+            assert!(wheel.insert(key as u32, *delay, "x").is_none());
+        }
+        // Canceled and rescheduled timers.
+        wheel.insert(100, 10, "canceled");
+        assert_eq!(wheel.cancel(&100), Some("canceled"));
+        wheel.insert(101, 70, "old");
+        assert_eq!(wheel.insert(101, 5, "new"), Some("old"));
+        let mut expired = Vec::new();
+        for _ in 0..=TIMER_WHEEL_RANGE {
+            let now = wheel.now();
+            for (key, value) in wheel.advance() {
+                expired.push((key, now, value));
+            }
+        }
+        let mut expected: Vec<(u32, u64, &str)> = delays
+            .iter()
+            .enumerate()
+            .map(|(key, delay)| (key as u32, *delay, "x"))
+            .collect();
+        expected.insert(1, (101, 5, "new"));
+        assert_eq!(expected.len(), 10);
+        expected.sort_by_key(|(key, now, _)| (*now, *key));
+        expired.sort_by_key(|(key, now, _)| (*now, *key));
+        assert_eq!(expired, expected);
+        assert!(wheel.is_empty());
+        // Coalesced to a multiple of 500 ms.
+        let config = TimerWheelConfig::new(
+            Duration::from_millis(100),
+            Duration::from_millis(500),
+        );
+        let mut wheel: TimerWheel<u32, ()> = TimerWheel::new(config);
+        wheel.insert(1, 1, ());
+        wheel.insert(2, 4, ());
+        wheel.insert(3, 6, ());
+        assert_eq!(wheel.deadline(&1), Some(5));
+        assert_eq!(wheel.deadline(&2), Some(5));
+        assert_eq!(wheel.deadline(&3), Some(10));
+        wheel.retain(|key, _| *key != 3);
+        let expired: Vec<u32> = (0..=10)
+            .flat_map(|_| wheel.advance())
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(expired.len(), 2);
+    }
+}
@@ -0,0 +1,136 @@
+/// Payload metadata of the topics, e.g. for a gateway bridging to HTTP or
+/// Kafka.
+///
+/// A topic name or a topic filter has a content type and an encoding, set
+/// in the [topic_metadata] table of the broker configuration:
+///
+/// [topic_metadata."sensors/+/temp"]
+/// content_type = "application/json"
+/// encoding = "utf-8"
+///
+/// or with MqttSnClient::set_topic_metadata(). The entries of the API are
+/// kept when the configuration is reloaded and go before the configured
+/// ones. The metadata of a topic is the entry of the topic name, otherwise
+/// the one of the first matching filter in lexical order. RichPublish
+/// carries the metadata of its topic, the MQTT-SN messages don't.
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+use crate::{
+    eformat,
+    filter::{match_topic, valid_filter},
+    function,
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct TopicMetadata {
+    /// MIME type of the payload, e.g. "application/json".
+    pub content_type: Option<String>,
+    /// Character set or content encoding of the payload, e.g. "utf-8".
+    pub encoding: Option<String>,
+}
+
+type MetadataTable = BTreeMap<String, TopicMetadata>;
+
+lazy_static! {
+    static ref CONFIGURED: RwLock<Arc<MetadataTable>> =
+        RwLock::new(Arc::new(BTreeMap::new()));
+    static ref API: RwLock<Arc<MetadataTable>> =
+        RwLock::new(Arc::new(BTreeMap::new()));
+}
+
+impl TopicMetadata {
+    /// The metadata of the topic name, None if no entry matches.
+    pub fn get(topic: &str) -> Option<TopicMetadata> {
+        let api = API.read().unwrap().clone();
+        let configured = CONFIGURED.read().unwrap().clone();
+        TopicMetadata::lookup(&api, topic)
+            .or_else(|| TopicMetadata::lookup(&configured, topic))
+    }
+
+    /// Set the metadata of the topic name or filter, the previous one is
+    /// returned.
+    pub fn set(
+        filter: &str,
+        metadata: TopicMetadata,
+    ) -> Result<Option<TopicMetadata>, String> {
+        if !valid_filter(filter) {
+            return Err(eformat!(filter, "invalid filter"));
+        }
+        let mut api = API.write().unwrap();
+        Ok(Arc::make_mut(&mut *api).insert(filter.to_string(), metadata))
+    }
+
+    /// Remove the metadata set with set(), the configured one is kept.
+    pub fn remove(filter: &str) -> Option<TopicMetadata> {
+        let mut api = API.write().unwrap();
+        Arc::make_mut(&mut *api).remove(filter)
+    }
+
+    /// Check the configured table, see BrokerConfig::apply().
+    pub fn validate(table: &MetadataTable) -> Result<(), String> {
+        match table.keys().find(|filter| !valid_filter(filter)) {
+            Some(filter) => Err(eformat!(filter, "invalid filter")),
+            None => Ok(()),
+        }
+    }
+
+    /// Replace the configured table.
+    pub fn replace(table: MetadataTable) {
+        *CONFIGURED.write().unwrap() = Arc::new(table);
+    }
+
+    fn lookup(table: &MetadataTable, topic: &str) -> Option<TopicMetadata> {
+        if table.is_empty() {
+            return None;
+        }
+        table.get(topic).cloned().or_else(|| {
+            table
+                .iter()
+                .find(|(filter, _)| match_topic(topic, filter))
+                .map(|(_, metadata)| metadata.clone())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_topic_metadata() {
+        use super::*;
+        let json = TopicMetadata {
+            content_type: Some("application/json".to_string()),
+            encoding: Some("utf-8".to_string()),
+        };
+        let csv = TopicMetadata {
+            content_type: Some("text/csv".to_string()),
+            encoding: None,
+        };
+        assert_eq!(
+            TopicMetadata::set("metadata/+/temp", json.clone()),
+            Ok(None)
+        );
+        assert_eq!(TopicMetadata::get("metadata/a/temp"), Some(json.clone()));
+        assert_eq!(TopicMetadata::get("metadata/a/humidity"), None);
+        // The entry of the topic name goes before the filter.
+        assert_eq!(
+            TopicMetadata::set("metadata/b/temp", csv.clone()),
+            Ok(None)
+        );
+        assert_eq!(TopicMetadata::get("metadata/b/temp"), Some(csv.clone()));
+        assert_eq!(TopicMetadata::get("metadata/a/temp"), Some(json.clone()));
+        assert!(TopicMetadata::set("metadata/b+", csv.clone()).is_err());
+        assert_eq!(TopicMetadata::remove("metadata/b/temp"), Some(csv));
+        assert_eq!(TopicMetadata::get("metadata/b/temp"), Some(json.clone()));
+        assert_eq!(
+            TopicMetadata::remove("metadata/+/temp"),
+            Some(json.clone())
+        );
+        assert_eq!(TopicMetadata::get("metadata/b/temp"), None);
+        let mut bad = BTreeMap::new();
+        bad.insert("metadata/#/temp".to_string(), json);
+        assert!(TopicMetadata::validate(&bad).is_err());
+    }
+}
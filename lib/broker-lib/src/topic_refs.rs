@@ -0,0 +1,242 @@
+/// Reference counting and reclamation of the topic ids.
+///
+/// A topic id is referenced by the subscriptions, the REGISTER of a client,
+/// the will of a client, a retained message, a last value, or the broker
+/// itself. The ids assigned by the broker with try_insert_topic_name() are
+/// pinned and never reclaimed. When the last reference is released, the id
+/// waits TOPIC_ID_GRACE_SECS for the messages in flight and for a client
+/// that reconnects, then collect() removes its topic name and the allocator
+/// can assign the id again. collect() runs before a new topic name is
+/// inserted.
+///
+/// The lock order is CONN_HASHMAP, RETAIN_STORE or LAST_VALUES, TOPIC_REFS,
+/// then the topic name map. TOPIC_REFS is held while a topic name is
+/// inserted, a topic id can't be reclaimed between the lookup and the new
+/// reference.
+use hashbrown::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{
+    broker_context::BrokerContext,
+    filter::{insert_topic_name, remove_topic_id},
+    TopicIdType,
+};
+
+/// Seconds between the last release and the reclamation.
+pub const TOPIC_ID_GRACE_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TopicRef {
+    Subscription(SocketAddr),
+    Registration(SocketAddr),
+    Will(SocketAddr),
+    Retained,
+    LastValue,
+    /// Assigned by the broker, never released.
+    Broker,
+}
+
+impl TopicRef {
+    #[inline(always)]
+    fn is_client(&self, addr: &SocketAddr) -> bool {
+        match self {
+            TopicRef::Registration(socket_addr)
+            | TopicRef::Will(socket_addr) => socket_addr == addr,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct RefTable {
+    refs: HashMap<TopicIdType, HashSet<TopicRef>>,
+    /// Topic ids without references since the instant.
+    released: HashMap<TopicIdType, Instant>,
+}
+
+impl RefTable {
+    fn acquire(&mut self, topic_id: TopicIdType, topic_ref: TopicRef) {
+        self.released.remove(&topic_id);
+        self.refs.entry(topic_id).or_default().insert(topic_ref);
+    }
+    fn release(
+        &mut self,
+        topic_id: TopicIdType,
+        topic_ref: &TopicRef,
+        now: Instant,
+    ) {
+        if let Some(topic_refs) = self.refs.get_mut(&topic_id) {
+            if topic_refs.remove(topic_ref) && topic_refs.is_empty() {
+                self.refs.remove(&topic_id);
+                self.released.insert(topic_id, now);
+            }
+        }
+    }
+    /// Reclaim the topic ids released before now - grace.
+    fn collect(&mut self, now: Instant) -> Vec<TopicIdType> {
+        let grace = Duration::from_secs(TOPIC_ID_GRACE_SECS);
+        let topic_ids: Vec<TopicIdType> = self
+            .released
+            .iter()
+            .filter(|(_, released)| now.duration_since(**released) >= grace)
+            .map(|(topic_id, _)| *topic_id)
+            .collect();
+        for topic_id in topic_ids.iter() {
+            self.released.remove(topic_id);
+            remove_topic_id(*topic_id);
+        }
+        topic_ids
+    }
+}
+
+/// Topic refs of a broker, see BrokerContext.
+#[derive(Default)]
+pub(crate) struct TopicRefsState {
+    topic_refs: Mutex<RefTable>,
+}
+
+#[inline(always)]
+fn state() -> &'static TopicRefsState {
+    &BrokerContext::current().topic_refs
+}
+
+#[derive(Debug, Clone)]
+pub struct TopicRefs {}
+
+impl TopicRefs {
+    /// The topic id of the topic name, a new id if the name is new, with
+    /// the reference.
+    pub fn insert(
+        topic_name: String,
+        topic_ref: TopicRef,
+    ) -> Result<TopicIdType, String> {
+        let mut table = state().topic_refs.lock().unwrap();
+        if !table.released.is_empty() {
+            table.collect(Instant::now());
+        }
+        let topic_id = insert_topic_name(topic_name)?;
+        table.acquire(topic_id, topic_ref);
+        Ok(topic_id)
+    }
+    pub fn acquire(topic_id: TopicIdType, topic_ref: TopicRef) {
+        state()
+            .topic_refs
+            .lock()
+            .unwrap()
+            .acquire(topic_id, topic_ref);
+    }
+    pub fn release(topic_id: TopicIdType, topic_ref: TopicRef) {
+        state().topic_refs.lock().unwrap().release(
+            topic_id,
+            &topic_ref,
+            Instant::now(),
+        );
+    }
+    /// Release the registrations and the will of a removed connection,
+    /// the subscriptions are released with the subscription map.
+    pub fn release_client(socket_addr: &SocketAddr) {
+        let now = Instant::now();
+        let mut table = state().topic_refs.lock().unwrap();
+        let client_refs: Vec<(TopicIdType, TopicRef)> = table
+            .refs
+            .iter()
+            .flat_map(|(topic_id, topic_refs)| {
+                topic_refs
+                    .iter()
+                    .filter(|topic_ref| topic_ref.is_client(socket_addr))
+                    .map(move |topic_ref| (*topic_id, *topic_ref))
+            })
+            .collect();
+        for (topic_id, topic_ref) in client_refs {
+            table.release(topic_id, &topic_ref, now);
+        }
+    }
+    /// Move the registrations and the will of the client to its new
+    /// address, see Connection::rebind().
+    pub fn rebind_client(old_addr: &SocketAddr, new_addr: SocketAddr) {
+        let mut table = state().topic_refs.lock().unwrap();
+        for topic_refs in table.refs.values_mut() {
+            let client_refs: Vec<TopicRef> = topic_refs
+                .iter()
+                .filter(|topic_ref| topic_ref.is_client(old_addr))
+                .copied()
+                .collect();
+            for topic_ref in client_refs {
+                topic_refs.remove(&topic_ref);
+                topic_refs.insert(match topic_ref {
+                    TopicRef::Will(_) => TopicRef::Will(new_addr),
+                    _ => TopicRef::Registration(new_addr),
+                });
+            }
+        }
+    }
+    /// True if the client registered the topic id, subscribed to its topic
+    /// name, or was sent a REGISTER with it.
+    pub fn is_known(topic_id: TopicIdType, socket_addr: &SocketAddr) -> bool {
+        match state().topic_refs.lock().unwrap().refs.get(&topic_id) {
+            Some(topic_refs) => {
+                topic_refs.contains(&TopicRef::Registration(*socket_addr))
+                    || topic_refs
+                        .contains(&TopicRef::Subscription(*socket_addr))
+            }
+            None => false,
+        }
+    }
+    /// Number of references of the topic id.
+    pub fn count(topic_id: TopicIdType) -> usize {
+        match state().topic_refs.lock().unwrap().refs.get(&topic_id) {
+            Some(topic_refs) => topic_refs.len(),
+            None => 0,
+        }
+    }
+    /// Reclaim the topic ids released more than TOPIC_ID_GRACE_SECS ago.
+    /// Returns the reclaimed topic ids.
+    pub fn collect() -> Vec<TopicIdType> {
+        state().topic_refs.lock().unwrap().collect(Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_topic_refs() {
+        use super::*;
+        use crate::filter::{
+            get_topic_id_with_topic_name, try_insert_topic_name,
+        };
+        let addr = "127.0.0.1:1300".parse::<SocketAddr>().unwrap();
+        let topic_id = TopicRefs::insert(
+            "topic_refs/churn".to_string(),
+            TopicRef::Subscription(addr),
+        )
+        .unwrap();
+        TopicRefs::acquire(topic_id, TopicRef::Registration(addr));
+        TopicRefs::acquire(topic_id, TopicRef::Registration(addr));
+        assert_eq!(TopicRefs::count(topic_id), 2);
+        TopicRefs::release(topic_id, TopicRef::Subscription(addr));
+        TopicRefs::release_client(&addr);
+        assert_eq!(TopicRefs::count(topic_id), 0);
+        // Kept during the grace period, a new reference cancels it.
+        assert!(!TopicRefs::collect().contains(&topic_id));
+        TopicRefs::acquire(topic_id, TopicRef::Retained);
+        TopicRefs::release(topic_id, TopicRef::Retained);
+        let later = Instant::now() + Duration::from_secs(TOPIC_ID_GRACE_SECS);
+        assert!(state()
+            .topic_refs
+            .lock()
+            .unwrap()
+            .collect(later)
+            .contains(&topic_id));
+        assert_eq!(
+            get_topic_id_with_topic_name("topic_refs/churn".to_string()),
+            None
+        );
+        // Pinned by the broker.
+        let pinned_id =
+            try_insert_topic_name("topic_refs/pinned".to_string()).unwrap();
+        TopicRefs::release(pinned_id, TopicRef::Subscription(addr));
+        assert_eq!(TopicRefs::count(pinned_id), 1);
+    }
+}
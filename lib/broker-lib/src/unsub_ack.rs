@@ -9,8 +9,8 @@ message. Its format is illustrated in Table 21:
 • MsgId: same value as the one contained in the corresponding UNSUBSCRIBE message.
 */
 use crate::{
-    broker_lib::MqttSnClient, eformat, function, msg_hdr::MsgHeader,
-    retransmit::RetransTimeWheel, MSG_LEN_UNSUBACK, MSG_TYPE_UNSUBACK,
+    broker_lib::MqttSnClient, codec, decode::Decode, eformat, function,
+    msg_hdr::MsgHeader, retransmit::RetransTimeWheel, MSG_LEN_UNSUBACK,
 };
 use bytes::{BufMut, BytesMut};
 use custom_debug::Debug;
@@ -27,6 +27,8 @@ pub struct UnsubAck {
     pub msg_id: u16,
 }
 
+codec!(UnsubAck, { msg_id: u16 });
+
 impl UnsubAck {
     pub fn recv(
         buf: &[u8],
@@ -34,7 +36,8 @@ impl UnsubAck {
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
-        let (unsub_ack, read_len) = UnsubAck::try_read(buf, size).unwrap();
+        let (unsub_ack, read_len) =
+            Decode::read(UnsubAck::try_read(buf, size), size, &msg_header)?;
         dbg!(unsub_ack.clone());
         let remote_socket_addr = msg_header.remote_socket_addr;
 
@@ -42,7 +45,6 @@ impl UnsubAck {
             match RetransTimeWheel::cancel_timer(
                 remote_socket_addr,
                 unsub_ack.msg_type,
-                0,
                 unsub_ack.msg_id,
             ) {
                 Ok(_) => Ok(()),
@@ -58,14 +60,7 @@ impl UnsubAck {
         msg_id: u16,
     ) -> Result<(), String> {
         let remote_socket_addr = msg_header.remote_socket_addr;
-        let mut bytes_buf = BytesMut::with_capacity(MSG_LEN_UNSUBACK as usize);
-        let unsub_ack = UnsubAck {
-            len: MSG_LEN_UNSUBACK,
-            msg_type: MSG_TYPE_UNSUBACK,
-            msg_id,
-        };
-        dbg!(unsub_ack.clone());
-        unsub_ack.try_write(&mut bytes_buf);
+        let bytes_buf = UnsubAck::encode(msg_id);
         dbg!(bytes_buf.clone());
         dbg!(remote_socket_addr);
         // transmit to network
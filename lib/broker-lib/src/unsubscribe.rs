@@ -29,8 +29,15 @@ extern crate trace_caller;
 use trace_caller::trace;
 
 use crate::{
-    broker_lib::MqttSnClient, eformat, filter::*, flags::*, function,
-    msg_hdr::*, retransmit::RetransTimeWheel, MSG_LEN_UNSUBSCRIBE_HEADER,
+    broker_lib::MqttSnClient,
+    codec::{encode_packet, get_u16_be, Packet, Topic},
+    decode::Decode,
+    eformat,
+    filter::*,
+    flags::*,
+    function,
+    msg_hdr::*,
+    retransmit::RetransTimeWheel,
     MSG_TYPE_UNSUBACK, MSG_TYPE_UNSUBSCRIBE,
 };
 
@@ -95,25 +102,7 @@ impl Unsubscribe {
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
-        let unsubscribe: Unsubscribe;
-        let _read_fixed_len: usize;
-        match msg_header.header_len {
-            MsgHeaderLenEnum::Short =>
-            // TODO replace unwrap
-            {
-                (unsubscribe, _read_fixed_len) =
-                    Unsubscribe::try_read(buf, size).unwrap()
-            }
-            MsgHeaderLenEnum::Long =>
-            // TODO replace unwrap
-            // For the 4-byte header, parse the body ignoring the first 2 bytes and
-            // don't use the length field for the unsubscribe struct.
-            // Use the length field from the msg_header.
-            {
-                (unsubscribe, _read_fixed_len) =
-                    Unsubscribe::try_read(&buf[3..], size).unwrap()
-            }
-        }
+        let unsubscribe = Unsubscribe::read(buf, size, &msg_header)?;
         let remote_socket_addr = msg_header.remote_socket_addr;
         dbg!(unsubscribe.clone());
         match flag_topic_id_type(unsubscribe.flags) {
@@ -124,24 +113,11 @@ impl Unsubscribe {
                 )?;
             }
             TOPIC_ID_TYPE_PRE_DEFINED => {
-                match unsubscribe.topic_name.parse::<u16>() {
-                    Ok(topic_id) => {
-                        dbg!(topic_id);
-                        unsubscribe_with_topic_id(
-                            remote_socket_addr,
-                            topic_id,
-                        )?;
-                        return Ok(());
-                    }
-                    Err(err) => {
-                        return Err(eformat!(
-                            remote_socket_addr,
-                            "error parsing topic_id",
-                            err,
-                            unsubscribe.topic_name
-                        ));
-                    }
-                }
+                // 2 bytes in the topic name field, not UTF-8.
+                let topic_id = Decode::topic_id(buf, 5, size, &msg_header)?;
+                dbg!(topic_id);
+                unsubscribe_with_topic_id(remote_socket_addr, topic_id)?;
+                return Ok(());
             }
             TOPIC_ID_TYPE_SHORT => {
                 return Err(eformat!(
@@ -164,6 +140,32 @@ impl Unsubscribe {
         }
         Ok(())
     }
+
+    /// UNSUBSCRIBE from the short view of the datagram, see
+    /// MsgHeader::short_view(). The topic name is empty unless the topic id
+    /// type is normal, see Decode.
+    fn read(
+        buf: &[u8],
+        size: usize,
+        msg_header: &MsgHeader,
+    ) -> Result<Unsubscribe, String> {
+        // flags and msg_id follow the header, then the topic name.
+        let offset = 2;
+        Decode::check_len(buf, size, offset + 3, msg_header)?;
+        let flags = buf[offset];
+        let topic_name = if flag_topic_id_type(flags) == TOPIC_ID_TYPE_NORMAL {
+            Decode::utf8(buf, offset + 3, size, msg_header)?.to_string()
+        } else {
+            String::new()
+        };
+        Ok(Unsubscribe {
+            len: msg_header.len as u8,
+            msg_type: MSG_TYPE_UNSUBSCRIBE,
+            flags,
+            msg_id: get_u16_be(buf, offset + 1),
+            topic_name,
+        })
+    }
     #[inline(always)]
     #[trace]
     pub fn send(
@@ -175,34 +177,33 @@ impl Unsubscribe {
         msg_header: MsgHeader,
     ) -> Result<(), String> {
         let remote_socket_addr = msg_header.remote_socket_addr;
-        if topic.len() + (MSG_LEN_UNSUBSCRIBE_HEADER as usize) < 256 {
-            let unsubscribe = Unsubscribe::new(qos, retain, msg_id, topic);
-            dbg!(&unsubscribe);
-            let mut bytes_buf =
-                BytesMut::with_capacity(unsubscribe.len as usize);
-            unsubscribe.try_write(&mut bytes_buf);
-            // transmit to network
-            if let Err(err) = client
-                .egress_tx
-                .try_send((remote_socket_addr, bytes_buf.to_owned()))
-            {
-                return Err(eformat!(remote_socket_addr, err));
-            }
-            // schedule retransmit
-            // Unsuback returns the msg_id, but not topic_id.
-            match RetransTimeWheel::schedule_timer(
-                remote_socket_addr,
-                MSG_TYPE_UNSUBACK,
-                0,
-                msg_id,
-                1,
-                bytes_buf,
-            ) {
-                Ok(()) => Ok(()),
-                Err(err) => Err(err),
-            }
-        } else {
-            Err(eformat!(remote_socket_addr, "topic name too long"))
+        let unsubscribe = Unsubscribe::new(qos, retain, msg_id, topic);
+        dbg!(&unsubscribe);
+        // The u8 len of Unsubscribe::new() is only valid below 256.
+        let bytes_buf = encode_packet(&Packet::Unsubscribe {
+            flags: unsubscribe.flags,
+            msg_id: unsubscribe.msg_id,
+            topic: Topic::Name(&unsubscribe.topic_name),
+        })?;
+        // transmit to network
+        if let Err(err) = client
+            .egress_tx
+            .try_send((remote_socket_addr, bytes_buf.to_owned()))
+        {
+            return Err(eformat!(remote_socket_addr, err));
+        }
+        // schedule retransmit
+        // Unsuback returns the msg_id, but not topic_id.
+        match RetransTimeWheel::schedule_timer(
+            remote_socket_addr,
+            MSG_TYPE_UNSUBACK,
+            0,
+            msg_id,
+            1,
+            bytes_buf,
+        ) {
+            Ok(_token) => Ok(()),
+            Err(err) => Err(err),
         }
     }
 }
@@ -0,0 +1,130 @@
+/// Out-of-band wake-up of ASLEEP clients, e.g. by SMS or a LoRa downlink.
+///
+/// AsleepMsgCache::insert() calls the WakeHook when a message is queued for
+/// a client that opted in with Wake::opt_in(). The hook is called at most
+/// once per min_interval for a client, the device wakes up and gets its
+/// queued messages with a PINGREQ. The hook runs on the publishing thread,
+/// a slow wake mechanism hands the request over to its own thread.
+use bytes::Bytes;
+use hashbrown::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::authorization::client_id_of;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WakeRequest {
+    pub socket_addr: SocketAddr,
+    pub client_id: Bytes,
+    /// Messages queued for the client, the new one included.
+    pub queued: usize,
+}
+
+/// Hook called to wake an ASLEEP client.
+pub type WakeHook = fn(&WakeRequest);
+
+#[derive(Debug)]
+struct WakeClient {
+    min_interval: Duration,
+    last_wake: Option<Instant>,
+}
+
+lazy_static! {
+    static ref WAKE_HOOK: Mutex<Option<WakeHook>> = Mutex::new(None);
+    static ref WAKE_CLIENTS: Mutex<HashMap<Bytes, WakeClient>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone)]
+pub struct Wake {}
+
+impl Wake {
+    pub fn set_hook(hook: WakeHook) {
+        *WAKE_HOOK.lock().unwrap() = Some(hook);
+    }
+    pub fn clear_hook() {
+        *WAKE_HOOK.lock().unwrap() = None;
+    }
+
+    /// Wake the client when a message is queued for it, at most once per
+    /// min_interval.
+    pub fn opt_in(client_id: Bytes, min_interval: Duration) {
+        WAKE_CLIENTS.lock().unwrap().insert(
+            client_id,
+            WakeClient {
+                min_interval,
+                last_wake: None,
+            },
+        );
+    }
+    /// false if the client didn't opt in.
+    pub fn opt_out(client_id: &Bytes) -> bool {
+        WAKE_CLIENTS.lock().unwrap().remove(client_id).is_some()
+    }
+    pub fn is_opted_in(client_id: &Bytes) -> bool {
+        WAKE_CLIENTS.lock().unwrap().contains_key(client_id)
+    }
+
+    /// A message is queued for the ASLEEP client, returns true if the hook
+    /// was called.
+    pub fn on_queued(socket_addr: SocketAddr, queued: usize) -> bool {
+        let hook = match *WAKE_HOOK.lock().unwrap() {
+            Some(hook) => hook,
+            None => return false,
+        };
+        let client_id = client_id_of(&socket_addr);
+        if !Wake::due(&client_id, Instant::now()) {
+            return false;
+        }
+        hook(&WakeRequest {
+            socket_addr,
+            client_id,
+            queued,
+        });
+        true
+    }
+
+    /// The client opted in and wasn't woken within its min_interval,
+    /// records the wake.
+    fn due(client_id: &Bytes, now: Instant) -> bool {
+        let mut clients = WAKE_CLIENTS.lock().unwrap();
+        let client = match clients.get_mut(client_id) {
+            Some(client) => client,
+            None => return false,
+        };
+        match client.last_wake {
+            Some(last_wake) if now < last_wake + client.min_interval => false,
+            _ => {
+                client.last_wake = Some(now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_wake() {
+        use super::*;
+        let client_id = Bytes::from("wakeTest");
+        let start = Instant::now();
+        let secs = |secs| start + Duration::from_secs(secs);
+        assert!(!Wake::due(&client_id, start));
+        Wake::opt_in(client_id.clone(), Duration::from_secs(60));
+        assert!(Wake::is_opted_in(&client_id));
+        assert!(Wake::due(&client_id, start));
+        // Rate limited.
+        assert!(!Wake::due(&client_id, secs(59)));
+        assert!(Wake::due(&client_id, secs(60)));
+        assert!(!Wake::due(&client_id, secs(61)));
+        assert!(Wake::opt_out(&client_id));
+        assert!(!Wake::opt_out(&client_id));
+        assert!(!Wake::due(&client_id, secs(1000)));
+        // Without a hook.
+        let addr = "10.7.0.1:1884".parse::<SocketAddr>().unwrap();
+        Wake::clear_hook();
+        assert!(!Wake::on_queued(addr, 1));
+    }
+}
@@ -8,8 +8,8 @@ use custom_debug::Debug;
 use getset::{CopyGetters, Getters, MutGetters};
 
 use crate::{
-    broker_lib::MqttSnClient, eformat, function, msg_hdr::MsgHeader,
-    MSG_LEN_WILL_MSG_REQ, MSG_TYPE_WILL_MSG_REQ,
+    broker_lib::MqttSnClient, client_mode::ClientMode, codec, eformat,
+    function, msg_hdr::MsgHeader,
 };
 
 #[derive(Debug, Clone, Copy, Getters, MutGetters, CopyGetters, Default)]
@@ -20,6 +20,8 @@ pub struct WillMsgReq {
     pub msg_type: u8,
 }
 
+codec!(WillMsgReq, {});
+
 impl WillMsgReq {
     /*
     fn constraint_len(_val: &u8) -> bool {
@@ -37,36 +39,30 @@ impl WillMsgReq {
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
-        if size == MSG_LEN_WILL_MSG_REQ as usize
-            && buf[0] == MSG_LEN_WILL_MSG_REQ
-        {
-            Ok(())
-        } else {
-            let remote_socket_addr = msg_header.remote_socket_addr;
-            Err(eformat!(remote_socket_addr, "len err", size))
+        match WillMsgReq::decode(buf, size) {
+            // Client mode, the gateway asks for the will message.
+            Ok(()) => ClientMode::on_will_msg_req(client, msg_header),
+            Err(why) => {
+                let remote_socket_addr = msg_header.remote_socket_addr;
+                Err(eformat!(remote_socket_addr, why))
+            }
         }
     }
 
     pub fn send(
         client: &MqttSnClient,
         msg_header: MsgHeader,
-    ) -> Result<(), String> {
-        let will = WillMsgReq {
-            len: MSG_LEN_WILL_MSG_REQ as u8,
-            msg_type: MSG_TYPE_WILL_MSG_REQ,
-        };
+    ) -> Result<BytesMut, String> {
+        let bytes = WillMsgReq::encode();
         let remote_socket_addr = msg_header.remote_socket_addr;
-        let mut bytes = BytesMut::with_capacity(MSG_LEN_WILL_MSG_REQ as usize);
-        dbg!(will.clone());
-        will.try_write(&mut bytes);
         dbg!(bytes.clone());
         dbg!(remote_socket_addr);
         // transmit to network
         match client
             .egress_tx
-            .try_send((remote_socket_addr, bytes.to_owned()))
+            .try_send((remote_socket_addr, bytes.clone()))
         {
-            Ok(()) => Ok(()),
+            Ok(()) => Ok(bytes),
             Err(err) => Err(eformat!(remote_socket_addr, err)),
         }
     }
@@ -10,8 +10,8 @@ use custom_debug::Debug;
 use getset::{CopyGetters, Getters, MutGetters};
 
 use crate::{
-    broker_lib::MqttSnClient, eformat, function, msg_hdr::MsgHeader,
-    ReturnCodeConst, MSG_LEN_WILL_MSG_RESP, MSG_TYPE_WILL_MSG_RESP,
+    broker_lib::MqttSnClient, codec, eformat, function, msg_hdr::MsgHeader,
+    ReturnCodeConst,
 };
 #[derive(Debug, Clone, Copy, Getters, MutGetters, CopyGetters, Default)]
 #[getset(get, set)]
@@ -22,6 +22,10 @@ pub struct WillMsgResp {
     pub return_code: u8,
 }
 
+codec!(WillMsgResp, {
+    return_code: u8,
+});
+
 impl WillMsgResp {
     pub fn recv(
         buf: &[u8],
@@ -29,13 +33,12 @@ impl WillMsgResp {
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
-        if size == MSG_LEN_WILL_MSG_RESP as usize
-            && buf[0] == MSG_LEN_WILL_MSG_RESP
-        {
-            Ok(())
-        } else {
-            let remote_socket_addr = msg_header.remote_socket_addr;
-            Err(eformat!(remote_socket_addr, "len err", size))
+        match WillMsgResp::decode(buf, size) {
+            Ok(_return_code) => Ok(()),
+            Err(why) => {
+                let remote_socket_addr = msg_header.remote_socket_addr;
+                Err(eformat!(remote_socket_addr, why))
+            }
         }
     }
     pub fn send(
@@ -43,15 +46,8 @@ impl WillMsgResp {
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
-        let will = WillMsgResp {
-            len: MSG_LEN_WILL_MSG_RESP as u8,
-            msg_type: MSG_TYPE_WILL_MSG_RESP,
-            return_code,
-        };
-        let mut bytes = BytesMut::with_capacity(MSG_LEN_WILL_MSG_RESP as usize);
-        dbg!(will.clone());
+        let bytes = WillMsgResp::encode(return_code);
         let remote_socket_addr = msg_header.remote_socket_addr;
-        will.try_write(&mut bytes);
         dbg!(bytes.clone());
         dbg!(remote_socket_addr);
         // transmit to network
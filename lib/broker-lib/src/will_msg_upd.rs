@@ -14,9 +14,14 @@ use std::mem;
 use std::str;
 
 use crate::{
-    broker_lib::MqttSnClient, connection::Connection, eformat, function,
-    msg_hdr::MsgHeader, will_msg_resp::WillMsgResp, MSG_LEN_WILL_MSG_HEADER,
-    MSG_TYPE_WILL_MSG, RETURN_CODE_ACCEPTED,
+    broker_lib::MqttSnClient,
+    codec::{encode_packet, Packet},
+    connection::Connection,
+    decode::Decode,
+    eformat, function,
+    msg_hdr::MsgHeader,
+    will_msg_resp::WillMsgResp,
+    MSG_LEN_WILL_MSG_UPD_HEADER, RETURN_CODE_ACCEPTED,
 };
 
 #[derive(Debug, Clone, Getters, MutGetters, CopyGetters, Default)]
@@ -27,17 +32,6 @@ pub struct WillMsgUpd {
     msg_type: u8,
     will_msg: String,
 }
-#[derive(Debug, Clone, Getters, MutGetters, CopyGetters, Default)]
-#[getset(get, set)]
-struct WillMsgUpd4 {
-    // NOTE: no pub
-    one: u8,
-    len: u16,
-    #[debug(format = "0x{:x}")]
-    msg_type: u8,
-    will_msg: String,
-}
-
 impl WillMsgUpd {
     pub fn recv(
         buf: &[u8],
@@ -46,34 +40,22 @@ impl WillMsgUpd {
         msg_header: MsgHeader,
     ) -> Result<(), String> {
         let remote_socket_addr = msg_header.remote_socket_addr;
-        if size < 256 {
-            let (will, len) = WillMsgUpd::try_read(buf, size).unwrap();
-            if size == len as usize {
-                Connection::update_will_msg(remote_socket_addr, will.will_msg)?;
-                WillMsgResp::send(RETURN_CODE_ACCEPTED, client, msg_header)?;
-                Ok(())
-            } else {
-                Err(eformat!(
-                    remote_socket_addr,
-                    "2-bytes len not supported", // TODO wrong message.
-                    size
-                ))
-            }
-        } else if size < 1400 {
-            let (will, len) = WillMsgUpd4::try_read(buf, size).unwrap();
-            if size == len as usize && will.one == 1 {
+        if size < MSG_LEN_WILL_MSG_UPD_HEADER as usize {
+            Err(eformat!(remote_socket_addr, "len err", size))
+        } else {
+            let (will, len) = Decode::read(
+                WillMsgUpd::try_read(buf, size),
+                size,
+                &msg_header,
+            )?;
+            // The will message must fill the datagram.
+            if size == len + will.will_msg.len() {
                 Connection::update_will_msg(remote_socket_addr, will.will_msg)?;
                 WillMsgResp::send(RETURN_CODE_ACCEPTED, client, msg_header)?;
                 Ok(())
             } else {
-                Err(eformat!(
-                    remote_socket_addr,
-                    "4-bytes len not supported", // TODO wrong message.
-                    size
-                ))
+                Err(eformat!(remote_socket_addr, "len err", size))
             }
-        } else {
-            Err(eformat!(remote_socket_addr, "len not supported", size))
         }
     }
     pub fn send(
@@ -81,42 +63,61 @@ impl WillMsgUpd {
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
-        let len: usize =
-            MSG_LEN_WILL_MSG_HEADER as usize + will_msg.len() as usize;
         let remote_socket_addr = msg_header.remote_socket_addr;
-        if len < 256 {
-            let will = WillMsgUpd {
-                len: len as u8,
-                msg_type: MSG_TYPE_WILL_MSG,
-                will_msg,
-            };
-            let mut bytes = BytesMut::with_capacity(len);
-            will.try_write(&mut bytes);
-            match client
-                .egress_tx
-                .try_send((remote_socket_addr, bytes.to_owned()))
-            {
-                Ok(()) => Ok(()),
-                Err(err) => Err(eformat!(remote_socket_addr, err)),
-            }
-        } else if len < 1400 {
-            let will = WillMsgUpd4 {
-                one: 1,
-                len: len as u16,
-                msg_type: MSG_TYPE_WILL_MSG,
-                will_msg,
-            };
-            let mut bytes = BytesMut::with_capacity(len);
-            will.try_write(&mut bytes);
-            match client
-                .egress_tx
-                .try_send((remote_socket_addr, bytes.to_owned()))
-            {
-                Ok(()) => Ok(()),
-                Err(err) => Err(eformat!(remote_socket_addr, err)),
-            }
-        } else {
-            Err(eformat!(remote_socket_addr, "len err", len))
+        let bytes = encode_packet(&Packet::WillMsgUpd {
+            will_msg: will_msg.as_bytes(),
+        })?;
+        match client.egress_tx.try_send((remote_socket_addr, bytes)) {
+            Ok(()) => Ok(()),
+            Err(err) => Err(eformat!(remote_socket_addr, err)),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_will_msg_upd_loopback() {
+        use super::*;
+        use crate::client_mode::Will;
+        use crate::flags::{QOS_LEVEL_0, RETAIN_FALSE};
+        use crate::test_support::{LoopbackBroker, TestClient};
+        use crate::{
+            MSG_LEN_WILL_MSG_RESP, MSG_TYPE_WILL_MSG_RESP,
+            MSG_TYPE_WILL_MSG_UPD,
+        };
+        use bytes::Bytes;
+        let mut client = TestClient::new(LoopbackBroker::addr()).unwrap();
+        let addr = client.local_addr();
+        let will = Will {
+            topic: "will_msg_upd/status".to_string(),
+            msg: "gone".to_string(),
+            qos: QOS_LEVEL_0,
+            retain: RETAIN_FALSE,
+        };
+        assert_eq!(
+            client.connect("willMsgUpd", 60, Some(&will)),
+            Ok(RETURN_CODE_ACCEPTED)
+        );
+        // New will message, the will topic is kept.
+        let msg = "battery low";
+        let mut bytes = BytesMut::new();
+        bytes.put_u8(2 + msg.len() as u8);
+        bytes.put_u8(MSG_TYPE_WILL_MSG_UPD);
+        bytes.put_slice(msg.as_bytes());
+        client.send(&bytes).unwrap();
+        let resp = client.expect(MSG_TYPE_WILL_MSG_RESP).unwrap();
+        assert_eq!(
+            &resp[..],
+            &[
+                MSG_LEN_WILL_MSG_RESP,
+                MSG_TYPE_WILL_MSG_RESP,
+                RETURN_CODE_ACCEPTED
+            ]
+        );
+        let conn = Connection::get(&addr).unwrap();
+        assert_eq!(conn.will_message, Bytes::from(msg));
+        assert_eq!(conn.will_topic, Bytes::from("will_msg_upd/status"));
+        client.disconnect(None).unwrap();
+    }
+}
@@ -0,0 +1,210 @@
+/// Will collection on a CONNECT with the Will flag, 6.3 of the spec.
+///
+/// CONNECT -> WILLTOPICREQ, WILLTOPIC -> WILLMSGREQ, WILLMSG -> CONNACK.
+/// The broker keeps the step of each client. A request is retransmitted
+/// by the RetransTimeWheel until the answer arrives, a timeout at any step
+/// aborts the connection. An answer out of order is dropped, the
+/// retransmitted request asks for the expected one again. An empty
+/// WILLTOPIC deletes the will and finishes with the CONNACK, an invalid
+/// one is answered with a CONNACK rejection, see WillTopic::validate().
+use hashbrown::HashMap;
+use log::*;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use crate::{
+    broker_lib::MqttSnClient, client_id::ClientId, conn_ack::ConnAck,
+    connection::Connection, eformat, function, keep_alive::KeepAliveTimeWheel,
+    msg_hdr::MsgHeader, retransmit::RetransTimeWheel, will_msg_req::WillMsgReq,
+    will_topic_req::WillTopicReq, MSG_TYPE_WILL_MSG, MSG_TYPE_WILL_TOPIC,
+    RETURN_CODE_ACCEPTED, RETURN_CODE_NOT_SUPPORTED,
+};
+
+/// Seconds before the first retransmission of a request.
+const WILL_REQ_DURATION: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WillStep {
+    /// WILLTOPICREQ sent.
+    WaitWillTopic,
+    /// WILLMSGREQ sent.
+    WaitWillMsg,
+}
+
+impl WillStep {
+    /// Message type of the answer.
+    fn msg_type(&self) -> u8 {
+        match self {
+            WillStep::WaitWillTopic => MSG_TYPE_WILL_TOPIC,
+            WillStep::WaitWillMsg => MSG_TYPE_WILL_MSG,
+        }
+    }
+}
+
+lazy_static! {
+    static ref WILL_SETUP: Mutex<HashMap<SocketAddr, WillStep>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone)]
+pub struct WillSetup {}
+
+impl WillSetup {
+    /// Step of the client, None if the will is not being collected.
+    pub fn step(socket_addr: &SocketAddr) -> Option<WillStep> {
+        WILL_SETUP.lock().unwrap().get(socket_addr).copied()
+    }
+
+    /// CONNECT with the Will flag, send the WILLTOPICREQ.
+    pub fn start(
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+    ) -> Result<(), String> {
+        let remote_socket_addr = msg_header.remote_socket_addr;
+        // A CONNECT again during the setup starts over.
+        let _result = RetransTimeWheel::cancel_timer(
+            remote_socket_addr,
+            MSG_TYPE_WILL_TOPIC,
+            0,
+        );
+        let _result = RetransTimeWheel::cancel_timer(
+            remote_socket_addr,
+            MSG_TYPE_WILL_MSG,
+            0,
+        );
+        WILL_SETUP
+            .lock()
+            .unwrap()
+            .insert(remote_socket_addr, WillStep::WaitWillTopic);
+        let bytes = WillTopicReq::send(client, msg_header)?;
+        RetransTimeWheel::schedule_timer(
+            remote_socket_addr,
+            MSG_TYPE_WILL_TOPIC,
+            0,
+            0,
+            WILL_REQ_DURATION,
+            bytes,
+        )
+    }
+
+    /// Check the answer of type msg_type is the expected one and stop the
+    /// retransmission of the request.
+    pub fn expect(socket_addr: SocketAddr, msg_type: u8) -> Result<(), String> {
+        match WillSetup::step(&socket_addr) {
+            Some(step) if step.msg_type() == msg_type => {
+                // Not found if the retransmit thread has just dropped it.
+                let _result =
+                    RetransTimeWheel::cancel_timer(socket_addr, msg_type, 0);
+                Ok(())
+            }
+            Some(step) => Err(eformat!(socket_addr, "out of order", step)),
+            None => Err(eformat!(socket_addr, "no will setup", msg_type)),
+        }
+    }
+
+    /// WILLTOPIC received, send the WILLMSGREQ.
+    pub fn request_msg(
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+    ) -> Result<(), String> {
+        let remote_socket_addr = msg_header.remote_socket_addr;
+        WILL_SETUP
+            .lock()
+            .unwrap()
+            .insert(remote_socket_addr, WillStep::WaitWillMsg);
+        let bytes = WillMsgReq::send(client, msg_header)?;
+        RetransTimeWheel::schedule_timer(
+            remote_socket_addr,
+            MSG_TYPE_WILL_MSG,
+            0,
+            0,
+            WILL_REQ_DURATION,
+            bytes,
+        )
+    }
+
+    /// Will collected, send the CONNACK.
+    pub fn finish(
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+    ) -> Result<(), String> {
+        WILL_SETUP
+            .lock()
+            .unwrap()
+            .remove(&msg_header.remote_socket_addr);
+        ConnAck::send(client, msg_header, RETURN_CODE_ACCEPTED)
+    }
+
+    /// Invalid WILLTOPIC, send a CONNACK with RETURN_CODE_NOT_SUPPORTED and
+    /// remove the connection. Returns why as the error for the caller to
+    /// log.
+    pub fn reject(
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+        why: String,
+    ) -> Result<(), String> {
+        let socket_addr = msg_header.remote_socket_addr;
+        WILL_SETUP.lock().unwrap().remove(&socket_addr);
+        ConnAck::send(client, msg_header, RETURN_CODE_NOT_SUPPORTED)?;
+        WillSetup::remove_connection(socket_addr);
+        Err(why)
+    }
+
+    /// The request of msg_type timed out, abort the connection. Ignored if
+    /// the client has already answered it.
+    pub fn abort(socket_addr: SocketAddr, msg_type: u8) {
+        {
+            let mut will_setup = WILL_SETUP.lock().unwrap();
+            match will_setup.get(&socket_addr) {
+                Some(step) if step.msg_type() == msg_type => {
+                    will_setup.remove(&socket_addr);
+                }
+                _ => return,
+            }
+        }
+        info!("{}: will setup timeout: 0x{:x}", socket_addr, msg_type);
+        WillSetup::remove_connection(socket_addr);
+    }
+
+    fn remove_connection(socket_addr: SocketAddr) {
+        // Not found if the connection was already removed.
+        if Connection::remove(&socket_addr).is_ok() {
+            ClientId::rev_delete(&socket_addr);
+            let _result = KeepAliveTimeWheel::cancel(&socket_addr);
+            RetransTimeWheel::cancel_all(socket_addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_will_setup() {
+        use super::*;
+        use crate::test_support::{LoopbackBroker, TestClient};
+        use crate::{
+            flags::{CLEAN_SESSION_TRUE, WILL_TRUE},
+            MSG_TYPE_CONNACK, MSG_TYPE_CONNECT, MSG_TYPE_WILL_TOPIC_REQ,
+        };
+        let mut client = TestClient::new(LoopbackBroker::addr()).unwrap();
+        let flags = CLEAN_SESSION_TRUE | WILL_TRUE;
+        let mut connect = vec![16, MSG_TYPE_CONNECT, flags, 1, 0, 60];
+        connect.extend_from_slice(b"will_setup");
+        client.send(&connect).unwrap();
+        client.expect(MSG_TYPE_WILL_TOPIC_REQ).unwrap();
+        let addr = client.local_addr();
+        assert_eq!(WillSetup::step(&addr), Some(WillStep::WaitWillTopic));
+        // Out of order, dropped.
+        client.send(&[3, MSG_TYPE_WILL_MSG, b'x']).unwrap();
+        assert_eq!(WillSetup::step(&addr), Some(WillStep::WaitWillTopic));
+        // An empty WILLTOPIC, no will.
+        client.send(&[2, MSG_TYPE_WILL_TOPIC]).unwrap();
+        let connack = client.expect(MSG_TYPE_CONNACK).unwrap();
+        assert_eq!(connack[2], RETURN_CODE_ACCEPTED);
+        assert_eq!(WillSetup::step(&addr), None);
+        // The timeout of an answered request is ignored.
+        WillSetup::abort(addr, MSG_TYPE_WILL_TOPIC);
+        assert!(Connection::contains_key(addr));
+        client.disconnect(None).unwrap();
+    }
+}
@@ -19,8 +19,16 @@ An empty WILLTOPIC message is a WILLTOPIC message without Flags and WillTopic fi
 6.4.
 */
 use crate::{
-    broker_lib::MqttSnClient, connection::Connection, eformat, function,
-    msg_hdr::MsgHeader, will_msg_req::WillMsgReq, MSG_LEN_WILL_TOPIC_HEADER,
+    broker_lib::MqttSnClient,
+    codec::{encode_packet, Packet},
+    connection::Connection,
+    decode::Decode,
+    eformat,
+    filter::has_wildcards,
+    flags::{flag_qos_level, QOS_LEVEL_3},
+    function,
+    msg_hdr::MsgHeader,
+    will_setup::WillSetup,
     MSG_TYPE_WILL_TOPIC,
 };
 use bytes::{BufMut, BytesMut};
@@ -40,19 +48,6 @@ pub struct WillTopic {
     will_topic: String,
 }
 
-#[derive(Debug, Clone, Getters, MutGetters, CopyGetters, Default)]
-#[getset(get, set)]
-struct WillTopic4 {
-    // NOTE: no pub
-    one: u8,
-    len: u16,
-    #[debug(format = "0x{:x}")]
-    msg_type: u8,
-    #[debug(format = "0b{:08b}")]
-    flags: u8,
-    will_topic: String,
-}
-
 impl WillTopic {
     pub fn recv(
         buf: &[u8],
@@ -61,89 +56,118 @@ impl WillTopic {
         msg_header: MsgHeader,
     ) -> Result<(), String> {
         let remote_socket_addr = msg_header.remote_socket_addr;
-        if size < 256 {
-            let (will, mut len) = WillTopic::try_read(buf, size).unwrap();
-            dbg!(&will);
-            dbg!((size, len));
-            len += will.will_topic.len() as usize;
-            if size == len as usize {
-                Connection::update_will_topic(
-                    remote_socket_addr,
-                    will.will_topic,
-                )?;
-                WillMsgReq::send(client, msg_header)?;
-                Ok(())
-            } else {
-                Err(eformat!(
-                    remote_socket_addr,
-                    "2-bytes len not supported",
-                    size
-                ))
-            }
-        } else if size < 1400 {
-            let (will, len) = WillTopic4::try_read(buf, size).unwrap();
-            if size == len as usize && will.one == 1 {
-                Connection::update_will_topic(
-                    remote_socket_addr,
-                    will.will_topic,
-                )?;
-                Ok(())
-            } else {
-                Err(eformat!(
-                    remote_socket_addr,
-                    "2-bytes len not supported",
-                    size
-                ))
+        WillSetup::expect(remote_socket_addr, MSG_TYPE_WILL_TOPIC)?;
+        if size == 2 {
+            // Empty WILLTOPIC, the client has no will.
+            Connection::delete_will(remote_socket_addr)?;
+            return WillSetup::finish(client, msg_header);
+        }
+        // The flags follow the header, then the will topic.
+        Decode::utf8(buf, 3, size, &msg_header)?;
+        let (will, mut len) =
+            Decode::read(WillTopic::try_read(buf, size), size, &msg_header)?;
+        dbg!(&will);
+        dbg!((size, len));
+        len += will.will_topic.len() as usize;
+        if size == len as usize {
+            if let Err(why) = WillTopic::validate(will.flags, &will.will_topic)
+            {
+                return WillSetup::reject(
+                    client,
+                    msg_header,
+                    eformat!(remote_socket_addr, why),
+                );
             }
+            Connection::update_will_topic(
+                remote_socket_addr,
+                will.flags,
+                will.will_topic,
+            )?;
+            WillSetup::request_msg(client, msg_header)
         } else {
             Err(eformat!(remote_socket_addr, "len err", size))
         }
     }
 
+    /// The will is published with the QoS and retain flag of the WILLTOPIC,
+    /// QoS -1 is for PUBLISH only. The will topic is a topic name.
+    pub fn validate(flags: u8, will_topic: &str) -> Result<(), String> {
+        if flag_qos_level(flags) == QOS_LEVEL_3 {
+            Err(eformat!("will QoS -1", will_topic))
+        } else if will_topic.is_empty() {
+            Err(eformat!("empty will topic"))
+        } else if has_wildcards(will_topic) {
+            Err(eformat!("will topic with wildcards", will_topic))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn send(
         flags: u8,
         will_topic: String,
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
-        let len: usize =
-            MSG_LEN_WILL_TOPIC_HEADER as usize + will_topic.len() as usize;
         let remote_socket_addr = msg_header.remote_socket_addr;
-        if len < 256 {
-            let will = WillTopic {
-                len: len as u8,
-                msg_type: MSG_TYPE_WILL_TOPIC,
-                flags,
-                will_topic,
-            };
-            let mut bytes = BytesMut::with_capacity(len);
-            will.try_write(&mut bytes);
-            match client
-                .egress_tx
-                .try_send((remote_socket_addr, bytes.to_owned()))
-            {
-                Ok(()) => Ok(()),
-                Err(err) => Err(eformat!(remote_socket_addr, err)),
-            }
-        } else if len < 1400 {
-            let will = WillTopic4 {
-                one: 1,
-                len: len as u16,
-                msg_type: MSG_TYPE_WILL_TOPIC,
-                flags,
-                will_topic,
-            };
-            let mut bytes = BytesMut::with_capacity(len);
-            will.try_write(&mut bytes);
-            match client
-                .egress_tx
-                .try_send((remote_socket_addr, bytes.to_owned()))
-            {
-                Ok(()) => Ok(()),
-                Err(err) => Err(eformat!(remote_socket_addr, err)),
-            }
-        } else {
-            Err(eformat!(remote_socket_addr, "len err", len))
+        let bytes = encode_packet(&Packet::WillTopic {
+            flags,
+            will_topic: &will_topic,
+        })?;
+        match client.egress_tx.try_send((remote_socket_addr, bytes)) {
+            Ok(()) => Ok(()),
+            Err(err) => Err(eformat!(remote_socket_addr, err)),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_will_topic_flags() {
+        use super::*;
+        use crate::flags::{
+            CLEAN_SESSION_TRUE, QOS_LEVEL_1, RETAIN_TRUE, WILL_TRUE,
+        };
+        use crate::test_support::{LoopbackBroker, TestClient};
+        use crate::{
+            MSG_TYPE_CONNACK, MSG_TYPE_CONNECT, MSG_TYPE_WILL_TOPIC_REQ,
+            RETURN_CODE_ACCEPTED, RETURN_CODE_NOT_SUPPORTED,
+        };
+        assert_eq!(
+            WillTopic::validate(QOS_LEVEL_1 | RETAIN_TRUE, "a/b"),
+            Ok(())
+        );
+        assert!(WillTopic::validate(QOS_LEVEL_3, "a/b").is_err());
+        assert!(WillTopic::validate(QOS_LEVEL_1, "").is_err());
+        assert!(WillTopic::validate(QOS_LEVEL_1, "a/+").is_err());
+        let connect = |client: &mut TestClient, client_id: &str| {
+            let flags = CLEAN_SESSION_TRUE | WILL_TRUE;
+            let len = 6 + client_id.len() as u8;
+            let mut connect = vec![len, MSG_TYPE_CONNECT, flags, 1, 0, 60];
+            connect.extend_from_slice(client_id.as_bytes());
+            client.send(&connect).unwrap();
+            client.expect(MSG_TYPE_WILL_TOPIC_REQ).unwrap();
+        };
+        // An empty WILLTOPIC, no will is stored.
+        let mut client = TestClient::new(LoopbackBroker::addr()).unwrap();
+        connect(&mut client, "willTopicEmpty");
+        client.send(&[2, MSG_TYPE_WILL_TOPIC]).unwrap();
+        let connack = client.expect(MSG_TYPE_CONNACK).unwrap();
+        assert_eq!(connack[2], RETURN_CODE_ACCEPTED);
+        let conn = Connection::get(&client.local_addr()).unwrap();
+        assert!(conn.will_topic.is_empty() && conn.will_message.is_empty());
+        assert_eq!(conn.will_topic_id, None);
+        client.disconnect(None).unwrap();
+        // QoS -1, the CONNECT is rejected.
+        let mut client = TestClient::new(LoopbackBroker::addr()).unwrap();
+        connect(&mut client, "willTopicQoS3");
+        client
+            .send(&[4, MSG_TYPE_WILL_TOPIC, QOS_LEVEL_3, b'w'])
+            .unwrap();
+        let connack = client.expect(MSG_TYPE_CONNACK).unwrap();
+        assert_eq!(connack[2], RETURN_CODE_NOT_SUPPORTED);
+        assert!(!Connection::contains_key(client.local_addr()));
+        assert_eq!(WillSetup::step(&client.local_addr()), None);
+    }
+}
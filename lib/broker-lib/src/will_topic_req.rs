@@ -7,8 +7,8 @@ The WILLTOPICREQ message is sent by the GW to request a client for sending the W
 format is shown in Table 11: it has only a header and no variable part.
 */
 use crate::{
-    broker_lib::MqttSnClient, eformat, function, msg_hdr::MsgHeader,
-    MSG_LEN_WILL_TOPIC_REQ, MSG_TYPE_WILL_TOPIC_REQ,
+    broker_lib::MqttSnClient, client_mode::ClientMode, codec, eformat,
+    function, msg_hdr::MsgHeader,
 };
 use bytes::{BufMut, BytesMut};
 use custom_debug::Debug;
@@ -21,6 +21,8 @@ pub struct WillTopicReq {
     pub msg_type: u8,
 }
 
+codec!(WillTopicReq, {});
+
 impl WillTopicReq {
     /*
     fn constraint_len(_val: &u8) -> bool {
@@ -38,37 +40,30 @@ impl WillTopicReq {
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
-        if size == MSG_LEN_WILL_TOPIC_REQ as usize
-            && buf[0] == MSG_LEN_WILL_TOPIC_REQ
-        {
-            Ok(())
-        } else {
-            let remote_socket_addr = msg_header.remote_socket_addr;
-            Err(eformat!(remote_socket_addr, "len err", size))
+        match WillTopicReq::decode(buf, size) {
+            // Client mode, the gateway asks for the will topic.
+            Ok(()) => ClientMode::on_will_topic_req(client, msg_header),
+            Err(why) => {
+                let remote_socket_addr = msg_header.remote_socket_addr;
+                Err(eformat!(remote_socket_addr, why))
+            }
         }
     }
 
     pub fn send(
         client: &MqttSnClient,
         msg_header: MsgHeader,
-    ) -> Result<(), String> {
-        let will = WillTopicReq {
-            len: MSG_LEN_WILL_TOPIC_REQ as u8,
-            msg_type: MSG_TYPE_WILL_TOPIC_REQ,
-        };
-        let mut bytes =
-            BytesMut::with_capacity(MSG_LEN_WILL_TOPIC_REQ as usize);
+    ) -> Result<BytesMut, String> {
+        let bytes = WillTopicReq::encode();
         let remote_socket_addr = msg_header.remote_socket_addr;
-        dbg!(will.clone());
-        will.try_write(&mut bytes);
         dbg!(bytes.clone());
         dbg!(remote_socket_addr);
         // transmit to network
         match client
             .egress_tx
-            .try_send((remote_socket_addr, bytes.to_owned()))
+            .try_send((remote_socket_addr, bytes.clone()))
         {
-            Ok(()) => Ok(()),
+            Ok(()) => Ok(bytes),
             Err(err) => Err(eformat!(remote_socket_addr, err)),
         }
     }
@@ -8,8 +8,8 @@ The WILLTOPICRESP message is sent by a GW to acknowledge the receipt and process
 • ReturnCode: “accepted”, or rejection reason
 */
 use crate::{
-    broker_lib::MqttSnClient, eformat, function, msg_hdr::MsgHeader,
-    ReturnCodeConst, MSG_LEN_WILL_TOPIC_RESP, MSG_TYPE_WILL_TOPIC_RESP,
+    broker_lib::MqttSnClient, codec, eformat, function, msg_hdr::MsgHeader,
+    ReturnCodeConst,
 };
 use bytes::{BufMut, BytesMut};
 use custom_debug::Debug;
@@ -23,6 +23,10 @@ pub struct WillTopicResp {
     pub return_code: u8,
 }
 
+codec!(WillTopicResp, {
+    return_code: u8,
+});
+
 impl WillTopicResp {
     pub fn recv(
         buf: &[u8],
@@ -31,13 +35,10 @@ impl WillTopicResp {
         msg_header: MsgHeader,
     ) -> Result<(), String> {
         let remote_socket_addr = msg_header.remote_socket_addr;
-        if size == MSG_LEN_WILL_TOPIC_RESP as usize
-            && buf[0] == MSG_LEN_WILL_TOPIC_RESP
-        {
+        match WillTopicResp::decode(buf, size) {
             // TODO cancel timer.
-            Ok(())
-        } else {
-            Err(eformat!(remote_socket_addr, "len err", size))
+            Ok(_return_code) => Ok(()),
+            Err(why) => Err(eformat!(remote_socket_addr, why)),
         }
     }
 
@@ -47,15 +48,7 @@ impl WillTopicResp {
         msg_header: MsgHeader,
     ) -> Result<(), String> {
         let remote_socket_addr = msg_header.remote_socket_addr;
-        let will = WillTopicResp {
-            len: MSG_LEN_WILL_TOPIC_RESP as u8,
-            msg_type: MSG_TYPE_WILL_TOPIC_RESP,
-            return_code,
-        };
-        let mut bytes =
-            BytesMut::with_capacity(MSG_LEN_WILL_TOPIC_RESP as usize);
-        dbg!(will.clone());
-        will.try_write(&mut bytes);
+        let bytes = WillTopicResp::encode(return_code);
         dbg!(bytes.clone());
         dbg!(remote_socket_addr);
         // transmit to network
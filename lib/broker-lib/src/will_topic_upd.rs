@@ -19,10 +19,16 @@ An empty WILLTOPICUPD message is a WILLTOPICUPD message without Flags and WillTo
 it is exactly 2 octets long). It is used by a client to delete its Will topic and Will message stored in the GW/server.
 */
 use crate::{
-    broker_lib::MqttSnClient, connection::Connection, eformat, function,
-    msg_hdr::MsgHeader, will_topic_resp::WillTopicResp,
-    MSG_LEN_WILL_TOPIC_UPD_HEADER, MSG_TYPE_WILL_TOPIC_UPD,
-    RETURN_CODE_ACCEPTED,
+    broker_lib::MqttSnClient,
+    codec::{encode_packet, Packet},
+    connection::Connection,
+    decode::Decode,
+    eformat, function,
+    msg_hdr::MsgHeader,
+    will_topic::WillTopic,
+    will_topic_resp::WillTopicResp,
+    MSG_LEN_WILL_TOPIC_UPD_EMPTY, MSG_LEN_WILL_TOPIC_UPD_HEADER,
+    RETURN_CODE_ACCEPTED, RETURN_CODE_NOT_SUPPORTED,
 };
 use bytes::{BufMut, BytesMut};
 use custom_debug::Debug;
@@ -40,19 +46,6 @@ pub struct WillTopicUpd {
     flags: u8,
     will_topic: String,
 }
-#[derive(Debug, Clone, Getters, MutGetters, CopyGetters, Default)]
-#[getset(get, set)]
-struct WillTopicUpd4 {
-    // NOTE: no pub
-    one: u8,
-    len: u16,
-    #[debug(format = "0x{:x}")]
-    msg_type: u8,
-    #[debug(format = "0b{:08b}")]
-    flags: u8,
-    will_topic: String,
-}
-
 impl WillTopicUpd {
     pub fn recv(
         buf: &[u8],
@@ -61,40 +54,43 @@ impl WillTopicUpd {
         msg_header: MsgHeader,
     ) -> Result<(), String> {
         let remote_socket_addr = msg_header.remote_socket_addr;
-        if size < 256 {
-            let (will, len) = WillTopicUpd::try_read(buf, size).unwrap();
-            if size == len as usize {
-                Connection::update_will_topic(
-                    remote_socket_addr,
-                    will.will_topic,
-                )?;
-                WillTopicResp::send(RETURN_CODE_ACCEPTED, client, msg_header)?;
-                Ok(())
-            } else {
-                Err(eformat!(
-                    remote_socket_addr,
-                    "2-bytes len not supported",
-                    size
-                ))
-            }
-        } else if size < 1400 {
-            let (will, len) = WillTopicUpd4::try_read(buf, size).unwrap();
-            if size == len as usize && will.one == 1 {
+        if size == MSG_LEN_WILL_TOPIC_UPD_EMPTY as usize {
+            // Empty WILLTOPICUPD, delete the will topic and will message.
+            Connection::delete_will(remote_socket_addr)?;
+            WillTopicResp::send(RETURN_CODE_ACCEPTED, client, msg_header)?;
+            Ok(())
+        } else if size < MSG_LEN_WILL_TOPIC_UPD_HEADER as usize {
+            Err(eformat!(remote_socket_addr, "len err", size))
+        } else if let Err(why) = Decode::utf8(buf, 3, size, &msg_header) {
+            Err(why)
+        } else {
+            let (will, len) = Decode::read(
+                WillTopicUpd::try_read(buf, size),
+                size,
+                &msg_header,
+            )?;
+            // The will topic must fill the datagram.
+            if size == len + will.will_topic.len() {
+                if let Err(why) =
+                    WillTopic::validate(will.flags, &will.will_topic)
+                {
+                    WillTopicResp::send(
+                        RETURN_CODE_NOT_SUPPORTED,
+                        client,
+                        msg_header,
+                    )?;
+                    return Err(eformat!(remote_socket_addr, why));
+                }
                 Connection::update_will_topic(
                     remote_socket_addr,
+                    will.flags,
                     will.will_topic,
                 )?;
                 WillTopicResp::send(RETURN_CODE_ACCEPTED, client, msg_header)?;
                 Ok(())
             } else {
-                Err(eformat!(
-                    remote_socket_addr,
-                    "4-bytes len not supported",
-                    size
-                ))
+                Err(eformat!(remote_socket_addr, "len err", size))
             }
-        } else {
-            Err(eformat!(remote_socket_addr, "len too big", size))
         }
     }
     pub fn send(
@@ -103,44 +99,72 @@ impl WillTopicUpd {
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
-        let len: usize =
-            MSG_LEN_WILL_TOPIC_UPD_HEADER as usize + will_topic.len() as usize;
         let remote_socket_addr = msg_header.remote_socket_addr;
-        if len < 256 {
-            let will = WillTopicUpd {
-                len: len as u8,
-                msg_type: MSG_TYPE_WILL_TOPIC_UPD,
-                flags,
-                will_topic,
-            };
-            let mut bytes = BytesMut::with_capacity(len);
-            will.try_write(&mut bytes);
-            match client
-                .egress_tx
-                .try_send((remote_socket_addr, bytes.to_owned()))
-            {
-                Ok(()) => Ok(()),
-                Err(err) => Err(eformat!(remote_socket_addr, err)),
-            }
-        } else if len < 1400 {
-            let will = WillTopicUpd4 {
-                one: 1,
-                len: len as u16,
-                msg_type: MSG_TYPE_WILL_TOPIC_UPD,
-                flags,
-                will_topic,
-            };
-            let mut bytes = BytesMut::with_capacity(len);
-            will.try_write(&mut bytes);
-            match client
-                .egress_tx
-                .try_send((remote_socket_addr, bytes.to_owned()))
-            {
-                Ok(()) => Ok(()),
-                Err(err) => Err(eformat!(remote_socket_addr, err)),
-            }
-        } else {
-            Err(eformat!(remote_socket_addr, "len err", len))
+        let bytes = encode_packet(&Packet::WillTopicUpd {
+            flags,
+            will_topic: &will_topic,
+        })?;
+        match client.egress_tx.try_send((remote_socket_addr, bytes)) {
+            Ok(()) => Ok(()),
+            Err(err) => Err(eformat!(remote_socket_addr, err)),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_will_topic_upd_loopback() {
+        use super::*;
+        use crate::client_mode::Will;
+        use crate::flags::{QOS_LEVEL_0, QOS_LEVEL_1, RETAIN_FALSE};
+        use crate::test_support::{LoopbackBroker, TestClient};
+        use crate::{
+            MSG_LEN_WILL_TOPIC_RESP, MSG_TYPE_WILL_TOPIC_RESP,
+            MSG_TYPE_WILL_TOPIC_UPD,
+        };
+        use bytes::Bytes;
+        let mut client = TestClient::new(LoopbackBroker::addr()).unwrap();
+        let addr = client.local_addr();
+        let will = Will {
+            topic: "will_topic_upd/old".to_string(),
+            msg: "gone".to_string(),
+            qos: QOS_LEVEL_0,
+            retain: RETAIN_FALSE,
+        };
+        assert_eq!(
+            client.connect("willTopicUpd", 60, Some(&will)),
+            Ok(RETURN_CODE_ACCEPTED)
+        );
+        let accepted = [
+            MSG_LEN_WILL_TOPIC_RESP,
+            MSG_TYPE_WILL_TOPIC_RESP,
+            RETURN_CODE_ACCEPTED,
+        ];
+        // New will topic and QoS, the will message is kept.
+        let topic = "will_topic_upd/new";
+        let mut bytes = BytesMut::new();
+        bytes.put_u8(3 + topic.len() as u8);
+        bytes.put_u8(MSG_TYPE_WILL_TOPIC_UPD);
+        bytes.put_u8(QOS_LEVEL_1);
+        bytes.put_slice(topic.as_bytes());
+        client.send(&bytes).unwrap();
+        let resp = client.expect(MSG_TYPE_WILL_TOPIC_RESP).unwrap();
+        assert_eq!(&resp[..], &accepted);
+        let conn = Connection::get(&addr).unwrap();
+        assert_eq!(conn.will_topic, Bytes::from(topic));
+        assert_eq!(conn.will_qos, QOS_LEVEL_1);
+        assert_eq!(conn.will_message, Bytes::from("gone"));
+        // Empty WILLTOPICUPD, the will topic and message are deleted.
+        client
+            .send(&[MSG_LEN_WILL_TOPIC_UPD_EMPTY, MSG_TYPE_WILL_TOPIC_UPD])
+            .unwrap();
+        let resp = client.expect(MSG_TYPE_WILL_TOPIC_RESP).unwrap();
+        assert_eq!(&resp[..], &accepted);
+        let conn = Connection::get(&addr).unwrap();
+        assert!(conn.will_topic.is_empty());
+        assert!(conn.will_message.is_empty());
+        assert_eq!(conn.will_topic_id, None);
+        client.disconnect(None).unwrap();
+    }
+}
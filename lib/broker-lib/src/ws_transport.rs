@@ -0,0 +1,213 @@
+/// WebSocket transport for browser-based dashboards.
+///
+/// A browser can't send UDP datagrams, it sends each MQTT-SN message in a
+/// binary WebSocket frame instead. An accepted WebSocket is a Conn
+/// registered with the Hub like a DTLS connection: the frames go to the
+/// same ingress channel and the replies go out through Hub::get_conn(), the
+/// rest of the broker doesn't know the transport. Text frames are ignored,
+/// the pings are answered by tungstenite.
+use async_trait::async_trait;
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use log::*;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio_tungstenite::{accept_async, tungstenite::Message, WebSocketStream};
+use util::Conn;
+
+use crate::{eformat, function, hub::Hub};
+
+type WsStream = WebSocketStream<TcpStream>;
+
+pub struct WsConn {
+    sink: Mutex<SplitSink<WsStream, Message>>,
+    stream: Mutex<SplitStream<WsStream>>,
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+}
+
+impl WsConn {
+    /// WebSocket handshake on an accepted TCP stream.
+    pub async fn accept(tcp_stream: TcpStream) -> Result<Self, String> {
+        let (local_addr, remote_addr) =
+            match (tcp_stream.local_addr(), tcp_stream.peer_addr()) {
+                (Ok(local_addr), Ok(remote_addr)) => (local_addr, remote_addr),
+                (Err(why), _) | (_, Err(why)) => return Err(eformat!(why)),
+            };
+        let ws_stream = match accept_async(tcp_stream).await {
+            Ok(ws_stream) => ws_stream,
+            Err(why) => return Err(eformat!(remote_addr, why)),
+        };
+        let (sink, stream) = ws_stream.split();
+        Ok(WsConn {
+            sink: Mutex::new(sink),
+            stream: Mutex::new(stream),
+            local_addr,
+            remote_addr,
+        })
+    }
+}
+
+#[async_trait]
+impl Conn for WsConn {
+    async fn connect(&self, _addr: SocketAddr) -> util::Result<()> {
+        Err(Error::new(ErrorKind::Other, "Not applicable").into())
+    }
+    /// The next binary frame, one MQTT-SN message.
+    async fn recv(&self, buf: &mut [u8]) -> util::Result<usize> {
+        let mut stream = self.stream.lock().await;
+        loop {
+            match stream.next().await {
+                Some(Ok(Message::Binary(data))) => {
+                    let len = data.len().min(buf.len());
+                    buf[..len].copy_from_slice(&data[..len]);
+                    return Ok(len);
+                }
+                Some(Ok(Message::Close(_))) | None => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "WebSocket closed",
+                    )
+                    .into());
+                }
+                // Text, ping and pong frames.
+                Some(Ok(_)) => continue,
+                Some(Err(why)) => {
+                    return Err(
+                        Error::new(ErrorKind::Other, why.to_string()).into()
+                    );
+                }
+            }
+        }
+    }
+    async fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> util::Result<(usize, SocketAddr)> {
+        let size = self.recv(buf).await?;
+        Ok((size, self.remote_addr))
+    }
+    async fn send(&self, buf: &[u8]) -> util::Result<usize> {
+        let mut sink = self.sink.lock().await;
+        match sink.send(Message::Binary(buf.to_vec())).await {
+            Ok(()) => Ok(buf.len()),
+            Err(why) => {
+                Err(Error::new(ErrorKind::Other, why.to_string()).into())
+            }
+        }
+    }
+    async fn send_to(
+        &self,
+        _buf: &[u8],
+        _target: SocketAddr,
+    ) -> util::Result<usize> {
+        Err(Error::new(ErrorKind::Other, "Not applicable").into())
+    }
+    async fn local_addr(&self) -> util::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+    async fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(self.remote_addr)
+    }
+    async fn close(&self) -> util::Result<()> {
+        let mut sink = self.sink.lock().await;
+        match sink.close().await {
+            Ok(()) => Ok(()),
+            Err(why) => {
+                Err(Error::new(ErrorKind::Other, why.to_string()).into())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WsTransport {}
+
+impl WsTransport {
+    /// Accept the WebSockets on socket_addr and register them with the
+    /// hub, e.g. MqttSnClient::hub. Returns the bound address.
+    pub async fn serve(
+        socket_addr: SocketAddr,
+        hub: Arc<Hub>,
+    ) -> Result<SocketAddr, String> {
+        let listener = match TcpListener::bind(socket_addr).await {
+            Ok(listener) => listener,
+            Err(why) => return Err(eformat!(socket_addr, why)),
+        };
+        let local_addr = match listener.local_addr() {
+            Ok(local_addr) => local_addr,
+            Err(why) => return Err(eformat!(socket_addr, why)),
+        };
+        tokio::spawn(async move {
+            loop {
+                let tcp_stream = match listener.accept().await {
+                    Ok((tcp_stream, _remote_addr)) => tcp_stream,
+                    Err(why) => {
+                        error!("{}", eformat!(local_addr, why));
+                        continue;
+                    }
+                };
+                let hub = Arc::clone(&hub);
+                // Don't block the accept loop during the handshake.
+                tokio::spawn(async move {
+                    match WsConn::accept(tcp_stream).await {
+                        Ok(ws_conn) => hub.register(Arc::new(ws_conn)).await,
+                        Err(why) => error!("{}", why),
+                    }
+                });
+            }
+        });
+        Ok(local_addr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_ws_transport() {
+        use super::*;
+        use crate::{MSG_TYPE_PINGREQ, MSG_TYPE_PINGRESP};
+        use bytes::Bytes;
+        use crossbeam::channel::unbounded;
+        use tokio_tungstenite::connect_async;
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (ingress_tx, ingress_rx) = unbounded();
+            let hub = Arc::new(Hub::new(Arc::new(ingress_tx)));
+            let listen_addr = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+            let addr = WsTransport::serve(listen_addr, Arc::clone(&hub))
+                .await
+                .unwrap();
+            let url = format!("ws://{}", addr);
+            let (mut ws_stream, _response) = connect_async(url).await.unwrap();
+            // Ignored.
+            ws_stream.send(Message::Text("hello".into())).await.unwrap();
+            // PINGREQ.
+            ws_stream
+                .send(Message::Binary(vec![2, MSG_TYPE_PINGREQ]))
+                .await
+                .unwrap();
+            let (remote_addr, bytes, conn): (_, Bytes, _) =
+                tokio::task::spawn_blocking(move || ingress_rx.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(&bytes[..], &[2, MSG_TYPE_PINGREQ]);
+            // The reply goes out through the hub.
+            let hub_conn = hub.get_conn(remote_addr).unwrap();
+            assert_eq!(hub_conn.remote_addr().await, conn.remote_addr().await);
+            hub_conn.send(&[2, MSG_TYPE_PINGRESP]).await.unwrap();
+            match ws_stream.next().await {
+                Some(Ok(Message::Binary(data))) => {
+                    assert_eq!(data, [2, MSG_TYPE_PINGRESP])
+                }
+                other => panic!("{:?}", other),
+            }
+        });
+    }
+}
@@ -0,0 +1,73 @@
+/// Message types, fixed message lengths and return codes of MQTT-SN v1.2.
+pub type MsgTypeConst = u8;
+pub const MSG_TYPE_ADVERTISE: MsgTypeConst = 0x0;
+pub const MSG_TYPE_SEARCH_GW: MsgTypeConst = 0x1;
+pub const MSG_TYPE_GW_INFO: MsgTypeConst = 0x2;
+pub const MSG_TYPE_CONNECT: MsgTypeConst = 0x4;
+pub const MSG_TYPE_CONNACK: MsgTypeConst = 0x5;
+pub const MSG_TYPE_SUBSCRIBE: MsgTypeConst = 0x12;
+pub const MSG_TYPE_SUBACK: MsgTypeConst = 0x13;
+pub const MSG_TYPE_UNSUBSCRIBE: MsgTypeConst = 0x14;
+pub const MSG_TYPE_UNSUBACK: MsgTypeConst = 0x15;
+pub const MSG_TYPE_PUBLISH: MsgTypeConst = 0xC;
+pub const MSG_TYPE_PUBACK: MsgTypeConst = 0xD;
+pub const MSG_TYPE_PUBCOMP: MsgTypeConst = 0xE;
+pub const MSG_TYPE_PUBREC: MsgTypeConst = 0xF;
+pub const MSG_TYPE_PUBREL: MsgTypeConst = 0x10;
+pub const MSG_TYPE_DISCONNECT: MsgTypeConst = 0x18;
+pub const MSG_TYPE_WILL_TOPIC_REQ: MsgTypeConst = 0x06;
+pub const MSG_TYPE_WILL_TOPIC: MsgTypeConst = 0x07;
+pub const MSG_TYPE_WILL_MSG_REQ: MsgTypeConst = 0x08;
+pub const MSG_TYPE_WILL_MSG: MsgTypeConst = 0x09;
+pub const MSG_TYPE_WILL_TOPIC_RESP: MsgTypeConst = 0x1B;
+pub const MSG_TYPE_WILL_MSG_RESP: MsgTypeConst = 0x1D;
+pub const MSG_TYPE_WILL_TOPIC_UPD: MsgTypeConst = 0x1A;
+pub const MSG_TYPE_WILL_MSG_UPD: MsgTypeConst = 0x1C;
+pub const MSG_TYPE_PINGREQ: MsgTypeConst = 0x16;
+pub const MSG_TYPE_PINGRESP: MsgTypeConst = 0x17;
+pub const MSG_TYPE_REGISTER: MsgTypeConst = 0x0A;
+pub const MSG_TYPE_REGACK: MsgTypeConst = 0x0B;
+// 0x1E-0xFD reserved
+pub const MSG_TYPE_ENCAP_MSG: MsgTypeConst = 0xFE;
+
+pub type MsgLenConst = u8;
+pub const MSG_LEN_ADVERTISE: MsgLenConst = 5;
+pub const MSG_LEN_SEARCH_GW: MsgLenConst = 3;
+pub const MSG_LEN_PUBACK: MsgLenConst = 7;
+pub const MSG_LEN_PUBREC: MsgLenConst = 4;
+pub const MSG_LEN_PUBREL: MsgLenConst = 4;
+pub const MSG_LEN_PUBCOMP: MsgLenConst = 4;
+pub const MSG_LEN_SUBACK: MsgLenConst = 8;
+pub const MSG_LEN_REGACK: MsgLenConst = 7;
+pub const MSG_LEN_CONNACK: MsgLenConst = 3;
+pub const MSG_LEN_DISCONNECT: MsgLenConst = 2;
+pub const MSG_LEN_DISCONNECT_DURATION: MsgLenConst = 4;
+pub const MSG_LEN_WILL_TOPIC_REQ: MsgLenConst = 2;
+pub const MSG_LEN_WILL_MSG_REQ: MsgLenConst = 2;
+pub const MSG_LEN_WILL_TOPIC_RESP: MsgLenConst = 3;
+pub const MSG_LEN_WILL_MSG_RESP: MsgLenConst = 3;
+pub const MSG_LEN_PINGRESP: MsgLenConst = 2;
+pub const MSG_LEN_UNSUBACK: MsgLenConst = 4;
+
+// Messages with a variable part, the length without it.
+pub const MSG_LEN_GW_INFO_HEADER: MsgLenConst = 3;
+pub const MSG_LEN_WILL_TOPIC_HEADER: MsgLenConst = 3;
+pub const MSG_LEN_WILL_MSG_HEADER: MsgLenConst = 2;
+pub const MSG_LEN_WILL_TOPIC_UPD_HEADER: MsgLenConst = 3;
+pub const MSG_LEN_WILL_MSG_UPD_HEADER: MsgLenConst = 2;
+// Empty WILLTOPICUPD, without Flags and WillTopic, deletes the will.
+pub const MSG_LEN_WILL_TOPIC_UPD_EMPTY: MsgLenConst = 2;
+pub const MSG_LEN_PUBLISH_HEADER: MsgLenConst = 7;
+pub const MSG_LEN_CONNECT_HEADER: MsgLenConst = 6;
+pub const MSG_LEN_PINGREQ_HEADER: MsgLenConst = 2;
+pub const MSG_LEN_SUBSCRIBE_HEADER: MsgLenConst = 7;
+pub const MSG_LEN_UNSUBSCRIBE_HEADER: MsgLenConst = 7;
+pub const MSG_LEN_REGISTER_HEADER: MsgLenConst = 6;
+// Length, MsgType and Ctrl, without the Wireless Node Id.
+pub const MSG_LEN_ENCAP_HEADER: MsgLenConst = 3;
+
+pub type ReturnCodeConst = u8;
+pub const RETURN_CODE_ACCEPTED: ReturnCodeConst = 0;
+pub const RETURN_CODE_CONGESTION: ReturnCodeConst = 1;
+pub const RETURN_CODE_INVALID_TOPIC_ID: ReturnCodeConst = 2;
+pub const RETURN_CODE_NOT_SUPPORTED: ReturnCodeConst = 3;
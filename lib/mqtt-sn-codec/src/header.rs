@@ -0,0 +1,115 @@
+/// The Length field and the MsgType, 5.2 of the spec.
+///
+/// The Length field is 1 octet for messages up to 255 octets, otherwise
+/// 0x01 followed by the 2-octet length, most significant octet first. The
+/// length includes the Length field.
+use crate::Error;
+
+/// Length of the 2-octet header, Length and MsgType.
+pub const SHORT_HEADER_LEN: usize = 2;
+/// Length of the 4-octet header, 0x01, Length(2) and MsgType.
+pub const LONG_HEADER_LEN: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header {
+    /// Message length, the header included.
+    pub len: usize,
+    /// SHORT_HEADER_LEN or LONG_HEADER_LEN.
+    pub header_len: usize,
+    pub msg_type: u8,
+}
+
+impl Header {
+    /// Header of a message with body_len octets after the MsgType, the
+    /// 1-octet Length field if the message fits in 255 octets.
+    pub fn new(msg_type: u8, body_len: usize) -> Result<Header, Error> {
+        let short_len = SHORT_HEADER_LEN + body_len;
+        let long_len = LONG_HEADER_LEN + body_len;
+        if short_len < 256 {
+            Ok(Header {
+                len: short_len,
+                header_len: SHORT_HEADER_LEN,
+                msg_type,
+            })
+        } else if long_len <= u16::MAX as usize {
+            Ok(Header {
+                len: long_len,
+                header_len: LONG_HEADER_LEN,
+                msg_type,
+            })
+        } else {
+            Err(Error::TooLong)
+        }
+    }
+
+    /// The header of the message at the start of buf, buf may be longer
+    /// than the message.
+    pub fn decode(buf: &[u8]) -> Result<Header, Error> {
+        let (len, header_len, msg_type) = match buf {
+            [1, high, low, msg_type, ..] => (
+                u16::from_be_bytes([*high, *low]) as usize,
+                LONG_HEADER_LEN,
+                *msg_type,
+            ),
+            [1, ..] => return Err(Error::Truncated),
+            [len, msg_type, ..] => (*len as usize, SHORT_HEADER_LEN, *msg_type),
+            _ => return Err(Error::Truncated),
+        };
+        if len < header_len {
+            Err(Error::Length)
+        } else if len > buf.len() {
+            Err(Error::Truncated)
+        } else {
+            Ok(Header {
+                len,
+                header_len,
+                msg_type,
+            })
+        }
+    }
+
+    /// Write the header at the start of buf, returns its length.
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.len() < self.header_len {
+            return Err(Error::BufferTooSmall);
+        }
+        if self.header_len == LONG_HEADER_LEN {
+            buf[0] = 1;
+            buf[1..3].copy_from_slice(&(self.len as u16).to_be_bytes());
+            buf[3] = self.msg_type;
+        } else {
+            buf[0] = self.len as u8;
+            buf[1] = self.msg_type;
+        }
+        Ok(self.header_len)
+    }
+
+    /// The fields after the MsgType, buf starts with the header.
+    pub fn body<'a>(&self, buf: &'a [u8]) -> &'a [u8] {
+        &buf[self.header_len..self.len]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_header() {
+        use super::*;
+        // (body length, message length, header length)
+        let cases =
+            [(0, 2, 2), (253, 255, 2), (254, 258, 4), (65531, 65535, 4)];
+        for (body_len, len, header_len) in cases.iter() {
+            let header = Header::new(0x0C, *body_len).unwrap();
+            assert_eq!((header.len, header.header_len), (*len, *header_len));
+            let mut buf = vec![0u8; *len];
+            assert_eq!(header.encode(&mut buf), Ok(*header_len));
+            assert_eq!(Header::decode(&buf), Ok(header));
+            assert_eq!(header.body(&buf).len(), *body_len);
+        }
+        assert_eq!(Header::new(0x0C, 65532), Err(Error::TooLong));
+        assert_eq!(Header::decode(&[1, 0]), Err(Error::Truncated));
+        assert_eq!(Header::decode(&[5, 0x0C, 0]), Err(Error::Truncated));
+        assert_eq!(Header::decode(&[1, 0, 3, 0x0C]), Err(Error::Length));
+        assert_eq!(Header::decode(&[0x0C]), Err(Error::Truncated));
+    }
+}
@@ -0,0 +1,67 @@
+//! MQTT-SN v1.2 wire format without the broker runtime.
+//!
+//! The message types, lengths, return codes and flags, the Length field
+//! and the encoding and decoding of the messages, without allocation and
+//! without std, for firmware. Packet::decode() borrows the variable fields
+//! from the datagram, Packet::encode() writes into a buffer of the caller
+//! and returns the message length:
+//!
+//! let mut buf = [0u8; 64];
+//! let puback = Packet::PubAck { topic_id: 1, msg_id: 7, return_code: 0 };
+//! let len = puback.encode(&mut buf)?;
+//! assert_eq!(Packet::decode(&buf[..len])?, puback);
+//!
+//! broker-lib re-exports the constants and the flags and encodes all its
+//! outgoing messages with Packet, see broker-lib/src/codec.rs. Its message
+//! structs decode the received messages next to the handling of the broker
+//! state.
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+pub mod consts;
+// The flag constants are grouped by field, DUP_QoS_RETAIN_WILL_....
+#[allow(clippy::unusual_byte_groupings)]
+pub mod flags;
+pub mod header;
+pub mod packet;
+
+pub use consts::*;
+pub use header::Header;
+pub use packet::{Packet, Topic};
+
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// The buffer ends before the message.
+    Truncated,
+    /// The Length field doesn't match the fields of the message type.
+    Length,
+    /// Longer than the 3-octet Length field.
+    TooLong,
+    /// The buffer of encode() is too small.
+    BufferTooSmall,
+    UnknownMsgType(u8),
+    /// The reserved TopicIdType.
+    TopicIdType,
+    /// A topic name that isn't UTF-8.
+    Utf8,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Truncated => write!(f, "truncated message"),
+            Error::Length => write!(f, "invalid length"),
+            Error::TooLong => write!(f, "message too long"),
+            Error::BufferTooSmall => write!(f, "buffer too small"),
+            Error::UnknownMsgType(msg_type) => {
+                write!(f, "unknown message type 0x{:x}", msg_type)
+            }
+            Error::TopicIdType => write!(f, "reserved topic id type"),
+            Error::Utf8 => write!(f, "topic name not UTF-8"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
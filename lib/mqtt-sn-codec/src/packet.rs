@@ -0,0 +1,883 @@
+/// The MQTT-SN messages, 5.4 of the spec.
+///
+/// The variable fields borrow from the datagram, a topic name or will topic
+/// is checked for UTF-8. A datagram is one message, except the Encapsulated
+/// message of a forwarder that carries the message after its header.
+use crate::{
+    consts::*,
+    flags::{
+        flag_topic_id_type, TOPIC_ID_TYPE_NORMAL, TOPIC_ID_TYPE_PRE_DEFINED,
+        TOPIC_ID_TYPE_SHORT,
+    },
+    header::Header,
+    Error,
+};
+
+/// Topic of a SUBSCRIBE or UNSUBSCRIBE, from the TopicIdType of the flags.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Topic<'a> {
+    Name(&'a str),
+    PreDefined(u16),
+    Short([u8; 2]),
+}
+
+impl<'a> Topic<'a> {
+    /// TopicIdType of the flags.
+    pub fn topic_id_type(&self) -> u8 {
+        match self {
+            Topic::Name(_) => TOPIC_ID_TYPE_NORMAL,
+            Topic::PreDefined(_) => TOPIC_ID_TYPE_PRE_DEFINED,
+            Topic::Short(_) => TOPIC_ID_TYPE_SHORT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Packet<'a> {
+    Advertise {
+        gw_id: u8,
+        duration: u16,
+    },
+    SearchGw {
+        radius: u8,
+    },
+    GwInfo {
+        gw_id: u8,
+        /// Empty when sent by a gateway.
+        gw_add: &'a [u8],
+    },
+    Connect {
+        flags: u8,
+        protocol_id: u8,
+        duration: u16,
+        client_id: &'a [u8],
+    },
+    ConnAck {
+        return_code: u8,
+    },
+    WillTopicReq,
+    WillTopic {
+        flags: u8,
+        will_topic: &'a str,
+    },
+    /// WILLTOPIC without Flags and WillTopic, deletes the will.
+    EmptyWillTopic,
+    WillMsgReq,
+    WillMsg {
+        will_msg: &'a [u8],
+    },
+    Register {
+        topic_id: u16,
+        msg_id: u16,
+        topic_name: &'a str,
+    },
+    RegAck {
+        topic_id: u16,
+        msg_id: u16,
+        return_code: u8,
+    },
+    Publish {
+        flags: u8,
+        topic_id: u16,
+        msg_id: u16,
+        data: &'a [u8],
+    },
+    PubAck {
+        topic_id: u16,
+        msg_id: u16,
+        return_code: u8,
+    },
+    PubComp {
+        msg_id: u16,
+    },
+    PubRec {
+        msg_id: u16,
+    },
+    PubRel {
+        msg_id: u16,
+    },
+    /// The TopicIdType of flags is the one of topic.
+    Subscribe {
+        flags: u8,
+        msg_id: u16,
+        topic: Topic<'a>,
+    },
+    SubAck {
+        flags: u8,
+        topic_id: u16,
+        msg_id: u16,
+        return_code: u8,
+    },
+    /// The TopicIdType of flags is the one of topic.
+    Unsubscribe {
+        flags: u8,
+        msg_id: u16,
+        topic: Topic<'a>,
+    },
+    UnsubAck {
+        msg_id: u16,
+    },
+    PingReq {
+        /// Empty, unless sent by a sleeping client.
+        client_id: &'a [u8],
+    },
+    PingResp,
+    Disconnect {
+        /// Sleep duration of a client going to sleep.
+        duration: Option<u16>,
+    },
+    WillTopicUpd {
+        flags: u8,
+        will_topic: &'a str,
+    },
+    /// WILLTOPICUPD without Flags and WillTopic, deletes the will.
+    EmptyWillTopicUpd,
+    WillTopicResp {
+        return_code: u8,
+    },
+    WillMsgUpd {
+        will_msg: &'a [u8],
+    },
+    WillMsgResp {
+        return_code: u8,
+    },
+    /// Encapsulated message of a forwarder, 5.5 of the spec, the Length
+    /// field covers the header only.
+    Encapsulated {
+        ctrl: u8,
+        wireless_node_id: &'a [u8],
+        msg: &'a [u8],
+    },
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Result<u8, Error> {
+        let val = *self.buf.get(self.pos).ok_or(Error::Length)?;
+        self.pos += 1;
+        Ok(val)
+    }
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_be_bytes([self.u8()?, self.u8()?]))
+    }
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.buf[self.pos..];
+        self.pos = self.buf.len();
+        rest
+    }
+    fn str(&mut self) -> Result<&'a str, Error> {
+        core::str::from_utf8(self.rest()).map_err(|_| Error::Utf8)
+    }
+    fn topic(&mut self, flags: u8) -> Result<Topic<'a>, Error> {
+        match flag_topic_id_type(flags) {
+            TOPIC_ID_TYPE_NORMAL => Ok(Topic::Name(self.str()?)),
+            TOPIC_ID_TYPE_PRE_DEFINED => Ok(Topic::PreDefined(self.u16()?)),
+            TOPIC_ID_TYPE_SHORT => Ok(Topic::Short([self.u8()?, self.u8()?])),
+            _ => Err(Error::TopicIdType),
+        }
+    }
+    fn is_empty(&self) -> bool {
+        self.pos == self.buf.len()
+    }
+}
+
+/// Writes the fields after the MsgType, or only counts them without a
+/// buffer.
+struct Writer<'b> {
+    buf: Option<&'b mut [u8]>,
+    pos: usize,
+}
+
+impl<'b> Writer<'b> {
+    fn slice(&mut self, src: &[u8]) -> Result<(), Error> {
+        if let Some(buf) = self.buf.as_deref_mut() {
+            buf.get_mut(self.pos..self.pos + src.len())
+                .ok_or(Error::BufferTooSmall)?
+                .copy_from_slice(src);
+        }
+        self.pos += src.len();
+        Ok(())
+    }
+    fn u8(&mut self, val: u8) -> Result<(), Error> {
+        self.slice(&[val])
+    }
+    fn u16(&mut self, val: u16) -> Result<(), Error> {
+        self.slice(&val.to_be_bytes())
+    }
+    fn topic(&mut self, topic: &Topic) -> Result<(), Error> {
+        match topic {
+            Topic::Name(name) => self.slice(name.as_bytes()),
+            Topic::PreDefined(topic_id) => self.u16(*topic_id),
+            Topic::Short(name) => self.slice(name),
+        }
+    }
+}
+
+impl<'a> Packet<'a> {
+    /// The message of the datagram.
+    pub fn decode(buf: &'a [u8]) -> Result<Packet<'a>, Error> {
+        let header = Header::decode(buf)?;
+        if header.msg_type == MSG_TYPE_ENCAP_MSG {
+            let mut reader = Reader {
+                buf: header.body(buf),
+                pos: 0,
+            };
+            return Ok(Packet::Encapsulated {
+                ctrl: reader.u8()?,
+                wireless_node_id: reader.rest(),
+                msg: &buf[header.len..],
+            });
+        }
+        if header.len != buf.len() {
+            return Err(Error::Length);
+        }
+        let mut reader = Reader {
+            buf: header.body(buf),
+            pos: 0,
+        };
+        let r = &mut reader;
+        let packet = match header.msg_type {
+            MSG_TYPE_ADVERTISE => Packet::Advertise {
+                gw_id: r.u8()?,
+                duration: r.u16()?,
+            },
+            MSG_TYPE_SEARCH_GW => Packet::SearchGw { radius: r.u8()? },
+            MSG_TYPE_GW_INFO => Packet::GwInfo {
+                gw_id: r.u8()?,
+                gw_add: r.rest(),
+            },
+            MSG_TYPE_CONNECT => Packet::Connect {
+                flags: r.u8()?,
+                protocol_id: r.u8()?,
+                duration: r.u16()?,
+                client_id: r.rest(),
+            },
+            MSG_TYPE_CONNACK => Packet::ConnAck {
+                return_code: r.u8()?,
+            },
+            MSG_TYPE_WILL_TOPIC_REQ => Packet::WillTopicReq,
+            MSG_TYPE_WILL_TOPIC if r.is_empty() => Packet::EmptyWillTopic,
+            MSG_TYPE_WILL_TOPIC => Packet::WillTopic {
+                flags: r.u8()?,
+                will_topic: r.str()?,
+            },
+            MSG_TYPE_WILL_MSG_REQ => Packet::WillMsgReq,
+            MSG_TYPE_WILL_MSG => Packet::WillMsg { will_msg: r.rest() },
+            MSG_TYPE_REGISTER => Packet::Register {
+                topic_id: r.u16()?,
+                msg_id: r.u16()?,
+                topic_name: r.str()?,
+            },
+            MSG_TYPE_REGACK => Packet::RegAck {
+                topic_id: r.u16()?,
+                msg_id: r.u16()?,
+                return_code: r.u8()?,
+            },
+            MSG_TYPE_PUBLISH => Packet::Publish {
+                flags: r.u8()?,
+                topic_id: r.u16()?,
+                msg_id: r.u16()?,
+                data: r.rest(),
+            },
+            MSG_TYPE_PUBACK => Packet::PubAck {
+                topic_id: r.u16()?,
+                msg_id: r.u16()?,
+                return_code: r.u8()?,
+            },
+            MSG_TYPE_PUBCOMP => Packet::PubComp { msg_id: r.u16()? },
+            MSG_TYPE_PUBREC => Packet::PubRec { msg_id: r.u16()? },
+            MSG_TYPE_PUBREL => Packet::PubRel { msg_id: r.u16()? },
+            MSG_TYPE_SUBSCRIBE => {
+                let flags = r.u8()?;
+                Packet::Subscribe {
+                    flags,
+                    msg_id: r.u16()?,
+                    topic: r.topic(flags)?,
+                }
+            }
+            MSG_TYPE_SUBACK => Packet::SubAck {
+                flags: r.u8()?,
+                topic_id: r.u16()?,
+                msg_id: r.u16()?,
+                return_code: r.u8()?,
+            },
+            MSG_TYPE_UNSUBSCRIBE => {
+                let flags = r.u8()?;
+                Packet::Unsubscribe {
+                    flags,
+                    msg_id: r.u16()?,
+                    topic: r.topic(flags)?,
+                }
+            }
+            MSG_TYPE_UNSUBACK => Packet::UnsubAck { msg_id: r.u16()? },
+            MSG_TYPE_PINGREQ => Packet::PingReq {
+                client_id: r.rest(),
+            },
+            MSG_TYPE_PINGRESP => Packet::PingResp,
+            MSG_TYPE_DISCONNECT if r.is_empty() => {
+                Packet::Disconnect { duration: None }
+            }
+            MSG_TYPE_DISCONNECT => Packet::Disconnect {
+                duration: Some(r.u16()?),
+            },
+            MSG_TYPE_WILL_TOPIC_UPD if r.is_empty() => {
+                Packet::EmptyWillTopicUpd
+            }
+            MSG_TYPE_WILL_TOPIC_UPD => Packet::WillTopicUpd {
+                flags: r.u8()?,
+                will_topic: r.str()?,
+            },
+            MSG_TYPE_WILL_TOPIC_RESP => Packet::WillTopicResp {
+                return_code: r.u8()?,
+            },
+            MSG_TYPE_WILL_MSG_UPD => Packet::WillMsgUpd { will_msg: r.rest() },
+            MSG_TYPE_WILL_MSG_RESP => Packet::WillMsgResp {
+                return_code: r.u8()?,
+            },
+            msg_type => return Err(Error::UnknownMsgType(msg_type)),
+        };
+        if reader.is_empty() {
+            Ok(packet)
+        } else {
+            Err(Error::Length)
+        }
+    }
+
+    /// Write the message at the start of buf, returns its length.
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let body_len = self.body_len()?;
+        let header = match self {
+            // The Length field covers the header only.
+            Packet::Encapsulated { msg, .. } => {
+                let header =
+                    Header::new(self.msg_type(), body_len - msg.len())?;
+                if buf.len() < header.len + msg.len() {
+                    return Err(Error::BufferTooSmall);
+                }
+                header
+            }
+            _ => Header::new(self.msg_type(), body_len)?,
+        };
+        let header_len = header.encode(buf)?;
+        let mut writer = Writer {
+            buf: Some(&mut buf[header_len..]),
+            pos: 0,
+        };
+        self.write_body(&mut writer)?;
+        Ok(header_len + writer.pos)
+    }
+
+    /// Length of the encoded message.
+    pub fn encoded_len(&self) -> Result<usize, Error> {
+        let body_len = self.body_len()?;
+        match self {
+            Packet::Encapsulated { msg, .. } => {
+                let header =
+                    Header::new(self.msg_type(), body_len - msg.len())?;
+                Ok(header.len + msg.len())
+            }
+            _ => Ok(Header::new(self.msg_type(), body_len)?.len),
+        }
+    }
+
+    pub fn msg_type(&self) -> u8 {
+        match self {
+            Packet::Advertise { .. } => MSG_TYPE_ADVERTISE,
+            Packet::SearchGw { .. } => MSG_TYPE_SEARCH_GW,
+            Packet::GwInfo { .. } => MSG_TYPE_GW_INFO,
+            Packet::Connect { .. } => MSG_TYPE_CONNECT,
+            Packet::ConnAck { .. } => MSG_TYPE_CONNACK,
+            Packet::WillTopicReq => MSG_TYPE_WILL_TOPIC_REQ,
+            Packet::WillTopic { .. } | Packet::EmptyWillTopic => {
+                MSG_TYPE_WILL_TOPIC
+            }
+            Packet::WillMsgReq => MSG_TYPE_WILL_MSG_REQ,
+            Packet::WillMsg { .. } => MSG_TYPE_WILL_MSG,
+            Packet::Register { .. } => MSG_TYPE_REGISTER,
+            Packet::RegAck { .. } => MSG_TYPE_REGACK,
+            Packet::Publish { .. } => MSG_TYPE_PUBLISH,
+            Packet::PubAck { .. } => MSG_TYPE_PUBACK,
+            Packet::PubComp { .. } => MSG_TYPE_PUBCOMP,
+            Packet::PubRec { .. } => MSG_TYPE_PUBREC,
+            Packet::PubRel { .. } => MSG_TYPE_PUBREL,
+            Packet::Subscribe { .. } => MSG_TYPE_SUBSCRIBE,
+            Packet::SubAck { .. } => MSG_TYPE_SUBACK,
+            Packet::Unsubscribe { .. } => MSG_TYPE_UNSUBSCRIBE,
+            Packet::UnsubAck { .. } => MSG_TYPE_UNSUBACK,
+            Packet::PingReq { .. } => MSG_TYPE_PINGREQ,
+            Packet::PingResp => MSG_TYPE_PINGRESP,
+            Packet::Disconnect { .. } => MSG_TYPE_DISCONNECT,
+            Packet::WillTopicUpd { .. } | Packet::EmptyWillTopicUpd => {
+                MSG_TYPE_WILL_TOPIC_UPD
+            }
+            Packet::WillTopicResp { .. } => MSG_TYPE_WILL_TOPIC_RESP,
+            Packet::WillMsgUpd { .. } => MSG_TYPE_WILL_MSG_UPD,
+            Packet::WillMsgResp { .. } => MSG_TYPE_WILL_MSG_RESP,
+            Packet::Encapsulated { .. } => MSG_TYPE_ENCAP_MSG,
+        }
+    }
+
+    /// Length of the fields after the MsgType.
+    fn body_len(&self) -> Result<usize, Error> {
+        let mut counter = Writer { buf: None, pos: 0 };
+        self.write_body(&mut counter)?;
+        Ok(counter.pos)
+    }
+
+    fn write_body(&self, w: &mut Writer) -> Result<(), Error> {
+        match self {
+            Packet::Advertise { gw_id, duration } => {
+                w.u8(*gw_id)?;
+                w.u16(*duration)
+            }
+            Packet::SearchGw { radius } => w.u8(*radius),
+            Packet::GwInfo { gw_id, gw_add } => {
+                w.u8(*gw_id)?;
+                w.slice(gw_add)
+            }
+            Packet::Connect {
+                flags,
+                protocol_id,
+                duration,
+                client_id,
+            } => {
+                w.u8(*flags)?;
+                w.u8(*protocol_id)?;
+                w.u16(*duration)?;
+                w.slice(client_id)
+            }
+            Packet::ConnAck { return_code }
+            | Packet::WillTopicResp { return_code }
+            | Packet::WillMsgResp { return_code } => w.u8(*return_code),
+            Packet::WillTopic { flags, will_topic }
+            | Packet::WillTopicUpd { flags, will_topic } => {
+                w.u8(*flags)?;
+                w.slice(will_topic.as_bytes())
+            }
+            Packet::WillMsg { will_msg } | Packet::WillMsgUpd { will_msg } => {
+                w.slice(will_msg)
+            }
+            Packet::Register {
+                topic_id,
+                msg_id,
+                topic_name,
+            } => {
+                w.u16(*topic_id)?;
+                w.u16(*msg_id)?;
+                w.slice(topic_name.as_bytes())
+            }
+            Packet::RegAck {
+                topic_id,
+                msg_id,
+                return_code,
+            }
+            | Packet::PubAck {
+                topic_id,
+                msg_id,
+                return_code,
+            } => {
+                w.u16(*topic_id)?;
+                w.u16(*msg_id)?;
+                w.u8(*return_code)
+            }
+            Packet::Publish {
+                flags,
+                topic_id,
+                msg_id,
+                data,
+            } => {
+                w.u8(*flags)?;
+                w.u16(*topic_id)?;
+                w.u16(*msg_id)?;
+                w.slice(data)
+            }
+            Packet::PubComp { msg_id }
+            | Packet::PubRec { msg_id }
+            | Packet::PubRel { msg_id }
+            | Packet::UnsubAck { msg_id } => w.u16(*msg_id),
+            Packet::Subscribe {
+                flags,
+                msg_id,
+                topic,
+            }
+            | Packet::Unsubscribe {
+                flags,
+                msg_id,
+                topic,
+            } => {
+                w.u8(*flags)?;
+                w.u16(*msg_id)?;
+                w.topic(topic)
+            }
+            Packet::SubAck {
+                flags,
+                topic_id,
+                msg_id,
+                return_code,
+            } => {
+                w.u8(*flags)?;
+                w.u16(*topic_id)?;
+                w.u16(*msg_id)?;
+                w.u8(*return_code)
+            }
+            Packet::PingReq { client_id } => w.slice(client_id),
+            Packet::Disconnect {
+                duration: Some(duration),
+            } => w.u16(*duration),
+            Packet::Encapsulated {
+                ctrl,
+                wireless_node_id,
+                msg,
+            } => {
+                w.u8(*ctrl)?;
+                w.slice(wireless_node_id)?;
+                w.slice(msg)
+            }
+            Packet::WillTopicReq
+            | Packet::EmptyWillTopic
+            | Packet::WillMsgReq
+            | Packet::PingResp
+            | Packet::Disconnect { duration: None }
+            | Packet::EmptyWillTopicUpd => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_packet_round_trip() {
+        use super::*;
+        use crate::flags::{QOS_LEVEL_1, RETAIN_TRUE};
+        let long_name = "t".repeat(300);
+        let packets = [
+            Packet::Advertise {
+                gw_id: 5,
+                duration: 900,
+            },
+            Packet::SearchGw { radius: 1 },
+            Packet::GwInfo {
+                gw_id: 5,
+                gw_add: &[],
+            },
+            Packet::Connect {
+                flags: 0b100,
+                protocol_id: 1,
+                duration: 60,
+                client_id: b"sensor1",
+            },
+            Packet::ConnAck { return_code: 0 },
+            Packet::WillTopicReq,
+            Packet::WillTopic {
+                flags: QOS_LEVEL_1 | RETAIN_TRUE,
+                will_topic: "a/will",
+            },
+            Packet::EmptyWillTopic,
+            Packet::WillMsgReq,
+            Packet::WillMsg { will_msg: b"gone" },
+            Packet::Register {
+                topic_id: 0,
+                msg_id: 1,
+                topic_name: &long_name,
+            },
+            Packet::RegAck {
+                topic_id: 0x1234,
+                msg_id: 1,
+                return_code: 0,
+            },
+            Packet::Publish {
+                flags: QOS_LEVEL_1,
+                topic_id: 0x1234,
+                msg_id: 0x5678,
+                data: b"21.5",
+            },
+            Packet::PubAck {
+                topic_id: 0x1234,
+                msg_id: 0x5678,
+                return_code: 0,
+            },
+            Packet::PubComp { msg_id: 2 },
+            Packet::PubRec { msg_id: 3 },
+            Packet::PubRel { msg_id: 4 },
+            Packet::Subscribe {
+                flags: QOS_LEVEL_1,
+                msg_id: 5,
+                topic: Topic::Name("a/+"),
+            },
+            Packet::Subscribe {
+                flags: QOS_LEVEL_1 | TOPIC_ID_TYPE_PRE_DEFINED,
+                msg_id: 6,
+                topic: Topic::PreDefined(7),
+            },
+            Packet::SubAck {
+                flags: QOS_LEVEL_1,
+                topic_id: 7,
+                msg_id: 6,
+                return_code: 0,
+            },
+            Packet::Unsubscribe {
+                flags: TOPIC_ID_TYPE_SHORT,
+                msg_id: 8,
+                topic: Topic::Short(*b"ab"),
+            },
+            Packet::UnsubAck { msg_id: 8 },
+            Packet::PingReq { client_id: b"" },
+            Packet::PingReq {
+                client_id: b"sleepy",
+            },
+            Packet::PingResp,
+            Packet::Disconnect { duration: None },
+            Packet::Disconnect {
+                duration: Some(300),
+            },
+            Packet::WillTopicUpd {
+                flags: 0,
+                will_topic: "b/will",
+            },
+            Packet::EmptyWillTopicUpd,
+            Packet::WillTopicResp { return_code: 0 },
+            Packet::WillMsgUpd { will_msg: b"bye" },
+            Packet::WillMsgResp { return_code: 3 },
+            Packet::Encapsulated {
+                ctrl: 1,
+                wireless_node_id: &[0xAA, 0xBB],
+                msg: &[2, MSG_TYPE_PINGRESP],
+            },
+        ];
+        let mut buf = [0u8; 512];
+        for packet in packets.iter() {
+            let len = packet.encode(&mut buf).unwrap();
+            assert_eq!(packet.encoded_len(), Ok(len));
+            assert_eq!(Packet::decode(&buf[..len]), Ok(*packet));
+        }
+        // The long REGISTER has the 3-octet Length field.
+        let register = Packet::Register {
+            topic_id: 0,
+            msg_id: 1,
+            topic_name: &long_name,
+        };
+        let len = register.encode(&mut buf).unwrap();
+        assert_eq!(&buf[..4], &[1, 0x01, 0x34, MSG_TYPE_REGISTER]);
+        assert_eq!(len, 308);
+        assert_eq!(
+            register.encode(&mut buf[..307]),
+            Err(Error::BufferTooSmall)
+        );
+        // Malformed datagrams.
+        let decode = Packet::decode;
+        assert_eq!(decode(&[4, MSG_TYPE_PUBREL, 0]), Err(Error::Truncated));
+        assert_eq!(decode(&[3, MSG_TYPE_PUBREL, 0]), Err(Error::Length));
+        assert_eq!(decode(&[5, MSG_TYPE_PUBREL, 0, 1, 0]), Err(Error::Length));
+        assert_eq!(decode(&[2, 0x30]), Err(Error::UnknownMsgType(0x30)));
+        assert_eq!(
+            decode(&[6, MSG_TYPE_SUBSCRIBE, 0b11, 0, 1, b'a']),
+            Err(Error::TopicIdType)
+        );
+        assert_eq!(
+            decode(&[7, MSG_TYPE_REGISTER, 0, 0, 0, 1, 0xFF]),
+            Err(Error::Utf8)
+        );
+    }
+
+    #[test]
+    fn test_packet_bytes() {
+        use super::*;
+        use crate::flags::{
+            CLEAN_SESSION_TRUE, QOS_LEVEL_1, QOS_LEVEL_2, RETAIN_TRUE,
+            WILL_TRUE,
+        };
+        // The datagram of each message type, 5.4 of the spec, topic id
+        // 0x0102 and msg id 0x0304.
+        let cases: [(&[u8], Packet); 33] = [
+            (
+                &[5, 0x00, 7, 0x03, 0x84],
+                Packet::Advertise {
+                    gw_id: 7,
+                    duration: 900,
+                },
+            ),
+            (&[3, 0x01, 1], Packet::SearchGw { radius: 1 }),
+            (
+                &[3, 0x02, 7],
+                Packet::GwInfo {
+                    gw_id: 7,
+                    gw_add: &[],
+                },
+            ),
+            (
+                &[7, 0x02, 7, 10, 0, 0, 1],
+                Packet::GwInfo {
+                    gw_id: 7,
+                    gw_add: &[10, 0, 0, 1],
+                },
+            ),
+            (
+                &[8, 0x04, 0b1100, 1, 0, 60, b'c', b'1'],
+                Packet::Connect {
+                    flags: WILL_TRUE | CLEAN_SESSION_TRUE,
+                    protocol_id: 1,
+                    duration: 60,
+                    client_id: b"c1",
+                },
+            ),
+            (&[3, 0x05, 0], Packet::ConnAck { return_code: 0 }),
+            (&[2, 0x06], Packet::WillTopicReq),
+            (
+                &[4, 0x07, 0x30, b'w'],
+                Packet::WillTopic {
+                    flags: QOS_LEVEL_1 | RETAIN_TRUE,
+                    will_topic: "w",
+                },
+            ),
+            (&[2, 0x07], Packet::EmptyWillTopic),
+            (&[2, 0x08], Packet::WillMsgReq),
+            (&[4, 0x09, b'o', b'k'], Packet::WillMsg { will_msg: b"ok" }),
+            (
+                &[7, 0x0A, 0, 0, 3, 4, b't'],
+                Packet::Register {
+                    topic_id: 0,
+                    msg_id: 0x0304,
+                    topic_name: "t",
+                },
+            ),
+            (
+                &[7, 0x0B, 1, 2, 3, 4, 0],
+                Packet::RegAck {
+                    topic_id: 0x0102,
+                    msg_id: 0x0304,
+                    return_code: 0,
+                },
+            ),
+            (
+                &[8, 0x0C, 0x20, 1, 2, 3, 4, b'x'],
+                Packet::Publish {
+                    flags: QOS_LEVEL_1,
+                    topic_id: 0x0102,
+                    msg_id: 0x0304,
+                    data: b"x",
+                },
+            ),
+            (
+                &[7, 0x0D, 1, 2, 3, 4, 2],
+                Packet::PubAck {
+                    topic_id: 0x0102,
+                    msg_id: 0x0304,
+                    return_code: 2,
+                },
+            ),
+            (&[4, 0x0E, 3, 4], Packet::PubComp { msg_id: 0x0304 }),
+            (&[4, 0x0F, 3, 4], Packet::PubRec { msg_id: 0x0304 }),
+            (&[4, 0x10, 3, 4], Packet::PubRel { msg_id: 0x0304 }),
+            (
+                &[7, 0x12, 0x40, 3, 4, b'a', b'/'],
+                Packet::Subscribe {
+                    flags: QOS_LEVEL_2,
+                    msg_id: 0x0304,
+                    topic: Topic::Name("a/"),
+                },
+            ),
+            (
+                &[8, 0x13, 0x20, 1, 2, 3, 4, 0],
+                Packet::SubAck {
+                    flags: QOS_LEVEL_1,
+                    topic_id: 0x0102,
+                    msg_id: 0x0304,
+                    return_code: 0,
+                },
+            ),
+            (
+                &[7, 0x14, 0x01, 3, 4, 1, 2],
+                Packet::Unsubscribe {
+                    flags: TOPIC_ID_TYPE_PRE_DEFINED,
+                    msg_id: 0x0304,
+                    topic: Topic::PreDefined(0x0102),
+                },
+            ),
+            (&[4, 0x15, 3, 4], Packet::UnsubAck { msg_id: 0x0304 }),
+            (&[2, 0x16], Packet::PingReq { client_id: b"" }),
+            (&[4, 0x16, b'c', b'1'], Packet::PingReq { client_id: b"c1" }),
+            (&[2, 0x17], Packet::PingResp),
+            (&[2, 0x18], Packet::Disconnect { duration: None }),
+            (
+                &[4, 0x18, 0x01, 0x2C],
+                Packet::Disconnect {
+                    duration: Some(300),
+                },
+            ),
+            (
+                &[4, 0x1A, 0x00, b'w'],
+                Packet::WillTopicUpd {
+                    flags: 0,
+                    will_topic: "w",
+                },
+            ),
+            (&[2, 0x1A], Packet::EmptyWillTopicUpd),
+            (&[3, 0x1B, 0], Packet::WillTopicResp { return_code: 0 }),
+            (&[3, 0x1C, b'x'], Packet::WillMsgUpd { will_msg: b"x" }),
+            (&[3, 0x1D, 3], Packet::WillMsgResp { return_code: 3 }),
+            (
+                &[4, 0xFE, 1, 0xAA, 2, 0x17],
+                Packet::Encapsulated {
+                    ctrl: 1,
+                    wireless_node_id: &[0xAA],
+                    msg: &[2, 0x17],
+                },
+            ),
+        ];
+        let mut buf = [0u8; 16];
+        for (bytes, packet) in cases.iter() {
+            assert_eq!(Packet::decode(bytes), Ok(*packet));
+            assert_eq!(packet.encode(&mut buf), Ok(bytes.len()));
+            assert_eq!(&buf[..bytes.len()], *bytes);
+        }
+        // A short topic, the 2 octets after the MsgId.
+        assert_eq!(
+            Packet::decode(&[7, 0x12, 0x02, 3, 4, b'a', b'b']),
+            Ok(Packet::Subscribe {
+                flags: TOPIC_ID_TYPE_SHORT,
+                msg_id: 0x0304,
+                topic: Topic::Short(*b"ab"),
+            })
+        );
+    }
+
+    #[test]
+    fn test_packet_fixed_len() {
+        use super::*;
+        // A fixed size message one octet too short or too long.
+        let fixed: [&[u8]; 15] = [
+            &[5, 0x00, 7, 0x03, 0x84],
+            &[3, 0x01, 1],
+            &[3, 0x05, 0],
+            &[7, 0x0B, 1, 2, 3, 4, 0],
+            &[7, 0x0D, 1, 2, 3, 4, 2],
+            &[4, 0x0E, 3, 4],
+            &[4, 0x0F, 3, 4],
+            &[4, 0x10, 3, 4],
+            &[8, 0x13, 0x20, 1, 2, 3, 4, 0],
+            &[4, 0x15, 3, 4],
+            &[3, 0x1B, 0],
+            &[3, 0x1D, 3],
+            &[2, 0x06],
+            &[2, 0x08],
+            &[2, 0x17],
+        ];
+        let mut buf = [0u8; 16];
+        for bytes in fixed.iter() {
+            let len = bytes.len();
+            buf[..len].copy_from_slice(bytes);
+            // The Length field matches the datagram.
+            buf[0] = len as u8 + 1;
+            buf[len] = 0;
+            assert_eq!(Packet::decode(&buf[..len + 1]), Err(Error::Length));
+            if len > 2 {
+                buf[0] = len as u8 - 1;
+                assert_eq!(Packet::decode(&buf[..len - 1]), Err(Error::Length));
+            }
+        }
+    }
+}
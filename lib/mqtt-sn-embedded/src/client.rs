@@ -0,0 +1,787 @@
+/// MQTT-SN client state machine, 6 of the spec.
+///
+/// The requests return the datagram to send, handle() takes a received
+/// datagram and returns what it means for the application. poll_transmit()
+/// returns the acks of the received messages, the retransmissions after
+/// Config::t_retry_ms and the keep alive PINGREQ, the firmware calls it
+/// until None after each handle() and again at next_timeout():
+///
+/// let mut client = Client::new("sensor1", Config::default())?;
+/// radio.send(&client.connect(clock.ms())?);
+/// loop {
+///     if let Some(datagram) = radio.recv_until(client.next_timeout()) {
+///         if let Some(event) = client.handle(&datagram)? { ... }
+///     }
+///     while let Some(bytes) = client.poll_transmit(clock.ms()) {
+///         radio.send(&bytes);
+///     }
+/// }
+///
+/// A request without ack after Config::n_retry retransmissions leaves the
+/// client Lost, it connects again. Sleeping clients, 6.14: sleep() sends
+/// the DISCONNECT with the sleep duration, wake() the PINGREQ with the
+/// client id, the gateway sends the buffered messages before the PINGRESP.
+use heapless::Vec;
+use mqtt_sn_codec::{
+    flags::{
+        flag_qos_level, flags_set, CLEAN_SESSION_FALSE, CLEAN_SESSION_TRUE,
+        DUP_FALSE, DUP_TRUE, QOS_LEVEL_0, QOS_LEVEL_1, QOS_LEVEL_2,
+        QOS_LEVEL_3, RETAIN_FALSE, TOPIC_ID_TYPE_NORMAL, WILL_FALSE,
+    },
+    header::Header,
+    Packet, Topic, RETURN_CODE_ACCEPTED,
+};
+
+use crate::{Buf, Error, MAX_CLIENT_ID_LEN, MAX_IN_FLIGHT};
+
+/// ProtocolId of CONNECT, MQTT-SN 1.2.
+const PROTOCOL_ID: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    Disconnected,
+    Connecting,
+    Active,
+    /// DISCONNECT sent, until the one of the gateway.
+    Disconnecting,
+    Asleep,
+    /// PINGREQ of wake() sent, until the PINGRESP.
+    Awake,
+    /// No ack after the retransmissions.
+    Lost,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// Keep alive of CONNECT in seconds, 0 disables it.
+    pub duration: u16,
+    pub clean_session: bool,
+    /// Tretry, 6.13 of the spec.
+    pub t_retry_ms: u64,
+    /// Nretry.
+    pub n_retry: u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            duration: 60,
+            clean_session: true,
+            t_retry_ms: 10_000,
+            n_retry: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event<'a> {
+    Connected,
+    ConnectRejected {
+        return_code: u8,
+    },
+    Registered {
+        msg_id: u16,
+        topic_id: u16,
+        return_code: u8,
+    },
+    Subscribed {
+        msg_id: u16,
+        topic_id: u16,
+        qos: u8,
+        return_code: u8,
+    },
+    /// PUBACK of a QoS 1 PUBLISH, INVALID_TOPIC_ID needs a new REGISTER,
+    /// e.g. after a restart of the gateway.
+    Published {
+        msg_id: u16,
+        topic_id: u16,
+        return_code: u8,
+    },
+    /// REGISTER of the gateway for a topic matching a wildcard filter.
+    TopicRegistered {
+        topic_id: u16,
+        topic_name: &'a str,
+    },
+    Message {
+        flags: u8,
+        topic_id: u16,
+        data: &'a [u8],
+    },
+    /// After the DISCONNECT of sleep() or the PINGRESP after wake().
+    Asleep,
+    /// DISCONNECT of disconnect() or of the gateway.
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Request {
+    Connect,
+    Register(u16),
+    Subscribe(u16),
+    Publish(u16),
+    Ping,
+    Disconnect,
+}
+
+#[derive(Debug, Clone)]
+struct InFlight {
+    request: Request,
+    bytes: Buf,
+    sent_ms: u64,
+    retries: u8,
+}
+
+#[derive(Debug)]
+pub struct Client {
+    client_id: Vec<u8, MAX_CLIENT_ID_LEN>,
+    config: Config,
+    state: State,
+    msg_id: u16,
+    in_flight: Vec<InFlight, MAX_IN_FLIGHT>,
+    /// Acks of the received messages.
+    replies: Vec<Buf, MAX_IN_FLIGHT>,
+    /// For the keep alive.
+    last_sent_ms: u64,
+    /// The DISCONNECT in flight is the one of sleep().
+    sleeping: bool,
+}
+
+impl Client {
+    pub fn new(client_id: &str, config: Config) -> Result<Self, Error> {
+        if client_id.is_empty() {
+            return Err(Error::ClientId);
+        }
+        let client_id = Vec::from_slice(client_id.as_bytes())
+            .map_err(|_| Error::ClientId)?;
+        Ok(Client {
+            client_id,
+            config,
+            state: State::Disconnected,
+            msg_id: 0,
+            in_flight: Vec::new(),
+            replies: Vec::new(),
+            last_sent_ms: 0,
+            sleeping: false,
+        })
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// CONNECT, also from Asleep to become Active again.
+    pub fn connect(&mut self, now_ms: u64) -> Result<Buf, Error> {
+        match self.state {
+            State::Disconnected | State::Lost | State::Asleep => (),
+            state => return Err(Error::State(state)),
+        }
+        let clean_session = if self.config.clean_session {
+            CLEAN_SESSION_TRUE
+        } else {
+            CLEAN_SESSION_FALSE
+        };
+        let bytes = encode(&Packet::Connect {
+            flags: flags_set(
+                DUP_FALSE,
+                QOS_LEVEL_0,
+                RETAIN_FALSE,
+                WILL_FALSE,
+                clean_session,
+                TOPIC_ID_TYPE_NORMAL,
+            ),
+            protocol_id: PROTOCOL_ID,
+            duration: self.config.duration,
+            client_id: &self.client_id,
+        })?;
+        self.in_flight.clear();
+        self.replies.clear();
+        self.state = State::Connecting;
+        self.send(Request::Connect, bytes, now_ms)
+    }
+
+    /// REGISTER, returns the msg id of the Registered event.
+    pub fn register(
+        &mut self,
+        topic_name: &str,
+        now_ms: u64,
+    ) -> Result<(u16, Buf), Error> {
+        self.check_active()?;
+        let msg_id = self.next_msg_id();
+        let bytes = encode(&Packet::Register {
+            topic_id: 0,
+            msg_id,
+            topic_name,
+        })?;
+        Ok((msg_id, self.send(Request::Register(msg_id), bytes, now_ms)?))
+    }
+
+    /// SUBSCRIBE with QoS 0 or 1, returns the msg id of the Subscribed
+    /// event.
+    pub fn subscribe(
+        &mut self,
+        topic: Topic,
+        qos: u8,
+        now_ms: u64,
+    ) -> Result<(u16, Buf), Error> {
+        self.check_active()?;
+        if qos != QOS_LEVEL_0 && qos != QOS_LEVEL_1 {
+            return Err(Error::QoS);
+        }
+        let msg_id = self.next_msg_id();
+        let bytes = encode(&Packet::Subscribe {
+            flags: flags_set(
+                DUP_FALSE,
+                qos,
+                RETAIN_FALSE,
+                WILL_FALSE,
+                CLEAN_SESSION_FALSE,
+                topic.topic_id_type(),
+            ),
+            msg_id,
+            topic,
+        })?;
+        Ok((
+            msg_id,
+            self.send(Request::Subscribe(msg_id), bytes, now_ms)?,
+        ))
+    }
+
+    /// PUBLISH with QoS -1 in any state, 0 and 1 when Active. Returns the
+    /// msg id of the Published event, 0 without ack.
+    pub fn publish(
+        &mut self,
+        topic_id_type: u8,
+        topic_id: u16,
+        qos: u8,
+        retain: u8,
+        data: &[u8],
+        now_ms: u64,
+    ) -> Result<(u16, Buf), Error> {
+        match qos {
+            QOS_LEVEL_2 => return Err(Error::QoS),
+            QOS_LEVEL_3 => (),
+            _ => self.check_active()?,
+        }
+        let msg_id = if qos == QOS_LEVEL_1 {
+            self.next_msg_id()
+        } else {
+            0
+        };
+        let bytes = encode(&Packet::Publish {
+            flags: flags_set(
+                DUP_FALSE,
+                qos,
+                retain,
+                WILL_FALSE,
+                CLEAN_SESSION_FALSE,
+                topic_id_type,
+            ),
+            topic_id,
+            msg_id,
+            data,
+        })?;
+        if qos == QOS_LEVEL_1 {
+            return Ok((
+                msg_id,
+                self.send(Request::Publish(msg_id), bytes, now_ms)?,
+            ));
+        }
+        self.last_sent_ms = now_ms;
+        Ok((msg_id, bytes))
+    }
+
+    /// DISCONNECT with the sleep duration in seconds, Asleep after the
+    /// DISCONNECT of the gateway.
+    pub fn sleep(&mut self, duration: u16, now_ms: u64) -> Result<Buf, Error> {
+        self.check_active()?;
+        self.disconnect_with(Some(duration), now_ms)
+    }
+
+    /// PINGREQ with the client id, the gateway sends the messages buffered
+    /// while Asleep and the PINGRESP.
+    pub fn wake(&mut self, now_ms: u64) -> Result<Buf, Error> {
+        if self.state != State::Asleep {
+            return Err(Error::State(self.state));
+        }
+        let bytes = encode(&Packet::PingReq {
+            client_id: &self.client_id,
+        })?;
+        let bytes = self.send(Request::Ping, bytes, now_ms)?;
+        self.state = State::Awake;
+        Ok(bytes)
+    }
+
+    pub fn disconnect(&mut self, now_ms: u64) -> Result<Buf, Error> {
+        match self.state {
+            State::Active | State::Asleep | State::Awake => (),
+            state => return Err(Error::State(state)),
+        }
+        self.disconnect_with(None, now_ms)
+    }
+
+    /// A datagram of the gateway, the acks without request in flight are
+    /// duplicates and ignored like the messages of other gateways.
+    pub fn handle<'a>(
+        &mut self,
+        datagram: &'a [u8],
+    ) -> Result<Option<Event<'a>>, Error> {
+        let event = match Packet::decode(datagram)? {
+            Packet::ConnAck { return_code } => {
+                if !self.acked(Request::Connect) {
+                    return Ok(None);
+                }
+                if return_code == RETURN_CODE_ACCEPTED {
+                    self.state = State::Active;
+                    Event::Connected
+                } else {
+                    self.state = State::Disconnected;
+                    Event::ConnectRejected { return_code }
+                }
+            }
+            Packet::RegAck {
+                topic_id,
+                msg_id,
+                return_code,
+            } => {
+                if !self.acked(Request::Register(msg_id)) {
+                    return Ok(None);
+                }
+                Event::Registered {
+                    msg_id,
+                    topic_id,
+                    return_code,
+                }
+            }
+            Packet::SubAck {
+                flags,
+                topic_id,
+                msg_id,
+                return_code,
+            } => {
+                if !self.acked(Request::Subscribe(msg_id)) {
+                    return Ok(None);
+                }
+                Event::Subscribed {
+                    msg_id,
+                    topic_id,
+                    qos: flag_qos_level(flags),
+                    return_code,
+                }
+            }
+            Packet::PubAck {
+                topic_id,
+                msg_id,
+                return_code,
+            } => {
+                if !self.acked(Request::Publish(msg_id)) {
+                    return Ok(None);
+                }
+                Event::Published {
+                    msg_id,
+                    topic_id,
+                    return_code,
+                }
+            }
+            Packet::Register {
+                topic_id,
+                msg_id,
+                topic_name,
+            } => {
+                self.reply(&Packet::RegAck {
+                    topic_id,
+                    msg_id,
+                    return_code: RETURN_CODE_ACCEPTED,
+                })?;
+                Event::TopicRegistered {
+                    topic_id,
+                    topic_name,
+                }
+            }
+            Packet::Publish {
+                flags,
+                topic_id,
+                msg_id,
+                data,
+            } => {
+                match flag_qos_level(flags) {
+                    QOS_LEVEL_1 => self.reply(&Packet::PubAck {
+                        topic_id,
+                        msg_id,
+                        return_code: RETURN_CODE_ACCEPTED,
+                    })?,
+                    // Subscribed with QoS 1 at most.
+                    QOS_LEVEL_2 => return Err(Error::QoS),
+                    _ => (),
+                }
+                Event::Message {
+                    flags,
+                    topic_id,
+                    data,
+                }
+            }
+            Packet::PingResp => {
+                if !self.acked(Request::Ping) || self.state != State::Awake {
+                    return Ok(None);
+                }
+                self.state = State::Asleep;
+                Event::Asleep
+            }
+            Packet::Disconnect { .. } => {
+                if self.acked(Request::Disconnect) && self.sleeping {
+                    self.state = State::Asleep;
+                    Event::Asleep
+                } else {
+                    self.in_flight.clear();
+                    self.state = State::Disconnected;
+                    Event::Disconnected
+                }
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(event))
+    }
+
+    /// The next datagram to send: an ack, a retransmission or the keep
+    /// alive PINGREQ.
+    pub fn poll_transmit(&mut self, now_ms: u64) -> Option<Buf> {
+        if let Some(bytes) = self.replies.pop() {
+            self.last_sent_ms = now_ms;
+            return Some(bytes);
+        }
+        let t_retry_ms = self.config.t_retry_ms;
+        let pos = self
+            .in_flight
+            .iter()
+            .position(|in_flight| now_ms >= in_flight.sent_ms + t_retry_ms);
+        if let Some(pos) = pos {
+            if self.in_flight[pos].retries >= self.config.n_retry {
+                self.in_flight.clear();
+                self.replies.clear();
+                self.state = State::Lost;
+                return None;
+            }
+            let in_flight = &mut self.in_flight[pos];
+            in_flight.retries += 1;
+            in_flight.sent_ms = now_ms;
+            if let Request::Publish(_) = in_flight.request {
+                set_dup(&mut in_flight.bytes);
+            }
+            self.last_sent_ms = now_ms;
+            return Some(in_flight.bytes.clone());
+        }
+        let ping_in_flight = self
+            .in_flight
+            .iter()
+            .any(|in_flight| in_flight.request == Request::Ping);
+        match self.keep_alive_ms() {
+            Some(keep_alive_ms)
+                if now_ms >= keep_alive_ms && !ping_in_flight =>
+            {
+                let bytes = encode(&Packet::PingReq { client_id: &[] }).ok()?;
+                self.send(Request::Ping, bytes, now_ms).ok()
+            }
+            _ => None,
+        }
+    }
+
+    /// Time of the next retransmission or keep alive, poll_transmit() is
+    /// called at this time.
+    pub fn next_timeout(&self) -> Option<u64> {
+        let retry_ms = self
+            .in_flight
+            .iter()
+            .map(|in_flight| in_flight.sent_ms + self.config.t_retry_ms)
+            .min();
+        match (retry_ms, self.keep_alive_ms()) {
+            (Some(retry_ms), Some(keep_alive_ms)) => {
+                Some(retry_ms.min(keep_alive_ms))
+            }
+            (retry_ms, keep_alive_ms) => retry_ms.or(keep_alive_ms),
+        }
+    }
+
+    fn keep_alive_ms(&self) -> Option<u64> {
+        if self.state == State::Active && self.config.duration > 0 {
+            Some(self.last_sent_ms + self.config.duration as u64 * 1000)
+        } else {
+            None
+        }
+    }
+
+    fn disconnect_with(
+        &mut self,
+        duration: Option<u16>,
+        now_ms: u64,
+    ) -> Result<Buf, Error> {
+        let bytes = encode(&Packet::Disconnect { duration })?;
+        self.in_flight.clear();
+        let bytes = self.send(Request::Disconnect, bytes, now_ms)?;
+        self.sleeping = duration.is_some();
+        self.state = State::Disconnecting;
+        Ok(bytes)
+    }
+
+    fn send(
+        &mut self,
+        request: Request,
+        bytes: Buf,
+        now_ms: u64,
+    ) -> Result<Buf, Error> {
+        self.in_flight
+            .push(InFlight {
+                request,
+                bytes: bytes.clone(),
+                sent_ms: now_ms,
+                retries: 0,
+            })
+            .map_err(|_| Error::InFlightFull)?;
+        self.last_sent_ms = now_ms;
+        Ok(bytes)
+    }
+
+    fn reply(&mut self, packet: &Packet) -> Result<(), Error> {
+        let bytes = encode(packet)?;
+        // Dropped when full, the gateway retransmits the message.
+        let _ = self.replies.push(bytes);
+        Ok(())
+    }
+
+    /// Remove the request of the ack, false without one.
+    fn acked(&mut self, request: Request) -> bool {
+        match self
+            .in_flight
+            .iter()
+            .position(|in_flight| in_flight.request == request)
+        {
+            Some(pos) => {
+                self.in_flight.swap_remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn check_active(&self) -> Result<(), Error> {
+        if self.state == State::Active {
+            Ok(())
+        } else {
+            Err(Error::State(self.state))
+        }
+    }
+
+    /// 1..=0xFFFF, 0 is the msg id of the messages without ack.
+    fn next_msg_id(&mut self) -> u16 {
+        self.msg_id = self.msg_id.wrapping_add(1).max(1);
+        self.msg_id
+    }
+}
+
+fn encode(packet: &Packet) -> Result<Buf, Error> {
+    let len = packet.encoded_len()?;
+    #[cfg(not(feature = "alloc"))]
+    let mut bytes = {
+        let mut bytes = Buf::new();
+        bytes.resize(len, 0).map_err(|_| Error::TooLong)?;
+        bytes
+    };
+    #[cfg(feature = "alloc")]
+    let mut bytes = alloc::vec![0; len];
+    packet.encode(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// DUP flag of a retransmitted PUBLISH, the Flags follow the MsgType.
+fn set_dup(bytes: &mut Buf) {
+    if let Ok(header) = Header::decode(bytes) {
+        bytes[header.header_len] |= DUP_TRUE;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_client_session() {
+        use super::*;
+        use mqtt_sn_codec::flags::{flag_is_dup, RETAIN_TRUE};
+        let gw = |packet: Packet| {
+            let mut buf = [0u8; 64];
+            let len = packet.encode(&mut buf).unwrap();
+            buf[..len].to_vec()
+        };
+        let config = Config {
+            n_retry: 2,
+            ..Config::default()
+        };
+        assert_eq!(Client::new("", config).err(), Some(Error::ClientId));
+        let too_long = "c".repeat(MAX_CLIENT_ID_LEN + 1);
+        assert_eq!(Client::new(&too_long, config).err(), Some(Error::ClientId));
+        let mut client = Client::new("sensor1", config).unwrap();
+
+        // CONNECT, retransmitted after Tretry.
+        let bytes = client.connect(0).unwrap();
+        match Packet::decode(&bytes).unwrap() {
+            Packet::Connect {
+                flags,
+                duration,
+                client_id,
+                ..
+            } => {
+                assert_eq!(flags, CLEAN_SESSION_TRUE);
+                assert_eq!(duration, 60);
+                assert_eq!(client_id, b"sensor1");
+            }
+            packet => panic!("{:?}", packet),
+        }
+        assert_eq!(client.next_timeout(), Some(10_000));
+        assert_eq!(client.poll_transmit(5_000), None);
+        assert_eq!(client.poll_transmit(10_000), Some(bytes));
+        let connack = gw(Packet::ConnAck { return_code: 0 });
+        assert_eq!(client.handle(&connack), Ok(Some(Event::Connected)));
+        assert_eq!(client.state(), State::Active);
+        // Duplicate.
+        assert_eq!(client.handle(&connack), Ok(None));
+
+        // REGISTER and PUBLISH QoS 1, retransmitted with DUP.
+        let (msg_id, _) = client.register("a/b", 10_000).unwrap();
+        let regack = gw(Packet::RegAck {
+            topic_id: 5,
+            msg_id,
+            return_code: 0,
+        });
+        assert_eq!(
+            client.handle(&regack),
+            Ok(Some(Event::Registered {
+                msg_id,
+                topic_id: 5,
+                return_code: 0
+            }))
+        );
+        let (msg_id, bytes) = client
+            .publish(
+                TOPIC_ID_TYPE_NORMAL,
+                5,
+                QOS_LEVEL_1,
+                RETAIN_TRUE,
+                b"21.5",
+                11_000,
+            )
+            .unwrap();
+        assert_eq!(msg_id, 2);
+        let dup = client.poll_transmit(21_000).unwrap();
+        assert_eq!(dup[3..], bytes[3..]);
+        assert!(flag_is_dup(dup[2]));
+        let puback = gw(Packet::PubAck {
+            topic_id: 5,
+            msg_id,
+            return_code: 0,
+        });
+        assert_eq!(
+            client.handle(&puback),
+            Ok(Some(Event::Published {
+                msg_id,
+                topic_id: 5,
+                return_code: 0
+            }))
+        );
+
+        // PUBLISH QoS 1 of the gateway, acked by poll_transmit().
+        let publish = gw(Packet::Publish {
+            flags: QOS_LEVEL_1,
+            topic_id: 5,
+            msg_id: 9,
+            data: b"on",
+        });
+        assert_eq!(
+            client.handle(&publish),
+            Ok(Some(Event::Message {
+                flags: QOS_LEVEL_1,
+                topic_id: 5,
+                data: b"on"
+            }))
+        );
+        let bytes = client.poll_transmit(22_000).unwrap();
+        assert_eq!(
+            Packet::decode(&bytes),
+            Ok(Packet::PubAck {
+                topic_id: 5,
+                msg_id: 9,
+                return_code: 0
+            })
+        );
+
+        // Keep alive.
+        assert_eq!(client.next_timeout(), Some(82_000));
+        let bytes = client.poll_transmit(82_000).unwrap();
+        assert_eq!(
+            Packet::decode(&bytes),
+            Ok(Packet::PingReq { client_id: b"" })
+        );
+        assert_eq!(client.handle(&gw(Packet::PingResp)), Ok(None));
+
+        // Asleep, awake until the PINGRESP.
+        let bytes = client.sleep(300, 90_000).unwrap();
+        assert_eq!(
+            Packet::decode(&bytes),
+            Ok(Packet::Disconnect {
+                duration: Some(300)
+            })
+        );
+        let disconnect = gw(Packet::Disconnect { duration: None });
+        assert_eq!(client.handle(&disconnect), Ok(Some(Event::Asleep)));
+        assert_eq!(client.next_timeout(), None);
+        let publish_qos1 = client.publish(
+            TOPIC_ID_TYPE_NORMAL,
+            5,
+            QOS_LEVEL_1,
+            RETAIN_FALSE,
+            b"",
+            0,
+        );
+        assert_eq!(publish_qos1.err(), Some(Error::State(State::Asleep)));
+        let publish_qos2 = client.publish(
+            TOPIC_ID_TYPE_NORMAL,
+            5,
+            QOS_LEVEL_2,
+            RETAIN_FALSE,
+            b"",
+            0,
+        );
+        assert_eq!(publish_qos2.err(), Some(Error::QoS));
+        let bytes = client.wake(300_000).unwrap();
+        assert_eq!(
+            Packet::decode(&bytes),
+            Ok(Packet::PingReq {
+                client_id: b"sensor1"
+            })
+        );
+        assert_eq!(client.state(), State::Awake);
+        assert_eq!(
+            client.handle(&gw(Packet::PingResp)),
+            Ok(Some(Event::Asleep))
+        );
+
+        // Lost after Nretry retransmissions.
+        client.connect(600_000).unwrap();
+        assert!(client.poll_transmit(610_000).is_some());
+        assert!(client.poll_transmit(620_000).is_some());
+        assert_eq!(client.poll_transmit(630_000), None);
+        assert_eq!(client.state(), State::Lost);
+        assert_eq!(client.next_timeout(), None);
+
+        #[cfg(not(feature = "alloc"))]
+        {
+            client.connect(700_000).unwrap();
+            client.handle(&connack).unwrap();
+            let data = [0u8; crate::MAX_PACKET_LEN];
+            let publish = client.publish(
+                TOPIC_ID_TYPE_NORMAL,
+                5,
+                QOS_LEVEL_0,
+                RETAIN_FALSE,
+                &data,
+                0,
+            );
+            assert_eq!(publish.err(), Some(Error::TooLong));
+        }
+    }
+}
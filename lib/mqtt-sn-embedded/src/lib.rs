@@ -0,0 +1,69 @@
+//! MQTT-SN client for firmware talking to the broker.
+//!
+//! A state machine without threads, sockets or a clock: the firmware sends
+//! the datagrams of the client over its radio or UDP stack and passes the
+//! received ones and its millisecond counter, see client.rs. The messages
+//! are encoded with mqtt-sn-codec into heapless buffers, or into a Vec with
+//! the alloc feature.
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+pub mod client;
+
+pub use client::{Client, Config, Event, State};
+
+use core::fmt;
+
+/// Longest outgoing message without the alloc feature, an IEEE 802.15.4
+/// frame.
+pub const MAX_PACKET_LEN: usize = 127;
+/// Requests waiting for their ack, CONNECT, REGISTER, SUBSCRIBE, PUBLISH
+/// QoS 1, PINGREQ and DISCONNECT.
+pub const MAX_IN_FLIGHT: usize = 4;
+/// 5.3.1 of the spec.
+pub const MAX_CLIENT_ID_LEN: usize = 23;
+
+/// An outgoing message.
+#[cfg(not(feature = "alloc"))]
+pub type Buf = heapless::Vec<u8, MAX_PACKET_LEN>;
+#[cfg(feature = "alloc")]
+pub type Buf = alloc::vec::Vec<u8>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    Codec(mqtt_sn_codec::Error),
+    /// The request isn't allowed in this state of the client.
+    State(State),
+    /// MAX_IN_FLIGHT requests wait for their ack.
+    InFlightFull,
+    /// Empty or longer than MAX_CLIENT_ID_LEN.
+    ClientId,
+    /// Longer than MAX_PACKET_LEN, without the alloc feature.
+    TooLong,
+    /// QoS 2, the client publishes and subscribes with QoS -1, 0 and 1.
+    QoS,
+}
+
+impl From<mqtt_sn_codec::Error> for Error {
+    fn from(err: mqtt_sn_codec::Error) -> Self {
+        Error::Codec(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Codec(err) => write!(f, "{}", err),
+            Error::State(state) => write!(f, "not allowed in {:?}", state),
+            Error::InFlightFull => write!(f, "too many requests in flight"),
+            Error::ClientId => write!(f, "invalid client id"),
+            Error::TooLong => write!(f, "message too long"),
+            Error::QoS => write!(f, "QoS 2 not supported"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}